@@ -1,3 +1,4 @@
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 fn main() {
@@ -24,4 +25,87 @@ fn main() {
 
     // Rerun build script if git changes
     println!("cargo:rerun-if-changed=.git/HEAD");
+
+    embed_protocols();
+    #[cfg(feature = "capi")]
+    generate_header();
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
+
+/// Generate `nooshdaroo.h` for the C API next to the built library
+/// (`target/<profile>`), and in OUT_DIR
+#[cfg(feature = "capi")]
+fn generate_header() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let source = manifest_dir.join("src/capi.rs");
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        include_guard: Some("NOOSHDAROO_H".to_string()),
+        header: Some("/* Nooshdaroo C API. Generated by cbindgen from src/capi.rs; do not edit. */".to_string()),
+        cpp_compat: true,
+        documentation: true,
+        enumeration: cbindgen::EnumConfig { prefix_with_name: true, ..Default::default() },
+        ..Default::default()
+    };
+    let bindings = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(&source)
+        .generate()
+        .expect("Failed to generate the C API header");
+
+    // OUT_DIR is target/<profile>/build/<package>/out
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    bindings.write_to_file(out_dir.join("nooshdaroo.h"));
+    if let Some(profile_dir) = out_dir.ancestors().nth(3) {
+        bindings.write_to_file(profile_dir.join("nooshdaroo.h"));
+    }
+}
+
+/// Generate `embedded_protocols.rs` in OUT_DIR listing every PSF file under
+/// `protocols/` as `(relative path, include_str!(...))`
+fn embed_protocols() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let protocol_dir = manifest_dir.join("protocols");
+    println!("cargo:rerun-if-changed=protocols");
+
+    let mut files = Vec::new();
+    collect_psf_files(&protocol_dir, &mut files);
+    files.sort();
+
+    let mut out = String::from("pub static EMBEDDED_PSF: &[(&str, &str)] = &[\n");
+    for file in &files {
+        let relative = file
+            .strip_prefix(&protocol_dir)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        println!("cargo:rerun-if-changed={}", file.display());
+        out.push_str(&format!(
+            "    ({:?}, include_str!({:?})),\n",
+            relative,
+            file.to_string_lossy()
+        ));
+    }
+    out.push_str("];\n");
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    std::fs::write(out_dir.join("embedded_protocols.rs"), out).unwrap();
+}
+
+fn collect_psf_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            collect_psf_files(&path, files);
+        } else if path.extension().and_then(|e| e.to_str()) == Some("psf") {
+            files.push(path);
+        }
+    }
 }
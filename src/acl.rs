@@ -0,0 +1,341 @@
+//! Destination access control
+//!
+//! `[access]` rules decide which destinations the proxy connects to, so an
+//! operator can keep it to web ports or stop it from sending mail. The
+//! first rule whose hosts or address ranges and ports match a destination
+//! decides, and destinations no rule matches get the default.
+//!
+//! The client checks each SOCKS request in [`AccessPolicy::allows`] and
+//! refuses denied ones with "connection not allowed by ruleset". Requests
+//! for a host name are only known by name there, so the server checks
+//! again in [`AccessPolicy::connect`], against every address the name
+//! resolves to, and connects only to allowed ones.
+//!
+//! The server also refuses to connect to [reserved](is_reserved) addresses
+//! unless `allow_private` covers them. Otherwise any client could reach
+//! services listening on the server's loopback or its private network, or
+//! a cloud metadata endpoint, and checking resolved addresses means a name
+//! pointing at one is refused as well.
+
+use crate::config::{AccessAction, AccessConfig, AccessRule};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::TcpStream;
+
+/// An address range such as `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse `address/prefix`, or a bare address for just that address
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| format!("invalid address range {:?}", text))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|&p| p <= bits),
+            None => Some(bits),
+        }
+        .ok_or_else(|| format!("invalid prefix length in {:?}", text))?;
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, canonical(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Loopback, private, link-local, shared, documentation, multicast and
+/// other special-purpose ranges (RFC 6890), which are not destinations on
+/// the Internet
+const RESERVED: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/127",
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Whether `addr` is loopback, private or otherwise not on the Internet.
+/// NAT64 (64:ff9b::/96) and 6to4 (2002::/16) addresses are judged by the
+/// IPv4 address they lead to.
+pub fn is_reserved(addr: IpAddr) -> bool {
+    let reserved = |addr| RESERVED.iter().any(|range| Cidr::parse(range).is_ok_and(|range| range.contains(addr)));
+    reserved(addr) || embedded_ipv4(addr).is_some_and(|v4| reserved(IpAddr::V4(v4)))
+}
+
+/// IPv4 address a NAT64 or 6to4 address reaches
+fn embedded_ipv4(addr: IpAddr) -> Option<Ipv4Addr> {
+    let IpAddr::V6(v6) = addr else { return None };
+    let bits = u128::from(v6);
+    if bits >> 32 == 0x0064_ff9b_0000_0000_0000_0000 {
+        Some(Ipv4Addr::from(bits as u32))
+    } else if bits >> 112 == 0x2002 {
+        Some(Ipv4Addr::from((bits >> 80) as u32))
+    } else {
+        None
+    }
+}
+
+/// IPv4-mapped IPv6 addresses as the IPv4 address they are
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        v4 => v4,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    action: AccessAction,
+    /// Lowercase patterns
+    hosts: Vec<String>,
+    cidrs: Vec<Cidr>,
+    /// Inclusive ranges
+    ports: Vec<(u16, u16)>,
+}
+
+impl Rule {
+    fn new(rule: &AccessRule) -> Result<Self, String> {
+        let ports = rule
+            .ports
+            .iter()
+            .map(|ports| parse_ports(ports).ok_or_else(|| format!("invalid port or range {:?}", ports)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            action: rule.action,
+            hosts: rule.hosts.iter().map(|host| normalize_host(host)).collect(),
+            cidrs: rule.cidrs.iter().map(|cidr| Cidr::parse(cidr)).collect::<Result<_, _>>()?,
+            ports,
+        })
+    }
+
+    fn matches(&self, host: &str, addr: Option<IpAddr>, port: u16) -> bool {
+        let destination = (self.hosts.is_empty() && self.cidrs.is_empty())
+            || self.hosts.iter().any(|pattern| wildcard_match(host.as_bytes(), pattern.as_bytes()))
+            || addr.is_some_and(|addr| self.cidrs.iter().any(|cidr| cidr.contains(addr)));
+        destination && (self.ports.is_empty() || self.ports.iter().any(|&(first, last)| (first..=last).contains(&port)))
+    }
+}
+
+/// Compiled `[access]` rules
+#[derive(Debug, Clone)]
+pub struct AccessPolicy {
+    default: AccessAction,
+    rules: Vec<Rule>,
+    /// Reserved ranges the server may connect to
+    allow_private: Vec<Cidr>,
+}
+
+impl AccessPolicy {
+    pub fn new(config: &AccessConfig) -> Result<Self, String> {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| Rule::new(rule).map_err(|e| format!("access.rules[{}]: {}", i, e)))
+            .collect::<Result<_, _>>()?;
+        let allow_private = config
+            .allow_private
+            .iter()
+            .map(|cidr| Cidr::parse(cidr).map_err(|e| format!("access.allow_private: {}", e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { default: config.default, rules, allow_private })
+    }
+
+    /// Action for `host:port`, where `addr` is the address being connected
+    /// to if known
+    pub fn decide(&self, host: &str, addr: Option<IpAddr>, port: u16) -> AccessAction {
+        let host = normalize_host(host);
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&host, addr, port))
+            .map_or(self.default, |rule| rule.action)
+    }
+
+    /// Whether a request for `host:port` may go ahead, as far as can be told
+    /// without resolving `host`
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.decide(host, host.parse().ok(), port) == AccessAction::Allow
+    }
+
+    /// Whether the server may connect to `addr`, which is neither reserved
+    /// nor in `allow_private`
+    pub fn allows_egress(&self, addr: IpAddr) -> bool {
+        !is_reserved(addr) || self.allow_private.iter().any(|range| range.contains(addr))
+    }
+
+    /// Connect to the first address of `host` that is allowed, by the rules
+    /// and for egress; fails with `PermissionDenied` when none is
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        let allowed: Vec<SocketAddr> = addrs
+            .iter()
+            .filter(|addr| self.allows_egress(addr.ip()))
+            .filter(|addr| self.decide(host, Some(addr.ip()), port) == AccessAction::Allow)
+            .copied()
+            .collect();
+        if allowed.is_empty() && !addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Connection to {}:{} not allowed", host, port),
+            ));
+        }
+        TcpStream::connect(&allowed[..]).await
+    }
+
+    /// [`connect`](Self::connect) to a `host:port` or `[ipv6]:port` target
+    pub async fn connect_target(&self, target: &str) -> io::Result<TcpStream> {
+        let (host, port) = split_target(target)?;
+        self.connect(host, port).await
+    }
+}
+
+/// Host and port of a `host:port` or `[ipv6]:port` target
+pub fn split_target(target: &str) -> io::Result<(&str, u16)> {
+    target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.trim_start_matches('[').trim_end_matches(']'), port.parse().ok()?)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid target address {}", target)))
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub(crate) fn parse_ports(ports: &str) -> Option<(u16, u16)> {
+    let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+    let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    (first <= last).then_some((first, last))
+}
+
+/// Match with `*` and `?` wildcards
+fn wildcard_match(text: &[u8], pattern: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| wildcard_match(&text[i..], rest)),
+        Some((b'?', rest)) => !text.is_empty() && wildcard_match(&text[1..], rest),
+        Some((c, rest)) => text.first() == Some(c) && wildcard_match(&text[1..], rest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: AccessAction, hosts: &[&str], cidrs: &[&str], ports: &[&str]) -> AccessRule {
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        AccessRule { action, hosts: strings(hosts), cidrs: strings(cidrs), ports: strings(ports) }
+    }
+
+    #[test]
+    fn test_access_rules() {
+        let policy = AccessPolicy::new(&AccessConfig {
+            default: AccessAction::Deny,
+            allow_private: Vec::new(),
+            rules: vec![
+                rule(AccessAction::Deny, &[], &[], &["25", "465", "587"]),
+                rule(AccessAction::Deny, &["*.internal.example"], &["10.0.0.0/8", "fd00::/8"], &[]),
+                rule(AccessAction::Allow, &[], &[], &["80", "443", "8000-8999"]),
+            ],
+        })
+        .unwrap();
+
+        assert!(policy.allows("example.com", 443));
+        assert!(policy.allows("Example.COM.", 8080));
+        assert!(!policy.allows("example.com", 22));
+        assert!(!policy.allows("mail.example.com", 25));
+        assert!(!policy.allows("db.internal.example", 443));
+        assert!(!policy.allows("10.1.2.3", 443));
+        assert!(!policy.allows("fd12::1", 80));
+        assert!(policy.allows("192.0.2.1", 443));
+        // Known only by name on the client; the server sees the address
+        assert!(policy.allows("intranet.example.net", 443));
+        assert_eq!(policy.decide("intranet.example.net", Some("10.0.0.5".parse().unwrap()), 443), AccessAction::Deny);
+        assert_eq!(policy.decide("x", Some("::ffff:10.0.0.5".parse().unwrap()), 443), AccessAction::Deny);
+
+        assert!(AccessPolicy::new(&AccessConfig::default()).unwrap().allows("anything", 1));
+        for bad in [rule(AccessAction::Deny, &[], &["10.0.0.0/33"], &[]), rule(AccessAction::Deny, &[], &[], &["90-80"])] {
+            assert!(AccessPolicy::new(&AccessConfig { rules: vec![bad], ..Default::default() }).is_err());
+        }
+    }
+
+    #[test]
+    fn test_egress_protection() {
+        for reserved in ["127.0.0.1", "10.1.2.3", "172.31.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fe80::1", "fd00::1", "::ffff:192.168.0.1"] {
+            assert!(is_reserved(reserved.parse().unwrap()), "{}", reserved);
+        }
+        for nat64 in ["64:ff9b::7f00:1", "64:ff9b::a9fe:a9fe", "2002:c0a8:101::1", "2002:7f00:1::", "64:ff9b:1::1"] {
+            assert!(is_reserved(nat64.parse().unwrap()), "{}", nat64);
+        }
+        for public in ["1.1.1.1", "172.32.0.1", "2606:4700::1111", "64:ff9b::101:101", "2002:101:101::1"] {
+            assert!(!is_reserved(public.parse().unwrap()), "{}", public);
+        }
+
+        let policy = AccessPolicy::new(&AccessConfig {
+            allow_private: vec!["10.20.0.0/16".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(policy.allows_egress("10.20.5.1".parse().unwrap()));
+        assert!(!policy.allows_egress("10.21.5.1".parse().unwrap()));
+        assert!(policy.allows_egress("93.184.216.34".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_connect_checks_resolved_addresses() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let deny_loopback = AccessPolicy::new(&AccessConfig {
+            default: AccessAction::Allow,
+            rules: vec![rule(AccessAction::Deny, &[], &["127.0.0.0/8", "::1"], &[])],
+            allow_private: vec!["127.0.0.0/8".to_string(), "::1".to_string()],
+        })
+        .unwrap();
+        let error = deny_loopback.connect("localhost", port).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Loopback is reserved, so needs allow_private
+        let error = AccessPolicy::new(&AccessConfig::default()).unwrap().connect("localhost", port).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        let allow_all = AccessPolicy::new(&AccessConfig {
+            allow_private: vec!["127.0.0.0/8".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(allow_all.connect("127.0.0.1", port).await.is_ok());
+        assert!(allow_all.connect_target(&format!("[::ffff:127.0.0.1]:{}", port)).await.is_ok());
+    }
+}
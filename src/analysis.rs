@@ -0,0 +1,694 @@
+//! Lightweight DPI classifier for self-testing protocol emulations
+//!
+//! Implements a small nDPI-style classifier so developers can check that a
+//! protocol emulation is actually identified as the protocol it imitates.
+//! Flows are classified from:
+//! - Payload signatures (TLS, HTTP, SSH, DNS, QUIC, SMTP, MQTT, ...)
+//! - TLS ClientHello fingerprints (JA3-style string and SNI)
+//! - Well-known port heuristics
+//! - Payload entropy (high-entropy flows without a signature look like tunnels)
+//! - Packet-size Markov transition features, compared against trained references
+
+use crate::pcap::{DecodedPacket, IpProtocol};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Number of packet-size bins used by the Markov features
+pub const SIZE_BINS: usize = 6;
+
+/// Upper bounds (inclusive) of the packet-size bins
+const SIZE_BIN_LIMITS: [usize; SIZE_BINS - 1] = [64, 128, 256, 512, 1024];
+
+/// Entropy (bits/byte) above which a payload is considered random-looking
+const HIGH_ENTROPY: f64 = 7.2;
+
+/// Direction-aware packet within a flow
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowPacket {
+    /// Time since the first packet of the flow
+    pub offset: Duration,
+    /// Whether the packet was sent by the flow initiator
+    pub from_client: bool,
+    /// Payload length
+    pub len: usize,
+}
+
+/// A bidirectional TCP/UDP flow
+#[derive(Debug, Clone)]
+pub struct Flow {
+    /// Transport protocol
+    pub transport: IpProtocol,
+    /// Flow initiator
+    pub client: SocketAddr,
+    /// Flow responder
+    pub server: SocketAddr,
+    /// Non-empty payload packets in order
+    pub packets: Vec<FlowPacket>,
+    /// Payloads sent by the client, in order
+    pub client_payloads: Vec<Vec<u8>>,
+    /// Payloads sent by the server, in order
+    pub server_payloads: Vec<Vec<u8>>,
+}
+
+impl Flow {
+    /// Total payload bytes in both directions
+    pub fn total_bytes(&self) -> usize {
+        self.packets.iter().map(|p| p.len).sum()
+    }
+}
+
+/// Group decoded packets into bidirectional flows
+pub fn flows_from_packets(packets: &[DecodedPacket]) -> Vec<Flow> {
+    let mut flows: Vec<Flow> = Vec::new();
+    let mut index: HashMap<(IpProtocol, SocketAddr, SocketAddr), usize> = HashMap::new();
+    let mut starts: Vec<Duration> = Vec::new();
+
+    for packet in packets {
+        let forward = (packet.protocol, packet.src, packet.dst);
+        let reverse = (packet.protocol, packet.dst, packet.src);
+
+        let (i, from_client) = if let Some(&i) = index.get(&forward) {
+            (i, true)
+        } else if let Some(&i) = index.get(&reverse) {
+            (i, false)
+        } else {
+            // A SYN+ACK means we missed the SYN; the sender is the server
+            let syn_ack = packet.protocol == IpProtocol::Tcp && packet.tcp_flags & 0x12 == 0x12;
+            let (client, server) = if syn_ack {
+                (packet.dst, packet.src)
+            } else {
+                (packet.src, packet.dst)
+            };
+            flows.push(Flow {
+                transport: packet.protocol,
+                client,
+                server,
+                packets: Vec::new(),
+                client_payloads: Vec::new(),
+                server_payloads: Vec::new(),
+            });
+            starts.push(packet.timestamp);
+            let i = flows.len() - 1;
+            index.insert((packet.protocol, client, server), i);
+            (i, !syn_ack)
+        };
+
+        if packet.payload.is_empty() {
+            continue;
+        }
+
+        let flow = &mut flows[i];
+        flow.packets.push(FlowPacket {
+            offset: packet.timestamp.saturating_sub(starts[i]),
+            from_client,
+            len: packet.payload.len(),
+        });
+        if from_client {
+            flow.client_payloads.push(packet.payload.clone());
+        } else {
+            flow.server_payloads.push(packet.payload.clone());
+        }
+    }
+
+    flows.retain(|f| !f.packets.is_empty());
+    flows
+}
+
+/// Shannon entropy of `data` in bits per byte
+pub fn shannon_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for &b in data {
+        counts[b as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Bin index for a payload size
+fn size_bin(len: usize) -> usize {
+    SIZE_BIN_LIMITS
+        .iter()
+        .position(|&limit| len <= limit)
+        .unwrap_or(SIZE_BINS - 1)
+}
+
+/// First-order Markov transition matrix over packet-size bins (rows sum to 1)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SizeMarkov {
+    /// Transition probabilities `[from][to]`
+    pub transitions: [[f64; SIZE_BINS]; SIZE_BINS],
+}
+
+impl SizeMarkov {
+    /// Build the transition matrix from a flow's packet sizes
+    pub fn from_flow(flow: &Flow) -> Self {
+        let mut counts = [[0f64; SIZE_BINS]; SIZE_BINS];
+        for pair in flow.packets.windows(2) {
+            counts[size_bin(pair[0].len)][size_bin(pair[1].len)] += 1.0;
+        }
+        Self::normalized(counts)
+    }
+
+    fn normalized(mut counts: [[f64; SIZE_BINS]; SIZE_BINS]) -> Self {
+        for row in counts.iter_mut() {
+            let total: f64 = row.iter().sum();
+            if total > 0.0 {
+                row.iter_mut().for_each(|v| *v /= total);
+            }
+        }
+        Self { transitions: counts }
+    }
+
+    /// Mean absolute difference between two matrices (0.0 = identical)
+    pub fn distance(&self, other: &SizeMarkov) -> f64 {
+        let sum: f64 = self
+            .transitions
+            .iter()
+            .flatten()
+            .zip(other.transitions.iter().flatten())
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        // Each row contributes at most 2.0
+        sum / (2.0 * SIZE_BINS as f64)
+    }
+
+    /// Average several matrices
+    pub fn average(models: &[SizeMarkov]) -> Self {
+        let mut sum = [[0f64; SIZE_BINS]; SIZE_BINS];
+        for model in models {
+            for (row, model_row) in sum.iter_mut().zip(model.transitions.iter()) {
+                for (v, m) in row.iter_mut().zip(model_row.iter()) {
+                    *v += m;
+                }
+            }
+        }
+        Self::normalized(sum)
+    }
+}
+
+/// TLS ClientHello fingerprint
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TlsFingerprint {
+    /// Legacy version field of the ClientHello
+    pub version: u16,
+    /// Offered cipher suites (GREASE removed)
+    pub cipher_suites: Vec<u16>,
+    /// Extension types in order (GREASE removed)
+    pub extensions: Vec<u16>,
+    /// Supported groups (GREASE removed)
+    pub groups: Vec<u16>,
+    /// EC point formats
+    pub point_formats: Vec<u8>,
+    /// Server name indication
+    pub sni: Option<String>,
+    /// ALPN protocols
+    pub alpn: Vec<String>,
+}
+
+impl TlsFingerprint {
+    /// JA3-style fingerprint string
+    pub fn ja3(&self) -> String {
+        let join = |v: Vec<String>| v.join("-");
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            join(self.cipher_suites.iter().map(|c| c.to_string()).collect()),
+            join(self.extensions.iter().map(|e| e.to_string()).collect()),
+            join(self.groups.iter().map(|g| g.to_string()).collect()),
+            join(self.point_formats.iter().map(|p| p.to_string()).collect()),
+        )
+    }
+}
+
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && (value >> 8) == (value & 0xff)
+}
+
+/// Parse a TLS ClientHello record into a fingerprint
+pub fn parse_client_hello(data: &[u8]) -> Option<TlsFingerprint> {
+    // Record header + handshake header
+    if data.len() < 9 || data[0] != 0x16 || data[5] != 0x01 {
+        return None;
+    }
+    let body = &data[9..];
+    let mut pos = 0;
+
+    let take = |pos: &mut usize, n: usize| -> Option<&[u8]> {
+        let slice = body.get(*pos..*pos + n)?;
+        *pos += n;
+        Some(slice)
+    };
+    let u16_at = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+
+    let version = u16_at(take(&mut pos, 2)?);
+    take(&mut pos, 32)?; // random
+    let sid_len = take(&mut pos, 1)?[0] as usize;
+    take(&mut pos, sid_len)?;
+
+    let cs_len = u16_at(take(&mut pos, 2)?) as usize;
+    let cipher_suites = take(&mut pos, cs_len)?
+        .chunks_exact(2)
+        .map(u16_at)
+        .filter(|c| !is_grease(*c))
+        .collect();
+
+    let comp_len = take(&mut pos, 1)?[0] as usize;
+    take(&mut pos, comp_len)?;
+
+    let mut fingerprint = TlsFingerprint {
+        version,
+        cipher_suites,
+        extensions: Vec::new(),
+        groups: Vec::new(),
+        point_formats: Vec::new(),
+        sni: None,
+        alpn: Vec::new(),
+    };
+
+    let ext_total = match take(&mut pos, 2) {
+        Some(b) => u16_at(b) as usize,
+        None => return Some(fingerprint),
+    };
+    let exts = body.get(pos..pos + ext_total).unwrap_or(&body[pos..]);
+    let mut e = 0;
+    while e + 4 <= exts.len() {
+        let ext_type = u16_at(&exts[e..]);
+        let ext_len = u16_at(&exts[e + 2..]) as usize;
+        let ext = match exts.get(e + 4..e + 4 + ext_len) {
+            Some(ext) => ext,
+            None => break,
+        };
+        e += 4 + ext_len;
+
+        if is_grease(ext_type) {
+            continue;
+        }
+        fingerprint.extensions.push(ext_type);
+
+        match ext_type {
+            0x0000 if ext.len() >= 5 => {
+                let name_len = u16_at(&ext[3..]) as usize;
+                if let Some(name) = ext.get(5..5 + name_len) {
+                    fingerprint.sni = Some(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            0x000a if ext.len() >= 2 => {
+                fingerprint.groups = ext[2..]
+                    .chunks_exact(2)
+                    .map(u16_at)
+                    .filter(|g| !is_grease(*g))
+                    .collect();
+            }
+            0x000b if !ext.is_empty() => {
+                fingerprint.point_formats = ext[1..].to_vec();
+            }
+            0x0010 if ext.len() >= 2 => {
+                let mut a = 2;
+                while a < ext.len() {
+                    let len = ext[a] as usize;
+                    if let Some(proto) = ext.get(a + 1..a + 1 + len) {
+                        fingerprint.alpn.push(String::from_utf8_lossy(proto).into_owned());
+                    }
+                    a += 1 + len;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(fingerprint)
+}
+
+/// Features extracted from a flow
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowFeatures {
+    /// Server port
+    pub server_port: u16,
+    /// Number of payload packets
+    pub packet_count: usize,
+    /// Total payload bytes
+    pub total_bytes: usize,
+    /// Mean payload entropy over packets of 16 bytes or more
+    pub mean_entropy: f64,
+    /// Packet-size Markov model
+    pub size_markov: SizeMarkov,
+    /// TLS ClientHello fingerprint, if the flow starts with one
+    pub tls: Option<TlsFingerprint>,
+}
+
+impl FlowFeatures {
+    /// Extract features from a flow
+    pub fn extract(flow: &Flow) -> Self {
+        let entropies: Vec<f64> = flow
+            .client_payloads
+            .iter()
+            .chain(flow.server_payloads.iter())
+            .filter(|p| p.len() >= 16)
+            .map(|p| shannon_entropy(p))
+            .collect();
+        let mean_entropy = if entropies.is_empty() {
+            0.0
+        } else {
+            entropies.iter().sum::<f64>() / entropies.len() as f64
+        };
+
+        Self {
+            server_port: flow.server.port(),
+            packet_count: flow.packets.len(),
+            total_bytes: flow.total_bytes(),
+            mean_entropy,
+            size_markov: SizeMarkov::from_flow(flow),
+            tls: flow.client_payloads.first().and_then(|p| parse_client_hello(p)),
+        }
+    }
+}
+
+/// Classification result for a flow
+#[derive(Debug, Clone, Serialize)]
+pub struct Classification {
+    /// Best-matching protocol
+    pub protocol: String,
+    /// Confidence (0.0-1.0)
+    pub confidence: f64,
+    /// All candidate protocols with their scores, best first
+    pub candidates: Vec<(String, f64)>,
+    /// Human-readable reasons for the decision
+    pub evidence: Vec<String>,
+}
+
+impl Classification {
+    /// Whether the flow classifies as `expected` (aliases such as
+    /// `https`/`tls` and `dns-udp-tunnel`/`dns` are treated as equal)
+    pub fn matches(&self, expected: &str) -> bool {
+        canonical(&self.protocol) == canonical(expected)
+    }
+}
+
+/// Collapse protocol ids that a DPI box cannot tell apart
+fn canonical(protocol: &str) -> &str {
+    match protocol {
+        "https" | "tls" | "tls13" | "http2" | "doh" | "grpc" => "tls",
+        "dns" | "dns-udp-tunnel" | "dns_udp_tunnel" | "dns-google" => "dns",
+        other => other,
+    }
+}
+
+/// Signature-, port-, entropy- and Markov-based flow classifier
+#[derive(Debug, Clone, Default)]
+pub struct Classifier {
+    references: HashMap<String, Vec<SizeMarkov>>,
+}
+
+impl Classifier {
+    /// Create a classifier with no trained size references
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a labeled flow as a packet-size reference
+    pub fn train(&mut self, label: &str, flow: &Flow) {
+        if flow.packets.len() >= 2 {
+            self.references
+                .entry(label.to_string())
+                .or_default()
+                .push(SizeMarkov::from_flow(flow));
+        }
+    }
+
+    /// Classify a single flow
+    pub fn classify(&self, flow: &Flow) -> Classification {
+        let features = FlowFeatures::extract(flow);
+        let mut scores: HashMap<String, f64> = HashMap::new();
+        let mut evidence = Vec::new();
+        let add = |scores: &mut HashMap<String, f64>, proto: &str, score: f64| {
+            *scores.entry(proto.to_string()).or_insert(0.0) += score;
+        };
+
+        // Payload signatures
+        let first_client = flow.client_payloads.first().map(|p| p.as_slice()).unwrap_or(&[]);
+        let first_server = flow.server_payloads.first().map(|p| p.as_slice()).unwrap_or(&[]);
+        let mut signature = false;
+        for (proto, reason) in payload_signatures(flow.transport, first_client, first_server) {
+            add(&mut scores, proto, 0.6);
+            evidence.push(reason);
+            signature = true;
+        }
+
+        if let Some(ref tls) = features.tls {
+            let proto = if tls.alpn.iter().any(|a| a == "h2" || a == "http/1.1") {
+                "https"
+            } else {
+                "tls"
+            };
+            add(&mut scores, proto, 0.2);
+            evidence.push(format!(
+                "TLS ClientHello (sni: {}, alpn: [{}], ja3: {})",
+                tls.sni.as_deref().unwrap_or("-"),
+                tls.alpn.join(","),
+                tls.ja3()
+            ));
+        }
+
+        // Port heuristics
+        if let Some(proto) = port_hint(flow.transport, features.server_port) {
+            add(&mut scores, proto, 0.2);
+            evidence.push(format!("well-known port {}", features.server_port));
+        }
+
+        // Entropy: random-looking payload without any signature is a tunnel tell
+        if !signature && features.mean_entropy >= HIGH_ENTROPY {
+            add(&mut scores, "unknown-encrypted", 0.5);
+            evidence.push(format!(
+                "high payload entropy ({:.2} bits/byte) with no protocol signature",
+                features.mean_entropy
+            ));
+        }
+
+        // Packet-size Markov similarity against trained references
+        if features.packet_count >= 2 {
+            let best = self
+                .references
+                .iter()
+                .map(|(label, models)| (label, features.size_markov.distance(&SizeMarkov::average(models))))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+            if let Some((label, distance)) = best {
+                let similarity = 1.0 - distance;
+                if similarity > 0.5 {
+                    add(&mut scores, label, 0.3 * similarity);
+                    evidence.push(format!("packet-size pattern resembles {} ({:.2})", label, similarity));
+                }
+            }
+        }
+
+        let mut candidates: Vec<(String, f64)> = scores.into_iter().collect();
+        candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        let (protocol, confidence) = candidates
+            .first()
+            .map(|(p, s)| (p.clone(), s.min(1.0)))
+            .unwrap_or_else(|| ("unknown".to_string(), 0.0));
+
+        Classification {
+            protocol,
+            confidence,
+            candidates,
+            evidence,
+        }
+    }
+
+    /// Classify every flow
+    pub fn classify_all(&self, flows: &[Flow]) -> Vec<Classification> {
+        flows.iter().map(|f| self.classify(f)).collect()
+    }
+}
+
+/// Port-based protocol guess
+fn port_hint(transport: IpProtocol, port: u16) -> Option<&'static str> {
+    match (transport, port) {
+        (IpProtocol::Tcp, 443) | (IpProtocol::Tcp, 8443) => Some("https"),
+        (IpProtocol::Tcp, 80) | (IpProtocol::Tcp, 8080) => Some("http"),
+        (IpProtocol::Tcp, 853) => Some("tls"),
+        (_, 53) => Some("dns"),
+        (IpProtocol::Tcp, 22) => Some("ssh"),
+        (IpProtocol::Tcp, 25) | (IpProtocol::Tcp, 587) => Some("smtp"),
+        (IpProtocol::Tcp, 143) => Some("imap"),
+        (IpProtocol::Tcp, 21) => Some("ftp"),
+        (IpProtocol::Tcp, 1883) => Some("mqtt"),
+        (IpProtocol::Tcp, 3389) => Some("rdp"),
+        (IpProtocol::Udp, 123) => Some("ntp"),
+        (IpProtocol::Udp, 443) => Some("quic"),
+        (IpProtocol::Udp, 3478) => Some("stun"),
+        (IpProtocol::Udp, 5060) | (IpProtocol::Tcp, 5060) => Some("sip"),
+        _ => None,
+    }
+}
+
+/// Match well-known payload signatures on the first client/server payloads
+fn payload_signatures(
+    transport: IpProtocol,
+    client: &[u8],
+    server: &[u8],
+) -> Vec<(&'static str, String)> {
+    let mut found = Vec::new();
+
+    match transport {
+        IpProtocol::Tcp => {
+            if client.len() >= 6 && client[0] == 0x16 && client[1] == 0x03 && client[5] == 0x01 {
+                found.push(("tls", "TLS handshake record".to_string()));
+            } else if client.len() >= 5 && client[0] == 0x17 && client[1] == 0x03 {
+                found.push(("tls", "TLS application data record".to_string()));
+            }
+
+            const METHODS: [&[u8]; 7] = [b"GET ", b"POST ", b"PUT ", b"HEAD ", b"CONNECT ", b"OPTIONS ", b"DELETE "];
+            if METHODS.iter().any(|m| client.starts_with(m)) || server.starts_with(b"HTTP/1.") {
+                found.push(("http", "HTTP request line".to_string()));
+            }
+            if client.starts_with(b"PRI * HTTP/2.0") {
+                found.push(("http2", "HTTP/2 connection preface".to_string()));
+            }
+            if client.starts_with(b"SSH-") || server.starts_with(b"SSH-") {
+                found.push(("ssh", "SSH version banner".to_string()));
+            }
+            if server.starts_with(b"220 ") && (client.starts_with(b"EHLO") || client.starts_with(b"HELO")) {
+                found.push(("smtp", "SMTP greeting".to_string()));
+            } else if server.starts_with(b"220") && client.starts_with(b"USER ") {
+                found.push(("ftp", "FTP login".to_string()));
+            }
+            if server.starts_with(b"* OK") {
+                found.push(("imap", "IMAP greeting".to_string()));
+            }
+            if client.len() > 10 && client[0] == 0x10 && client.windows(4).any(|w| w == b"MQTT") {
+                found.push(("mqtt", "MQTT CONNECT".to_string()));
+            }
+            if client.starts_with(b"\x13BitTorrent protocol") {
+                found.push(("bittorrent", "BitTorrent handshake".to_string()));
+            }
+            if client.len() >= 14 && u16::from_be_bytes([client[0], client[1]]) as usize == client.len() - 2
+                && looks_like_dns(&client[2..])
+            {
+                found.push(("dns", "DNS over TCP query".to_string()));
+            }
+        }
+        IpProtocol::Udp => {
+            if looks_like_dns(client) {
+                found.push(("dns", "DNS query header".to_string()));
+            } else if client.len() >= 7 && client[0] & 0xc0 == 0xc0
+                && u32::from_be_bytes([client[1], client[2], client[3], client[4]]) == 1
+            {
+                found.push(("quic", "QUIC v1 long header".to_string()));
+            } else if client.len() == 48 && matches!(client[0] & 0x07, 3 | 4) {
+                found.push(("ntp", "NTP packet".to_string()));
+            } else if client.len() >= 20 && client[4..8] == [0x21, 0x12, 0xa4, 0x42] {
+                found.push(("stun", "STUN magic cookie".to_string()));
+            } else if client.len() >= 12 && client[0] >> 6 == 2 {
+                found.push(("rtp", "RTP version 2 header".to_string()));
+            }
+        }
+    }
+
+    found
+}
+
+/// Heuristic check for a DNS query message
+fn looks_like_dns(msg: &[u8]) -> bool {
+    if msg.len() < 17 {
+        return false;
+    }
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let opcode = (flags >> 11) & 0x0f;
+    // Standard query with a single question, first label length sane
+    qdcount == 1 && opcode == 0 && (1..=63).contains(&msg[12])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn packet(src: &str, dst: &str, protocol: IpProtocol, payload: &[u8], ms: u64) -> DecodedPacket {
+        DecodedPacket {
+            timestamp: Duration::from_millis(ms),
+            protocol,
+            src: src.parse::<SocketAddr>().unwrap(),
+            dst: dst.parse::<SocketAddr>().unwrap(),
+            tcp_flags: 0x18,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(shannon_entropy(&[0u8; 64]), 0.0);
+        let all: Vec<u8> = (0..=255).collect();
+        assert!((shannon_entropy(&all) - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_flows_group_both_directions() {
+        let packets = vec![
+            packet("10.0.0.1:40000", "10.0.0.2:80", IpProtocol::Tcp, b"GET / HTTP/1.1\r\n\r\n", 0),
+            packet("10.0.0.2:80", "10.0.0.1:40000", IpProtocol::Tcp, b"HTTP/1.1 200 OK\r\n\r\n", 5),
+            packet("10.0.0.1:40001", "10.0.0.2:80", IpProtocol::Tcp, b"GET /b HTTP/1.1\r\n\r\n", 7),
+        ];
+        let flows = flows_from_packets(&packets);
+        assert_eq!(flows.len(), 2);
+        assert_eq!(flows[0].packets.len(), 2);
+        assert!(!flows[0].packets[1].from_client);
+
+        let class = Classifier::new().classify(&flows[0]);
+        assert_eq!(class.protocol, "http");
+    }
+
+    #[test]
+    fn test_tls_client_hello_fingerprint() {
+        let hello = crate::traceroute::probe_payload("https", "example.com");
+        let fp = parse_client_hello(&hello).unwrap();
+        assert_eq!(fp.sni.as_deref(), Some("example.com"));
+        assert_eq!(fp.cipher_suites, vec![0x1301, 0x1302, 0x1303]);
+        assert!(fp.ja3().starts_with("771,4865-4866-4867,0-"));
+
+        let flows = flows_from_packets(&[packet("10.0.0.1:40000", "10.0.0.2:443", IpProtocol::Tcp, &hello, 0)]);
+        let class = Classifier::new().classify(&flows[0]);
+        assert!(class.matches("https"));
+    }
+
+    #[test]
+    fn test_random_payload_is_flagged() {
+        let noise: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
+        let flows = flows_from_packets(&[packet("10.0.0.1:40000", "10.0.0.2:9999", IpProtocol::Tcp, &noise, 0)]);
+        let class = Classifier::new().classify(&flows[0]);
+        assert_eq!(class.protocol, "unknown-encrypted");
+    }
+
+    #[test]
+    fn test_markov_reference_matching() {
+        let sizes = [100usize, 1400, 1400, 100, 1400, 1400];
+        let packets: Vec<DecodedPacket> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| packet("10.0.0.1:40000", "10.0.0.2:9000", IpProtocol::Tcp, &vec![b'a'; len], i as u64))
+            .collect();
+        let flows = flows_from_packets(&packets);
+
+        let mut classifier = Classifier::new();
+        classifier.train("bulk", &flows[0]);
+        let class = classifier.classify(&flows[0]);
+        assert_eq!(class.protocol, "bulk");
+
+        let same = SizeMarkov::from_flow(&flows[0]);
+        assert_eq!(same.distance(&same), 0.0);
+    }
+}
@@ -4,6 +4,7 @@
 //! to make proxy traffic indistinguishable from legitimate app traffic.
 
 use rand::distributions::Distribution;
+use rand::Rng;
 use rand_distr::{Normal, Uniform, Exp, WeightedIndex};
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
@@ -575,6 +576,216 @@ impl ApplicationProfile {
         }
     }
 
+    /// Counter-Strike 2 match on a 64-tick server
+    ///
+    /// Small input packets every tick from the client, delta-compressed
+    /// world snapshots back; the lobby before the match is a short TCP burst.
+    pub fn cs2() -> Self {
+        Self {
+            name: "Counter-Strike 2".to_string(),
+            category: AppCategory::Gaming,
+            upstream: PacketProfile {
+                size_distribution: SizeDistribution::Normal {
+                    mean: 110,
+                    stddev: 30,
+                },
+                packet_rate: RateDistribution {
+                    mean: 64.0,
+                    stddev: 2.0,
+                    min: 60.0,
+                    max: 128.0,
+                },
+                delay_distribution: DelayDistribution {
+                    mean_ms: 16,  // 64 tick
+                    stddev_ms: 1,
+                },
+            },
+            downstream: PacketProfile {
+                size_distribution: SizeDistribution::Multimodal {
+                    modes: vec![
+                        (180, 0.55),  // Quiet deltas
+                        (420, 0.35),  // Firefights
+                        (1200, 0.10), // Round start, full updates
+                    ],
+                },
+                packet_rate: RateDistribution {
+                    mean: 64.0,
+                    stddev: 2.0,
+                    min: 60.0,
+                    max: 70.0,
+                },
+                delay_distribution: DelayDistribution {
+                    mean_ms: 16,
+                    stddev_ms: 1,
+                },
+            },
+            burst_patterns: vec![
+                BurstPattern {
+                    name: "Round start full snapshot".to_string(),
+                    interval: Duration::from_secs(115),
+                    packet_count: 8,
+                    packet_size: 1200,
+                    probability: 0.9,
+                },
+            ],
+            states: vec![
+                ConnectionState {
+                    name: "Lobby".to_string(),
+                    duration: Duration::from_secs(20),
+                    pattern: StatePattern::Bursty {
+                        avg_rate: 10.0,
+                        burst_size: 12,
+                    },
+                    next_state: Some("Match".to_string()),
+                },
+                ConnectionState {
+                    name: "Match".to_string(),
+                    duration: Duration::from_secs(2400), // 40 min
+                    pattern: StatePattern::Steady { rate: 64.0 },
+                    next_state: None,
+                },
+            ],
+            session_duration: Duration::from_secs(2400),
+        }
+    }
+
+    /// Fortnite battle royale match (30 Hz server)
+    pub fn fortnite() -> Self {
+        Self {
+            name: "Fortnite".to_string(),
+            category: AppCategory::Gaming,
+            upstream: PacketProfile {
+                size_distribution: SizeDistribution::Normal {
+                    mean: 90,
+                    stddev: 25,
+                },
+                packet_rate: RateDistribution {
+                    mean: 30.0,
+                    stddev: 3.0,
+                    min: 20.0,
+                    max: 60.0,
+                },
+                delay_distribution: DelayDistribution {
+                    mean_ms: 33,
+                    stddev_ms: 3,
+                },
+            },
+            downstream: PacketProfile {
+                size_distribution: SizeDistribution::Bimodal {
+                    mode1: 300,  // Replicated actors nearby
+                    mode2: 1100, // Bus and drop, crowded areas
+                    mode1_weight: 0.8,
+                },
+                packet_rate: RateDistribution {
+                    mean: 30.0,
+                    stddev: 2.0,
+                    min: 25.0,
+                    max: 35.0,
+                },
+                delay_distribution: DelayDistribution {
+                    mean_ms: 33,
+                    stddev_ms: 2,
+                },
+            },
+            burst_patterns: vec![],
+            states: vec![
+                ConnectionState {
+                    name: "Lobby".to_string(),
+                    duration: Duration::from_secs(60),
+                    pattern: StatePattern::Bursty {
+                        avg_rate: 5.0,
+                        burst_size: 20,
+                    },
+                    next_state: Some("Match".to_string()),
+                },
+                ConnectionState {
+                    name: "Match".to_string(),
+                    duration: Duration::from_secs(1500),
+                    pattern: StatePattern::RampDown {
+                        start_rate: 30.0,
+                        end_rate: 20.0,
+                    },
+                    next_state: None,
+                },
+            ],
+            session_duration: Duration::from_secs(1500),
+        }
+    }
+
+    /// Steam game download from a content server (see [`crate::steam_cdn`])
+    pub fn steam() -> Self {
+        Self {
+            name: "Steam".to_string(),
+            category: AppCategory::FileTransfer,
+            upstream: PacketProfile {
+                size_distribution: SizeDistribution::Bimodal {
+                    mode1: 66,   // ACKs
+                    mode2: 420,  // Chunk requests
+                    mode1_weight: 0.9,
+                },
+                packet_rate: RateDistribution {
+                    mean: 300.0,
+                    stddev: 80.0,
+                    min: 50.0,
+                    max: 800.0,
+                },
+                delay_distribution: DelayDistribution {
+                    mean_ms: 3,
+                    stddev_ms: 2,
+                },
+            },
+            downstream: PacketProfile {
+                size_distribution: SizeDistribution::Normal {
+                    mean: 1460,  // Full segments
+                    stddev: 15,
+                },
+                packet_rate: RateDistribution {
+                    mean: 4000.0,  // Saturates the link
+                    stddev: 1500.0,
+                    min: 500.0,
+                    max: 9000.0,
+                },
+                delay_distribution: DelayDistribution {
+                    mean_ms: 1,
+                    stddev_ms: 1,
+                },
+            },
+            burst_patterns: vec![
+                BurstPattern {
+                    name: "Depot chunk".to_string(),
+                    interval: Duration::from_millis(250),
+                    packet_count: 720,  // Up to 1 MiB
+                    packet_size: 1460,
+                    probability: 1.0,
+                },
+            ],
+            states: vec![
+                ConnectionState {
+                    name: "Manifest".to_string(),
+                    duration: Duration::from_secs(2),
+                    pattern: StatePattern::Bursty {
+                        avg_rate: 200.0,
+                        burst_size: 60,
+                    },
+                    next_state: Some("Downloading".to_string()),
+                },
+                ConnectionState {
+                    name: "Downloading".to_string(),
+                    duration: Duration::from_secs(1800),
+                    pattern: StatePattern::Steady { rate: 4000.0 },
+                    next_state: Some("Verifying".to_string()),
+                },
+                ConnectionState {
+                    name: "Verifying".to_string(),
+                    duration: Duration::from_secs(30),
+                    pattern: StatePattern::Idle,
+                    next_state: None,
+                },
+            ],
+            session_duration: Duration::from_secs(1800),
+        }
+    }
+
     /// Get profile by name
     pub fn get(name: &str) -> Option<Self> {
         match name.to_lowercase().as_str() {
@@ -584,6 +795,9 @@ impl ApplicationProfile {
             "teams" => Some(Self::teams()),
             "https" | "browsing" => Some(Self::https_browsing()),
             "whatsapp" => Some(Self::whatsapp()),
+            "cs2" | "counter-strike" => Some(Self::cs2()),
+            "fortnite" => Some(Self::fortnite()),
+            "steam" | "steam-cdn" => Some(Self::steam()),
             _ => None,
         }
     }
@@ -597,6 +811,9 @@ impl ApplicationProfile {
             "teams".to_string(),
             "https".to_string(),
             "whatsapp".to_string(),
+            "cs2".to_string(),
+            "fortnite".to_string(),
+            "steam".to_string(),
         ]
     }
 }
@@ -688,16 +905,23 @@ impl ApplicationEmulator {
     }
 
     fn generate_size(&mut self, dist: &SizeDistribution) -> usize {
-        match dist {
+        dist.sample(&mut self.rng)
+    }
+}
+
+impl SizeDistribution {
+    /// Draw one packet size
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        match self {
             SizeDistribution::Normal { mean, stddev } => {
                 let normal = Normal::new(*mean as f64, *stddev as f64)
                     .unwrap_or_else(|_| Normal::new(1400.0, 200.0).unwrap());
-                normal.sample(&mut self.rng)
+                normal.sample(rng)
                     .max(64.0)
                     .min(1500.0) as usize
             }
             SizeDistribution::Bimodal { mode1, mode2, mode1_weight } => {
-                if rand::random::<f64>() < *mode1_weight {
+                if rng.gen::<f64>() < *mode1_weight {
                     *mode1
                 } else {
                     *mode2
@@ -706,16 +930,16 @@ impl ApplicationEmulator {
             SizeDistribution::Multimodal { modes } => {
                 let weights: Vec<f64> = modes.iter().map(|(_, w)| *w).collect();
                 let dist = WeightedIndex::new(&weights).unwrap();
-                modes[dist.sample(&mut self.rng)].0
+                modes[dist.sample(rng)].0
             }
             SizeDistribution::Uniform { min, max } => {
                 let uniform = Uniform::new(*min, *max);
-                uniform.sample(&mut self.rng)
+                uniform.sample(rng)
             }
             SizeDistribution::Exponential { mean } => {
                 let rate = 1.0 / *mean as f64;
                 let exp = Exp::new(rate).unwrap_or_else(|_| Exp::new(1.0 / 1400.0).unwrap());
-                exp.sample(&mut self.rng)
+                exp.sample(rng)
                     .max(64.0)
                     .min(1500.0) as usize
             }
@@ -765,6 +989,30 @@ mod tests {
         assert_eq!(initial_state.name, "Handshake");
     }
 
+    #[test]
+    fn test_gaming_profiles() {
+        for name in ["cs2", "fortnite"] {
+            let profile = ApplicationProfile::get(name).unwrap();
+            assert_eq!(profile.category, AppCategory::Gaming);
+            assert_eq!(profile.states[0].name, "Lobby");
+            let mut emulator = ApplicationEmulator::new(profile);
+            for _ in 0..100 {
+                assert!(emulator.generate_upstream_size() <= 300);
+                assert!(emulator.generate_delay(false) < Duration::from_millis(50));
+            }
+        }
+    }
+
+    #[test]
+    fn test_steam_profile() {
+        let profile = ApplicationProfile::get("steam").unwrap();
+        assert_eq!(profile.category, AppCategory::FileTransfer);
+        let mut emulator = ApplicationEmulator::new(profile);
+        for _ in 0..100 {
+            assert!(emulator.generate_downstream_size() > 1350);
+        }
+    }
+
     #[test]
     fn test_get_profile() {
         assert!(ApplicationProfile::get("zoom").is_some());
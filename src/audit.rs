@@ -0,0 +1,300 @@
+//! Privacy-aware connection audit log
+//!
+//! Operators need to see what their server carried to debug it and, in
+//! some places, to meet legal obligations, but every field kept about a
+//! user is one that can be seized. With `[server.audit]` configured the
+//! server writes a `start` and a `stop` record per tunnel, one JSON object
+//! per line in `audit-YYYY-MM-DD.jsonl` under the configured directory:
+//!
+//! - `connection`: a number pairing the two records
+//! - `client`: the client address as precise as `client_ip` allows; the
+//!   default keeps only the /24 (IPv4) or /48 (IPv6) network
+//! - `protocol`: the protocol the tunnel was disguised as
+//! - on `stop`, `duration` in seconds and `bytes_received`/`bytes_sent`,
+//!   counted toward and from the destination (null for UDP DNS sessions,
+//!   whose traffic is not counted)
+//!
+//! Destinations are never recorded. A file is deleted once all its records
+//! are older than the retention, when the log is opened and at each new
+//! day.
+
+use crate::config::{AuditConfig, ClientIpPrivacy};
+use crate::protocol::ProtocolId;
+use chrono::NaiveDate;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+const FILE_PREFIX: &str = "audit-";
+const FILE_SUFFIX: &str = ".jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEvent {
+    Start,
+    Stop,
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// RFC 3339, UTC
+    pub time: String,
+    pub event: AuditEvent,
+    pub connection: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub protocol: String,
+    /// Seconds the tunnel was open, on `stop`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_received: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_sent: Option<u64>,
+}
+
+/// Writes audit records to a file per day
+pub struct AuditLog {
+    directory: PathBuf,
+    client_ip: ClientIpPrivacy,
+    /// Days a file is kept after its own
+    retention_days: i64,
+    /// Key of `hash` client addresses, new at every start
+    key: hmac::Key,
+    /// Day and file records are being written to
+    file: Mutex<Option<(NaiveDate, File)>>,
+    next_connection: AtomicU64,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("directory", &self.directory)
+            .field("client_ip", &self.client_ip)
+            .field("retention_days", &self.retention_days)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        std::fs::create_dir_all(&config.directory)?;
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &ring::rand::SystemRandom::new())
+            .map_err(|_| io::Error::other("failed to generate audit log key"))?;
+        let log = Self {
+            directory: config.directory.clone(),
+            client_ip: config.client_ip,
+            retention_days: (config.retention.as_secs() / 86400).max(1) as i64,
+            key,
+            file: Mutex::new(None),
+            next_connection: AtomicU64::new(1),
+        };
+        log.prune(chrono::Utc::now().date_naive())?;
+        Ok(log)
+    }
+
+    /// How `client` appears in records
+    pub fn client_label(&self, client: IpAddr) -> Option<String> {
+        let client = match client {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(client),
+            v4 => v4,
+        };
+        match self.client_ip {
+            ClientIpPrivacy::Omit => None,
+            ClientIpPrivacy::Full => Some(client.to_string()),
+            ClientIpPrivacy::Truncate => Some(match client {
+                IpAddr::V4(v4) => format!("{}/24", Ipv4Addr::from(u32::from(v4) & 0xffff_ff00)),
+                IpAddr::V6(v6) => format!("{}/48", Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1))),
+            }),
+            ClientIpPrivacy::Hash => {
+                let tag = hmac::sign(&self.key, client.to_string().as_bytes());
+                Some(hex::encode(&tag.as_ref()[..8]))
+            }
+        }
+    }
+
+    /// Record the start of a tunnel from `client`; its stop is recorded
+    /// when the returned connection is dropped
+    pub fn connection(&self, client: IpAddr, protocol: &ProtocolId) -> AuditedConnection<'_> {
+        let connection = AuditedConnection {
+            log: self,
+            id: self.next_connection.fetch_add(1, Ordering::Relaxed),
+            client: self.client_label(client),
+            protocol: protocol.to_string(),
+            started: Instant::now(),
+            traffic: None,
+        };
+        connection.write(AuditEvent::Start);
+        connection
+    }
+
+    /// Append a record to today's file
+    pub fn write(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let today = chrono::Utc::now().date_naive();
+        let mut file = self.file.lock().unwrap();
+        if file.as_ref().is_none_or(|(day, _)| *day != today) {
+            let path = self.directory.join(format!("{}{}{}", FILE_PREFIX, today.format("%Y-%m-%d"), FILE_SUFFIX));
+            *file = Some((today, OpenOptions::new().create(true).append(true).open(path)?));
+            if let Err(e) = self.prune(today) {
+                log::warn!("Failed to delete expired audit logs: {}", e);
+            }
+        }
+        let (_, file) = file.as_mut().unwrap();
+        file.write_all(&line)
+    }
+
+    /// Delete the files of days past the retention
+    fn prune(&self, today: NaiveDate) -> io::Result<()> {
+        for entry in std::fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(day) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX))
+                .and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok())
+            else {
+                continue;
+            };
+            if (today - day).num_days() > self.retention_days {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A tunnel being audited; its stop record is written when dropped
+#[derive(Debug)]
+pub struct AuditedConnection<'a> {
+    log: &'a AuditLog,
+    id: u64,
+    client: Option<String>,
+    protocol: String,
+    started: Instant,
+    /// Bytes received and sent, if counted
+    traffic: Option<(u64, u64)>,
+}
+
+impl AuditedConnection<'_> {
+    /// Bytes carried toward and from the destination, for the stop record
+    pub fn set_traffic(&mut self, received: u64, sent: u64) {
+        self.traffic = Some((received, sent));
+    }
+
+    fn write(&self, event: AuditEvent) {
+        let stop = event == AuditEvent::Stop;
+        let record = AuditRecord {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            event,
+            connection: self.id,
+            client: self.client.clone(),
+            protocol: self.protocol.clone(),
+            duration: stop.then(|| self.started.elapsed().as_secs_f64()),
+            bytes_received: self.traffic.filter(|_| stop).map(|(received, _)| received),
+            bytes_sent: self.traffic.filter(|_| stop).map(|(_, sent)| sent),
+        };
+        if let Err(e) = self.log.write(&record) {
+            log::warn!("Failed to write audit record: {}", e);
+        }
+    }
+}
+
+impl Drop for AuditedConnection<'_> {
+    fn drop(&mut self) {
+        self.write(AuditEvent::Stop);
+    }
+}
+
+static LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// Start auditing the server's tunnels to `audit`
+pub fn install(audit: AuditLog) {
+    if LOG.set(audit).is_err() {
+        log::warn!("Audit log already installed");
+    }
+}
+
+/// Record the start of a tunnel, if auditing is enabled
+pub fn connection(client: IpAddr, protocol: &ProtocolId) -> Option<AuditedConnection<'static>> {
+    LOG.get().map(|audit| audit.connection(client, protocol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_audit_log() {
+        let directory = std::env::temp_dir().join(format!("nooshdaroo-audit-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let expired = directory.join("audit-2020-01-01.jsonl");
+        std::fs::write(&expired, "{}\n").unwrap();
+        let unrelated = directory.join("notes.txt");
+        std::fs::write(&unrelated, "keep").unwrap();
+
+        let config = AuditConfig {
+            directory: directory.clone(),
+            client_ip: ClientIpPrivacy::Truncate,
+            retention: Duration::from_secs(7 * 86400),
+        };
+        let log = AuditLog::open(&config).unwrap();
+        assert!(!expired.exists());
+        assert!(unrelated.exists());
+
+        {
+            let mut connection = log.connection("203.0.113.77".parse().unwrap(), &ProtocolId::from("https"));
+            connection.set_traffic(1200, 34000);
+        }
+        drop(log.connection("2001:db8:1:2::9".parse().unwrap(), &ProtocolId::from("dns")));
+
+        let today = chrono::Utc::now().date_naive().format("%Y-%m-%d");
+        let contents = std::fs::read_to_string(directory.join(format!("audit-{}.jsonl", today))).unwrap();
+        let records: Vec<AuditRecord> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!((records[0].event, records[1].event), (AuditEvent::Start, AuditEvent::Stop));
+        assert_eq!(records[0].connection, records[1].connection);
+        assert_eq!(records[0].client.as_deref(), Some("203.0.113.0/24"));
+        assert_eq!(records[0].bytes_received, None);
+        assert_eq!((records[1].bytes_received, records[1].bytes_sent), (Some(1200), Some(34000)));
+        assert!(records[1].duration.is_some());
+        assert_eq!(records[3].client.as_deref(), Some("2001:db8:1::/48"));
+        assert_eq!(records[3].bytes_sent, None);
+        assert!(!contents.contains("203.0.113.77"));
+
+        std::fs::remove_dir_all(&directory).ok();
+    }
+
+    #[test]
+    fn test_client_ip_privacy() {
+        let directory = std::env::temp_dir().join(format!("nooshdaroo-audit-{}", rand::random::<u32>()));
+        let client: IpAddr = "198.51.100.23".parse().unwrap();
+        let open = |client_ip| {
+            AuditLog::open(&AuditConfig { directory: directory.clone(), client_ip, ..Default::default() }).unwrap()
+        };
+
+        assert_eq!(open(ClientIpPrivacy::Omit).client_label(client), None);
+        assert_eq!(open(ClientIpPrivacy::Full).client_label(client).as_deref(), Some("198.51.100.23"));
+        let mapped: IpAddr = "::ffff:198.51.100.23".parse().unwrap();
+        assert_eq!(open(ClientIpPrivacy::Truncate).client_label(mapped).as_deref(), Some("198.51.100.0/24"));
+
+        // Stable for one log, unlinkable across restarts
+        let hashed = open(ClientIpPrivacy::Hash);
+        let label = hashed.client_label(client).unwrap();
+        assert_eq!(label.len(), 16);
+        assert_eq!(hashed.client_label(client).unwrap(), label);
+        assert_ne!(open(ClientIpPrivacy::Hash).client_label(client).unwrap(), label);
+
+        std::fs::remove_dir_all(&directory).ok();
+    }
+}
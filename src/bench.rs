@@ -0,0 +1,424 @@
+//! Throughput and latency benchmarking through the tunnel
+//!
+//! Provides the building blocks for `nooshdaroo bench`:
+//! - A tiny sink server speaking a request/response protocol: each exchange
+//!   is `[request_len: u32][response_len: u32][request bytes]`, answered with
+//!   `response_len` bytes
+//! - Traffic patterns (bulk transfer, request/response rounds) driven over any stream
+//! - A byte-counting TCP forwarder to measure on-the-wire protocol overhead
+//! - A minimal SOCKS5 CONNECT client to drive traffic through a local client
+
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Chunk size used when streaming bulk payloads
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Traffic pattern pushed through the tunnel
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum TrafficPattern {
+    /// Upload `bytes` and download `bytes` in a single exchange
+    Bulk { bytes: usize },
+    /// `rounds` exchanges of `request_size` up and `response_size` down
+    RequestResponse {
+        request_size: usize,
+        response_size: usize,
+        rounds: usize,
+    },
+}
+
+/// Parse a size such as `512`, `64K` or `10M`
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (num, mult) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1024),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    let size = num
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid size: {}", s))?
+        .checked_mul(mult)
+        .ok_or_else(|| format!("Size too large: {}", s))?;
+    // Sizes travel as 32-bit lengths in the sink protocol
+    if u32::try_from(size).is_err() {
+        return Err(format!("Size too large: {} (at most 4G - 1)", s));
+    }
+    Ok(size)
+}
+
+impl FromStr for TrafficPattern {
+    type Err = String;
+
+    /// Parse `bulk:<size>` or `rr:<request>:<response>:<rounds>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["bulk", size] => Ok(Self::Bulk { bytes: parse_size(size)? }),
+            ["rr", req, resp, rounds] => Ok(Self::RequestResponse {
+                request_size: parse_size(req)?,
+                response_size: parse_size(resp)?,
+                rounds: rounds.parse().map_err(|_| format!("Invalid round count: {}", rounds))?,
+            }),
+            _ => Err(format!(
+                "Invalid traffic pattern '{}': expected bulk:<size> or rr:<request>:<response>:<rounds>",
+                s
+            )),
+        }
+    }
+}
+
+/// Measurements from running a pattern once
+#[derive(Debug, Clone, Default)]
+pub struct PatternStats {
+    /// Application payload bytes moved in both directions
+    pub payload_bytes: u64,
+    /// Wall-clock time for the whole pattern
+    pub elapsed: Duration,
+    /// Per-exchange round-trip times
+    pub rtts: Vec<Duration>,
+}
+
+impl PatternStats {
+    /// Payload goodput in megabits per second
+    pub fn goodput_mbps(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.payload_bytes as f64 * 8.0 / self.elapsed.as_secs_f64() / 1_000_000.0
+    }
+
+    /// Mean round-trip time in milliseconds
+    pub fn rtt_mean_ms(&self) -> f64 {
+        if self.rtts.is_empty() {
+            return 0.0;
+        }
+        self.rtts.iter().sum::<Duration>().as_secs_f64() * 1000.0 / self.rtts.len() as f64
+    }
+
+    /// Round-trip time percentile (0.0-1.0) in milliseconds
+    pub fn rtt_percentile_ms(&self, p: f64) -> f64 {
+        if self.rtts.is_empty() {
+            return 0.0;
+        }
+        let mut sorted = self.rtts.clone();
+        sorted.sort();
+        let idx = ((sorted.len() - 1) as f64 * p.clamp(0.0, 1.0)).round() as usize;
+        sorted[idx].as_secs_f64() * 1000.0
+    }
+}
+
+/// Benchmark result for a single protocol
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchResult {
+    /// Protocol id
+    pub protocol: String,
+    /// Time to connect through the tunnel (SOCKS5 + handshake), in ms
+    pub setup_ms: f64,
+    /// Goodput through the tunnel (Mbit/s)
+    pub goodput_mbps: f64,
+    /// Goodput of a direct connection (Mbit/s)
+    pub baseline_goodput_mbps: f64,
+    /// Mean round-trip time through the tunnel (ms)
+    pub rtt_mean_ms: f64,
+    /// 99th percentile round-trip time through the tunnel (ms)
+    pub rtt_p99_ms: f64,
+    /// Latency added by the tunnel over a direct connection (ms)
+    pub added_latency_ms: f64,
+    /// Application payload bytes
+    pub payload_bytes: u64,
+    /// Bytes observed on the wire between client and server
+    pub wire_bytes: u64,
+    /// Wire bytes over payload bytes, as a percentage above 100%
+    pub overhead_pct: f64,
+    /// Error, if the run failed
+    pub error: Option<String>,
+}
+
+impl BenchResult {
+    /// Combine tunnel and baseline measurements
+    pub fn new(protocol: &str, setup: Duration, tunnel: &PatternStats, baseline: &PatternStats, wire_bytes: u64) -> Self {
+        let overhead_pct = if tunnel.payload_bytes > 0 {
+            (wire_bytes as f64 / tunnel.payload_bytes as f64 - 1.0) * 100.0
+        } else {
+            0.0
+        };
+
+        Self {
+            protocol: protocol.to_string(),
+            setup_ms: setup.as_secs_f64() * 1000.0,
+            goodput_mbps: tunnel.goodput_mbps(),
+            baseline_goodput_mbps: baseline.goodput_mbps(),
+            rtt_mean_ms: tunnel.rtt_mean_ms(),
+            rtt_p99_ms: tunnel.rtt_percentile_ms(0.99),
+            added_latency_ms: tunnel.rtt_mean_ms() - baseline.rtt_mean_ms(),
+            payload_bytes: tunnel.payload_bytes,
+            wire_bytes,
+            overhead_pct,
+            error: None,
+        }
+    }
+
+    /// Result for a protocol whose run failed
+    pub fn failed(protocol: &str, error: impl ToString) -> Self {
+        Self {
+            protocol: protocol.to_string(),
+            setup_ms: 0.0,
+            goodput_mbps: 0.0,
+            baseline_goodput_mbps: 0.0,
+            rtt_mean_ms: 0.0,
+            rtt_p99_ms: 0.0,
+            added_latency_ms: 0.0,
+            payload_bytes: 0,
+            wire_bytes: 0,
+            overhead_pct: 0.0,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// Serve the benchmark sink protocol on `listener` until it fails
+pub async fn run_sink_server(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_sink_connection(stream).await {
+                log::debug!("Bench sink connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve_sink_connection(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let zeros = vec![0u8; CHUNK_SIZE];
+
+    loop {
+        let mut header = [0u8; 8];
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let request_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let response_len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+        let mut remaining = request_len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            stream.read_exact(&mut buf[..n]).await?;
+            remaining -= n;
+        }
+
+        let mut remaining = response_len;
+        while remaining > 0 {
+            let n = remaining.min(zeros.len());
+            stream.write_all(&zeros[..n]).await?;
+            remaining -= n;
+        }
+        stream.flush().await?;
+    }
+}
+
+/// Perform one sink exchange, returning its round-trip time
+pub async fn sink_exchange<S>(stream: &mut S, request_len: usize, response_len: usize) -> io::Result<Duration>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let length = |len: usize| {
+        u32::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Exchange of {} bytes too large", len)))
+    };
+    let (request_header, response_header) = (length(request_len)?, length(response_len)?);
+    let start = Instant::now();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let send = async {
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&request_header.to_be_bytes());
+        header[4..].copy_from_slice(&response_header.to_be_bytes());
+        writer.write_all(&header).await?;
+
+        let chunk = vec![0xa5u8; CHUNK_SIZE.min(request_len.max(1))];
+        let mut remaining = request_len;
+        while remaining > 0 {
+            let n = remaining.min(chunk.len());
+            writer.write_all(&chunk[..n]).await?;
+            remaining -= n;
+        }
+        writer.flush().await
+    };
+
+    let receive = async {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut remaining = response_len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            reader.read_exact(&mut buf[..n]).await?;
+            remaining -= n;
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    tokio::try_join!(send, receive)?;
+    Ok(start.elapsed())
+}
+
+/// Drive `pattern` over a stream connected to a sink server
+pub async fn run_pattern<S>(stream: &mut S, pattern: &TrafficPattern) -> io::Result<PatternStats>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    let mut stats = PatternStats::default();
+
+    match *pattern {
+        TrafficPattern::Bulk { bytes } => {
+            stats.rtts.push(sink_exchange(stream, bytes, bytes).await?);
+            stats.payload_bytes = 2 * bytes as u64;
+        }
+        TrafficPattern::RequestResponse { request_size, response_size, rounds } => {
+            for _ in 0..rounds {
+                stats.rtts.push(sink_exchange(stream, request_size, response_size).await?);
+            }
+            stats.payload_bytes = ((request_size + response_size) * rounds) as u64;
+        }
+    }
+
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Byte counters for a forwarded connection
+#[derive(Debug, Default)]
+pub struct ByteCounter {
+    /// Bytes from the connecting side towards the upstream
+    pub upstream: AtomicU64,
+    /// Bytes from the upstream back to the connecting side
+    pub downstream: AtomicU64,
+}
+
+impl ByteCounter {
+    /// Total bytes in both directions
+    pub fn total(&self) -> u64 {
+        self.upstream.load(Ordering::Relaxed) + self.downstream.load(Ordering::Relaxed)
+    }
+
+    /// Reset both counters to zero
+    pub fn reset(&self) {
+        self.upstream.store(0, Ordering::Relaxed);
+        self.downstream.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Forward every connection accepted on `listener` to `upstream`, counting bytes
+pub async fn run_counting_relay(listener: TcpListener, upstream: SocketAddr, counter: Arc<ByteCounter>) -> io::Result<()> {
+    loop {
+        let (inbound, _) = listener.accept().await?;
+        let counter = counter.clone();
+        tokio::spawn(async move {
+            let outbound = match TcpStream::connect(upstream).await {
+                Ok(s) => s,
+                Err(e) => {
+                    log::debug!("Bench relay failed to reach {}: {}", upstream, e);
+                    return;
+                }
+            };
+            let _ = inbound.set_nodelay(true);
+            let _ = outbound.set_nodelay(true);
+
+            let (mut in_read, mut in_write) = inbound.into_split();
+            let (mut out_read, mut out_write) = outbound.into_split();
+
+            let up = copy_counting(&mut in_read, &mut out_write, &counter.upstream);
+            let down = copy_counting(&mut out_read, &mut in_write, &counter.downstream);
+            let _ = tokio::join!(up, down);
+        });
+    }
+}
+
+async fn copy_counting<R, W>(reader: &mut R, writer: &mut W, counter: &AtomicU64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        writer.write_all(&buf[..n]).await?;
+    }
+}
+
+/// Open a connection to `host:port` through a SOCKS5 proxy (no authentication)
+pub async fn socks5_connect(proxy: SocketAddr, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.set_nodelay(true)?;
+    crate::socks5::client_connect(&mut stream, host, port, None).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_parsing() {
+        assert_eq!("bulk:10M".parse::<TrafficPattern>().unwrap(), TrafficPattern::Bulk { bytes: 10 * 1024 * 1024 });
+        assert_eq!(
+            "rr:256:4K:100".parse::<TrafficPattern>().unwrap(),
+            TrafficPattern::RequestResponse { request_size: 256, response_size: 4096, rounds: 100 }
+        );
+        assert!("stream:5".parse::<TrafficPattern>().is_err());
+        assert!("bulk:abc".parse::<TrafficPattern>().is_err());
+        assert!(parse_size("4G").is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX / 2)).is_err());
+        assert_eq!(parse_size("4194303K"), Ok(4194303 * 1024));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let stats = PatternStats {
+            payload_bytes: 1_000_000,
+            elapsed: Duration::from_secs(1),
+            rtts: (1..=100).map(Duration::from_millis).collect(),
+        };
+        assert!((stats.goodput_mbps() - 8.0).abs() < 1e-9);
+        assert!((stats.rtt_mean_ms() - 50.5).abs() < 1e-6);
+        assert!((stats.rtt_percentile_ms(0.99) - 99.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_sink_through_counting_relay() {
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink_addr = sink.local_addr().unwrap();
+        tokio::spawn(run_sink_server(sink));
+
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let counter = Arc::new(ByteCounter::default());
+        tokio::spawn(run_counting_relay(relay, sink_addr, counter.clone()));
+
+        let mut stream = TcpStream::connect(relay_addr).await.unwrap();
+        let pattern = TrafficPattern::RequestResponse { request_size: 100, response_size: 1000, rounds: 5 };
+        let stats = run_pattern(&mut stream, &pattern).await.unwrap();
+
+        assert_eq!(stats.rtts.len(), 5);
+        assert_eq!(stats.payload_bytes, 5500);
+        // Payload plus an 8-byte header per exchange
+        assert_eq!(counter.total(), 5500 + 5 * 8);
+    }
+}
@@ -0,0 +1,8 @@
+//! Swift and Kotlin binding generator for the mobile API
+//!
+//! Run it on the built library (see `src/mobile_api.rs`):
+//! `cargo run --features uniffi --bin uniffi-bindgen -- generate --library <lib> --language kotlin --out-dir <dir>`
+
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
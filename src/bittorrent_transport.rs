@@ -0,0 +1,275 @@
+//! Tunnel stream carried as a BitTorrent peer connection
+//!
+//! Where peer-to-peer traffic dominates, a long-lived connection moving
+//! data both ways in 16 KiB blocks is what most flows look like. This
+//! transport speaks the peer wire protocol (BEP 3): the 68-byte handshake
+//! with an info hash and client-style peer IDs, a `bitfield` sized like a
+//! real torrent's, `interested`/`unchoke`, and then tunnel data in `piece`
+//! messages walking through the torrent's pieces block by block.
+//!
+//! ```toml
+//! [socks]
+//! transport = "bittorrent"
+//!
+//! [server]
+//! transport = "bittorrent"
+//! ```
+//!
+//! Every block is full-sized, `length (2, BE) | data | random fill`, as
+//! blocks are on the wire, so small writes cost a whole block. Only TCP
+//! peer connections are emulated; tunnels that need UDP use `kcp`.
+
+use rand::{Rng, RngCore};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// Buffer between the tunnel and the peer wire tasks
+const BUFFER_SIZE: usize = 64 * 1024;
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Reserved bits of libtorrent-based clients: extension protocol, DHT and fast extension
+const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+
+const HANDSHAKE_SIZE: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Block size every client requests
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Piece length of the emulated torrent
+const PIECE_LENGTH: u32 = 256 * 1024;
+
+/// Number of pieces of the emulated torrent
+const PIECE_COUNT: std::ops::Range<u32> = 800..4000;
+
+/// Largest message accepted
+const MAX_MESSAGE: usize = BLOCK_SIZE + 64;
+
+const MSG_UNCHOKE: u8 = 1;
+const MSG_INTERESTED: u8 = 2;
+const MSG_BITFIELD: u8 = 5;
+const MSG_PIECE: u8 = 7;
+
+/// Peer ID prefixes (Azureus style) the client picks from
+const CLIENT_PREFIXES: &[&[u8; 8]] = &[b"-qB4630-", b"-TR4050-", b"-DE211s-", b"-lt20A0-"];
+
+/// Start the client side over `stream`; the returned stream carries the tunnel
+pub async fn connect<S>(mut stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut info_hash = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut info_hash);
+    stream.write_all(&handshake(&info_hash)).await?;
+    stream.flush().await?;
+    if read_handshake(&mut stream).await? != info_hash {
+        return Err(invalid("Peer answered for another torrent".to_string()));
+    }
+
+    // A partial download, and interest in the rest
+    let pieces = rand::thread_rng().gen_range(PIECE_COUNT);
+    stream.write_all(&bitfield(pieces, false)).await?;
+    stream.write_all(&message(MSG_INTERESTED, &[])).await?;
+    stream.flush().await?;
+    Ok(spawn_pumps(stream, pieces))
+}
+
+/// Start the server side over an accepted `stream`; the returned stream
+/// carries the tunnel
+pub async fn accept<S>(mut stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let info_hash = read_handshake(&mut stream).await?;
+    stream.write_all(&handshake(&info_hash)).await?;
+
+    // A seed, unchoking its new peer
+    let pieces = rand::thread_rng().gen_range(PIECE_COUNT);
+    stream.write_all(&bitfield(pieces, true)).await?;
+    stream.write_all(&message(MSG_UNCHOKE, &[])).await?;
+    stream.flush().await?;
+    Ok(spawn_pumps(stream, pieces))
+}
+
+fn spawn_pumps<S>(stream: S, pieces: u32) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+    let (mut net_read, mut net_write) = tokio::io::split(stream);
+
+    tokio::spawn(async move {
+        if let Err(e) = send_pieces(&mut app_read, &mut net_write, pieces).await {
+            log::debug!("BitTorrent upload ended: {}", e);
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = receive_pieces(&mut net_read, &mut app_write).await {
+            log::debug!("BitTorrent download ended: {}", e);
+        }
+        let _ = app_write.shutdown().await;
+    });
+    tunnel
+}
+
+/// Send tunnel data as `piece` messages, block after block from a random
+/// starting piece
+async fn send_pieces<R, W>(app: &mut R, net: &mut W, pieces: u32) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut index = rand::thread_rng().gen_range(0..pieces);
+    let mut begin = 0u32;
+    let mut buf = vec![0u8; BLOCK_SIZE - 2];
+    loop {
+        let n = app.read(&mut buf).await?;
+        if n == 0 {
+            return net.shutdown().await;
+        }
+
+        let mut payload = Vec::with_capacity(8 + BLOCK_SIZE);
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(&(n as u16).to_be_bytes());
+        payload.extend_from_slice(&buf[..n]);
+        let mut fill = vec![0u8; BLOCK_SIZE - 2 - n];
+        rand::thread_rng().fill_bytes(&mut fill);
+        payload.extend_from_slice(&fill);
+        net.write_all(&message(MSG_PIECE, &payload)).await?;
+        net.flush().await?;
+
+        begin += BLOCK_SIZE as u32;
+        if begin == PIECE_LENGTH {
+            begin = 0;
+            index = (index + 1) % pieces;
+        }
+    }
+}
+
+/// Write the data in received `piece` messages to `app`, skipping the rest
+async fn receive_pieces<R, W>(net: &mut R, app: &mut W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let length = match net.read_u32().await {
+            Ok(length) => length as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if length > MAX_MESSAGE {
+            return Err(invalid(format!("Peer message too large: {}", length)));
+        }
+        let mut body = vec![0u8; length];
+        net.read_exact(&mut body).await?;
+
+        // Keep-alives are empty; everything but pieces is control chatter
+        if body.first() != Some(&MSG_PIECE) {
+            continue;
+        }
+        let block = body.get(9..).unwrap_or_default();
+        let data = match block {
+            [high, low, rest @ ..] => rest.get(..u16::from_be_bytes([*high, *low]) as usize),
+            _ => None,
+        };
+        let data = data.ok_or_else(|| invalid("Truncated piece block".to_string()))?;
+        app.write_all(data).await?;
+    }
+}
+
+fn handshake(info_hash: &[u8; 20]) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut handshake = Vec::with_capacity(HANDSHAKE_SIZE);
+    handshake.push(PROTOCOL.len() as u8);
+    handshake.extend_from_slice(PROTOCOL);
+    handshake.extend_from_slice(&RESERVED);
+    handshake.extend_from_slice(info_hash);
+    handshake.extend_from_slice(CLIENT_PREFIXES[rng.gen_range(0..CLIENT_PREFIXES.len())]);
+    // Clients fill the rest of the peer ID with printable characters
+    const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    handshake.extend((0..12).map(|_| CHARSET[rng.gen_range(0..CHARSET.len())]));
+    handshake
+}
+
+/// Read the peer's handshake; returns its info hash
+async fn read_handshake<S>(stream: &mut S) -> io::Result<[u8; 20]>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut handshake = [0u8; HANDSHAKE_SIZE];
+    stream.read_exact(&mut handshake).await?;
+    if handshake[0] as usize != PROTOCOL.len() || &handshake[1..20] != PROTOCOL {
+        return Err(invalid("Not a BitTorrent handshake".to_string()));
+    }
+    let mut info_hash = [0u8; 20];
+    info_hash.copy_from_slice(&handshake[28..48]);
+    Ok(info_hash)
+}
+
+/// Bitfield of a torrent with `pieces` pieces, complete or partly downloaded
+fn bitfield(pieces: u32, complete: bool) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut bits = vec![0u8; pieces.div_ceil(8) as usize];
+    let have = if complete { pieces } else { rng.gen_range(0..pieces) };
+    for piece in 0..pieces {
+        if complete || rng.gen_range(0..pieces) < have {
+            bits[(piece / 8) as usize] |= 0x80 >> (piece % 8);
+        }
+    }
+    message(MSG_BITFIELD, &bits)
+}
+
+fn message(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.extend_from_slice(&(1 + payload.len() as u32).to_be_bytes());
+    message.push(id);
+    message.extend_from_slice(payload);
+    message
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peer_wire_round_trip() {
+        let (client_io, server_io) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(accept(server_io));
+        let mut client = connect(client_io).await.unwrap();
+        let mut server = server.await.unwrap().unwrap();
+
+        let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"seed").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"seed");
+    }
+
+    #[tokio::test]
+    async fn test_blocks_are_full_sized() {
+        let (mut app, mut tunnel) = tokio::io::duplex(BUFFER_SIZE);
+        let mut wire = Vec::new();
+        app.write_all(b"x").await.unwrap();
+        drop(app);
+        send_pieces(&mut tunnel, &mut wire, 100).await.unwrap();
+
+        assert_eq!(wire.len(), 4 + 1 + 8 + BLOCK_SIZE);
+        assert_eq!(wire[4], MSG_PIECE);
+        assert_eq!(&wire[9..13], &[0, 0, 0, 0][..]);
+        let mut data = Vec::new();
+        receive_pieces(&mut &wire[..], &mut data).await.unwrap();
+        assert_eq!(data, b"x");
+    }
+}
@@ -0,0 +1,317 @@
+//! Detection of censorship events
+//!
+//! Censors interfere in a few characteristic ways, each of which the client
+//! can notice from its own traffic:
+//!
+//! - a TCP reset arriving within [`RESET_WINDOW`] of the handshake's first
+//!   message (the ClientHello for TLS-like protocols), the signature of an
+//!   on-path DPI box matching the handshake
+//! - throughput that collapses from normal rates to a steady trickle of a
+//!   few kbps, as throttling leaves connections open but unusable
+//! - DNS answers pointing at addresses censors are known to inject
+//!
+//! Detectors report [`BlockingEvent`]s with [`record`]. Recent events are
+//! listed in the client's [`crate::ProtocolStats`], and [`subscribe`]
+//! delivers them as they happen; [`spawn_monitor`] feeds them to the
+//! shape-shift controller, raising suspicion of the protocol affected so
+//! adaptive strategies move away from it.
+
+use crate::counters::TrafficRegistry;
+use crate::protocol::ProtocolId;
+use crate::shapeshift::ShapeShiftController;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, RwLock};
+
+/// Resets this soon after the first handshake message count as injected
+pub const RESET_WINDOW: Duration = Duration::from_secs(2);
+
+/// Events kept for [`recent`]
+const MAX_EVENTS: usize = 64;
+
+/// How often relayed traffic is sampled for throughput collapse
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples a collapse must persist for
+const COLLAPSE_SAMPLES: usize = 10;
+
+/// Bytes per second a protocol must have reached before a collapse counts
+const HEALTHY_RATE: f64 = 64.0 * 1024.0;
+
+/// Bytes per second (16 kbps) below which throughput has collapsed
+const COLLAPSED_RATE: f64 = 2.0 * 1024.0;
+
+/// Addresses censors inject into DNS answers: the Great Firewall's
+/// well-known forged answers and Iran's 10.10.34.34-36 block pages
+const POISON_ADDRS: &[Ipv4Addr] = &[
+    Ipv4Addr::new(8, 7, 198, 45),
+    Ipv4Addr::new(37, 61, 54, 158),
+    Ipv4Addr::new(46, 82, 174, 68),
+    Ipv4Addr::new(59, 24, 3, 173),
+    Ipv4Addr::new(78, 16, 49, 15),
+    Ipv4Addr::new(93, 46, 8, 89),
+    Ipv4Addr::new(159, 106, 121, 75),
+    Ipv4Addr::new(203, 98, 7, 65),
+    Ipv4Addr::new(243, 185, 187, 39),
+    Ipv4Addr::new(253, 157, 14, 165),
+    Ipv4Addr::new(10, 10, 34, 34),
+    Ipv4Addr::new(10, 10, 34, 35),
+    Ipv4Addr::new(10, 10, 34, 36),
+];
+
+/// Kind of censorship behaviour observed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockingKind {
+    /// Connection reset right after the handshake's first message
+    HandshakeReset,
+    /// Throughput fell from normal rates to a trickle
+    ThroughputCollapse,
+    /// DNS answer pointing at a known injected address
+    DnsPoisoning,
+}
+
+impl std::fmt::Display for BlockingKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::HandshakeReset => "handshake reset",
+            Self::ThroughputCollapse => "throughput collapse",
+            Self::DnsPoisoning => "DNS poisoning",
+        })
+    }
+}
+
+/// One observed censorship event
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockingEvent {
+    pub kind: BlockingKind,
+    /// Protocol that was blocked, if the event concerns one
+    pub protocol: Option<ProtocolId>,
+    /// Server the blocked connection went to
+    pub server: Option<SocketAddr>,
+    /// What was seen
+    pub detail: String,
+    /// When it was seen
+    pub at: SystemTime,
+}
+
+impl BlockingEvent {
+    pub fn new(kind: BlockingKind, detail: impl Into<String>) -> Self {
+        Self { kind, protocol: None, server: None, detail: detail.into(), at: SystemTime::now() }
+    }
+
+    pub fn with_protocol(mut self, protocol: ProtocolId) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    pub fn with_server(mut self, server: SocketAddr) -> Self {
+        self.server = Some(server);
+        self
+    }
+}
+
+static RECENT: Mutex<VecDeque<BlockingEvent>> = Mutex::new(VecDeque::new());
+
+fn channel() -> &'static broadcast::Sender<BlockingEvent> {
+    static CHANNEL: OnceLock<broadcast::Sender<BlockingEvent>> = OnceLock::new();
+    CHANNEL.get_or_init(|| broadcast::channel(MAX_EVENTS).0)
+}
+
+/// Report an event to the log, [`recent`] and subscribers
+pub fn record(event: BlockingEvent) {
+    log::warn!(
+        "Possible censorship ({}){}{}: {}",
+        event.kind,
+        event.protocol.as_ref().map(|p| format!(" of {}", p)).unwrap_or_default(),
+        event.server.map(|s| format!(" to {}", s)).unwrap_or_default(),
+        event.detail
+    );
+    {
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() >= MAX_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+    }
+    // Nobody listening is fine
+    let _ = channel().send(event);
+}
+
+/// Most recent events, oldest first
+pub fn recent() -> Vec<BlockingEvent> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
+
+/// Events recorded from now on
+pub fn subscribe() -> broadcast::Receiver<BlockingEvent> {
+    channel().subscribe()
+}
+
+/// Whether a failed handshake looks like an injected reset: the connection
+/// was reset within [`RESET_WINDOW`] of starting it
+pub fn is_handshake_reset(error: &anyhow::Error, elapsed: Duration) -> bool {
+    elapsed <= RESET_WINDOW
+        && error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .any(|e| e.kind() == std::io::ErrorKind::ConnectionReset)
+}
+
+/// Whether `addr` is a known injected DNS answer
+pub fn is_poisoned(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => POISON_ADDRS.contains(&v4),
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().is_some_and(|v4| POISON_ADDRS.contains(&v4)),
+    }
+}
+
+/// Record an event if the addresses `host` resolved to include a known
+/// injected answer, returning whether they did
+pub fn check_resolved(host: &str, addrs: &[SocketAddr]) -> bool {
+    match addrs.iter().find(|addr| is_poisoned(addr.ip())) {
+        Some(addr) => {
+            record(BlockingEvent::new(
+                BlockingKind::DnsPoisoning,
+                format!("{} resolved to injected address {}", host, addr.ip()),
+            ));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Watches a running byte count for a collapse to a trickle
+#[derive(Debug, Default)]
+pub struct ThroughputMonitor {
+    /// Bytes per second over the last samples, newest last
+    rates: VecDeque<f64>,
+    last: Option<(Instant, u64)>,
+    /// Best rate over a full window since the last collapse
+    peak: f64,
+}
+
+impl ThroughputMonitor {
+    /// Feed the total byte count at `now`, returning the collapsed rate in
+    /// bytes per second when throughput has just collapsed
+    pub fn observe(&mut self, total: u64, now: Instant) -> Option<f64> {
+        let (then, before) = self.last.replace((now, total))?;
+        let elapsed = now.duration_since(then).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        if self.rates.len() == COLLAPSE_SAMPLES {
+            self.rates.pop_front();
+        }
+        self.rates.push_back(total.saturating_sub(before) as f64 / elapsed);
+        if self.rates.len() < COLLAPSE_SAMPLES {
+            return None;
+        }
+
+        let rate = self.rates.iter().sum::<f64>() / self.rates.len() as f64;
+        // Idle connections stop completely; throttled ones keep trickling
+        let trickling = self.rates.iter().all(|&r| r > 0.0 && r < COLLAPSED_RATE);
+        if trickling && self.peak >= HEALTHY_RATE {
+            self.peak = rate;
+            return Some(rate);
+        }
+        self.peak = self.peak.max(rate);
+        None
+    }
+}
+
+/// Watch relayed traffic for throughput collapse and feed every event to
+/// `controller`
+pub fn spawn_monitor(controller: Arc<RwLock<ShapeShiftController>>) {
+    tokio::spawn(async move {
+        let traffic: Arc<TrafficRegistry> = controller.read().await.traffic().clone();
+        let mut monitors: HashMap<ProtocolId, ThroughputMonitor> = HashMap::new();
+        let mut events = subscribe();
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let now = Instant::now();
+                    for (protocol, snapshot) in traffic.by_protocol() {
+                        let monitor = monitors.entry(protocol.clone()).or_default();
+                        if let Some(rate) = monitor.observe(snapshot.bytes_received, now) {
+                            record(
+                                BlockingEvent::new(
+                                    BlockingKind::ThroughputCollapse,
+                                    format!("throughput fell to {:.1} kbps", rate * 8.0 / 1000.0),
+                                )
+                                .with_protocol(protocol),
+                            );
+                        }
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(event) => controller.write().await.record_blocking(&event),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return,
+                },
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput_collapse() {
+        let mut monitor = ThroughputMonitor::default();
+        let start = Instant::now();
+        let mut total = 0;
+        let at = |second: u64, total: u64, monitor: &mut ThroughputMonitor| {
+            monitor.observe(total, start + Duration::from_secs(second))
+        };
+
+        // Fast, then idle: not a collapse
+        let mut second = 0;
+        for _ in 0..=COLLAPSE_SAMPLES {
+            total += 500_000;
+            assert_eq!(at(second, total, &mut monitor), None);
+            second += 1;
+        }
+        for _ in 0..COLLAPSE_SAMPLES {
+            assert_eq!(at(second, total, &mut monitor), None);
+            second += 1;
+        }
+
+        // Fast, then a steady trickle: reported once
+        for _ in 0..COLLAPSE_SAMPLES {
+            total += 500_000;
+            at(second, total, &mut monitor);
+            second += 1;
+        }
+        let mut collapses = Vec::new();
+        for _ in 0..2 * COLLAPSE_SAMPLES {
+            total += 1_000;
+            collapses.extend(at(second, total, &mut monitor));
+            second += 1;
+        }
+        assert_eq!(collapses, vec![1_000.0]);
+    }
+
+    #[test]
+    fn test_detectors() {
+        let reset = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).context("handshake");
+        assert!(is_handshake_reset(&reset, Duration::from_millis(300)));
+        assert!(!is_handshake_reset(&reset, Duration::from_secs(30)));
+        assert!(!is_handshake_reset(&anyhow::anyhow!("bad key"), Duration::from_millis(300)));
+
+        assert!(is_poisoned("10.10.34.35".parse().unwrap()));
+        assert!(is_poisoned("::ffff:159.106.121.75".parse().unwrap()));
+        assert!(!is_poisoned("1.1.1.1".parse().unwrap()));
+
+        let mut events = subscribe();
+        assert!(check_resolved("blocked.example", &["10.10.34.34:443".parse().unwrap()]));
+        assert!(!check_resolved("fine.example", &["192.0.2.1:443".parse().unwrap()]));
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, BlockingKind::DnsPoisoning);
+        assert!(recent().contains(&event));
+    }
+}
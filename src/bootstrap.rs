@@ -0,0 +1,603 @@
+//! Out-of-band server discovery
+//!
+//! Clients in heavily filtered networks cannot rely on a hardcoded server
+//! address staying reachable. Instead they can fetch a small, signed
+//! bootstrap document listing current servers and their keys from channels
+//! that are expensive to block:
+//!
+//! - `https://` URLs on allow-listed CDNs
+//! - `dns:<domain>`: TXT records under a domain the operator controls
+//! - `file:<path>`: a document passed along by other means
+//!
+//! Every channel carries the same envelope, `<signature>.<payload>`: the
+//! base64 JSON [`BootstrapDocument`] and a base64 Ed25519 signature over
+//! those base64 bytes, made with a key listed in `[bootstrap]
+//! trusted_keys`. The channel itself is never trusted, so a document passes
+//! through mirrors, resolvers and messengers unchanged. In DNS the envelope
+//! is split into TXT strings of up to 255 bytes, which the client joins.
+//! `nooshdaroo sign-bootstrap` produces envelopes.
+
+use crate::config::{BootstrapConfig, UpstreamServerConfig};
+use crate::psf::signature::{PsfSigner, PsfVerifier};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Largest HTTPS response accepted
+const MAX_DOCUMENT_SIZE: usize = 256 * 1024;
+
+/// EDNS0 UDP payload size advertised to the resolver
+const EDNS_UDP_SIZE: u16 = 4096;
+
+/// DNS record type TXT
+const TYPE_TXT: u16 = 16;
+
+/// Servers published by an operator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BootstrapDocument {
+    /// Unix seconds when the document was signed
+    pub issued_at: u64,
+    /// Unix seconds after which the document must not be used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    pub servers: Vec<UpstreamServerConfig>,
+}
+
+impl BootstrapDocument {
+    /// Sign the document, returning its envelope
+    pub fn seal(&self, signer: &PsfSigner) -> Result<String, String> {
+        let json = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        Ok(seal_json(&json, signer))
+    }
+
+    /// Verify an envelope and decode the document if it is current at `now`
+    pub fn open(envelope: &str, verifier: &PsfVerifier, now: SystemTime) -> Result<Self, String> {
+        let json = open_json(envelope, verifier)?;
+        let document: Self =
+            serde_json::from_slice(&json).map_err(|e| format!("Invalid bootstrap document: {}", e))?;
+
+        let now = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if document.expires_at.is_some_and(|expires| expires <= now) {
+            return Err("Bootstrap document has expired".to_string());
+        }
+        if document.servers.is_empty() {
+            return Err("Bootstrap document lists no servers".to_string());
+        }
+        Ok(document)
+    }
+}
+
+/// Envelope carrying `json`, signed by `signer`
+pub(crate) fn seal_json(json: &[u8], signer: &PsfSigner) -> String {
+    let payload = BASE64.encode(json);
+    format!("{}.{}", signer.sign(payload.as_bytes()), payload)
+}
+
+/// JSON carried by an envelope, if a trusted key signed it
+pub(crate) fn open_json(envelope: &str, verifier: &PsfVerifier) -> Result<Vec<u8>, String> {
+    let (signature, payload) = envelope
+        .trim()
+        .split_once('.')
+        .ok_or("Envelope is not <signature>.<payload>")?;
+    let signature = BASE64
+        .decode(signature)
+        .map_err(|e| format!("Invalid envelope signature: {}", e))?;
+    if !verifier.verify(payload.as_bytes(), &signature) {
+        return Err("Envelope is not signed by a trusted key".to_string());
+    }
+    BASE64
+        .decode(payload)
+        .map_err(|e| format!("Invalid envelope payload: {}", e))
+}
+
+/// Where a bootstrap document can be fetched from
+#[derive(Debug, Clone, PartialEq)]
+pub enum BootstrapSource {
+    Https(String),
+    DnsTxt(String),
+    File(PathBuf),
+}
+
+impl FromStr for BootstrapSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            https_host(s)?;
+            Ok(Self::Https(s.to_string()))
+        } else if let Some(domain) = s.strip_prefix("dns:") {
+            if domain.is_empty() || domain.split('.').any(|label| label.is_empty() || label.len() > 63) {
+                return Err(format!("Invalid bootstrap domain '{}'", domain));
+            }
+            Ok(Self::DnsTxt(domain.to_string()))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(Self::File(PathBuf::from(path)))
+        } else {
+            Err(format!("Unknown bootstrap source '{}' (expected https://, dns: or file:)", s))
+        }
+    }
+}
+
+impl std::fmt::Display for BootstrapSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Https(url) => f.write_str(url),
+            Self::DnsTxt(domain) => write!(f, "dns:{}", domain),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Parse the configured sources, rejecting HTTPS hosts that are not allowed
+pub fn parse_sources(config: &BootstrapConfig) -> Result<Vec<BootstrapSource>, String> {
+    config
+        .sources
+        .iter()
+        .map(|source| {
+            let source: BootstrapSource = source.parse()?;
+            if let BootstrapSource::Https(ref url) = source {
+                let host = https_host(url)?;
+                if !is_allowed_host(host, &config.allowed_hosts) {
+                    return Err(format!("Bootstrap host {} is not in bootstrap.allowed_hosts", host));
+                }
+            }
+            Ok(source)
+        })
+        .collect()
+}
+
+/// Whether `host` is one of `allowed` or a subdomain of one
+pub fn is_allowed_host(host: &str, allowed: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        host == allowed || host.strip_suffix(allowed.as_str()).is_some_and(|rest| rest.ends_with('.'))
+    })
+}
+
+/// Fetches bootstrap documents from the configured sources
+pub struct Bootstrapper {
+    sources: Vec<BootstrapSource>,
+    verifier: PsfVerifier,
+    resolver: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Bootstrapper {
+    pub fn new(config: &BootstrapConfig) -> Result<Self, String> {
+        Ok(Self {
+            sources: parse_sources(config)?,
+            verifier: PsfVerifier::new(&config.trusted_keys)?,
+            resolver: config.resolver,
+            timeout: config.timeout,
+        })
+    }
+
+    /// First valid document from the sources, tried in order
+    pub async fn fetch(&self) -> Result<BootstrapDocument, String> {
+        let mut errors = Vec::new();
+        for source in &self.sources {
+            let result = match tokio::time::timeout(self.timeout, self.fetch_envelopes(source)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {:?}", self.timeout)),
+            };
+            let envelopes = match result {
+                Ok(envelopes) => envelopes,
+                Err(e) => {
+                    log::warn!("Bootstrap source {} failed: {}", source, e);
+                    errors.push(format!("{}: {}", source, e));
+                    continue;
+                }
+            };
+            for envelope in envelopes {
+                match BootstrapDocument::open(&envelope, &self.verifier, SystemTime::now()) {
+                    Ok(document) => {
+                        log::info!("Bootstrapped {} servers from {}", document.servers.len(), source);
+                        return Ok(document);
+                    }
+                    Err(e) => {
+                        log::warn!("Ignoring bootstrap document from {}: {}", source, e);
+                        errors.push(format!("{}: {}", source, e));
+                    }
+                }
+            }
+        }
+        Err(format!("No bootstrap source yielded servers ({})", errors.join("; ")))
+    }
+
+    /// Candidate envelopes from one source
+    async fn fetch_envelopes(&self, source: &BootstrapSource) -> Result<Vec<String>, String> {
+        match source {
+            BootstrapSource::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .map(|envelope| vec![envelope])
+                .map_err(|e| e.to_string()),
+            BootstrapSource::Https(url) => {
+                let url = url.clone();
+                let timeout = self.timeout;
+                let body = tokio::task::spawn_blocking(move || https_get(&url, timeout, MAX_DOCUMENT_SIZE))
+                    .await
+                    .map_err(|e| e.to_string())??;
+                let envelope = String::from_utf8(body).map_err(|_| "Response is not text".to_string())?;
+                Ok(vec![envelope])
+            }
+            BootstrapSource::DnsTxt(domain) => {
+                let resolver = match self.resolver {
+                    Some(resolver) => resolver,
+                    None => system_resolver()?,
+                };
+                query_txt(domain, resolver, self.timeout).await
+            }
+        }
+    }
+}
+
+/// Host part of an `https://` URL
+pub(crate) fn https_host(url: &str) -> Result<&str, String> {
+    let rest = url.strip_prefix("https://").ok_or_else(|| format!("Not an https:// URL: {}", url))?;
+    let authority = rest.split('/').next().unwrap_or_default();
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => authority,
+    };
+    if host.is_empty() || host.contains('@') {
+        return Err(format!("Invalid bootstrap URL: {}", url));
+    }
+    Ok(host)
+}
+
+/// Port part of an `https://` URL
+pub(crate) fn https_port(url: &str) -> u16 {
+    let authority = url.trim_start_matches("https://").split('/').next().unwrap_or_default();
+    authority
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(443)
+}
+
+/// Fetch `url` with a plain HTTP/1.0 GET over TLS (blocking), reading at
+/// most `max_size` bytes
+pub(crate) fn https_get(url: &str, timeout: Duration, max_size: usize) -> Result<Vec<u8>, String> {
+    use std::net::ToSocketAddrs;
+
+    let host = https_host(url)?;
+    let addrs: Vec<_> = (host, https_port(url))
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .collect();
+    if crate::blocking::check_resolved(host, &addrs) {
+        return Err(format!("DNS answer for {} is poisoned", host));
+    }
+    https_get_from(url, &addrs, "*/*", timeout, max_size)
+}
+
+/// [`https_get`] from the first of `addrs` that accepts a connection,
+/// asking for `accept` content
+pub(crate) fn https_get_from(
+    url: &str,
+    addrs: &[SocketAddr],
+    accept: &str,
+    timeout: Duration,
+    max_size: usize,
+) -> Result<Vec<u8>, String> {
+    let host = https_host(url)?;
+    let rest = &url["https://".len()..];
+    let authority = rest.split('/').next().unwrap_or_default();
+    let path = &rest[authority.len()..];
+    let path = if path.is_empty() { "/" } else { path };
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().certs {
+        roots.add(cert).ok();
+    }
+    let tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+    let connection = rustls::ClientConnection::new(Arc::new(tls_config), server_name).map_err(|e| e.to_string())?;
+
+    let mut last_error = format!("No address for {}", host);
+    let socket = addrs
+        .iter()
+        .find_map(|addr| {
+            std::net::TcpStream::connect_timeout(addr, timeout)
+                .map_err(|e| last_error = format!("{}: {}", addr, e))
+                .ok()
+        })
+        .ok_or(last_error)?;
+    socket.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    socket.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    let mut stream = rustls::StreamOwned::new(connection, socket);
+
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: Mozilla/5.0\r\nAccept: {}\r\nConnection: close\r\n\r\n",
+        path, host, accept
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                response.extend_from_slice(&chunk[..n]);
+                if response.len() > max_size {
+                    return Err("Response too large".to_string());
+                }
+            }
+            // Many servers close without close_notify once the body is sent
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    http_body(&response).map(<[u8]>::to_vec)
+}
+
+/// Body of a complete HTTP/1.x response, if its status is 200
+fn http_body(response: &[u8]) -> Result<&[u8], String> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("Incomplete HTTP response")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(&response[end + 4..]),
+        _ => Err(format!("HTTP request failed: {}", status)),
+    }
+}
+
+/// First nameserver in /etc/resolv.conf
+fn system_resolver() -> Result<SocketAddr, String> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")
+        .map_err(|e| format!("No bootstrap.resolver set and /etc/resolv.conf unreadable: {}", e))?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse::<std::net::IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| "No bootstrap.resolver set and no nameserver in /etc/resolv.conf".to_string())
+}
+
+/// TXT records of `domain`, each with its strings joined
+async fn query_txt(domain: &str, resolver: SocketAddr, timeout: Duration) -> Result<Vec<String>, String> {
+    let id = rand::random::<u16>();
+    let query = build_query(id, domain, TYPE_TXT)?;
+
+    let bind: SocketAddr = if resolver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = tokio::net::UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+    socket.send_to(&query, resolver).await.map_err(|e| e.to_string())?;
+    let mut buf = vec![0u8; EDNS_UDP_SIZE as usize];
+    let response = loop {
+        let (n, from) = tokio::time::timeout(timeout, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| format!("No answer from resolver {}", resolver))?
+            .map_err(|e| e.to_string())?;
+        // Ignore stray datagrams
+        if from == resolver && n >= 2 && buf[..2] == id.to_be_bytes() {
+            break buf[..n].to_vec();
+        }
+    };
+
+    match parse_txt_response(&response) {
+        Err(TxtError::Truncated) => {
+            // Too large for UDP: ask again over TCP
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let mut stream = tokio::net::TcpStream::connect(resolver).await.map_err(|e| e.to_string())?;
+            let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&query);
+            stream.write_all(&framed).await.map_err(|e| e.to_string())?;
+            let len = stream.read_u16().await.map_err(|e| e.to_string())?;
+            let mut response = vec![0u8; len as usize];
+            stream.read_exact(&mut response).await.map_err(|e| e.to_string())?;
+            parse_txt_response(&response).map_err(|e| e.to_string())
+        }
+        result => result.map_err(|e| e.to_string()),
+    }
+}
+
+/// `qtype` query for `domain` with an EDNS0 OPT record
+pub(crate) fn build_query(id: u16, domain: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(64);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // RD
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]); // QD=1, AN=0, NS=0, AR=1
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("Invalid domain '{}'", domain));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0, 1]); // IN
+    // OPT: root name, type 41, class = UDP size, TTL 0, no options
+    query.push(0);
+    query.extend_from_slice(&41u16.to_be_bytes());
+    query.extend_from_slice(&EDNS_UDP_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(query)
+}
+
+#[derive(Debug)]
+enum TxtError {
+    Truncated,
+    Invalid(String),
+}
+
+impl std::fmt::Display for TxtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated => f.write_str("DNS response truncated"),
+            Self::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// TXT answers of a DNS response
+fn parse_txt_response(response: &[u8]) -> Result<Vec<String>, TxtError> {
+    let invalid = || TxtError::Invalid("Malformed DNS response".to_string());
+    if response.len() < 12 {
+        return Err(invalid());
+    }
+    if response[2] & 0x02 != 0 {
+        return Err(TxtError::Truncated);
+    }
+    let rcode = response[3] & 0x0f;
+    if rcode != 0 {
+        return Err(TxtError::Invalid(format!("DNS query failed (rcode {})", rcode)));
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(response, pos).ok_or_else(invalid)?;
+        let fixed = response.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        let rdata = response.get(pos..pos + rdlength).ok_or_else(invalid)?;
+        pos += rdlength;
+        if rtype != TYPE_TXT {
+            continue;
+        }
+        // Character-strings: length byte + data, joined
+        let mut text = Vec::with_capacity(rdata.len());
+        let mut i = 0;
+        while i < rdata.len() {
+            let len = rdata[i] as usize;
+            text.extend_from_slice(rdata.get(i + 1..i + 1 + len).ok_or_else(invalid)?);
+            i += 1 + len;
+        }
+        records.push(String::from_utf8_lossy(&text).into_owned());
+    }
+    if records.is_empty() {
+        return Err(TxtError::Invalid("No TXT records found".to_string()));
+    }
+    Ok(records)
+}
+
+/// Position after the (possibly compressed) name at `pos`
+pub(crate) fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // Compression pointer ends the name
+            l if l & 0xc0 == 0xc0 => return Some(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> BootstrapDocument {
+        BootstrapDocument {
+            issued_at: 1_700_000_000,
+            expires_at: None,
+            servers: vec![UpstreamServerConfig {
+                address: "192.0.2.1:443".to_string(),
+                public_key: Some("c2VydmVyLWtleQ==".to_string()),
+                protocols: vec!["https".to_string()],
+                weight: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_envelope() {
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        let verifier = PsfVerifier::new(&[signer.public_key_base64()]).unwrap();
+        let envelope = document().seal(&signer).unwrap();
+        assert_eq!(BootstrapDocument::open(&envelope, &verifier, SystemTime::now()).unwrap(), document());
+
+        // Tampered, foreign and expired documents are rejected
+        let (signature, payload) = envelope.split_once('.').unwrap();
+        let mut other = document();
+        other.servers[0].address = "198.51.100.66:443".to_string();
+        let forged = format!("{}.{}", signature, BASE64.encode(serde_json::to_vec(&other).unwrap()));
+        assert!(BootstrapDocument::open(&forged, &verifier, SystemTime::now()).is_err());
+        let stranger = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        assert!(BootstrapDocument::open(&document().seal(&stranger).unwrap(), &verifier, SystemTime::now()).is_err());
+        let expiring = BootstrapDocument { expires_at: Some(1_700_000_100), ..document() };
+        let sealed = expiring.seal(&signer).unwrap();
+        assert!(BootstrapDocument::open(&sealed, &verifier, UNIX_EPOCH + Duration::from_secs(1_700_000_050)).is_ok());
+        assert!(BootstrapDocument::open(&sealed, &verifier, SystemTime::now()).is_err());
+        assert!(BootstrapDocument::open(payload, &verifier, SystemTime::now()).is_err());
+
+        // Only allow-listed CDNs are fetched over HTTPS
+        let mut config = BootstrapConfig {
+            sources: vec![
+                "dns:_nd.example.org".to_string(),
+                "https://d111.cloudfront.net/bridges".to_string(),
+                "file:/tmp/bridges".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_sources(&config).unwrap(),
+            vec![
+                BootstrapSource::DnsTxt("_nd.example.org".to_string()),
+                BootstrapSource::Https("https://d111.cloudfront.net/bridges".to_string()),
+                BootstrapSource::File(PathBuf::from("/tmp/bridges")),
+            ]
+        );
+        config.sources.push("https://evilcloudfront.net/bridges".to_string());
+        assert!(parse_sources(&config).is_err());
+        assert!("ftp://example.org".parse::<BootstrapSource>().is_err());
+        assert_eq!(http_body(b"HTTP/1.1 200 OK\r\nServer: x\r\n\r\nbody").unwrap(), b"body");
+        assert!(http_body(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_txt_source() {
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        let envelope = document().seal(&signer).unwrap();
+
+        // A resolver answering with the envelope split into 255-byte strings
+        let resolver = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let resolver_addr = resolver.local_addr().unwrap();
+        let answer = envelope.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (n, from) = resolver.recv_from(&mut buf).await.unwrap();
+            let question_end = skip_name(&buf[..n], 12).unwrap() + 4;
+            let mut rdata = Vec::new();
+            for chunk in answer.as_bytes().chunks(255) {
+                rdata.push(chunk.len() as u8);
+                rdata.extend_from_slice(chunk);
+            }
+            let mut response = buf[..question_end].to_vec();
+            response[2] |= 0x80;
+            response[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 16, 0, 1, 0, 0, 0, 60]);
+            response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            response.extend_from_slice(&rdata);
+            resolver.send_to(&response, from).await.unwrap();
+        });
+
+        let config = BootstrapConfig {
+            sources: vec!["dns:_nd.example.org".to_string()],
+            trusted_keys: vec![signer.public_key_base64()],
+            resolver: Some(resolver_addr),
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let document = Bootstrapper::new(&config).unwrap().fetch().await.unwrap();
+        assert_eq!(document.servers[0].address, "192.0.2.1:443");
+    }
+}
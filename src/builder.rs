@@ -0,0 +1,176 @@
+//! Typed construction of [`NooshdarooClient`]s
+//!
+//! [`NooshdarooClient::builder`] assembles the configuration with typed
+//! setters instead of a hand-built [`NooshdarooConfig`]. The server address
+//! and its public key are required: `build` only exists once both are set,
+//! so forgetting one is a compile error rather than a failed connection.
+//!
+//! ```rust,no_run
+//! use nooshdaroo::{NooshdarooClient, StrategyType, TransportType};
+//!
+//! # fn example() -> Result<(), nooshdaroo::NooshdarooError> {
+//! let client = NooshdarooClient::builder()
+//!     .server("vpn.example.org:443")
+//!     .server_public_key("pHXN8bsAvSZDH4aF2vdFxtVmZcUd5Zl2cRlyBi7lXUE=")
+//!     .transport(TransportType::Tcp)
+//!     .strategy(StrategyType::default())
+//!     .shaping_profile("iran")?
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ```compile_fail
+//! // No server key: there is no `build` to call
+//! let client = nooshdaroo::NooshdarooClient::builder().server("vpn.example.org:443").build();
+//! ```
+
+use crate::config::{NooshdarooConfig, TrafficShapingConfig, TransportType};
+use crate::noise_transport::NoiseConfig;
+use crate::strategy::StrategyType;
+use crate::{NooshdarooClient, NooshdarooError};
+use std::path::PathBuf;
+
+/// Required setting not given yet
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Missing;
+
+/// Required setting given
+#[derive(Debug, Clone)]
+pub struct Set(String);
+
+/// Builder for a [`NooshdarooClient`], tracking in its type whether the
+/// server address (`S`) and the server's public key (`K`) are set
+#[derive(Debug, Clone)]
+pub struct NooshdarooClientBuilder<S = Missing, K = Missing> {
+    config: NooshdarooConfig,
+    server: S,
+    server_key: K,
+}
+
+impl NooshdarooClientBuilder {
+    pub fn new() -> Self {
+        Self { config: NooshdarooConfig::default(), server: Missing, server_key: Missing }
+    }
+}
+
+impl Default for NooshdarooClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, K> NooshdarooClientBuilder<S, K> {
+    /// Server to tunnel through: "host:port", or a relay chain entered at
+    /// its first hop
+    pub fn server(self, address: impl Into<String>) -> NooshdarooClientBuilder<Set, K> {
+        NooshdarooClientBuilder { config: self.config, server: Set(address.into()), server_key: self.server_key }
+    }
+
+    /// The server's static Noise public key (base64)
+    pub fn server_public_key(self, key: impl Into<String>) -> NooshdarooClientBuilder<S, Set> {
+        NooshdarooClientBuilder { config: self.config, server: self.server, server_key: Set(key.into()) }
+    }
+
+    /// The client's own static Noise private key (base64), for servers that
+    /// authenticate clients
+    pub fn local_private_key(mut self, key: impl Into<String>) -> Self {
+        self.noise().local_private_key = Some(key.into());
+        self
+    }
+
+    /// Transport carrying the tunnel to the server
+    pub fn transport(mut self, transport: TransportType) -> Self {
+        self.config.socks.transport = transport;
+        self
+    }
+
+    /// Protocol shape-shifting strategy
+    pub fn strategy(mut self, strategy: StrategyType) -> Self {
+        self.config.shapeshift.strategy = strategy;
+        self
+    }
+
+    /// Traffic shaping settings
+    pub fn traffic_shaping(mut self, shaping: TrafficShapingConfig) -> Self {
+        self.config.traffic_shaping = shaping;
+        self
+    }
+
+    /// Traffic shaping of a preset profile, built-in or a user profile (see
+    /// [`crate::profiles`])
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no profile has this name
+    pub fn shaping_profile(mut self, name: &str) -> Result<Self, NooshdarooError> {
+        let profile = crate::profiles::load_profile(name).map_err(|e| NooshdarooError::InvalidConfig(e.to_string()))?;
+        self.config.traffic_shaping = profile.traffic_shaping;
+        Ok(self)
+    }
+
+    /// Directory protocol definitions are loaded from
+    pub fn protocol_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.protocol_dir = dir.into();
+        self
+    }
+
+    /// Any other setting, applied to the configuration as built so far
+    pub fn configure(mut self, configure: impl FnOnce(&mut NooshdarooConfig)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    fn noise(&mut self) -> &mut NoiseConfig {
+        self.config.transport.get_or_insert_with(NoiseConfig::default)
+    }
+}
+
+impl NooshdarooClientBuilder<Set, Set> {
+    /// The configuration the client would be built with
+    pub fn into_config(mut self) -> NooshdarooConfig {
+        let key = std::mem::take(&mut self.server_key.0);
+        self.noise().remote_public_key = Some(key);
+        self.config.socks.server_address = Some(self.server.0);
+        self.config
+    }
+
+    /// Build the client
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration is invalid or the client cannot
+    /// be created (see [`NooshdarooClient::new`])
+    pub fn build(self) -> Result<NooshdarooClient, NooshdarooError> {
+        let config = self.into_config();
+        config.validate().map_err(NooshdarooError::InvalidConfig)?;
+        NooshdarooClient::new(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let config = NooshdarooClient::builder()
+            .transport(TransportType::Kcp)
+            .server_public_key("c2VydmVy")
+            .local_private_key("Y2xpZW50")
+            .server("vpn.example.org:443")
+            .shaping_profile("iran")
+            .unwrap()
+            .into_config();
+        assert_eq!(config.socks.server_address.as_deref(), Some("vpn.example.org:443"));
+        assert_eq!(config.socks.transport, TransportType::Kcp);
+        let noise = config.transport.unwrap();
+        assert_eq!(noise.remote_public_key.as_deref(), Some("c2VydmVy"));
+        assert_eq!(noise.local_private_key.as_deref(), Some("Y2xpZW50"));
+        assert_eq!(
+            toml::to_string(&config.traffic_shaping).unwrap(),
+            toml::to_string(&crate::profiles::load_profile("iran").unwrap().traffic_shaping).unwrap()
+        );
+        assert!(NooshdarooClient::builder().shaping_profile("no-such-profile").is_err());
+    }
+}
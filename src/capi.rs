@@ -0,0 +1,476 @@
+//! C API for embedding the client (feature "capi")
+//!
+//! A stable C ABI over [`NooshdarooClient`] for applications in Go, Swift,
+//! C++ and other languages with a C FFI. Build the library with
+//!
+//! ```text
+//! cargo rustc --release --lib --features capi --crate-type cdylib     # or staticlib
+//! ```
+//!
+//! which also generates `nooshdaroo.h` next to it in `target/release`.
+//!
+//! A client is created from a TOML configuration and owns its own runtime:
+//! `nooshdaroo_client_start` runs the SOCKS5 listener of `[socks]` in the
+//! background until `nooshdaroo_client_stop`. Statistics and events are
+//! handed over as JSON. Strings returned by the library belong to the
+//! caller and are released with `nooshdaroo_string_free`; functions that
+//! fail return `NULL` or a negative status and leave a message for
+//! `nooshdaroo_last_error`. A panic inside the library is caught at the
+//! boundary and reported the same way, as `NooshdarooStatus_Failed` or
+//! `NULL`.
+
+use crate::config::NooshdarooConfig;
+use crate::NooshdarooClient;
+use std::cell::RefCell;
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+/// Events queued for a slow callback before newer ones are dropped
+const EVENT_QUEUE: usize = 256;
+
+/// Result of a C API call
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NooshdarooStatus {
+    Ok = 0,
+    /// A pointer or string argument was NULL or not UTF-8
+    InvalidArgument = -1,
+    /// The configuration cannot be used
+    InvalidConfig = -2,
+    /// The listener is already running
+    AlreadyRunning = -3,
+    /// The listener is not running
+    NotRunning = -4,
+    /// Any other failure; see `nooshdaroo_last_error`
+    Failed = -5,
+}
+
+/// Receives each client event as a JSON object with a "type" field; the
+/// string is only valid during the call. It runs on a thread of its own,
+/// outside the library's runtime, and may call any `nooshdaroo_*` function
+pub type NooshdarooEventCallback = Option<unsafe extern "C" fn(event_json: *const c_char, user_data: *mut c_void)>;
+
+/// A client with its runtime, created by `nooshdaroo_client_new`
+pub struct NooshdarooHandle {
+    runtime: Runtime,
+    client: NooshdarooClient,
+    listener: Option<JoinHandle<()>>,
+    events: Option<EventDispatch>,
+}
+
+/// Delivery of events to a callback: a runtime task forwards them to a
+/// thread that makes the calls, so callbacks never run on the runtime
+struct EventDispatch {
+    forwarder: JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+    wake: mpsc::SyncSender<Option<CString>>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl EventDispatch {
+    /// Stop calling the callback; unless called from the callback itself,
+    /// wait for a call in progress to return
+    fn stop(self) {
+        self.forwarder.abort();
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.wake.try_send(None);
+        if self.thread.thread().id() != std::thread::current().id() {
+            let _ = self.thread.join();
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: impl Into<String>) {
+    let message = message.into();
+    log::debug!("C API error: {}", message);
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
+
+fn fail(status: NooshdarooStatus, message: impl Into<String>) -> NooshdarooStatus {
+    set_error(message);
+    status
+}
+
+/// Run `f`, turning a panic into `on_panic` so it never unwinds into C
+fn guard<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        set_error("Internal error (panic) in the library");
+        on_panic
+    })
+}
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Interior NULs cannot occur in JSON or protocol names; drop them anyway
+    CString::new(s.replace('\0', "")).unwrap_or_default().into_raw()
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// The user data pointer handed back to the event callback; the caller
+/// promises it may be used from the library's threads
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Library version, a static string
+#[no_mangle]
+pub extern "C" fn nooshdaroo_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Message of the last failed call on this thread, or NULL
+///
+/// # Safety
+/// Free the returned string with `nooshdaroo_string_free`
+#[no_mangle]
+pub extern "C" fn nooshdaroo_last_error() -> *mut c_char {
+    guard(std::ptr::null_mut(), || {
+        match LAST_ERROR.with(|error| error.borrow().clone()) {
+            Some(message) => into_c_string(message),
+            None => std::ptr::null_mut(),
+        }
+    })
+}
+
+/// Free a string returned by the library
+///
+/// # Safety
+/// `s` must come from this library and not be freed twice
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_string_free(s: *mut c_char) {
+    guard((), || {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    })
+}
+
+/// Create a client from a TOML configuration, or NULL on failure
+///
+/// # Safety
+/// `config_toml` must be a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_new(config_toml: *const c_char) -> *mut NooshdarooHandle {
+    guard(std::ptr::null_mut(), || {
+        let Some(config_toml) = str_arg(config_toml) else {
+            set_error("config_toml is NULL or not UTF-8");
+            return std::ptr::null_mut();
+        };
+        let config: NooshdarooConfig = match toml::from_str(config_toml) {
+            Ok(config) => config,
+            Err(e) => {
+                set_error(format!("Invalid configuration: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+        if let Err(e) = config.validate() {
+            set_error(format!("Invalid configuration: {}", e));
+            return std::ptr::null_mut();
+        }
+        let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                set_error(format!("Cannot start runtime: {}", e));
+                return std::ptr::null_mut();
+            }
+        };
+        // Background tasks of the client belong to its runtime
+        let client = {
+            let _guard = runtime.enter();
+            NooshdarooClient::new(config)
+        };
+        match client {
+            Ok(client) => Box::into_raw(Box::new(NooshdarooHandle { runtime, client, listener: None, events: None })),
+            Err(e) => {
+                set_error(e.to_string());
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Stop and free a client
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new` and not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_free(handle: *mut NooshdarooHandle) {
+    guard((), || {
+        if !handle.is_null() {
+            let mut handle = Box::from_raw(handle);
+            if let Some(events) = handle.events.take() {
+                events.stop();
+            }
+            // Tasks still running are cancelled with the runtime
+            handle.runtime.shutdown_background();
+        }
+    })
+}
+
+/// Start the SOCKS5 listener of `[socks]`, tunneling to its server
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new`
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_start(handle: *mut NooshdarooHandle) -> NooshdarooStatus {
+    guard(NooshdarooStatus::Failed, || {
+        let Some(handle) = handle.as_mut() else {
+            return fail(NooshdarooStatus::InvalidArgument, "handle is NULL");
+        };
+        if handle.listener.as_ref().is_some_and(|listener| !listener.is_finished()) {
+            return fail(NooshdarooStatus::AlreadyRunning, "The client is already running");
+        }
+        let listener = match handle.runtime.block_on(handle.client.socks_listener()) {
+            Ok(listener) => listener,
+            Err(e @ crate::NooshdarooError::InvalidConfig(_)) => return fail(NooshdarooStatus::InvalidConfig, e.to_string()),
+            Err(e) => return fail(NooshdarooStatus::Failed, e.to_string()),
+        };
+        log::info!("Starting SOCKS5 listener on {}", handle.client.config().socks.listen_addr);
+        handle.listener = Some(handle.runtime.spawn(async move {
+            if let Err(e) = listener.listen().await {
+                log::error!("SOCKS5 listener failed: {}", e);
+            }
+        }));
+        NooshdarooStatus::Ok
+    })
+}
+
+/// Stop the SOCKS5 listener; tunnels already open are not interrupted
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new`
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_stop(handle: *mut NooshdarooHandle) -> NooshdarooStatus {
+    guard(NooshdarooStatus::Failed, || {
+        let Some(handle) = handle.as_mut() else {
+            return fail(NooshdarooStatus::InvalidArgument, "handle is NULL");
+        };
+        match handle.listener.take() {
+            Some(listener) => {
+                listener.abort();
+                NooshdarooStatus::Ok
+            }
+            None => fail(NooshdarooStatus::NotRunning, "The client is not running"),
+        }
+    })
+}
+
+/// Protocol new tunnels use, or NULL on failure
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new`; free the returned
+/// string with `nooshdaroo_string_free`
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_protocol(handle: *const NooshdarooHandle) -> *mut c_char {
+    guard(std::ptr::null_mut(), || {
+        let Some(handle) = handle.as_ref() else {
+            set_error("handle is NULL");
+            return std::ptr::null_mut();
+        };
+        into_c_string(handle.runtime.block_on(handle.client.current_protocol()).to_string())
+    })
+}
+
+/// Rotate to the next protocol of the configured strategy
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new`
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_rotate(handle: *const NooshdarooHandle) -> NooshdarooStatus {
+    guard(NooshdarooStatus::Failed, || {
+        let Some(handle) = handle.as_ref() else {
+            return fail(NooshdarooStatus::InvalidArgument, "handle is NULL");
+        };
+        match handle.runtime.block_on(handle.client.rotate()) {
+            Ok(()) => NooshdarooStatus::Ok,
+            Err(e) => fail(NooshdarooStatus::Failed, e.to_string()),
+        }
+    })
+}
+
+/// Statistics as a JSON object, or NULL on failure
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new`; free the returned
+/// string with `nooshdaroo_string_free`
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_stats(handle: *const NooshdarooHandle) -> *mut c_char {
+    guard(std::ptr::null_mut(), || {
+        let Some(handle) = handle.as_ref() else {
+            set_error("handle is NULL");
+            return std::ptr::null_mut();
+        };
+        let stats = handle.runtime.block_on(handle.client.stats());
+        into_c_string(stats.to_json().to_string())
+    })
+}
+
+/// Call `callback` with every client event from now on, replacing any
+/// earlier callback; a NULL callback stops the calls
+///
+/// `callback` runs on a thread of its own, one event at a time, and may
+/// call any `nooshdaroo_*` function, this one and `nooshdaroo_client_free`
+/// included. Once this function or `nooshdaroo_client_free` returns, the
+/// previous callback is not called again; when they are called from the
+/// callback itself, that holds once the callback returns. Events a slow
+/// callback has not taken are dropped past a queue of 256.
+///
+/// # Safety
+/// `handle` must come from `nooshdaroo_client_new`; `user_data` must stay
+/// valid, and be usable from other threads, until the callback is replaced
+/// or the client freed
+#[no_mangle]
+pub unsafe extern "C" fn nooshdaroo_client_set_event_callback(
+    handle: *mut NooshdarooHandle,
+    callback: NooshdarooEventCallback,
+    user_data: *mut c_void,
+) -> NooshdarooStatus {
+    guard(NooshdarooStatus::Failed, || {
+        let Some(handle) = handle.as_mut() else {
+            return fail(NooshdarooStatus::InvalidArgument, "handle is NULL");
+        };
+        if let Some(events) = handle.events.take() {
+            events.stop();
+        }
+        let Some(callback) = callback else {
+            return NooshdarooStatus::Ok;
+        };
+
+        // Callbacks run on their own thread: one calling back into the
+        // library would otherwise block on the runtime from inside it
+        let (queue, calls) = mpsc::sync_channel::<Option<CString>>(EVENT_QUEUE);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let user_data = UserData(user_data);
+        let thread = {
+            let stopped = stopped.clone();
+            std::thread::Builder::new().name("nooshdaroo-events".to_string()).spawn(move || {
+                let user_data = user_data;
+                while let Ok(Some(json)) = calls.recv() {
+                    if stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    callback(json.as_ptr(), user_data.0);
+                }
+            })
+        };
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => return fail(NooshdarooStatus::Failed, format!("Cannot start the event thread: {}", e)),
+        };
+
+        let mut events = handle.client.events();
+        let wake = queue.clone();
+        let forwarder = handle.runtime.spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let Ok(json) = CString::new(serde_json::to_string(&event).unwrap_or_default()) else {
+                            continue;
+                        };
+                        match queue.try_send(Some(json)) {
+                            Ok(()) => {}
+                            Err(mpsc::TrySendError::Full(_)) => log::warn!("Event callback fell behind, event dropped"),
+                            Err(mpsc::TrySendError::Disconnected(_)) => return,
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("Event callback fell behind, {} events dropped", missed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+        handle.events = Some(EventDispatch { forwarder, stopped, wake, thread });
+        NooshdarooStatus::Ok
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_lifecycle() {
+        unsafe {
+            let mut config = NooshdarooConfig::default();
+            config.shapeshift.strategy = crate::StrategyType::Fixed(crate::strategy::FixedStrategy::new("https".into()));
+            let config = CString::new(toml::to_string(&config).unwrap()).unwrap();
+            let handle = nooshdaroo_client_new(config.as_ptr());
+            assert!(!handle.is_null());
+
+            let protocol = nooshdaroo_client_protocol(handle);
+            assert_eq!(CStr::from_ptr(protocol).to_str().unwrap(), "https");
+            nooshdaroo_string_free(protocol);
+
+            let stats = nooshdaroo_client_stats(handle);
+            let json: serde_json::Value = serde_json::from_str(CStr::from_ptr(stats).to_str().unwrap()).unwrap();
+            assert_eq!(json["current_protocol"], "https");
+            nooshdaroo_string_free(stats);
+
+            // No [transport] key to reach a server with
+            assert_eq!(nooshdaroo_client_start(handle), NooshdarooStatus::InvalidConfig);
+            assert_eq!(nooshdaroo_client_stop(handle), NooshdarooStatus::NotRunning);
+            let error = nooshdaroo_last_error();
+            assert!(CStr::from_ptr(error).to_str().unwrap().contains("not running"));
+            nooshdaroo_string_free(error);
+            nooshdaroo_client_free(handle);
+
+            let invalid = CString::new("mode = 42").unwrap();
+            assert!(nooshdaroo_client_new(invalid.as_ptr()).is_null());
+            assert!(nooshdaroo_client_new(std::ptr::null()).is_null());
+        }
+    }
+
+    struct Probe {
+        handle: *mut NooshdarooHandle,
+        seen: std::sync::Mutex<mpsc::Sender<bool>>,
+    }
+
+    unsafe extern "C" fn call_back_in(_event: *const c_char, user_data: *mut c_void) {
+        let probe = &*(user_data as *const Probe);
+        let stats = nooshdaroo_client_stats(probe.handle);
+        let _ = probe.seen.lock().unwrap().send(!stats.is_null());
+        nooshdaroo_string_free(stats);
+    }
+
+    #[test]
+    fn test_event_callback_may_call_library() {
+        unsafe {
+            let config = CString::new(toml::to_string(&NooshdarooConfig::default()).unwrap()).unwrap();
+            let handle = nooshdaroo_client_new(config.as_ptr());
+            assert!(!handle.is_null());
+            let (seen, calls) = mpsc::channel();
+            let probe = Probe { handle, seen: std::sync::Mutex::new(seen) };
+            let status =
+                nooshdaroo_client_set_event_callback(handle, Some(call_back_in), &probe as *const Probe as *mut c_void);
+            assert_eq!(status, NooshdarooStatus::Ok);
+
+            let event = crate::events::ClientEvent::QuotaWarning { used: 1, limit: 2 };
+            (*handle).client.controller.blocking_read().events().emit(event.clone());
+            assert!(calls.recv_timeout(std::time::Duration::from_secs(5)).unwrap());
+
+            // Another client's events do not reach this callback
+            let other = nooshdaroo_client_new(config.as_ptr());
+            (*other).client.controller.blocking_read().events().emit(event);
+            assert!(calls.recv_timeout(std::time::Duration::from_millis(200)).is_err());
+            nooshdaroo_client_free(other);
+
+            assert_eq!(nooshdaroo_client_set_event_callback(handle, None, std::ptr::null_mut()), NooshdarooStatus::Ok);
+            nooshdaroo_client_free(handle);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Debug capture of wrapped tunnel traffic
+//!
+//! Records the exact bytes exchanged with the server (after protocol wrapping
+//! and encryption) into a pcap file, synthesizing TCP segments so the stream
+//! can be opened in Wireshark without capture privileges.
+//!
+//! When plaintext lengths are requested, each relayed chunk is annotated with
+//! a UDP packet between the same hosts on [`ANNOTATION_PORT`] carrying
+//! `nooshdaroo out|in plaintext=<len>`: before the wire bytes of outbound
+//! chunks, after the wire bytes inbound chunks were decoded from.
+
+use crate::pcap::{build_tcp_packet, build_udp_packet, PcapWriter};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// UDP port used for plaintext length annotations
+pub const ANNOTATION_PORT: u16 = 9;
+
+/// Largest payload per synthesized TCP segment
+const SEGMENT_SIZE: usize = 1460;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Direction of captured traffic, relative to the local side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Local side to server
+    Outbound,
+    /// Server to local side
+    Inbound,
+}
+
+/// Shared pcap file that tunnel connections are recorded into
+#[derive(Clone)]
+pub struct PacketCapture {
+    writer: Arc<Mutex<PcapWriter<BufWriter<File>>>>,
+    plaintext_lengths: bool,
+}
+
+impl PacketCapture {
+    /// Create (or truncate) the capture file at `path`
+    pub fn create(path: &Path, plaintext_lengths: bool) -> io::Result<Self> {
+        let writer = PcapWriter::new(BufWriter::new(File::create(path)?))?;
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            plaintext_lengths,
+        })
+    }
+
+    /// Start recording a connection between `local` and `remote`
+    pub fn flow(&self, local: SocketAddr, remote: SocketAddr) -> CaptureFlow {
+        let mut state = FlowState {
+            capture: self.clone(),
+            local,
+            remote,
+            seq_out: rand::random(),
+            seq_in: rand::random(),
+        };
+        state.handshake();
+        CaptureFlow {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn write(&self, packet: Option<Vec<u8>>) {
+        let Some(packet) = packet else {
+            return;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.write_packet(timestamp, &packet).and_then(|_| writer.flush()) {
+            log::warn!("Failed to write capture packet: {}", e);
+        }
+    }
+}
+
+struct FlowState {
+    capture: PacketCapture,
+    local: SocketAddr,
+    remote: SocketAddr,
+    seq_out: u32,
+    seq_in: u32,
+}
+
+impl FlowState {
+    fn endpoints(&self, direction: Direction) -> (SocketAddr, SocketAddr, u32, u32) {
+        match direction {
+            Direction::Outbound => (self.local, self.remote, self.seq_out, self.seq_in),
+            Direction::Inbound => (self.remote, self.local, self.seq_in, self.seq_out),
+        }
+    }
+
+    fn advance(&mut self, direction: Direction, len: u32) {
+        match direction {
+            Direction::Outbound => self.seq_out = self.seq_out.wrapping_add(len),
+            Direction::Inbound => self.seq_in = self.seq_in.wrapping_add(len),
+        }
+    }
+
+    fn handshake(&mut self) {
+        let (local, remote) = (self.local, self.remote);
+        self.capture.write(build_tcp_packet(local, remote, self.seq_out, 0, TCP_SYN, &[]));
+        self.seq_out = self.seq_out.wrapping_add(1);
+        self.capture.write(build_tcp_packet(remote, local, self.seq_in, self.seq_out, TCP_SYN | TCP_ACK, &[]));
+        self.seq_in = self.seq_in.wrapping_add(1);
+        self.capture.write(build_tcp_packet(local, remote, self.seq_out, self.seq_in, TCP_ACK, &[]));
+    }
+
+    fn data(&mut self, direction: Direction, bytes: &[u8]) {
+        for segment in bytes.chunks(SEGMENT_SIZE) {
+            let (src, dst, seq, ack) = self.endpoints(direction);
+            self.capture.write(build_tcp_packet(src, dst, seq, ack, TCP_PSH | TCP_ACK, segment));
+            self.advance(direction, segment.len() as u32);
+        }
+    }
+
+    fn close(&mut self, direction: Direction) {
+        let (src, dst, seq, ack) = self.endpoints(direction);
+        self.capture.write(build_tcp_packet(src, dst, seq, ack, TCP_FIN | TCP_ACK, &[]));
+        self.advance(direction, 1);
+    }
+}
+
+/// Recording handle for one connection
+#[derive(Clone)]
+pub struct CaptureFlow {
+    state: Arc<Mutex<FlowState>>,
+}
+
+impl CaptureFlow {
+    /// Record wire bytes sent in `direction`
+    pub fn record(&self, direction: Direction, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.state.lock().unwrap().data(direction, bytes);
+        }
+    }
+
+    /// Annotate the capture with the plaintext length of the next chunk
+    /// relayed in `direction` (no-op unless enabled)
+    pub fn note_plaintext(&self, direction: Direction, len: usize) {
+        let state = self.state.lock().unwrap();
+        if !state.capture.plaintext_lengths {
+            return;
+        }
+        let (src, dst, _, _) = state.endpoints(direction);
+        let label = match direction {
+            Direction::Outbound => "out",
+            Direction::Inbound => "in",
+        };
+        let text = format!("nooshdaroo {} plaintext={}", label, len);
+        let meta_dst = SocketAddr::new(dst.ip(), ANNOTATION_PORT);
+        state.capture.write(build_udp_packet(src, meta_dst, text.as_bytes()));
+    }
+
+    /// Record the connection being closed by `direction`'s sender
+    pub fn close(&self, direction: Direction) {
+        self.state.lock().unwrap().close(direction);
+    }
+}
+
+/// Stream wrapper that records everything read and written
+pub struct CaptureStream<S> {
+    inner: S,
+    flow: CaptureFlow,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl<S> CaptureStream<S> {
+    /// Wrap `inner`, recording into `flow`
+    pub fn new(inner: S, flow: CaptureFlow) -> Self {
+        Self { inner, flow, read_closed: false, write_closed: false }
+    }
+
+    /// Recording handle of this stream
+    pub fn flow(&self) -> &CaptureFlow {
+        &self.flow
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CaptureStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[before..];
+            if read.is_empty() {
+                if !self.read_closed && buf.remaining() > 0 {
+                    self.read_closed = true;
+                    self.flow.close(Direction::Inbound);
+                }
+            } else {
+                self.flow.record(Direction::Inbound, read);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CaptureStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.flow.record(Direction::Outbound, &buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_shutdown(cx);
+        if let Poll::Ready(Ok(())) = result {
+            if !self.write_closed {
+                self.write_closed = true;
+                self.flow.close(Direction::Outbound);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcap::{IpProtocol, PcapFile};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_capture_stream_records_both_directions() {
+        let path = std::env::temp_dir().join(format!("nooshdaroo-capture-{}.pcap", rand::random::<u32>()));
+        let capture = PacketCapture::create(&path, true).unwrap();
+
+        let local: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let remote: SocketAddr = "127.0.0.1:8443".parse().unwrap();
+        let (client, mut server) = tokio::io::duplex(4096);
+        let mut stream = CaptureStream::new(client, capture.flow(local, remote));
+
+        stream.flow().note_plaintext(Direction::Outbound, 3);
+        stream.write_all(b"wrapped-out").await.unwrap();
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"wrapped-in").await.unwrap();
+        let mut buf = [0u8; 10];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        let packets = PcapFile::open(&path).unwrap().packets();
+        std::fs::remove_file(&path).ok();
+
+        // SYN, SYN/ACK, ACK, annotation, outbound data, inbound data
+        assert_eq!(packets.len(), 6);
+        assert_eq!(packets[0].tcp_flags, TCP_SYN);
+        assert_eq!(packets[3].protocol, IpProtocol::Udp);
+        assert_eq!(packets[3].payload, b"nooshdaroo out plaintext=3");
+        assert_eq!(packets[4].src, local);
+        assert_eq!(packets[4].payload, b"wrapped-out");
+        assert_eq!(packets[5].src, remote);
+        assert_eq!(packets[5].payload, b"wrapped-in");
+    }
+}
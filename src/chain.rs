@@ -0,0 +1,317 @@
+//! Multi-hop relay chains
+//!
+//! `server_address` may name a chain of Nooshdaroo servers instead of a
+//! single one:
+//!
+//! ```toml
+//! [socks]
+//! server_address = "https@192.0.2.10:443 -> ssh@relay.example.net:22#<key> -> 203.0.113.7:8443"
+//! ```
+//!
+//! The client opens a tunnel to the entry hop and asks it to connect to the
+//! next hop, exactly as it would ask for any other target. The next tunnel
+//! is then established through that one, end to end between the client and
+//! the next hop, and so on until the exit hop connects to the destination.
+//! Each hop only forwards the still-encrypted tunnel of the hops behind it,
+//! so no single server sees both the user and the destination.
+//!
+//! A hop is `[protocol@]host:port[#public_key]`. The protocol defaults to
+//! the client's protocol and the key to the configured server key. Only the
+//! entry hop may use a UDP transport, and it must be given as an IP address.
+//!
+//! The client runs a separate Noise session with every hop, each nested in
+//! the one before, so a hop can route the session it carries but not
+//! decrypt it. Instead of the bare `host:port` a hop is sent a
+//! [`RoutingHeader`] of fixed size, which also asks it to pad every record
+//! of its session (see [`ChainPaddingConfig`](crate::config::ChainPaddingConfig)).
+
+use crate::noise_transport::NoiseConfig;
+use crate::protocol::ProtocolId;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+/// Separator between hops in a chain
+const HOP_SEPARATOR: &str = "->";
+
+/// One server of a relay chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainHop {
+    /// `host:port`, resolved by the previous hop (except for the entry hop)
+    pub address: String,
+    /// Protocol of the tunnel to this hop
+    pub protocol: Option<ProtocolId>,
+    /// Server's Noise static public key (base64), if it differs from the default
+    pub public_key: Option<String>,
+}
+
+impl ChainHop {
+    /// Protocol of the tunnel to this hop, given the client's `default`
+    pub fn protocol_or(&self, default: &ProtocolId) -> ProtocolId {
+        self.protocol.clone().unwrap_or_else(|| default.clone())
+    }
+
+    /// Noise configuration for this hop from the client's base config
+    pub fn noise_config(&self, base: &NoiseConfig) -> NoiseConfig {
+        match self.public_key {
+            Some(ref key) => base.with_remote_key(key),
+            None => base.clone(),
+        }
+    }
+}
+
+impl FromStr for ChainHop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (rest, public_key) = match s.split_once('#') {
+            Some((rest, key)) if !key.is_empty() => (rest, Some(key.to_string())),
+            Some(_) => return Err(format!("Empty public key in hop '{}'", s)),
+            None => (s, None),
+        };
+        let (protocol, address) = match rest.split_once('@') {
+            Some((protocol, address)) => (Some(ProtocolId::from(protocol)), address),
+            None => (None, rest),
+        };
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(format!("Hop '{}' is not host:port", s)),
+        }
+        Ok(Self {
+            address: address.to_string(),
+            protocol,
+            public_key,
+        })
+    }
+}
+
+impl fmt::Display for ChainHop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref protocol) = self.protocol {
+            write!(f, "{}@", protocol.as_str())?;
+        }
+        // Keys are left out so chains can be logged
+        f.write_str(&self.address)
+    }
+}
+
+/// Servers a tunnel passes through, entry first
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayChain {
+    pub hops: Vec<ChainHop>,
+}
+
+impl RelayChain {
+    /// Whether `spec` names a chain rather than a single server
+    pub fn is_chain(spec: &str) -> bool {
+        spec.contains(HOP_SEPARATOR)
+    }
+
+    /// Parse `entry -> ... -> exit`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let hops = spec
+            .split(HOP_SEPARATOR)
+            .map(str::parse)
+            .collect::<Result<Vec<ChainHop>, String>>()?;
+        if hops.len() < 2 {
+            return Err(format!("Relay chain '{}' needs at least two hops", spec));
+        }
+        Ok(Self { hops })
+    }
+
+    /// Check that every hop can be reached with `default_protocol` as the
+    /// client's protocol
+    pub fn validate(&self, default_protocol: &ProtocolId) -> Result<(), String> {
+        if self.entry().address.parse::<SocketAddr>().is_err() {
+            return Err(format!("Entry hop '{}' must be an IP address and port", self.entry().address));
+        }
+        for hop in &self.hops[1..] {
+            if is_udp_protocol(&hop.protocol_or(default_protocol)) {
+                return Err(format!("Hop '{}' cannot use a UDP transport; only the entry hop can", hop));
+            }
+        }
+        Ok(())
+    }
+
+    /// First server, the only one the client connects to directly
+    pub fn entry(&self) -> &ChainHop {
+        &self.hops[0]
+    }
+
+    /// Address of the entry hop
+    pub fn entry_addr(&self) -> Result<SocketAddr, String> {
+        self.entry()
+            .address
+            .parse()
+            .map_err(|_| format!("Entry hop '{}' must be an IP address and port", self.entry().address))
+    }
+}
+
+impl fmt::Display for RelayChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, hop) in self.hops.iter().enumerate() {
+            if i > 0 {
+                write!(f, " {} ", HOP_SEPARATOR)?;
+            }
+            write!(f, "{}", hop)?;
+        }
+        Ok(())
+    }
+}
+
+/// First byte of a routing header; a plain `host:port` target never starts
+/// with it
+const ROUTING_HEADER_VERSION: u8 = 0x01;
+
+/// Size of every routing header, whatever the address it carries
+pub const ROUTING_HEADER_SIZE: usize = 320;
+
+/// Header flag: pad every record of the session after this header
+const FLAG_PAD_RECORDS: u8 = 0x01;
+
+/// Header flag: the session carries typed records and may hop protocols
+const FLAG_PROTOCOL_HOPS: u8 = 0x02;
+
+/// Header flag: the session only carries ping frames for the server to echo
+const FLAG_PING: u8 = 0x04;
+
+/// Where a hop should forward the session, sent as its first message
+///
+/// A client that wants [protocol hops](crate::protocol_hop) or is
+/// [measuring the path](crate::path_quality) also sends one to a single
+/// server.
+///
+/// ```text
+/// version (1) | flags (1) | host length (1) | host | port (2, BE) | zeros
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingHeader {
+    /// Next hop or final target, `host:port` (IPv6 hosts in brackets)
+    pub target: String,
+    /// Both sides pad records from the next message on
+    pub pad_records: bool,
+    /// Both sides use [`HopSession`](crate::protocol_hop::HopSession)
+    /// framing once the server has replied
+    pub protocol_hops: bool,
+    /// The server echoes [ping frames](crate::path_quality) instead of
+    /// opening `target`
+    pub ping: bool,
+}
+
+impl RoutingHeader {
+    /// Whether the first message of a session is a routing header rather
+    /// than a plain target
+    pub fn is_header(message: &[u8]) -> bool {
+        message.first() == Some(&ROUTING_HEADER_VERSION)
+    }
+
+    /// Encode, padded to [`ROUTING_HEADER_SIZE`]
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let (host, port) = self
+            .target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| format!("Target '{}' is not host:port", self.target))?;
+        if host.is_empty() || host.len() > u8::MAX as usize {
+            return Err(format!("Target host '{}' must be 1 to 255 bytes", host));
+        }
+        let mut header = Vec::with_capacity(ROUTING_HEADER_SIZE);
+        header.push(ROUTING_HEADER_VERSION);
+        let mut flags = 0;
+        if self.pad_records {
+            flags |= FLAG_PAD_RECORDS;
+        }
+        if self.protocol_hops {
+            flags |= FLAG_PROTOCOL_HOPS;
+        }
+        if self.ping {
+            flags |= FLAG_PING;
+        }
+        header.push(flags);
+        header.push(host.len() as u8);
+        header.extend_from_slice(host.as_bytes());
+        header.extend_from_slice(&port.to_be_bytes());
+        header.resize(ROUTING_HEADER_SIZE, 0);
+        Ok(header)
+    }
+
+    /// Decode a header produced by [`encode`](Self::encode)
+    pub fn decode(message: &[u8]) -> Result<Self, String> {
+        if !Self::is_header(message) {
+            return Err("Not a routing header".to_string());
+        }
+        let (flags, host_len) = match message {
+            [_, flags, host_len, ..] => (*flags, *host_len as usize),
+            _ => return Err("Truncated routing header".to_string()),
+        };
+        let host = message.get(3..3 + host_len).ok_or("Truncated routing header")?;
+        let port = message.get(3 + host_len..5 + host_len).ok_or("Truncated routing header")?;
+        let host = std::str::from_utf8(host).map_err(|_| "Routing header host is not UTF-8".to_string())?;
+        Ok(Self {
+            target: format!("{}:{}", host, u16::from_be_bytes([port[0], port[1]])),
+            pad_records: flags & FLAG_PAD_RECORDS != 0,
+            protocol_hops: flags & FLAG_PROTOCOL_HOPS != 0,
+            ping: flags & FLAG_PING != 0,
+        })
+    }
+}
+
+/// Protocols carried over UDP, which cannot run inside another tunnel
+pub fn is_udp_protocol(protocol: &ProtocolId) -> bool {
+    matches!(protocol.as_str(), "dns-udp-tunnel" | "dns_udp_tunnel" | "dnsudptunnel")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chain() {
+        let chain = RelayChain::parse("https@192.0.2.10:443 -> ssh@relay.example.net:22#S2V5 -> 203.0.113.7:8443").unwrap();
+        assert_eq!(chain.hops.len(), 3);
+        assert_eq!(chain.entry_addr().unwrap(), "192.0.2.10:443".parse().unwrap());
+        assert_eq!(chain.hops[1].protocol, Some(ProtocolId::from("ssh")));
+        assert_eq!(chain.hops[1].public_key.as_deref(), Some("S2V5"));
+        assert_eq!(chain.hops[2].protocol_or(&ProtocolId::from("https")), ProtocolId::from("https"));
+        assert_eq!(
+            chain.to_string(),
+            "https@192.0.2.10:443 -> ssh@relay.example.net:22 -> 203.0.113.7:8443"
+        );
+        assert!(chain.validate(&ProtocolId::from("https")).is_ok());
+
+        assert!(!RelayChain::is_chain("192.0.2.10:443"));
+        assert!(RelayChain::parse("192.0.2.10:443 ->").is_err());
+        assert!(RelayChain::parse("192.0.2.10:443 -> relay.example.net").is_err());
+
+        // DNS over UDP cannot be nested inside the entry tunnel
+        let chain = RelayChain::parse("192.0.2.10:53 -> dns-udp-tunnel@relay.example.net:53").unwrap();
+        assert!(chain.validate(&ProtocolId::from("https")).is_err());
+        let chain = RelayChain::parse("relay.example.net:443 -> 203.0.113.7:443").unwrap();
+        assert!(chain.validate(&ProtocolId::from("https")).is_err());
+    }
+
+    #[test]
+    fn test_routing_header() {
+        let header = RoutingHeader {
+            target: "[2001:db8::1]:8443".to_string(),
+            pad_records: true,
+            protocol_hops: false,
+            ping: false,
+        };
+        let encoded = header.encode().unwrap();
+        assert_eq!(encoded.len(), ROUTING_HEADER_SIZE);
+        assert!(RoutingHeader::is_header(&encoded));
+        assert_eq!(RoutingHeader::decode(&encoded).unwrap(), header);
+
+        // Every target gives the same size on the wire
+        let short = RoutingHeader { target: "a.io:1".to_string(), pad_records: false, protocol_hops: true, ping: true };
+        assert_eq!(short.encode().unwrap().len(), ROUTING_HEADER_SIZE);
+        assert_eq!(RoutingHeader::decode(&short.encode().unwrap()).unwrap(), short);
+
+        assert!(!RoutingHeader::is_header(b"example.com:443"));
+        assert!(RoutingHeader::decode(&encoded[..10]).is_err());
+        let long = RoutingHeader { target: format!("{}:443", "a".repeat(256)), pad_records: false, protocol_hops: false, ping: false };
+        assert!(long.encode().is_err());
+    }
+}
@@ -0,0 +1,410 @@
+//! Server clusters sharing state by gossip
+//!
+//! Several servers behind one UDP load balancer each see a share of the
+//! DNS-tunnel clients, and a client may land on another node when the
+//! balancer rehashes. A UDP DNS-tunnel session lives in the node that
+//! performed its Noise handshake, so instead of moving it the nodes tell
+//! each other which clients they serve:
+//!
+//! - every `gossip_interval` each node sends its peers the clients it has
+//!   heard from recently and the bytes each client address moved through it
+//!   this quota period
+//! - a node receiving a query from a client another node serves forwards the
+//!   datagram there, and relays the answers back out of its own socket, so
+//!   the client keeps talking to the address it always did
+//! - a client's usage is the sum over all nodes, each node's share being the
+//!   largest it announced; past `quota_bytes` its queries are dropped
+//!
+//! Announcements from a node expire after [`PEER_TIMEOUT_INTERVALS`]
+//! gossip intervals, after which its clients start new sessions wherever
+//! they land. Every message carries an HMAC-SHA256 tag under the shared
+//! `secret` and a timestamp, and is ignored when either is off.
+
+use crate::config::ClusterConfig;
+use base64::Engine;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Gossip intervals without news after which a node's clients are free
+pub const PEER_TIMEOUT_INTERVALS: u32 = 5;
+
+/// Clients a node stops announcing after this long without a query, as the
+/// DNS tunnel drops their sessions
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Messages older or newer than this are replays or from a skewed clock
+const MAX_CLOCK_SKEW: u64 = 30;
+
+/// Clients per announcement, keeping it within one datagram
+const CLIENTS_PER_MESSAGE: usize = 500;
+
+const TAG_LEN: usize = 32;
+
+/// Gossip between nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// Clients the sender serves and its traffic per client address
+    State { period: u64, clients: Vec<SocketAddr>, usage: HashMap<IpAddr, u64> },
+    /// A datagram from `client` for the node serving it
+    Forward { client: SocketAddr, port: u16, datagram: String },
+    /// An answer for the forwarding node to send to `client`
+    Reply { client: SocketAddr, port: u16, packet: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Envelope {
+    node: String,
+    /// Unix seconds
+    sent_at: u64,
+    message: Message,
+}
+
+/// Datagram traffic between nodes for the DNS tunnel server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A query of a client served here, received by the node `via`
+    Forwarded { client: SocketAddr, port: u16, datagram: Vec<u8>, via: SocketAddr },
+    /// An answer to a client whose queries this node forwarded, to send
+    /// from hop port `port`
+    Reply { client: SocketAddr, port: u16, packet: Vec<u8> },
+}
+
+/// Where a client's datagrams are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Local,
+    /// Forward to the node with this gossip address
+    Forward(SocketAddr),
+}
+
+/// A client served by another node
+#[derive(Debug)]
+struct Claim {
+    /// Gossip address of the node serving the client
+    addr: SocketAddr,
+    seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Clients served here and when they were last heard from
+    local: HashMap<SocketAddr, Instant>,
+    remote: HashMap<SocketAddr, Claim>,
+    period: u64,
+    /// Bytes per client address per node in `period`
+    usage: HashMap<IpAddr, HashMap<String, u64>>,
+}
+
+/// This node's view of the cluster
+pub struct Cluster {
+    node: String,
+    peers: Vec<SocketAddr>,
+    interval: Duration,
+    quota_bytes: u64,
+    quota_period: Duration,
+    key: hmac::Key,
+    socket: UdpSocket,
+    state: Mutex<State>,
+    events: tokio::sync::Mutex<mpsc::UnboundedReceiver<Event>>,
+    events_tx: mpsc::UnboundedSender<Event>,
+}
+
+impl std::fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cluster").field("node", &self.node).field("peers", &self.peers).finish_non_exhaustive()
+    }
+}
+
+impl Cluster {
+    /// Bind the gossip socket and start gossiping with the peers
+    pub async fn start(config: &ClusterConfig) -> io::Result<Arc<Self>> {
+        let cluster = Arc::new(Self::bind(config).await?);
+        log::info!(
+            "Cluster node {} gossiping on UDP {} with {} peers",
+            cluster.node,
+            config.listen,
+            cluster.peers.len()
+        );
+        // Both run for the life of the server
+        let receiver = Arc::clone(&cluster);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                match receiver.socket.recv_from(&mut buf).await {
+                    Ok((n, from)) => receiver.receive(&buf[..n], from),
+                    Err(e) => log::debug!("Cluster gossip receive failed: {}", e),
+                }
+            }
+        });
+        let announcer = Arc::clone(&cluster);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(announcer.interval);
+            loop {
+                ticker.tick().await;
+                announcer.announce().await;
+            }
+        });
+        Ok(cluster)
+    }
+
+    async fn bind(config: &ClusterConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind(config.listen).await?;
+        let (events_tx, events) = mpsc::unbounded_channel();
+        Ok(Self {
+            node: config.node_id.clone().unwrap_or_else(|| format!("{:016x}", rand::random::<u64>())),
+            peers: config.peers.clone(),
+            interval: config.gossip_interval,
+            quota_bytes: config.quota_bytes,
+            quota_period: config.quota_period,
+            key: hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()),
+            socket,
+            state: Mutex::new(State::default()),
+            events: tokio::sync::Mutex::new(events),
+            events_tx,
+        })
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Where datagrams from `client` go: here when it is served here or by
+    /// nobody, which makes this node serve it
+    pub fn route(&self, client: SocketAddr) -> Route {
+        let mut state = self.state.lock().unwrap();
+        if !state.local.contains_key(&client) {
+            let timeout = self.peer_timeout();
+            if let Some(claim) = state.remote.get(&client).filter(|claim| claim.seen.elapsed() < timeout) {
+                return Route::Forward(claim.addr);
+            }
+            state.remote.remove(&client);
+        }
+        state.local.insert(client, Instant::now());
+        Route::Local
+    }
+
+    /// Count `bytes` moved by `client`, returning whether its address is
+    /// still within the quota
+    pub fn charge(&self, client: IpAddr, bytes: u64) -> bool {
+        let period = self.current_period();
+        let mut state = self.state.lock().unwrap();
+        state.roll_over(period);
+        let usage = state.usage.entry(client).or_default();
+        *usage.entry(self.node.clone()).or_default() += bytes;
+        self.quota_bytes == 0 || usage.values().sum::<u64>() <= self.quota_bytes
+    }
+
+    /// Bytes `client` moved through the whole cluster this period
+    pub fn usage(&self, client: IpAddr) -> u64 {
+        let state = self.state.lock().unwrap();
+        if state.period != self.current_period() {
+            return 0;
+        }
+        state.usage.get(&client).map(|usage| usage.values().sum()).unwrap_or(0)
+    }
+
+    /// Send a datagram from `client` to the node serving it
+    pub async fn forward(&self, node: SocketAddr, client: SocketAddr, port: u16, datagram: &[u8]) {
+        let datagram = base64::engine::general_purpose::STANDARD.encode(datagram);
+        self.send(node, Message::Forward { client, port, datagram }).await;
+    }
+
+    /// Send an answer back to the node that forwarded `client`'s query
+    pub async fn reply(&self, via: SocketAddr, client: SocketAddr, port: u16, packet: &[u8]) {
+        let packet = base64::engine::general_purpose::STANDARD.encode(packet);
+        self.send(via, Message::Reply { client, port, packet }).await;
+    }
+
+    /// Next forwarded datagram or answer to relay
+    pub async fn next_event(&self) -> Event {
+        // The sender lives in self, so the channel never closes
+        self.events.lock().await.recv().await.expect("cluster event channel open")
+    }
+
+    fn peer_timeout(&self) -> Duration {
+        self.interval * PEER_TIMEOUT_INTERVALS
+    }
+
+    fn current_period(&self) -> u64 {
+        unix_now() / self.quota_period.as_secs().max(1)
+    }
+
+    /// Tell every peer what this node serves
+    async fn announce(&self) {
+        let period = self.current_period();
+        let (clients, usage) = {
+            let mut state = self.state.lock().unwrap();
+            state.roll_over(period);
+            state.local.retain(|_, seen| seen.elapsed() < CLIENT_TIMEOUT);
+            let clients: Vec<SocketAddr> = state.local.keys().copied().collect();
+            let usage: HashMap<IpAddr, u64> = state
+                .usage
+                .iter()
+                .filter_map(|(client, usage)| Some((*client, *usage.get(&self.node)?)))
+                .collect();
+            (clients, usage)
+        };
+        let mut chunks: Vec<&[SocketAddr]> = clients.chunks(CLIENTS_PER_MESSAGE).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            // Usage goes out once per round
+            let usage = if i == 0 { usage.clone() } else { HashMap::new() };
+            let message = Message::State { period, clients: chunk.to_vec(), usage };
+            for &peer in &self.peers {
+                self.send(peer, message.clone()).await;
+            }
+        }
+    }
+
+    async fn send(&self, to: SocketAddr, message: Message) {
+        let envelope = Envelope { node: self.node.clone(), sent_at: unix_now(), message };
+        if let Err(e) = self.socket.send_to(&self.seal(&envelope), to).await {
+            log::debug!("Cluster gossip to {} failed: {}", to, e);
+        }
+    }
+
+    fn seal(&self, envelope: &Envelope) -> Vec<u8> {
+        let mut packet = serde_json::to_vec(envelope).expect("envelope serializes");
+        let tag = hmac::sign(&self.key, &packet);
+        packet.extend_from_slice(tag.as_ref());
+        packet
+    }
+
+    fn open(&self, packet: &[u8]) -> Option<Envelope> {
+        let body_len = packet.len().checked_sub(TAG_LEN)?;
+        let (body, tag) = packet.split_at(body_len);
+        hmac::verify(&self.key, body, tag).ok()?;
+        let envelope: Envelope = serde_json::from_slice(body).ok()?;
+        (envelope.sent_at.abs_diff(unix_now()) <= MAX_CLOCK_SKEW && envelope.node != self.node).then_some(envelope)
+    }
+
+    fn receive(&self, packet: &[u8], from: SocketAddr) {
+        let Some(envelope) = self.open(packet) else {
+            log::debug!("Ignoring unauthenticated cluster message from {}", from);
+            return;
+        };
+        let event = match envelope.message {
+            Message::State { period, clients, usage } => {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                for client in clients {
+                    // A client talking to this node again belongs here
+                    if state.local.get(&client).is_none_or(|seen| seen.elapsed() >= self.interval) {
+                        state.local.remove(&client);
+                        state.remote.insert(client, Claim { addr: from, seen: now });
+                    }
+                }
+                state.roll_over(self.current_period());
+                if period == state.period {
+                    for (client, bytes) in usage {
+                        let share = state.usage.entry(client).or_default().entry(envelope.node.clone()).or_default();
+                        *share = (*share).max(bytes);
+                    }
+                }
+                return;
+            }
+            Message::Forward { client, port, datagram } => {
+                let Ok(datagram) = base64::engine::general_purpose::STANDARD.decode(datagram) else {
+                    return;
+                };
+                self.state.lock().unwrap().local.insert(client, Instant::now());
+                Event::Forwarded { client, port, datagram, via: from }
+            }
+            Message::Reply { client, port, packet } => {
+                let Ok(packet) = base64::engine::general_purpose::STANDARD.decode(packet) else {
+                    return;
+                };
+                Event::Reply { client, port, packet }
+            }
+        };
+        let _ = self.events_tx.send(event);
+    }
+}
+
+impl State {
+    /// Start counting afresh when a new quota period begins
+    fn roll_over(&mut self, period: u64) {
+        if self.period != period {
+            self.period = period;
+            self.usage.clear();
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(node: &str, peers: Vec<SocketAddr>) -> ClusterConfig {
+        ClusterConfig {
+            node_id: Some(node.to_string()),
+            listen: "127.0.0.1:0".parse().unwrap(),
+            peers,
+            secret: "0123456789abcdef-shared".to_string(),
+            gossip_interval: Duration::from_millis(50),
+            quota_bytes: 1000,
+            quota_period: Duration::from_secs(86400),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_gossip() {
+        let a = Cluster::start(&config("a", Vec::new())).await.unwrap();
+        let a_addr = a.socket.local_addr().unwrap();
+        let b = Cluster::start(&config("b", vec![a_addr])).await.unwrap();
+        let b_addr = b.socket.local_addr().unwrap();
+
+        // b serves a client and has seen most of its quota
+        let client: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        assert_eq!(b.route(client), Route::Local);
+        assert!(b.charge(client.ip(), 800));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // a hands the client's datagrams to b, and counts it against the
+        // quota b reported
+        assert_eq!(a.route(client), Route::Forward(b_addr));
+        assert_eq!(a.usage(client.ip()), 800);
+        assert!(!a.charge(client.ip(), 300));
+
+        a.forward(b_addr, client, 53, b"query").await;
+        let event = tokio::time::timeout(Duration::from_secs(5), b.next_event()).await.unwrap();
+        assert_eq!(event, Event::Forwarded { client, port: 53, datagram: b"query".to_vec(), via: a_addr });
+        b.reply(a_addr, client, 53, b"answer").await;
+        let event = tokio::time::timeout(Duration::from_secs(5), a.next_event()).await.unwrap();
+        assert_eq!(event, Event::Reply { client, port: 53, packet: b"answer".to_vec() });
+    }
+
+    #[tokio::test]
+    async fn test_cluster_authentication() {
+        let cluster = Cluster::bind(&config("a", Vec::new())).await.unwrap();
+        let envelope = Envelope {
+            node: "b".to_string(),
+            sent_at: unix_now(),
+            message: Message::State { period: 0, clients: Vec::new(), usage: HashMap::new() },
+        };
+        let packet = cluster.seal(&envelope);
+        assert_eq!(cluster.open(&packet), Some(envelope.clone()));
+
+        let mut forged = packet.clone();
+        forged[10] ^= 1;
+        assert_eq!(cluster.open(&forged), None);
+        let stale = Envelope { sent_at: unix_now() - 3600, ..envelope };
+        assert_eq!(cluster.open(&cluster.seal(&stale)), None);
+        let own = Envelope { node: "a".to_string(), ..stale };
+        assert_eq!(cluster.open(&cluster.seal(&own)), None);
+    }
+}
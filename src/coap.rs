@@ -0,0 +1,674 @@
+//! CoAP framing for KCP sessions
+//!
+//! Constrained devices talk CoAP (RFC 7252) over UDP to their cloud
+//! endpoints, small datagrams in both directions at all hours, which makes
+//! it another quiet disguise for the UDP path besides DNS. With
+//! `relay.coap` set, `kcp` datagrams travel as CoAP messages:
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//!
+//! [server]
+//! transport = "kcp"
+//!
+//! [relay]
+//! coap = true
+//! ```
+//!
+//! Both ends must set it. The client registers as an observer (RFC 7641)
+//! with a confirmable `GET /commands` and `Observe: 0`, and the server
+//! acknowledges with a piggybacked `2.05 Content`. Upstream datagrams are
+//! `POST /telemetry` requests with `No-Response` (RFC 7967), each with a
+//! fresh token; downstream ones are notifications echoing the observe
+//! token, with an increasing `Observe` sequence. Every [`CON_EVERY`]th
+//! message is confirmable, acknowledged by the peer and retransmitted with
+//! CoAP's exponential backoff until it is, and duplicates are dropped by
+//! message ID, as CoAP endpoints do. Payloads are sent as
+//! `application/octet-stream`.
+
+use crate::config::TurnConfig;
+use crate::kcp_transport::{self, KcpListener, Socket};
+use async_trait::async_trait;
+use rand::Rng;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const VERSION: u8 = 1;
+
+/// Message types
+const CON: u8 = 0;
+const NON: u8 = 1;
+const ACK: u8 = 2;
+const RST: u8 = 3;
+
+/// Codes, class << 5 | detail
+const EMPTY: u8 = 0x00;
+const GET: u8 = 0x01;
+const POST: u8 = 0x02;
+const CONTENT: u8 = 0x45;
+
+/// Option numbers
+const OBSERVE: u16 = 6;
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+const NO_RESPONSE: u16 = 258;
+
+/// application/octet-stream
+const OCTET_STREAM: u32 = 42;
+
+/// No-Response value suppressing 2.xx responses
+const SUPPRESS_SUCCESS: u32 = 2;
+
+const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Resource the client observes
+const COMMANDS: &str = "commands";
+
+/// Resource the client posts to
+const TELEMETRY: &str = "telemetry";
+
+/// One message in this many is confirmable
+pub const CON_EVERY: u32 = 16;
+
+/// CoAP's transmission parameters (RFC 7252 section 4.8)
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+/// Peers silent for this long are forgotten, about CoAP's EXCHANGE_LIFETIME
+const PEER_TIMEOUT: Duration = Duration::from_secs(247);
+
+/// Message IDs remembered per peer for deduplication
+const RECENT_IDS: usize = 256;
+
+/// How often retransmissions are checked
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+/// Bytes of datagrams a server queues until the client observes; more are
+/// dropped like a full socket buffer and KCP retransmits them
+const QUEUE_BYTES: usize = 64 * 1024;
+
+/// Received datagrams waiting for `recv_from`
+const RECEIVE_QUEUE: usize = 1024;
+
+/// Open a KCP session to `server_addr` carried in CoAP messages, through a
+/// relay allocated on `turn` if given
+pub async fn connect(server_addr: SocketAddr, turn: Option<&TurnConfig>) -> io::Result<DuplexStream> {
+    match turn {
+        Some(turn) => {
+            let (relay, client) = crate::turn_relay::allocate(turn).await?;
+            Ok(kcp_transport::connect_over(Arc::new(CoapConn::new(relay, true)), server_addr, client))
+        }
+        None => {
+            let udp = Arc::new(UdpSocket::bind(kcp_transport::any_local_addr(server_addr)).await?);
+            Ok(kcp_transport::connect_over(Arc::new(CoapConn::new(udp, true)), server_addr, ()))
+        }
+    }
+}
+
+/// Accept KCP sessions on `addr` carried in CoAP messages
+pub async fn bind(addr: SocketAddr) -> io::Result<KcpListener> {
+    let udp = Arc::new(UdpSocket::bind(addr).await?);
+    KcpListener::bind_over(Arc::new(CoapConn::new(udp, false)))
+}
+
+/// Datagram socket sending CoAP messages over `inner`, as the observing
+/// client or the server
+pub struct CoapConn {
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    client: bool,
+    received: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl CoapConn {
+    pub fn new(inner: Socket, client: bool) -> Self {
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, received) = mpsc::channel(RECEIVE_QUEUE);
+        let tasks = [
+            tokio::spawn(run_timers(Arc::clone(&inner), Arc::clone(&peers))),
+            tokio::spawn(receive(Arc::clone(&inner), Arc::clone(&peers), client, tx)),
+        ];
+        Self {
+            inner,
+            peers,
+            client,
+            received: tokio::sync::Mutex::new(received),
+            tasks,
+        }
+    }
+}
+
+impl Drop for CoapConn {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl webrtc::util::Conn for CoapConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(unsupported())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(webrtc::util::Error::ErrUseClosedNetworkConn)?;
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// Send `buf` to `target` in a request or notification
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let messages = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.entry(target).or_insert_with(Peer::new);
+            peer.send(buf, self.client)
+        };
+        for message in messages {
+            self.inner.send_to(&message, target).await?;
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// Confirmable message awaiting its acknowledgement
+struct Pending {
+    message_id: u16,
+    message: Vec<u8>,
+    sent: Instant,
+    timeout: Duration,
+    retransmissions: u32,
+}
+
+/// CoAP state for one peer address
+struct Peer {
+    next_message_id: u16,
+    /// Messages sent, to pick the confirmable ones
+    sent: u32,
+    pending: Vec<Pending>,
+    recent: VecDeque<u16>,
+    /// Token of the observation
+    token: Vec<u8>,
+    /// Client: message ID of the observe request awaiting its ACK
+    observe_request: Option<u16>,
+    /// The observation is established
+    observing: bool,
+    /// Server: sequence number of the next notification
+    observe_sequence: u32,
+    /// Server: datagrams waiting for the client to observe
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+    last_received: Instant,
+}
+
+impl Peer {
+    fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            next_message_id: rng.gen(),
+            sent: 0,
+            pending: Vec::new(),
+            recent: VecDeque::new(),
+            token: rng.gen::<[u8; 8]>().to_vec(),
+            observe_request: None,
+            observing: false,
+            observe_sequence: rng.gen_range(2..1000),
+            queue: VecDeque::new(),
+            queued: 0,
+            last_received: Instant::now(),
+        }
+    }
+
+    /// Messages sending `datagram`, registering the observation first if
+    /// the client has none
+    fn send(&mut self, datagram: &[u8], client: bool) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        if client {
+            if !self.observing && self.observe_request.is_none() {
+                messages.push(self.observe());
+            }
+            messages.push(self.post(datagram));
+        } else if self.observing {
+            messages.push(self.notify(datagram));
+        } else if self.queued + datagram.len() <= QUEUE_BYTES {
+            self.queued += datagram.len();
+            self.queue.push_back(datagram.to_vec());
+        }
+        messages
+    }
+
+    /// Confirmable `GET /commands` with `Observe: 0`
+    fn observe(&mut self) -> Vec<u8> {
+        let message_id = self.message_id();
+        let options = [(OBSERVE, uint(0)), (URI_PATH, COMMANDS.as_bytes().to_vec())];
+        let message = encode(CON, GET, message_id, &self.token, &options, &[]);
+        self.observe_request = Some(message_id);
+        self.confirm(message_id, message)
+    }
+
+    /// `POST /telemetry` carrying `datagram`
+    fn post(&mut self, datagram: &[u8]) -> Vec<u8> {
+        let (kind, message_id) = (self.kind(), self.message_id());
+        let token = rand::thread_rng().gen::<[u8; 4]>();
+        let options = [
+            (URI_PATH, TELEMETRY.as_bytes().to_vec()),
+            (CONTENT_FORMAT, uint(OCTET_STREAM)),
+            (NO_RESPONSE, uint(SUPPRESS_SUCCESS)),
+        ];
+        let message = encode(kind, POST, message_id, &token, &options, datagram);
+        self.confirm_if(kind, message_id, message)
+    }
+
+    /// Notification of the observed resource carrying `datagram`
+    fn notify(&mut self, datagram: &[u8]) -> Vec<u8> {
+        let (kind, message_id) = (self.kind(), self.message_id());
+        let options = self.content_options();
+        let message = encode(kind, CONTENT, message_id, &self.token, &options, datagram);
+        self.confirm_if(kind, message_id, message)
+    }
+
+    /// `Observe` and `Content-Format` of the next notification
+    fn content_options(&mut self) -> [(u16, Vec<u8>); 2] {
+        let sequence = self.observe_sequence;
+        self.observe_sequence = (self.observe_sequence + 1) & 0xff_ffff;
+        [(OBSERVE, uint(sequence)), (CONTENT_FORMAT, uint(OCTET_STREAM))]
+    }
+
+    /// Confirmable for every [`CON_EVERY`]th message, with no other
+    /// confirmable outstanding (NSTART 1)
+    fn kind(&mut self) -> u8 {
+        self.sent = self.sent.wrapping_add(1);
+        if self.sent.is_multiple_of(CON_EVERY) && self.pending.is_empty() {
+            CON
+        } else {
+            NON
+        }
+    }
+
+    fn message_id(&mut self) -> u16 {
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        message_id
+    }
+
+    fn confirm_if(&mut self, kind: u8, message_id: u16, message: Vec<u8>) -> Vec<u8> {
+        if kind == CON {
+            self.confirm(message_id, message)
+        } else {
+            message
+        }
+    }
+
+    /// Track a confirmable message for retransmission
+    fn confirm(&mut self, message_id: u16, message: Vec<u8>) -> Vec<u8> {
+        // Initial timeout between ACK_TIMEOUT and ACK_TIMEOUT * ACK_RANDOM_FACTOR (1.5)
+        let timeout = ACK_TIMEOUT.mul_f64(rand::thread_rng().gen_range(1.0..1.5));
+        self.pending.push(Pending { message_id, message: message.clone(), sent: Instant::now(), timeout, retransmissions: 0 });
+        message
+    }
+
+    /// Handle a received `message`: messages to send back, and the datagram
+    /// it carried, if any
+    fn receive(&mut self, message: &Message, client: bool) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        self.last_received = Instant::now();
+        match message.kind {
+            ACK | RST => {
+                self.pending.retain(|pending| pending.message_id != message.message_id);
+                if client && self.observe_request == Some(message.message_id) {
+                    self.observe_request = None;
+                    self.observing = message.kind == ACK;
+                } else if !client && message.kind == RST {
+                    // The client cancelled the observation
+                    self.observing = false;
+                }
+                return (Vec::new(), None);
+            }
+            _ => {}
+        }
+
+        let duplicate = self.recent.contains(&message.message_id);
+        if !duplicate {
+            if self.recent.len() == RECENT_IDS {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(message.message_id);
+        }
+
+        let mut replies = Vec::new();
+        let mut datagram = None;
+        match (message.code, client) {
+            (GET, false) if message.option(OBSERVE).is_some() => {
+                self.token = message.token.to_vec();
+                self.observing = true;
+                let options = self.content_options();
+                let kind = if message.kind == CON { ACK } else { NON };
+                let message_id = if message.kind == CON { message.message_id } else { self.message_id() };
+                replies.push(encode(kind, CONTENT, message_id, &self.token, &options, &[]));
+                // Notifications of what waited for the observation
+                self.queued = 0;
+                let queue: Vec<Vec<u8>> = self.queue.drain(..).collect();
+                replies.extend(queue.iter().map(|datagram| self.notify(datagram)));
+                return (replies, None);
+            }
+            (POST, false) | (CONTENT, true) if !duplicate => {
+                if client && message.token != self.token {
+                    // Not our observation: tell the server to stop
+                    replies.push(encode(RST, EMPTY, message.message_id, &[], &[], &[]));
+                    return (replies, None);
+                }
+                datagram = Some(message.payload.to_vec()).filter(|payload| !payload.is_empty());
+            }
+            _ => {}
+        }
+        if message.kind == CON {
+            replies.push(encode(ACK, EMPTY, message.message_id, &[], &[], &[]));
+        }
+        (replies, datagram)
+    }
+
+    /// Retransmissions due now; a confirmable message unacknowledged after
+    /// [`MAX_RETRANSMIT`] retransmissions is given up
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        let mut given_up = Vec::new();
+        for pending in self.pending.iter_mut().filter(|pending| pending.sent.elapsed() >= pending.timeout) {
+            if pending.retransmissions == MAX_RETRANSMIT {
+                given_up.push(pending.message_id);
+                continue;
+            }
+            messages.push(pending.message.clone());
+            pending.sent = Instant::now();
+            pending.timeout *= 2;
+            pending.retransmissions += 1;
+        }
+        self.pending.retain(|pending| !given_up.contains(&pending.message_id));
+        if self.observe_request.is_some_and(|request| given_up.contains(&request)) {
+            self.observe_request = None;
+        }
+        messages
+    }
+}
+
+/// Retransmit confirmable messages and forget silent peers
+async fn run_timers(inner: Socket, peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>) {
+    let mut interval = tokio::time::interval(TIMER_TICK);
+    loop {
+        interval.tick().await;
+        let messages: Vec<(Vec<u8>, SocketAddr)> = {
+            let mut peers = peers.lock().unwrap();
+            peers.retain(|_, peer| peer.last_received.elapsed() < PEER_TIMEOUT || !peer.pending.is_empty());
+            peers
+                .iter_mut()
+                .flat_map(|(addr, peer)| peer.tick().into_iter().map(|message| (message, *addr)))
+                .collect()
+        };
+        for (message, addr) in messages {
+            if let Err(e) = inner.send_to(&message, addr).await {
+                log::debug!("CoAP: send to {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// Handle received messages, passing on the datagrams they carry
+async fn receive(
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    client: bool,
+    received: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, from) = match inner.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(webrtc::util::Error::ErrUseClosedNetworkConn) => return,
+            Err(e) => {
+                log::debug!("CoAP: receive error: {}", e);
+                continue;
+            }
+        };
+        let Some(message) = Message::parse(&buf[..n]) else {
+            continue;
+        };
+        let (replies, datagram) = {
+            let mut peers = peers.lock().unwrap();
+            // Only requests start talking to a new peer
+            if (client || !matches!(message.code, GET | POST)) && !peers.contains_key(&from) {
+                continue;
+            }
+            peers.entry(from).or_insert_with(Peer::new).receive(&message, client)
+        };
+        for reply in replies {
+            if let Err(e) = inner.send_to(&reply, from).await {
+                log::debug!("CoAP: send to {} failed: {}", from, e);
+            }
+        }
+        if let Some(datagram) = datagram {
+            match received.try_send((datagram, from)) {
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => log::debug!("CoAP: receive queue full, dropping datagram"),
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// A parsed CoAP message
+struct Message<'a> {
+    kind: u8,
+    code: u8,
+    message_id: u16,
+    token: &'a [u8],
+    options: Vec<(u16, &'a [u8])>,
+    payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let (&first, rest) = bytes.split_first()?;
+        let token_length = (first & 0x0f) as usize;
+        if first >> 6 != VERSION || token_length > 8 || rest.len() < 3 + token_length {
+            return None;
+        }
+        let code = rest[0];
+        let message_id = u16::from_be_bytes([rest[1], rest[2]]);
+        let (token, mut rest) = rest[3..].split_at(token_length);
+
+        let mut options = Vec::new();
+        let mut number = 0u16;
+        let mut payload: &[u8] = &[];
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == PAYLOAD_MARKER {
+                if tail.is_empty() {
+                    return None;
+                }
+                payload = tail;
+                break;
+            }
+            rest = tail;
+            let delta = option_nibble(byte >> 4, &mut rest)?;
+            let length = option_nibble(byte & 0x0f, &mut rest)? as usize;
+            number = number.checked_add(delta)?;
+            if rest.len() < length {
+                return None;
+            }
+            let (value, tail) = rest.split_at(length);
+            options.push((number, value));
+            rest = tail;
+        }
+        Some(Self { kind: (first >> 4) & 0x03, code, message_id, token, options, payload })
+    }
+
+    fn option(&self, number: u16) -> Option<&'a [u8]> {
+        self.options.iter().find(|(n, _)| *n == number).map(|(_, value)| *value)
+    }
+}
+
+/// Option delta or length from its nibble and extended bytes
+fn option_nibble(nibble: u8, rest: &mut &[u8]) -> Option<u16> {
+    let (value, extended) = match nibble {
+        0..=12 => (nibble as u16, 0),
+        13 => (13 + *rest.first()? as u16, 1),
+        14 => (269u16.checked_add(u16::from_be_bytes([*rest.first()?, *rest.get(1)?]))?, 2),
+        _ => return None,
+    };
+    *rest = &rest[extended..];
+    Some(value)
+}
+
+/// Nibble and extended bytes of an option delta or length
+fn put_option_nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// Message with `options` in increasing number order
+fn encode(kind: u8, code: u8, message_id: u16, token: &[u8], options: &[(u16, Vec<u8>)], payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(16 + token.len() + payload.len());
+    message.push(VERSION << 6 | kind << 4 | token.len() as u8);
+    message.push(code);
+    message.extend_from_slice(&message_id.to_be_bytes());
+    message.extend_from_slice(token);
+    let mut previous = 0;
+    for (number, value) in options {
+        let (delta, delta_extended) = put_option_nibble(number - previous);
+        let (length, length_extended) = put_option_nibble(value.len() as u16);
+        message.push(delta << 4 | length);
+        message.extend_from_slice(&delta_extended);
+        message.extend_from_slice(&length_extended);
+        message.extend_from_slice(value);
+        previous = *number;
+    }
+    if !payload.is_empty() {
+        message.push(PAYLOAD_MARKER);
+        message.extend_from_slice(payload);
+    }
+    message
+}
+
+/// Option value of an unsigned integer, in as few bytes as it takes
+fn uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn unsupported() -> webrtc::util::Error {
+    webrtc::util::Error::Other("CoAP sockets are unconnected".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_observe_and_notifications() {
+        let (mut client, mut server) = (Peer::new(), Peer::new());
+        let messages = client.send(b"up", true);
+        assert_eq!(messages.len(), 2);
+        let get = Message::parse(&messages[0]).unwrap();
+        assert_eq!((get.kind, get.code, get.token.len()), (CON, GET, 8));
+        assert_eq!((get.option(OBSERVE), get.option(URI_PATH)), (Some(&[][..]), Some(&b"commands"[..])));
+        let post = Message::parse(&messages[1]).unwrap();
+        assert_eq!((post.kind, post.code, post.payload), (NON, POST, &b"up"[..]));
+        assert_eq!(post.option(NO_RESPONSE), Some(&[2u8][..]));
+
+        // Downstream waits for the observation
+        assert!(server.send(b"down", false).is_empty());
+        assert_eq!(server.receive(&post, false).1, Some(b"up".to_vec()));
+        let (replies, _) = server.receive(&get, false);
+        assert_eq!(replies.len(), 2);
+        let ack = Message::parse(&replies[0]).unwrap();
+        assert_eq!((ack.kind, ack.code, ack.message_id, ack.token), (ACK, CONTENT, get.message_id, get.token));
+        client.receive(&ack, true);
+        assert!(client.observing && client.pending.is_empty());
+
+        let notification = Message::parse(&replies[1]).unwrap();
+        assert_eq!((notification.code, notification.token), (CONTENT, get.token));
+        let sequence = |message: &Message| message.option(OBSERVE).unwrap().iter().fold(0u32, |n, b| n << 8 | *b as u32);
+        assert!(sequence(&notification) > sequence(&ack));
+        assert_eq!(client.receive(&notification, true).1, Some(b"down".to_vec()));
+        // Duplicates are dropped
+        assert_eq!(client.receive(&notification, true).1, None);
+
+        // Every CON_EVERY-th message is confirmable and acknowledged
+        let kinds: Vec<u8> = (0..CON_EVERY).map(|_| Message::parse(&server.send(b"x", false)[0]).unwrap().kind).collect();
+        assert_eq!(kinds.iter().filter(|kind| **kind == CON).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_kcp_over_coap() {
+        let mut listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = connect(listener.local_addr(), None).await.unwrap();
+
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = vec![0u8; data.len()];
+        tokio::time::timeout(Duration::from_secs(5), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"bye").await.unwrap();
+        let mut buf = [0u8; 3];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"bye");
+    }
+}
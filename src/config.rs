@@ -2,8 +2,10 @@
 
 use super::strategy::StrategyType;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// Default protocol directory (no longer used since protocols are embedded)
 fn default_protocol_dir() -> PathBuf {
@@ -44,6 +46,66 @@ pub struct NooshdarooConfig {
     /// Transport encryption (Noise Protocol)
     #[serde(default)]
     pub transport: Option<crate::noise_transport::NoiseConfig>,
+
+    /// Trust settings for protocol definition files
+    #[serde(default)]
+    pub protocol_trust: ProtocolTrustConfig,
+
+    /// Relay buffer sizes and backpressure limits
+    #[serde(default)]
+    pub relay: RelayConfig,
+
+    /// Persistent history of tunnel activity
+    #[serde(default)]
+    pub history: HistoryConfig,
+
+    /// A/B comparison of candidate protocols on live connections
+    #[serde(default)]
+    pub experiment: ExperimentConfig,
+
+    /// Anonymized connectivity measurements in OONI's format
+    #[serde(default)]
+    pub measurements: MeasurementsConfig,
+
+    /// Tunnel RTT, jitter and loss measured with in-band pings
+    #[serde(default)]
+    pub path_quality: PathQualityConfig,
+
+    /// Destinations the proxy may connect to
+    #[serde(default)]
+    pub access: AccessConfig,
+
+    /// Local services the `agent` command exposes through a server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverse: Option<ReverseAgentConfig>,
+
+    /// Direct tunnel to another client for the `peer` command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerConfig>,
+
+    /// Trusted clients relaying connections for one another
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshConfig>,
+
+    /// Out-of-band discovery of servers
+    #[serde(default)]
+    pub bootstrap: BootstrapConfig,
+
+    /// DNS over HTTPS for names nooshdaroo resolves itself
+    #[serde(default)]
+    pub doh: DohConfig,
+
+    /// Names with fixed addresses, consulted before any resolution
+    #[serde(default, skip_serializing_if = "HostsConfig::is_empty")]
+    pub hosts: HostsConfig,
+
+    /// Traffic limit that raises quota warnings for the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaConfig>,
+
+    /// Signed protocol library updates fetched from mirrors
+    #[serde(default)]
+    pub protocol_updates: ProtocolUpdatesConfig,
 }
 
 impl Default for NooshdarooConfig {
@@ -58,194 +120,2329 @@ impl Default for NooshdarooConfig {
             server: None,
             detection: DetectionConfig::default(),
             transport: None,
+            protocol_trust: ProtocolTrustConfig::default(),
+            relay: RelayConfig::default(),
+            history: HistoryConfig::default(),
+            experiment: ExperimentConfig::default(),
+            measurements: MeasurementsConfig::default(),
+            path_quality: PathQualityConfig::default(),
+            access: AccessConfig::default(),
+            reverse: None,
+            peer: None,
+            mesh: None,
+            bootstrap: BootstrapConfig::default(),
+            doh: DohConfig::default(),
+            hosts: HostsConfig::default(),
+            quota: None,
+            protocol_updates: ProtocolUpdatesConfig::default(),
         }
     }
 }
 
-/// Operating mode
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum NooshdarooMode {
-    Client,
-    Server,
+/// Relay buffer sizes and backpressure limits
+///
+/// Relay loops move one chunk at a time, so a connection holds at most one
+/// read buffer per direction plus the chunk being delivered. These limits
+/// bound that chunk, how long a slow peer may take to accept it, and how
+/// many tunnels a server runs at once.
+///
+/// Connections that stop carrying data are closed after `idle_timeout`
+/// (keepalives do not count as data) the way the cover protocol would end
+/// them: a TLS close_notify before the FIN on TLS-emulating tunnels, then
+/// up to `drain_timeout` for the peer to finish. DNS tunnels likewise keep
+/// polling for up to `drain_timeout` after the local side closes.
+///
+/// One client address may hold at most `max_sessions_per_client` tunnels
+/// and DNS sessions, and each DNS session at most `session_buffer` bytes of
+/// queries waiting for its handler, so a single client cannot starve the
+/// others; see [`crate::isolation`].
+///
+/// ```toml
+/// [relay]
+/// buffer_size = 16384
+/// max_in_flight = 65536
+/// write_timeout = "30s"
+/// max_connections = 2000
+/// max_sessions_per_client = 32
+/// session_buffer = 262144
+/// idle_timeout = "5m"
+/// drain_timeout = "5s"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Bytes read per relay iteration (segmentation `record_size` takes
+    /// precedence when segmentation control is enabled)
+    #[serde(default = "default_relay_buffer_size")]
+    pub buffer_size: usize,
+
+    /// Largest chunk a connection may hold for delivery to its peer; larger
+    /// tunnel messages close the connection
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+
+    /// Close the connection when a peer does not accept a chunk within this
+    /// time
+    #[serde(default = "default_write_timeout", with = "humantime_serde")]
+    pub write_timeout: Duration,
+
+    /// Maximum concurrent tunnels on the server (0 = unlimited); further
+    /// connections wait in the accept backlog
+    #[serde(default)]
+    pub max_connections: usize,
+
+    /// Tunnels and DNS sessions one client IP address may hold open on the
+    /// server (0 = unlimited); further ones are refused
+    #[serde(default)]
+    pub max_sessions_per_client: usize,
+
+    /// Bytes of queries a DNS session may have waiting for its handler;
+    /// further queries are dropped for the client to retransmit
+    #[serde(default = "default_session_buffer")]
+    pub session_buffer: usize,
+
+    /// Close connections that carry no data for this long (unset = never)
+    #[serde(default, with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Time the peer gets to finish once a connection starts closing
+    #[serde(default = "default_drain_timeout", with = "humantime_serde")]
+    pub drain_timeout: Duration,
+
+    /// Keepalive queries holding NAT mappings of UDP transports open
+    #[serde(default)]
+    pub nat_keepalive: NatKeepaliveConfig,
+
+    /// Record padding on the tunnels of a relay chain
+    #[serde(default)]
+    pub chain_padding: ChainPaddingConfig,
+
+    /// Time-scheduled server ports for UDP transports
+    #[serde(default)]
+    pub port_hopping: PortHoppingConfig,
+
+    /// Forward error correction on UDP transports
+    #[serde(default)]
+    pub fec: FecConfig,
+
+    /// Gaming application profile whose netcode `kcp` datagrams imitate, such
+    /// as "cs2"; see [`crate::game_netcode`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_netcode: Option<String>,
+
+    /// Codec of the voice call `kcp` datagrams travel in; see
+    /// [`crate::rtp_voip`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voip_codec: Option<VoipCodec>,
+
+    /// Frame `kcp` datagrams as WireGuard handshake and transport messages;
+    /// see [`crate::wireguard`]
+    #[serde(default)]
+    pub wireguard: bool,
+
+    /// Frame `kcp` datagrams as OpenVPN UDP packets; see [`crate::openvpn`]
+    #[serde(default)]
+    pub openvpn: bool,
+
+    /// Frame `kcp` datagrams as CoAP requests and observe notifications;
+    /// see [`crate::coap`]
+    #[serde(default)]
+    pub coap: bool,
+
+    /// Register and place the `relay.voip_codec` calls of `kcp` sessions
+    /// with SIP over TCP first; see [`crate::sip`]
+    #[serde(default)]
+    pub sip: bool,
 }
 
-/// Encryption configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EncryptionConfig {
-    /// Cipher to use
-    pub cipher: CipherType,
+fn default_relay_buffer_size() -> usize {
+    8192
+}
 
-    /// Key derivation function
-    pub key_derivation: KdfType,
+fn default_max_in_flight() -> usize {
+    // One maximum-size Noise message
+    65535
+}
 
-    /// Password for key derivation (optional, not currently used by Noise Protocol)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub password: Option<String>,
+fn default_session_buffer() -> usize {
+    256 * 1024
+}
 
-    /// Optional salt
-    pub salt: Option<String>,
+fn default_write_timeout() -> Duration {
+    Duration::from_secs(60)
 }
 
-impl Default for EncryptionConfig {
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl Default for RelayConfig {
     fn default() -> Self {
         Self {
-            cipher: CipherType::ChaCha20Poly1305,
-            key_derivation: KdfType::Argon2,
-            password: None,
-            salt: None,
+            buffer_size: default_relay_buffer_size(),
+            max_in_flight: default_max_in_flight(),
+            write_timeout: default_write_timeout(),
+            max_connections: 0,
+            max_sessions_per_client: 0,
+            session_buffer: default_session_buffer(),
+            idle_timeout: None,
+            drain_timeout: default_drain_timeout(),
+            nat_keepalive: NatKeepaliveConfig::default(),
+            chain_padding: ChainPaddingConfig::default(),
+            port_hopping: PortHoppingConfig::default(),
+            fec: FecConfig::default(),
+            game_netcode: None,
+            voip_codec: None,
+            wireguard: false,
+            openvpn: false,
+            coap: false,
+            sip: false,
         }
     }
 }
 
-/// Cipher types
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub enum CipherType {
-    ChaCha20Poly1305,
-    Aes256Gcm,
+/// NAT keepalive for UDP transports
+///
+/// A UDP tunnel is never silent for longer than the keepalive interval: a
+/// plain query for the tunnel domain goes out first, so stateful NATs and
+/// firewalls keep the mapping the server identifies the session by. The
+/// interval starts at `initial_interval` and adapts between the bounds to
+/// the idle gaps each server's path is seen to survive or not.
+///
+/// ```toml
+/// [relay.nat_keepalive]
+/// enabled = true
+/// initial_interval = "10s"
+/// min_interval = "2s"
+/// max_interval = "25s"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NatKeepaliveConfig {
+    /// Enable keepalive queries
+    #[serde(default = "default_nat_keepalive_enabled")]
+    pub enabled: bool,
+
+    /// Interval before anything has been learned about the path
+    #[serde(default = "default_nat_initial_interval", with = "humantime_serde")]
+    pub initial_interval: Duration,
+
+    /// Shortest interval the estimate may fall to
+    #[serde(default = "default_nat_min_interval", with = "humantime_serde")]
+    pub min_interval: Duration,
+
+    /// Longest interval the estimate may grow to
+    #[serde(default = "default_nat_max_interval", with = "humantime_serde")]
+    pub max_interval: Duration,
 }
 
-/// Key derivation function types
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub enum KdfType {
-    Argon2,
-    Pbkdf2,
+fn default_nat_keepalive_enabled() -> bool {
+    true
+}
+
+fn default_nat_initial_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_nat_min_interval() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_nat_max_interval() -> Duration {
+    // Under the 30s UDP timeout of many consumer NATs
+    Duration::from_secs(25)
+}
+
+impl Default for NatKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_interval: default_nat_initial_interval(),
+            min_interval: default_nat_min_interval(),
+            max_interval: default_nat_max_interval(),
+        }
+    }
+}
+
+/// Scheduled port hopping for UDP transports
+///
+/// The server port of the `dns-udp-tunnel` protocol changes every `slot`,
+/// derived from `key` (shared by client and server) and the time, so
+/// blocking one port only lasts until the next slot; see
+/// [`crate::port_hop`]. A `--multi-port` server follows the same schedule. Client and server clocks must
+/// agree to within a slot. The port in the server address is ignored.
+///
+/// ```toml
+/// [relay.port_hopping]
+/// enabled = true
+/// key = "a shared secret"
+/// port_range = [20000, 60000]
+/// slot = "60s"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortHoppingConfig {
+    /// Hop ports on UDP transports
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Secret the ports are derived from
+    #[serde(default)]
+    pub key: Option<String>,
+
+    /// Ports to hop between (inclusive)
+    #[serde(default = "default_port_hop_range")]
+    pub port_range: (u16, u16),
+
+    /// Time each port is used for
+    #[serde(default = "default_port_hop_slot", with = "humantime_serde")]
+    pub slot: Duration,
+}
+
+fn default_port_hop_range() -> (u16, u16) {
+    (20000, 60000)
+}
+
+fn default_port_hop_slot() -> Duration {
+    Duration::from_secs(60)
+}
+
+impl Default for PortHoppingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key: None,
+            port_range: default_port_hop_range(),
+            slot: default_port_hop_slot(),
+        }
+    }
+}
+
+/// Forward error correction for UDP transports
+///
+/// The datagrams of the `dns-udp-tunnel` protocol are sent in groups of
+/// `group_size`, each followed by Reed-Solomon parity datagrams, so the
+/// receiver rebuilds lost datagrams instead of waiting for the reliability
+/// layer to retransmit them; see [`crate::fec`]. `redundancy` is the
+/// number of parity datagrams per data datagram: 0.25 survives the loss of
+/// any 2 datagrams of a group of 8. A group is closed early once it has
+/// been open for `flush_delay`. Client and server must both enable it; the
+/// server answers with the redundancy the client sends.
+///
+/// ```toml
+/// [relay.fec]
+/// enabled = true
+/// redundancy = 0.25
+/// group_size = 8
+/// flush_delay = "20ms"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FecConfig {
+    /// Add parity datagrams on UDP transports
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Parity datagrams per data datagram
+    #[serde(default = "default_fec_redundancy")]
+    pub redundancy: f64,
+
+    /// Data datagrams per group
+    #[serde(default = "default_fec_group_size")]
+    pub group_size: usize,
+
+    /// Longest a group stays open before its parity is sent
+    #[serde(default = "default_fec_flush_delay", with = "humantime_serde")]
+    pub flush_delay: Duration,
+}
+
+fn default_fec_redundancy() -> f64 {
+    0.25
+}
+
+fn default_fec_group_size() -> usize {
+    8
+}
+
+fn default_fec_flush_delay() -> Duration {
+    Duration::from_millis(20)
+}
+
+impl FecConfig {
+    /// Parity datagrams of a full group
+    pub fn parity_shards(&self) -> usize {
+        (self.group_size as f64 * self.redundancy).ceil() as usize
+    }
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            redundancy: default_fec_redundancy(),
+            group_size: default_fec_group_size(),
+            flush_delay: default_fec_flush_delay(),
+        }
+    }
+}
+
+/// Record padding for relay chains
+///
+/// Every tunnel of a [relay chain](crate::chain) pads its records on its
+/// own: each plaintext is padded up to a multiple of `block` bytes plus up
+/// to `max_extra_blocks` random blocks, so the sizes one hop forwards do
+/// not line up with those the next hop sees. `enabled` decides whether the
+/// client asks for padding; servers always honour the request, padding
+/// their side with their own `block` and `max_extra_blocks`.
+///
+/// ```toml
+/// [relay.chain_padding]
+/// enabled = true
+/// block = 256
+/// max_extra_blocks = 2
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainPaddingConfig {
+    /// Ask every hop to pad records
+    #[serde(default = "default_chain_padding_enabled")]
+    pub enabled: bool,
+
+    /// Padded records are a multiple of this many bytes
+    #[serde(default = "default_chain_padding_block")]
+    pub block: usize,
+
+    /// Most random blocks added on top
+    #[serde(default = "default_chain_padding_extra_blocks")]
+    pub max_extra_blocks: usize,
+}
+
+fn default_chain_padding_enabled() -> bool {
+    true
+}
+
+fn default_chain_padding_block() -> usize {
+    256
+}
+
+fn default_chain_padding_extra_blocks() -> usize {
+    2
+}
+
+impl ChainPaddingConfig {
+    /// Padding this side applies to its records
+    pub fn record_padding(&self) -> crate::noise_transport::RecordPadding {
+        crate::noise_transport::RecordPadding {
+            block: self.block,
+            max_extra_blocks: self.max_extra_blocks,
+        }
+    }
+}
+
+impl Default for ChainPaddingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            block: default_chain_padding_block(),
+            max_extra_blocks: default_chain_padding_extra_blocks(),
+        }
+    }
+}
+
+/// Persistent history of tunnel activity
+///
+/// When enabled (and built with the `history` feature), the client keeps an
+/// SQLite database of hourly transfer and handshake failures per protocol,
+/// protocol rotations and path-test results, queried with
+/// `nooshdaroo stats --since 24h`.
+///
+/// ```toml
+/// [history]
+/// enabled = true
+/// path = "/var/lib/nooshdaroo/history.db"
+/// sample_interval = "1m"
+/// retention = "30days"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Database file
+    #[serde(default = "default_history_path")]
+    pub path: PathBuf,
+
+    /// How often live counters are written to the database
+    #[serde(default = "default_history_sample_interval", with = "humantime_serde")]
+    pub sample_interval: Duration,
+
+    /// Records older than this are deleted
+    #[serde(default = "default_history_retention", with = "humantime_serde")]
+    pub retention: Duration,
+}
+
+fn default_history_path() -> PathBuf {
+    PathBuf::from("nooshdaroo-history.db")
+}
+
+fn default_history_sample_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_history_retention() -> Duration {
+    Duration::from_secs(30 * 24 * 3600)
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_history_path(),
+            sample_interval: default_history_sample_interval(),
+            retention: default_history_retention(),
+        }
+    }
+}
+
+/// A/B comparison of candidate protocols
+///
+/// When enabled, the client tunnels `fraction` of new connections over the
+/// candidate `protocols` in turn and records per-protocol success rates,
+/// handshake times and throughput (see [`crate::experiment`]). Once every
+/// candidate has `min_samples` connections the best one carries all new
+/// connections. Results, without addresses or destinations, are written to
+/// `report` as JSON for sharing.
+///
+/// ```toml
+/// [experiment]
+/// enabled = true
+/// protocols = ["https", "quic", "dns"]
+/// fraction = 0.2
+/// min_samples = 30
+/// report = "experiment.json"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExperimentConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Candidate protocols
+    #[serde(default)]
+    pub protocols: Vec<String>,
+
+    /// Share of new connections taking part until the experiment converges
+    #[serde(default = "default_experiment_fraction")]
+    pub fraction: f64,
+
+    /// Connections per candidate before picking the best
+    #[serde(default = "default_experiment_min_samples")]
+    pub min_samples: u32,
+
+    /// JSON file the results are written to
+    #[serde(default)]
+    pub report: Option<PathBuf>,
+}
+
+fn default_experiment_fraction() -> f64 {
+    0.1
+}
+
+fn default_experiment_min_samples() -> u32 {
+    30
+}
+
+impl Default for ExperimentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocols: Vec::new(),
+            fraction: default_experiment_fraction(),
+            min_samples: default_experiment_min_samples(),
+            report: None,
+        }
+    }
+}
+
+/// Anonymized connectivity measurements
+///
+/// When enabled, the client appends one measurement per tunnel attempt to
+/// `path`, one JSON object per line in OONI's base data format (see
+/// [`crate::measurement`]): the protocol, whether the tunnel came up, how
+/// it failed and how long it took. Server and destination addresses and
+/// the client's IP are never written; country and network are only as
+/// precise as `probe_cc` and `probe_asn`. The file can be reviewed and then
+/// submitted to OONI voluntarily.
+///
+/// ```toml
+/// [measurements]
+/// enabled = true
+/// path = "nooshdaroo-measurements.jsonl"
+/// probe_cc = "IR"
+/// probe_asn = "AS197207"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeasurementsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// JSON lines file measurements are appended to
+    #[serde(default = "default_measurements_path")]
+    pub path: PathBuf,
+
+    /// Two-letter country code, "ZZ" when not disclosed
+    #[serde(default = "default_probe_cc")]
+    pub probe_cc: String,
+
+    /// Network the client is on, "AS0" when not disclosed
+    #[serde(default = "default_probe_asn")]
+    pub probe_asn: String,
+}
+
+fn default_measurements_path() -> PathBuf {
+    PathBuf::from("nooshdaroo-measurements.jsonl")
+}
+
+fn default_probe_cc() -> String {
+    "ZZ".to_string()
+}
+
+fn default_probe_asn() -> String {
+    "AS0".to_string()
+}
+
+impl Default for MeasurementsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_measurements_path(),
+            probe_cc: default_probe_cc(),
+            probe_asn: default_probe_asn(),
+        }
+    }
+}
+
+/// Tunnel path quality measurement
+///
+/// When enabled, the client keeps a tunnel to each server that carries
+/// only ping frames, sent every `interval` and echoed by the server (see
+/// [`crate::path_quality`]). A ping not echoed within `timeout` counts as
+/// lost. RTT, jitter and loss over the last `window` pings are reported
+/// per server in the client's stats.
+///
+/// ```toml
+/// [path_quality]
+/// enabled = true
+/// interval = "10s"
+/// timeout = "5s"
+/// window = 60
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathQualityConfig {
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Time between pings
+    #[serde(default = "default_path_quality_interval", with = "humantime_serde")]
+    pub interval: Duration,
+
+    /// Time after which an unanswered ping counts as lost
+    #[serde(default = "default_path_quality_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+
+    /// Pings the figures are computed over
+    #[serde(default = "default_path_quality_window")]
+    pub window: usize,
+}
+
+fn default_path_quality_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_path_quality_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_path_quality_window() -> usize {
+    60
+}
+
+impl Default for PathQualityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: default_path_quality_interval(),
+            timeout: default_path_quality_timeout(),
+            window: default_path_quality_window(),
+        }
+    }
+}
+
+/// Reverse tunnels, agent side (see [`crate::reverse`])
+///
+/// `nooshdaroo agent` dials `server` with `protocol` and the Noise
+/// settings of `[transport]`, and asks it to listen on each service's
+/// `remote_port`; connections there are carried back and forwarded to the
+/// service's `local` address. The agent reconnects after `retry_interval`
+/// when the server goes away.
+///
+/// ```toml
+/// [reverse]
+/// server = "203.0.113.10:443"
+/// secret = "long random string shared with the agents"
+///
+/// [[reverse.services]]
+/// name = "ssh"
+/// local = "127.0.0.1:22"
+/// remote_port = 10022
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReverseAgentConfig {
+    pub server: SocketAddr,
+
+    pub secret: String,
+
+    #[serde(default = "default_reverse_protocol")]
+    pub protocol: String,
+
+    pub services: Vec<ReverseServiceConfig>,
+
+    #[serde(default = "default_reverse_retry_interval", with = "humantime_serde")]
+    pub retry_interval: Duration,
+}
+
+/// A local service exposed through the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReverseServiceConfig {
+    pub name: String,
+
+    /// Address the service listens on, such as "127.0.0.1:22"
+    pub local: String,
+
+    /// Port it is exposed on at the server
+    pub remote_port: u16,
+}
+
+fn default_reverse_protocol() -> String {
+    "https".to_string()
+}
+
+fn default_reverse_retry_interval() -> Duration {
+    Duration::from_secs(5)
 }
 
-/// SOCKS5 server configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SocksConfig {
-    /// Listen address for SOCKS5 server
-    pub listen_addr: SocketAddr,
+/// A direct tunnel to another client (see [`crate::punch`])
+///
+/// `nooshdaroo peer` meets the client holding `peer_public_key` at a
+/// server's `rendezvous` and punches a UDP path to it, falling back to the
+/// server relaying the datagrams when no direct path opens within
+/// `punch_timeout` and `relay` is set. The peers authenticate each other
+/// with their Noise keys. One side sets `listen` and carries connections
+/// accepted there to the other, which sets `forward` and connects them to
+/// that address.
+///
+/// ```toml
+/// [peer]
+/// rendezvous = "203.0.113.10:3479"
+/// private_key = "base64..."
+/// peer_public_key = "base64..."
+/// listen = "127.0.0.1:2222"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerConfig {
+    pub rendezvous: SocketAddr,
+
+    /// This client's Noise private key (base64)
+    pub private_key: String,
+
+    /// The other client's Noise public key (base64)
+    pub peer_public_key: String,
+
+    /// Address local connections to the peer are accepted on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<SocketAddr>,
+
+    /// Address connections from the peer are forwarded to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward: Option<String>,
+
+    #[serde(default = "default_punch_timeout", with = "humantime_serde")]
+    pub punch_timeout: Duration,
+
+    #[serde(default = "default_peer_relay")]
+    pub relay: bool,
+}
+
+/// Friend-to-friend relaying (see [`crate::mesh`])
+///
+/// `nooshdaroo mesh` keeps this node reachable by each of `peers` through
+/// hole punching at `rendezvous`, and carries connections for them: to
+/// their destinations when `exit` is set, otherwise on to a peer marked
+/// `exit`. Connections to the `socks` port take the same route.
+///
+/// ```toml
+/// [mesh]
+/// rendezvous = "203.0.113.10:3479"
+/// private_key = "base64..."
+/// socks = "127.0.0.1:1081"
+///
+/// [[mesh.peers]]
+/// name = "sara"
+/// public_key = "base64..."
+/// exit = true
+/// bandwidth = 250000
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeshConfig {
+    pub rendezvous: SocketAddr,
+
+    /// This node's Noise private key (base64)
+    pub private_key: String,
+
+    /// Local SOCKS5 proxy routing this node's own connections through the
+    /// mesh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socks: Option<SocketAddr>,
+
+    /// Open connections to destinations for peers
+    #[serde(default)]
+    pub exit: bool,
+
+    /// SOCKS5 proxy exit connections are opened through, such as this
+    /// machine's own nooshdaroo client (default: directly)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_proxy: Option<SocketAddr>,
+
+    pub peers: Vec<MeshPeerConfig>,
+
+    #[serde(default = "default_punch_timeout", with = "humantime_serde")]
+    pub punch_timeout: Duration,
+
+    /// Let the rendezvous relay sessions that cannot punch through
+    #[serde(default = "default_peer_relay")]
+    pub relay: bool,
+}
+
+/// A trusted client of the mesh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeshPeerConfig {
+    pub name: String,
+
+    /// Its Noise public key (base64)
+    pub public_key: String,
+
+    /// It carries connections to destinations, itself or through another
+    /// peer
+    #[serde(default)]
+    pub exit: bool,
+
+    /// Bytes per second its connections through this node may use, both
+    /// ways together (0 = unlimited)
+    #[serde(default)]
+    pub bandwidth: u64,
+}
+
+fn default_punch_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_peer_relay() -> bool {
+    true
+}
+
+/// Destination access control (see [`crate::acl`])
+///
+/// Rules are tried in order and the first one matching a destination
+/// decides; destinations no rule matches get `default`. A rule matches when
+/// the host is one of `hosts` (`*` and `?` wildcards) or the address within
+/// one of `cidrs`, and the port is one of `ports` (single ports or ranges);
+/// a criterion left out matches anything.
+///
+/// The client checks SOCKS requests before tunneling them, matching `cidrs`
+/// only against requests for an address. The server checks again against
+/// every address a host resolves to.
+///
+/// Whatever the rules say, the server does not connect to loopback,
+/// private, link-local and other reserved addresses, which would let
+/// clients into its own network, except those within `allow_private`.
+///
+/// ```toml
+/// [access]
+/// default = "deny"
+/// allow_private = ["10.20.0.0/16"]
+///
+/// # No mail from the proxy
+/// [[access.rules]]
+/// action = "deny"
+/// ports = ["25", "465", "587"]
+///
+/// [[access.rules]]
+/// action = "allow"
+/// ports = ["80", "443", "8000-8999"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct AccessConfig {
+    #[serde(default)]
+    pub default: AccessAction,
+
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+
+    /// Reserved address ranges the server may connect to anyway
+    #[serde(default)]
+    pub allow_private: Vec<String>,
+}
+
+/// One access control rule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccessRule {
+    pub action: AccessAction,
+
+    /// Host names, such as "*.example.com"
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Address ranges, such as "10.0.0.0/8" or "2001:db8::/32"
+    #[serde(default)]
+    pub cidrs: Vec<String>,
+
+    /// Ports, such as "443" or "8000-8999"
+    #[serde(default)]
+    pub ports: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Operating mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NooshdarooMode {
+    Client,
+    Server,
+}
+
+/// Encryption configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// Cipher to use
+    pub cipher: CipherType,
+
+    /// Key derivation function
+    pub key_derivation: KdfType,
+
+    /// Password for key derivation (optional, not currently used by Noise Protocol)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    /// Optional salt
+    pub salt: Option<String>,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            cipher: CipherType::ChaCha20Poly1305,
+            key_derivation: KdfType::Argon2,
+            password: None,
+            salt: None,
+        }
+    }
+}
+
+/// Cipher types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CipherType {
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+/// Key derivation function types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KdfType {
+    Argon2,
+    Pbkdf2,
+}
+
+/// SOCKS5 server configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocksConfig {
+    /// Listen address(es) for SOCKS5 server
+    pub listen_addr: ListenAddrs,
+
+    /// Remote server address for tunneling (client mode), or a relay chain
+    /// such as `"192.0.2.1:443 -> ssh@relay.example.net:22 -> 203.0.113.7:443"`
+    /// (see [`crate::chain`])
+    pub server_address: Option<String>,
+
+    /// Transport type (TCP, UDP, KCP, chunked HTTP, SMTP, RDP, BitTorrent, FTP, gRPC, OpenVPN, MQTT, SIP, RTSP, SMB, syslog or Steam)
+    /// For Iran censorship bypass, use UDP on port 53
+    #[serde(default)]
+    pub transport: TransportType,
+
+    /// Require authentication
+    pub auth_required: bool,
+
+    /// Username for authentication
+    pub username: Option<String>,
+
+    /// Password for authentication
+    pub password: Option<String>,
+
+    /// Additional upstream servers for client-side load balancing.
+    /// When non-empty, `server_address` (if set) is treated as one more entry.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<UpstreamServerConfig>,
+
+    /// Seconds between background health checks of upstream servers
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval_secs: u64,
+
+    /// Seconds allowed for connecting to a server and completing the tunnel
+    /// handshake before the attempt fails over to the next server
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout_secs: u64,
+
+    /// File where the client keeps its last working protocol, port and
+    /// upstream health, so a restart reuses that path instead of
+    /// rediscovering one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_file: Option<PathBuf>,
+
+    /// Seconds a saved session stays usable
+    #[serde(default = "default_session_max_age")]
+    pub session_max_age_secs: u64,
+
+    /// `Host` header sent with the `http-chunked` and `steam` transports,
+    /// `:authority` of the `grpc` transport's call, TLS server name of
+    /// OpenVPN and `syslog` sessions, SIP domain of `sip` calls and server
+    /// name in the share path of `smb` sessions (defaults to the server's IP
+    /// address)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_host: Option<String>,
+
+    /// User name in the `rdp` transport's routing cookie (omitted if unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdp_username: Option<String>,
+
+    /// MQTT version of the `mqtt` transport's session ("3.1.1" or "5")
+    #[serde(default)]
+    pub mqtt_version: MqttVersion,
+
+    /// Relay `kcp` sessions through this TURN server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turn: Option<TurnConfig>,
+
+    /// Reach the server through this SOCKS5 or HTTP proxy, such as Tor or
+    /// a corporate proxy (TCP-based transports only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_proxy: Option<UpstreamProxyConfig>,
+
+    /// Without `upstream_proxy`, let this proxy auto-config script choose
+    /// the proxy to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_proxy_pac: Option<PacConfig>,
+
+    /// Without `upstream_proxy` or `upstream_proxy_pac`, use the proxy in
+    /// `HTTPS_PROXY` or `ALL_PROXY`, honouring `NO_PROXY`
+    #[serde(default)]
+    pub upstream_proxy_from_env: bool,
+
+    /// Block plaintext DNS leaving the host outside the tunnel with
+    /// firewall rules (see [`crate::dns_leak`]); needs administrator rights
+    #[serde(default)]
+    pub dns_leak_protection: bool,
+
+    /// Block traffic outside the tunnel while it is down (see
+    /// [`crate::kill_switch`]); needs administrator rights
+    #[serde(default)]
+    pub kill_switch: bool,
+
+    /// Also accept HTTP CONNECT over TLS, for browsers' "secure proxy"
+    /// setting and devices elsewhere on the network
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https: Option<HttpsProxyConfig>,
+
+    /// Answer DNS locally, resolving through the tunnel with a cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DnsProxyConfig>,
+}
+
+/// Tunneled DNS with a cache (see [`crate::dns_cache`])
+///
+/// ```toml
+/// [socks.dns]
+/// listen = "127.0.0.1:53"
+/// upstream = "1.1.1.1:53"
+/// max_entries = 4096
+/// negative_ttl = "1m"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsProxyConfig {
+    pub listen: SocketAddr,
+
+    /// Resolver the tunnel carries queries to, over TCP
+    #[serde(default = "default_dns_upstream")]
+    pub upstream: SocketAddr,
+
+    #[serde(default = "default_dns_cache_entries")]
+    pub max_entries: usize,
+
+    /// Longest time an answer is cached, whatever its TTL
+    #[serde(default = "default_dns_max_ttl", with = "humantime_serde")]
+    pub max_ttl: Duration,
+
+    /// How long NXDOMAIN and empty answers are cached at most
+    #[serde(default = "default_dns_negative_ttl", with = "humantime_serde")]
+    pub negative_ttl: Duration,
+}
+
+fn default_dns_upstream() -> SocketAddr {
+    SocketAddr::from(([1, 1, 1, 1], 53))
+}
+
+fn default_dns_cache_entries() -> usize {
+    4096
+}
+
+fn default_dns_max_ttl() -> Duration {
+    Duration::from_secs(24 * 3600)
+}
+
+fn default_dns_negative_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// HTTPS proxy listener (see [`crate::https_proxy`])
+///
+/// The certificate and key are generated on first start if `cert` does not
+/// exist yet; import `cert` into the browsers and devices using the proxy.
+/// With `socks.auth_required`, clients must send `socks.username` and
+/// `socks.password` as Basic proxy credentials.
+///
+/// ```toml
+/// [socks.https]
+/// listen = "0.0.0.0:8443"
+/// cert = "/etc/nooshdaroo/proxy.crt"
+/// key = "/etc/nooshdaroo/proxy.key"
+/// hostnames = ["proxy.lan", "192.168.1.20"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpsProxyConfig {
+    pub listen: SocketAddr,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+
+    /// Names and addresses a generated certificate is valid for
+    #[serde(default = "default_https_hostnames")]
+    pub hostnames: Vec<String>,
+}
+
+fn default_https_hostnames() -> Vec<String> {
+    vec!["localhost".to_string(), "127.0.0.1".to_string()]
+}
+
+fn default_health_check_interval() -> u64 {
+    30
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_session_max_age() -> u64 {
+    24 * 3600
+}
+
+impl Default for SocksConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: ListenAddrs::from("127.0.0.1:1080".parse::<SocketAddr>().unwrap()),
+            server_address: None,
+            transport: TransportType::default(),
+            auth_required: false,
+            username: None,
+            password: None,
+            servers: Vec::new(),
+            health_check_interval_secs: default_health_check_interval(),
+            connect_timeout_secs: default_connect_timeout(),
+            session_file: None,
+            session_max_age_secs: default_session_max_age(),
+            http_host: None,
+            rdp_username: None,
+            mqtt_version: MqttVersion::default(),
+            turn: None,
+            upstream_proxy: None,
+            upstream_proxy_pac: None,
+            upstream_proxy_from_env: false,
+            dns_leak_protection: false,
+            kill_switch: false,
+            https: None,
+            dns: None,
+        }
+    }
+}
+
+/// Addresses a listener binds: one, as `listen_addr = "0.0.0.0:443"`, or
+/// several, as `listen_addr = ["0.0.0.0:443", "[::]:443"]` for dual-stack
+/// or specific interfaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "OneOrMore", into = "OneOrMore")]
+pub struct ListenAddrs(Vec<SocketAddr>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OneOrMore {
+    One(SocketAddr),
+    More(Vec<SocketAddr>),
+}
+
+impl ListenAddrs {
+    /// The first address, used where only one can be bound
+    pub fn first(&self) -> SocketAddr {
+        self.0[0]
+    }
+
+    /// All the addresses
+    pub fn iter(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.0.iter()
+    }
+
+    /// Number of addresses
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Always false: there is at least one address
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<SocketAddr> for ListenAddrs {
+    fn from(addr: SocketAddr) -> Self {
+        Self(vec![addr])
+    }
+}
+
+impl TryFrom<OneOrMore> for ListenAddrs {
+    type Error = String;
+
+    fn try_from(addrs: OneOrMore) -> Result<Self, Self::Error> {
+        match addrs {
+            OneOrMore::One(addr) => Ok(addr.into()),
+            OneOrMore::More(addrs) if addrs.is_empty() => Err("listen_addr must list at least one address".to_string()),
+            OneOrMore::More(addrs) => Ok(Self(addrs)),
+        }
+    }
+}
+
+impl From<ListenAddrs> for OneOrMore {
+    fn from(addrs: ListenAddrs) -> Self {
+        match addrs.0.as_slice() {
+            [addr] => OneOrMore::One(*addr),
+            _ => OneOrMore::More(addrs.0),
+        }
+    }
+}
+
+/// Comma-separated addresses, as given to `--bind`
+impl std::str::FromStr for ListenAddrs {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addrs = s.split(',').map(|addr| addr.trim().parse()).collect::<Result<Vec<SocketAddr>, _>>()?;
+        Ok(Self(addrs))
+    }
+}
+
+impl std::fmt::Display for ListenAddrs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addrs: Vec<String> = self.0.iter().map(SocketAddr::to_string).collect();
+        write!(f, "{}", addrs.join(", "))
+    }
+}
+
+/// Proxy the client connects to its server through
+///
+/// ```toml
+/// [socks.upstream_proxy]
+/// address = "127.0.0.1:9050"
+/// username = "alice"
+/// password = "secret"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamProxyConfig {
+    #[serde(default)]
+    pub protocol: ProxyProtocol,
+
+    /// Proxy address (host:port)
+    pub address: String,
+
+    /// How to authenticate to an HTTP proxy
+    #[serde(default)]
+    pub auth: ProxyAuth,
+
+    /// User name for username/password authentication (RFC 1929) or HTTP
+    /// proxy authentication, as `DOMAIN\user` for NTLM; without one the
+    /// proxy must accept clients without authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// Proxy auto-config (PAC) script choosing the client's upstream proxy
+///
+/// Only a JavaScript subset is understood (no loops or regular expressions)
+/// unless the client is built with the `pac-js` feature. A script that
+/// cannot be fetched or run blocks connections to the server instead of
+/// letting them go direct.
+///
+/// ```toml
+/// [socks.upstream_proxy_pac]
+/// url = "http://wpad.corp.example/wpad.dat"
+/// auth = "ntlm"
+/// username = "CORP\\alice"
+/// password = "secret"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PacConfig {
+    /// `http://` URL or path of the script
+    pub url: String,
+
+    /// How to authenticate to the HTTP proxies the script picks
+    #[serde(default)]
+    pub auth: ProxyAuth,
+
+    /// Credentials for the HTTP proxies the script picks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// How the client asks an upstream proxy for a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// SOCKS5 `CONNECT`
+    #[default]
+    Socks5,
+    /// HTTP `CONNECT`
+    Http,
+}
+
+/// Authentication scheme offered to an upstream HTTP proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyAuth {
+    #[default]
+    Basic,
+    /// NTLMv2 challenge-response
+    Ntlm,
+    /// Kerberos through the system GSSAPI library (feature "kerberos", Unix)
+    /// when a ticket is cached, else NTLMv2 tokens under `Negotiate`
+    Negotiate,
+}
+
+/// A single upstream server entry (client mode)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamServerConfig {
+    /// Server address (`ip:port` or `host:port`, resolved like
+    /// `server_address`)
+    pub address: String,
+
+    /// Server's Noise static public key (base64). Falls back to
+    /// `[transport].remote_public_key` when omitted.
+    #[serde(default)]
+    pub public_key: Option<String>,
+
+    /// Protocols this server accepts. Empty means any protocol.
+    #[serde(default)]
+    pub protocols: Vec<String>,
+
+    /// Relative weight when distributing connections
+    #[serde(default = "default_server_weight")]
+    pub weight: u32,
+}
+
+fn default_server_weight() -> u32 {
+    1
+}
+
+/// Shape-shifting configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShapeShiftConfig {
+    /// Shape-shifting strategy
+    pub strategy: StrategyType,
+
+    /// Protocol hops inside established tunnels
+    #[serde(default)]
+    pub hopping: ProtocolHopConfig,
+}
+
+impl Default for ShapeShiftConfig {
+    fn default() -> Self {
+        Self {
+            strategy: StrategyType::default(),
+            hopping: ProtocolHopConfig::default(),
+        }
+    }
+}
+
+/// Protocol hops inside established tunnels
+///
+/// Rotation only changes the protocol of new connections. With hopping
+/// enabled, the client also moves each tunnel to another protocol every
+/// `interval_bytes` relayed, and when the strategy rotates: a hop control
+/// message switches the protocol wrapper, or TLS session emulation, on both
+/// ends at an agreed message boundary (see [`crate::protocol_hop`]). Hops
+/// are agreed with the server when the tunnel opens; relay chains and UDP
+/// transports never hop.
+///
+/// ```toml
+/// [shapeshift.hopping]
+/// enabled = true
+/// interval_bytes = 8388608
+/// protocols = ["https", "http", "ssh"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolHopConfig {
+    /// Negotiate hops on new tunnels
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Bytes relayed in either direction between hops
+    #[serde(default = "default_hop_interval_bytes")]
+    pub interval_bytes: u64,
+
+    /// Protocols to hop between; when empty, TLS protocols hop between TLS
+    /// session emulation and their protocol wrapper
+    #[serde(default)]
+    pub protocols: Vec<String>,
+}
+
+fn default_hop_interval_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+impl Default for ProtocolHopConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_bytes: default_hop_interval_bytes(),
+            protocols: Vec::new(),
+        }
+    }
+}
+
+/// Traffic shaping configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficShapingConfig {
+    /// Enable traffic shaping
+    pub enabled: bool,
+
+    /// Packet size distribution
+    pub packet_size_distribution: DistributionType,
+
+    /// Mean packet size (bytes)
+    pub mean_packet_size: usize,
+
+    /// Standard deviation for packet size
+    pub stddev_packet_size: usize,
+
+    /// Mean inter-packet delay (microseconds)
+    pub mean_delay: u64,
+
+    /// Standard deviation for delay
+    pub stddev_delay: u64,
+
+    /// Enable burst mode
+    pub enable_bursts: bool,
+
+    /// Burst size (packets)
+    pub burst_size: usize,
+
+    /// Burst probability (0.0 - 1.0)
+    pub burst_probability: f64,
+
+    /// Per-protocol timing jitter on tunnel writes
+    #[serde(default)]
+    pub jitter: JitterConfig,
+
+    /// TCP segment sizes of tunnel sockets
+    #[serde(default)]
+    pub segmentation: SegmentationConfig,
+
+    /// TCP/IP stack fingerprint of tunnel sockets
+    #[serde(default)]
+    pub tcp_fingerprint: TcpFingerprintConfig,
+
+    /// Outbound TTL / hop limit of tunnel sockets
+    #[serde(default)]
+    pub ttl: TtlConfig,
+
+    /// Protocol-native keepalives on idle tunnels
+    #[serde(default)]
+    pub keepalive: KeepaliveConfig,
+}
+
+impl Default for TrafficShapingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            packet_size_distribution: DistributionType::Normal,
+            mean_packet_size: 1400,
+            stddev_packet_size: 200,
+            mean_delay: 50,
+            stddev_delay: 20,
+            enable_bursts: false,
+            burst_size: 5,
+            burst_probability: 0.1,
+            jitter: JitterConfig::default(),
+            segmentation: SegmentationConfig::default(),
+            tcp_fingerprint: TcpFingerprintConfig::default(),
+            ttl: TtlConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+        }
+    }
+}
+
+/// Timing jitter applied in the relay loops
+///
+/// Writes to the tunnel are paced so the gaps between them follow a
+/// distribution typical of the cover protocol (resolver query spacing for
+/// DNS, keystroke timing for SSH). Writes that are already further apart
+/// than the sampled gap are not delayed.
+///
+/// ```toml
+/// [traffic_shaping.jitter]
+/// enabled = true
+///
+/// [traffic_shaping.jitter.protocols.ssh]
+/// distribution = "normal"
+/// mean_delay = 40000
+/// stddev_delay = 25000
+/// max_delay = 200000
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JitterConfig {
+    /// Enable jitter injection
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Overrides of the built-in distributions, keyed by protocol family
+    /// (`dns`, `ssh`, `https`, `quic`, ...) or full protocol id
+    #[serde(default)]
+    pub protocols: HashMap<String, JitterDistribution>,
+}
+
+/// TCP segmentation control for tunnel sockets
+///
+/// By default the relay hands the kernel up to 8 KiB at a time and lets it
+/// cut segments wherever it likes. When enabled, each tunnel write is a
+/// single protocol record sized to fit one segment, so packet sizes on the
+/// wire follow the emulated protocol.
+///
+/// ```toml
+/// [traffic_shaping.segmentation]
+/// enabled = true
+/// mss = 1360
+/// notsent_lowat = 16384
+/// coalesce_window = 2000
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SegmentationConfig {
+    /// Enable segmentation control
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Clamp the TCP maximum segment size (TCP_MAXSEG)
+    #[serde(default)]
+    pub mss: Option<u32>,
+
+    /// Plaintext bytes per tunnel write (defaults to what fits one `mss` segment)
+    #[serde(default)]
+    pub record_size: Option<usize>,
+
+    /// Cap unsent data queued in the kernel (TCP_NOTSENT_LOWAT; Linux and macOS)
+    #[serde(default)]
+    pub notsent_lowat: Option<u32>,
+
+    /// Wait up to this long for more data before sending a partial record,
+    /// so small writes are merged into one tunnel record (microseconds, 0 = off)
+    #[serde(default)]
+    pub coalesce_window: u64,
+}
+
+/// TCP/IP stack fingerprint of tunnel sockets
+///
+/// Passive fingerprinting (p0f and friends) reads the initial TTL, MSS,
+/// window and option layout of SYN and SYN-ACK packets, so a Linux host
+/// emulating a Windows browser is visible below the protocol layer. The
+/// signature is either a preset name (`windows`, `linux`, `macos`, `ios`,
+/// `android`, `freebsd`) or a p0f v3 signature string.
+///
+/// Only the TTL, MSS and window clamp of tunnel sockets are set; their TCP
+/// option layout, window scale and timestamps stay the kernel's.
+///
+/// ```toml
+/// [traffic_shaping.tcp_fingerprint]
+/// enabled = true
+/// signature = "windows"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TcpFingerprintConfig {
+    /// Enable fingerprint camouflage
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Preset name or p0f v3 signature
+    #[serde(default = "default_tcp_signature")]
+    pub signature: String,
+
+    /// Clamp the receive window to the signature's SYN window
+    /// (TCP_WINDOW_CLAMP; Linux). This also caps the window scale and with it
+    /// throughput on high-latency paths, so it is off by default.
+    #[serde(default)]
+    pub clamp_window: bool,
+}
+
+fn default_tcp_signature() -> String {
+    "windows".to_string()
+}
+
+impl Default for TcpFingerprintConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            signature: default_tcp_signature(),
+            clamp_window: false,
+        }
+    }
+}
+
+/// Outbound TTL / hop limit of tunnel sockets
+///
+/// Every connection from one host leaving with the same TTL is a stable
+/// identifier across flows. When enabled, each tunnel socket gets the base
+/// TTL lowered by a random amount up to `variation`, which reads as a
+/// slightly different hop distance while keeping the initial-TTL guess of
+/// the fingerprint intact. A low base TTL (see the suggestion printed by
+/// `locate-blocking`) lets packets reach the server but expire before
+/// inspection points further along the path.
+///
+/// ```toml
+/// [traffic_shaping.ttl]
+/// enabled = true
+/// ttl = 64
+/// variation = 3
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TtlConfig {
+    /// Enable TTL control
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Base TTL (defaults to the TCP fingerprint's initial TTL, else 64)
+    #[serde(default)]
+    pub ttl: Option<u8>,
+
+    /// Maximum random reduction applied per connection
+    #[serde(default)]
+    pub variation: u8,
+}
+
+/// Protocol-native keepalives on idle tunnels
+///
+/// A tunnel with nothing to relay goes completely silent, while real TLS,
+/// SSH and DNS sessions keep trickling idle traffic (padding records,
+/// `SSH_MSG_IGNORE`, SOA refresh queries). When enabled, a tunnel idle for
+/// the cover protocol's keepalive interval sends an empty message framed by
+/// its protocol wrapper, which the far end drops. Intervals are keyed by
+/// protocol family or full protocol id, in seconds (0 = no keepalive).
+///
+/// ```toml
+/// [traffic_shaping.keepalive]
+/// enabled = true
+///
+/// [traffic_shaping.keepalive.intervals]
+/// ssh = 15
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeepaliveConfig {
+    /// Enable idle keepalives
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Overrides of the built-in intervals (seconds)
+    #[serde(default)]
+    pub intervals: HashMap<String, u64>,
+}
+
+/// Distribution of the gap between consecutive tunnel writes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JitterDistribution {
+    /// Shape of the distribution
+    pub distribution: DistributionType,
+
+    /// Mean gap (microseconds)
+    pub mean_delay: u64,
+
+    /// Standard deviation (microseconds; spread for uniform)
+    #[serde(default)]
+    pub stddev_delay: u64,
+
+    /// Upper bound on any single delay (microseconds)
+    pub max_delay: u64,
+}
+
+/// Statistical distribution types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistributionType {
+    Normal,
+    Uniform,
+    Exponential,
+}
+
+/// Audio codec of an emulated voice call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoipCodec {
+    Opus,
+    /// G.711 µ-law
+    Pcmu,
+    /// G.711 A-law
+    Pcma,
+}
+
+/// MQTT version the `mqtt` transport speaks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MqttVersion {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+/// Transport type (TCP or UDP)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportType {
+    Tcp,
+    Udp,
+    /// Reliable stream over plain UDP (see [`crate::kcp_transport`])
+    Kcp,
+    /// Chunked HTTP/1.1 bodies over TCP (see [`crate::http_chunked`])
+    #[serde(rename = "http-chunked")]
+    HttpChunked,
+    /// SMTP session upgraded with STARTTLS over TCP (see [`crate::smtp_transport`])
+    Smtp,
+    /// RDP session negotiated over TCP (see [`crate::rdp_transport`])
+    Rdp,
+    /// BitTorrent peer connection over TCP (see [`crate::bittorrent_transport`])
+    Bittorrent,
+    /// FTP session with passive data connections (see [`crate::ftp_transport`])
+    Ftp,
+    /// Bidirectional gRPC stream over HTTP/2 (see [`crate::grpc_transport`])
+    Grpc,
+    /// OpenVPN session over TCP (see [`crate::openvpn`])
+    Openvpn,
+    /// MQTT session of an IoT device (see [`crate::mqtt_transport`])
+    Mqtt,
+    /// SIP registration and call of a VoIP phone (see [`crate::sip`])
+    Sip,
+    /// RTSP session of an IP camera (see [`crate::rtsp_transport`])
+    Rtsp,
+    /// SMB2 session with a file server (see [`crate::smb_transport`])
+    Smb,
+    /// Syslog shipped over TLS to a log collector (see [`crate::syslog_transport`])
+    Syslog,
+    /// Steam game download from a content server (see [`crate::steam_cdn`])
+    Steam,
+}
+
+impl Default for TransportType {
+    fn default() -> Self {
+        Self::Tcp // Default to TCP for backwards compatibility
+    }
+}
+
+/// Server configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// Listen address(es)
+    pub listen_addr: ListenAddrs,
+
+    /// Transport type (TCP, UDP, KCP, chunked HTTP, SMTP, RDP, BitTorrent, FTP, gRPC, OpenVPN, MQTT, SIP, RTSP, SMB, syslog or Steam)
+    /// For Iran censorship bypass, use UDP on port 53
+    #[serde(default)]
+    pub transport: TransportType,
+
+    /// Serve TCP tunnels from io_uring worker threads
+    /// (requires building with the `io-uring` feature; Linux only)
+    #[serde(default)]
+    pub io_uring: bool,
+
+    /// Number of io_uring worker threads (0 = one per CPU)
+    #[serde(default)]
+    pub io_uring_workers: usize,
+
+    /// Hostname in the `smtp` transport's greeting and `EHLO` reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp_hostname: Option<String>,
+
+    /// Address the `ftp` transport advertises for data connections, when
+    /// the server is behind NAT (default: the address clients connected to)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ftp_passive_address: Option<std::net::IpAddr>,
+
+    /// TURN server to run in front of a `kcp` listener
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub turn: Option<TurnServerConfig>,
+
+    /// Graceful shutdown on `SIGUSR2` or `nooshdaroo drain`
+    #[serde(default)]
+    pub drain: DrainConfig,
+
+    /// Servers sharing UDP DNS-tunnel sessions and quotas behind a load
+    /// balancer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<ClusterConfig>,
+
+    /// Privacy-aware log of the tunnels the server carried
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<AuditConfig>,
+
+    /// Public ports agents behind NAT may expose their services on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverse: Option<ReverseServerConfig>,
+
+    /// Where clients meet to punch direct paths to each other
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendezvous: Option<RendezvousConfig>,
+
+    /// Serve as an obfuscated bridge for the local Tor relay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tor: Option<TorBridgeConfig>,
+}
+
+/// Reverse tunnels, server side (see [`crate::reverse`])
+///
+/// Agents that know `secret` may have the server listen on ports within
+/// `ports` on `bind` and forward connections to them back to services on
+/// the agents' machines.
+///
+/// ```toml
+/// [server.reverse]
+/// secret = "long random string shared with the agents"
+/// ports = ["10000-10100"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReverseServerConfig {
+    pub secret: String,
+
+    /// Ports agents may claim, such as "2222" or "10000-10100"
+    pub ports: Vec<String>,
+
+    /// Address the public ports are bound on
+    #[serde(default = "default_reverse_bind")]
+    pub bind: std::net::IpAddr,
+}
+
+/// Hole punching rendezvous (see [`crate::punch`])
+///
+/// Clients with a `[peer]` section find each other's addresses at
+/// `listen`; with `relay` the server also carries the datagrams of pairs
+/// that could not punch through.
+///
+/// ```toml
+/// [server.rendezvous]
+/// listen = "0.0.0.0:3479"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RendezvousConfig {
+    pub listen: SocketAddr,
+
+    #[serde(default = "default_peer_relay")]
+    pub relay: bool,
+}
+
+/// Tor bridge mode (see [`crate::tor_bridge`])
+///
+/// Tunnels Tor clients open to the bridge are connected to the local Tor
+/// relay's `or_port`; `fingerprint` is the relay's identity, included in
+/// the bridge lines `nooshdaroo tor-bridge-line` prints.
+///
+/// ```toml
+/// [server.tor]
+/// or_port = "127.0.0.1:9001"
+/// fingerprint = "4A0C4E2B7F1D3C5E6A8B9D0F1E2C3B4A5D6E7F80"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TorBridgeConfig {
+    pub or_port: SocketAddr,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+fn default_reverse_bind() -> std::net::IpAddr {
+    std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)
+}
+
+/// Servers behind one load balancer that share state by gossip (see
+/// [`crate::cluster`])
+///
+/// Each node announces the UDP DNS-tunnel sessions it serves and the
+/// traffic of each client to its `peers` every `gossip_interval`. A node
+/// receiving queries of a session another node serves forwards them there,
+/// so sessions survive the load balancer moving a client. With
+/// `quota_bytes` set, a client address moving more than that through the
+/// whole cluster in one `quota_period` is cut off until the next.
+///
+/// ```toml
+/// [server.cluster]
+/// listen = "10.0.0.1:7946"
+/// peers = ["10.0.0.2:7946", "10.0.0.3:7946"]
+/// secret = "long random string shared by the nodes"
+/// quota_bytes = 10737418240
+/// quota_period = "1day"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// Name of this node (default: random)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+
+    /// UDP address gossip is exchanged on
+    pub listen: SocketAddr,
+
+    /// Gossip addresses of the other nodes
+    #[serde(default)]
+    pub peers: Vec<SocketAddr>,
+
+    /// Key authenticating gossip between the nodes
+    pub secret: String,
+
+    #[serde(default = "default_gossip_interval", with = "humantime_serde")]
+    pub gossip_interval: Duration,
+
+    /// Bytes a client address may move per period across the cluster
+    /// (0 = unlimited)
+    #[serde(default)]
+    pub quota_bytes: u64,
+
+    #[serde(default = "default_quota_period", with = "humantime_serde")]
+    pub quota_period: Duration,
+}
+
+fn default_gossip_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_quota_period() -> Duration {
+    Duration::from_secs(86400)
+}
+
+/// Connection audit log (see [`crate::audit`])
+///
+/// Records when each tunnel opened and closed, its protocol and the bytes
+/// it carried, one JSON object per line in a file per day under
+/// `directory`. Destinations are never written, and client addresses only
+/// as precisely as `client_ip` allows. Files older than `retention` are
+/// deleted.
+///
+/// ```toml
+/// [server.audit]
+/// directory = "/var/log/nooshdaroo"
+/// client_ip = "hash"
+/// retention = "7days"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default = "default_audit_directory")]
+    pub directory: PathBuf,
+
+    #[serde(default)]
+    pub client_ip: ClientIpPrivacy,
+
+    /// How long records are kept, in whole days
+    #[serde(default = "default_audit_retention", with = "humantime_serde")]
+    pub retention: Duration,
+}
+
+fn default_audit_directory() -> PathBuf {
+    PathBuf::from("audit")
+}
+
+fn default_audit_retention() -> Duration {
+    Duration::from_secs(7 * 86400)
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_audit_directory(),
+            client_ip: ClientIpPrivacy::default(),
+            retention: default_audit_retention(),
+        }
+    }
+}
+
+/// How much of a client's address the audit log keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIpPrivacy {
+    /// No address
+    Omit,
+    /// The /24 network of IPv4 and /48 of IPv6 addresses
+    #[default]
+    Truncate,
+    /// A keyed hash, with a new key every time the server starts, so a
+    /// client's connections are linked only until then
+    Hash,
+    /// The whole address
+    Full,
+}
+
+/// Graceful server drain (see [`crate::drain`])
+///
+/// Once asked to drain, the server stops accepting tunnels and exits when
+/// the open ones have finished, or after `timeout`. With `handoff` it first
+/// starts a new server process that takes over the listening sockets, for
+/// upgrades without refused connections (Unix only).
+///
+/// ```toml
+/// [server.drain]
+/// timeout = "10m"
+/// handoff = true
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DrainConfig {
+    /// Longest the open tunnels are waited for
+    #[serde(default = "default_server_drain_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+
+    /// Hand the listening sockets to a new process of the same executable
+    #[serde(default)]
+    pub handoff: bool,
+}
+
+fn default_server_drain_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        Self { timeout: default_server_drain_timeout(), handoff: false }
+    }
+}
+
+/// TURN server a client relays its `kcp` sessions through (see
+/// [`crate::turn_relay`])
+///
+/// ```toml
+/// [socks.turn]
+/// server = "turn.example.com:3478"
+/// username = "alice"
+/// password = "secret"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurnConfig {
+    /// TURN server address (host:port, UDP)
+    pub server: String,
+
+    /// Long-term credentials
+    pub username: String,
+    pub password: String,
+}
+
+/// TURN server run alongside a `kcp` listener, so clients' traffic is
+/// TURN to the wire without a third-party relay (see [`crate::turn_relay`])
+///
+/// ```toml
+/// [server.turn]
+/// listen = "0.0.0.0:3478"
+/// relay_address = "203.0.113.7"
+/// realm = "example.com"
+/// username = "alice"
+/// password = "secret"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurnServerConfig {
+    /// UDP address the TURN server listens on
+    pub listen: SocketAddr,
+
+    /// Public address handed out for relayed allocations
+    pub relay_address: std::net::IpAddr,
+
+    /// Realm of the long-term credentials, sent in the clear; a domain
+    /// name like a real deployment's
+    pub realm: String,
+
+    /// The one accepted user
+    pub username: String,
+    pub password: String,
+}
+
+/// Trust settings for PSF files loaded from the protocol directory
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProtocolTrustConfig {
+    /// Base64 Ed25519 public keys allowed to sign PSF files.
+    /// Signatures are only checked when at least one key is set.
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+
+    /// Skip PSF files without a signature (otherwise they load with a warning)
+    #[serde(default)]
+    pub require_signatures: bool,
+}
 
-    /// Remote server address for tunneling (client mode)
-    pub server_address: Option<String>,
+/// Out-of-band server discovery
+///
+/// Sources are tried in order until one yields a bootstrap document signed
+/// by a trusted key (see [`crate::bootstrap`]); its servers join
+/// `[socks] servers`. A source is an `https://` URL on one of
+/// `allowed_hosts` (or a subdomain), `dns:<domain>` for TXT records, or
+/// `file:<path>`.
+///
+/// ```toml
+/// [bootstrap]
+/// sources = ["dns:_nd.example.org", "https://cdn.jsdelivr.net/gh/example/bridges/list", "file:/etc/nooshdaroo/bridges"]
+/// trusted_keys = ["<base64 Ed25519 public key>"]
+/// resolver = "9.9.9.9:53"
+/// timeout = "10s"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BootstrapConfig {
+    /// Where to look for servers, in order
+    #[serde(default)]
+    pub sources: Vec<String>,
 
-    /// Transport type (TCP or UDP)
-    /// For Iran censorship bypass, use UDP on port 53
+    /// Base64 Ed25519 public keys allowed to sign bootstrap documents
     #[serde(default)]
-    pub transport: TransportType,
+    pub trusted_keys: Vec<String>,
 
-    /// Require authentication
-    pub auth_required: bool,
+    /// Hosts `https://` sources may point at
+    #[serde(default = "default_bootstrap_hosts")]
+    pub allowed_hosts: Vec<String>,
 
-    /// Username for authentication
-    pub username: Option<String>,
+    /// DNS resolver for `dns:` sources (unset = first nameserver in
+    /// /etc/resolv.conf)
+    #[serde(default)]
+    pub resolver: Option<SocketAddr>,
 
-    /// Password for authentication
-    pub password: Option<String>,
+    /// Time allowed per source
+    #[serde(default = "default_bootstrap_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
 }
 
-impl Default for SocksConfig {
-    fn default() -> Self {
-        Self {
-            listen_addr: "127.0.0.1:1080".parse().unwrap(),
-            server_address: None,
-            transport: TransportType::default(),
-            auth_required: false,
-            username: None,
-            password: None,
-        }
-    }
+fn default_bootstrap_hosts() -> Vec<String> {
+    // Large shared CDNs that are costly to block outright
+    [
+        "cdn.jsdelivr.net",
+        "raw.githubusercontent.com",
+        "storage.googleapis.com",
+        "cloudfront.net",
+        "azureedge.net",
+        "fastly.net",
+    ]
+    .iter()
+    .map(|host| host.to_string())
+    .collect()
 }
 
-/// Shape-shifting configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ShapeShiftConfig {
-    /// Shape-shifting strategy
-    pub strategy: StrategyType,
+fn default_bootstrap_timeout() -> Duration {
+    Duration::from_secs(10)
 }
 
-impl Default for ShapeShiftConfig {
+impl Default for BootstrapConfig {
     fn default() -> Self {
         Self {
-            strategy: StrategyType::default(),
+            sources: Vec::new(),
+            trusted_keys: Vec::new(),
+            allowed_hosts: default_bootstrap_hosts(),
+            resolver: None,
+            timeout: default_bootstrap_timeout(),
         }
     }
 }
 
-/// Traffic shaping configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TrafficShapingConfig {
-    /// Enable traffic shaping
+/// DNS over HTTPS for the client's own lookups (see [`crate::doh`])
+///
+/// Server host names and path test targets are resolved through `servers`,
+/// tried in order, so the local network's resolver cannot answer or poison
+/// them. Each server is reached at its `addresses` and authenticated by its
+/// certificate, so finding it needs no DNS either. Only with
+/// `system_fallback` does the system resolver answer when no server does.
+///
+/// ```toml
+/// [doh]
+/// servers = [{ url = "https://dns.quad9.net/dns-query", addresses = ["9.9.9.9", "149.112.112.112"] }]
+/// timeout = "5s"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DohConfig {
+    #[serde(default = "default_doh_enabled")]
     pub enabled: bool,
 
-    /// Packet size distribution
-    pub packet_size_distribution: DistributionType,
+    #[serde(default = "default_doh_servers")]
+    pub servers: Vec<DohServerConfig>,
 
-    /// Mean packet size (bytes)
-    pub mean_packet_size: usize,
+    /// Time allowed per server
+    #[serde(default = "default_doh_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
 
-    /// Standard deviation for packet size
-    pub stddev_packet_size: usize,
+    #[serde(default)]
+    pub system_fallback: bool,
+}
 
-    /// Mean inter-packet delay (microseconds)
-    pub mean_delay: u64,
+/// Static host mapping (see [`crate::hosts`])
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HostsConfig {
+    /// Hosts file in `/etc/hosts` format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
 
-    /// Standard deviation for delay
-    pub stddev_delay: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub entries: HashMap<String, std::net::IpAddr>,
+}
 
-    /// Enable burst mode
-    pub enable_bursts: bool,
+impl HostsConfig {
+    /// Whether no names are mapped
+    pub fn is_empty(&self) -> bool {
+        self.file.is_none() && self.entries.is_empty()
+    }
+}
 
-    /// Burst size (packets)
-    pub burst_size: usize,
+/// Traffic quota of the client, such as a mobile data plan
+///
+/// Traffic relayed since the client started is counted against
+/// `limit_bytes`; a [`crate::events::ClientEvent::QuotaWarning`] is emitted
+/// once it reaches `warn_ratio` of the limit and again at the limit. Traffic
+/// is never cut off.
+///
+/// ```toml
+/// [quota]
+/// limit_bytes = 5368709120
+/// warn_ratio = 0.9
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    pub limit_bytes: u64,
+
+    #[serde(default = "default_quota_warn_ratio")]
+    pub warn_ratio: f64,
+}
 
-    /// Burst probability (0.0 - 1.0)
-    pub burst_probability: f64,
+fn default_quota_warn_ratio() -> f64 {
+    0.8
 }
 
-impl Default for TrafficShapingConfig {
-    fn default() -> Self {
-        Self {
-            enabled: true,
-            packet_size_distribution: DistributionType::Normal,
-            mean_packet_size: 1400,
-            stddev_packet_size: 200,
-            mean_delay: 50,
-            stddev_delay: 20,
-            enable_bursts: false,
-            burst_size: 5,
-            burst_probability: 0.1,
-        }
-    }
+/// A DNS over HTTPS server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DohServerConfig {
+    /// RFC 8484 endpoint, such as "https://cloudflare-dns.com/dns-query"
+    pub url: String,
+
+    /// Addresses of the endpoint's host
+    pub addresses: Vec<std::net::IpAddr>,
 }
 
-/// Statistical distribution types
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum DistributionType {
-    Normal,
-    Uniform,
-    Exponential,
+fn default_doh_enabled() -> bool {
+    true
 }
 
-/// Transport type (TCP or UDP)
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum TransportType {
-    Tcp,
-    Udp,
+fn default_doh_servers() -> Vec<DohServerConfig> {
+    let server = |url: &str, addresses: &[&str]| DohServerConfig {
+        url: url.to_string(),
+        addresses: addresses.iter().map(|addr| addr.parse().unwrap()).collect(),
+    };
+    vec![
+        server("https://cloudflare-dns.com/dns-query", &["1.1.1.1", "1.0.0.1"]),
+        server("https://dns.google/dns-query", &["8.8.8.8", "8.8.4.4"]),
+        server("https://dns.quad9.net/dns-query", &["9.9.9.9", "149.112.112.112"]),
+    ]
 }
 
-impl Default for TransportType {
+fn default_doh_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl Default for DohConfig {
     fn default() -> Self {
-        Self::Tcp // Default to TCP for backwards compatibility
+        Self {
+            enabled: default_doh_enabled(),
+            servers: default_doh_servers(),
+            timeout: default_doh_timeout(),
+            system_fallback: false,
+        }
     }
 }
 
-/// Server configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerConfig {
-    /// Listen address
-    pub listen_addr: SocketAddr,
+/// Over-the-air protocol library updates
+///
+/// Every `interval` the client fetches a protocol bundle from the first
+/// mirror that answers and, if it is signed by a key in `[protocol_trust]
+/// trusted_keys` and newer than the one in use, swaps it into the running
+/// library (see [`crate::protocol_update`]). Mirrors are `https://` URLs on
+/// one of `allowed_hosts` (or a subdomain) or `file:<path>`. The last
+/// bundle accepted is kept in `cache` for the next start.
+///
+/// ```toml
+/// [protocol_updates]
+/// mirrors = ["https://cdn.jsdelivr.net/gh/example/protocols/bundle", "https://example.azureedge.net/bundle"]
+/// interval = "6h"
+/// cache = "/var/lib/nooshdaroo/protocols.bundle"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolUpdatesConfig {
+    /// Where to fetch bundles from, in order
+    #[serde(default)]
+    pub mirrors: Vec<String>,
 
-    /// Transport type (TCP or UDP)
-    /// For Iran censorship bypass, use UDP on port 53
+    /// Hosts `https://` mirrors may point at
+    #[serde(default = "default_bootstrap_hosts")]
+    pub allowed_hosts: Vec<String>,
+
+    /// Time between update checks
+    #[serde(default = "default_update_interval", with = "humantime_serde")]
+    pub interval: Duration,
+
+    /// Time allowed per mirror
+    #[serde(default = "default_update_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+
+    /// File keeping the last bundle accepted
     #[serde(default)]
-    pub transport: TransportType,
+    pub cache: Option<PathBuf>,
+}
+
+fn default_update_interval() -> Duration {
+    Duration::from_secs(6 * 3600)
+}
+
+fn default_update_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl Default for ProtocolUpdatesConfig {
+    fn default() -> Self {
+        Self {
+            mirrors: Vec::new(),
+            allowed_hosts: default_bootstrap_hosts(),
+            interval: default_update_interval(),
+            timeout: default_update_timeout(),
+            cache: None,
+        }
+    }
 }
 
 /// Detection resistance configuration
@@ -273,6 +2470,15 @@ pub struct DetectionConfig {
 
     /// Decoy traffic rate (packets per second)
     pub decoy_traffic_rate: f64,
+
+    /// Re-encode tunnel payloads to match the entropy of the cover protocol
+    /// (base64 in HTTP, base32 in DNS, ...). Must match on client and server.
+    #[serde(default)]
+    pub normalize_payload_entropy: bool,
+
+    /// Payload encoding overrides keyed by protocol id or family
+    #[serde(default)]
+    pub payload_encodings: HashMap<String, crate::entropy::PayloadEncoding>,
 }
 
 fn default_tls_session_emulation() -> bool {
@@ -289,6 +2495,19 @@ impl Default for DetectionConfig {
             suspicion_threshold: 0.7,
             enable_decoy_traffic: false,
             decoy_traffic_rate: 0.1,
+            normalize_payload_entropy: false,
+            payload_encodings: HashMap::new(),
+        }
+    }
+}
+
+impl DetectionConfig {
+    /// Payload encoding to use with `protocol`
+    pub fn payload_encoding(&self, protocol: &str) -> crate::entropy::PayloadEncoding {
+        if self.normalize_payload_entropy {
+            crate::entropy::PayloadEncoding::resolve(protocol, &self.payload_encodings)
+        } else {
+            crate::entropy::PayloadEncoding::Raw
         }
     }
 }
@@ -320,10 +2539,454 @@ impl NooshdarooConfig {
             return Err("Suspicion threshold must be between 0.0 and 1.0".to_string());
         }
 
+        if self.protocol_trust.require_signatures && self.protocol_trust.trusted_keys.is_empty() {
+            return Err("require_signatures needs at least one trusted key".to_string());
+        }
+        crate::psf::signature::PsfVerifier::new(&self.protocol_trust.trusted_keys)?;
+
+        if self.traffic_shaping.tcp_fingerprint.enabled {
+            self.traffic_shaping
+                .tcp_fingerprint
+                .signature
+                .parse::<crate::tcp_fingerprint::TcpSignature>()?;
+        }
+
+        for entry in &self.socks.servers {
+            crate::acl::split_target(&entry.address)
+                .map_err(|_| format!("socks.servers address must be ip:port or host:port: {}", entry.address))?;
+        }
+
+        if self.traffic_shaping.ttl.enabled && self.traffic_shaping.ttl.ttl == Some(0) {
+            return Err("traffic_shaping.ttl.ttl must be at least 1".to_string());
+        }
+
+        if !(512..=65519).contains(&self.relay.buffer_size) {
+            return Err("relay.buffer_size must be between 512 and 65519 bytes".to_string());
+        }
+        if self.relay.max_in_flight < self.relay.buffer_size {
+            return Err("relay.max_in_flight must be at least relay.buffer_size".to_string());
+        }
+        if self.relay.session_buffer < self.relay.buffer_size {
+            return Err("relay.session_buffer must be at least relay.buffer_size".to_string());
+        }
+        if self.relay.write_timeout.is_zero() {
+            return Err("relay.write_timeout must be greater than zero".to_string());
+        }
+        if self.relay.idle_timeout.is_some_and(|t| t.is_zero()) {
+            return Err("relay.idle_timeout must be greater than zero".to_string());
+        }
+        let nat = &self.relay.nat_keepalive;
+        if nat.enabled && (nat.min_interval.is_zero() || nat.min_interval > nat.max_interval) {
+            return Err("relay.nat_keepalive needs 0 < min_interval <= max_interval".to_string());
+        }
+        if !self.bootstrap.sources.is_empty() {
+            if self.bootstrap.trusted_keys.is_empty() {
+                return Err("bootstrap.sources needs at least one key in bootstrap.trusted_keys".to_string());
+            }
+            crate::psf::signature::PsfVerifier::new(&self.bootstrap.trusted_keys)?;
+            crate::bootstrap::parse_sources(&self.bootstrap)?;
+        }
+        if self.experiment.enabled {
+            if self.experiment.protocols.len() < 2 {
+                return Err("experiment.protocols needs at least two protocols to compare".to_string());
+            }
+            if !(self.experiment.fraction > 0.0 && self.experiment.fraction <= 1.0) {
+                return Err("experiment.fraction must be greater than 0.0 and at most 1.0".to_string());
+            }
+            if self.experiment.min_samples == 0 {
+                return Err("experiment.min_samples must be at least 1".to_string());
+            }
+        }
+        if self.measurements.enabled {
+            let cc = &self.measurements.probe_cc;
+            if cc.len() != 2 || !cc.chars().all(|c| c.is_ascii_uppercase()) {
+                return Err("measurements.probe_cc must be a two-letter country code such as \"IR\"".to_string());
+            }
+            let asn = &self.measurements.probe_asn;
+            if !asn.strip_prefix("AS").is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) {
+                return Err("measurements.probe_asn must look like \"AS12345\"".to_string());
+            }
+        }
+        if let Some(cluster) = self.server.as_ref().and_then(|s| s.cluster.as_ref()) {
+            if cluster.secret.len() < 16 {
+                return Err("server.cluster.secret must be at least 16 characters".to_string());
+            }
+            if cluster.gossip_interval.is_zero() || cluster.quota_period.as_secs() == 0 {
+                return Err("server.cluster.gossip_interval and quota_period must be greater than zero".to_string());
+            }
+        }
+        if let Some(reverse) = self.server.as_ref().and_then(|s| s.reverse.as_ref()) {
+            if reverse.secret.len() < 16 {
+                return Err("server.reverse.secret must be at least 16 characters".to_string());
+            }
+            crate::reverse::PortRanges::parse(&reverse.ports).map_err(|e| format!("server.reverse.ports: {}", e))?;
+        }
+        if let Some(fingerprint) = self.server.as_ref().and_then(|s| s.tor.as_ref()).and_then(|t| t.fingerprint.as_ref()) {
+            if fingerprint.len() != 40 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("server.tor.fingerprint must be 40 hexadecimal characters".to_string());
+            }
+        }
+        if let Some(reverse) = &self.reverse {
+            if reverse.services.is_empty() {
+                return Err("reverse.services must list at least one service".to_string());
+            }
+            if reverse.retry_interval.is_zero() {
+                return Err("reverse.retry_interval must be greater than zero".to_string());
+            }
+        }
+        if let Some(peer) = &self.peer {
+            if peer.listen.is_some() == peer.forward.is_some() {
+                return Err("peer needs exactly one of listen and forward".to_string());
+            }
+            if peer.punch_timeout.is_zero() {
+                return Err("peer.punch_timeout must be greater than zero".to_string());
+            }
+        }
+        if let Some(mesh) = &self.mesh {
+            if mesh.peers.is_empty() {
+                return Err("mesh.peers must list at least one peer".to_string());
+            }
+            if mesh.exit_proxy.is_some() && !mesh.exit {
+                return Err("mesh.exit_proxy needs mesh.exit".to_string());
+            }
+            if mesh.punch_timeout.is_zero() {
+                return Err("mesh.punch_timeout must be greater than zero".to_string());
+            }
+        }
+        if let Some(quota) = &self.quota {
+            if quota.limit_bytes == 0 {
+                return Err("quota.limit_bytes must be greater than zero".to_string());
+            }
+            if !(quota.warn_ratio > 0.0 && quota.warn_ratio <= 1.0) {
+                return Err("quota.warn_ratio must be greater than 0 and at most 1".to_string());
+            }
+        }
+        if let Some(audit) = self.server.as_ref().and_then(|s| s.audit.as_ref()) {
+            if audit.retention < Duration::from_secs(86400) {
+                return Err("server.audit.retention must be at least one day".to_string());
+            }
+        }
+        crate::acl::AccessPolicy::new(&self.access)?;
+        if self.path_quality.enabled {
+            if self.path_quality.interval.is_zero() || self.path_quality.timeout.is_zero() {
+                return Err("path_quality.interval and path_quality.timeout must be greater than zero".to_string());
+            }
+            if self.path_quality.window == 0 {
+                return Err("path_quality.window must be at least 1".to_string());
+            }
+        }
+        if !self.protocol_updates.mirrors.is_empty() {
+            if self.protocol_trust.trusted_keys.is_empty() {
+                return Err("protocol_updates.mirrors needs at least one key in protocol_trust.trusted_keys".to_string());
+            }
+            if self.protocol_updates.interval.is_zero() {
+                return Err("protocol_updates.interval must be greater than 0".to_string());
+            }
+            crate::protocol_update::parse_mirrors(&self.protocol_updates)?;
+        }
+        if self.shapeshift.hopping.enabled && self.shapeshift.hopping.interval_bytes == 0 {
+            return Err("shapeshift.hopping.interval_bytes must be greater than 0".to_string());
+        }
+
+        if self.relay.port_hopping.enabled {
+            crate::port_hop::PortHopSchedule::new(&self.relay.port_hopping)?;
+        }
+        if self.relay.fec.enabled {
+            let fec = &self.relay.fec;
+            if !fec.redundancy.is_finite() || fec.redundancy < 0.0 {
+                return Err("relay.fec.redundancy must be zero or more".to_string());
+            }
+            if fec.group_size == 0 || fec.group_size + fec.parity_shards() > crate::fec::MAX_SHARDS {
+                return Err(format!(
+                    "relay.fec.group_size plus its parity datagrams must be between 1 and {}",
+                    crate::fec::MAX_SHARDS
+                ));
+            }
+        }
+
+        if let Some(ref name) = self.relay.game_netcode {
+            let gaming = crate::app_profiles::ApplicationProfile::get(name)
+                .is_some_and(|profile| profile.category == crate::app_profiles::AppCategory::Gaming);
+            if !gaming {
+                return Err(format!("relay.game_netcode: {:?} is not a gaming application profile", name));
+            }
+            if self.relay.voip_codec.is_some() {
+                return Err("relay.game_netcode and relay.voip_codec cannot both be set".to_string());
+            }
+        }
+        let framings = [
+            self.relay.game_netcode.is_some(),
+            self.relay.voip_codec.is_some(),
+            self.relay.wireguard,
+            self.relay.openvpn,
+            self.relay.coap,
+        ];
+        if framings.iter().filter(|set| **set).count() > 1 {
+            return Err(
+                "only one of relay.game_netcode, relay.voip_codec, relay.wireguard, relay.openvpn and relay.coap can be set"
+                    .to_string(),
+            );
+        }
+
+        if self.relay.sip && self.relay.voip_codec.is_none() {
+            return Err("relay.sip requires relay.voip_codec".to_string());
+        }
+
+        if !(1..=16384).contains(&self.relay.chain_padding.block) {
+            return Err("relay.chain_padding.block must be between 1 and 16384 bytes".to_string());
+        }
+        if self.history.enabled && self.history.sample_interval.is_zero() {
+            return Err("history.sample_interval must be greater than zero".to_string());
+        }
+
+        if let Some(ref address) = self.socks.server_address {
+            if crate::chain::RelayChain::is_chain(address) {
+                if !self.socks.servers.is_empty() {
+                    return Err("socks.server_address cannot be a relay chain when socks.servers is set".to_string());
+                }
+                // Hops without a protocol are checked again once the client's
+                // protocol is known
+                crate::chain::RelayChain::parse(address)?.validate(&crate::protocol::ProtocolId::default())?;
+            }
+        }
+
+        if let Some(ref upstream) = self.socks.upstream_proxy {
+            if upstream.address.is_empty() {
+                return Err("socks.upstream_proxy.address is required".to_string());
+            }
+            if upstream.username.is_some() != upstream.password.is_some() {
+                return Err("socks.upstream_proxy needs both a username and a password, or neither".to_string());
+            }
+            if upstream.protocol == ProxyProtocol::Socks5
+                && [&upstream.username, &upstream.password].iter().any(|s| s.as_ref().is_some_and(|s| s.is_empty() || s.len() > 255))
+            {
+                return Err("socks.upstream_proxy username and password must be 1 to 255 bytes".to_string());
+            }
+            if upstream.protocol == ProxyProtocol::Http && upstream.username.as_ref().is_some_and(|s| s.is_empty() || s.contains(':')) {
+                return Err("socks.upstream_proxy username must be non-empty and without ':' for an HTTP proxy".to_string());
+            }
+            if upstream.auth != ProxyAuth::Basic && (upstream.protocol != ProxyProtocol::Http || upstream.username.is_none()) {
+                return Err(format!("socks.upstream_proxy auth {:?} needs an HTTP proxy and credentials", upstream.auth));
+            }
+            if matches!(self.socks.transport, TransportType::Udp | TransportType::Kcp | TransportType::Ftp) {
+                return Err(format!("socks.upstream_proxy does not support the {:?} transport", self.socks.transport));
+            }
+        }
+
+        if let Some(ref pac) = self.socks.upstream_proxy_pac {
+            if self.socks.upstream_proxy.is_some() {
+                return Err("socks.upstream_proxy and socks.upstream_proxy_pac are mutually exclusive".to_string());
+            }
+            if pac.url.is_empty() || pac.url.starts_with("https://") {
+                return Err("socks.upstream_proxy_pac.url must be an http:// URL or a file path".to_string());
+            }
+            if pac.username.is_some() != pac.password.is_some() {
+                return Err("socks.upstream_proxy_pac needs both a username and a password, or neither".to_string());
+            }
+            if pac.auth != ProxyAuth::Basic && pac.username.is_none() {
+                return Err(format!("socks.upstream_proxy_pac auth {:?} needs credentials", pac.auth));
+            }
+            if matches!(self.socks.transport, TransportType::Udp | TransportType::Kcp | TransportType::Ftp) {
+                return Err(format!("socks.upstream_proxy_pac does not support the {:?} transport", self.socks.transport));
+            }
+            if self.socks.kill_switch {
+                // The proxies a PAC script picks are only known per connection
+                return Err("socks.kill_switch cannot be combined with socks.upstream_proxy_pac".to_string());
+            }
+        }
+
+        if self.socks.kill_switch && self.socks.transport == TransportType::Udp {
+            return Err("socks.kill_switch does not support the Udp transport".to_string());
+        }
+
+        if let Some(https) = &self.socks.https {
+            if https.hostnames.is_empty() {
+                return Err("socks.https.hostnames must not be empty".to_string());
+            }
+            if self.socks.auth_required && (self.socks.username.is_none() || self.socks.password.is_none()) {
+                return Err("socks.https with socks.auth_required needs socks.username and socks.password".to_string());
+            }
+        }
+
+        if self.doh.enabled {
+            if self.doh.servers.is_empty() && !self.doh.system_fallback {
+                return Err("doh.servers must not be empty".to_string());
+            }
+            for server in &self.doh.servers {
+                crate::bootstrap::https_host(&server.url).map_err(|e| format!("doh.servers: {}", e))?;
+                if server.addresses.is_empty() {
+                    return Err(format!("DoH server {} has no addresses", server.url));
+                }
+            }
+        }
+
+        // Validate upstream server list
+        for server in &self.socks.servers {
+            if server.address.parse::<SocketAddr>().is_err() {
+                return Err(format!("Invalid upstream server address: {}", server.address));
+            }
+            if server.weight == 0 {
+                return Err(format!("Upstream server {} has zero weight", server.address));
+            }
+        }
+
         Ok(())
     }
 }
 
+/// URI scheme for shareable client configurations
+pub const SHARE_LINK_SCHEME: &str = "nooshdaroo://";
+
+/// Compact client configuration in the style of ss:// and vmess:// links:
+///
+/// `nooshdaroo://<public-key>@<host>:<port>?pattern=nk&protocol=https,dns&elligator=1#<name>`
+///
+/// The public key is URL-safe base64 without padding. Protocols are hints in
+/// order of preference; the first one is used as the fixed protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareLink {
+    /// Server address (host:port)
+    pub server: String,
+    /// Server's Noise static public key (standard base64)
+    pub public_key: String,
+    /// Noise pattern
+    pub pattern: crate::noise_transport::NoisePattern,
+    /// Server expects Elligator2-encoded handshake keys
+    pub elligator: bool,
+    /// Preferred protocols, best first
+    pub protocols: Vec<String>,
+    /// Human-readable label
+    pub name: Option<String>,
+}
+
+impl ShareLink {
+    /// Render as a `nooshdaroo://` URI
+    pub fn to_uri(&self) -> String {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(&self.public_key)
+            .map(|bytes| URL_SAFE_NO_PAD.encode(bytes))
+            .unwrap_or_else(|_| self.public_key.clone());
+        let mut uri = format!("{}{}@{}?pattern={}", SHARE_LINK_SCHEME, key, self.server, self.pattern);
+        if !self.protocols.is_empty() {
+            uri.push_str("&protocol=");
+            uri.push_str(&self.protocols.join(","));
+        }
+        if self.elligator {
+            uri.push_str("&elligator=1");
+        }
+        if let Some(ref name) = self.name {
+            uri.push('#');
+            uri.push_str(&percent_encode(name));
+        }
+        uri
+    }
+
+    /// Apply to a client configuration, overriding server, key, pattern and protocol
+    pub fn apply_to(&self, config: &mut NooshdarooConfig) {
+        config.mode = NooshdarooMode::Client;
+        config.socks.server_address = Some(self.server.clone());
+
+        let transport = config.transport.get_or_insert_with(Default::default);
+        transport.pattern = self.pattern;
+        transport.remote_public_key = Some(self.public_key.clone());
+        transport.remote_public_keys.clear();
+        transport.elligator = self.elligator;
+
+        if let Some(protocol) = self.protocols.first() {
+            config.shapeshift.strategy = StrategyType::Fixed(crate::strategy::FixedStrategy::new(
+                crate::protocol::ProtocolId::from(protocol.as_str()),
+            ));
+        }
+    }
+}
+
+impl std::str::FromStr for ShareLink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let rest = s
+            .trim()
+            .strip_prefix(SHARE_LINK_SCHEME)
+            .ok_or_else(|| format!("Share link must start with {}", SHARE_LINK_SCHEME))?;
+        let (rest, name) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, Some(percent_decode(name)?)),
+            None => (rest, None),
+        };
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (key, server) = authority
+            .split_once('@')
+            .ok_or("Share link is missing the server public key")?;
+        let server = server.trim_end_matches('/');
+
+        let key_bytes = URL_SAFE_NO_PAD
+            .decode(key.trim_end_matches('='))
+            .map_err(|e| format!("Invalid public key in share link: {}", e))?;
+        if key_bytes.len() != 32 {
+            return Err("Share link public key must be 32 bytes".to_string());
+        }
+        match server.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(format!("Invalid server address in share link: {}", server)),
+        }
+
+        let mut link = ShareLink {
+            server: server.to_string(),
+            public_key: base64::engine::general_purpose::STANDARD.encode(key_bytes),
+            pattern: Default::default(),
+            elligator: false,
+            protocols: Vec::new(),
+            name: name.filter(|n| !n.is_empty()),
+        };
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "pattern" => link.pattern = value.parse()?,
+                "elligator" => link.elligator = matches!(value, "1" | "true"),
+                "protocol" => {
+                    link.protocols = value
+                        .split(',')
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                // Unknown parameters are ignored so newer links still load
+                _ => {}
+            }
+        }
+        Ok(link)
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or("Truncated escape in share link")?;
+            out.push(u8::from_str_radix(hex, 16).map_err(|_| "Invalid escape in share link")?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| "Share link name is not UTF-8".to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -358,9 +3021,238 @@ mod tests {
 
         // Should succeed with server config
         config.server = Some(ServerConfig {
-            listen_addr: "0.0.0.0:443".parse().unwrap(),
+            listen_addr: ListenAddrs::from("0.0.0.0:443".parse::<SocketAddr>().unwrap()),
             transport: TransportType::Tcp,
+            io_uring: false,
+            io_uring_workers: 0,
+            smtp_hostname: None,
+            ftp_passive_address: None,
+            turn: None,
+            drain: DrainConfig::default(),
+            cluster: None,
+            audit: None,
+            reverse: None,
+            rendezvous: None,
+            tor: None,
+        });
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_upstream_servers_parse() {
+        let toml_str = r#"
+mode = "client"
+
+[encryption]
+cipher = "cha-cha20-poly1305"
+key_derivation = "argon2"
+
+[shapeshift.strategy]
+type = "fixed"
+protocol = "https"
+
+[socks]
+listen_addr = "127.0.0.1:1080"
+auth_required = false
+
+[[socks.servers]]
+address = "192.0.2.1:443"
+public_key = "abc"
+protocols = ["https", "tls13"]
+
+[[socks.servers]]
+address = "192.0.2.2:8443"
+weight = 3
+"#;
+        let mut config: NooshdarooConfig = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.socks.servers.len(), 2);
+        assert_eq!(config.socks.servers[0].weight, 1);
+        assert_eq!(config.socks.servers[1].weight, 3);
+        assert!(config.socks.servers[1].protocols.is_empty());
+        assert_eq!(config.socks.health_check_interval_secs, 30);
+
+        config.encryption.password = Some("test".to_string());
+        assert!(config.validate().is_ok());
+
+        config.socks.servers[1].address = "not-an-address".to_string();
+        assert!(config.validate().is_err());
+        config.socks.servers[1].address = "192.0.2.2:8443".to_string();
+
+        config.socks.upstream_proxy = Some(UpstreamProxyConfig {
+            protocol: ProxyProtocol::Socks5,
+            address: "127.0.0.1:9050".to_string(),
+            auth: ProxyAuth::Basic,
+            username: Some("alice".to_string()),
+            password: None,
         });
+        assert!(config.validate().is_err());
+        config.socks.upstream_proxy.as_mut().unwrap().password = Some("secret".to_string());
+        assert!(config.validate().is_ok());
+        config.socks.transport = TransportType::Kcp;
+        assert!(config.validate().is_err());
+        config.socks.transport = TransportType::Tcp;
+        let upstream: UpstreamProxyConfig = toml::from_str("protocol = \"http\"\naddress = \"proxy:8080\"").unwrap();
+        assert_eq!(upstream.protocol, ProxyProtocol::Http);
+        config.socks.upstream_proxy.as_mut().unwrap().auth = ProxyAuth::Ntlm;
+        assert!(config.validate().is_err());
+        config.socks.upstream_proxy.as_mut().unwrap().protocol = ProxyProtocol::Http;
+        assert!(config.validate().is_ok());
+        let pac: PacConfig = toml::from_str("url = \"http://wpad/wpad.dat\"").unwrap();
+        config.socks.upstream_proxy_pac = Some(pac);
+        assert!(config.validate().is_err());
+        config.socks.upstream_proxy = None;
+        assert!(config.validate().is_ok());
+        config.socks.upstream_proxy_pac = None;
+        config.socks.upstream_proxy = None;
+
+        // A relay chain is a single path and cannot be load balanced
+        let servers = std::mem::take(&mut config.socks.servers);
+        config.socks.server_address = Some("192.0.2.1:443 -> ssh@relay.example.net:22".to_string());
+        assert!(config.validate().is_ok());
+        config.socks.server_address = Some("192.0.2.1:443 -> dns-udp-tunnel@relay.example.net:53".to_string());
+        assert!(config.validate().is_err());
+        config.socks.server_address = Some("192.0.2.1:443 -> relay.example.net:22".to_string());
+        config.socks.servers = servers;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_listen_addrs() {
+        #[derive(Deserialize, Serialize)]
+        struct Listen {
+            listen_addr: ListenAddrs,
+        }
+        let one: Listen = toml::from_str("listen_addr = \"0.0.0.0:443\"").unwrap();
+        assert_eq!(one.listen_addr.len(), 1);
+        assert_eq!(toml::to_string(&one).unwrap().trim(), "listen_addr = \"0.0.0.0:443\"");
+
+        let both: Listen = toml::from_str("listen_addr = [\"0.0.0.0:443\", \"[::]:443\"]").unwrap();
+        assert_eq!(both.listen_addr, "0.0.0.0:443, [::]:443".parse().unwrap());
+        assert_eq!(both.listen_addr.first(), "0.0.0.0:443".parse::<SocketAddr>().unwrap());
+        assert!(toml::from_str::<Listen>("listen_addr = []").is_err());
+    }
+
+    #[test]
+    fn test_relay_limits() {
+        let toml_str = r#"
+mode = "server"
+
+[encryption]
+cipher = "cha-cha20-poly1305"
+key_derivation = "argon2"
+password = "test"
+
+[shapeshift.strategy]
+type = "fixed"
+protocol = "https"
+
+[server]
+listen_addr = "0.0.0.0:443"
+
+[relay]
+buffer_size = 16384
+write_timeout = "15s"
+idle_timeout = "5m"
+"#;
+        let mut config: NooshdarooConfig = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.relay.buffer_size, 16384);
+        assert_eq!(config.relay.max_in_flight, 65535);
+        assert_eq!(config.relay.write_timeout, Duration::from_secs(15));
+        assert_eq!(config.relay.max_connections, 0);
+        assert_eq!(config.relay.max_sessions_per_client, 0);
+        assert_eq!(config.relay.session_buffer, 262144);
+        assert_eq!(config.relay.idle_timeout, Some(Duration::from_secs(300)));
+        assert_eq!(config.relay.drain_timeout, Duration::from_secs(5));
+        assert!(config.validate().is_ok());
+
+        config.relay.idle_timeout = Some(Duration::ZERO);
+        assert!(config.validate().is_err());
+        config.relay.idle_timeout = None;
+        config.relay.nat_keepalive.min_interval = Duration::from_secs(60);
+        assert!(config.validate().is_err());
+        config.relay.nat_keepalive = NatKeepaliveConfig::default();
+        assert_eq!(config.relay.chain_padding, ChainPaddingConfig::default());
+        config.relay.chain_padding.block = 0;
+        assert!(config.validate().is_err());
+        config.relay.chain_padding.block = 256;
+        config.relay.port_hopping.enabled = true;
+        assert!(config.validate().is_err());
+        config.relay.port_hopping.key = Some("secret".to_string());
+        assert!(config.validate().is_ok());
+        config.relay.fec.enabled = true;
+        assert_eq!(config.relay.fec.parity_shards(), 2);
+        config.relay.fec.group_size = 250;
+        assert!(config.validate().is_err());
+        config.relay.fec.group_size = 8;
+        assert!(config.validate().is_ok());
+        config.relay.game_netcode = Some("zoom".to_string());
+        assert!(config.validate().is_err());
+        config.relay.game_netcode = Some("fortnite".to_string());
+        assert!(config.validate().is_ok());
+        config.relay.voip_codec = Some(VoipCodec::Opus);
+        assert!(config.validate().is_err());
+        config.relay.game_netcode = None;
         assert!(config.validate().is_ok());
+        config.relay.wireguard = true;
+        assert!(config.validate().is_err());
+        config.relay.voip_codec = None;
+        assert!(config.validate().is_ok());
+        config.relay.openvpn = true;
+        assert!(config.validate().is_err());
+        config.relay.wireguard = false;
+        assert!(config.validate().is_ok());
+        config.relay.openvpn = false;
+        config.relay.coap = true;
+        assert!(config.validate().is_ok());
+        config.relay.wireguard = true;
+        assert!(config.validate().is_err());
+        config.relay.wireguard = false;
+        config.relay.coap = false;
+        config.relay.sip = true;
+        assert!(config.validate().is_err());
+        config.relay.voip_codec = Some(VoipCodec::Pcmu);
+        assert!(config.validate().is_ok());
+        config.relay.sip = false;
+        config.relay.voip_codec = None;
+
+        config.relay.max_in_flight = 4096;
+        assert!(config.validate().is_err());
+        config.relay.max_in_flight = 65535;
+        config.relay.buffer_size = 100;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_share_link_round_trip() {
+        use crate::noise_transport::NoisePattern;
+
+        let link = ShareLink {
+            server: "vpn.example.com:8443".to_string(),
+            public_key: "vFgDlT8j1sJQcuL/0HuNiIcsKXbtLxNHWeQz6L4N7Gs=".to_string(),
+            pattern: NoisePattern::KK,
+            elligator: true,
+            protocols: vec!["https".to_string(), "dns".to_string()],
+            name: Some("Home server".to_string()),
+        };
+        let uri = link.to_uri();
+        assert!(uri.starts_with("nooshdaroo://vFgDlT8j1sJQcuL_0HuNiIcsKXbtLxNHWeQz6L4N7Gs@vpn.example.com:8443?"));
+        assert!(uri.ends_with("#Home%20server"));
+        assert_eq!(uri.parse::<ShareLink>().unwrap(), link);
+
+        let mut config = NooshdarooConfig::default();
+        link.apply_to(&mut config);
+        assert_eq!(config.socks.server_address.as_deref(), Some("vpn.example.com:8443"));
+        let transport = config.transport.unwrap();
+        assert_eq!(transport.pattern, NoisePattern::KK);
+        assert!(transport.elligator);
+        assert_eq!(transport.remote_public_key.as_deref(), Some(link.public_key.as_str()));
+
+        let minimal: ShareLink = "nooshdaroo://vFgDlT8j1sJQcuL_0HuNiIcsKXbtLxNHWeQz6L4N7Gs@1.2.3.4:443".parse().unwrap();
+        assert_eq!(minimal.pattern, NoisePattern::NK);
+        assert!(minimal.protocols.is_empty() && minimal.name.is_none() && !minimal.elligator);
+
+        assert!("ss://abc@1.2.3.4:443".parse::<ShareLink>().is_err());
+        assert!("nooshdaroo://short@1.2.3.4:443".parse::<ShareLink>().is_err());
+        assert!("nooshdaroo://vFgDlT8j1sJQcuL_0HuNiIcsKXbtLxNHWeQz6L4N7Gs@1.2.3.4".parse::<ShareLink>().is_err());
     }
 }
@@ -0,0 +1,282 @@
+//! Byte and packet counters for relayed traffic
+//!
+//! Relay loops record every chunk they move through a [`ConnectionCounters`],
+//! which credits both the connection and the protocol carrying it. The
+//! per-protocol aggregates live in a [`TrafficRegistry`] owned by the
+//! shape-shift controller and surface in [`ProtocolStats`](crate::ProtocolStats).
+//! Directions are relative to the tunnel: "sent" went into it, "received"
+//! came out of it. Alongside traffic they count connections, handshake
+//! failures and handshake round trips, from which each protocol's average
+//! RTT and failure rate are derived.
+
+use crate::protocol::ProtocolId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Lock-free traffic counters
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    handshake_failures: AtomicU64,
+    connections: AtomicU64,
+    rtt_micros: AtomicU64,
+    rtt_samples: AtomicU64,
+}
+
+impl TrafficCounters {
+    /// Record one chunk of `bytes` written into the tunnel
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record one chunk of `bytes` read from the tunnel
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a tunnel that could not be established
+    pub fn record_handshake_failure(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record an established connection
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record one handshake round trip
+    pub fn record_rtt(&self, rtt: Duration) {
+        self.rtt_micros.fetch_add(rtt.as_micros() as u64, Ordering::Relaxed);
+        self.rtt_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current values
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            handshake_failures: self.handshake_failures.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            rtt_micros: self.rtt_micros.load(Ordering::Relaxed),
+            rtt_samples: self.rtt_samples.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of [`TrafficCounters`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub handshake_failures: u64,
+    pub connections: u64,
+    /// Sum of sampled handshake round trips
+    pub rtt_micros: u64,
+    pub rtt_samples: u64,
+}
+
+impl TrafficSnapshot {
+    /// Bytes in both directions
+    pub fn bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+
+    /// Packets in both directions
+    pub fn packets(&self) -> u64 {
+        self.packets_sent + self.packets_received
+    }
+
+    /// Mean handshake round trip, if any was sampled
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        (self.rtt_samples > 0).then(|| Duration::from_micros(self.rtt_micros / self.rtt_samples))
+    }
+
+    /// Share of connection attempts whose tunnel could not be established
+    pub fn failure_rate(&self) -> f64 {
+        let attempts = self.connections + self.handshake_failures;
+        if attempts == 0 {
+            0.0
+        } else {
+            self.handshake_failures as f64 / attempts as f64
+        }
+    }
+
+    /// Change since `earlier`
+    pub fn since(&self, earlier: &TrafficSnapshot) -> TrafficSnapshot {
+        TrafficSnapshot {
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            packets_sent: self.packets_sent.saturating_sub(earlier.packets_sent),
+            packets_received: self.packets_received.saturating_sub(earlier.packets_received),
+            handshake_failures: self.handshake_failures.saturating_sub(earlier.handshake_failures),
+            connections: self.connections.saturating_sub(earlier.connections),
+            rtt_micros: self.rtt_micros.saturating_sub(earlier.rtt_micros),
+            rtt_samples: self.rtt_samples.saturating_sub(earlier.rtt_samples),
+        }
+    }
+
+    fn add(&mut self, other: &TrafficSnapshot) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.packets_sent += other.packets_sent;
+        self.packets_received += other.packets_received;
+        self.handshake_failures += other.handshake_failures;
+        self.connections += other.connections;
+        self.rtt_micros += other.rtt_micros;
+        self.rtt_samples += other.rtt_samples;
+    }
+}
+
+/// Counters of a single relayed connection
+///
+/// Clones share the same connection totals, so the caller can keep one to
+/// report after handing the other to a relay loop.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionCounters {
+    connection: Arc<TrafficCounters>,
+    protocol: Arc<TrafficCounters>,
+}
+
+impl ConnectionCounters {
+    /// Record one chunk written into the tunnel
+    pub fn record_sent(&self, bytes: usize) {
+        self.connection.record_sent(bytes);
+        self.protocol.record_sent(bytes);
+    }
+
+    /// Record one chunk read from the tunnel
+    pub fn record_received(&self, bytes: usize) {
+        self.connection.record_received(bytes);
+        self.protocol.record_received(bytes);
+    }
+
+    /// Record the handshake round trip of this connection
+    pub fn record_rtt(&self, rtt: Duration) {
+        self.connection.record_rtt(rtt);
+        self.protocol.record_rtt(rtt);
+    }
+
+    /// Credit further traffic to another protocol (after a rotation)
+    pub fn set_protocol(&mut self, protocol: Arc<TrafficCounters>) {
+        self.protocol = protocol;
+    }
+
+    /// Totals for this connection
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        self.connection.snapshot()
+    }
+}
+
+/// Per-protocol traffic aggregates shared by all connections
+#[derive(Debug, Default)]
+pub struct TrafficRegistry {
+    protocols: Mutex<HashMap<ProtocolId, Arc<TrafficCounters>>>,
+}
+
+impl TrafficRegistry {
+    /// Aggregate counters for `protocol`, created on first use
+    pub fn protocol(&self, protocol: &ProtocolId) -> Arc<TrafficCounters> {
+        let mut protocols = self.protocols.lock().unwrap();
+        Arc::clone(protocols.entry(protocol.clone()).or_default())
+    }
+
+    /// Fresh counters for a new connection carried by `protocol`
+    pub fn connection(&self, protocol: &ProtocolId) -> ConnectionCounters {
+        let counters = ConnectionCounters {
+            connection: Arc::default(),
+            protocol: self.protocol(protocol),
+        };
+        counters.connection.record_connection();
+        counters.protocol.record_connection();
+        counters
+    }
+
+    /// Totals per protocol
+    pub fn by_protocol(&self) -> HashMap<ProtocolId, TrafficSnapshot> {
+        self.protocols
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, counters)| (id.clone(), counters.snapshot()))
+            .collect()
+    }
+
+    /// Totals across all protocols
+    pub fn total(&self) -> TrafficSnapshot {
+        let mut total = TrafficSnapshot::default();
+        for counters in self.protocols.lock().unwrap().values() {
+            total.add(&counters.snapshot());
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_counters() {
+        let registry = TrafficRegistry::default();
+        let https = ProtocolId::from("https");
+        let ssh = ProtocolId::from("ssh");
+
+        let mut first = registry.connection(&https);
+        let second = registry.connection(&https);
+        first.record_sent(100);
+        first.record_received(400);
+        second.record_sent(50);
+
+        let handle = first.clone();
+        first.set_protocol(registry.protocol(&ssh));
+        first.record_received(10);
+
+        assert_eq!(
+            handle.snapshot(),
+            TrafficSnapshot {
+                bytes_sent: 100,
+                bytes_received: 410,
+                packets_sent: 1,
+                packets_received: 2,
+                handshake_failures: 0,
+                connections: 1,
+                rtt_micros: 0,
+                rtt_samples: 0,
+            }
+        );
+        let by_protocol = registry.by_protocol();
+        assert_eq!(by_protocol[&https].bytes(), 550);
+        assert_eq!(by_protocol[&ssh].bytes(), 10);
+        assert_eq!(registry.total().bytes(), 560);
+        assert_eq!(registry.total().packets(), 4);
+    }
+
+    #[test]
+    fn test_protocol_breakdown() {
+        let registry = TrafficRegistry::default();
+        let https = ProtocolId::from("https");
+
+        registry.connection(&https).record_rtt(Duration::from_millis(40));
+        registry.connection(&https).record_rtt(Duration::from_millis(80));
+        registry.connection(&https);
+        registry.protocol(&https).record_handshake_failure();
+
+        let usage = registry.by_protocol()[&https];
+        assert_eq!(usage.connections, 3);
+        assert_eq!(usage.avg_rtt(), Some(Duration::from_millis(60)));
+        assert_eq!(usage.failure_rate(), 0.25);
+        assert_eq!(TrafficSnapshot::default().avg_rtt(), None);
+        assert_eq!(TrafficSnapshot::default().failure_rate(), 0.0);
+    }
+}
@@ -0,0 +1,393 @@
+//! Tunneled DNS with a client-side cache
+//!
+//! With `[socks.dns]`, the client answers plain DNS on `listen` (point the
+//! system resolver at it, ideally together with
+//! `socks.dns_leak_protection`) and carries each query over DNS-over-TCP
+//! to `upstream` through its own SOCKS5 listener, so lookups take the
+//! tunnel like any other connection. A lookup that way costs a full
+//! wrapped exchange, which over the DNS tunnel or a relay chain is slow,
+//! so answers are cached for as long as their TTLs allow:
+//!
+//! - positive answers for their smallest record TTL, capped at `max_ttl`
+//! - NXDOMAIN and empty answers for the SOA's negative TTL (RFC 2308),
+//!   or `negative_ttl` without an SOA, capped at `negative_ttl`
+//! - failures and truncated answers not at all
+//!
+//! Cached answers are served with their TTLs reduced by the time spent in
+//! the cache. Hits, negative hits, misses and the number of entries show
+//! up as `dns_cache` in the client's [`crate::ProtocolStats`].
+
+use crate::config::DnsProxyConfig;
+use crate::shapeshift::ShapeShiftController;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::RwLock;
+
+/// DNS record type SOA
+const TYPE_SOA: u16 = 6;
+
+/// DNS record type OPT, whose TTL field holds EDNS flags
+const TYPE_OPT: u16 = 41;
+
+/// Response code NXDOMAIN
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Time allowed for one tunneled lookup
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Cache counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DnsCacheStats {
+    /// Queries answered from a cached answer
+    pub hits: u64,
+    /// Queries answered from a cached NXDOMAIN or empty answer
+    pub negative_hits: u64,
+    /// Queries that went through the tunnel
+    pub misses: u64,
+    /// Answers currently cached
+    pub entries: usize,
+}
+
+/// Name (lowercased), type and class of a question
+type Question = (String, u16, u16);
+
+#[derive(Debug)]
+struct Entry {
+    response: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+    negative: bool,
+}
+
+/// TTL-respecting cache of DNS responses, keyed by question
+#[derive(Debug)]
+pub struct DnsCache {
+    entries: Mutex<HashMap<Question, Entry>>,
+    max_entries: usize,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DnsCache {
+    pub fn new(max_entries: usize, max_ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+            max_ttl,
+            negative_ttl,
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Cached response to `query`, with the query's ID and aged TTLs
+    pub fn get(&self, query: &[u8]) -> Option<Vec<u8>> {
+        self.get_at(query, Instant::now())
+    }
+
+    fn get_at(&self, query: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let question = question(query)?;
+        let mut entries = self.entries.lock().unwrap();
+        let response = match entries.get(&question) {
+            Some(entry) if entry.expires > now => {
+                let counter = if entry.negative { &self.negative_hits } else { &self.hits };
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut response = entry.response.clone();
+                response[..2].copy_from_slice(&query[..2]);
+                age_ttls(&mut response, now.duration_since(entry.stored).as_secs() as u32);
+                Some(response)
+            }
+            Some(_) => {
+                entries.remove(&question);
+                None
+            }
+            None => None,
+        };
+        if response.is_none() {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        response
+    }
+
+    /// Cache `response` to `query` if its TTLs and response code allow
+    pub fn insert(&self, query: &[u8], response: &[u8]) {
+        self.insert_at(query, response, Instant::now())
+    }
+
+    fn insert_at(&self, query: &[u8], response: &[u8], now: Instant) {
+        let (Some(question), Some((ttl, negative))) = (question(query), self.lifetime(response)) else {
+            return;
+        };
+        if ttl.is_zero() || self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&question) {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                let soonest = entries.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        let entry = Entry { response: response.to_vec(), stored: now, expires: now + ttl, negative };
+        entries.insert(question, entry);
+    }
+
+    /// How long `response` may be cached and whether it is negative
+    fn lifetime(&self, response: &[u8]) -> Option<(Duration, bool)> {
+        let header = response.get(..12)?;
+        // Truncated answers are retried over TCP by clients; keep the full one
+        if header[2] & 0x02 != 0 {
+            return None;
+        }
+        let rcode = header[3] & 0x0f;
+        let answers = u16::from_be_bytes([header[6], header[7]]);
+        let records = records(response)?;
+        match rcode {
+            0 if answers > 0 => {
+                let ttl = records
+                    .iter()
+                    .take(answers as usize)
+                    .filter(|record| record.rtype != TYPE_OPT)
+                    .map(|record| record.ttl)
+                    .min()?;
+                Some((Duration::from_secs(ttl as u64).min(self.max_ttl), false))
+            }
+            0 | RCODE_NXDOMAIN => {
+                let soa = records.iter().skip(answers as usize).find(|record| record.rtype == TYPE_SOA);
+                let ttl = match soa {
+                    Some(soa) => {
+                        let minimum = response.get(soa.rdata_end - 4..soa.rdata_end)?;
+                        let minimum = u32::from_be_bytes([minimum[0], minimum[1], minimum[2], minimum[3]]);
+                        Duration::from_secs(soa.ttl.min(minimum) as u64).min(self.negative_ttl)
+                    }
+                    None => self.negative_ttl,
+                };
+                Some((ttl, true))
+            }
+            _ => None,
+        }
+    }
+
+    /// Current counters
+    pub fn stats(&self) -> DnsCacheStats {
+        DnsCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+}
+
+/// The single question of a standard query
+fn question(message: &[u8]) -> Option<Question> {
+    let header = message.get(..12)?;
+    let opcode = (header[2] >> 3) & 0x0f;
+    if opcode != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *message.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Queries do not compress their one name
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(message.get(pos..pos + len)?).to_ascii_lowercase());
+        pos += len;
+    }
+    let fixed = message.get(pos..pos + 4)?;
+    Some((labels.join("."), u16::from_be_bytes([fixed[0], fixed[1]]), u16::from_be_bytes([fixed[2], fixed[3]])))
+}
+
+/// Type, TTL position and value, and end of data of a resource record
+struct Record {
+    rtype: u16,
+    ttl_pos: usize,
+    ttl: u32,
+    rdata_end: usize,
+}
+
+/// Resource records of all sections of `message`, in order
+fn records(message: &[u8]) -> Option<Vec<Record>> {
+    let count = |i: usize| u16::from_be_bytes([message[i], message[i + 1]]) as usize;
+    let (questions, total) = (count(4), count(6) + count(8) + count(10));
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = crate::bootstrap::skip_name(message, pos)? + 4;
+    }
+    let mut records = Vec::with_capacity(total);
+    for _ in 0..total {
+        pos = crate::bootstrap::skip_name(message, pos)?;
+        let fixed = message.get(pos..pos + 10)?;
+        let rdata_end = pos + 10 + u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if rdata_end > message.len() {
+            return None;
+        }
+        records.push(Record {
+            rtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            ttl_pos: pos + 4,
+            ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            rdata_end,
+        });
+        pos = rdata_end;
+    }
+    Some(records)
+}
+
+/// Reduce every TTL in `message` by `elapsed` seconds
+fn age_ttls(message: &mut [u8], elapsed: u32) {
+    for record in records(message).unwrap_or_default() {
+        if record.rtype != TYPE_OPT {
+            let ttl = record.ttl.saturating_sub(elapsed);
+            message[record.ttl_pos..record.ttl_pos + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+    }
+}
+
+/// Answer DNS on `dns.listen` through the SOCKS5 listener at `socks`,
+/// reporting cache counters to `controller`
+pub async fn serve(
+    dns: &DnsProxyConfig,
+    socks: SocketAddr,
+    controller: Option<Arc<RwLock<ShapeShiftController>>>,
+) -> anyhow::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(dns.listen).await?);
+    let cache = Arc::new(DnsCache::new(dns.max_entries, dns.max_ttl, dns.negative_ttl));
+    log::info!("Resolving DNS on {} through the tunnel to {}", dns.listen, dns.upstream);
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let (n, client) = socket.recv_from(&mut buf).await?;
+        let query = buf[..n].to_vec();
+        let (socket, cache, controller, upstream) = (Arc::clone(&socket), Arc::clone(&cache), controller.clone(), dns.upstream);
+        tokio::spawn(async move {
+            let response = match cache.get(&query) {
+                Some(response) => response,
+                None => match tokio::time::timeout(LOOKUP_TIMEOUT, lookup(socks, upstream, &query)).await {
+                    Ok(Ok(response)) => {
+                        cache.insert(&query, &response);
+                        response
+                    }
+                    Ok(Err(e)) => {
+                        log::warn!("Tunneled DNS lookup failed: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        log::warn!("Tunneled DNS lookup timed out");
+                        return;
+                    }
+                },
+            };
+            if let Err(e) = socket.send_to(&response, client).await {
+                log::debug!("Cannot answer DNS client {}: {}", client, e);
+            }
+            if let Some(controller) = controller {
+                controller.write().await.record_dns_cache(cache.stats());
+            }
+        });
+    }
+}
+
+/// Ask `upstream` over DNS-over-TCP through the SOCKS5 listener at `socks`
+async fn lookup(socks: SocketAddr, upstream: SocketAddr, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(socks).await?;
+    stream.set_nodelay(true)?;
+    crate::socks5::client_connect(&mut stream, &upstream.ip().to_string(), upstream.port(), None).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+    let len = stream.read_u16().await?;
+    let mut response = vec![0u8; len as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Response to `query` with `rcode` and the given records appended
+    fn respond(query: &[u8], rcode: u8, answers: u16, authority: u16, records: &[&[u8]]) -> Vec<u8> {
+        // Drop the query's OPT record
+        let mut response = query[..query.len() - 11].to_vec();
+        response[2..12].copy_from_slice(&[0x81, 0x80 | rcode, 0, 1, 0, answers as u8, 0, authority as u8, 0, 0]);
+        for record in records {
+            response.extend_from_slice(record);
+        }
+        response
+    }
+
+    #[test]
+    fn test_positive_cache() {
+        let cache = DnsCache::new(16, Duration::from_secs(3600), Duration::from_secs(60));
+        let now = Instant::now();
+        let query = crate::bootstrap::build_query(7, "Example.com", 1).unwrap();
+        assert_eq!(cache.get_at(&query, now), None);
+
+        let a = |ttl: u32, ip: [u8; 4]| {
+            let mut record = vec![0xc0, 12, 0, 1, 0, 1];
+            record.extend_from_slice(&ttl.to_be_bytes());
+            record.extend_from_slice(&[0, 4]);
+            record.extend_from_slice(&ip);
+            record
+        };
+        let response = respond(&query, 0, 2, 0, &[&a(300, [192, 0, 2, 1]), &a(120, [192, 0, 2, 2])]);
+        cache.insert_at(&query, &response, now);
+
+        // Another client asking in other case, 20 seconds later
+        let again = crate::bootstrap::build_query(9, "example.COM", 1).unwrap();
+        let cached = cache.get_at(&again, now + Duration::from_secs(20)).unwrap();
+        assert_eq!(cached[..2], [0, 9]);
+        let ttls: Vec<u32> = records(&cached).unwrap().iter().map(|record| record.ttl).collect();
+        assert_eq!(ttls, vec![280, 100]);
+
+        // The smallest TTL bounds the entry
+        assert_eq!(cache.get_at(&again, now + Duration::from_secs(121)), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 0));
+
+        // Failures are not cached
+        cache.insert_at(&query, &respond(&query, 2, 0, 0, &[]), now);
+        assert_eq!(cache.stats().entries, 0);
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let cache = DnsCache::new(1, Duration::from_secs(3600), Duration::from_secs(600));
+        let now = Instant::now();
+        let query = crate::bootstrap::build_query(1, "missing.example.com", 1).unwrap();
+
+        // SOA with TTL 900 and negative TTL (MINIMUM) 30
+        let mut soa = vec![0xc0, 20, 0, 6, 0, 1, 0, 0, 3, 132, 0, 24, 0xc0, 20, 0xc0, 20];
+        soa.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 30]);
+        cache.insert_at(&query, &respond(&query, RCODE_NXDOMAIN, 0, 1, &[&soa]), now);
+        assert!(cache.get_at(&query, now + Duration::from_secs(29)).is_some());
+        assert!(cache.get_at(&query, now + Duration::from_secs(30)).is_none());
+        assert_eq!(cache.stats().negative_hits, 1);
+
+        // Without an SOA, negative_ttl applies; a full cache makes room
+        cache.insert_at(&query, &respond(&query, RCODE_NXDOMAIN, 0, 0, &[]), now);
+        let other = crate::bootstrap::build_query(1, "other.example.com", 1).unwrap();
+        cache.insert_at(&other, &respond(&other, 0, 0, 0, &[]), now + Duration::from_secs(1));
+        assert_eq!(cache.stats().entries, 1);
+        assert!(cache.get_at(&other, now + Duration::from_secs(600)).is_some());
+        assert!(cache.get_at(&query, now + Duration::from_secs(1)).is_none());
+    }
+}
@@ -349,7 +349,8 @@ impl DnsDualTransportClient {
         let mut builder = snow::Builder::new(params);
 
         // Set remote public key
-        if let Some(ref remote_key) = self.noise_config.remote_public_key {
+        let remote_keys = self.noise_config.candidate_remote_keys(std::time::SystemTime::now());
+        if let Some(remote_key) = remote_keys.first() {
             let key = crate::noise_transport::NoiseKeypair::decode_public_key(remote_key)?;
             builder = builder.remote_public_key(&key);
         }
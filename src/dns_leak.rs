@@ -0,0 +1,96 @@
+//! DNS leak protection
+//!
+//! Applications that resolve names themselves instead of handing them to
+//! the SOCKS5 proxy send plaintext DNS queries straight to the local
+//! resolver's upstream, telling the censor every site visited. With
+//! `socks.dns_leak_protection = true`, the client installs firewall rules
+//! that block DNS (port 53 over UDP and TCP) leaving the host for anywhere
+//! but the tunnel servers and loopback, and counts the queries blocked:
+//!
+//! | Platform | Firewall                                                     |
+//! |----------|--------------------------------------------------------------|
+//! | Linux    | `NOOSHDAROO_DNS` chain in `iptables` and `ip6tables` `OUTPUT` |
+//! | macOS    | `com.apple/nooshdaroo-dns` `pf` anchor                       |
+//! | Windows  | `netsh advfirewall` block rules (not counted)                |
+//!
+//! Blocked queries show up as `dns_leaks_blocked` in the client's
+//! [`crate::ProtocolStats`] and as warnings in the log. The rules are
+//! removed when the client exits. Installing them needs administrator
+//! rights; the client refuses to start unprotected if it cannot.
+
+use crate::firewall::{Block, Rules};
+use crate::shapeshift::ShapeShiftController;
+use anyhow::Result;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+const BLOCK: Block = Block {
+    chain: "NOOSHDAROO_DNS",
+    anchor: "com.apple/nooshdaroo-dns",
+    rule: "nooshdaroo DNS leak protection",
+    port: Some(53),
+};
+
+/// How often the blocked query count is checked
+const MONITOR_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Installed DNS blocking rules, removed when dropped
+#[derive(Debug)]
+pub struct DnsLeakGuard {
+    rules: Rules,
+}
+
+impl DnsLeakGuard {
+    /// Block DNS to anywhere but `servers` and loopback
+    pub fn install(servers: &[IpAddr]) -> Result<Self> {
+        let rules = Rules::install(BLOCK, servers)?;
+        log::info!("DNS leak protection enabled");
+        Ok(Self { rules })
+    }
+
+    /// Queries blocked so far, where the firewall counts them
+    pub fn blocked(&self) -> Result<Option<u64>> {
+        self.rules.blocked()
+    }
+
+    /// Remove the rules
+    pub fn remove(self) -> Result<()> {
+        self.rules.remove()
+    }
+}
+
+/// Report newly blocked queries to `controller` and the log until the
+/// guard is dropped
+pub fn spawn_monitor(guard: &Arc<DnsLeakGuard>, controller: Option<Arc<RwLock<ShapeShiftController>>>) {
+    let guard = Arc::downgrade(guard);
+    tokio::spawn(async move {
+        let mut reported = 0;
+        loop {
+            tokio::time::sleep(MONITOR_INTERVAL).await;
+            let Some(guard) = guard.upgrade() else {
+                return;
+            };
+            let blocked = match tokio::task::spawn_blocking(move || guard.blocked()).await {
+                Ok(Ok(Some(blocked))) => blocked,
+                Ok(Ok(None)) => return,
+                Ok(Err(e)) => {
+                    log::debug!("Cannot read the DNS leak counters: {}", e);
+                    continue;
+                }
+                Err(_) => return,
+            };
+            if blocked > reported {
+                log::warn!(
+                    "Blocked {} DNS queries leaking outside the tunnel; resolve names through the proxy (socks5h)",
+                    blocked - reported
+                );
+                if let Some(ref controller) = controller {
+                    controller.write().await.record_dns_leaks(blocked - reported);
+                }
+                reported = blocked;
+            }
+        }
+    });
+}
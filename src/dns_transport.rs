@@ -19,7 +19,7 @@
 //! ```
 
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -33,17 +33,87 @@ use tokio::time::{timeout, Duration};
 use crate::dns_tunnel::{
     build_dns_query, build_dns_response, parse_dns_query, parse_dns_response,
 };
+use crate::cluster::{Cluster, Event as ClusterEvent, Route};
+use crate::config::FecConfig;
+use crate::fec::{FecDecoder, FecEncoder};
+use crate::nat_keepalive::NatKeepalive;
+use crate::port_hop::{PortHopListener, PortHopSchedule};
+
+/// Time a query after a long silence has to be answered before the NAT
+/// mapping is considered lost
+const KEEPALIVE_ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// DNS tunnel transport for client-side
 pub struct DnsTransportClient {
     socket: Arc<UdpSocket>,
     server_addr: SocketAddr,
     session_id: u16,
+    activity: Arc<std::sync::Mutex<QueryActivity>>,
+    nat: Option<Arc<NatKeepalive>>,
+    /// Server port schedule; the socket is left unconnected when set
+    hop: Option<PortHopSchedule>,
+    fec: Option<Arc<std::sync::Mutex<FecCodec>>>,
+}
+
+/// Forward error correction of one session
+struct FecCodec {
+    encoder: FecEncoder,
+    decoder: FecDecoder,
+    /// Payloads received or rebuilt, waiting to be delivered
+    ready: VecDeque<Vec<u8>>,
+}
+
+impl FecCodec {
+    fn new(config: &FecConfig) -> Self {
+        Self {
+            encoder: FecEncoder::new(config),
+            decoder: FecDecoder::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Frame `data`; `Some(group)` when it opened a group that has to be
+    /// flushed after the flush delay
+    fn encode(&mut self, data: &[u8]) -> (Vec<Vec<u8>>, Option<u32>) {
+        let was_open = self.encoder.open_group();
+        let datagrams = self.encoder.push(data);
+        match (was_open, self.encoder.open_group()) {
+            (None, Some(group)) => (datagrams, Some(group)),
+            _ => (datagrams, None),
+        }
+    }
+}
+
+/// Query timing seen by the NAT keepalive
+struct QueryActivity {
+    last_query: std::time::Instant,
+    /// Oldest unanswered query: when it was sent and the silence before it
+    outstanding: Option<(std::time::Instant, Duration)>,
+}
+
+impl QueryActivity {
+    fn record_query(&mut self) {
+        let now = std::time::Instant::now();
+        if self.outstanding.is_none() {
+            self.outstanding = Some((now, now - self.last_query));
+        }
+        self.last_query = now;
+    }
 }
 
 impl DnsTransportClient {
     /// Create new DNS transport client
     pub async fn connect(server_addr: SocketAddr) -> Result<Self> {
+        Self::open(server_addr, None).await
+    }
+
+    /// Create a client that sends each query to the port `schedule` gives
+    /// the current time slot, on the host of `server_addr`
+    pub async fn connect_hopping(server_addr: SocketAddr, schedule: PortHopSchedule) -> Result<Self> {
+        Self::open(server_addr, Some(schedule)).await
+    }
+
+    async fn open(server_addr: SocketAddr, hop: Option<PortHopSchedule>) -> Result<Self> {
         // Bind to random local port - match server's interface for localhost
         let local_addr: SocketAddr = if server_addr.is_ipv4() {
             if server_addr.ip().is_loopback() {
@@ -64,7 +134,9 @@ impl DnsTransportClient {
 
         // Connect the UDP socket to server (like WireGuard) for NAT traversal
         // This creates a "connected" UDP socket that NAT routers handle bidirectionally
-        socket.connect(server_addr).await?;
+        if hop.is_none() {
+            socket.connect(server_addr).await?;
+        }
 
         let session_id = rand::random::<u16>();
 
@@ -78,44 +150,192 @@ impl DnsTransportClient {
             socket: Arc::new(socket),
             server_addr,
             session_id,
+            activity: Arc::new(std::sync::Mutex::new(QueryActivity {
+                last_query: std::time::Instant::now(),
+                outstanding: None,
+            })),
+            nat: None,
+            hop,
+            fec: None,
         })
     }
 
+    /// Send one query to the server, at the current hop port when hopping
+    async fn send_query(socket: &UdpSocket, server_addr: SocketAddr, hop: Option<&PortHopSchedule>, query: &[u8]) -> io::Result<usize> {
+        match hop {
+            Some(schedule) => socket.send_to(query, SocketAddr::new(server_addr.ip(), schedule.current_port())).await,
+            None => socket.send(query).await,
+        }
+    }
+
+    /// Keep the NAT mapping open with keepalive queries whenever the tunnel
+    /// is quieter than `nat`'s interval, and teach it from their answers
+    pub fn with_nat_keepalive(mut self, nat: Arc<NatKeepalive>) -> Self {
+        let socket = Arc::downgrade(&self.socket);
+        let activity = Arc::downgrade(&self.activity);
+        let keepalive = Arc::clone(&nat);
+        let session_id = self.session_id;
+        let server_addr = self.server_addr;
+        let hop = self.hop.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(interval) = keepalive.interval() else {
+                    return;
+                };
+                tokio::time::sleep(Duration::from_secs(1).min(interval)).await;
+                let (Some(socket), Some(activity)) = (socket.upgrade(), activity.upgrade()) else {
+                    return;
+                };
+
+                let due = {
+                    let mut activity = activity.lock().unwrap();
+                    if let Some((sent, silence)) = activity.outstanding {
+                        if sent.elapsed() >= KEEPALIVE_ANSWER_TIMEOUT {
+                            keepalive.record_unanswered(silence);
+                            activity.outstanding = None;
+                        }
+                    }
+                    let due = activity.last_query.elapsed() >= interval;
+                    if due {
+                        activity.record_query();
+                    }
+                    due
+                };
+                if due {
+                    // A plain lookup of the tunnel domain; the server answers it empty
+                    log::debug!("DNS transport idle for {:?}, sending keepalive query", interval);
+                    let query = build_dns_query(&[], session_id);
+                    if let Err(e) = Self::send_query(&socket, server_addr, hop.as_ref(), &query).await {
+                        log::debug!("DNS keepalive send failed: {}", e);
+                    }
+                }
+            }
+        });
+        self.nat = Some(nat);
+        self
+    }
+
+    /// Add parity datagrams to everything sent and rebuild lost answers
+    /// from the server's, when `config` enables forward error correction
+    pub fn with_fec(mut self, config: &FecConfig) -> Self {
+        if config.enabled {
+            self.fec = Some(Arc::new(std::sync::Mutex::new(FecCodec::new(config))));
+        }
+        self
+    }
+
     /// Send data through DNS tunnel
     pub async fn send(&self, data: &[u8]) -> Result<()> {
-        // Build DNS query with payload
-        let transaction_id = self.session_id;
-        let dns_query = build_dns_query(data, transaction_id);
-
-        log::debug!(
-            "DNS transport sending {} bytes (DNS packet: {} bytes)",
-            data.len(),
-            dns_query.len()
-        );
+        let datagrams = match self.fec {
+            Some(ref fec) => self.encode(fec, data),
+            None => vec![data.to_vec()],
+        };
+
+        for datagram in datagrams {
+            // Build DNS query with payload
+            let transaction_id = self.session_id;
+            let dns_query = build_dns_query(&datagram, transaction_id);
 
-        // Send UDP packet (using send() not send_to() since socket is connected)
-        self.socket.send(&dns_query).await?;
+            log::debug!(
+                "DNS transport sending {} bytes (DNS packet: {} bytes)",
+                datagram.len(),
+                dns_query.len()
+            );
+
+            // Send UDP packet (using send() not send_to() since socket is connected)
+            self.activity.lock().unwrap().record_query();
+            Self::send_query(&self.socket, self.server_addr, self.hop.as_ref(), &dns_query).await?;
+        }
 
         Ok(())
     }
 
+    /// Frame `data` for forward error correction, sending the parity of a
+    /// group it opens once the flush delay has passed
+    fn encode(&self, fec: &Arc<std::sync::Mutex<FecCodec>>, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut codec = fec.lock().unwrap();
+        let (datagrams, opened) = codec.encode(data);
+        if let Some(group) = opened {
+            let delay = codec.encoder.flush_delay();
+            let fec = Arc::downgrade(fec);
+            let socket = Arc::downgrade(&self.socket);
+            let session_id = self.session_id;
+            let server_addr = self.server_addr;
+            let hop = self.hop.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let (Some(fec), Some(socket)) = (fec.upgrade(), socket.upgrade()) else {
+                    return;
+                };
+                let parity = fec.lock().unwrap().encoder.flush(group);
+                for datagram in parity {
+                    let query = build_dns_query(&datagram, session_id);
+                    if let Err(e) = Self::send_query(&socket, server_addr, hop.as_ref(), &query).await {
+                        log::debug!("DNS parity send failed: {}", e);
+                        return;
+                    }
+                }
+            });
+        }
+        datagrams
+    }
+
     /// Receive data from DNS tunnel
     pub async fn receive(&self) -> Result<Vec<u8>> {
+        if let Some(ref fec) = self.fec {
+            if let Some(payload) = fec.lock().unwrap().ready.pop_front() {
+                return Ok(payload);
+            }
+        }
+
         // Wait for DNS response (using recv() not recv_from() since socket is connected)
         let mut buf = vec![0u8; 4096];
 
-        let n = timeout(Duration::from_secs(10), self.socket.recv(&mut buf))
-            .await
-            .map_err(|_| anyhow!("DNS receive timeout"))?
-            .map_err(|e| anyhow!("DNS receive error: {}", e))?;
+        loop {
+            let n = timeout(Duration::from_secs(10), self.recv_answer(&mut buf))
+                .await
+                .map_err(|_| anyhow!("DNS receive timeout"))?
+                .map_err(|e| anyhow!("DNS receive error: {}", e))?;
 
-        log::debug!("DNS transport received {} bytes", n);
+            log::debug!("DNS transport received {} bytes", n);
+            if let Some((_, silence)) = self.activity.lock().unwrap().outstanding.take() {
+                if let Some(ref nat) = self.nat {
+                    nat.record_answered(silence);
+                }
+            }
 
-        // Parse DNS response to extract payload
-        let payload = parse_dns_response(&buf[..n])
-            .map_err(|e| anyhow!("Failed to parse DNS response: {}", e))?;
+            // Parse DNS response to extract payload
+            let payload = parse_dns_response(&buf[..n])
+                .map_err(|e| anyhow!("Failed to parse DNS response: {}", e))?;
+
+            // Keepalive answers carry no datagram
+            let Some(fec) = self.fec.as_ref().filter(|_| !payload.is_empty()) else {
+                return Ok(payload);
+            };
+            let mut codec = fec.lock().unwrap();
+            match codec.decoder.receive(&payload) {
+                Ok(payloads) => codec.ready.extend(payloads),
+                Err(e) => log::debug!("DNS transport dropping answer: {}", e),
+            }
+            if let Some(payload) = codec.ready.pop_front() {
+                return Ok(payload);
+            }
+        }
+    }
 
-        Ok(payload)
+    /// Receive one datagram from the server; an unconnected hopping socket
+    /// takes answers from any of its ports
+    async fn recv_answer(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.hop.is_none() {
+            return self.socket.recv(buf).await;
+        }
+        loop {
+            let (n, from) = self.socket.recv_from(buf).await?;
+            if from.ip() == self.server_addr.ip() {
+                return Ok(n);
+            }
+            log::debug!("Ignoring datagram from {}", from);
+        }
     }
 
     /// Send data and wait for response
@@ -127,13 +347,41 @@ impl DnsTransportClient {
 
 /// DNS tunnel transport for server-side
 pub struct DnsTransportServer {
-    socket: Arc<UdpSocket>,
+    socket: ServerSocket,
     sessions: Arc<Mutex<HashMap<SocketAddr, Session>>>,
+    fec: Option<FecConfig>,
+    /// Queries received or rebuilt, waiting to be delivered
+    ready: Mutex<VecDeque<(Vec<u8>, SocketAddr, u16)>>,
+    /// Other servers behind the same load balancer
+    cluster: Option<Arc<Cluster>>,
+}
+
+/// Where the server listens
+#[derive(Clone)]
+enum ServerSocket {
+    Fixed(Arc<UdpSocket>),
+    Hopping(Arc<PortHopListener>),
+}
+
+impl ServerSocket {
+    /// Send to `target`, from hop port `port` when hopping
+    async fn send_to(&self, packet: &[u8], target: SocketAddr, port: u16) -> io::Result<usize> {
+        match self {
+            Self::Fixed(socket) => socket.send_to(packet, target).await,
+            Self::Hopping(listener) => listener.send_to(packet, target, port).await,
+        }
+    }
 }
 
 struct Session {
     last_seen: std::time::Instant,
     transaction_id: u16,
+    /// Hop port the client last queried, which answers must leave from
+    port: u16,
+    fec: Option<FecCodec>,
+    /// Gossip address of the cluster node the client's queries arrive at,
+    /// when it is not this one
+    via: Option<SocketAddr>,
 }
 
 impl DnsTransportServer {
@@ -144,34 +392,133 @@ impl DnsTransportServer {
         log::info!("DNS transport server listening on UDP {}", listen_addr);
 
         Ok(Self {
-            socket: Arc::new(socket),
+            socket: ServerSocket::Fixed(Arc::new(socket)),
             sessions: Arc::new(Mutex::new(HashMap::new())),
+            fec: None,
+            ready: Mutex::new(VecDeque::new()),
+            cluster: None,
         })
     }
 
+    /// Create a server listening on `ip` at the ports of `schedule`
+    pub async fn bind_hopping(ip: std::net::IpAddr, schedule: PortHopSchedule) -> Result<Self> {
+        let listener = PortHopListener::bind(ip, schedule).await?;
+
+        log::info!("DNS transport server hopping UDP ports on {} (now {:?})", ip, listener.ports());
+
+        Ok(Self {
+            socket: ServerSocket::Hopping(listener),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            fec: None,
+            ready: Mutex::new(VecDeque::new()),
+            cluster: None,
+        })
+    }
+
+    /// Expect parity datagrams from clients and add them to answers, at
+    /// the redundancy each client uses, when `config` enables forward
+    /// error correction
+    pub fn with_fec(mut self, config: &FecConfig) -> Self {
+        if config.enabled {
+            self.fec = Some(config.clone());
+        }
+        self
+    }
+
+    /// Share sessions and quotas with the other nodes of `cluster`
+    pub fn with_cluster(mut self, cluster: Arc<Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Next datagram from a client
+    async fn recv_datagram(&self) -> io::Result<(Vec<u8>, SocketAddr, u16)> {
+        match self.socket {
+            ServerSocket::Fixed(ref socket) => {
+                let mut buf = vec![0u8; 4096];
+                let (n, src_addr) = socket.recv_from(&mut buf).await?;
+                buf.truncate(n);
+                Ok((buf, src_addr, 0))
+            }
+            ServerSocket::Hopping(ref listener) => listener.recv_from().await,
+        }
+    }
+
     /// Receive DNS query from client
     pub async fn receive_query(&self) -> Result<(Vec<u8>, SocketAddr, u16)> {
-        let mut buf = vec![0u8; 4096];
+        loop {
+            if let Some(query) = self.ready.lock().await.pop_front() {
+                return Ok(query);
+            }
 
-        // Receive UDP packet
-        let (n, src_addr) = self.socket.recv_from(&mut buf).await?;
+            // Receive UDP packet, directly or forwarded by another node
+            let (buf, src_addr, port, via) = match self.cluster {
+                Some(ref cluster) => tokio::select! {
+                    received = self.recv_datagram() => {
+                        let (buf, src_addr, port) = received?;
+                        if let Route::Forward(node) = cluster.route(src_addr) {
+                            cluster.forward(node, src_addr, port, &buf).await;
+                            continue;
+                        }
+                        (buf, src_addr, port, None)
+                    }
+                    event = cluster.next_event() => match event {
+                        ClusterEvent::Forwarded { client, port, datagram, via } => (datagram, client, port, Some(via)),
+                        ClusterEvent::Reply { client, port, packet } => {
+                            self.socket.send_to(&packet, client, port).await?;
+                            continue;
+                        }
+                    },
+                },
+                None => {
+                    let (buf, src_addr, port) = self.recv_datagram().await?;
+                    (buf, src_addr, port, None)
+                }
+            };
 
-        log::debug!("DNS transport received {} bytes from {}", n, src_addr);
+            log::debug!("DNS transport received {} bytes from {}", buf.len(), src_addr);
+            if let Some(ref cluster) = self.cluster {
+                if !cluster.charge(src_addr.ip(), buf.len() as u64) {
+                    log::debug!("DNS transport dropping query from {}: over the cluster quota", src_addr);
+                    continue;
+                }
+            }
 
-        // Parse DNS query
-        let (transaction_id, payload) = parse_dns_query(&buf[..n])
-            .map_err(|e| anyhow!("Failed to parse DNS query: {}", e))?;
+            // Parse DNS query
+            let (transaction_id, payload) = parse_dns_query(&buf)
+                .map_err(|e| anyhow!("Failed to parse DNS query: {}", e))?;
 
-        // Track session
-        self.sessions.lock().await.insert(
-            src_addr,
-            Session {
+            // Track session
+            let mut sessions = self.sessions.lock().await;
+            let session = sessions.entry(src_addr).or_insert_with(|| Session {
                 last_seen: std::time::Instant::now(),
                 transaction_id,
-            },
-        );
-
-        Ok((payload, src_addr, transaction_id))
+                port,
+                fec: self.fec.as_ref().map(FecCodec::new),
+                via,
+            });
+            session.last_seen = std::time::Instant::now();
+            session.transaction_id = transaction_id;
+            session.port = port;
+            session.via = via;
+
+            // Keepalive queries carry no datagram
+            let Some(codec) = session.fec.as_mut().filter(|_| !payload.is_empty()) else {
+                return Ok((payload, src_addr, transaction_id));
+            };
+            match codec.decoder.receive(&payload) {
+                Ok(payloads) => {
+                    // Answer with the client's redundancy
+                    if let Some((data_shards, parity_shards)) = codec.decoder.peer_shape() {
+                        codec.encoder.set_shape(data_shards, parity_shards);
+                    }
+                    drop(sessions);
+                    let mut ready = self.ready.lock().await;
+                    ready.extend(payloads.into_iter().map(|payload| (payload, src_addr, transaction_id)));
+                }
+                Err(e) => log::debug!("DNS transport dropping query from {}: {}", src_addr, e),
+            }
+        }
     }
 
     /// Send DNS response to client
@@ -181,22 +528,66 @@ impl DnsTransportServer {
         client_addr: SocketAddr,
         transaction_id: u16,
     ) -> Result<()> {
-        // Build DNS response
-        let dns_response = build_dns_response(&[], data, transaction_id);
-
-        log::debug!(
-            "DNS transport sending {} bytes to {} (DNS packet: {} bytes)",
-            data.len(),
-            client_addr,
-            dns_response.len()
-        );
+        let (datagrams, port, via) = {
+            let mut sessions = self.sessions.lock().await;
+            let session = sessions.get_mut(&client_addr);
+            let port = session.as_ref().map(|s| s.port).unwrap_or_default();
+            let via = session.as_ref().and_then(|s| s.via);
+            match session.and_then(|s| s.fec.as_mut()).filter(|_| !data.is_empty()) {
+                Some(codec) => {
+                    let (datagrams, opened) = codec.encode(data);
+                    if let Some(group) = opened {
+                        self.flush_after(codec.encoder.flush_delay(), group, client_addr, transaction_id);
+                    }
+                    (datagrams, port, via)
+                }
+                None => (vec![data.to_vec()], port, via),
+            }
+        };
 
-        // Send UDP packet
-        self.socket.send_to(&dns_response, client_addr).await?;
+        for datagram in datagrams {
+            // Build DNS response
+            let dns_response = build_dns_response(&[], &datagram, transaction_id);
+
+            log::debug!(
+                "DNS transport sending {} bytes to {} (DNS packet: {} bytes)",
+                datagram.len(),
+                client_addr,
+                dns_response.len()
+            );
+
+            // Send UDP packet
+            deliver(&self.socket, self.cluster.as_deref(), via, &dns_response, client_addr, port).await?;
+        }
 
         Ok(())
     }
 
+    /// Send the parity of `client_addr`'s answer group `group` once `delay`
+    /// has passed, unless the group filled up before
+    fn flush_after(&self, delay: Duration, group: u32, client_addr: SocketAddr, transaction_id: u16) {
+        let socket = self.socket.clone();
+        let cluster = self.cluster.clone();
+        let sessions = Arc::downgrade(&self.sessions);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let Some(sessions) = sessions.upgrade() else {
+                return;
+            };
+            let (parity, port, via) = match sessions.lock().await.get_mut(&client_addr) {
+                Some(Session { fec: Some(codec), port, via, .. }) => (codec.encoder.flush(group), *port, *via),
+                _ => return,
+            };
+            for datagram in parity {
+                let response = build_dns_response(&[], &datagram, transaction_id);
+                if let Err(e) = deliver(&socket, cluster.as_deref(), via, &response, client_addr, port).await {
+                    log::debug!("DNS parity send to {} failed: {}", client_addr, e);
+                    return;
+                }
+            }
+        });
+    }
+
     /// Clean up old sessions
     pub async fn cleanup_sessions(&self, max_age: Duration) {
         let now = std::time::Instant::now();
@@ -206,6 +597,25 @@ impl DnsTransportServer {
     }
 }
 
+/// Send `packet` to `client`, back through the cluster node `via` when its
+/// queries arrive there
+async fn deliver(
+    socket: &ServerSocket,
+    cluster: Option<&Cluster>,
+    via: Option<SocketAddr>,
+    packet: &[u8],
+    client: SocketAddr,
+    port: u16,
+) -> io::Result<()> {
+    match (cluster, via) {
+        (Some(cluster), Some(via)) => cluster.reply(via, client, port, packet).await,
+        _ => {
+            socket.send_to(packet, client, port).await?;
+        }
+    }
+    Ok(())
+}
+
 /// DNS Transport Stream Adapter
 ///
 /// Makes DnsTransportClient compatible with AsyncRead/AsyncWrite traits
@@ -399,3 +809,97 @@ impl AsyncWrite for DnsStream {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NatKeepaliveConfig;
+
+    #[tokio::test]
+    async fn test_nat_keepalive_query() {
+        let server = DnsTransportServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let server_addr = match server.socket {
+            ServerSocket::Fixed(ref socket) => socket.local_addr().unwrap(),
+            ServerSocket::Hopping(_) => unreachable!(),
+        };
+
+        let interval = Duration::from_millis(100);
+        let nat = Arc::new(NatKeepalive::new(&NatKeepaliveConfig {
+            enabled: true,
+            initial_interval: interval,
+            min_interval: interval,
+            max_interval: interval,
+        }));
+        let client = DnsTransportClient::connect(server_addr).await.unwrap().with_nat_keepalive(nat);
+
+        // The idle client sends an empty lookup, which the server answers empty
+        let (payload, client_addr, tx_id) = timeout(Duration::from_secs(2), server.receive_query())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(payload.is_empty());
+        server.send_response(&[], client_addr, tx_id).await.unwrap();
+        // The answer carries no tunnel data, but shows the mapping is alive
+        assert!(client.receive().await.is_err());
+        assert!(client.activity.lock().unwrap().outstanding.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_port_hopping_round_trip() {
+        let schedule = PortHopSchedule::new(&crate::config::PortHoppingConfig {
+            enabled: true,
+            key: Some("test".to_string()),
+            port_range: (45000, 45999),
+            slot: Duration::from_secs(3600),
+        })
+        .unwrap();
+        let server = DnsTransportServer::bind_hopping("127.0.0.1".parse().unwrap(), schedule.clone()).await.unwrap();
+
+        // The port of the server address plays no part
+        let client = DnsTransportClient::connect_hopping("127.0.0.1:1".parse().unwrap(), schedule.clone())
+            .await
+            .unwrap();
+        client.send(b"hello").await.unwrap();
+        let (payload, client_addr, tx_id) = timeout(Duration::from_secs(2), server.receive_query())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, b"hello");
+        server.send_response(b"world", client_addr, tx_id).await.unwrap();
+        assert_eq!(client.receive().await.unwrap(), b"world");
+    }
+
+    #[tokio::test]
+    async fn test_fec_round_trip() {
+        let config = FecConfig {
+            enabled: true,
+            redundancy: 0.5,
+            group_size: 2,
+            flush_delay: Duration::from_millis(10),
+        };
+        let server = DnsTransportServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap().with_fec(&config);
+        let server_addr = match server.socket {
+            ServerSocket::Fixed(ref socket) => socket.local_addr().unwrap(),
+            ServerSocket::Hopping(_) => unreachable!(),
+        };
+        let client = DnsTransportClient::connect(server_addr).await.unwrap().with_fec(&config);
+
+        // Parity of a full group and of a flushed one is taken in, never delivered
+        for message in [&b"one"[..], b"two", b"three"] {
+            client.send(message).await.unwrap();
+        }
+        for message in [&b"one"[..], b"two", b"three"] {
+            let (payload, client_addr, tx_id) = timeout(Duration::from_secs(2), server.receive_query())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(payload, message);
+            server.send_response(b"ok", client_addr, tx_id).await.unwrap();
+        }
+        for _ in 0..3 {
+            assert_eq!(client.receive().await.unwrap(), b"ok");
+        }
+        assert!(timeout(Duration::from_millis(100), server.receive_query()).await.is_err());
+        assert!(timeout(Duration::from_millis(100), client.receive()).await.is_err());
+    }
+}
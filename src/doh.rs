@@ -0,0 +1,179 @@
+//! DNS over HTTPS for nooshdaroo's own lookups
+//!
+//! Names the client resolves itself, such as a server's host name or the
+//! target of a path test, go to the `[doh]` servers (RFC 8484, GET with a
+//! `dns` parameter) instead of the local network's resolver, which in a
+//! censored network may answer with a blocked or attacker-chosen address.
+//! Each DoH server is dialled at its configured addresses and authenticated
+//! by its certificate, so the lookup does not depend on local DNS at all.
+//! Names mapped in `[hosts]` are never looked up (see [`crate::hosts`]).
+
+use crate::config::{DohConfig, DohServerConfig, HostsConfig};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// DNS record type A
+const TYPE_A: u16 = 1;
+
+/// DNS record type AAAA
+const TYPE_AAAA: u16 = 28;
+
+/// Largest DNS message accepted
+const MAX_RESPONSE_SIZE: usize = 65535;
+
+/// Addresses of `host`, which may already be an address or be mapped in
+/// `hosts`
+pub async fn resolve(config: &DohConfig, hosts: &HostsConfig, host: &str) -> Result<Vec<IpAddr>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    if let Some(addrs) = crate::hosts::lookup(hosts, host) {
+        return Ok(addrs);
+    }
+    if !config.enabled {
+        return system_lookup(host).await;
+    }
+
+    let mut errors = Vec::new();
+    for server in &config.servers {
+        let (query_server, name, timeout) = (server.clone(), host.to_string(), config.timeout);
+        let result = tokio::task::spawn_blocking(move || lookup(&query_server, &name, timeout))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result);
+        match result {
+            Ok(addrs) => {
+                log::debug!("Resolved {} to {:?} over DoH", host, addrs);
+                return Ok(addrs);
+            }
+            Err(e) => {
+                log::warn!("DoH server {} failed for {}: {}", server.url, host, e);
+                errors.push(e);
+            }
+        }
+    }
+    if config.system_fallback {
+        log::warn!("No DoH server resolved {}; falling back to the system resolver", host);
+        return system_lookup(host).await;
+    }
+    Err(format!("No DoH server resolved {} ({})", host, errors.join("; ")))
+}
+
+/// Address of `target` ("host:port", "[ipv6]:port" or an address), the
+/// first of its host's addresses
+pub async fn resolve_socket_addr(config: &DohConfig, hosts: &HostsConfig, target: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let (host, port) = crate::acl::split_target(target).map_err(|e| e.to_string())?;
+    let ip = *resolve(config, hosts, host).await?.first().ok_or_else(|| format!("No address for {}", host))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn system_lookup(host: &str) -> Result<Vec<IpAddr>, String> {
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .map(|addr| addr.ip())
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("No address for {}", host));
+    }
+    Ok(addrs)
+}
+
+/// A and AAAA records of `name` from `server` (blocking), IPv4 first
+fn lookup(server: &DohServerConfig, name: &str, timeout: Duration) -> Result<Vec<IpAddr>, String> {
+    let port = crate::bootstrap::https_port(&server.url);
+    let addrs: Vec<SocketAddr> = server.addresses.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+    let mut found = Vec::new();
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        // ID 0, as RFC 8484 recommends for cacheable GET requests
+        let query = crate::bootstrap::build_query(0, name, qtype)?;
+        let separator = if server.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}dns={}", server.url, separator, URL_SAFE_NO_PAD.encode(&query));
+        let response = crate::bootstrap::https_get_from(&url, &addrs, "application/dns-message", timeout, MAX_RESPONSE_SIZE)?;
+        found.extend(parse_addresses(&response)?);
+    }
+    if found.is_empty() {
+        return Err(format!("No A or AAAA records for {}", name));
+    }
+    Ok(found)
+}
+
+/// A and AAAA answers of a DNS response
+fn parse_addresses(response: &[u8]) -> Result<Vec<IpAddr>, String> {
+    let invalid = || "Malformed DNS response".to_string();
+    if response.len() < 12 {
+        return Err(invalid());
+    }
+    let rcode = response[3] & 0x0f;
+    // NXDOMAIN is an answer too, just an empty one
+    if rcode != 0 && rcode != 3 {
+        return Err(format!("DNS query failed (rcode {})", rcode));
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = crate::bootstrap::skip_name(response, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = crate::bootstrap::skip_name(response, pos).ok_or_else(invalid)?;
+        let fixed = response.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        let rdata = response.get(pos..pos + rdlength).ok_or_else(invalid)?;
+        pos += rdlength;
+        // CNAMEs are followed by the resolver; only their targets matter
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+            (TYPE_AAAA, 16) => addrs.push(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap()))),
+            _ => {}
+        }
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_addresses() {
+        let mut response = crate::bootstrap::build_query(0, "example.com", TYPE_A).unwrap();
+        // Flags QR|RD|RA, one question and three answers, no OPT record
+        response[2..12].copy_from_slice(&[0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0]);
+        response.truncate(response.len() - 11);
+        // CNAME to a name elsewhere in the message, then A and AAAA records
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 7]);
+        response.extend_from_slice(&[0xc0, 12, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        response.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+
+        let addrs = parse_addresses(&response).unwrap();
+        assert_eq!(addrs, vec!["192.0.2.7".parse::<IpAddr>().unwrap(), "2001:db8::7".parse().unwrap()]);
+
+        response[3] = 0x82; // SERVFAIL
+        assert!(parse_addresses(&response).is_err());
+        assert!(parse_addresses(&response[..8]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_addresses_without_lookup() {
+        // No server can answer, so any lookup would fail
+        let config = DohConfig { servers: Vec::new(), ..DohConfig::default() };
+        let mut hosts = HostsConfig::default();
+        assert_eq!(resolve_socket_addr(&config, &hosts, "192.0.2.7:443").await.unwrap(), "192.0.2.7:443".parse().unwrap());
+        assert_eq!(resolve(&config, &hosts, "[2001:db8::7]").await.unwrap(), vec!["2001:db8::7".parse::<IpAddr>().unwrap()]);
+        assert!(resolve_socket_addr(&config, &hosts, "server.example:443").await.is_err());
+        hosts.entries.insert("server.example".to_string(), "192.0.2.9".parse().unwrap());
+        assert_eq!(resolve_socket_addr(&config, &hosts, "server.example:443").await.unwrap(), "192.0.2.9:443".parse().unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+//! Graceful server drain and listener handoff
+//!
+//! `SIGUSR2`, which `nooshdaroo drain <PID|PIDFILE>` sends, puts the server
+//! into drain mode: it stops accepting tunnels, keeps relaying the open ones
+//! until they finish and then exits, or once `[server.drain] timeout` has
+//! elapsed.
+//!
+//! With `handoff = true` the server first starts a new process with the same
+//! executable and arguments and hands it the listening sockets, so an
+//! upgraded binary takes over without a window in which connections are
+//! refused. The sockets are inherited file descriptors whose numbers are
+//! passed in [`LISTEN_FDS_ENV`]; [`crate::listen::TcpListeners::bind`]
+//! adopts them instead of binding.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Environment variable listing the listening sockets a draining server
+/// handed to its successor
+pub const LISTEN_FDS_ENV: &str = "NOOSHDAROO_LISTEN_FDS";
+
+/// How often drain state is polled
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn request_drain(_signal: libc::c_int) {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Enter drain mode
+pub fn start() {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Wait for `SIGUSR2` or [`start`]
+pub async fn requested() {
+    #[cfg(unix)]
+    {
+        let handler = request_drain as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGUSR2, handler);
+        }
+    }
+    while !is_draining() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// An open tunnel the drain waits for, counted until dropped
+#[derive(Debug)]
+pub struct Relay(());
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Count a tunnel as open for as long as the returned guard lives
+pub fn track() -> Relay {
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    Relay(())
+}
+
+/// Tunnels open right now
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Wait until every tunnel has closed, up to `timeout`; returns whether
+/// they all did
+pub async fn finished(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while active() > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    true
+}
+
+/// Ask the server with process ID `pid` to drain
+#[cfg(unix)]
+pub fn signal(pid: u32) -> io::Result<()> {
+    let pid = libc::pid_t::try_from(pid).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid process ID"))?;
+    // SAFETY: kill has no memory effects
+    if unsafe { libc::kill(pid, libc::SIGUSR2) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn signal(_pid: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "draining by signal needs Unix; pause the service instead"))
+}
+
+/// Start a new process of this executable with the same arguments,
+/// inheriting the listening sockets `fds`; returns its process ID
+#[cfg(unix)]
+pub fn hand_off(fds: &[std::os::unix::io::RawFd]) -> io::Result<u32> {
+    for &fd in fds {
+        // SAFETY: fcntl on a descriptor the caller keeps open
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let fds = fds.iter().map(ToString::to_string).collect::<Vec<_>>().join(",");
+    let child = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FDS_ENV, fds)
+        .spawn()?;
+    Ok(child.id())
+}
+
+/// Listening sockets handed over by a draining predecessor, taken from the
+/// environment so only the first caller gets them
+pub fn inherited_fds() -> io::Result<Option<Vec<i32>>> {
+    let Some(value) = std::env::var_os(LISTEN_FDS_ENV) else {
+        return Ok(None);
+    };
+    std::env::remove_var(LISTEN_FDS_ENV);
+    let fds = parse_fds(&value.to_string_lossy())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is malformed", LISTEN_FDS_ENV)))?;
+    Ok(Some(fds))
+}
+
+/// Whether this process was started by a draining predecessor
+pub fn is_successor() -> bool {
+    std::env::var_os(LISTEN_FDS_ENV).is_some()
+}
+
+fn parse_fds(value: &str) -> Option<Vec<i32>> {
+    value
+        .split(',')
+        .map(|fd| fd.trim().parse::<i32>().ok().filter(|&fd| fd > 2))
+        .collect::<Option<Vec<_>>>()
+        .filter(|fds| !fds.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_relays() {
+        let relay = track();
+        let other = track();
+        assert_eq!(active(), 2);
+        drop(other);
+        assert!(!finished(Duration::from_millis(250)).await);
+
+        let waiting = tokio::spawn(finished(Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(relay);
+        assert!(waiting.await.unwrap());
+
+        assert_eq!(parse_fds("3,4"), Some(vec![3, 4]));
+        assert_eq!(parse_fds("1"), None);
+        assert_eq!(parse_fds(""), None);
+    }
+}
@@ -0,0 +1,403 @@
+//! Elligator2 encoding of X25519 handshake ephemerals
+//!
+//! A raw Curve25519 public key is easy to spot on the wire: only about half
+//! of all 32-byte strings are valid u-coordinates, and the top bit is always
+//! clear. Elligator2 maps suitable public keys to *representatives* that are
+//! indistinguishable from uniform random bytes, as obfs4 does.
+//!
+//! Ephemeral keys are generated as "dirty" points (the usual `k*B` plus a
+//! random low-order point) so the decoded u-coordinates are not confined to
+//! the prime-order subgroup either. X25519 clamps scalars to multiples of 8,
+//! so the low-order component vanishes from every Diffie-Hellman result and
+//! peers see an ordinary key.
+//!
+//! [`ElligatorResolver`] plugs this into snow: ephemerals it generates are
+//! always representable, and the handshake swaps the leading `e` of each
+//! message with [`encode`] / [`decode`].
+
+use curve25519_dalek::constants::EIGHT_TORSION;
+use curve25519_dalek::edwards::EdwardsPoint;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use rand::RngCore;
+use snow::params::{CipherChoice, DHChoice, HashChoice};
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::types::{Cipher, Dh, Hash, Random};
+
+/// Length of a public key and of its representative
+pub const KEY_LEN: usize = 32;
+
+/// Montgomery curve coefficient A of Curve25519
+const A: u64 = 486662;
+
+/// Generate an X25519 keypair whose public key has an Elligator2 representative
+///
+/// Returns `(private, public)`.
+pub fn generate_keypair(rng: &mut dyn RngCore) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    loop {
+        let mut private = [0u8; KEY_LEN];
+        rng.fill_bytes(&mut private);
+        let torsion = EIGHT_TORSION[(rng.next_u32() & 7) as usize];
+        let public = (EdwardsPoint::mul_base_clamped(private) + torsion)
+            .to_montgomery()
+            .to_bytes();
+        if is_representable(&public) {
+            return (private, public);
+        }
+    }
+}
+
+/// Whether `public` can be encoded with [`encode`]
+pub fn is_representable(public: &[u8; KEY_LEN]) -> bool {
+    let u = Fe::from_bytes(public);
+    let u_plus_a = u.add(&Fe::from_u64(A));
+    // -2u(u + A) must be a non-zero square
+    let t = u.mul(&u_plus_a).mul(&Fe::from_u64(2)).neg();
+    !u.is_zero() && !u_plus_a.is_zero() && t.is_square()
+}
+
+/// Encode a public key as a uniformly random-looking representative
+///
+/// `tweak` supplies the random bits: bit 0 picks one of the two
+/// representatives of the point, bits 6-7 fill the unused top bits.
+pub fn encode(public: &[u8; KEY_LEN], tweak: u8) -> Option<[u8; KEY_LEN]> {
+    if !is_representable(public) {
+        return None;
+    }
+    let u = Fe::from_bytes(public);
+    let u_plus_a = u.add(&Fe::from_u64(A));
+    let two = Fe::from_u64(2);
+
+    // r^2 = -u / (2(u + A))  or  -(u + A) / (2u)
+    let (num, den) = if tweak & 1 == 0 {
+        (u, u_plus_a)
+    } else {
+        (u_plus_a, u)
+    };
+    let r = num.neg().mul(&den.mul(&two).invert()).sqrt()?;
+
+    // Of r and -r take the one below (p - 1) / 2, leaving the top two bits free
+    let (r, neg) = (r.to_bytes(), r.neg().to_bytes());
+    let mut out = if le_less_than(&neg, &r) { neg } else { r };
+    out[31] |= tweak & 0xc0;
+    Some(out)
+}
+
+/// Map a representative back to the public key it encodes
+pub fn decode(representative: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let mut bytes = *representative;
+    bytes[31] &= 0x3f;
+    let r = Fe::from_bytes(&bytes);
+    let a = Fe::from_u64(A);
+
+    // w = -A / (1 + 2r^2); the denominator is never zero since -1/2 is not a square
+    let w = a.neg().mul(&Fe::ONE.add(&r.square().mul(&Fe::from_u64(2))).invert());
+    let g = w.mul(&w.square().add(&a.mul(&w)).add(&Fe::ONE));
+    let u = if g.is_square() { w } else { w.neg().sub(&a) };
+    u.to_bytes()
+}
+
+/// Replace the public key at the start of a handshake message with its representative
+pub fn encode_in_place(message: &mut [u8]) -> anyhow::Result<()> {
+    let public: [u8; KEY_LEN] = message
+        .get(..KEY_LEN)
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Handshake message too short for an ephemeral key"))?;
+    let representative = encode(&public, rand::random())
+        .ok_or_else(|| anyhow::anyhow!("Ephemeral key has no Elligator2 representative"))?;
+    message[..KEY_LEN].copy_from_slice(&representative);
+    Ok(())
+}
+
+/// Replace the representative at the start of a handshake message with the public key
+pub fn decode_in_place(message: &mut [u8]) -> anyhow::Result<()> {
+    let representative: [u8; KEY_LEN] = message
+        .get(..KEY_LEN)
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("Handshake message too short for an ephemeral key"))?;
+    message[..KEY_LEN].copy_from_slice(&decode(&representative));
+    Ok(())
+}
+
+/// snow resolver whose Curve25519 ephemerals are Elligator2-representable
+#[derive(Default)]
+pub struct ElligatorResolver;
+
+impl CryptoResolver for ElligatorResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        DefaultResolver.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        match choice {
+            DHChoice::Curve25519 => Some(Box::<ElligatorDh>::default()),
+            _ => DefaultResolver.resolve_dh(choice),
+        }
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        DefaultResolver.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        DefaultResolver.resolve_cipher(choice)
+    }
+}
+
+/// X25519 whose generated keys are representable dirty points
+#[derive(Default)]
+struct ElligatorDh {
+    private: [u8; KEY_LEN],
+    public: [u8; KEY_LEN],
+}
+
+impl Dh for ElligatorDh {
+    fn name(&self) -> &'static str {
+        "25519"
+    }
+
+    fn pub_len(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn priv_len(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn set(&mut self, private: &[u8]) {
+        self.private.copy_from_slice(&private[..KEY_LEN]);
+        self.public = MontgomeryPoint::mul_base_clamped(self.private).to_bytes();
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        (self.private, self.public) = generate_keypair(rng);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.public
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.private
+    }
+
+    fn dh(&self, public: &[u8], out: &mut [u8]) -> Result<(), snow::Error> {
+        let mut point = [0u8; KEY_LEN];
+        point.copy_from_slice(&public[..KEY_LEN]);
+        let shared = MontgomeryPoint(point).mul_clamped(self.private).to_bytes();
+        out[..KEY_LEN].copy_from_slice(&shared);
+        Ok(())
+    }
+}
+
+/// Compare two little-endian 256-bit numbers
+fn le_less_than(a: &[u8; KEY_LEN], b: &[u8; KEY_LEN]) -> bool {
+    a.iter().rev().cmp(b.iter().rev()) == std::cmp::Ordering::Less
+}
+
+const MASK: u64 = (1 << 51) - 1;
+
+/// Element of GF(2^255 - 19) in five 51-bit limbs
+#[derive(Clone, Copy, Debug)]
+struct Fe([u64; 5]);
+
+impl Fe {
+    const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+    fn from_u64(n: u64) -> Fe {
+        Fe([n, 0, 0, 0, 0])
+    }
+
+    /// Load 255 bits, ignoring the top bit
+    fn from_bytes(bytes: &[u8; KEY_LEN]) -> Fe {
+        let load = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Fe([
+            load(0) & MASK,
+            (load(6) >> 3) & MASK,
+            (load(12) >> 6) & MASK,
+            (load(19) >> 1) & MASK,
+            (load(24) >> 12) & MASK,
+        ])
+    }
+
+    /// Canonical little-endian encoding
+    fn to_bytes(self) -> [u8; KEY_LEN] {
+        let mut l = Fe::carry(self.0.map(u128::from)).0;
+
+        // Add 19 and see whether it carries past 2^255, i.e. whether l >= p
+        let mut q = (l[0] + 19) >> 51;
+        for limb in &l[1..] {
+            q = (limb + q) >> 51;
+        }
+        l[0] += 19 * q;
+        for i in 0..4 {
+            l[i + 1] += l[i] >> 51;
+            l[i] &= MASK;
+        }
+        l[4] &= MASK;
+
+        let mut out = [0u8; KEY_LEN];
+        let (mut acc, mut bits, mut i) = (0u128, 0, 0);
+        for limb in l {
+            acc |= u128::from(limb) << bits;
+            bits += 51;
+            while bits >= 8 {
+                out[i] = acc as u8;
+                acc >>= 8;
+                bits -= 8;
+                i += 1;
+            }
+        }
+        out[i] = acc as u8;
+        out
+    }
+
+    fn carry(c: [u128; 5]) -> Fe {
+        let mut c = c;
+        for i in 0..4 {
+            c[i + 1] += c[i] >> 51;
+            c[i] &= MASK as u128;
+        }
+        let top = c[4] >> 51;
+        c[4] &= MASK as u128;
+        c[0] += top * 19;
+        c[1] += c[0] >> 51;
+        c[0] &= MASK as u128;
+        Fe(c.map(|l| l as u64))
+    }
+
+    fn add(&self, other: &Fe) -> Fe {
+        Fe::carry(std::array::from_fn(|i| u128::from(self.0[i] + other.0[i])))
+    }
+
+    fn sub(&self, other: &Fe) -> Fe {
+        // Add 16p first so the limbs never underflow
+        const SIXTEEN_P: [u64; 5] = [
+            36028797018963664,
+            36028797018963952,
+            36028797018963952,
+            36028797018963952,
+            36028797018963952,
+        ];
+        Fe::carry(std::array::from_fn(|i| u128::from(self.0[i] + SIXTEEN_P[i] - other.0[i])))
+    }
+
+    fn neg(&self) -> Fe {
+        Fe([0; 5]).sub(self)
+    }
+
+    fn mul(&self, other: &Fe) -> Fe {
+        let a = self.0.map(u128::from);
+        let b = other.0.map(u128::from);
+        let b19 = b.map(|l| l * 19);
+        Fe::carry([
+            a[0] * b[0] + a[4] * b19[1] + a[3] * b19[2] + a[2] * b19[3] + a[1] * b19[4],
+            a[1] * b[0] + a[0] * b[1] + a[4] * b19[2] + a[3] * b19[3] + a[2] * b19[4],
+            a[2] * b[0] + a[1] * b[1] + a[0] * b[2] + a[4] * b19[3] + a[3] * b19[4],
+            a[3] * b[0] + a[2] * b[1] + a[1] * b[2] + a[0] * b[3] + a[4] * b19[4],
+            a[4] * b[0] + a[3] * b[1] + a[2] * b[2] + a[1] * b[3] + a[0] * b[4],
+        ])
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    /// `self^exp` for a little-endian exponent
+    fn pow(&self, exp: &[u8; KEY_LEN]) -> Fe {
+        let mut result = Fe::ONE;
+        for byte in exp.iter().rev() {
+            for bit in (0..8).rev() {
+                result = result.square();
+                if (byte >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+
+    /// Little-endian exponent with the given end bytes and 0xff in between
+    const fn exponent(low: u8, high: u8) -> [u8; KEY_LEN] {
+        let mut e = [0xff; KEY_LEN];
+        e[0] = low;
+        e[31] = high;
+        e
+    }
+
+    fn invert(&self) -> Fe {
+        // p - 2
+        self.pow(&Fe::exponent(0xeb, 0x7f))
+    }
+
+    fn is_zero(&self) -> bool {
+        self.to_bytes() == [0; KEY_LEN]
+    }
+
+    fn equals(&self, other: &Fe) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+
+    /// Euler's criterion; zero counts as a square
+    fn is_square(&self) -> bool {
+        // (p - 1) / 2
+        let chi = self.pow(&Fe::exponent(0xf6, 0x3f));
+        chi.is_zero() || chi.equals(&Fe::ONE)
+    }
+
+    fn sqrt(&self) -> Option<Fe> {
+        // (p + 3) / 8, then fix up with sqrt(-1) = 2^((p - 1) / 4)
+        let candidate = self.pow(&Fe::exponent(0xfe, 0x0f));
+        let check = candidate.square();
+        if check.equals(self) {
+            Some(candidate)
+        } else if check.equals(&self.neg()) {
+            let sqrt_m1 = Fe::from_u64(2).pow(&Fe::exponent(0xfb, 0x1f));
+            Some(candidate.mul(&sqrt_m1))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let mut rng = rand::thread_rng();
+        let mut top_bits = 0u8;
+        for _ in 0..32 {
+            let (private, public) = generate_keypair(&mut rng);
+            let tweak: u8 = rand::random();
+            let representative = encode(&public, tweak).unwrap();
+            top_bits |= representative[31];
+            assert_eq!(decode(&representative), public);
+
+            // The low-order component cancels out of Diffie-Hellman
+            let clean = MontgomeryPoint::mul_base_clamped(private);
+            let (peer, _) = generate_keypair(&mut rng);
+            assert_eq!(
+                MontgomeryPoint(public).mul_clamped(peer),
+                clean.mul_clamped(peer)
+            );
+        }
+        // Padding bits are randomised rather than always clear
+        assert_eq!(top_bits & 0xc0, 0xc0);
+    }
+
+    #[test]
+    fn test_field_arithmetic() {
+        let x = Fe::from_bytes(&[7; KEY_LEN]);
+        assert!(x.mul(&x.invert()).equals(&Fe::ONE));
+        assert!(x.square().sqrt().unwrap().square().equals(&x.square()));
+        assert!(x.sub(&x).is_zero());
+        // 2 is not a square mod p
+        assert!(!Fe::from_u64(2).is_square());
+        // p itself encodes as zero
+        let mut p = [0xff; KEY_LEN];
+        p[0] = 0xed;
+        p[31] = 0x7f;
+        assert!(Fe::from_bytes(&p).is_zero());
+    }
+}
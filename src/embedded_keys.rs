@@ -31,9 +31,28 @@
 //! // Or get a specific regional server
 //! let eu_key = get_production_key(ServerEndpoint::EuropeWest);
 //! ```
+//!
+//! ### Key Rotation
+//!
+//! Ship the next server key ahead of time, and revoke compromised ones, so
+//! deployed clients keep working across a rotation:
+//!
+//! ```bash
+//! # KEY@NOT_BEFORE..NOT_AFTER, comma-separated; either bound may be omitted
+//! export NOOSHDAROO_PRIMARY_ROTATION_KEYS="newkey...=@2025-06-01T00:00:00Z.."
+//! export NOOSHDAROO_REVOKED_KEYS="leakedkey...="
+//! cargo build --release
+//! ```
 
+use crate::noise_transport::{NoiseConfig, PinnedKey};
 use std::collections::HashMap;
 
+/// Compromised server keys (comma-separated) that must never be accepted
+const REVOKED_KEYS: &str = match option_env!("NOOSHDAROO_REVOKED_KEYS") {
+    Some(keys) => keys,
+    None => "",
+};
+
 /// Server endpoint identifiers
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum ServerEndpoint {
@@ -60,12 +79,48 @@ pub struct ServerConfig {
     pub address: &'static str,
     /// Server's public key (base64-encoded X25519 key)
     pub public_key: &'static str,
+    /// Additional keys with validity windows (`KEY@FROM..TO`, comma-separated)
+    pub rotation_keys: &'static str,
     /// Server region/location
     pub region: &'static str,
     /// Is this server recommended for production?
     pub production: bool,
 }
 
+impl ServerConfig {
+    /// All embedded keys for this server, with revoked keys flagged
+    ///
+    /// Invalid rotation entries are logged and skipped.
+    pub fn pinned_keys(&self) -> Vec<PinnedKey> {
+        let revoked = revoked_keys();
+        std::iter::once(PinnedKey::new(self.public_key))
+            .chain(self.rotation_keys.split(',').filter(|s| !s.trim().is_empty()).filter_map(|s| {
+                s.parse::<PinnedKey>()
+                    .map_err(|e| log::warn!("Ignoring embedded rotation key for {}: {}", self.name, e))
+                    .ok()
+            }))
+            .map(|mut key| {
+                key.revoked |= revoked.contains(&key.key.as_str());
+                key
+            })
+            .collect()
+    }
+
+    /// Client Noise configuration pinned to this server's keys
+    pub fn noise_config(&self) -> NoiseConfig {
+        NoiseConfig {
+            remote_public_key: Some(self.public_key.to_string()),
+            remote_public_keys: self.pinned_keys(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Server keys revoked at build time via `NOOSHDAROO_REVOKED_KEYS`
+pub fn revoked_keys() -> Vec<&'static str> {
+    REVOKED_KEYS.split(',').map(str::trim).filter(|k| !k.is_empty()).collect()
+}
+
 /// Get embedded server public key for a specific endpoint
 ///
 /// This function returns compile-time embedded server keys based on build
@@ -109,6 +164,7 @@ pub fn get_server_config(endpoint: ServerEndpoint) -> ServerConfig {
                 .unwrap_or("vpn.nooshdaroo.com:8443"),
             public_key: option_env!("NOOSHDAROO_PRIMARY_KEY")
                 .unwrap_or(DEFAULT_PRIMARY_KEY),
+            rotation_keys: option_env!("NOOSHDAROO_PRIMARY_ROTATION_KEYS").unwrap_or(""),
             region: "Global",
             production: true,
         },
@@ -119,6 +175,7 @@ pub fn get_server_config(endpoint: ServerEndpoint) -> ServerConfig {
                 .unwrap_or("vpn-backup.nooshdaroo.com:8443"),
             public_key: option_env!("NOOSHDAROO_FALLBACK_KEY")
                 .unwrap_or(DEFAULT_FALLBACK_KEY),
+            rotation_keys: option_env!("NOOSHDAROO_FALLBACK_ROTATION_KEYS").unwrap_or(""),
             region: "Global",
             production: true,
         },
@@ -129,6 +186,7 @@ pub fn get_server_config(endpoint: ServerEndpoint) -> ServerConfig {
                 .unwrap_or("eu-west.nooshdaroo.com:8443"),
             public_key: option_env!("NOOSHDAROO_EU_KEY")
                 .unwrap_or(DEFAULT_EU_KEY),
+            rotation_keys: option_env!("NOOSHDAROO_EU_ROTATION_KEYS").unwrap_or(""),
             region: "Europe (Netherlands)",
             production: true,
         },
@@ -139,6 +197,7 @@ pub fn get_server_config(endpoint: ServerEndpoint) -> ServerConfig {
                 .unwrap_or("ap-east.nooshdaroo.com:8443"),
             public_key: option_env!("NOOSHDAROO_AP_KEY")
                 .unwrap_or(DEFAULT_AP_KEY),
+            rotation_keys: option_env!("NOOSHDAROO_AP_ROTATION_KEYS").unwrap_or(""),
             region: "Asia Pacific (Singapore)",
             production: true,
         },
@@ -149,6 +208,7 @@ pub fn get_server_config(endpoint: ServerEndpoint) -> ServerConfig {
                 .unwrap_or("na-east.nooshdaroo.com:8443"),
             public_key: option_env!("NOOSHDAROO_NA_KEY")
                 .unwrap_or(DEFAULT_NA_KEY),
+            rotation_keys: option_env!("NOOSHDAROO_NA_ROTATION_KEYS").unwrap_or(""),
             region: "North America (New York)",
             production: true,
         },
@@ -159,6 +219,7 @@ pub fn get_server_config(endpoint: ServerEndpoint) -> ServerConfig {
                 .unwrap_or("localhost:8443"),
             public_key: option_env!("NOOSHDAROO_DEV_KEY")
                 .unwrap_or(DEFAULT_DEV_KEY),
+            rotation_keys: option_env!("NOOSHDAROO_DEV_ROTATION_KEYS").unwrap_or(""),
             region: "Local",
             production: false,
         },
@@ -292,6 +353,23 @@ mod tests {
         assert!(!prod_servers.iter().any(|(e, _)| *e == ServerEndpoint::Development));
     }
 
+    #[test]
+    fn test_pinned_keys() {
+        let config = ServerConfig {
+            rotation_keys: "next=@2025-06-01T00:00:00Z..,bogus@2025,",
+            ..get_server_config(ServerEndpoint::Development)
+        };
+        let keys = config.pinned_keys();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], PinnedKey::new(DEFAULT_DEV_KEY));
+        assert_eq!(keys[1].key, "next=");
+        assert!(keys[1].not_before.is_some());
+
+        let noise = config.noise_config();
+        let now = std::time::SystemTime::now();
+        assert_eq!(noise.candidate_remote_keys(now), vec!["next=", DEFAULT_DEV_KEY]);
+    }
+
     #[test]
     fn test_server_regions() {
         let eu_config = get_server_config(ServerEndpoint::EuropeWest);
@@ -0,0 +1,32 @@
+//! Standard PSF protocol definitions compiled into the binary
+//!
+//! Every `.psf` file under `protocols/` is embedded at build time (see
+//! `build.rs`), so a single static binary works without the directory.
+//! Files on disk in the protocol directory take precedence over these.
+
+include!(concat!(env!("OUT_DIR"), "/embedded_protocols.rs"));
+
+/// Iterate over embedded PSF files as (path relative to `protocols/`, content)
+pub fn iter() -> impl Iterator<Item = (&'static str, &'static str)> {
+    EMBEDDED_PSF.iter().copied()
+}
+
+/// Get an embedded PSF file by its path relative to `protocols/`
+pub fn get(relative_path: &str) -> Option<&'static str> {
+    EMBEDDED_PSF
+        .iter()
+        .find(|(path, _)| *path == relative_path)
+        .map(|(_, content)| *content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standard_set_is_embedded() {
+        assert!(iter().count() >= 10);
+        assert!(get("http/https.psf").unwrap().contains("@SEGMENT"));
+        assert!(get("missing/none.psf").is_none());
+    }
+}
@@ -0,0 +1,128 @@
+//! Payload entropy normalization
+//!
+//! Noise ciphertext sits at ~8 bits/byte of entropy, which stands out inside
+//! protocols that normally carry structured or textual data. A
+//! [`PayloadEncoding`] re-encodes tunnel payloads before protocol wrapping
+//! so their byte distribution resembles what the cover protocol carries:
+//! base64 in HTTP bodies, MIME-wrapped base64 in mail, base32 labels in DNS.
+//! Protocols that are encrypted on the wire (TLS, SSH, QUIC) stay raw.
+
+use data_encoding::{BASE32_DNSSEC, BASE64, BASE64_MIME, HEXLOWER_PERMISSIVE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+/// How payload bytes are encoded inside protocol frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    /// Ciphertext as-is (~8 bits/byte)
+    #[default]
+    Raw,
+    /// Lowercase hex (4 bits/byte, 2x size)
+    Hex,
+    /// Lowercase DNS-safe base32 (5 bits/byte, 1.6x size)
+    Base32,
+    /// Standard base64 (6 bits/byte, 1.33x size)
+    Base64,
+    /// Base64 wrapped at 76 columns with CRLF, as in MIME mail bodies
+    Mime,
+}
+
+impl PayloadEncoding {
+    /// Encoding that matches the cover protocol, by protocol family
+    pub fn for_protocol(protocol: &str) -> Self {
+        let family = protocol.split(['-', '_']).next().unwrap_or(protocol);
+        match family.to_lowercase().as_str() {
+            "http" | "websocket" => PayloadEncoding::Base64,
+            "smtp" | "imap" | "pop3" => PayloadEncoding::Mime,
+            "dns" | "mdns" => PayloadEncoding::Base32,
+            _ => PayloadEncoding::Raw,
+        }
+    }
+
+    /// Resolve the encoding for `protocol`, preferring an override by full
+    /// protocol id, then by family
+    pub fn resolve(protocol: &str, overrides: &HashMap<String, PayloadEncoding>) -> Self {
+        let family = protocol.split(['-', '_']).next().unwrap_or(protocol);
+        overrides
+            .get(protocol)
+            .or_else(|| overrides.get(family))
+            .copied()
+            .unwrap_or_else(|| Self::for_protocol(protocol))
+    }
+
+    /// Encode a payload
+    pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            PayloadEncoding::Raw => data.to_vec(),
+            PayloadEncoding::Hex => HEXLOWER_PERMISSIVE.encode(data).into_bytes(),
+            PayloadEncoding::Base32 => BASE32_DNSSEC.encode(data).into_bytes(),
+            PayloadEncoding::Base64 => BASE64.encode(data).into_bytes(),
+            PayloadEncoding::Mime => BASE64_MIME.encode(data).into_bytes(),
+        }
+    }
+
+    /// Decode a payload produced by [`encode`](Self::encode)
+    pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let decoded = match self {
+            PayloadEncoding::Raw => return Ok(data.to_vec()),
+            PayloadEncoding::Hex => HEXLOWER_PERMISSIVE.decode(data),
+            PayloadEncoding::Base32 => BASE32_DNSSEC.decode(&data.to_ascii_lowercase()),
+            PayloadEncoding::Base64 => BASE64.decode(data),
+            PayloadEncoding::Mime => BASE64_MIME.decode(data),
+        };
+        decoded.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid {:?} payload: {}", self, e)))
+    }
+
+    /// Encoded size of a payload of `len` bytes
+    pub fn encoded_len(&self, len: usize) -> usize {
+        match self {
+            PayloadEncoding::Raw => len,
+            PayloadEncoding::Hex => HEXLOWER_PERMISSIVE.encode_len(len),
+            PayloadEncoding::Base32 => BASE32_DNSSEC.encode_len(len),
+            PayloadEncoding::Base64 => BASE64.encode_len(len),
+            PayloadEncoding::Mime => BASE64_MIME.encode_len(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::shannon_entropy;
+
+    #[test]
+    fn test_encodings_lower_entropy() {
+        let mut data = vec![0u8; 4096];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut data);
+        assert!(shannon_entropy(&data) > 7.9);
+
+        for (encoding, max_entropy) in [
+            (PayloadEncoding::Hex, 4.01),
+            (PayloadEncoding::Base32, 5.01),
+            (PayloadEncoding::Base64, 6.01),
+            (PayloadEncoding::Mime, 6.1),
+        ] {
+            let encoded = encoding.encode(&data);
+            assert_eq!(encoded.len(), encoding.encoded_len(data.len()));
+            assert!(shannon_entropy(&encoded) < max_entropy, "{:?}", encoding);
+            assert!(encoded.iter().all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace()));
+            assert_eq!(encoding.decode(&encoded).unwrap(), data);
+        }
+        assert!(PayloadEncoding::Base64.decode(b"not base64!").is_err());
+    }
+
+    #[test]
+    fn test_protocol_mapping() {
+        assert_eq!(PayloadEncoding::for_protocol("http"), PayloadEncoding::Base64);
+        assert_eq!(PayloadEncoding::for_protocol("smtp"), PayloadEncoding::Mime);
+        assert_eq!(PayloadEncoding::for_protocol("dns-google-com"), PayloadEncoding::Base32);
+        assert_eq!(PayloadEncoding::for_protocol("https"), PayloadEncoding::Raw);
+        assert_eq!(PayloadEncoding::for_protocol("ssh"), PayloadEncoding::Raw);
+
+        let overrides = HashMap::from([("dns".to_string(), PayloadEncoding::Hex)]);
+        assert_eq!(PayloadEncoding::resolve("dns_google", &overrides), PayloadEncoding::Hex);
+        assert_eq!(PayloadEncoding::resolve("http", &overrides), PayloadEncoding::Base64);
+    }
+}
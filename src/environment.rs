@@ -0,0 +1,408 @@
+//! Censorship environment detection
+//!
+//! Runs a quick battery of network tests at startup and picks the preset from
+//! [`profiles`](crate::profiles) that best matches what was observed:
+//!
+//! - **DNS injection**: a query for a sensitive domain sent to an address that
+//!   runs no DNS server should never be answered; forged A records for it (but
+//!   not for a control domain) mean an on-path injector (characteristic of the
+//!   Great Firewall). Answers for every domain only indicate a transparent
+//!   DNS proxy.
+//! - **DNS poisoning**: a resolver answering sensitive domains with private or
+//!   well-known block-page addresses (e.g. Iran's `10.10.34.0/24`).
+//! - **TLS SNI filtering**: a ClientHello naming a sensitive domain is reset or
+//!   dropped while one naming a control domain gets a response.
+//! - **Port reachability**: which common ports accept TCP connections.
+
+use crate::traceroute::probe_payload;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Settings for the environment probe
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    /// Public resolver queried for poisoning checks
+    pub resolver: SocketAddr,
+    /// Address with no DNS service, used to detect injected answers
+    pub injection_target: SocketAddr,
+    /// TLS server used for SNI tests
+    pub tls_endpoint: SocketAddr,
+    /// Host checked for port reachability
+    pub reachability_host: IpAddr,
+    /// Ports checked for reachability
+    pub ports: Vec<u16>,
+    /// Domains commonly blocked by national firewalls
+    pub sensitive_domains: Vec<String>,
+    /// Domain not expected to be blocked anywhere
+    pub control_domain: String,
+    /// Timeout for each individual test
+    pub timeout: Duration,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            resolver: "8.8.8.8:53".parse().unwrap(),
+            // TEST-NET-3, never runs a resolver
+            injection_target: "203.0.113.53:53".parse().unwrap(),
+            tls_endpoint: "1.1.1.1:443".parse().unwrap(),
+            reachability_host: "1.1.1.1".parse().unwrap(),
+            ports: vec![22, 53, 80, 443, 853, 8443],
+            sensitive_domains: vec![
+                "www.google.com".to_string(),
+                "www.facebook.com".to_string(),
+                "www.instagram.com".to_string(),
+                "www.linkedin.com".to_string(),
+            ],
+            control_domain: "www.example.com".to_string(),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// DNS test result for one domain
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsProbe {
+    /// Domain queried
+    pub domain: String,
+    /// Forged A records received from the address that runs no resolver
+    pub injected: bool,
+    /// Addresses returned by the public resolver
+    pub answers: Vec<IpAddr>,
+    /// Whether the answers look like a block page or bogus address
+    pub poisoned: bool,
+}
+
+/// TLS SNI test result for one domain
+#[derive(Debug, Clone, Serialize)]
+pub struct SniProbe {
+    /// Domain placed in the SNI
+    pub domain: String,
+    /// Whether the server responded to the ClientHello
+    pub answered: bool,
+}
+
+/// Everything observed by [`probe_environment`]
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EnvironmentReport {
+    /// Whether the address that runs no resolver answered the control domain
+    pub dns_intercepted: bool,
+    /// DNS tests per sensitive domain
+    pub dns: Vec<DnsProbe>,
+    /// Whether the control SNI got a TLS response
+    pub sni_control_answered: bool,
+    /// SNI tests per sensitive domain
+    pub sni: Vec<SniProbe>,
+    /// Ports that accepted a TCP connection
+    pub open_ports: Vec<u16>,
+    /// Ports that did not
+    pub closed_ports: Vec<u16>,
+}
+
+impl EnvironmentReport {
+    /// Whether any DNS answer was injected on path
+    pub fn dns_injection(&self) -> bool {
+        self.dns.iter().any(|p| p.injected)
+    }
+
+    /// Whether any resolver answer was poisoned
+    pub fn dns_poisoning(&self) -> bool {
+        self.dns.iter().any(|p| p.poisoned)
+    }
+
+    /// Whether poisoned answers point into Iran's block-page range
+    pub fn iran_block_page(&self) -> bool {
+        self.dns.iter().flat_map(|p| &p.answers).any(|ip| match ip {
+            IpAddr::V4(v4) => v4.octets()[..3] == [10, 10, 34],
+            IpAddr::V6(_) => false,
+        })
+    }
+
+    /// Whether sensitive SNIs fail while the control SNI works
+    pub fn sni_filtering(&self) -> bool {
+        self.sni_control_answered && self.sni.iter().any(|p| !p.answered)
+    }
+
+    /// Whether nothing at all could be reached
+    pub fn offline(&self) -> bool {
+        self.open_ports.is_empty() && !self.sni_control_answered
+    }
+}
+
+/// Preset chosen from an [`EnvironmentReport`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileRecommendation {
+    /// Preset name for [`load_profile`](crate::profiles::load_profile), or
+    /// `None` if no censorship was detected and defaults are fine
+    pub profile: Option<&'static str>,
+    /// Observations that led to the choice
+    pub reasons: Vec<String>,
+}
+
+/// Pick the preset that best matches `report`
+pub fn recommend_profile(report: &EnvironmentReport) -> ProfileRecommendation {
+    let mut reasons = Vec::new();
+    if report.dns_injection() {
+        reasons.push("DNS answers injected on path".to_string());
+    }
+    if report.dns_intercepted {
+        reasons.push("DNS traffic is transparently intercepted".to_string());
+    }
+    if report.dns_poisoning() {
+        reasons.push("resolver returned bogus addresses for sensitive domains".to_string());
+    }
+    if report.sni_filtering() {
+        let blocked: Vec<&str> = report
+            .sni
+            .iter()
+            .filter(|p| !p.answered)
+            .map(|p| p.domain.as_str())
+            .collect();
+        reasons.push(format!("TLS blocked by SNI for {}", blocked.join(", ")));
+    }
+    if !report.closed_ports.is_empty() && !report.open_ports.is_empty() {
+        reasons.push(format!("ports {:?} unreachable", report.closed_ports));
+    }
+
+    let only_web = !report.open_ports.is_empty()
+        && report.open_ports.iter().all(|p| matches!(p, 53 | 80 | 443));
+
+    let profile = if report.iran_block_page() {
+        Some("iran")
+    } else if report.dns_injection() {
+        Some("china")
+    } else if report.sni_filtering() || report.dns_poisoning() {
+        Some("russia")
+    } else if report.offline() {
+        reasons.push("no test endpoint reachable".to_string());
+        None
+    } else if only_web && !report.closed_ports.is_empty() {
+        Some("airport")
+    } else if !report.closed_ports.is_empty() {
+        Some("corporate")
+    } else {
+        None
+    };
+
+    if profile.is_none() && reasons.is_empty() {
+        reasons.push("no interference detected".to_string());
+    }
+    ProfileRecommendation { profile, reasons }
+}
+
+/// Run all tests described by `config`
+pub async fn probe_environment(config: &ProbeConfig) -> EnvironmentReport {
+    let mut report = EnvironmentReport {
+        dns_intercepted: dns_query(config.injection_target, &config.control_domain, config.timeout)
+            .await
+            .is_some(),
+        ..Default::default()
+    };
+
+    for domain in &config.sensitive_domains {
+        let injected = !report.dns_intercepted
+            && dns_query(config.injection_target, domain, config.timeout)
+                .await
+                .is_some_and(|answers| !answers.is_empty());
+        let answers = dns_query(config.resolver, domain, config.timeout)
+            .await
+            .unwrap_or_default();
+        let poisoned = answers.iter().any(is_bogus_answer);
+        report.dns.push(DnsProbe {
+            domain: domain.clone(),
+            injected,
+            answers,
+            poisoned,
+        });
+    }
+
+    report.sni_control_answered = tls_answers(config.tls_endpoint, &config.control_domain, config.timeout).await;
+    if report.sni_control_answered {
+        for domain in &config.sensitive_domains {
+            let answered = tls_answers(config.tls_endpoint, domain, config.timeout).await;
+            report.sni.push(SniProbe {
+                domain: domain.clone(),
+                answered,
+            });
+        }
+    }
+
+    for &port in &config.ports {
+        let addr = SocketAddr::new(config.reachability_host, port);
+        match tokio::time::timeout(config.timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => report.open_ports.push(port),
+            _ => report.closed_ports.push(port),
+        }
+    }
+
+    report
+}
+
+/// Probe the network and recommend a preset in one step
+pub async fn detect_profile(config: &ProbeConfig) -> (EnvironmentReport, ProfileRecommendation) {
+    let report = probe_environment(config).await;
+    let recommendation = recommend_profile(&report);
+    (report, recommendation)
+}
+
+/// Addresses censors commonly answer with instead of the real one
+fn is_bogus_answer(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_unspecified()
+                || v4.is_link_local()
+                || *v4 == Ipv4Addr::BROADCAST
+        }
+        IpAddr::V6(v6) => v6.is_loopback() || v6.is_unspecified(),
+    }
+}
+
+/// Send an A query over UDP; returns the answer addresses if any reply arrives
+async fn dns_query(server: SocketAddr, domain: &str, timeout: Duration) -> Option<Vec<IpAddr>> {
+    let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = UdpSocket::bind(bind).await.ok()?;
+    // The DNS probe payload is framed for TCP; drop the length prefix
+    let query = probe_payload("dns", domain).split_off(2);
+    socket.send_to(&query, server).await.ok()?;
+
+    let mut buf = [0u8; 1500];
+    let len = tokio::time::timeout(timeout, socket.recv(&mut buf)).await.ok()?.ok()?;
+    Some(parse_a_records(&buf[..len]))
+}
+
+/// A records from the answer section of a DNS response
+fn parse_a_records(msg: &[u8]) -> Vec<IpAddr> {
+    let mut records = Vec::new();
+    if msg.len() < 12 {
+        return records;
+    }
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let skip_name = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *msg.get(pos)? as usize;
+            if len == 0 {
+                return Some(pos + 1);
+            }
+            if len & 0xc0 == 0xc0 {
+                return Some(pos + 2);
+            }
+            pos += len + 1;
+        }
+    };
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        match skip_name(pos) {
+            Some(p) => pos = p + 4,
+            None => return records,
+        }
+    }
+    for _ in 0..answers {
+        let Some(p) = skip_name(pos) else {
+            break;
+        };
+        let Some(header) = msg.get(p..p + 10) else {
+            break;
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let Some(rdata) = msg.get(p + 10..p + 10 + rdlen) else {
+            break;
+        };
+        if rtype == 1 && rdlen == 4 {
+            records.push(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])));
+        }
+        pos = p + 10 + rdlen;
+    }
+    records
+}
+
+/// Whether `endpoint` sends anything back to a ClientHello for `domain`
+async fn tls_answers(endpoint: SocketAddr, domain: &str, timeout: Duration) -> bool {
+    let attempt = async {
+        let mut stream = TcpStream::connect(endpoint).await.ok()?;
+        stream.write_all(&probe_payload("https", domain)).await.ok()?;
+        let mut buf = [0u8; 64];
+        let n = stream.read(&mut buf).await.ok()?;
+        // A TLS record (handshake or alert) means the server itself answered
+        (n > 0 && matches!(buf[0], 0x15 | 0x16)).then_some(())
+    };
+    matches!(tokio::time::timeout(timeout, attempt).await, Ok(Some(())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dns(domain: &str, injected: bool, answers: &[&str]) -> DnsProbe {
+        let answers: Vec<IpAddr> = answers.iter().map(|a| a.parse().unwrap()).collect();
+        DnsProbe {
+            domain: domain.to_string(),
+            injected,
+            poisoned: answers.iter().any(is_bogus_answer),
+            answers,
+        }
+    }
+
+    fn open_network() -> EnvironmentReport {
+        EnvironmentReport {
+            dns_intercepted: false,
+            dns: vec![dns("www.google.com", false, &["142.250.1.1"])],
+            sni_control_answered: true,
+            sni: vec![SniProbe { domain: "www.google.com".to_string(), answered: true }],
+            open_ports: vec![22, 53, 80, 443],
+            closed_ports: vec![],
+        }
+    }
+
+    #[test]
+    fn test_recommendations() {
+        assert_eq!(recommend_profile(&open_network()).profile, None);
+
+        let mut intercepted = open_network();
+        intercepted.dns_intercepted = true;
+        let recommendation = recommend_profile(&intercepted);
+        assert_eq!(recommendation.profile, None);
+        assert!(recommendation.reasons[0].contains("intercepted"));
+
+        let mut gfw = open_network();
+        gfw.dns = vec![dns("www.google.com", true, &["31.13.64.1"])];
+        assert_eq!(recommend_profile(&gfw).profile, Some("china"));
+
+        let mut iran = open_network();
+        iran.dns = vec![dns("www.instagram.com", false, &["10.10.34.36"])];
+        assert_eq!(recommend_profile(&iran).profile, Some("iran"));
+
+        let mut sni = open_network();
+        sni.sni[0].answered = false;
+        let recommendation = recommend_profile(&sni);
+        assert_eq!(recommendation.profile, Some("russia"));
+        assert!(recommendation.reasons[0].contains("www.google.com"));
+
+        let mut corporate = open_network();
+        corporate.open_ports = vec![80, 443, 8443];
+        corporate.closed_ports = vec![22, 53];
+        assert_eq!(recommend_profile(&corporate).profile, Some("corporate"));
+
+        let mut wifi = open_network();
+        wifi.open_ports = vec![53, 443];
+        wifi.closed_ports = vec![22, 80, 8443];
+        assert_eq!(recommend_profile(&wifi).profile, Some("airport"));
+    }
+
+    #[test]
+    fn test_parse_a_records() {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        msg.extend_from_slice(&[3, b'w', b'w', b'w', 0, 0, 1, 0, 1]);
+        msg.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 10, 34, 35]);
+        assert_eq!(parse_a_records(&msg), vec!["10.10.34.35".parse::<IpAddr>().unwrap()]);
+        assert!(parse_a_records(&msg[..20]).is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+//! Client events for embedding applications
+//!
+//! Things an application or mobile UI would otherwise have to notice by
+//! polling [`crate::ProtocolStats`] are delivered as [`ClientEvent`]s to
+//! everyone subscribed to that client's [`EventSender`] (see
+//! [`NooshdarooClient::events`](crate::NooshdarooClient::events)). Each
+//! client has its own, so two clients in one process never see each other's
+//! events:
+//!
+//! ```rust,no_run
+//! use nooshdaroo::{ClientEvent, NooshdarooClient, NooshdarooConfig};
+//!
+//! # async fn example() -> Result<(), nooshdaroo::NooshdarooError> {
+//! let client = NooshdarooClient::new(NooshdarooConfig::default())?;
+//! let mut events = client.events();
+//! while let Ok(event) = events.recv().await {
+//!     if let ClientEvent::TunnelDown { server, reason, .. } = event {
+//!         println!("Tunnel to {} is down: {}", server, reason);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Subscribers that fall more than [`CAPACITY`] events behind miss the
+//! oldest ones and get `RecvError::Lagged` instead.
+
+use crate::counters::TrafficRegistry;
+use crate::netflow_evasion::PathTestResult;
+use crate::protocol::ProtocolId;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Events buffered for each subscriber
+pub const CAPACITY: usize = 256;
+
+/// How often relayed traffic is checked against the quota
+const QUOTA_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something that happened to the client
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum ClientEvent {
+    /// The protocol new tunnels use changed, by strategy or by hand
+    ProtocolRotated { from: ProtocolId, to: ProtocolId },
+    /// No server could carry a connection: every server tried was
+    /// unreachable or failed the handshake
+    TunnelDown { server: SocketAddr, protocol: ProtocolId, reason: String },
+    /// A path test finished, with its results best first
+    PathTestCompleted { server: String, results: Vec<PathTestResult> },
+    /// Traffic since start reached `used` bytes of the `[quota]` limit
+    QuotaWarning { used: u64, limit: u64 },
+}
+
+/// One client's event channel, owned by its
+/// [`ShapeShiftController`](crate::ShapeShiftController) and cloned to
+/// whatever emits on its behalf
+#[derive(Debug, Clone)]
+pub struct EventSender(broadcast::Sender<ClientEvent>);
+
+impl EventSender {
+    pub fn new() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+
+    /// Deliver `event` to subscribers
+    pub fn emit(&self, event: ClientEvent) {
+        log::debug!("Client event: {:?}", event);
+        // Nobody listening is fine
+        let _ = self.0.send(event);
+    }
+
+    /// Events emitted from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for EventSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tracks traffic against the `[quota]` limit, warning once when it reaches
+/// the warning ratio and once more when it reaches the limit
+#[derive(Debug, Clone)]
+pub struct QuotaTracker {
+    limit: u64,
+    warn_at: u64,
+    warned: u8,
+    events: EventSender,
+}
+
+impl QuotaTracker {
+    pub fn new(config: &crate::config::QuotaConfig, events: EventSender) -> Self {
+        Self {
+            limit: config.limit_bytes,
+            warn_at: (config.limit_bytes as f64 * config.warn_ratio) as u64,
+            warned: 0,
+            events,
+        }
+    }
+
+    /// Record that `used` bytes have been relayed in total, emitting a
+    /// [`ClientEvent::QuotaWarning`] when a threshold is crossed
+    pub fn update(&mut self, used: u64) {
+        let crossed = [self.warn_at, self.limit].iter().filter(|threshold| used >= **threshold).count() as u8;
+        if crossed > self.warned {
+            self.warned = crossed;
+            log::warn!("{} of the {} byte quota used", used, self.limit);
+            self.events.emit(ClientEvent::QuotaWarning { used, limit: self.limit });
+        }
+    }
+}
+
+/// Check the traffic in `traffic` against `config` until the registry is
+/// dropped, warning on `events`
+pub fn spawn_quota_monitor(config: &crate::config::QuotaConfig, traffic: &Arc<TrafficRegistry>, events: EventSender) {
+    let mut quota = QuotaTracker::new(config, events);
+    let traffic = Arc::downgrade(traffic);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(QUOTA_INTERVAL);
+        loop {
+            interval.tick().await;
+            match traffic.upgrade() {
+                Some(traffic) => quota.update(traffic.total().bytes()),
+                None => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuotaConfig;
+
+    #[tokio::test]
+    async fn test_quota_warnings() {
+        let sender = EventSender::new();
+        let mut events = sender.subscribe();
+        let mut other = EventSender::new().subscribe();
+        let mut quota = QuotaTracker::new(&QuotaConfig { limit_bytes: 1000, warn_ratio: 0.8 }, sender);
+        quota.update(500);
+        quota.update(850);
+        quota.update(900);
+        quota.update(1200);
+        quota.update(1500);
+
+        let mut warnings = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let ClientEvent::QuotaWarning { used, limit: 1000 } = event {
+                warnings.push(used);
+            }
+        }
+        assert_eq!(warnings, vec![850, 1200]);
+        // Another client's subscribers see nothing
+        assert!(other.try_recv().is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! A/B comparison of candidate protocols
+//!
+//! Which protocol gets through best depends on the network, and the only
+//! reliable way to find out is to try. With `[experiment] enabled = true`
+//! the client tunnels a fraction of new connections over each candidate in
+//! turn, recording whether the tunnel came up, how long the handshake took
+//! and how fast data moved. Once every candidate has enough connections the
+//! best one (highest success rate, then throughput) carries all new
+//! connections.
+//!
+//! The results can be written to a JSON [`ExperimentReport`] for sharing
+//! with other users in the same network. It holds counts and timings per
+//! protocol only, never addresses or destinations.
+
+use crate::config::ExperimentConfig;
+use crate::protocol::ProtocolId;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the report file is rewritten while the experiment runs
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Success rates closer than this count as a tie, decided by throughput
+const SUCCESS_RATE_TIE: f64 = 0.05;
+
+/// Compares candidate protocols on live connections
+#[derive(Debug)]
+pub struct Experiment {
+    fraction: f64,
+    min_samples: u32,
+    report: Option<PathBuf>,
+    started_at: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    arms: Vec<Arm>,
+    winner: Option<ProtocolId>,
+    finished_at: Option<u64>,
+}
+
+/// Results for one candidate protocol
+#[derive(Debug, Clone, Default)]
+struct Arm {
+    protocol: ProtocolId,
+    /// Connections sent over the protocol, finished or not
+    assigned: u32,
+    successes: u32,
+    failures: u32,
+    handshake: Duration,
+    bytes: u64,
+    transfer: Duration,
+}
+
+impl Arm {
+    fn finished(&self) -> u32 {
+        self.successes + self.failures
+    }
+
+    fn success_rate(&self) -> f64 {
+        match self.finished() {
+            0 => 0.0,
+            n => self.successes as f64 / n as f64,
+        }
+    }
+
+    /// Bytes per second over the tunnels that carried data
+    fn throughput(&self) -> f64 {
+        match self.transfer.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    fn beats(&self, other: &Arm) -> bool {
+        let difference = self.success_rate() - other.success_rate();
+        if difference.abs() > SUCCESS_RATE_TIE {
+            difference > 0.0
+        } else {
+            self.throughput() > other.throughput()
+        }
+    }
+}
+
+impl Experiment {
+    pub fn new(config: &ExperimentConfig) -> Self {
+        let arms = config
+            .protocols
+            .iter()
+            .map(|protocol| Arm { protocol: ProtocolId::from(protocol.as_str()), ..Default::default() })
+            .collect();
+        Self {
+            fraction: config.fraction,
+            min_samples: config.min_samples,
+            report: config.report.clone(),
+            started_at: unix_now(),
+            state: Mutex::new(State { arms, winner: None, finished_at: None }),
+        }
+    }
+
+    /// Candidate protocols
+    pub fn protocols(&self) -> Vec<ProtocolId> {
+        self.state.lock().unwrap().arms.iter().map(|arm| arm.protocol.clone()).collect()
+    }
+
+    /// Best protocol, once the experiment has converged
+    pub fn winner(&self) -> Option<ProtocolId> {
+        self.state.lock().unwrap().winner.clone()
+    }
+
+    /// Protocol for a new connection, and whether its outcome should be
+    /// recorded: a candidate for `fraction` of connections, the winner for
+    /// all once converged, `default` otherwise
+    pub fn assign(&self, default: &ProtocolId) -> (ProtocolId, bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(ref winner) = state.winner {
+            return (winner.clone(), false);
+        }
+        if rand::random::<f64>() >= self.fraction {
+            return (default.clone(), false);
+        }
+        // The least tried candidate, so all reach min_samples together
+        match state.arms.iter_mut().min_by_key(|arm| arm.assigned) {
+            Some(arm) => {
+                arm.assigned += 1;
+                (arm.protocol.clone(), true)
+            }
+            None => (default.clone(), false),
+        }
+    }
+
+    /// Record a tunnel established over `protocol`
+    pub fn record_success(&self, protocol: &ProtocolId, handshake: Duration) {
+        self.record(protocol, |arm| {
+            arm.successes += 1;
+            arm.handshake += handshake;
+        });
+    }
+
+    /// Record a connection no server could tunnel over `protocol`
+    pub fn record_failure(&self, protocol: &ProtocolId) {
+        self.record(protocol, |arm| arm.failures += 1);
+    }
+
+    /// Record data relayed through a tunnel over `protocol`
+    pub fn record_transfer(&self, protocol: &ProtocolId, bytes: u64, elapsed: Duration) {
+        if bytes == 0 {
+            return;
+        }
+        self.record(protocol, |arm| {
+            arm.bytes += bytes;
+            arm.transfer += elapsed;
+        });
+    }
+
+    fn record(&self, protocol: &ProtocolId, update: impl FnOnce(&mut Arm)) {
+        let mut state = self.state.lock().unwrap();
+        let Some(arm) = state.arms.iter_mut().find(|arm| arm.protocol == *protocol) else {
+            return;
+        };
+        update(arm);
+        if state.winner.is_some() || state.arms.iter().any(|arm| arm.finished() < self.min_samples) {
+            return;
+        }
+        let best = state
+            .arms
+            .iter()
+            .reduce(|best, arm| if arm.beats(best) { arm } else { best })
+            .map(|arm| arm.protocol.clone());
+        if let Some(ref best) = best {
+            log::info!("Protocol experiment converged on {}; using it for all new connections", best);
+        }
+        state.winner = best;
+        state.finished_at = Some(unix_now());
+        drop(state);
+        if let Some(ref path) = self.report {
+            self.save(path);
+        }
+    }
+
+    /// Results so far
+    pub fn report(&self) -> ExperimentReport {
+        let state = self.state.lock().unwrap();
+        ExperimentReport {
+            started_at: self.started_at,
+            finished_at: state.finished_at,
+            fraction: self.fraction,
+            min_samples: self.min_samples,
+            winner: state.winner.as_ref().map(ToString::to_string),
+            protocols: state
+                .arms
+                .iter()
+                .map(|arm| ArmReport {
+                    protocol: arm.protocol.to_string(),
+                    successes: arm.successes,
+                    failures: arm.failures,
+                    success_rate: arm.success_rate(),
+                    mean_handshake_ms: (arm.successes > 0)
+                        .then(|| arm.handshake.as_secs_f64() * 1000.0 / arm.successes as f64),
+                    throughput_bytes_per_sec: arm.throughput(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the results to `path`
+    pub fn save(&self, path: &Path) {
+        let json = serde_json::to_string_pretty(&self.report()).expect("report serializes");
+        if let Err(e) = std::fs::write(path, json) {
+            log::warn!("Failed to write experiment report {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Rewrite the configured report file until the experiment converges or is
+/// dropped
+pub fn spawn_reporter(experiment: &Arc<Experiment>) {
+    if experiment.report.is_none() {
+        return;
+    }
+    let experiment = Arc::downgrade(experiment);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(REPORT_INTERVAL).await;
+            let Some(experiment) = experiment.upgrade() else {
+                return;
+            };
+            if experiment.winner().is_some() {
+                return;
+            }
+            if let Some(ref path) = experiment.report {
+                experiment.save(path);
+            }
+        }
+    });
+}
+
+/// Shareable experiment results
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExperimentReport {
+    /// Unix seconds
+    pub started_at: u64,
+    /// Unix seconds when the experiment converged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    pub fraction: f64,
+    pub min_samples: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner: Option<String>,
+    pub protocols: Vec<ArmReport>,
+}
+
+/// Results for one candidate protocol
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArmReport {
+    pub protocol: String,
+    pub successes: u32,
+    pub failures: u32,
+    pub success_rate: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_handshake_ms: Option<f64>,
+    pub throughput_bytes_per_sec: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_experiment_converges() {
+        let config = ExperimentConfig {
+            enabled: true,
+            protocols: vec!["https".to_string(), "quic".to_string(), "dns".to_string()],
+            fraction: 1.0,
+            min_samples: 4,
+            report: None,
+        };
+        let experiment = Experiment::new(&config);
+        let default = ProtocolId::from("ssh");
+
+        for round in 0..4 {
+            for _ in 0..3 {
+                let (protocol, recorded) = experiment.assign(&default);
+                assert!(recorded);
+                match protocol.as_str() {
+                    // Blocked every other time
+                    "https" if round % 2 == 0 => experiment.record_failure(&protocol),
+                    "https" => experiment.record_success(&protocol, Duration::from_millis(80)),
+                    "quic" => {
+                        experiment.record_success(&protocol, Duration::from_millis(40));
+                        experiment.record_transfer(&protocol, 1_000_000, Duration::from_secs(1));
+                    }
+                    _ => {
+                        experiment.record_success(&protocol, Duration::from_millis(60));
+                        experiment.record_transfer(&protocol, 50_000, Duration::from_secs(1));
+                    }
+                }
+            }
+        }
+
+        // Every candidate was tried evenly and the fastest reliable one won
+        assert_eq!(experiment.winner(), Some(ProtocolId::from("quic")));
+        assert_eq!(experiment.assign(&default), (ProtocolId::from("quic"), false));
+        let report = experiment.report();
+        assert_eq!(report.winner.as_deref(), Some("quic"));
+        let https = report.protocols.iter().find(|arm| arm.protocol == "https").unwrap();
+        assert_eq!((https.successes, https.failures), (2, 2));
+        assert_eq!(https.mean_handshake_ms, Some(80.0));
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<ExperimentReport>(&json).unwrap(), report);
+    }
+}
@@ -0,0 +1,359 @@
+//! Forward error correction for UDP transports
+//!
+//! Datagrams are sent in groups: the data datagrams go out as they come,
+//! and once a group is full (or has been open for the flush delay) it is
+//! followed by Reed-Solomon parity datagrams. Any `n` datagrams of a group
+//! of `n` data datagrams rebuild the lost ones, so a burst of loss costs no
+//! retransmission round trip. Every datagram starts with a header:
+//!
+//! ```text
+//! group (4, BE) | index (1) | data shards (1) | parity shards (1) | payload
+//! ```
+//!
+//! Data datagrams carry the shape of a full group and their payload as is.
+//! Parity datagrams carry the shape of the group they protect, which is
+//! smaller when it was closed early, and a parity shard over the group's
+//! payloads, each prefixed with its length and padded to the longest.
+
+use crate::config::FecConfig;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Most datagrams, data and parity, in one group
+pub const MAX_SHARDS: usize = 255;
+
+/// Size of the header in front of every datagram
+pub const HEADER_SIZE: usize = 7;
+
+/// Length prefix of a payload inside a shard
+const LENGTH_PREFIX: usize = 2;
+
+/// Groups the decoder remembers; older ones are given up on
+const DECODE_WINDOW: usize = 64;
+
+/// Header of a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    group: u32,
+    index: usize,
+    data_shards: usize,
+    parity_shards: usize,
+}
+
+impl Header {
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(HEADER_SIZE + payload.len());
+        datagram.extend_from_slice(&self.group.to_be_bytes());
+        datagram.push(self.index as u8);
+        datagram.push(self.data_shards as u8);
+        datagram.push(self.parity_shards as u8);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    fn decode(datagram: &[u8]) -> Result<(Self, &[u8]), String> {
+        if datagram.len() < HEADER_SIZE {
+            return Err("FEC datagram too short".to_string());
+        }
+        let header = Self {
+            group: u32::from_be_bytes([datagram[0], datagram[1], datagram[2], datagram[3]]),
+            index: datagram[4] as usize,
+            data_shards: datagram[5] as usize,
+            parity_shards: datagram[6] as usize,
+        };
+        if header.data_shards == 0 || header.data_shards + header.parity_shards > MAX_SHARDS {
+            return Err(format!("Invalid FEC group of {}+{}", header.data_shards, header.parity_shards));
+        }
+        Ok((header, &datagram[HEADER_SIZE..]))
+    }
+
+    /// Whether the datagram carries data rather than parity
+    fn is_data(&self) -> bool {
+        self.index < self.data_shards
+    }
+}
+
+/// Groups outgoing datagrams and adds their parity
+pub struct FecEncoder {
+    data_shards: usize,
+    parity_shards: usize,
+    flush_delay: Duration,
+    group: u32,
+    /// Payloads of the open group
+    pending: Vec<Vec<u8>>,
+}
+
+impl FecEncoder {
+    pub fn new(config: &FecConfig) -> Self {
+        Self {
+            data_shards: config.group_size.max(1),
+            parity_shards: config.parity_shards(),
+            flush_delay: config.flush_delay,
+            // A new session never continues the groups of an old one
+            group: rand::random(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Data and parity datagrams of a full group
+    pub fn shape(&self) -> (usize, usize) {
+        (self.data_shards, self.parity_shards)
+    }
+
+    /// Use `data_shards` and `parity_shards` from the next group on
+    pub fn set_shape(&mut self, data_shards: usize, parity_shards: usize) {
+        self.data_shards = data_shards;
+        self.parity_shards = parity_shards;
+    }
+
+    /// How long a group may stay open
+    pub fn flush_delay(&self) -> Duration {
+        self.flush_delay
+    }
+
+    /// Group the next datagram joins, if one is open
+    pub fn open_group(&self) -> Option<u32> {
+        (!self.pending.is_empty()).then_some(self.group)
+    }
+
+    /// Frame `payload` as the next data datagram, followed by the parity
+    /// datagrams of its group when it fills the group
+    pub fn push(&mut self, payload: &[u8]) -> Vec<Vec<u8>> {
+        let header = Header {
+            group: self.group,
+            index: self.pending.len(),
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+        };
+        let mut datagrams = vec![header.encode(payload)];
+        self.pending.push(payload.to_vec());
+        if self.pending.len() >= self.data_shards {
+            datagrams.extend(self.close());
+        }
+        datagrams
+    }
+
+    /// Parity datagrams of `group` if it is still open, closing it
+    pub fn flush(&mut self, group: u32) -> Vec<Vec<u8>> {
+        if self.open_group() == Some(group) {
+            self.close()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn close(&mut self) -> Vec<Vec<u8>> {
+        let payloads = std::mem::take(&mut self.pending);
+        let group = self.group;
+        self.group = self.group.wrapping_add(1);
+
+        let data_shards = payloads.len();
+        let parity_shards = (data_shards * self.parity_shards).div_ceil(self.data_shards);
+        if parity_shards == 0 {
+            return Vec::new();
+        }
+        let shard_len = LENGTH_PREFIX + payloads.iter().map(Vec::len).max().unwrap_or(0);
+        let mut shards: Vec<Vec<u8>> = payloads.iter().map(|payload| to_shard(payload, shard_len)).collect();
+        shards.resize(data_shards + parity_shards, vec![0u8; shard_len]);
+        let encoded = ReedSolomon::new(data_shards, parity_shards).and_then(|rs| rs.encode(&mut shards));
+        if let Err(e) = encoded {
+            log::warn!("FEC: cannot encode group {}: {:?}", group, e);
+            return Vec::new();
+        }
+
+        shards
+            .drain(data_shards..)
+            .enumerate()
+            .map(|(i, shard)| {
+                Header { group, index: data_shards + i, data_shards, parity_shards }.encode(&shard)
+            })
+            .collect()
+    }
+}
+
+/// Payload as a shard of `shard_len` bytes
+fn to_shard(payload: &[u8], shard_len: usize) -> Vec<u8> {
+    let mut shard = Vec::with_capacity(shard_len);
+    shard.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    shard.extend_from_slice(payload);
+    shard.resize(shard_len, 0);
+    shard
+}
+
+/// Payload of a rebuilt shard
+fn from_shard(shard: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::from_be_bytes([*shard.first()?, *shard.get(1)?]) as usize;
+    shard.get(LENGTH_PREFIX..LENGTH_PREFIX + len).map(<[u8]>::to_vec)
+}
+
+/// Datagrams received of one group
+#[derive(Default)]
+struct Group {
+    data: HashMap<usize, Vec<u8>>,
+    parity: HashMap<usize, Vec<u8>>,
+    /// Shape of the group, known from its parity
+    shape: Option<(usize, usize)>,
+    complete: bool,
+}
+
+/// Passes data datagrams on and rebuilds lost ones from parity
+#[derive(Default)]
+pub struct FecDecoder {
+    groups: HashMap<u32, Group>,
+    /// Groups by arrival, oldest first
+    order: VecDeque<u32>,
+    /// Shape of a full group, from the last data datagram
+    peer_shape: Option<(usize, usize)>,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Data and parity datagrams of a full group of the sender
+    pub fn peer_shape(&self) -> Option<(usize, usize)> {
+        self.peer_shape
+    }
+
+    /// Payloads delivered by `datagram`: its own if it carries data, and
+    /// any it made it possible to rebuild
+    pub fn receive(&mut self, datagram: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let (header, payload) = Header::decode(datagram)?;
+        if header.is_data() {
+            self.peer_shape = Some((header.data_shards, header.parity_shards));
+        }
+        let group = match self.groups.get_mut(&header.group) {
+            Some(group) => group,
+            None => {
+                if self.order.len() == DECODE_WINDOW {
+                    if let Some(oldest) = self.order.pop_front() {
+                        self.groups.remove(&oldest);
+                    }
+                }
+                self.order.push_back(header.group);
+                self.groups.entry(header.group).or_default()
+            }
+        };
+
+        let mut delivered = Vec::new();
+        if header.is_data() {
+            if group.complete || group.data.contains_key(&header.index) {
+                return Ok(delivered);
+            }
+            group.data.insert(header.index, payload.to_vec());
+            delivered.push(payload.to_vec());
+        } else {
+            if group.complete {
+                return Ok(delivered);
+            }
+            group.shape = Some((header.data_shards, header.parity_shards));
+            group.parity.insert(header.index, payload.to_vec());
+        }
+
+        let Some((data_shards, parity_shards)) = group.shape else {
+            return Ok(delivered);
+        };
+        if group.data.keys().any(|&index| index >= data_shards) {
+            group.complete = true;
+            return Err(format!("FEC group {} has data beyond its {} shards", header.group, data_shards));
+        }
+        if group.data.len() == data_shards {
+            group.complete = true;
+        } else if group.data.len() + group.parity.len() >= data_shards {
+            group.complete = true;
+            delivered.extend(Self::rebuild(group, data_shards, parity_shards)?);
+        }
+        Ok(delivered)
+    }
+
+    /// Payloads of the data shards `group` is missing
+    fn rebuild(group: &Group, data_shards: usize, parity_shards: usize) -> Result<Vec<Vec<u8>>, String> {
+        let shard_len = group.parity.values().map(Vec::len).max().unwrap_or(0);
+        let mut shards: Vec<Option<Vec<u8>>> = (0..data_shards + parity_shards)
+            .map(|index| match group.data.get(&index) {
+                Some(payload) => Some(to_shard(payload, shard_len)),
+                None => group.parity.get(&index).filter(|shard| shard.len() == shard_len).cloned(),
+            })
+            .collect();
+        ReedSolomon::new(data_shards, parity_shards)
+            .and_then(|rs| rs.reconstruct_data(&mut shards))
+            .map_err(|e| format!("Cannot rebuild FEC group: {:?}", e))?;
+
+        let mut rebuilt = Vec::new();
+        for (index, shard) in shards.iter().enumerate().take(data_shards) {
+            if group.data.contains_key(&index) {
+                continue;
+            }
+            let payload = shard.as_deref().and_then(from_shard).ok_or("Rebuilt FEC shard is malformed")?;
+            rebuilt.push(payload);
+        }
+        Ok(rebuilt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(redundancy: f64) -> FecConfig {
+        FecConfig {
+            enabled: true,
+            redundancy,
+            group_size: 8,
+            flush_delay: Duration::from_millis(20),
+        }
+    }
+
+    #[test]
+    fn test_rebuild_lost_datagrams() {
+        let mut encoder = FecEncoder::new(&config(0.25));
+        assert_eq!(encoder.shape(), (8, 2));
+        let payloads: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 100 + i as usize * 10]).collect();
+        let datagrams: Vec<Vec<u8>> = payloads.iter().flat_map(|payload| encoder.push(payload)).collect();
+        assert_eq!(datagrams.len(), 10);
+        assert_eq!(encoder.open_group(), None);
+
+        // Any two datagrams of the group may be lost
+        let mut decoder = FecDecoder::new();
+        let mut received = Vec::new();
+        for (i, datagram) in datagrams.iter().enumerate() {
+            if i != 2 && i != 5 {
+                received.extend(decoder.receive(datagram).unwrap());
+            }
+        }
+        assert_eq!(decoder.peer_shape(), Some((8, 2)));
+        received.sort();
+        assert_eq!(received, payloads);
+
+        // Duplicates and late datagrams are not delivered again
+        assert!(decoder.receive(&datagrams[2]).unwrap().is_empty());
+        assert!(decoder.receive(&datagrams[0]).unwrap().is_empty());
+        assert!(decoder.receive(&datagrams[0][..3]).is_err());
+    }
+
+    #[test]
+    fn test_flush_partial_group() {
+        let mut encoder = FecEncoder::new(&config(0.5));
+        let first = encoder.push(b"one");
+        let group = encoder.open_group().unwrap();
+        let second = encoder.push(b"two, a little longer");
+        // A closed group has parity in proportion to its size
+        let parity = encoder.flush(group);
+        assert_eq!(parity.len(), 1);
+        assert!(encoder.flush(group).is_empty());
+        assert_eq!(encoder.open_group(), None);
+
+        let mut decoder = FecDecoder::new();
+        assert_eq!(decoder.receive(&second[0]).unwrap(), vec![b"two, a little longer".to_vec()]);
+        assert_eq!(decoder.receive(&parity[0]).unwrap(), vec![b"one".to_vec()]);
+        assert!(decoder.receive(&first[0]).unwrap().is_empty());
+
+        // No redundancy still frames, without parity
+        let mut encoder = FecEncoder::new(&config(0.0));
+        encoder.push(b"data");
+        let group = encoder.open_group().unwrap();
+        assert!(encoder.flush(group).is_empty());
+    }
+}
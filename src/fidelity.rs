@@ -0,0 +1,291 @@
+//! Emulation fidelity scoring
+//!
+//! Compares the frames a tunnel actually produces against the signature of the
+//! protocol it claims to be: fixed header bytes and length fields from the PSF
+//! data format, and packet sizes and timing from [`ProtocolMeta`]. The result
+//! complements the static [`DetectionScore`](crate::protocol::DetectionScore)
+//! with a measured score for the running tunnel.
+
+use crate::protocol::ProtocolMeta;
+use crate::psf::types::{FieldType, SemanticType};
+use crate::psf::ProtocolFrame;
+use serde::Serialize;
+use std::ops::Range;
+use std::time::Instant;
+
+/// Weight of header conformance in the overall score
+const HEADER_WEIGHT: f64 = 0.5;
+/// Weight of packet size conformance in the overall score
+const SIZE_WEIGHT: f64 = 0.3;
+/// Weight of timing conformance in the overall score
+const TIMING_WEIGHT: f64 = 0.2;
+
+/// What a protocol's frames are expected to look like on the wire
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolSignature {
+    /// Fixed bytes at known offsets from the start of each frame
+    pub fixed_bytes: Vec<(usize, Vec<u8>)>,
+    /// Offset and width of a big-endian length field counting the bytes after it
+    pub length_field: Option<(usize, usize)>,
+    /// Typical frame size range
+    pub size_range: Option<Range<usize>>,
+    /// Typical inter-frame delay in microseconds
+    pub avg_delay_us: Option<u64>,
+}
+
+impl ProtocolSignature {
+    /// Build a signature from a PSF data frame and protocol metadata
+    pub fn new(frame: Option<&ProtocolFrame>, meta: Option<&ProtocolMeta>) -> Self {
+        let mut signature = Self::default();
+
+        if let Some(frame) = frame {
+            // Only fields before the first variable-size field have a fixed offset
+            let mut offset = 0;
+            for field in &frame.format.fields {
+                let size = match field.field_type {
+                    FieldType::UInt(n) | FieldType::ByteArray(n) => n,
+                    _ => break,
+                };
+                let semantic = frame
+                    .semantics
+                    .iter()
+                    .find(|r| r.field == field.name)
+                    .map(|r| &r.semantic);
+                match semantic {
+                    Some(SemanticType::FixedValue(value)) if size <= 8 => {
+                        let bytes = value.to_be_bytes()[8 - size..].to_vec();
+                        signature.fixed_bytes.push((offset, bytes));
+                    }
+                    Some(SemanticType::FixedBytes(bytes)) if bytes.len() == size => {
+                        signature.fixed_bytes.push((offset, bytes.clone()));
+                    }
+                    Some(SemanticType::Length) if signature.length_field.is_none() && size <= 8 => {
+                        signature.length_field = Some((offset, size));
+                    }
+                    _ => {}
+                }
+                offset += size;
+            }
+        }
+
+        if let Some(meta) = meta {
+            signature.size_range = Some(meta.typical_packet_size.clone());
+            signature.avg_delay_us = meta.metadata.avg_packet_delay;
+        }
+
+        signature
+    }
+
+    /// Fraction of header checks `frame` passes, or `None` if there are none
+    fn header_conformance(&self, frame: &[u8]) -> Option<f64> {
+        let mut checks = 0usize;
+        let mut passed = 0usize;
+
+        for (offset, expected) in &self.fixed_bytes {
+            checks += 1;
+            if frame.get(*offset..offset + expected.len()) == Some(expected.as_slice()) {
+                passed += 1;
+            }
+        }
+
+        if let Some((offset, width)) = self.length_field {
+            checks += 1;
+            if let Some(bytes) = frame.get(offset..offset + width) {
+                let value = bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+                if value == (frame.len() - offset - width) as u64 {
+                    passed += 1;
+                }
+            }
+        }
+
+        (checks > 0).then(|| passed as f64 / checks as f64)
+    }
+}
+
+/// Measured emulation fidelity (each component 0.0 - 1.0, higher = closer)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FidelityScore {
+    /// Fraction of frames whose fixed header bytes and length fields match
+    pub header: f64,
+    /// Fraction of frames within the typical size range
+    pub size: f64,
+    /// Closeness of the mean inter-frame delay to the typical delay
+    pub timing: f64,
+    /// Weighted combination of the above
+    pub overall: f64,
+    /// Number of frames observed
+    pub frames: u64,
+}
+
+/// Accumulates observed frames and scores them against a signature
+#[derive(Debug, Clone)]
+pub struct FidelityScorer {
+    signature: ProtocolSignature,
+    frames: u64,
+    header_sum: f64,
+    header_frames: u64,
+    size_hits: u64,
+    delay_sum_us: f64,
+    last_frame: Option<Instant>,
+}
+
+impl FidelityScorer {
+    /// Create a scorer for `signature`
+    pub fn new(signature: ProtocolSignature) -> Self {
+        Self {
+            signature,
+            frames: 0,
+            header_sum: 0.0,
+            header_frames: 0,
+            size_hits: 0,
+            delay_sum_us: 0.0,
+            last_frame: None,
+        }
+    }
+
+    /// Record a wrapped frame sent now
+    pub fn observe(&mut self, frame: &[u8]) {
+        self.observe_at(frame, Instant::now());
+    }
+
+    /// Record a wrapped frame sent at `at`
+    pub fn observe_at(&mut self, frame: &[u8], at: Instant) {
+        self.frames += 1;
+
+        if let Some(conformance) = self.signature.header_conformance(frame) {
+            self.header_sum += conformance;
+            self.header_frames += 1;
+        }
+
+        if self
+            .signature
+            .size_range
+            .as_ref()
+            .is_none_or(|range| frame.len() >= range.start && frame.len() <= range.end)
+        {
+            self.size_hits += 1;
+        }
+
+        if let Some(last) = self.last_frame {
+            self.delay_sum_us += at.saturating_duration_since(last).as_micros() as f64;
+        }
+        self.last_frame = Some(at);
+    }
+
+    /// Number of frames observed so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Current score. Components without a signature or observations score 1.0.
+    pub fn score(&self) -> FidelityScore {
+        let header = if self.header_frames > 0 {
+            self.header_sum / self.header_frames as f64
+        } else {
+            1.0
+        };
+
+        let size = if self.frames > 0 {
+            self.size_hits as f64 / self.frames as f64
+        } else {
+            1.0
+        };
+
+        let timing = match self.signature.avg_delay_us {
+            Some(expected) if expected > 0 && self.frames > 1 => {
+                let observed = self.delay_sum_us / (self.frames - 1) as f64;
+                let expected = expected as f64;
+                observed.min(expected) / observed.max(expected)
+            }
+            _ => 1.0,
+        };
+
+        FidelityScore {
+            header,
+            size,
+            timing,
+            overall: header * HEADER_WEIGHT + size * SIZE_WEIGHT + timing * TIMING_WEIGHT,
+            frames: self.frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psf::PsfInterpreter;
+    use std::time::Duration;
+
+    const TLS_PSF: &str = r#"
+@SEGMENT.FORMATS
+
+  DEFINE Record
+    { NAME: content_type   ; TYPE: u8 },
+    { NAME: legacy_version ; TYPE: u16 },
+    { NAME: length         ; TYPE: u16 },
+    { NAME: encrypted      ; TYPE: [u8; length] };
+
+@SEGMENT.SEMANTICS
+
+  { FORMAT: Record; FIELD: content_type;   SEMANTIC: FIXED_VALUE(0x17) };
+  { FORMAT: Record; FIELD: legacy_version; SEMANTIC: FIXED_VALUE(0x0303) };
+  { FORMAT: Record; FIELD: length;         SEMANTIC: LENGTH };
+  { FORMAT: Record; FIELD: encrypted;      SEMANTIC: PAYLOAD };
+
+@SEGMENT.SEQUENCE
+
+  { ROLE: CLIENT; PHASE: DATA; FORMAT: Record };
+  { ROLE: SERVER; PHASE: DATA; FORMAT: Record };
+"#;
+
+    fn tls_frame() -> ProtocolFrame {
+        PsfInterpreter::load_from_string(TLS_PSF)
+            .unwrap()
+            .create_frame("client", "data")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_signature_from_psf() {
+        let signature = ProtocolSignature::new(Some(&tls_frame()), None);
+        assert_eq!(signature.fixed_bytes, vec![(0, vec![0x17]), (1, vec![0x03, 0x03])]);
+        assert_eq!(signature.length_field, Some((3, 2)));
+    }
+
+    #[test]
+    fn test_conforming_frames_score_high() {
+        let frame = tls_frame();
+        let mut signature = ProtocolSignature::new(Some(&frame), None);
+        signature.size_range = Some(50..1500);
+        signature.avg_delay_us = Some(10_000);
+
+        let mut scorer = FidelityScorer::new(signature);
+        let start = Instant::now();
+        for i in 0..10u64 {
+            let wrapped = frame.wrap(&[0xab; 200]).unwrap();
+            scorer.observe_at(&wrapped, start + Duration::from_millis(10 * i));
+        }
+
+        let score = scorer.score();
+        assert_eq!(score.frames, 10);
+        assert_eq!(score.header, 1.0);
+        assert_eq!(score.size, 1.0);
+        assert!(score.timing > 0.99);
+        assert!(score.overall > 0.99);
+    }
+
+    #[test]
+    fn test_raw_frames_score_low() {
+        let mut signature = ProtocolSignature::new(Some(&tls_frame()), None);
+        signature.size_range = Some(50..1500);
+
+        let mut scorer = FidelityScorer::new(signature);
+        scorer.observe(&[0x00; 4000]);
+        scorer.observe(&[0x42; 10]);
+
+        let score = scorer.score();
+        assert!(score.header < 0.5);
+        assert_eq!(score.size, 0.0);
+        assert!(score.overall < 0.5);
+    }
+}
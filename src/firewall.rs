@@ -0,0 +1,361 @@
+//! Outbound firewall rules shared by [`crate::dns_leak`] and
+//! [`crate::kill_switch`]
+//!
+//! A [`Block`] rejects outbound traffic, either to one port over UDP and
+//! TCP or all of it, except to a list of allowed addresses and loopback:
+//!
+//! | Platform | Firewall                                           |
+//! |----------|----------------------------------------------------|
+//! | Linux    | A chain in `iptables` and `ip6tables` `OUTPUT`     |
+//! | macOS    | A `com.apple/*` `pf` anchor                        |
+//! | Windows  | `netsh advfirewall` block rules (not counted)      |
+//!
+//! Blocking all traffic still lets DHCP through on Linux and macOS, so the
+//! host keeps its address. Like [`crate::key_provider`], this shells out to
+//! the platform tools.
+
+use crate::key_provider::run;
+use anyhow::{bail, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::Command;
+
+/// Traffic to block and the names of the rules on each firewall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Block {
+    /// iptables chain holding the rules
+    pub chain: &'static str,
+    /// pf anchor holding the rules; `com.apple/*` is evaluated by the
+    /// stock pf.conf
+    pub anchor: &'static str,
+    /// Name of the Windows Firewall rules
+    pub rule: &'static str,
+    /// Destination port blocked over UDP and TCP, or `None` for all traffic
+    pub port: Option<u16>,
+}
+
+/// Installed rules, removed when dropped
+#[derive(Debug)]
+pub(crate) struct Rules {
+    block: Block,
+    firewall: Firewall,
+    removed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Firewall {
+    Iptables,
+    /// Whether pf was enabled for the rules, and so is disabled after
+    Pf { enabled_pf: bool },
+    Netsh,
+}
+
+impl Rules {
+    /// Install `block`, letting traffic to `allowed` and loopback through
+    pub fn install(block: Block, allowed: &[IpAddr]) -> Result<Self> {
+        let firewall = if cfg!(target_os = "macos") {
+            install_pf(block, allowed)?
+        } else if cfg!(windows) {
+            install_netsh(block, allowed)?
+        } else {
+            install_iptables(block, allowed)?
+        };
+        Ok(Self { block, firewall, removed: false })
+    }
+
+    /// Packets blocked so far, where the firewall counts them
+    pub fn blocked(&self) -> Result<Option<u64>> {
+        Ok(match self.firewall {
+            Firewall::Iptables => {
+                let mut blocked = 0;
+                for program in ["iptables", "ip6tables"] {
+                    let listing = run(Command::new(program).args(["-L", self.block.chain, "-v", "-x", "-n"]), None)?;
+                    blocked += parse_iptables_rejected(&listing);
+                }
+                Some(blocked)
+            }
+            Firewall::Pf { .. } => Some(parse_pf_blocked(&run(
+                Command::new("pfctl").args(["-a", self.block.anchor, "-v", "-s", "rules"]),
+                None,
+            )?)),
+            Firewall::Netsh => None,
+        })
+    }
+
+    /// Remove the rules
+    pub fn remove(mut self) -> Result<()> {
+        self.removed = true;
+        remove(self.block, self.firewall)
+    }
+}
+
+impl Drop for Rules {
+    fn drop(&mut self) {
+        if !self.removed {
+            if let Err(e) = remove(self.block, self.firewall) {
+                log::warn!("Failed to remove the {} firewall rules: {}", self.block.chain, e);
+            }
+        }
+    }
+}
+
+/// `OUTPUT` rule jumping to the chain
+fn iptables_hook(block: Block, protocol: Option<&str>) -> Vec<String> {
+    let mut rule = vec!["OUTPUT".to_string()];
+    if let (Some(port), Some(protocol)) = (block.port, protocol) {
+        rule.extend(["-p".to_string(), protocol.to_string(), "--dport".to_string(), port.to_string()]);
+    }
+    rule.extend(["-j".to_string(), block.chain.to_string()]);
+    rule
+}
+
+/// Protocols hooked into `OUTPUT`: both for a port, everything otherwise
+fn iptables_protocols(block: Block) -> Vec<Option<&'static str>> {
+    match block.port {
+        Some(_) => vec![Some("udp"), Some("tcp")],
+        None => vec![None],
+    }
+}
+
+fn install_iptables(block: Block, allowed: &[IpAddr]) -> Result<Firewall> {
+    // Rules left behind by a client that did not exit cleanly
+    let _ = remove(block, Firewall::Iptables);
+    let chain = block.chain.to_string();
+    for (program, loopback, dhcp) in [("iptables", "127.0.0.0/8", "67"), ("ip6tables", "::1/128", "547")] {
+        let ipv6 = program == "ip6tables";
+        let mut rules: Vec<Vec<String>> = vec![vec!["-N".into(), chain.clone()]];
+        for address in allowed.iter().filter(|address| address.is_ipv6() == ipv6) {
+            rules.push(vec!["-A".into(), chain.clone(), "-d".into(), address.to_string(), "-j".into(), "RETURN".into()]);
+        }
+        rules.push(vec!["-A".into(), chain.clone(), "-d".into(), loopback.into(), "-j".into(), "RETURN".into()]);
+        if block.port.is_none() {
+            rules.push(["-A", &chain, "-p", "udp", "--dport", dhcp, "-j", "RETURN"].map(String::from).to_vec());
+        }
+        rules.push(vec!["-A".into(), chain.clone(), "-j".into(), "REJECT".into()]);
+        for protocol in iptables_protocols(block) {
+            let mut hook = vec!["-I".to_string()];
+            hook.extend(iptables_hook(block, protocol));
+            rules.push(hook);
+        }
+        for rule in rules {
+            if let Err(e) = run(Command::new(program).args(&rule), None) {
+                let _ = remove(block, Firewall::Iptables);
+                return Err(e);
+            }
+        }
+    }
+    Ok(Firewall::Iptables)
+}
+
+/// Packets the chain's REJECT rule matched, from `iptables -L -v -x -n`
+fn parse_iptables_rejected(listing: &str) -> u64 {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.get(2) == Some(&"REJECT")).then(|| fields[0].parse::<u64>().ok()).flatten()
+        })
+        .sum()
+}
+
+/// pf rules for `block`
+fn pf_rules(block: Block, allowed: &[IpAddr]) -> String {
+    let (proto, port) = match block.port {
+        Some(port) => (" proto { udp tcp }".to_string(), format!(" port {}", port)),
+        None => (String::new(), String::new()),
+    };
+    let mut rules = String::new();
+    if !allowed.is_empty() {
+        let allowed: Vec<String> = allowed.iter().map(IpAddr::to_string).collect();
+        rules.push_str(&format!("pass out quick{} to {{ {} }}{}\n", proto, allowed.join(" "), port));
+    }
+    rules.push_str(&format!("pass out quick on lo0{} to any{}\n", proto, port));
+    if block.port.is_none() {
+        rules.push_str("pass out quick proto udp to any port { 67 547 }\n");
+    }
+    rules.push_str(&format!("block return out quick{} to any{}\n", proto, port));
+    rules
+}
+
+fn install_pf(block: Block, allowed: &[IpAddr]) -> Result<Firewall> {
+    run(Command::new("pfctl").args(["-a", block.anchor, "-f", "-"]), Some(&pf_rules(block, allowed)))?;
+
+    let info = run(Command::new("pfctl").args(["-s", "info"]), None)?;
+    let enabled_pf = !info.lines().any(|line| line.starts_with("Status: Enabled"));
+    if enabled_pf {
+        run(Command::new("pfctl").arg("-e"), None)?;
+    }
+    Ok(Firewall::Pf { enabled_pf })
+}
+
+/// Packets blocked by the anchor's block rules, from `pfctl -v -s rules`
+fn parse_pf_blocked(listing: &str) -> u64 {
+    let mut blocked = 0;
+    let mut in_block_rule = false;
+    for line in listing.lines() {
+        let line = line.trim();
+        if let Some(counters) = line.strip_prefix('[') {
+            if in_block_rule {
+                blocked += counters
+                    .split_whitespace()
+                    .skip_while(|word| *word != "Packets:")
+                    .nth(1)
+                    .and_then(|packets| packets.parse::<u64>().ok())
+                    .unwrap_or(0);
+            }
+        } else {
+            in_block_rule = line.starts_with("block");
+        }
+    }
+    blocked
+}
+
+fn install_netsh(block: Block, allowed: &[IpAddr]) -> Result<Firewall> {
+    // Windows Firewall applies block rules before allow rules, so the
+    // exceptions are left out of the blocked ranges instead
+    let mut v4 = vec![(u32::from(Ipv4Addr::new(127, 0, 0, 0)) as u128, u32::from(Ipv4Addr::new(127, 255, 255, 255)) as u128)];
+    let mut v6 = vec![(1u128, 1u128)];
+    for address in allowed {
+        match address {
+            IpAddr::V4(ip) => v4.push((u32::from(*ip) as u128, u32::from(*ip) as u128)),
+            IpAddr::V6(ip) => v6.push((u128::from(*ip), u128::from(*ip))),
+        }
+    }
+    let mut ranges: Vec<String> = ranges_excluding(u32::MAX as u128, v4)
+        .into_iter()
+        .map(|(first, last)| format!("{}-{}", Ipv4Addr::from(first as u32), Ipv4Addr::from(last as u32)))
+        .collect();
+    ranges.extend(
+        ranges_excluding(u128::MAX, v6)
+            .into_iter()
+            .map(|(first, last)| format!("{}-{}", Ipv6Addr::from(first), Ipv6Addr::from(last))),
+    );
+
+    let _ = remove(block, Firewall::Netsh);
+    let protocols: &[&str] = if block.port.is_some() { &["UDP", "TCP"] } else { &["any"] };
+    for protocol in protocols {
+        let mut args = vec![
+            "advfirewall".to_string(),
+            "firewall".to_string(),
+            "add".to_string(),
+            "rule".to_string(),
+            format!("name={}", block.rule),
+            "dir=out".to_string(),
+            "action=block".to_string(),
+            format!("protocol={}", protocol),
+        ];
+        if let Some(port) = block.port {
+            args.push(format!("remoteport={}", port));
+        }
+        args.push(format!("remoteip={}", ranges.join(",")));
+        if let Err(e) = run(Command::new("netsh").args(&args), None) {
+            let _ = remove(block, Firewall::Netsh);
+            return Err(e);
+        }
+    }
+    Ok(Firewall::Netsh)
+}
+
+/// Ranges covering `0..=max` apart from `excluded`
+fn ranges_excluding(max: u128, mut excluded: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    excluded.sort();
+    let mut ranges = Vec::new();
+    let mut next = Some(0u128);
+    for (first, last) in excluded {
+        if let Some(start) = next {
+            if first > start {
+                ranges.push((start, first - 1));
+            }
+            if last >= start {
+                next = last.checked_add(1).filter(|&n| n <= max);
+            }
+        }
+    }
+    if let Some(start) = next {
+        ranges.push((start, max));
+    }
+    ranges
+}
+
+fn remove(block: Block, firewall: Firewall) -> Result<()> {
+    let mut failures = Vec::new();
+    let mut attempt = |result: Result<String>| {
+        if let Err(e) = result {
+            failures.push(e.to_string());
+        }
+    };
+    match firewall {
+        Firewall::Iptables => {
+            for program in ["iptables", "ip6tables"] {
+                for protocol in iptables_protocols(block) {
+                    let mut hook = vec!["-D".to_string()];
+                    hook.extend(iptables_hook(block, protocol));
+                    attempt(run(Command::new(program).args(&hook), None));
+                }
+                attempt(run(Command::new(program).args(["-F", block.chain]), None));
+                attempt(run(Command::new(program).args(["-X", block.chain]), None));
+            }
+        }
+        Firewall::Pf { enabled_pf } => {
+            attempt(run(Command::new("pfctl").args(["-a", block.anchor, "-F", "rules"]), None));
+            if enabled_pf {
+                attempt(run(Command::new("pfctl").arg("-d"), None));
+            }
+        }
+        Firewall::Netsh => {
+            attempt(run(
+                Command::new("netsh").args(["advfirewall", "firewall", "delete", "rule", &format!("name={}", block.rule)]),
+                None,
+            ));
+        }
+    }
+    if !failures.is_empty() {
+        bail!("{}", failures.join("; "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_ranges() {
+        let server = u32::from(Ipv4Addr::new(192, 0, 2, 7)) as u128;
+        let loopback = (u32::from(Ipv4Addr::new(127, 0, 0, 0)) as u128, u32::from(Ipv4Addr::new(127, 255, 255, 255)) as u128);
+        let ranges = ranges_excluding(u32::MAX as u128, vec![(server, server), loopback]);
+        assert_eq!(
+            ranges,
+            vec![(0, loopback.0 - 1), (loopback.1 + 1, server - 1), (server + 1, u32::MAX as u128)]
+        );
+        assert_eq!(ranges_excluding(u128::MAX, vec![(0, 5), (3, u128::MAX)]), vec![]);
+    }
+
+    #[test]
+    fn test_parse_counters() {
+        let iptables = "Chain NOOSHDAROO_DNS (2 references)\n    pkts      bytes target     prot opt in     out     source               destination\n      12      720 RETURN     all  --  *      *       0.0.0.0/0            192.0.2.7\n       0        0 RETURN     all  --  *      *       0.0.0.0/0            127.0.0.0/8\n       3      180 REJECT     all  --  *      *       0.0.0.0/0            0.0.0.0/0            reject-with icmp-port-unreachable\n";
+        assert_eq!(parse_iptables_rejected(iptables), 3);
+
+        let pf = "pass out quick proto udp from any to 192.0.2.7 port = 53 keep state\n  [ Evaluations: 40  Packets: 12  Bytes: 720  States: 0  ]\nblock return out quick proto udp from any to any port = 53\n  [ Evaluations: 40  Packets: 5  Bytes: 300  States: 0  ]\nblock return out quick proto tcp from any to any port = 53\n  [ Evaluations: 40  Packets: 2  Bytes: 120  States: 0  ]\n";
+        assert_eq!(parse_pf_blocked(pf), 7);
+    }
+
+    #[test]
+    fn test_pf_rules() {
+        let servers = [IpAddr::from([192, 0, 2, 7])];
+        let dns = Block { chain: "DNS", anchor: "com.apple/dns", rule: "dns", port: Some(53) };
+        assert_eq!(
+            pf_rules(dns, &servers),
+            "pass out quick proto { udp tcp } to { 192.0.2.7 } port 53\n\
+             pass out quick on lo0 proto { udp tcp } to any port 53\n\
+             block return out quick proto { udp tcp } to any port 53\n"
+        );
+        let all = Block { port: None, ..dns };
+        assert_eq!(
+            pf_rules(all, &servers),
+            "pass out quick to { 192.0.2.7 }\n\
+             pass out quick on lo0 to any\n\
+             pass out quick proto udp to any port { 67 547 }\n\
+             block return out quick to any\n"
+        );
+    }
+}
@@ -0,0 +1,578 @@
+//! Tunnel carried as an FTP session with passive data connections
+//!
+//! A connection that carries data both ways for hours is odd on port 21;
+//! an FTP session moving files is not. This transport logs in over the
+//! control connection like an FTP client and moves tunnel data the way FTP
+//! moves files: each transfer opens a passive data connection (`PASV`, or
+//! `EPSV` over IPv6), sends one file with `STOR` or fetches one with
+//! `RETR`, and closes it. The control connection only ever carries
+//! commands and replies.
+//!
+//! ```toml
+//! [socks]
+//! transport = "ftp"
+//!
+//! [server]
+//! transport = "ftp"
+//! # Address in PASV replies, when the server is behind NAT
+//! ftp_passive_address = "203.0.113.7"
+//! ```
+//!
+//! FTP runs one transfer at a time and only the client issues commands, so
+//! the client uploads while it has tunnel data and otherwise asks for a
+//! download, which the server holds open until it has data to send. When
+//! tunnel data turns up on the client first, it sends `ABOR` and uploads
+//! instead. A transfer ends once its data pauses for [`TRANSFER_IDLE`], or
+//! after a few megabytes or seconds, and the next one starts with a new
+//! data connection.
+//!
+//! The server replies like vsftpd and accepts any login; data connections
+//! are only accepted from the client's address.
+
+use rand::Rng;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Buffer between the tunnel and the FTP tasks
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Longest command or reply line
+const MAX_LINE: usize = 512;
+
+/// A transfer ends after its data has paused this long
+pub const TRANSFER_IDLE: Duration = Duration::from_millis(150);
+
+/// Size after which a transfer ends, in bytes
+const TRANSFER_SIZE: Range<usize> = 1 << 20..8 << 20;
+
+/// Duration after which a transfer ends, in milliseconds
+const TRANSFER_TIME_MS: Range<u64> = 1000..5000;
+
+/// Time the client gets to open a data connection
+const DATA_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commands the server answers before a login
+const MAX_LOGIN_COMMANDS: usize = 20;
+
+/// Client pause before each login command, in milliseconds
+const CLIENT_DELAY_MS: Range<u64> = 20..150;
+
+/// Log in over `control` and start the client side; `server_ip` is where
+/// data connections go. The returned stream carries the tunnel
+pub async fn connect<S>(control: S, server_ip: IpAddr) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(control);
+    let mut replies = spawn_line_reader(reader);
+    expect(&mut replies, &[220]).await?;
+    for (command, code) in [
+        ("USER anonymous", 331),
+        ("PASS anonymous@", 230),
+        ("SYST", 215),
+        ("FEAT", 211),
+        ("PWD", 257),
+        ("TYPE I", 200),
+    ] {
+        let delay = rand::thread_rng().gen_range(CLIENT_DELAY_MS);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        send(&mut writer, command).await?;
+        expect(&mut replies, &[code]).await?;
+    }
+
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        let mut client = Client {
+            writer,
+            replies,
+            server_ip,
+            pending: Vec::new(),
+            app_closed: false,
+        };
+        let (mut app_read, mut app_write) = tokio::io::split(local);
+        if let Err(e) = client.run(&mut app_read, &mut app_write).await {
+            log::debug!("FTP client session ended: {}", e);
+        }
+        let _ = app_write.shutdown().await;
+    });
+    Ok(tunnel)
+}
+
+/// Answer an accepted `control` connection; once the client has logged in
+/// the returned stream carries the tunnel. Data connections are accepted on
+/// `local_ip` from `client_ip`, and advertised at `passive_ip` if given
+pub async fn accept<S>(
+    control: S,
+    local_ip: IpAddr,
+    client_ip: IpAddr,
+    passive_ip: Option<IpAddr>,
+) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(control);
+    let mut server = Server {
+        writer,
+        commands: spawn_line_reader(reader),
+        local_ip,
+        client_ip,
+        passive_ip: passive_ip.unwrap_or(local_ip),
+        listener: None,
+        app_closed: false,
+    };
+    server.reply("220 (vsFTPd 3.0.5)").await?;
+
+    let mut user = false;
+    for _ in 0..MAX_LOGIN_COMMANDS {
+        let line = next_line(&mut server.commands).await?;
+        let (verb, _) = split_command(&line);
+        let response = match verb.as_str() {
+            "USER" => {
+                user = true;
+                "331 Please specify the password."
+            }
+            "PASS" if user => {
+                server.reply("230 Login successful.").await?;
+                let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+                tokio::spawn(async move {
+                    let (mut app_read, mut app_write) = tokio::io::split(local);
+                    if let Err(e) = server.run(&mut app_read, &mut app_write).await {
+                        log::debug!("FTP server session ended: {}", e);
+                    }
+                    let _ = app_write.shutdown().await;
+                });
+                return Ok(tunnel);
+            }
+            "PASS" => "503 Login with USER first.",
+            "QUIT" => {
+                server.reply("221 Goodbye.").await?;
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "FTP client quit"));
+            }
+            "FEAT" => FEATURES,
+            _ => "530 Please login with USER and PASS.",
+        };
+        server.reply(response).await?;
+    }
+    Err(invalid("FTP client sent too many commands".to_string()))
+}
+
+const FEATURES: &str = "211-Features:\r\n EPRT\r\n EPSV\r\n MDTM\r\n PASV\r\n REST STREAM\r\n SIZE\r\n TVFS\r\n211 End";
+
+struct Client<W> {
+    writer: W,
+    replies: mpsc::Receiver<io::Result<String>>,
+    server_ip: IpAddr,
+    /// Tunnel data waiting for the next upload
+    pending: Vec<u8>,
+    app_closed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> Client<W> {
+    /// Upload while there is tunnel data, download otherwise
+    async fn run<R, A>(&mut self, app_read: &mut R, app_write: &mut A) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        A: AsyncWrite + Unpin,
+    {
+        loop {
+            if !self.pending.is_empty() {
+                self.upload(app_read).await?;
+            } else if self.app_closed {
+                send(&mut self.writer, "QUIT").await?;
+                expect(&mut self.replies, &[221]).await?;
+                return Ok(());
+            } else {
+                self.download(app_read, app_write).await?;
+            }
+        }
+    }
+
+    /// Open a data connection and start `command` on it
+    async fn transfer(&mut self, command: String) -> io::Result<TcpStream> {
+        let port = if self.server_ip.is_ipv4() {
+            send(&mut self.writer, "PASV").await?;
+            let reply = expect(&mut self.replies, &[227]).await?;
+            parse_pasv(&reply).ok_or_else(|| invalid(format!("Malformed PASV reply {:?}", reply)))?
+        } else {
+            send(&mut self.writer, "EPSV").await?;
+            let reply = expect(&mut self.replies, &[229]).await?;
+            parse_epsv(&reply).ok_or_else(|| invalid(format!("Malformed EPSV reply {:?}", reply)))?
+        };
+        // Like most clients, ignore the address in PASV replies: behind NAT
+        // it is often unreachable
+        let data = TcpStream::connect(SocketAddr::new(self.server_ip, port)).await?;
+        send(&mut self.writer, &command).await?;
+        expect(&mut self.replies, &[150]).await?;
+        Ok(data)
+    }
+
+    async fn upload<R: AsyncRead + Unpin>(&mut self, app_read: &mut R) -> io::Result<()> {
+        let mut data = self.transfer(format!("STOR {}", file_name())).await?;
+        data.write_all(&self.pending).await?;
+        let mut sent = std::mem::take(&mut self.pending).len();
+
+        let (limit, deadline) = transfer_limits();
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        while sent < limit && Instant::now() < deadline {
+            match tokio::time::timeout(TRANSFER_IDLE, app_read.read(&mut buf)).await {
+                Err(_) => break,
+                Ok(Ok(0)) => {
+                    self.app_closed = true;
+                    break;
+                }
+                Ok(Ok(n)) => {
+                    data.write_all(&buf[..n]).await?;
+                    sent += n;
+                }
+                Ok(Err(e)) => return Err(e),
+            }
+        }
+        data.shutdown().await?;
+        expect(&mut self.replies, &[226]).await?;
+        Ok(())
+    }
+
+    /// Fetch a file, aborting if tunnel data turns up before the server
+    /// has sent anything
+    async fn download<R, A>(&mut self, app_read: &mut R, app_write: &mut A) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        A: AsyncWrite + Unpin,
+    {
+        let mut data = self.transfer(format!("RETR {}", file_name())).await?;
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut app_buf = vec![0u8; BUFFER_SIZE];
+        let (mut received, mut aborted) = (0, false);
+        loop {
+            tokio::select! {
+                read = data.read(&mut buf) => match read? {
+                    0 => break,
+                    n => {
+                        app_write.write_all(&buf[..n]).await?;
+                        received += n;
+                    }
+                },
+                read = app_read.read(&mut app_buf), if received == 0 && !aborted => {
+                    match read? {
+                        0 => self.app_closed = true,
+                        n => self.pending.extend_from_slice(&app_buf[..n]),
+                    }
+                    send(&mut self.writer, "ABOR").await?;
+                    aborted = true;
+                }
+            }
+        }
+        if aborted {
+            // The transfer's reply, then the abort's
+            expect(&mut self.replies, &[426, 226]).await?;
+            expect(&mut self.replies, &[225, 226]).await?;
+        } else {
+            expect(&mut self.replies, &[226]).await?;
+        }
+        Ok(())
+    }
+}
+
+struct Server<W> {
+    writer: W,
+    commands: mpsc::Receiver<io::Result<String>>,
+    local_ip: IpAddr,
+    client_ip: IpAddr,
+    passive_ip: IpAddr,
+    /// Listener of the last `PASV` or `EPSV`
+    listener: Option<TcpListener>,
+    app_closed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> Server<W> {
+    async fn run<R, A>(&mut self, app_read: &mut R, app_write: &mut A) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        A: AsyncWrite + Unpin,
+    {
+        loop {
+            if self.app_closed {
+                return self.reply("221 Goodbye.").await;
+            }
+            let Some(line) = self.commands.recv().await else {
+                return Ok(());
+            };
+            let line = line?;
+            let (verb, argument) = split_command(&line);
+            match verb.as_str() {
+                "PASV" | "EPSV" => {
+                    let listener = TcpListener::bind(SocketAddr::new(self.local_ip, 0)).await?;
+                    let port = listener.local_addr()?.port();
+                    self.listener = Some(listener);
+                    let response = match self.passive_ip {
+                        IpAddr::V4(ip) if verb == "PASV" => {
+                            let [a, b, c, d] = ip.octets();
+                            format!("227 Entering Passive Mode ({},{},{},{},{},{}).", a, b, c, d, port >> 8, port & 0xff)
+                        }
+                        _ if verb == "PASV" => "500 PASV not supported over IPv6.".to_string(),
+                        _ => format!("229 Entering Extended Passive Mode (|||{}|)", port),
+                    };
+                    self.reply(&response).await?;
+                }
+                "STOR" | "RETR" => {
+                    let Some(mut data) = self.accept_data().await? else {
+                        continue;
+                    };
+                    if verb == "STOR" {
+                        self.reply("150 Ok to send data.").await?;
+                        tokio::io::copy(&mut data, app_write).await?;
+                        self.reply("226 Transfer complete.").await?;
+                    } else {
+                        let response = format!("150 Opening BINARY mode data connection for {}.", argument);
+                        self.reply(&response).await?;
+                        self.serve_download(data, app_read).await?;
+                    }
+                }
+                "ABOR" => self.reply("225 No transfer to ABOR.").await?,
+                "NOOP" => self.reply("200 NOOP ok.").await?,
+                "TYPE" => self.reply("200 Switching to Binary mode.").await?,
+                "SYST" => self.reply("215 UNIX Type: L8").await?,
+                "FEAT" => self.reply(FEATURES).await?,
+                "PWD" => self.reply("257 \"/\" is the current directory").await?,
+                "CWD" => self.reply("250 Directory successfully changed.").await?,
+                "QUIT" => return self.reply("221 Goodbye.").await,
+                _ => self.reply("500 Unknown command.").await?,
+            }
+        }
+    }
+
+    /// The data connection of the last passive listener, from the client
+    async fn accept_data(&mut self) -> io::Result<Option<TcpStream>> {
+        let Some(listener) = self.listener.take() else {
+            self.reply("425 Use PORT or PASV first.").await?;
+            return Ok(None);
+        };
+        loop {
+            let accepted = tokio::time::timeout(DATA_CONNECT_TIMEOUT, listener.accept()).await;
+            match accepted {
+                Ok(Ok((data, from))) if from.ip() == self.client_ip => return Ok(Some(data)),
+                Ok(Ok((_, from))) => log::debug!("FTP: refusing data connection from {}", from),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {
+                    self.reply("425 Failed to establish connection.").await?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Send tunnel data once there is some, until the transfer's limits or
+    /// the client's `ABOR`
+    async fn serve_download<R: AsyncRead + Unpin>(&mut self, mut data: TcpStream, app_read: &mut R) -> io::Result<()> {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut aborted = false;
+        let first = tokio::select! {
+            read = app_read.read(&mut buf) => read?,
+            command = self.commands.recv() => {
+                match command {
+                    Some(Ok(line)) if split_command(&line).0 == "ABOR" => aborted = true,
+                    Some(Ok(line)) => log::debug!("FTP: ignoring {:?} during a transfer", line),
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                }
+                0
+            }
+        };
+
+        if first == 0 && !aborted {
+            self.app_closed = true;
+        } else if first > 0 {
+            data.write_all(&buf[..first]).await?;
+            let (limit, deadline) = transfer_limits();
+            let mut sent = first;
+            while sent < limit && Instant::now() < deadline {
+                match tokio::time::timeout(TRANSFER_IDLE, app_read.read(&mut buf)).await {
+                    Err(_) => break,
+                    Ok(Ok(0)) => {
+                        self.app_closed = true;
+                        break;
+                    }
+                    Ok(Ok(n)) => {
+                        data.write_all(&buf[..n]).await?;
+                        sent += n;
+                    }
+                    Ok(Err(e)) => return Err(e),
+                }
+            }
+        }
+        data.shutdown().await?;
+
+        if aborted {
+            self.reply("426 Failure writing network stream.").await?;
+            self.reply("226 ABOR successful.").await
+        } else {
+            self.reply("226 Transfer complete.").await
+        }
+    }
+
+    async fn reply(&mut self, response: &str) -> io::Result<()> {
+        send(&mut self.writer, response).await
+    }
+}
+
+/// Read CRLF-terminated lines in a task, so waiting for one can be cancelled
+fn spawn_line_reader<R>(reader: R) -> mpsc::Receiver<io::Result<String>>
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(reader).take(u64::MAX);
+        loop {
+            let mut line = Vec::new();
+            reader.set_limit(MAX_LINE as u64);
+            let line = match reader.read_until(b'\n', &mut line).await {
+                Ok(0) => return,
+                Ok(_) if !line.ends_with(b"\r\n") => Err(invalid("FTP line too long or unterminated".to_string())),
+                Ok(_) => String::from_utf8(line[..line.len() - 2].to_vec()).map_err(|_| invalid("FTP line is not UTF-8".to_string())),
+                Err(e) => Err(e),
+            };
+            let failed = line.is_err();
+            if tx.send(line).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+async fn next_line(lines: &mut mpsc::Receiver<io::Result<String>>) -> io::Result<String> {
+    lines
+        .recv()
+        .await
+        .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::UnexpectedEof, "FTP control connection closed")))
+}
+
+/// Read a possibly multi-line reply, failing unless its code is one of
+/// `codes`; returns the text of its first line
+async fn expect(replies: &mut mpsc::Receiver<io::Result<String>>, codes: &[u16]) -> io::Result<String> {
+    let line = next_line(replies).await?;
+    let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+    let code = code.filter(|code| codes.contains(code));
+    let Some(code) = code else {
+        return Err(invalid(format!("Unexpected FTP reply {:?}", line)));
+    };
+    if line.as_bytes().get(3) == Some(&b'-') {
+        // Continues up to a line starting with the code and a space
+        let end = format!("{} ", code);
+        while !next_line(replies).await?.starts_with(&end) {}
+    }
+    Ok(line.get(4..).unwrap_or("").to_string())
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes()).await?;
+    writer.write_all(b"\r\n").await?;
+    writer.flush().await
+}
+
+/// Upper-case verb and argument of a command line
+fn split_command(line: &str) -> (String, &str) {
+    let (verb, argument) = line.split_once(' ').unwrap_or((line, ""));
+    (verb.to_ascii_uppercase(), argument)
+}
+
+/// Port in `Entering Passive Mode (h1,h2,h3,h4,p1,p2).`
+fn parse_pasv(reply: &str) -> Option<u16> {
+    let numbers = &reply[reply.find('(')? + 1..reply.find(')')?];
+    let fields: Vec<u8> = numbers.split(',').map(|n| n.trim().parse().ok()).collect::<Option<_>>()?;
+    match fields[..] {
+        [_, _, _, _, high, low] => Some(u16::from_be_bytes([high, low])),
+        _ => None,
+    }
+}
+
+/// Port in `Entering Extended Passive Mode (|||port|)`
+fn parse_epsv(reply: &str) -> Option<u16> {
+    reply[reply.find("(|||")? + 4..].split('|').next()?.parse().ok()
+}
+
+fn transfer_limits() -> (usize, Instant) {
+    let mut rng = rand::thread_rng();
+    let duration = Duration::from_millis(rng.gen_range(TRANSFER_TIME_MS));
+    (rng.gen_range(TRANSFER_SIZE), Instant::now() + duration)
+}
+
+/// A plausible name for a transferred file
+fn file_name() -> String {
+    const NAMES: &[(&str, &str)] = &[
+        ("IMG_", ".JPG"),
+        ("DSC", ".jpg"),
+        ("scan", ".pdf"),
+        ("backup-", ".tar.gz"),
+        ("export_", ".csv"),
+        ("access.log.", ".gz"),
+    ];
+    let mut rng = rand::thread_rng();
+    let (prefix, extension) = NAMES[rng.gen_range(0..NAMES.len())];
+    format!("{}{:04}{}", prefix, rng.gen_range(1..10000), extension)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tunnel_over_transfers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (control, from) = listener.accept().await.unwrap();
+            accept(control, server_addr.ip(), from.ip(), None).await
+        });
+        let control = TcpStream::connect(server_addr).await.unwrap();
+        let mut client = connect(control, server_addr.ip()).await.unwrap();
+        let mut server = server.await.unwrap().unwrap();
+
+        // The first upload aborts the waiting download
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"file contents").await.unwrap();
+        let mut buf = [0u8; 13];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"file contents");
+    }
+
+    #[tokio::test]
+    async fn test_prober_sees_ftp_server() {
+        let (prober, control) = tokio::io::duplex(4096);
+        let local_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let server = tokio::spawn(accept(control, local_ip, local_ip, None));
+        let (reader, mut writer) = tokio::io::split(prober);
+        let mut replies = spawn_line_reader(reader);
+
+        assert_eq!(expect(&mut replies, &[220]).await.unwrap(), "(vsFTPd 3.0.5)");
+        send(&mut writer, "RETR /etc/passwd").await.unwrap();
+        expect(&mut replies, &[530]).await.unwrap();
+        send(&mut writer, "FEAT").await.unwrap();
+        expect(&mut replies, &[211]).await.unwrap();
+        send(&mut writer, "QUIT").await.unwrap();
+        expect(&mut replies, &[221]).await.unwrap();
+        assert!(server.await.unwrap().is_err());
+
+        assert_eq!(parse_pasv("Entering Passive Mode (192,0,2,7,195,80)."), Some(50000));
+        assert_eq!(parse_epsv("Entering Extended Passive Mode (|||50000|)"), Some(50000));
+    }
+}
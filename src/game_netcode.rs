@@ -0,0 +1,405 @@
+//! Game netcode framing for KCP sessions
+//!
+//! A KCP flow has its own shape: mostly full-sized segments sent whenever
+//! the window allows, and silence when the tunnel is idle. An online match
+//! looks nothing like it: both ends send one small packet every server
+//! tick, client input upstream and world snapshots downstream, from the
+//! first second of the match to the last, whether anything happens or not.
+//! With `relay.game_netcode` naming a gaming application profile (see
+//! [`crate::app_profiles`]), `kcp` datagrams travel in packets paced at the
+//! profile's tick rate and padded to its packet sizes.
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//!
+//! [server]
+//! transport = "kcp"
+//!
+//! [relay]
+//! game_netcode = "cs2"
+//! ```
+//!
+//! Both ends must name the same profile. Every packet is `sequence (4, LE) |
+//! ack (4, LE) | ack bits (4, LE) | count (1) | (length (2, LE) | datagram)*
+//! | random fill`, with the acknowledgement header game networking
+//! libraries send; a tick without tunnel data sends a filler snapshot.
+//! Packets grow past the profile's sizes while data is queued, like the
+//! full updates at round start, and a tick sends at most
+//! [`MAX_PACKETS_PER_TICK`] packets, which bounds the tunnel's throughput.
+//! A peer is ticked until [`PEER_TIMEOUT`] after its last tunnel datagram.
+//! The lobby phase of the profiles runs over HTTPS in real games and is
+//! not emulated here.
+
+use crate::app_profiles::{ApplicationProfile, SizeDistribution};
+use crate::config::TurnConfig;
+use crate::kcp_transport::{self, KcpListener, Socket};
+use async_trait::async_trait;
+use rand::RngCore;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use webrtc::util::Conn;
+
+/// `sequence | ack | ack bits | count`
+const HEADER_SIZE: usize = 13;
+
+/// Packets stop taking datagrams at this size; a lone KCP segment always fits
+const MAX_PACKET: usize = 1400;
+
+/// Packets sent to a peer per tick while data is queued
+pub const MAX_PACKETS_PER_TICK: usize = 8;
+
+/// A peer is ticked for this long after its last tunnel datagram; KCP pings
+/// idle sessions more often than this
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Datagrams queued per peer; more are dropped, like a full socket buffer
+const PEER_QUEUE: usize = 512;
+
+/// Received datagrams waiting for `recv_from`
+const RECEIVE_QUEUE: usize = 1024;
+
+/// Open a KCP session to `server_addr` in netcode framing after `profile`,
+/// through a relay allocated on `turn` if given
+pub async fn connect(
+    server_addr: SocketAddr,
+    profile: &ApplicationProfile,
+    turn: Option<&TurnConfig>,
+) -> io::Result<DuplexStream> {
+    match turn {
+        Some(turn) => {
+            let (relay, client) = crate::turn_relay::allocate(turn).await?;
+            let socket = Arc::new(GameConn::new(relay, profile, true));
+            Ok(kcp_transport::connect_over(socket, server_addr, client))
+        }
+        None => {
+            let udp = Arc::new(UdpSocket::bind(kcp_transport::any_local_addr(server_addr)).await?);
+            let socket = Arc::new(GameConn::new(udp, profile, true));
+            Ok(kcp_transport::connect_over(socket, server_addr, ()))
+        }
+    }
+}
+
+/// Accept KCP sessions on `addr` in netcode framing after `profile`
+pub async fn bind(addr: SocketAddr, profile: &ApplicationProfile) -> io::Result<KcpListener> {
+    let udp = Arc::new(UdpSocket::bind(addr).await?);
+    KcpListener::bind_over(Arc::new(GameConn::new(udp, profile, false)))
+}
+
+/// Datagram socket sending in game ticks over `inner`
+pub struct GameConn {
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    received: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl GameConn {
+    /// Frame datagrams on `inner` like the client (upstream) or server side
+    /// of `profile`
+    pub fn new(inner: Socket, profile: &ApplicationProfile, client: bool) -> Self {
+        let side = if client { &profile.upstream } else { &profile.downstream };
+        let tick = Duration::from_secs_f64(1.0 / side.packet_rate.mean.max(1.0));
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, received) = mpsc::channel(RECEIVE_QUEUE);
+        let tasks = [
+            tokio::spawn(send_ticks(Arc::clone(&inner), Arc::clone(&peers), tick, side.size_distribution.clone())),
+            tokio::spawn(receive(Arc::clone(&inner), Arc::clone(&peers), tx)),
+        ];
+        Self {
+            inner,
+            peers,
+            received: tokio::sync::Mutex::new(received),
+            tasks,
+        }
+    }
+}
+
+impl Drop for GameConn {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for GameConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(unsupported())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(webrtc::util::Error::ErrUseClosedNetworkConn)?;
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// Queue `buf` for the next tick to `target`
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(target).or_insert_with(Peer::new);
+        peer.last_data = Instant::now();
+        if peer.queue.len() < PEER_QUEUE {
+            peer.queue.push_back(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// Netcode state of one peer
+struct Peer {
+    queue: VecDeque<Vec<u8>>,
+    /// Sequence number of our next packet
+    sequence: u32,
+    /// Latest sequence number received, and the 32 before it
+    ack: u32,
+    ack_bits: u32,
+    last_data: Instant,
+}
+
+impl Peer {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            sequence: 0,
+            ack: 0,
+            ack_bits: 0,
+            last_data: Instant::now(),
+        }
+    }
+
+    /// Next packet, carrying queued datagrams and padded to at least `size`
+    fn packet(&mut self, size: usize, rng: &mut impl RngCore) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(MAX_PACKET.max(size));
+        packet.extend_from_slice(&self.sequence.to_le_bytes());
+        packet.extend_from_slice(&self.ack.to_le_bytes());
+        packet.extend_from_slice(&self.ack_bits.to_le_bytes());
+        packet.push(0);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut count = 0u8;
+        while let Some(datagram) = self.queue.front() {
+            if count == u8::MAX || (count > 0 && packet.len() + 2 + datagram.len() > MAX_PACKET) {
+                break;
+            }
+            packet.extend_from_slice(&(datagram.len() as u16).to_le_bytes());
+            packet.extend_from_slice(datagram);
+            self.queue.pop_front();
+            count += 1;
+        }
+        packet[HEADER_SIZE - 1] = count;
+
+        if packet.len() < size {
+            let start = packet.len();
+            packet.resize(size, 0);
+            rng.fill_bytes(&mut packet[start..]);
+        }
+        packet
+    }
+
+    /// Record packet `sequence` as received
+    fn acknowledge(&mut self, sequence: u32) {
+        let ahead = sequence.wrapping_sub(self.ack) as i32;
+        if ahead > 0 {
+            self.ack_bits = if ahead < 32 { (self.ack_bits << ahead) | (1 << (ahead - 1)) } else { 0 };
+            self.ack = sequence;
+        } else if (-32..0).contains(&ahead) {
+            self.ack_bits |= 1 << (-ahead - 1);
+        }
+    }
+}
+
+/// Send every active peer its packets each tick
+async fn send_ticks(
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    tick: Duration,
+    sizes: SizeDistribution,
+) {
+    let mut interval = tokio::time::interval(tick);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let packets = {
+            let mut rng = rand::thread_rng();
+            let mut peers = peers.lock().unwrap();
+            peers.retain(|_, peer| peer.last_data.elapsed() < PEER_TIMEOUT);
+            let mut packets = Vec::new();
+            for (addr, peer) in peers.iter_mut() {
+                for _ in 0..MAX_PACKETS_PER_TICK {
+                    packets.push((peer.packet(sizes.sample(&mut rng), &mut rng), *addr));
+                    if peer.queue.is_empty() {
+                        break;
+                    }
+                }
+            }
+            packets
+        };
+        for (packet, addr) in packets {
+            if let Err(e) = inner.send_to(&packet, addr).await {
+                log::debug!("Game netcode: send to {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// Unpack received packets into their datagrams
+async fn receive(inner: Socket, peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>, received: mpsc::Sender<(Vec<u8>, SocketAddr)>) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, from) = match inner.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(webrtc::util::Error::ErrUseClosedNetworkConn) => return,
+            Err(e) => {
+                log::debug!("Game netcode: receive error: {}", e);
+                continue;
+            }
+        };
+        let Some((sequence, datagrams)) = unpack(&buf[..n]) else {
+            log::debug!("Game netcode: ignoring malformed packet from {}", from);
+            continue;
+        };
+
+        {
+            // Fillers keep the acknowledgements current but not the peer alive
+            let mut peers = peers.lock().unwrap();
+            if datagrams.is_empty() {
+                if let Some(peer) = peers.get_mut(&from) {
+                    peer.acknowledge(sequence);
+                }
+            } else {
+                let peer = peers.entry(from).or_insert_with(Peer::new);
+                peer.acknowledge(sequence);
+                peer.last_data = Instant::now();
+            }
+        }
+        for datagram in datagrams {
+            match received.try_send((datagram.to_vec(), from)) {
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => log::debug!("Game netcode: receive queue full, dropping datagram"),
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// Sequence number and datagrams of `packet`
+///
+/// The peer's acknowledgements are not used: KCP retransmits on its own.
+fn unpack(packet: &[u8]) -> Option<(u32, Vec<&[u8]>)> {
+    let header = packet.get(..HEADER_SIZE)?;
+    let sequence = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let mut rest = &packet[HEADER_SIZE..];
+    let mut datagrams = Vec::with_capacity(header[HEADER_SIZE - 1] as usize);
+    for _ in 0..header[HEADER_SIZE - 1] {
+        let length = u16::from_le_bytes([*rest.first()?, *rest.get(1)?]) as usize;
+        datagrams.push(rest.get(2..2 + length)?);
+        rest = &rest[2 + length..];
+    }
+    Some((sequence, datagrams))
+}
+
+fn unsupported() -> webrtc::util::Error {
+    webrtc::util::Error::Other("Game netcode sockets are unconnected".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_packets_carry_datagrams_and_acks() {
+        let mut rng = rand::thread_rng();
+        let mut peer = Peer::new();
+        let filler = peer.packet(120, &mut rng);
+        assert_eq!(filler.len(), 120);
+        assert_eq!(unpack(&filler), Some((0, vec![])));
+
+        peer.queue.extend([vec![1u8; 1200], vec![2u8; 150], vec![3u8; 100]]);
+        let first = peer.packet(120, &mut rng);
+        assert_eq!(first.len(), HEADER_SIZE + 2 + 1200 + 2 + 150);
+        let second = peer.packet(400, &mut rng);
+        assert_eq!(second.len(), 400);
+        assert_eq!(unpack(&second), Some((2, vec![&[3u8; 100][..]])));
+        assert_eq!(unpack(&second[..HEADER_SIZE + 10]), None);
+
+        let mut receiver = Peer::new();
+        for sequence in [0, 1, 3, 2] {
+            receiver.acknowledge(sequence);
+        }
+        assert_eq!((receiver.ack, receiver.ack_bits), (3, 0b111));
+    }
+
+    #[tokio::test]
+    async fn test_kcp_in_game_ticks() {
+        let profile = ApplicationProfile::cs2();
+        let mut listener = bind("127.0.0.1:0".parse().unwrap(), &profile).await.unwrap();
+        let mut client = connect(listener.local_addr(), &profile, None).await.unwrap();
+
+        let data: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = vec![0u8; data.len()];
+        tokio::time::timeout(Duration::from_secs(10), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"snapshot").await.unwrap();
+        let mut buf = [0u8; 8];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"snapshot");
+    }
+}
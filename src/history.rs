@@ -0,0 +1,382 @@
+//! Persistent history of tunnel activity (feature `history`)
+//!
+//! An SQLite database of hourly transfer and handshake failures per
+//! protocol, protocol rotations and path-test results. The client samples
+//! the shape-shift controller's live counters into it with a
+//! [`HistoryRecorder`]; `nooshdaroo stats` reads it back as a
+//! [`HistorySummary`] so blocking patterns can be analyzed over days.
+
+use crate::counters::TrafficSnapshot;
+use crate::netflow_evasion::PathTestResult;
+use crate::protocol::ProtocolId;
+use crate::ProtocolStats;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use rusqlite::Error;
+pub type Result<T> = rusqlite::Result<T>;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS transfer (
+        hour INTEGER NOT NULL,
+        protocol TEXT NOT NULL,
+        connections INTEGER NOT NULL DEFAULT 0,
+        bytes_sent INTEGER NOT NULL DEFAULT 0,
+        bytes_received INTEGER NOT NULL DEFAULT 0,
+        packets INTEGER NOT NULL DEFAULT 0,
+        handshake_failures INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (hour, protocol)
+    );
+    CREATE TABLE IF NOT EXISTS rotations (
+        at INTEGER NOT NULL,
+        from_protocol TEXT NOT NULL,
+        to_protocol TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS rotations_at ON rotations (at);
+    CREATE TABLE IF NOT EXISTS path_tests (
+        at INTEGER NOT NULL,
+        protocol TEXT NOT NULL,
+        port INTEGER NOT NULL,
+        success INTEGER NOT NULL,
+        latency_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS path_tests_at ON path_tests (at);
+";
+
+/// Changes to databases created by older versions, in order: a database
+/// with `user_version` N still needs `MIGRATIONS[N..]`. [`SCHEMA`] already
+/// includes all of them.
+const MIGRATIONS: &[&str] = &[
+    // 1: connections per hour and protocol
+    "ALTER TABLE transfer ADD COLUMN connections INTEGER NOT NULL DEFAULT 0",
+];
+
+fn unix_secs(at: SystemTime) -> i64 {
+    at.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// Transfer through one protocol during one hour
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HourlyTransfer {
+    /// Start of the hour (Unix seconds)
+    pub hour: i64,
+    pub protocol: String,
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets: u64,
+    pub handshake_failures: u64,
+}
+
+/// A switch from one protocol to another
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RotationRecord {
+    /// Unix seconds
+    pub at: i64,
+    pub from: String,
+    pub to: String,
+}
+
+/// One probed protocol/port path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathTestRecord {
+    /// Unix seconds
+    pub at: i64,
+    pub protocol: String,
+    pub port: u16,
+    pub success: bool,
+    pub latency_ms: u64,
+}
+
+/// Everything recorded since a point in time, oldest first
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistorySummary {
+    pub transfer: Vec<HourlyTransfer>,
+    pub rotations: Vec<RotationRecord>,
+    pub path_tests: Vec<PathTestRecord>,
+}
+
+/// SQLite-backed history database
+pub struct HistoryStore {
+    conn: Mutex<Connection>,
+}
+
+impl HistoryStore {
+    /// Open (creating if needed) the database at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// A throwaway database, for tests and dry runs
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self> {
+        let tx = conn.transaction()?;
+        let existing: bool =
+            tx.query_row("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'transfer')", [], |row| row.get(0))?;
+        let version: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if existing {
+            for migration in MIGRATIONS.iter().skip(version) {
+                tx.execute_batch(migration)?;
+            }
+        }
+        tx.execute_batch(SCHEMA)?;
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        tx.commit()?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// Add `delta` to the transfer of `protocol` in the hour containing `at`
+    pub fn record_transfer(&self, at: SystemTime, protocol: &ProtocolId, delta: &TrafficSnapshot) -> Result<()> {
+        let hour = unix_secs(at) / 3600 * 3600;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO transfer (hour, protocol, connections, bytes_sent, bytes_received, packets, handshake_failures)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (hour, protocol) DO UPDATE SET
+                 connections = connections + excluded.connections,
+                 bytes_sent = bytes_sent + excluded.bytes_sent,
+                 bytes_received = bytes_received + excluded.bytes_received,
+                 packets = packets + excluded.packets,
+                 handshake_failures = handshake_failures + excluded.handshake_failures",
+            params![
+                hour,
+                protocol.as_str(),
+                delta.connections as i64,
+                delta.bytes_sent as i64,
+                delta.bytes_received as i64,
+                delta.packets() as i64,
+                delta.handshake_failures as i64,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Record a protocol rotation
+    pub fn record_rotation(&self, at: SystemTime, from: &ProtocolId, to: &ProtocolId) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO rotations (at, from_protocol, to_protocol) VALUES (?1, ?2, ?3)",
+            params![unix_secs(at), from.as_str(), to.as_str()],
+        )?;
+        Ok(())
+    }
+
+    /// Record the results of a path test run
+    pub fn record_path_tests(&self, at: SystemTime, results: &[PathTestResult]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for result in results {
+            tx.execute(
+                "INSERT INTO path_tests (at, protocol, port, success, latency_ms) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    unix_secs(at),
+                    result.protocol.as_str(),
+                    result.addr.port(),
+                    result.success,
+                    result.latency.as_millis() as i64,
+                ],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Everything recorded at or after `since` (transfer by whole hours)
+    pub fn summary(&self, since: SystemTime) -> Result<HistorySummary> {
+        let since = unix_secs(since);
+        let conn = self.conn.lock().unwrap();
+
+        let transfer = conn
+            .prepare(
+                "SELECT hour, protocol, connections, bytes_sent, bytes_received, packets, handshake_failures
+                 FROM transfer WHERE hour >= ?1 / 3600 * 3600 ORDER BY hour, protocol",
+            )?
+            .query_map([since], |row| {
+                Ok(HourlyTransfer {
+                    hour: row.get(0)?,
+                    protocol: row.get(1)?,
+                    connections: row.get::<_, i64>(2)? as u64,
+                    bytes_sent: row.get::<_, i64>(3)? as u64,
+                    bytes_received: row.get::<_, i64>(4)? as u64,
+                    packets: row.get::<_, i64>(5)? as u64,
+                    handshake_failures: row.get::<_, i64>(6)? as u64,
+                })
+            })?
+            .collect::<Result<_>>()?;
+
+        let rotations = conn
+            .prepare("SELECT at, from_protocol, to_protocol FROM rotations WHERE at >= ?1 ORDER BY at")?
+            .query_map([since], |row| {
+                Ok(RotationRecord { at: row.get(0)?, from: row.get(1)?, to: row.get(2)? })
+            })?
+            .collect::<Result<_>>()?;
+
+        let path_tests = conn
+            .prepare(
+                "SELECT at, protocol, port, success, latency_ms FROM path_tests
+                 WHERE at >= ?1 ORDER BY at, protocol, port",
+            )?
+            .query_map([since], |row| {
+                Ok(PathTestRecord {
+                    at: row.get(0)?,
+                    protocol: row.get(1)?,
+                    port: row.get(2)?,
+                    success: row.get(3)?,
+                    latency_ms: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<Result<_>>()?;
+
+        Ok(HistorySummary { transfer, rotations, path_tests })
+    }
+
+    /// Delete records older than `before`, returning how many were removed
+    pub fn prune(&self, before: SystemTime) -> Result<usize> {
+        let before = unix_secs(before);
+        let conn = self.conn.lock().unwrap();
+        let mut removed = conn.execute("DELETE FROM transfer WHERE hour + 3600 <= ?1", [before])?;
+        removed += conn.execute("DELETE FROM rotations WHERE at < ?1", [before])?;
+        removed += conn.execute("DELETE FROM path_tests WHERE at < ?1", [before])?;
+        Ok(removed)
+    }
+}
+
+/// Writes changes in the controller's live statistics to a [`HistoryStore`]
+pub struct HistoryRecorder {
+    last_traffic: HashMap<ProtocolId, TrafficSnapshot>,
+    last_protocol: Option<ProtocolId>,
+    last_switches: u64,
+}
+
+impl HistoryRecorder {
+    pub fn new() -> Self {
+        Self {
+            last_traffic: HashMap::new(),
+            last_protocol: None,
+            last_switches: 0,
+        }
+    }
+
+    /// Record what changed in `stats` since the previous sample
+    pub fn sample(&mut self, store: &HistoryStore, stats: &ProtocolStats, at: SystemTime) -> Result<()> {
+        for (protocol, traffic) in &stats.protocol_traffic {
+            let previous = self.last_traffic.get(protocol).copied().unwrap_or_default();
+            let delta = traffic.since(&previous);
+            if delta != TrafficSnapshot::default() {
+                store.record_transfer(at, protocol, &delta)?;
+            }
+            self.last_traffic.insert(protocol.clone(), *traffic);
+        }
+
+        if let Some(ref previous) = self.last_protocol {
+            if stats.total_switches > self.last_switches && *previous != stats.current_protocol {
+                store.record_rotation(at, previous, &stats.current_protocol)?;
+            }
+        }
+        self.last_protocol = Some(stats.current_protocol.clone());
+        self.last_switches = stats.total_switches;
+        Ok(())
+    }
+}
+
+impl Default for HistoryRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::counters::TrafficRegistry;
+    use std::time::Duration;
+
+    #[test]
+    fn test_history_recording() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let registry = TrafficRegistry::default();
+        let https = ProtocolId::from("https");
+        let ssh = ProtocolId::from("ssh");
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut stats = ProtocolStats { current_protocol: https.clone(), ..Default::default() };
+        let mut recorder = HistoryRecorder::new();
+        let counters = registry.connection(&https);
+
+        counters.record_sent(100);
+        counters.record_received(1000);
+        stats.protocol_traffic = registry.by_protocol();
+        recorder.sample(&store, &stats, start).unwrap();
+
+        // Same hour: accumulates. Rotation and a failure on the new protocol.
+        counters.record_received(500);
+        registry.protocol(&ssh).record_handshake_failure();
+        stats.protocol_traffic = registry.by_protocol();
+        stats.current_protocol = ssh.clone();
+        stats.total_switches = 1;
+        recorder.sample(&store, &stats, start + Duration::from_secs(60)).unwrap();
+
+        store
+            .record_path_tests(
+                start + Duration::from_secs(120),
+                &[PathTestResult {
+                    addr: "192.0.2.1:443".parse().unwrap(),
+                    protocol: https.clone(),
+                    latency: Duration::from_millis(42),
+                    success: true,
+                    packet_loss: 0.0,
+                    throughput: 0,
+                    detection_risk: 0.1,
+                }],
+            )
+            .unwrap();
+
+        let summary = store.summary(start).unwrap();
+        assert_eq!(summary.transfer.len(), 2);
+        let https_hour = summary.transfer.iter().find(|t| t.protocol == "https").unwrap();
+        assert_eq!(https_hour.connections, 1);
+        assert_eq!((https_hour.bytes_sent, https_hour.bytes_received, https_hour.packets), (100, 1500, 3));
+        let ssh_hour = summary.transfer.iter().find(|t| t.protocol == "ssh").unwrap();
+        assert_eq!(ssh_hour.handshake_failures, 1);
+        assert_eq!(summary.rotations, vec![RotationRecord { at: 1_700_000_060, from: "https".into(), to: "ssh".into() }]);
+        assert_eq!(summary.path_tests[0].latency_ms, 42);
+
+        assert!(store.summary(start + Duration::from_secs(7200)).unwrap().transfer.is_empty());
+        assert_eq!(store.prune(start + Duration::from_secs(7200)).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_migrates_old_databases() {
+        // transfer as created before it counted connections
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE transfer (
+                 hour INTEGER NOT NULL,
+                 protocol TEXT NOT NULL,
+                 bytes_sent INTEGER NOT NULL DEFAULT 0,
+                 bytes_received INTEGER NOT NULL DEFAULT 0,
+                 packets INTEGER NOT NULL DEFAULT 0,
+                 handshake_failures INTEGER NOT NULL DEFAULT 0,
+                 PRIMARY KEY (hour, protocol)
+             );
+             INSERT INTO transfer (hour, protocol, bytes_sent) VALUES (1699999200, 'https', 10);",
+        )
+        .unwrap();
+
+        let store = HistoryStore::init(conn).unwrap();
+        let delta = TrafficSnapshot { connections: 2, bytes_sent: 5, ..Default::default() };
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        store.record_transfer(at, &ProtocolId::from("https"), &delta).unwrap();
+        let transfer = &store.summary(at).unwrap().transfer[0];
+        assert_eq!((transfer.connections, transfer.bytes_sent), (2, 15));
+
+        // Reopening a current database changes nothing
+        let conn = store.conn.into_inner().unwrap();
+        let store = HistoryStore::init(conn).unwrap();
+        assert_eq!(store.summary(at).unwrap().transfer[0].connections, 2);
+    }
+}
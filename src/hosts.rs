@@ -0,0 +1,93 @@
+//! Static host mapping
+//!
+//! Names in `[hosts]` resolve to fixed addresses before any remote
+//! resolution: the client's own lookups (server host names, path test
+//! targets) skip DoH and the system resolver for them, and SOCKS5 requests
+//! for them reach the server with the mapped address instead of the name.
+//! This reaches servers whose names are poisoned, and serves split-horizon
+//! setups where a name means something else inside the tunnel.
+//!
+//! ```toml
+//! [hosts]
+//! file = "/etc/nooshdaroo/hosts"
+//! entries = { "vpn.example.org" = "203.0.113.7", "intranet.corp" = "10.1.2.3" }
+//! ```
+//!
+//! `file` uses the `/etc/hosts` format (`address name [alias...]`, `#`
+//! comments) and is read again for every lookup, so edits apply without a
+//! restart. `entries` take precedence over the file.
+
+use crate::config::HostsConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Addresses of `host` from `[hosts]`, if it is mapped
+pub fn lookup(config: &HostsConfig, host: &str) -> Option<Vec<IpAddr>> {
+    if config.is_empty() {
+        return None;
+    }
+    let name = normalize(host);
+    if let Some((_, ip)) = config.entries.iter().find(|(entry, _)| normalize(entry) == name) {
+        return Some(vec![*ip]);
+    }
+    let path = config.file.as_ref()?;
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            log::warn!("Cannot read hosts file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    parse(&text).remove(&name)
+}
+
+/// Addresses of the names in a hosts file, in file order
+pub fn parse(text: &str) -> HashMap<String, Vec<IpAddr>> {
+    let mut names: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(ip) = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+            continue;
+        };
+        for name in fields {
+            let addrs = names.entry(normalize(name)).or_default();
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    names
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts_lookup() {
+        let path = std::env::temp_dir().join(format!("nooshdaroo-hosts-{}", rand::random::<u32>()));
+        std::fs::write(
+            &path,
+            "# split horizon\n203.0.113.7  vpn.example.org vpn\n2001:db8::7 vpn.example.org # v6\nbogus line\n",
+        )
+        .unwrap();
+        let mut config = HostsConfig { file: Some(path.clone()), ..Default::default() };
+        assert_eq!(
+            lookup(&config, "VPN.example.org.").unwrap(),
+            vec!["203.0.113.7".parse::<IpAddr>().unwrap(), "2001:db8::7".parse().unwrap()]
+        );
+        assert_eq!(lookup(&config, "vpn").unwrap(), vec!["203.0.113.7".parse::<IpAddr>().unwrap()]);
+        assert_eq!(lookup(&config, "other.example.org"), None);
+
+        // Entries win over the file
+        config.entries.insert("vpn.example.org".to_string(), "192.0.2.1".parse().unwrap());
+        assert_eq!(lookup(&config, "vpn.example.org").unwrap(), vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(lookup(&HostsConfig::default(), "vpn.example.org"), None);
+    }
+}
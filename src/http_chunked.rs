@@ -0,0 +1,376 @@
+//! Tunnel stream carried in chunked HTTP/1.1 bodies
+//!
+//! For middleboxes that parse and validate HTTP rather than match a few
+//! header bytes, tunnels can run as well-formed HTTP/1.1 on a long-lived
+//! keep-alive connection. The client streams its side as the chunked body
+//! of a `POST`; the server answers with a `200 OK` whose chunked body
+//! carries the other direction while the request is still being sent.
+//!
+//! ```toml
+//! [socks]
+//! transport = "http-chunked"
+//! http_host = "upload.example.com"
+//!
+//! [server]
+//! transport = "http-chunked"
+//! ```
+//!
+//! Every few megabytes or minutes the client finishes its request and
+//! starts another, on a fresh path, the way an uploader splits a file; the
+//! server finishes the matching response and opens the next one when the
+//! new request arrives. Noise and protocol wrapping run on top unchanged.
+
+use rand::Rng;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::sync::mpsc;
+
+/// Buffer between the tunnel and the HTTP framing tasks
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest request or response head accepted
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Largest chunk accepted
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Request body size after which the client starts a new request
+const REQUEST_BYTES: std::ops::Range<usize> = 1024 * 1024..8 * 1024 * 1024;
+
+/// Request age after which the client starts a new request
+const REQUEST_AGE: std::ops::Range<u64> = 30..180;
+
+const USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+
+const UPLOAD_PATHS: &[&str] = &["/upload", "/api/v1/sync", "/api/v2/files/chunk", "/sync/stream", "/backup/part"];
+
+const CONTENT_TYPES: &[&str] = &["application/octet-stream", "application/x-protobuf", "application/zip"];
+
+/// Start the client side over `stream`; the returned stream carries the tunnel
+pub async fn connect<S>(stream: S, host: &str) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    let (app_read, app_write) = tokio::io::split(local);
+    let (net_read, net_write) = tokio::io::split(stream);
+
+    let host = host.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = send_requests(app_read, net_write, &host).await {
+            log::debug!("HTTP upload stream ended: {}", e);
+        }
+    });
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(net_read);
+        if let Err(e) = receive_bodies(&mut reader, app_write, |head| check_head(head, "HTTP/1.1 200"), None).await {
+            log::debug!("HTTP download stream ended: {}", e);
+        }
+    });
+    Ok(tunnel)
+}
+
+/// Start the server side over an accepted `stream`; the returned stream
+/// carries the tunnel
+pub async fn accept<S>(stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    let (app_read, app_write) = tokio::io::split(local);
+    let (net_read, net_write) = tokio::io::split(stream);
+
+    // Request boundaries, so each response ends with its request
+    let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(net_read);
+        if let Err(e) = receive_bodies(&mut reader, app_write, |head| check_head(head, "POST "), Some(requests_tx)).await {
+            log::debug!("HTTP request stream ended: {}", e);
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = send_responses(app_read, net_write, requests_rx).await {
+            log::debug!("HTTP response stream ended: {}", e);
+        }
+    });
+    Ok(tunnel)
+}
+
+/// Request boundary seen by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Boundary {
+    Started,
+    Ended,
+}
+
+async fn send_requests<R, W>(mut app: R, mut net: W, host: &str) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let (budget, age) = {
+            let mut rng = rand::thread_rng();
+            (rng.gen_range(REQUEST_BYTES), Duration::from_secs(rng.gen_range(REQUEST_AGE)))
+        };
+        net.write_all(request_head(host).as_bytes()).await?;
+        net.flush().await?;
+
+        let deadline = tokio::time::sleep(age);
+        tokio::pin!(deadline);
+        let mut sent = 0;
+        while sent < budget {
+            let n = tokio::select! {
+                n = app.read(&mut buf) => n?,
+                _ = &mut deadline => break,
+            };
+            if n == 0 {
+                net.write_all(b"0\r\n\r\n").await?;
+                return net.shutdown().await;
+            }
+            write_chunk(&mut net, &buf[..n]).await?;
+            sent += n;
+        }
+        net.write_all(b"0\r\n\r\n").await?;
+    }
+}
+
+async fn send_responses<R, W>(mut app: R, mut net: W, mut requests: mpsc::UnboundedReceiver<Boundary>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        // A response only goes out once its request has
+        match requests.recv().await {
+            Some(Boundary::Started) => {}
+            Some(Boundary::Ended) => continue,
+            None => return net.shutdown().await,
+        }
+        net.write_all(response_head().as_bytes()).await?;
+        net.flush().await?;
+
+        loop {
+            tokio::select! {
+                n = app.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        net.write_all(b"0\r\n\r\n").await?;
+                        return net.shutdown().await;
+                    }
+                    write_chunk(&mut net, &buf[..n]).await?;
+                }
+                boundary = requests.recv() => {
+                    if boundary != Some(Boundary::Started) {
+                        break;
+                    }
+                }
+            }
+        }
+        net.write_all(b"0\r\n\r\n").await?;
+        net.flush().await?;
+    }
+}
+
+/// Parse messages off `net`, validating each head with `check` and writing
+/// their bodies to `app`
+async fn receive_bodies<R, W, F>(
+    net: &mut R,
+    mut app: W,
+    check: F,
+    boundaries: Option<mpsc::UnboundedSender<Boundary>>,
+) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(&str) -> io::Result<()>,
+{
+    let notify = |boundary| {
+        if let Some(ref tx) = boundaries {
+            let _ = tx.send(boundary);
+        }
+    };
+    loop {
+        let Some(head) = read_head(net).await? else {
+            return app.shutdown().await;
+        };
+        check(&head)?;
+        notify(Boundary::Started);
+
+        loop {
+            let size_line = read_line(net).await?;
+            let size = size_line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| invalid(format!("Bad chunk size line {:?}", size_line)))?;
+            if size > MAX_CHUNK_SIZE {
+                return Err(invalid(format!("Chunk too large: {}", size)));
+            }
+            if size == 0 {
+                // Trailers, up to the blank line
+                while !read_line(net).await?.is_empty() {}
+                break;
+            }
+            let mut chunk = vec![0u8; size];
+            net.read_exact(&mut chunk).await?;
+            if !read_line(net).await?.is_empty() {
+                return Err(invalid("Chunk not followed by CRLF".to_string()));
+            }
+            app.write_all(&chunk).await?;
+        }
+        notify(Boundary::Ended);
+    }
+}
+
+fn request_head(host: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let path = UPLOAD_PATHS[rng.gen_range(0..UPLOAD_PATHS.len())];
+    let content_type = CONTENT_TYPES[rng.gen_range(0..CONTENT_TYPES.len())];
+    format!(
+        "POST {}?id={:016x} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: {}\r\n\
+         Accept: */*\r\n\
+         Accept-Encoding: gzip, deflate, br\r\n\
+         Content-Type: {}\r\n\
+         Transfer-Encoding: chunked\r\n\
+         Connection: keep-alive\r\n\r\n",
+        path,
+        rng.gen::<u64>(),
+        host,
+        USER_AGENT,
+        content_type
+    )
+}
+
+fn response_head() -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         Server: nginx\r\n\
+         Date: {}\r\n\
+         Content-Type: application/octet-stream\r\n\
+         Transfer-Encoding: chunked\r\n\
+         Connection: keep-alive\r\n\
+         Cache-Control: no-store\r\n\r\n",
+        chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT")
+    )
+}
+
+async fn write_chunk<W>(net: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    net.write_all(&chunk).await?;
+    net.flush().await
+}
+
+/// Start line and headers up to the blank line, or `None` at a clean end of stream
+async fn read_head<R>(net: &mut R) -> io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = String::new();
+    loop {
+        let line = match read_line(net).await {
+            Ok(line) => line,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && head.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if line.is_empty() {
+            return Ok(Some(head));
+        }
+        head.push_str(&line);
+        head.push('\n');
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(invalid("HTTP head too large".to_string()));
+        }
+    }
+}
+
+/// One CRLF-terminated line, without the terminator
+async fn read_line<R>(net: &mut R) -> io::Result<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *net).take(MAX_HEAD_SIZE as u64).read_until(b'\n', &mut line).await?;
+    if line.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with(b"\r\n") {
+        return Err(invalid("HTTP line not terminated by CRLF".to_string()));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| invalid("HTTP line is not UTF-8".to_string()))
+}
+
+/// Check a head starts with `start` and has a chunked body
+fn check_head(head: &str, start: &str) -> io::Result<()> {
+    if !head.starts_with(start) {
+        return Err(invalid(format!("Unexpected HTTP start line {:?}", head.lines().next().unwrap_or(""))));
+    }
+    let chunked = head.lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    if !chunked {
+        return Err(invalid("HTTP body is not chunked".to_string()));
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chunked_round_trip() {
+        let (client_io, server_io) = tokio::io::duplex(BUFFER_SIZE);
+        let mut client = connect(client_io, "upload.example.com").await.unwrap();
+        let mut server = accept(server_io).await.unwrap();
+
+        client.write_all(b"hello over http").await.unwrap();
+        let mut buf = [0u8; 15];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello over http");
+
+        server.write_all(b"and back").await.unwrap();
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"and back");
+    }
+
+    #[tokio::test]
+    async fn test_bodies_across_requests() {
+        // Two pipelined requests, one with a chunk extension and a trailer
+        let wire = b"POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     5\r\nhello\r\n0\r\n\r\n\
+                     POST /sync HTTP/1.1\r\nHost: a\r\ntransfer-encoding: Chunked\r\n\r\n\
+                     6;x=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut body = Vec::new();
+        receive_bodies(&mut &wire[..], &mut body, |head| check_head(head, "POST "), Some(tx)).await.unwrap();
+        assert_eq!(body, b"hello world");
+        let mut boundaries = Vec::new();
+        while let Ok(boundary) = rx.try_recv() {
+            boundaries.push(boundary);
+        }
+        assert_eq!(boundaries, [Boundary::Started, Boundary::Ended, Boundary::Started, Boundary::Ended]);
+
+        let unchunked = b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let result = receive_bodies(&mut &unchunked[..], Vec::new(), |head| check_head(head, "POST "), None).await;
+        assert!(result.is_err());
+    }
+}
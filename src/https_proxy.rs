@@ -0,0 +1,395 @@
+//! HTTPS proxy listener
+//!
+//! Browsers' "secure proxy" setting and devices elsewhere on the LAN reach
+//! the client over TLS instead of plaintext SOCKS5, so proxy credentials
+//! and destinations are not visible on the local network. The listener
+//! accepts HTTP CONNECT over TLS and hands every request to the client's
+//! own SOCKS5 listener, so it takes the same tunnel path, access rules and
+//! failover as SOCKS5 connections.
+//!
+//! There is no async TLS crate in the tree, so [`TlsStream`] drives a
+//! rustls server connection over any tokio stream itself.
+
+use crate::config::HttpsProxyConfig;
+use anyhow::{Context as _, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest CONNECT request head accepted
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Server side of a TLS connection over `S`
+pub struct TlsStream<S> {
+    io: S,
+    conn: rustls::ServerConnection,
+    closed: bool,
+}
+
+/// Blocking-style view of an async stream for rustls, reporting
+/// `WouldBlock` while the stream is not ready
+struct SyncAdapter<'a, 'b, S> {
+    io: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+impl<S: AsyncRead + Unpin> Read for SyncAdapter<'_, '_, S> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(out);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for SyncAdapter<'_, '_, S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, data) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+fn would_block<T>(result: io::Result<T>) -> Poll<io::Result<T>> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> TlsStream<S> {
+    /// Complete the server side of a TLS handshake on `io`
+    pub async fn accept(io: S, config: Arc<rustls::ServerConfig>) -> io::Result<Self> {
+        let conn = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+        let mut stream = Self { io, conn, closed: false };
+        while stream.conn.is_handshaking() {
+            if stream.conn.wants_write() {
+                std::future::poll_fn(|cx| stream.poll_write_tls(cx)).await?;
+            } else if std::future::poll_fn(|cx| stream.poll_read_tls(cx)).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed during TLS handshake"));
+            }
+        }
+        while stream.conn.wants_write() {
+            std::future::poll_fn(|cx| stream.poll_write_tls(cx)).await?;
+        }
+        Ok(stream)
+    }
+
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let n = ready!(would_block(self.conn.read_tls(&mut SyncAdapter { io: &mut self.io, cx })))?;
+        if let Err(e) = self.conn.process_new_packets() {
+            // Best effort to tell the peer why
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        match ready!(would_block(self.conn.write_tls(&mut SyncAdapter { io: &mut self.io, cx })))? {
+            0 => Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+            n => Poll::Ready(Ok(n)),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // Closed without close_notify, as many clients do
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Poll::Ready(Ok(())),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            while this.conn.wants_write() {
+                match this.poll_write_tls(cx) {
+                    Poll::Ready(result) => result.map(drop)?,
+                    Poll::Pending => break,
+                }
+            }
+            ready!(this.poll_read_tls(cx))?;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let n = this.conn.writer().write(data)?;
+            while this.conn.wants_write() {
+                match this.poll_write_tls(cx) {
+                    Poll::Ready(result) => result.map(drop)?,
+                    Poll::Pending if n > 0 => return Poll::Ready(Ok(n)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            if n > 0 || data.is_empty() {
+                return Poll::Ready(Ok(n));
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        while this.conn.wants_write() {
+            ready!(this.poll_write_tls(cx))?;
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            self.conn.send_close_notify();
+            self.closed = true;
+        }
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+/// TLS settings of the listener, generating its certificate and key first
+/// if `cert` does not exist yet
+pub fn server_config(https: &HttpsProxyConfig) -> Result<Arc<rustls::ServerConfig>> {
+    if !https.cert.exists() {
+        let generated = rcgen::generate_simple_self_signed(https.hostnames.clone())?;
+        std::fs::write(&https.key, generated.key_pair.serialize_pem())
+            .with_context(|| format!("Failed to write {}", https.key.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&https.key, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::write(&https.cert, generated.cert.pem())
+            .with_context(|| format!("Failed to write {}", https.cert.display()))?;
+        log::info!("Generated a certificate for {} in {}", https.hostnames.join(", "), https.cert.display());
+    }
+    let cert = std::fs::read(&https.cert).with_context(|| format!("Failed to read {}", https.cert.display()))?;
+    let key = std::fs::read(&https.key).with_context(|| format!("Failed to read {}", https.key.display()))?;
+    let chain = CertificateDer::pem_slice_iter(&cert)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {}", https.cert.display()))?;
+    let key = PrivateKeyDer::from_pem_slice(&key).with_context(|| format!("Invalid private key in {}", https.key.display()))?;
+    if let Some(leaf) = chain.first() {
+        log::info!("HTTPS proxy certificate SHA-256 fingerprint: {}", fingerprint(leaf));
+    }
+    let config = rustls::ServerConfig::builder().with_no_client_auth().with_single_cert(chain, key)?;
+    Ok(Arc::new(config))
+}
+
+/// SHA-256 fingerprint of a certificate, as browsers show it
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    digest.as_ref().iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Accept HTTP CONNECT over TLS on `[socks.https]` and carry each request
+/// through the SOCKS5 listener at `socks`; `credentials` are the user name
+/// and password clients must present
+pub async fn serve(https: &HttpsProxyConfig, socks: SocketAddr, credentials: Option<(String, String)>) -> Result<()> {
+    let tls = server_config(https)?;
+    let listener = TcpListener::bind(https.listen)
+        .await
+        .with_context(|| format!("Failed to bind the HTTPS proxy on {}", https.listen))?;
+    log::info!("HTTPS proxy listening on {}", https.listen);
+    let credentials = Arc::new(credentials);
+    loop {
+        let (socket, peer_addr) = listener.accept().await?;
+        let (tls, credentials) = (Arc::clone(&tls), Arc::clone(&credentials));
+        tokio::spawn(async move {
+            if let Err(e) = handle(socket, tls, socks, &credentials).await {
+                log::debug!("HTTPS proxy connection from {} failed: {}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    socket: TcpStream,
+    tls: Arc<rustls::ServerConfig>,
+    socks: SocketAddr,
+    credentials: &Option<(String, String)>,
+) -> Result<()> {
+    socket.set_nodelay(true)?;
+    let mut client = TlsStream::accept(socket, tls).await?;
+
+    let mut request = Vec::new();
+    let head_len = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            client.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n").await?;
+            anyhow::bail!("Request head too large");
+        }
+        let mut chunk = [0u8; 1024];
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&request[..head_len]).into_owned();
+
+    if let Some((username, password)) = credentials {
+        if !authorized(&head, username, password) {
+            client
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"nooshdaroo\"\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            anyhow::bail!("Missing or wrong proxy credentials");
+        }
+    }
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let authority = match (request_line.next(), request_line.next()) {
+        (Some("CONNECT"), Some(authority)) => authority.to_string(),
+        (method, _) => {
+            client.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\n\r\n").await?;
+            anyhow::bail!("Unsupported method {}", method.unwrap_or_default());
+        }
+    };
+    let (host, port) = crate::acl::split_target(&authority)?;
+
+    let mut upstream = match connect(socks, host, port).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await?;
+            return Err(e.context(format!("Failed to reach {}", authority)));
+        }
+    };
+    client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+    log::info!("HTTPS proxy CONNECT to {}", authority);
+
+    // Anything the client sent after the request head
+    upstream.write_all(&request[head_len..]).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Connect to `host:port` through the SOCKS5 listener at `socks`
+async fn connect(socks: SocketAddr, host: &str, port: u16) -> Result<TcpStream> {
+    let mut upstream = TcpStream::connect(socks).await?;
+    upstream.set_nodelay(true)?;
+    crate::socks5::client_connect(&mut upstream, host, port, None).await?;
+    Ok(upstream)
+}
+
+/// Whether the request head carries Basic proxy credentials for
+/// `username` and `password`
+fn authorized(head: &str, username: &str, password: &str) -> bool {
+    let expected = STANDARD.encode(format!("{}:{}", username, password));
+    head.lines().any(|line| match line.split_once(':') {
+        Some((name, value)) if name.trim().eq_ignore_ascii_case("proxy-authorization") => {
+            let mut value = value.split_whitespace();
+            matches!((value.next(), value.next()), (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("basic") && token == expected)
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::{send_reply, socks5_handshake, ReplyCode};
+
+    /// Send `request` over TLS the way a browser would, returning the
+    /// response to it and the reply to `ping`
+    fn browse(addr: SocketAddr, cert: &std::path::Path, request: &str) -> (String, Vec<u8>) {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(&std::fs::read(cert).unwrap()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let conn = rustls::ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, std::net::TcpStream::connect(addr).unwrap());
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        if !response.starts_with("HTTP/1.1 200") {
+            return (response, Vec::new());
+        }
+        stream.write_all(b"ping").unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).unwrap();
+        (response, echo.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_https_connect() {
+        // Stands in for the client's SOCKS5 listener, echoing what it carries
+        let socks = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = socks.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = socks.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (_, target) = socks5_handshake(&mut socket).await.unwrap();
+                    assert_eq!((target.host.as_str(), target.port), ("example.com", 443));
+                    send_reply(&mut socket, ReplyCode::Succeeded, &target).await.unwrap();
+                    let (mut reader, mut writer) = socket.split();
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                });
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("nooshdaroo-https-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let probe = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let https = HttpsProxyConfig {
+            listen: probe.local_addr().unwrap(),
+            cert: dir.join("proxy.crt"),
+            key: dir.join("proxy.key"),
+            hostnames: vec!["localhost".to_string()],
+        };
+        drop(probe);
+        let credentials = Some(("alice".to_string(), "secret".to_string()));
+        let server = https.clone();
+        tokio::spawn(async move { serve(&server, socks_addr, credentials).await });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let (addr, cert) = (https.listen, https.cert.clone());
+        let (denied, (response, echo)) = tokio::task::spawn_blocking(move || {
+            let denied = browse(addr, &cert, "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n");
+            let auth = STANDARD.encode("alice:secret");
+            let request = format!("CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic {}\r\n\r\n", auth);
+            (denied, browse(addr, &cert, &request))
+        })
+        .await
+        .unwrap();
+        assert!(denied.0.starts_with("HTTP/1.1 407"));
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(echo, b"ping");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,137 @@
+//! Per-client resource isolation on the server
+//!
+//! A server shares its sockets, memory and tasks among every client, so a
+//! single misbehaving or malicious one could exhaust them for all. Two caps
+//! from `[relay]` keep each client to its share:
+//!
+//! - `max_sessions_per_client`: tunnels and DNS sessions one client IP
+//!   address may hold open; further connections are closed and queries
+//!   opening further DNS sessions dropped, counted by [`ClientSessions`]
+//! - `session_buffer`: bytes of queries a DNS session may have waiting for
+//!   its handler, reserved from its [`SessionBudget`]; a session whose
+//!   target is slow stops at its budget instead of piling up queries
+//!
+//! DNS sessions are also locked one by one rather than through the session
+//! map, so a session waiting on its target holds up only its own queries.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sessions each client address holds open
+#[derive(Debug)]
+pub struct ClientSessions {
+    /// 0 = unlimited
+    limit: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ClientSessions {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self { limit, open: Mutex::new(HashMap::new()) })
+    }
+
+    /// Open a session for `client`, or `None` when it is at the limit
+    pub fn try_open(self: &Arc<Self>, client: IpAddr) -> Option<ClientSession> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(client).or_insert(0);
+        if self.limit > 0 && *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(ClientSession { sessions: Arc::clone(self), client })
+    }
+
+    /// Sessions `client` holds open
+    pub fn open(&self, client: IpAddr) -> usize {
+        self.open.lock().unwrap().get(&client).copied().unwrap_or(0)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// A session counted against its client until dropped
+#[derive(Debug)]
+pub struct ClientSession {
+    sessions: Arc<ClientSessions>,
+    client: IpAddr,
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        let mut open = self.sessions.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// Bytes a session may have buffered, reserved per query
+#[derive(Debug, Clone)]
+pub struct SessionBudget(Arc<Semaphore>);
+
+impl SessionBudget {
+    pub fn new(bytes: usize) -> Self {
+        Self(Arc::new(Semaphore::new(bytes.min(Semaphore::MAX_PERMITS))))
+    }
+
+    /// Reserve `bytes` until the returned permit is dropped, or `None` when
+    /// they would exceed the budget
+    pub fn try_reserve(&self, bytes: usize) -> Option<OwnedSemaphorePermit> {
+        let bytes = u32::try_from(bytes.max(1)).ok()?;
+        Arc::clone(&self.0).try_acquire_many_owned(bytes).ok()
+    }
+
+    /// Bytes not reserved
+    pub fn available(&self) -> usize {
+        self.0.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_session_limit() {
+        let sessions = ClientSessions::new(2);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = sessions.try_open(client).unwrap();
+        let _second = sessions.try_open(client).unwrap();
+        assert!(sessions.try_open(client).is_none());
+        // Other clients are unaffected
+        assert!(sessions.try_open(other).is_some());
+        assert_eq!(sessions.open(client), 2);
+
+        drop(first);
+        assert_eq!(sessions.open(client), 1);
+        assert!(sessions.try_open(client).is_some());
+
+        let unlimited = ClientSessions::new(0);
+        let held: Vec<_> = (0..100).map(|_| unlimited.try_open(client).unwrap()).collect();
+        assert_eq!(unlimited.open(client), 100);
+        drop(held);
+        assert_eq!(unlimited.open(client), 0);
+    }
+
+    #[test]
+    fn test_session_budget() {
+        let budget = SessionBudget::new(1000);
+        let first = budget.try_reserve(600).unwrap();
+        assert!(budget.try_reserve(600).is_none());
+        let second = budget.try_reserve(400).unwrap();
+        assert_eq!(budget.available(), 0);
+        drop(first);
+        assert_eq!(budget.available(), 600);
+        drop(second);
+        assert!(budget.try_reserve(2000).is_none());
+    }
+}
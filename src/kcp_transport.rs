@@ -0,0 +1,402 @@
+//! Reliable stream transport over plain UDP
+//!
+//! For networks that throttle or reset TCP but let UDP through, tunnels can
+//! run over KCP on a bare UDP socket instead of a TCP connection. KCP gives
+//! ordered, reliable delivery with fast retransmission and no congestion
+//! backoff, so latency stays low on lossy paths. The Noise handshake and
+//! protocol wrapping run on top exactly as they do over TCP; unlike
+//! `dns-udp-tunnel` there is no DNS encapsulation underneath.
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//!
+//! [server]
+//! transport = "kcp"
+//! ```
+//!
+//! Every datagram is a KCP segment of the session's conversation id, picked
+//! at random by the client; the server tells sessions apart by source
+//! address and conversation. Datagrams shorter than a KCP header are
+//! control messages, `conversation (4, LE) | kind (1)`, closing the session
+//! once all data has been acknowledged or keeping an idle one alive.
+//! Clients can also reach the server through a TURN relay (see
+//! [`crate::turn_relay`]).
+
+use kcp::Kcp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use webrtc::util::Conn;
+
+/// Largest datagram sent
+pub const KCP_MTU: usize = 1200;
+
+/// KCP segment header; shorter datagrams are control messages
+const KCP_HEADER_SIZE: usize = 24;
+
+/// KCP command carrying data
+const KCP_CMD_PUSH: u8 = 81;
+
+/// Control message: the sender has sent everything and is gone
+const CONTROL_CLOSE: u8 = 1;
+
+/// Control message: the sender is alive but has nothing to send
+const CONTROL_PING: u8 = 2;
+
+/// Close messages are repeated in case some are lost
+const CLOSE_REPEATS: usize = 3;
+
+/// How often KCP timers run
+const UPDATE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Quiet time after which a ping is sent
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Silence after which the peer is considered gone
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Send and receive windows, in segments
+const WINDOW: u16 = 256;
+
+/// Buffer between the session and its stream
+const STREAM_BUFFER: usize = 256 * 1024;
+
+/// Datagrams queued for a session
+const SESSION_QUEUE: usize = 1024;
+
+/// Connections waiting for [`KcpListener::accept`]
+const ACCEPT_QUEUE: usize = 128;
+
+/// Where a session's datagrams go: a UDP socket, a TURN allocation (see
+/// [`crate::turn_relay`]) or game netcode framing (see
+/// [`crate::game_netcode`])
+pub type Socket = Arc<dyn Conn + Send + Sync>;
+
+/// Open a KCP session to `server_addr`
+///
+/// The returned stream carries the session's data; there is no handshake at
+/// this layer, so an unreachable server shows as the stream closing once
+/// KCP gives up retransmitting.
+pub async fn connect(server_addr: SocketAddr) -> io::Result<DuplexStream> {
+    let socket = Arc::new(UdpSocket::bind(any_local_addr(server_addr)).await?);
+    Ok(connect_over(socket, server_addr, ()))
+}
+
+/// Unspecified address of `peer`'s family, for a client socket
+pub(crate) fn any_local_addr(peer: SocketAddr) -> SocketAddr {
+    if peer.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    }
+}
+
+/// Open a KCP session to `server_addr` over `socket`, keeping `guard` (such
+/// as the client owning the socket) alive as long as the session
+pub(crate) fn connect_over<G>(socket: Socket, server_addr: SocketAddr, guard: G) -> DuplexStream
+where
+    G: Send + 'static,
+{
+    let conv = rand::random::<u32>();
+    let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+
+    let receiver = Arc::clone(&socket);
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut buf = vec![0u8; KCP_MTU];
+        loop {
+            let received = tokio::select! {
+                received = receiver.recv_from(&mut buf) => received,
+                _ = tx.closed() => return,
+            };
+            match received {
+                Ok((n, from)) if from == server_addr && conversation(&buf[..n]) == Some(conv) => {
+                    if tx.send(buf[..n].to_vec()).await.is_err() {
+                        return;
+                    }
+                }
+                Ok((_, from)) => log::debug!("KCP: ignoring datagram from {}", from),
+                Err(e) => log::debug!("KCP: receive error: {}", e),
+            }
+        }
+    });
+
+    let (stream, session) = tokio::io::duplex(STREAM_BUFFER);
+    tokio::spawn(drive(conv, socket, server_addr, rx, session));
+    log::debug!("KCP session {:08x} to {}", conv, server_addr);
+    stream
+}
+
+/// Sessions of a listener by client address and conversation
+type Sessions = Arc<Mutex<HashMap<(SocketAddr, u32), mpsc::Sender<Vec<u8>>>>>;
+
+/// Accepts KCP sessions on a UDP socket
+pub struct KcpListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(DuplexStream, SocketAddr)>,
+    task: JoinHandle<()>,
+}
+
+impl KcpListener {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_over(Arc::new(UdpSocket::bind(addr).await?))
+    }
+
+    /// Accept sessions on an already bound `socket`
+    pub fn bind_over(socket: Socket) -> io::Result<Self> {
+        let local_addr = socket.local_addr().map_err(conn_error)?;
+        let (tx, accepted) = mpsc::channel(ACCEPT_QUEUE);
+        let task = tokio::spawn(Self::dispatch(socket, tx));
+        Ok(Self { local_addr, accepted, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Next new session, with the client's address
+    pub async fn accept(&mut self) -> io::Result<(DuplexStream, SocketAddr)> {
+        self.accepted
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "KCP listener closed"))
+    }
+
+    /// Route datagrams to their sessions, starting a session for the first
+    /// data segment of an unknown conversation
+    async fn dispatch(socket: Socket, accepted: mpsc::Sender<(DuplexStream, SocketAddr)>) {
+        let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+        let mut buf = vec![0u8; KCP_MTU];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    log::debug!("KCP: receive error: {}", e);
+                    continue;
+                }
+            };
+            let datagram = &buf[..n];
+            let Some(conv) = conversation(datagram) else {
+                continue;
+            };
+
+            let session = sessions.lock().unwrap().get(&(from, conv)).cloned();
+            if let Some(session) = session {
+                if session.try_send(datagram.to_vec()).is_err() {
+                    log::debug!("KCP: session {:08x} of {} is behind, dropping datagram", conv, from);
+                }
+                continue;
+            }
+            let opens_session = n >= KCP_HEADER_SIZE && datagram[4] == KCP_CMD_PUSH && kcp::get_sn(datagram) == 0;
+            if !opens_session {
+                continue;
+            }
+
+            let (tx, rx) = mpsc::channel(SESSION_QUEUE);
+            let _ = tx.try_send(datagram.to_vec());
+            let (stream, session) = tokio::io::duplex(STREAM_BUFFER);
+            if accepted.try_send((stream, from)).is_err() {
+                log::warn!("KCP: accept queue full, dropping session from {}", from);
+                continue;
+            }
+            log::debug!("KCP session {:08x} from {}", conv, from);
+            sessions.lock().unwrap().insert((from, conv), tx);
+
+            let socket = Arc::clone(&socket);
+            let sessions = Arc::clone(&sessions);
+            tokio::spawn(async move {
+                drive(conv, socket, from, rx, session).await;
+                sessions.lock().unwrap().remove(&(from, conv));
+            });
+        }
+    }
+}
+
+impl Drop for KcpListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// KCP output: every write is one datagram
+struct Output(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run one session until both sides have closed or the peer is gone
+async fn drive(
+    conv: u32,
+    socket: Socket,
+    peer: SocketAddr,
+    datagrams: mpsc::Receiver<Vec<u8>>,
+    stream: DuplexStream,
+) {
+    match run_session(conv, socket.as_ref(), peer, datagrams, stream).await {
+        Ok(()) => log::debug!("KCP session {:08x} with {} closed", conv, peer),
+        Err(e) => log::debug!("KCP session {:08x} with {} ended: {}", conv, peer, e),
+    }
+}
+
+async fn run_session(
+    conv: u32,
+    socket: &(dyn Conn + Send + Sync),
+    peer: SocketAddr,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    stream: DuplexStream,
+) -> io::Result<()> {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let mut kcp = Kcp::new_stream(conv, Output(Arc::clone(&sent)));
+    kcp.set_nodelay(true, UPDATE_INTERVAL.as_millis() as i32, 2, true);
+    kcp.set_wndsize(WINDOW, WINDOW);
+    kcp.set_mtu(KCP_MTU).map_err(kcp_error)?;
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let clock = Instant::now();
+    let mut ticker = tokio::time::interval(UPDATE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut buf = vec![0u8; kcp.mss() * 8];
+    let (mut last_heard, mut last_sent) = (Instant::now(), Instant::now());
+    let (mut local_closed, mut peer_closed, mut close_sent, mut stream_shut) = (false, false, false, false);
+
+    loop {
+        let accepting = !local_closed && kcp.wait_snd() < WINDOW as usize * 2;
+        tokio::select! {
+            datagram = datagrams.recv() => {
+                let Some(datagram) = datagram else {
+                    return Ok(());
+                };
+                last_heard = Instant::now();
+                if datagram.len() >= KCP_HEADER_SIZE {
+                    kcp.input(&datagram).map_err(kcp_error)?;
+                } else if datagram.get(4) == Some(&CONTROL_CLOSE) {
+                    peer_closed = true;
+                }
+            }
+            read = reader.read(&mut buf), if accepting => match read {
+                Ok(0) | Err(_) => local_closed = true,
+                Ok(n) => {
+                    kcp.send(&buf[..n]).map_err(kcp_error)?;
+                }
+            },
+            _ = ticker.tick() => {}
+        }
+
+        kcp.update(clock.elapsed().as_millis() as u32).map_err(kcp_error)?;
+        kcp.flush().map_err(kcp_error)?;
+        let outgoing = std::mem::take(&mut *sent.lock().unwrap());
+        if !outgoing.is_empty() {
+            last_sent = Instant::now();
+        }
+        for datagram in outgoing {
+            socket.send_to(&datagram, peer).await.map_err(conn_error)?;
+        }
+
+        while let Ok(size) = kcp.peeksize() {
+            let mut data = vec![0u8; size];
+            let n = kcp.recv(&mut data).map_err(kcp_error)?;
+            writer.write_all(&data[..n]).await?;
+        }
+
+        if kcp.is_dead_link() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Peer stopped acknowledging"));
+        }
+        if last_heard.elapsed() > IDLE_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Peer went silent"));
+        }
+        // Everything the peer sent was acknowledged before it closed
+        if peer_closed && !stream_shut {
+            writer.shutdown().await.ok();
+            stream_shut = true;
+        }
+        if local_closed && !close_sent && kcp.wait_snd() == 0 {
+            for _ in 0..CLOSE_REPEATS {
+                socket.send_to(&control(conv, CONTROL_CLOSE), peer).await.map_err(conn_error)?;
+            }
+            close_sent = true;
+        }
+        if close_sent && peer_closed {
+            return Ok(());
+        }
+        if last_sent.elapsed() > PING_INTERVAL {
+            socket.send_to(&control(conv, CONTROL_PING), peer).await.map_err(conn_error)?;
+            last_sent = Instant::now();
+        }
+    }
+}
+
+/// Conversation of a segment or control message (`kcp::get_conv` insists
+/// on a full segment header)
+fn conversation(datagram: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(datagram.get(..4)?.try_into().ok()?))
+}
+
+fn control(conv: u32, kind: u8) -> [u8; 5] {
+    let conv = conv.to_le_bytes();
+    [conv[0], conv[1], conv[2], conv[3], kind]
+}
+
+fn kcp_error(e: kcp::Error) -> io::Error {
+    io::Error::other(format!("KCP: {:?}", e))
+}
+
+fn conn_error(e: webrtc::util::Error) -> io::Error {
+    match e {
+        webrtc::util::Error::Io(e) => e.0,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_kcp_round_trip() {
+        let mut listener = KcpListener::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = connect(listener.local_addr()).await.unwrap();
+
+        // Larger than a window's worth of segments
+        let request: Vec<u8> = (0..400_000u32).map(|i| i as u8).collect();
+        let expected = request.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&request).await.unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+
+        let (mut server, from) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(from.ip().is_loopback() || from.ip().is_unspecified());
+        let mut received = vec![0u8; expected.len()];
+        tokio::time::timeout(Duration::from_secs(10), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, expected);
+
+        // Closing one side ends the other's stream once all data is through
+        server.write_all(b"done").await.unwrap();
+        server.shutdown().await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(5), writer).await.unwrap().unwrap();
+        assert_eq!(reply, b"done");
+    }
+}
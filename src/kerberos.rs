@@ -0,0 +1,277 @@
+//! Kerberos authentication for upstream HTTP proxies
+//!
+//! The `Negotiate` scheme (RFC 4559) carries SPNEGO tokens. With the
+//! `kerberos` feature on Unix, the tokens come from the system GSSAPI
+//! library (MIT `libgssapi_krb5` or Heimdal `libgssapi`), loaded at run time
+//! and using the user's ticket cache (`kinit`). Without it, or when there is
+//! no ticket, [`Context::new`] fails and the caller falls back to NTLM
+//! tokens under `Negotiate`. Windows SSPI and the macOS GSS framework, whose
+//! structures are packed differently, are not used.
+
+use std::io;
+
+/// A Kerberos security context with the proxy `HTTP@host`
+pub struct Context {
+    #[cfg(all(unix, feature = "kerberos"))]
+    inner: gssapi::Context,
+    #[cfg(not(all(unix, feature = "kerberos")))]
+    never: std::convert::Infallible,
+}
+
+impl Context {
+    /// Start a context with the proxy `host`, returning the first token
+    pub fn new(host: &str) -> io::Result<(Self, Vec<u8>)> {
+        #[cfg(all(unix, feature = "kerberos"))]
+        {
+            let mut inner = gssapi::Context::new(host)?;
+            let token = inner.step(None)?.unwrap_or_default();
+            Ok((Self { inner }, token))
+        }
+        #[cfg(not(all(unix, feature = "kerberos")))]
+        {
+            let _ = host;
+            Err(io::Error::new(io::ErrorKind::Unsupported, "built without Kerberos support"))
+        }
+    }
+
+    /// Answer the proxy's `token`; `None` once the context is complete
+    pub fn step(&mut self, token: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        #[cfg(all(unix, feature = "kerberos"))]
+        {
+            self.inner.step(Some(token))
+        }
+        #[cfg(not(all(unix, feature = "kerberos")))]
+        {
+            let _ = token;
+            match self.never {}
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "kerberos"))]
+mod gssapi {
+    //! Just enough of the GSSAPI C binding (RFC 2744)
+
+    use libloading::Library;
+    use std::ffi::c_void;
+    use std::io;
+    use std::ptr;
+    use std::sync::OnceLock;
+
+    /// Libraries tried in order
+    const LIBRARIES: &[&str] = &["libgssapi_krb5.so.2", "libgssapi_krb5.so", "libgssapi.so.3"];
+
+    /// GSS_C_NT_HOSTBASED_SERVICE, 1.2.840.113554.1.2.1.4
+    const HOSTBASED_SERVICE: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x01\x04";
+    /// SPNEGO, 1.3.6.1.5.5.2
+    const SPNEGO: &[u8] = b"\x2b\x06\x01\x05\x05\x02";
+
+    const COMPLETE: u32 = 0;
+    /// Calling and routine error fields of a major status
+    const ERROR_MASK: u32 = 0xffff_0000;
+    const GSS_CODE: i32 = 1;
+    const MECH_CODE: i32 = 2;
+
+    #[repr(C)]
+    struct Buffer {
+        length: usize,
+        value: *mut c_void,
+    }
+
+    #[repr(C)]
+    struct Oid {
+        length: u32,
+        elements: *mut c_void,
+    }
+
+    type ImportName = unsafe extern "C" fn(*mut u32, *const Buffer, *const Oid, *mut *mut c_void) -> u32;
+    type InitSecContext = unsafe extern "C" fn(
+        *mut u32,
+        *mut c_void,
+        *mut *mut c_void,
+        *mut c_void,
+        *const Oid,
+        u32,
+        u32,
+        *const c_void,
+        *const Buffer,
+        *mut *const Oid,
+        *mut Buffer,
+        *mut u32,
+        *mut u32,
+    ) -> u32;
+    type ReleaseBuffer = unsafe extern "C" fn(*mut u32, *mut Buffer) -> u32;
+    type ReleaseName = unsafe extern "C" fn(*mut u32, *mut *mut c_void) -> u32;
+    type DeleteSecContext = unsafe extern "C" fn(*mut u32, *mut *mut c_void, *mut Buffer) -> u32;
+    type DisplayStatus = unsafe extern "C" fn(*mut u32, u32, i32, *const Oid, *mut u32, *mut Buffer) -> u32;
+
+    struct Api {
+        import_name: ImportName,
+        init_sec_context: InitSecContext,
+        release_buffer: ReleaseBuffer,
+        release_name: ReleaseName,
+        delete_sec_context: DeleteSecContext,
+        display_status: DisplayStatus,
+        _library: Library,
+    }
+
+    impl Api {
+        fn load() -> Result<Self, String> {
+            let mut errors = Vec::new();
+            for name in LIBRARIES {
+                // SAFETY: the GSSAPI libraries have no load-time side effects
+                // beyond their own initialization
+                match unsafe { Library::new(name) }.and_then(|library| unsafe { Self::bind(library) }) {
+                    Ok(api) => return Ok(api),
+                    Err(e) => errors.push(e.to_string()),
+                }
+            }
+            Err(format!("No GSSAPI library found ({})", errors.join("; ")))
+        }
+
+        /// # Safety
+        /// The symbols must have the RFC 2744 signatures
+        unsafe fn bind(library: Library) -> Result<Self, libloading::Error> {
+            Ok(Self {
+                import_name: *library.get(b"gss_import_name\0")?,
+                init_sec_context: *library.get(b"gss_init_sec_context\0")?,
+                release_buffer: *library.get(b"gss_release_buffer\0")?,
+                release_name: *library.get(b"gss_release_name\0")?,
+                delete_sec_context: *library.get(b"gss_delete_sec_context\0")?,
+                display_status: *library.get(b"gss_display_status\0")?,
+                _library: library,
+            })
+        }
+
+        fn get() -> io::Result<&'static Self> {
+            static API: OnceLock<Result<Api, String>> = OnceLock::new();
+            API.get_or_init(Self::load).as_ref().map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e.clone()))
+        }
+
+        /// Error of a failed call, with the library's messages
+        fn error(&self, major: u32, minor: u32) -> io::Error {
+            let mut messages = Vec::new();
+            for (status, kind, mech) in [(major, GSS_CODE, ptr::null()), (minor, MECH_CODE, &oid(SPNEGO) as *const Oid)] {
+                let mut context = 0;
+                loop {
+                    let (mut ignored, mut buffer) = (0, Buffer { length: 0, value: ptr::null_mut() });
+                    // SAFETY: the buffer is released below
+                    let result = unsafe { (self.display_status)(&mut ignored, status, kind, mech, &mut context, &mut buffer) };
+                    if result != COMPLETE {
+                        break;
+                    }
+                    let message = String::from_utf8_lossy(unsafe { buffer_bytes(&buffer) });
+                    let message = message.trim_end_matches('\0').to_string();
+                    unsafe { (self.release_buffer)(&mut ignored, &mut buffer) };
+                    if !message.is_empty() {
+                        messages.push(message);
+                    }
+                    if context == 0 {
+                        break;
+                    }
+                }
+            }
+            io::Error::new(io::ErrorKind::PermissionDenied, format!("Kerberos: {}", messages.join(": ")))
+        }
+    }
+
+    pub(super) struct Context {
+        api: &'static Api,
+        name: *mut c_void,
+        context: *mut c_void,
+    }
+
+    // SAFETY: the handles are only used through `&mut self`
+    unsafe impl Send for Context {}
+
+    impl Context {
+        pub(super) fn new(host: &str) -> io::Result<Self> {
+            let api = Api::get()?;
+            let service = format!("HTTP@{}", host);
+            let buffer = Buffer { length: service.len(), value: service.as_ptr() as *mut c_void };
+            let (mut minor, mut name) = (0, ptr::null_mut());
+            // SAFETY: the library copies the name before returning
+            let major = unsafe { (api.import_name)(&mut minor, &buffer, &oid(HOSTBASED_SERVICE), &mut name) };
+            if major & ERROR_MASK != 0 {
+                return Err(api.error(major, minor));
+            }
+            Ok(Self { api, name, context: ptr::null_mut() })
+        }
+
+        /// Next token for the proxy, answering its `input`
+        pub(super) fn step(&mut self, input: Option<&[u8]>) -> io::Result<Option<Vec<u8>>> {
+            let input = input.map(|token| Buffer { length: token.len(), value: token.as_ptr() as *mut c_void });
+            let (mut minor, mut output) = (0, Buffer { length: 0, value: ptr::null_mut() });
+            // SAFETY: GSS_C_NO_CREDENTIAL uses the default ticket cache; the
+            // output buffer is released below
+            let major = unsafe {
+                (self.api.init_sec_context)(
+                    &mut minor,
+                    ptr::null_mut(),
+                    &mut self.context,
+                    self.name,
+                    &oid(SPNEGO),
+                    0,
+                    0,
+                    ptr::null(),
+                    input.as_ref().map_or(ptr::null(), |buffer| buffer as *const Buffer),
+                    ptr::null_mut(),
+                    &mut output,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            let token = unsafe { buffer_bytes(&output) }.to_vec();
+            unsafe { (self.api.release_buffer)(&mut 0, &mut output) };
+            if major & ERROR_MASK != 0 {
+                return Err(self.api.error(major, minor));
+            }
+            Ok((!token.is_empty()).then_some(token))
+        }
+    }
+
+    impl Drop for Context {
+        fn drop(&mut self) {
+            let mut minor = 0;
+            // SAFETY: both handles came from the library, or are null
+            unsafe {
+                if !self.context.is_null() {
+                    (self.api.delete_sec_context)(&mut minor, &mut self.context, ptr::null_mut());
+                }
+                if !self.name.is_null() {
+                    (self.api.release_name)(&mut minor, &mut self.name);
+                }
+            }
+        }
+    }
+
+    fn oid(elements: &'static [u8]) -> Oid {
+        Oid { length: elements.len() as u32, elements: elements.as_ptr() as *mut c_void }
+    }
+
+    /// # Safety
+    /// `buffer` must be empty or point to `length` readable bytes
+    unsafe fn buffer_bytes(buffer: &Buffer) -> &[u8] {
+        if buffer.value.is_null() || buffer.length == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(buffer.value as *const u8, buffer.length)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_without_ticket() {
+        // No ticket cache in the test environment: the error has to come
+        // back as an error, whether or not a GSSAPI library is installed
+        std::env::set_var("KRB5CCNAME", "FILE:/nonexistent/nooshdaroo-test-ccache");
+        match Context::new("proxy.corp.example") {
+            Ok(_) => panic!("no Kerberos ticket should be available"),
+            Err(e) => assert!(!e.to_string().is_empty()),
+        }
+    }
+}
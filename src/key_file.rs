@@ -0,0 +1,156 @@
+//! Passphrase-encrypted private key files
+//!
+//! Keys are encrypted with ChaCha20-Poly1305 under a key derived from a
+//! passphrase with Argon2id, and stored as a single line:
+//!
+//! ```text
+//! $nooshdaroo-key$v=1$m=19456,t=2,p=1$<salt>$<nonce><ciphertext>
+//! ```
+//!
+//! (salt and ciphertext in unpadded base64). Reference such a file from the
+//! config as `local_private_key = "encrypted:/path/to/server.key"`; the
+//! passphrase is taken from [`PASSPHRASE_ENV`] or prompted for on the terminal.
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use std::path::Path;
+
+/// Environment variable holding the passphrase for non-interactive startup
+pub const PASSPHRASE_ENV: &str = "NOOSHDAROO_KEY_PASSPHRASE";
+
+const HEADER: &str = "nooshdaroo-key";
+const VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+
+/// Encrypt a base64 private key with `passphrase`
+pub fn encrypt_key(key: &str, passphrase: &str) -> Result<String> {
+    let params = Params::default();
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+
+    let aead = derive_key(passphrase, &salt, &params)?;
+    let mut sealed = key.as_bytes().to_vec();
+    aead.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(HEADER), &mut sealed)
+        .map_err(|_| anyhow!("Failed to encrypt key"))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&sealed);
+    Ok(format!(
+        "${}$v={}$m={},t={},p={}${}${}",
+        HEADER,
+        VERSION,
+        params.m_cost(),
+        params.t_cost(),
+        params.p_cost(),
+        BASE64.encode(salt),
+        BASE64.encode(payload)
+    ))
+}
+
+/// Decrypt the contents of a key file with `passphrase`
+pub fn decrypt_key(contents: &str, passphrase: &str) -> Result<String> {
+    let fields: Vec<&str> = contents.trim().split('$').collect();
+    let ["", HEADER, version, params, salt, payload] = fields.as_slice() else {
+        bail!("Not a nooshdaroo encrypted key file");
+    };
+    if *version != format!("v={}", VERSION) {
+        bail!("Unsupported key file version: {}", version);
+    }
+
+    let mut costs = [0u32; 3];
+    for (cost, part) in costs.iter_mut().zip(params.split(',')) {
+        *cost = part
+            .split_once('=')
+            .and_then(|(_, v)| v.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid key file parameters: {}", params))?;
+    }
+    let params = Params::new(costs[0], costs[1], costs[2], None)
+        .map_err(|e| anyhow!("Invalid key file parameters: {}", e))?;
+
+    let salt = BASE64.decode(salt).context("Invalid key file salt")?;
+    let payload = BASE64.decode(payload).context("Invalid key file payload")?;
+    if payload.len() < NONCE_LEN {
+        bail!("Key file payload is truncated");
+    }
+    let (nonce, sealed) = payload.split_at(NONCE_LEN);
+
+    let aead = derive_key(passphrase, &salt, &params)?;
+    let mut buf = sealed.to_vec();
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid key file nonce"))?;
+    let plain = aead
+        .open_in_place(nonce, Aad::from(HEADER), &mut buf)
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted key file"))?;
+    String::from_utf8(plain.to_vec()).context("Decrypted key is not valid text")
+}
+
+/// Read and decrypt the key file at `path`, asking for the passphrase if needed
+pub fn load_key_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read key file {}", path.display()))?;
+    let passphrase = read_passphrase(&format!("Passphrase for {}: ", path.display()), false)?;
+    decrypt_key(&contents, &passphrase)
+        .with_context(|| format!("Failed to decrypt {}", path.display()))
+}
+
+/// Encrypt `key` with a passphrase and write it to `path` (mode 600)
+pub fn write_key_file(path: &Path, key: &str) -> Result<()> {
+    let passphrase = read_passphrase(&format!("New passphrase for {}: ", path.display()), true)?;
+    std::fs::write(path, format!("{}\n", encrypt_key(key, &passphrase)?))
+        .with_context(|| format!("Failed to write key file {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Passphrase from [`PASSPHRASE_ENV`], or prompted on the terminal
+/// (twice when `confirm` is set)
+pub fn read_passphrase(prompt: &str, confirm: bool) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password(prompt)
+        .with_context(|| format!("No terminal to read the passphrase from; set {}", PASSPHRASE_ENV))?;
+    if passphrase.is_empty() {
+        bail!("Empty passphrase");
+    }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        bail!("Passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: &Params) -> Result<LessSafeKey> {
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    let unbound = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| anyhow!("Invalid derived key"))?;
+    Ok(LessSafeKey::new(unbound))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let key = "YYssGez5DVypNoauFczvguddWUMqzOxVv+49BdnRIA0=";
+        let encrypted = encrypt_key(key, "correct horse").unwrap();
+        assert!(encrypted.starts_with("$nooshdaroo-key$v=1$m=19456,t=2,p=1$"));
+        assert!(!encrypted.contains(key));
+
+        assert_eq!(decrypt_key(&encrypted, "correct horse").unwrap(), key);
+        assert!(decrypt_key(&encrypted, "wrong horse").is_err());
+
+        // Fresh salt and nonce every time
+        assert_ne!(encrypt_key(key, "correct horse").unwrap(), encrypted);
+
+        assert!(decrypt_key(key, "correct horse").is_err());
+        assert!(decrypt_key(&encrypted.replace("v=1", "v=9"), "correct horse").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Private key storage backends
+//!
+//! `local_private_key` may hold the base64 key itself or a reference to where
+//! it is stored, so the key never has to sit in the config file:
+//!
+//! | Reference                  | Backend                                        |
+//! |----------------------------|------------------------------------------------|
+//! | `keychain:<name>`          | Platform keychain (see below)                  |
+//! | `macos-keychain:<name>`    | macOS Keychain via `security`                  |
+//! | `secret-service:<name>`    | Linux Secret Service via `secret-tool`         |
+//! | `dpapi:<path>`             | Windows DPAPI-protected file via PowerShell    |
+//! | `tpm:<handle>`             | Linux TPM 2.0 sealed object via `tpm2_unseal`  |
+//! | `encrypted:<path>`         | Passphrase-encrypted file ([`crate::key_file`]) |
+//! | `env:<VAR>`                | Environment variable                           |
+//! | `file:<path>`              | Plain file (protect with file permissions)     |
+//!
+//! `keychain:` picks the native store: Keychain on macOS, Secret Service on
+//! Linux, and a DPAPI file in `%APPDATA%\nooshdaroo\keys` on Windows.
+//! Backends shell out to the platform tools, so no native libraries are linked.
+//! Resolved keys are cached for the life of the process.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+/// Service name keys are filed under in OS key stores
+pub const KEY_SERVICE: &str = "nooshdaroo";
+
+/// Where a private key is stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// The base64 key itself
+    Inline(String),
+    /// macOS Keychain generic password
+    MacKeychain(String),
+    /// Secret Service (GNOME Keyring, KWallet) item
+    SecretService(String),
+    /// File protected with Windows DPAPI for the current user
+    Dpapi(PathBuf),
+    /// TPM 2.0 sealed object handle or context file
+    Tpm(String),
+    /// Passphrase-encrypted key file
+    Encrypted(PathBuf),
+    /// Environment variable
+    Env(String),
+    /// Plain file
+    File(PathBuf),
+}
+
+impl KeySource {
+    /// Parse a `local_private_key` value
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        let Some((scheme, name)) = spec.split_once(':') else {
+            return Ok(KeySource::Inline(spec.to_string()));
+        };
+        if name.is_empty() {
+            bail!("Key reference {} is missing a name", spec);
+        }
+        Ok(match scheme {
+            "keychain" => Self::native(name),
+            "macos-keychain" => KeySource::MacKeychain(name.to_string()),
+            "secret-service" => KeySource::SecretService(name.to_string()),
+            "dpapi" => KeySource::Dpapi(PathBuf::from(name)),
+            "tpm" => KeySource::Tpm(name.to_string()),
+            "encrypted" => KeySource::Encrypted(PathBuf::from(name)),
+            "env" => KeySource::Env(name.to_string()),
+            "file" => KeySource::File(PathBuf::from(name)),
+            _ => bail!(
+                "Unknown key provider {}: (expected keychain, macos-keychain, secret-service, dpapi, tpm, encrypted, env or file)",
+                scheme
+            ),
+        })
+    }
+
+    /// The platform's native key store for `name`
+    pub fn native(name: &str) -> Self {
+        if cfg!(target_os = "macos") {
+            KeySource::MacKeychain(name.to_string())
+        } else if cfg!(windows) {
+            let base = std::env::var_os("APPDATA").map(PathBuf::from).unwrap_or_default();
+            KeySource::Dpapi(base.join(KEY_SERVICE).join("keys").join(format!("{}.dpapi", name)))
+        } else {
+            KeySource::SecretService(name.to_string())
+        }
+    }
+
+    /// Read the base64 key
+    pub fn load(&self) -> Result<String> {
+        let key = match self {
+            KeySource::Inline(key) => key.clone(),
+            KeySource::MacKeychain(name) => run(
+                Command::new("security")
+                    .args(["find-generic-password", "-s", KEY_SERVICE, "-a", name, "-w"]),
+                None,
+            )
+            .with_context(|| format!("Failed to read {} from the macOS Keychain", name))?,
+            KeySource::SecretService(name) => run(
+                Command::new("secret-tool").args(["lookup", "service", KEY_SERVICE, "key", name]),
+                None,
+            )
+            .with_context(|| format!("Failed to read {} from the Secret Service", name))?,
+            KeySource::Dpapi(path) => run(
+                Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", DPAPI_UNPROTECT]),
+                Some(&path.display().to_string()),
+            )
+            .with_context(|| format!("Failed to unprotect {} with DPAPI", path.display()))?,
+            KeySource::Tpm(handle) => run(Command::new("tpm2_unseal").args(["-c", handle]), None)
+                .with_context(|| format!("Failed to unseal {} from the TPM", handle))?,
+            KeySource::Encrypted(path) => crate::key_file::load_key_file(path)?,
+            KeySource::Env(var) => {
+                std::env::var(var).map_err(|_| anyhow!("Environment variable {} is not set", var))?
+            }
+            KeySource::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read key file {}", path.display()))?,
+        };
+        let key = key.trim().to_string();
+        if key.is_empty() {
+            bail!("Key from {} is empty", self);
+        }
+        Ok(key)
+    }
+
+    /// Write a base64 key to this location
+    pub fn store(&self, key: &str) -> Result<()> {
+        match self {
+            KeySource::MacKeychain(name) => {
+                // A trailing -w makes security prompt for the key (and again to
+                // confirm), so it never shows up in the process list
+                run(
+                    Command::new("security").args([
+                        "add-generic-password", "-U", "-s", KEY_SERVICE, "-a", name, "-w",
+                    ]),
+                    Some(&format!("{}\n{}\n", key, key)),
+                )
+                .with_context(|| format!("Failed to store {} in the macOS Keychain", name))?;
+            }
+            KeySource::SecretService(name) => {
+                run(
+                    Command::new("secret-tool").args([
+                        "store", "--label", &format!("Nooshdaroo key {}", name),
+                        "service", KEY_SERVICE, "key", name,
+                    ]),
+                    Some(key),
+                )
+                .with_context(|| format!("Failed to store {} in the Secret Service", name))?;
+            }
+            KeySource::Dpapi(path) => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                run(
+                    Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", DPAPI_PROTECT]),
+                    Some(&format!("{}\n{}", path.display(), key)),
+                )
+                .with_context(|| format!("Failed to protect {} with DPAPI", path.display()))?;
+            }
+            KeySource::File(path) => {
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create(true).truncate(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(0o600);
+                }
+                let mut file = options
+                    .open(path)
+                    .with_context(|| format!("Failed to write key file {}", path.display()))?;
+                // The mode only applies to new files; an existing one is
+                // restricted before the key goes in
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+                }
+                file.write_all(format!("{}\n", key).as_bytes())
+                    .with_context(|| format!("Failed to write key file {}", path.display()))?;
+            }
+            KeySource::Encrypted(path) => crate::key_file::write_key_file(path, key)?,
+            KeySource::Tpm(_) => bail!("Seal keys into the TPM with tpm2-tools (tpm2_create -i -), then reference the handle"),
+            KeySource::Inline(_) | KeySource::Env(_) => bail!("Cannot store a key in {}", self),
+        }
+        cache().lock().unwrap().remove(&self.to_string());
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Inline(_) => write!(f, "inline key"),
+            KeySource::MacKeychain(name) => write!(f, "macos-keychain:{}", name),
+            KeySource::SecretService(name) => write!(f, "secret-service:{}", name),
+            KeySource::Dpapi(path) => write!(f, "dpapi:{}", path.display()),
+            KeySource::Tpm(handle) => write!(f, "tpm:{}", handle),
+            KeySource::Encrypted(path) => write!(f, "encrypted:{}", path.display()),
+            KeySource::Env(var) => write!(f, "env:{}", var),
+            KeySource::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Resolve a `local_private_key` value to the base64 key, caching lookups
+pub fn resolve_private_key(spec: &str) -> Result<String> {
+    let source = KeySource::parse(spec)?;
+    if let KeySource::Inline(key) = source {
+        return Ok(key);
+    }
+    let id = source.to_string();
+    if let Some(key) = cache().lock().unwrap().get(&id) {
+        return Ok(key.clone());
+    }
+    let key = source.load()?;
+    cache().lock().unwrap().insert(id, key.clone());
+    Ok(key)
+}
+
+fn cache() -> &'static Mutex<HashMap<String, String>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Reads the file path from stdin and prints the unprotected key
+const DPAPI_UNPROTECT: &str = "Add-Type -AssemblyName System.Security; \
+    $p = [Console]::In.ReadLine(); \
+    $b = [Security.Cryptography.ProtectedData]::Unprotect([IO.File]::ReadAllBytes($p), $null, 'CurrentUser'); \
+    [Text.Encoding]::UTF8.GetString($b)";
+
+/// Reads the file path and key from stdin and writes the protected key
+const DPAPI_PROTECT: &str = "Add-Type -AssemblyName System.Security; \
+    $p = [Console]::In.ReadLine(); $k = [Console]::In.ReadLine(); \
+    $b = [Security.Cryptography.ProtectedData]::Protect([Text.Encoding]::UTF8.GetBytes($k), $null, 'CurrentUser'); \
+    [IO.File]::WriteAllBytes($p, $b)";
+
+/// Run a helper tool, optionally feeding `input` on stdin, returning stdout
+pub(crate) fn run(command: &mut Command, input: Option<&str>) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Cannot run {}", program))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_sources() {
+        let inline = "aGVsbG8gd29ybGQgaGVsbG8gd29ybGQgaGVsbG8gd28=";
+        assert_eq!(KeySource::parse(inline).unwrap(), KeySource::Inline(inline.to_string()));
+        assert_eq!(KeySource::parse("tpm:0x81000001").unwrap(), KeySource::Tpm("0x81000001".to_string()));
+        assert_eq!(KeySource::parse("env:NK_KEY").unwrap(), KeySource::Env("NK_KEY".to_string()));
+        assert_eq!(
+            KeySource::parse("secret-service:server").unwrap(),
+            KeySource::SecretService("server".to_string())
+        );
+        assert_eq!(KeySource::parse("keychain:nooshdaroo").unwrap(), KeySource::native("nooshdaroo"));
+        assert!(KeySource::parse("vault:key").is_err());
+        assert!(KeySource::parse("keychain:").is_err());
+    }
+
+    #[test]
+    fn test_file_and_env_providers() {
+        let path = std::env::temp_dir().join(format!("nooshdaroo-key-{}", rand::random::<u32>()));
+        let source = KeySource::File(path.clone());
+        source.store("c2VjcmV0").unwrap();
+        assert_eq!(resolve_private_key(&format!("file:{}", path.display())).unwrap(), "c2VjcmV0");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        // Cached until stored again
+        std::fs::write(&path, "Y2hhbmdlZA==").unwrap();
+        assert_eq!(resolve_private_key(&format!("file:{}", path.display())).unwrap(), "c2VjcmV0");
+        source.store("bmV3").unwrap();
+        assert_eq!(resolve_private_key(&format!("file:{}", path.display())).unwrap(), "bmV3");
+        std::fs::remove_file(&path).ok();
+
+        assert!(resolve_private_key("env:NOOSHDAROO_TEST_KEY_THAT_IS_UNSET").is_err());
+        assert!(KeySource::Env("X".to_string()).store("key").is_err());
+    }
+}
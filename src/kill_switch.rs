@@ -0,0 +1,167 @@
+//! Kill switch for when the tunnel is down
+//!
+//! Applications that find the proxy failing often retry without it,
+//! sending traffic past the tunnel in the clear. With
+//! `socks.kill_switch = true`, once tunnels to every server have failed
+//! [`FAILURES_TO_ENGAGE`] times in a row the client blocks all outbound
+//! traffic except to the tunnel servers, the upstream proxy and loopback
+//! (see [`crate::firewall`]), and closes the flows it is still proxying so
+//! applications notice at once. The block is lifted as soon as a tunnel is
+//! established again, and when the client exits.
+//!
+//! The rules need administrator rights; the client checks it can install
+//! them when starting and refuses to run unprotected otherwise.
+
+use crate::firewall::{Block, Rules};
+use anyhow::Result;
+use std::net::IpAddr;
+use std::sync::Mutex as StdMutex;
+use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
+
+const BLOCK: Block = Block {
+    chain: "NOOSHDAROO_KILL",
+    anchor: "com.apple/nooshdaroo-kill-switch",
+    rule: "nooshdaroo kill switch",
+    port: None,
+};
+
+/// Consecutive failed tunnels after which the tunnel counts as down
+pub const FAILURES_TO_ENGAGE: u32 = 3;
+
+/// Blocks traffic outside the tunnel while it is down
+#[derive(Debug)]
+pub struct KillSwitch {
+    /// Addresses still reachable while engaged
+    allowed: Vec<IpAddr>,
+    /// Whether to install firewall rules when engaged
+    firewall: bool,
+    state: Mutex<State>,
+    /// Tasks relaying proxied flows, closed when engaging
+    flows: StdMutex<Vec<AbortHandle>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    failures: u32,
+    engaged: bool,
+    rules: Option<Rules>,
+}
+
+impl KillSwitch {
+    /// Kill switch letting traffic to `allowed` through when engaged.
+    /// Installs and removes the rules once to check that it can.
+    pub fn new(allowed: Vec<IpAddr>) -> Result<Self> {
+        Rules::install(BLOCK, &allowed)?.remove()?;
+        log::info!("Kill switch armed; traffic outside the tunnel is blocked while it is down");
+        Ok(Self {
+            allowed,
+            firewall: true,
+            state: Mutex::new(State::default()),
+            flows: StdMutex::new(Vec::new()),
+        })
+    }
+
+    /// Whether traffic is being blocked
+    pub async fn engaged(&self) -> bool {
+        self.state.lock().await.engaged
+    }
+
+    /// Close the task relaying a flow when engaging
+    pub fn track(&self, flow: AbortHandle) {
+        let mut flows = self.flows.lock().unwrap();
+        flows.retain(|flow| !flow.is_finished());
+        flows.push(flow);
+    }
+
+    /// Record a tunnel established, lifting the block
+    pub async fn record_tunnel_up(&self) {
+        let mut state = self.state.lock().await;
+        state.failures = 0;
+        if !state.engaged {
+            return;
+        }
+        state.engaged = false;
+        if let Some(rules) = state.rules.take() {
+            match tokio::task::spawn_blocking(move || rules.remove()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Failed to lift the kill switch: {}", e),
+                Err(e) => log::error!("Failed to lift the kill switch: {}", e),
+            }
+        }
+        log::info!("Tunnel is back up; kill switch lifted");
+    }
+
+    /// Lift the block for good, as the client exits
+    pub async fn disarm(&self) {
+        let mut state = self.state.lock().await;
+        state.engaged = false;
+        if let Some(rules) = state.rules.take() {
+            if let Err(e) = rules.remove() {
+                log::error!("Failed to lift the kill switch: {}", e);
+            }
+        }
+    }
+
+    /// Record a connection that no server could tunnel, engaging after
+    /// [`FAILURES_TO_ENGAGE`] in a row
+    pub async fn record_tunnel_down(&self) {
+        let mut state = self.state.lock().await;
+        state.failures += 1;
+        if state.engaged || state.failures < FAILURES_TO_ENGAGE {
+            return;
+        }
+        state.engaged = true;
+        log::warn!("Tunnel is down; kill switch engaged, blocking traffic outside the tunnel");
+        if self.firewall {
+            let allowed = self.allowed.clone();
+            match tokio::task::spawn_blocking(move || Rules::install(BLOCK, &allowed)).await {
+                Ok(Ok(rules)) => state.rules = Some(rules),
+                Ok(Err(e)) => log::error!("Failed to install the kill switch rules: {}", e),
+                Err(e) => log::error!("Failed to install the kill switch rules: {}", e),
+            }
+        }
+        let flows = std::mem::take(&mut *self.flows.lock().unwrap());
+        let open = flows.iter().filter(|flow| !flow.is_finished()).count();
+        for flow in flows {
+            flow.abort();
+        }
+        if open > 0 {
+            log::warn!("Closed {} proxied flows", open);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_engage_and_lift() {
+        let kill_switch = KillSwitch {
+            allowed: Vec::new(),
+            firewall: false,
+            state: Mutex::new(State::default()),
+            flows: StdMutex::new(Vec::new()),
+        };
+        let flow = tokio::spawn(std::future::pending::<()>());
+        kill_switch.track(flow.abort_handle());
+
+        for _ in 1..FAILURES_TO_ENGAGE {
+            kill_switch.record_tunnel_down().await;
+        }
+        // A tunnel in between resets the count
+        kill_switch.record_tunnel_up().await;
+        for _ in 1..FAILURES_TO_ENGAGE {
+            kill_switch.record_tunnel_down().await;
+        }
+        assert!(!kill_switch.engaged().await);
+
+        kill_switch.record_tunnel_down().await;
+        assert!(kill_switch.engaged().await);
+        assert!(flow.await.unwrap_err().is_cancelled());
+
+        kill_switch.record_tunnel_up().await;
+        assert!(!kill_switch.engaged().await);
+    }
+}
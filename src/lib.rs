@@ -65,37 +65,125 @@
 //!                     └──────────────┘
 //! ```
 
+pub mod acl;
+pub mod analysis;
 pub mod app_profiles;
+pub mod audit;
 pub mod bandwidth;
+pub mod bench;
+pub mod bittorrent_transport;
+pub mod blocking;
+pub mod bootstrap;
+pub mod builder;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod capture;
+pub mod chain;
+pub mod cluster;
+pub mod coap;
 pub mod config;
+pub mod counters;
+pub mod dns_cache;
+pub mod dns_leak;
 pub mod dns_transport;
 pub mod dns_tunnel;
 pub mod dns_udp_tunnel;
+pub mod doh;
 pub use dns_udp_tunnel::{DnsUdpTunnelServer, DnsUdpTunnelClient, DnsUdpTunnelClientPipelined};
 pub mod reliable_transport;
+pub mod reverse;
+pub mod drain;
+pub mod elligator;
 pub mod embedded_keys;
+pub mod embedded_protocols;
+pub mod entropy;
+pub mod environment;
+pub mod events;
+pub mod experiment;
+pub mod fec;
+pub mod fidelity;
+pub mod firewall;
+pub mod ftp_transport;
+pub mod game_netcode;
+pub mod grpc_transport;
+#[cfg(feature = "history")]
+pub mod history;
+pub mod hosts;
+pub mod http_chunked;
+pub mod https_proxy;
+pub mod isolation;
 pub mod json_logger;
+pub mod kcp_transport;
+pub mod kerberos;
+pub mod key_file;
+pub mod key_provider;
+pub mod kill_switch;
 pub mod library;
+pub mod listen;
+pub mod measurement;
+pub mod mesh;
 pub mod mobile;
+#[cfg(feature = "uniffi")]
+pub mod mobile_api;
+pub mod mqtt_transport;
 pub mod multiport_server;
+pub mod nat_keepalive;
 pub mod netflow_evasion;
+#[cfg(feature = "node")]
+pub mod node;
 pub mod noise_transport;
 pub mod nquic;
+pub mod ntlm;
+pub mod ntp_channel;
+pub mod openvpn;
+pub mod pac;
+pub mod path_quality;
+pub mod pcap;
+pub mod port_hop;
 pub mod profiles;
 pub mod protocol;
+pub mod protocol_hop;
+pub mod protocol_update;
 pub mod proxy;
 pub mod psf;
+pub mod punch;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod qr;
+pub mod rdp_transport;
+pub mod rtp_voip;
+pub mod rtsp_transport;
+pub mod segmentation;
+pub mod service;
+pub mod session;
 pub mod shapeshift;
+pub mod sip;
+pub mod smb_transport;
+pub mod smtp_transport;
 pub mod socks5;
 pub mod socat;
+pub mod speedtest;
+pub mod steam_cdn;
 pub mod strategy;
+pub mod stream;
+pub mod syslog_transport;
+pub mod system_proxy;
+pub mod tcp_fingerprint;
+pub mod tls_handshake;
 pub mod tls_record_layer;
+pub mod tor_bridge;
 pub mod traceroute;
 pub mod traffic;
 pub mod transport;
 pub mod transports;
+pub mod turn_relay;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 pub mod socks_udp;
 pub mod udp_proxy;
+pub mod upstream;
+pub mod upstream_proxy;
+pub mod wireguard;
 pub mod protocol_wrapper;
 
 // Re-export core types
@@ -104,18 +192,21 @@ pub use bandwidth::{
     AdaptiveRateLimiter, BandwidthController, NetworkMetrics, NetworkMonitor, QualityProfile,
     QualityTier,
 };
-pub use config::{NooshdarooConfig, ShapeShiftConfig, TrafficShapingConfig, TransportType, ServerConfig};
-pub use library::ProtocolLibrary;
+pub use builder::NooshdarooClientBuilder;
+pub use config::{NooshdarooConfig, ShapeShiftConfig, TrafficShapingConfig, TransportType, ServerConfig, ListenAddrs, UpstreamServerConfig, ProtocolTrustConfig, ShareLink};
+pub use library::{ProtocolFilter, ProtocolLibrary};
 pub use mobile::{MobileConfigBuilder, NooshdarooMobileConfig};
 pub use noise_transport::{
     generate_keypair as generate_noise_keypair, NoiseConfig, NoiseKeypair, NoisePattern,
     NoiseTransport,
 };
-pub use protocol::{DetectionScore, ProtocolId, ProtocolMeta, Transport};
+pub use protocol::{DetectionScore, ProtocolId, ProtocolMeta, RiskLevel, Transport};
 pub use protocol_wrapper::{ProtocolWrapper, WrapperRole};
 pub use proxy::{HttpProxyServer, ProxyType, UnifiedProxyListener};
 pub use psf::{PsfInterpreter, ProtocolFrame};
+pub use events::{ClientEvent, EventSender};
 pub use shapeshift::ShapeShiftController;
+pub use stream::NooshdarooStream;
 pub use socat::{Bidirectional, ClientToServer, RelayMode, ServerToClient, SocatBuilder, SocatRelay};
 pub use strategy::{ShapeShiftStrategy, StrategyType};
 pub use transport::{
@@ -126,10 +217,15 @@ pub use socks_udp::{UdpSocksHeader, UdpSocksServer};
 pub use udp_proxy::{SimpleUdpForwarder, UdpProxyServer};
 pub use multiport_server::MultiPortServer;
 pub use netflow_evasion::{PathTester, MultiPortConfig};
+pub use upstream::{FailoverEvent, UpstreamLease, UpstreamPool, UpstreamServer, UpstreamStatus};
 
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+// UniFFI's scaffolding for the types exported in mobile_api
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!("nooshdaroo");
+
 /// Nooshdaroo client instance for managing shape-shifted connections
 ///
 /// # Example
@@ -151,9 +247,10 @@ use tokio::sync::RwLock;
 /// # }
 /// ```
 pub struct NooshdarooClient {
-    config: NooshdarooConfig,
+    config: Arc<NooshdarooConfig>,
     library: Arc<ProtocolLibrary>,
     pub controller: Arc<RwLock<ShapeShiftController>>,
+    events: events::EventSender,
 }
 
 impl NooshdarooClient {
@@ -166,19 +263,28 @@ impl NooshdarooClient {
     /// - Configuration is invalid
     /// - Shape-shift controller initialization fails
     pub fn new(config: NooshdarooConfig) -> Result<Self, NooshdarooError> {
-        let library = Arc::new(ProtocolLibrary::load(&config.protocol_dir)?);
-        let controller = Arc::new(RwLock::new(ShapeShiftController::new(
-            config.shapeshift.clone(),
-            Arc::clone(&library),
-        )?));
+        let library = Arc::new(ProtocolLibrary::load_with_trust(&config.protocol_dir, &config.protocol_trust)?);
+        let controller = ShapeShiftController::new(config.shapeshift.clone(), Arc::clone(&library))?;
+        // Outside a runtime there is no traffic to count yet
+        if let (Some(quota), Ok(_)) = (&config.quota, tokio::runtime::Handle::try_current()) {
+            events::spawn_quota_monitor(quota, controller.traffic(), controller.events().clone());
+        }
+        let events = controller.events().clone();
+        let controller = Arc::new(RwLock::new(controller));
 
         Ok(Self {
-            config,
+            config: Arc::new(config),
             library,
             controller,
+            events,
         })
     }
 
+    /// Start building a client with typed settings (see [`builder`])
+    pub fn builder() -> NooshdarooClientBuilder {
+        NooshdarooClientBuilder::new()
+    }
+
     /// Get the currently active protocol ID
     pub async fn current_protocol(&self) -> ProtocolId {
         self.controller.read().await.current_protocol()
@@ -207,6 +313,36 @@ impl NooshdarooClient {
         self.controller.write().await.rotate()
     }
 
+    /// Open a connection to `target` ("host:port") through the tunnel, with
+    /// the current protocol (see [`stream`])
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - No server address or server key is configured
+    /// - Access rules deny the target
+    /// - The tunnel cannot be set up or the server cannot reach the target
+    pub async fn connect(&self, target: &str) -> Result<NooshdarooStream, NooshdarooError> {
+        stream::connect(&self.config, &self.controller, target).await
+    }
+
+    /// SOCKS5 listener on the `[socks]` listen address, tunneling through
+    /// the configured server like the `client` command
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no server address or server key is configured, or
+    /// the server's name cannot be resolved
+    pub async fn socks_listener(&self) -> Result<UnifiedProxyListener, NooshdarooError> {
+        stream::socks_listener(&self.config, &self.controller).await
+    }
+
+    /// Subscribe to this client's events from now on: protocol rotations,
+    /// tunnels going down and quota warnings (see [`events`])
+    pub fn events(&self) -> tokio::sync::broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
     /// Get reference to the protocol library
     pub fn library(&self) -> &Arc<ProtocolLibrary> {
         &self.library
@@ -216,6 +352,7 @@ impl NooshdarooClient {
     pub fn config(&self) -> &NooshdarooConfig {
         &self.config
     }
+
 }
 
 /// Nooshdaroo server instance for receiving shape-shifted connections
@@ -248,7 +385,7 @@ impl NooshdarooServer {
     /// - Protocol library cannot be loaded
     /// - Configuration is invalid
     pub fn new(config: NooshdarooConfig) -> Result<Self, NooshdarooError> {
-        let library = Arc::new(ProtocolLibrary::load(&config.protocol_dir)?);
+        let library = Arc::new(ProtocolLibrary::load_with_trust(&config.protocol_dir, &config.protocol_trust)?);
 
         Ok(Self { config, library })
     }
@@ -282,10 +419,44 @@ pub struct ProtocolStats {
     pub bytes_transferred: u64,
     /// Total packets transferred
     pub packets_transferred: u64,
+    /// Connections, traffic, handshake RTT and failures per protocol
+    pub protocol_traffic: std::collections::HashMap<ProtocolId, counters::TrafficSnapshot>,
     /// Time since client started
     pub uptime: std::time::Duration,
     /// Time of last protocol switch
     pub last_switch: Option<std::time::Instant>,
+    /// Total number of upstream server failovers
+    pub total_failovers: u64,
+    /// Time of last upstream server failover
+    pub last_failover: Option<std::time::Instant>,
+    /// Measured emulation fidelity of the current protocol (0.0 - 1.0)
+    pub emulation_fidelity: Option<f64>,
+    /// DNS queries blocked from leaking outside the tunnel (see [`dns_leak`])
+    pub dns_leaks_blocked: u64,
+    /// Time of the last blocked DNS leak
+    pub last_dns_leak: Option<std::time::Instant>,
+    /// Tunneled DNS cache counters (see [`dns_cache`])
+    pub dns_cache: dns_cache::DnsCacheStats,
+    /// Recent censorship events detected (see [`blocking`]), oldest first
+    pub blocking_events: Vec<blocking::BlockingEvent>,
+    /// Tunnel RTT, jitter and loss per server (see [`path_quality`])
+    pub path_quality: std::collections::HashMap<std::net::SocketAddr, path_quality::PathQuality>,
+}
+
+impl ProtocolStats {
+    /// Summary as a JSON object, for language bindings
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "current_protocol": self.current_protocol,
+            "total_switches": self.total_switches,
+            "bytes_transferred": self.bytes_transferred,
+            "packets_transferred": self.packets_transferred,
+            "uptime_secs": self.uptime.as_secs(),
+            "total_failovers": self.total_failovers,
+            "dns_leaks_blocked": self.dns_leaks_blocked,
+            "blocking_events": self.blocking_events.len(),
+        })
+    }
 }
 
 /// Nooshdaroo error types
@@ -314,6 +485,10 @@ pub enum NooshdarooError {
     /// PSF parsing error
     #[error("PSF parse error: {0}")]
     PsfParse(String),
+
+    /// Tunnel setup error
+    #[error("Tunnel error: {0}")]
+    Tunnel(String),
 }
 
 #[cfg(test)]
@@ -1,21 +1,83 @@
 //! Protocol library manager
 
-use super::protocol::{ProtocolBuilder, ProtocolId, ProtocolMeta, Transport};
-use std::collections::HashMap;
+use super::config::ProtocolTrustConfig;
+use super::protocol::{ProtocolBuilder, ProtocolId, ProtocolMeta, RiskLevel, Transport};
+use super::psf::signature::{PsfVerifier, Verification};
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 
+/// Criteria for selecting protocols from the library. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolFilter {
+    /// Protocol category (e.g., "web")
+    pub category: Option<String>,
+    /// Port the protocol must commonly use
+    pub port: Option<u16>,
+    /// Transport the protocol must support
+    pub transport: Option<Transport>,
+    /// Tag the protocol must carry
+    pub tag: Option<String>,
+    /// Highest acceptable risk level
+    pub max_risk: Option<RiskLevel>,
+    /// Region the protocol must not be known blocked in
+    pub region: Option<String>,
+}
+
+impl ProtocolFilter {
+    /// Whether `protocol` satisfies every set criterion
+    pub fn matches(&self, protocol: &ProtocolMeta) -> bool {
+        self.category.as_ref().is_none_or(|c| protocol.metadata.category.eq_ignore_ascii_case(c))
+            && self.port.is_none_or(|port| protocol.uses_port(port))
+            && self.transport.is_none_or(|t| protocol.supports_transport(t))
+            && self.tag.as_ref().is_none_or(|tag| protocol.has_tag(tag))
+            && self.max_risk.is_none_or(|risk| protocol.risk_level() <= risk)
+            && self.region.as_ref().is_none_or(|region| protocol.suitable_for_region(region))
+    }
+}
+
 /// Protocol library containing all available protocol definitions
 pub struct ProtocolLibrary {
     protocols: HashMap<ProtocolId, ProtocolMeta>,
     protocol_dir: PathBuf,
+    verifier: Option<PsfVerifier>,
+    require_signatures: bool,
 }
 
 impl ProtocolLibrary {
     /// Load protocol library from directory
     pub fn load(protocol_dir: &Path) -> Result<Self, crate::NooshdarooError> {
+        Self::load_with_trust(protocol_dir, &ProtocolTrustConfig::default())
+    }
+
+    /// Load protocol library from directory, verifying PSF signatures
+    /// against the trusted keys in `trust`
+    pub fn load_with_trust(
+        protocol_dir: &Path,
+        trust: &ProtocolTrustConfig,
+    ) -> Result<Self, crate::NooshdarooError> {
+        Self::load_with_updates(protocol_dir, trust, &BTreeMap::new())
+    }
+
+    /// Load protocol library like [`Self::load_with_trust`], adding the PSF
+    /// files of a verified update bundle (relative path to content). They
+    /// take precedence over the protocols compiled into the binary but not
+    /// over files on disk.
+    pub fn load_with_updates(
+        protocol_dir: &Path,
+        trust: &ProtocolTrustConfig,
+        updates: &BTreeMap<String, String>,
+    ) -> Result<Self, crate::NooshdarooError> {
+        let verifier = if trust.trusted_keys.is_empty() {
+            None
+        } else {
+            Some(PsfVerifier::new(&trust.trusted_keys).map_err(crate::NooshdarooError::InvalidConfig)?)
+        };
+
         let mut library = Self {
             protocols: HashMap::new(),
             protocol_dir: protocol_dir.to_path_buf(),
+            verifier,
+            require_signatures: trust.require_signatures,
         };
 
         // Load built-in protocols
@@ -26,6 +88,17 @@ impl ProtocolLibrary {
             library.scan_directory(protocol_dir)?;
         }
 
+        // The bundle was verified as a whole, so its files carry no signatures
+        for (relative, content) in updates {
+            library.load_psf_content(&protocol_dir.join(relative), content)?;
+        }
+
+        // Fill in the standard set compiled into the binary; files on disk
+        // were loaded first and take precedence
+        for (relative, content) in crate::embedded_protocols::iter() {
+            library.load_psf_content(&protocol_dir.join(relative), content)?;
+        }
+
         Ok(library)
     }
 
@@ -62,6 +135,56 @@ impl ProtocolLibrary {
             .collect()
     }
 
+    /// Get protocols matching `filter`, best evasion score first
+    pub fn query(&self, filter: &ProtocolFilter) -> Vec<&ProtocolMeta> {
+        let mut matches: Vec<_> = self
+            .protocols
+            .values()
+            .filter(|p| filter.matches(p))
+            .collect();
+
+        matches.sort_by(|a, b| {
+            b.evasion_score()
+                .partial_cmp(&a.evasion_score())
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.id.as_str().cmp(b.id.as_str()))
+        });
+
+        matches
+    }
+
+    /// Get protocols commonly seen on `port`
+    pub fn for_port(&self, port: u16) -> Vec<&ProtocolMeta> {
+        self.query(&ProtocolFilter { port: Some(port), ..Default::default() })
+    }
+
+    /// Get protocols that can be carried over `transport`
+    pub fn by_transport(&self, transport: Transport) -> Vec<&ProtocolMeta> {
+        self.query(&ProtocolFilter { transport: Some(transport), ..Default::default() })
+    }
+
+    /// Get protocols carrying `tag`
+    pub fn by_tag(&self, tag: &str) -> Vec<&ProtocolMeta> {
+        self.query(&ProtocolFilter { tag: Some(tag.to_string()), ..Default::default() })
+    }
+
+    /// Get protocols not known to be blocked in `region`
+    pub fn for_region(&self, region: &str) -> Vec<&ProtocolMeta> {
+        self.query(&ProtocolFilter { region: Some(region.to_string()), ..Default::default() })
+    }
+
+    /// Get all categories present in the library, sorted
+    pub fn categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = self
+            .protocols
+            .values()
+            .map(|p| p.metadata.category.as_str())
+            .collect();
+        categories.sort_unstable();
+        categories.dedup();
+        categories
+    }
+
     /// Get protocols suitable for evasion (sorted by score)
     pub fn evasion_candidates(&self, min_score: f64) -> Vec<&ProtocolMeta> {
         let mut candidates: Vec<_> = self
@@ -100,6 +223,8 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(1.0, 0.05, 0.3) // Very common, low suspicion
                 .category("web")
+                .ports(vec![8443])
+                .tags(&["tls", "browser"])
                 .psf_path(self.protocol_dir.join("http/https.psf"))
                 .build(),
         );
@@ -116,6 +241,8 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(0.9, 0.1, 0.4)
                 .category("web")
+                .ports(vec![8443])
+                .tags(&["tls", "browser", "multiplexed"])
                 .psf_path(self.protocol_dir.join("http/http2.psf"))
                 .build(),
         );
@@ -129,6 +256,7 @@ impl ProtocolLibrary {
                 .packet_size(64, 512)
                 .detection(1.0, 0.05, 0.2) // Extremely common
                 .category("infrastructure")
+                .tags(&["resolver", "plaintext"])
                 .psf_path(self.protocol_dir.join("dns/dns.psf"))
                 .build(),
         );
@@ -145,6 +273,7 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(0.7, 0.15, 0.35)
                 .category("infrastructure")
+                .tags(&["tls", "resolver"])
                 .psf_path(self.protocol_dir.join("dns/doh.psf"))
                 .build(),
         );
@@ -161,6 +290,8 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(0.95, 0.05, 0.4)
                 .category("security")
+                .ports(vec![853, 8443])
+                .tags(&["tls"])
                 .psf_path(self.protocol_dir.join("tls/tls13.psf"))
                 .build(),
         );
@@ -177,6 +308,8 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(0.75, 0.2, 0.6)
                 .category("transport")
+                .tags(&["browser", "multiplexed"])
+                .blocked_in(&["china", "iran"])
                 .psf_path(self.protocol_dir.join("quic/quic.psf"))
                 .build(),
         );
@@ -193,6 +326,9 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(0.8, 0.25, 0.45)
                 .category("remote")
+                .ports(vec![2222])
+                .tags(&["admin"])
+                .blocked_in(&["iran", "turkmenistan"])
                 .psf_path(self.protocol_dir.join("ssh/ssh.psf"))
                 .build(),
         );
@@ -208,6 +344,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.7, 0.15, 0.35)
                 .category("web")
+                .ports(vec![80])
+                .tags(&["browser"])
                 .psf_path(self.protocol_dir.join("websocket/websocket.psf"))
                 .build(),
         );
@@ -223,6 +361,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.75, 0.2, 0.3)
                 .category("email")
+                .ports(vec![465, 587])
+                .tags(&["plaintext"])
                 .psf_path(self.protocol_dir.join("smtp/smtp.psf"))
                 .build(),
         );
@@ -238,6 +378,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.7, 0.2, 0.35)
                 .category("email")
+                .ports(vec![993])
+                .tags(&["plaintext"])
                 .psf_path(self.protocol_dir.join("smtp/imap.psf"))
                 .build(),
         );
@@ -253,6 +395,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.5, 0.3, 0.3)
                 .category("file-transfer")
+                .ports(vec![20])
+                .tags(&["plaintext", "legacy"])
                 .psf_path(self.protocol_dir.join("ftp/ftp.psf"))
                 .build(),
         );
@@ -266,6 +410,7 @@ impl ProtocolLibrary {
                 .packet_size(48, 90)
                 .detection(0.9, 0.1, 0.2)
                 .category("infrastructure")
+                .tags(&["plaintext", "small-packets"])
                 .psf_path(self.protocol_dir.join("http/ntp.psf"))
                 .build(),
         );
@@ -280,6 +425,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.6, 0.25, 0.35)
                 .category("iot")
+                .ports(vec![8883])
+                .tags(&["pubsub"])
                 .psf_path(self.protocol_dir.join("http/mqtt.psf"))
                 .build(),
         );
@@ -293,6 +440,7 @@ impl ProtocolLibrary {
                 .packet_size(160, 1500)
                 .detection(0.65, 0.2, 0.4)
                 .category("media")
+                .tags(&["realtime", "streaming"])
                 .psf_path(self.protocol_dir.join("http/rtp.psf"))
                 .build(),
         );
@@ -308,6 +456,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.55, 0.25, 0.45)
                 .category("voip")
+                .ports(vec![5061])
+                .tags(&["realtime", "plaintext"])
                 .psf_path(self.protocol_dir.join("http/sip.psf"))
                 .build(),
         );
@@ -323,6 +473,7 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.5, 0.3, 0.4)
                 .category("media")
+                .tags(&["streaming", "plaintext"])
                 .psf_path(self.protocol_dir.join("http/rtsp.psf"))
                 .build(),
         );
@@ -336,6 +487,8 @@ impl ProtocolLibrary {
                 .packet_size(64, 1500)
                 .detection(0.6, 0.2, 0.35)
                 .category("management")
+                .ports(vec![162])
+                .tags(&["plaintext", "small-packets"])
                 .psf_path(self.protocol_dir.join("http/snmp.psf"))
                 .build(),
         );
@@ -351,6 +504,8 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.55, 0.25, 0.4)
                 .category("directory")
+                .ports(vec![636])
+                .tags(&["enterprise"])
                 .psf_path(self.protocol_dir.join("http/ldap.psf"))
                 .build(),
         );
@@ -365,6 +520,9 @@ impl ProtocolLibrary {
                 .stateful()
                 .detection(0.5, 0.6, 0.3) // High suspicion
                 .category("p2p")
+                .ports(vec![6889])
+                .tags(&["bulk"])
+                .blocked_in(&["china", "iran"])
                 .psf_path(self.protocol_dir.join("http/bittorrent.psf"))
                 .build(),
         );
@@ -380,6 +538,7 @@ impl ProtocolLibrary {
                 .encrypted()
                 .detection(0.6, 0.2, 0.5)
                 .category("rpc")
+                .tags(&["tls", "multiplexed"])
                 .psf_path(self.protocol_dir.join("http/grpc.psf"))
                 .build(),
         );
@@ -426,6 +585,32 @@ impl ProtocolLibrary {
         let content = fs::read_to_string(path)
             .map_err(|e| crate::NooshdarooError::Io(e))?;
 
+        // Check the signature before trusting anything in the file
+        if let Some(ref verifier) = self.verifier {
+            match verifier.verify_file(path, content.as_bytes()) {
+                Verification::Verified => {
+                    log::debug!("Verified signature of {}", path.display());
+                }
+                Verification::Unsigned if self.require_signatures => {
+                    log::warn!("Skipping unsigned protocol file {}", path.display());
+                    return Ok(());
+                }
+                Verification::Unsigned => {
+                    log::warn!("Loading unsigned protocol file {}", path.display());
+                }
+                Verification::Invalid(reason) => {
+                    log::error!("Rejecting protocol file {}: {}", path.display(), reason);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.load_psf_content(path, &content)
+    }
+
+    /// Load protocol metadata from PSF content found at `path`
+    fn load_psf_content(&mut self, path: &Path, content: &str) -> Result<(), crate::NooshdarooError> {
+
         // Extract protocol name from filename
         let filename = path.file_stem()
             .and_then(|s| s.to_str())
@@ -513,6 +698,98 @@ mod tests {
         assert_eq!(https.unwrap().default_port, 443);
     }
 
+    #[test]
+    fn test_query_api() {
+        let library = ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap();
+
+        let on_443 = library.for_port(443);
+        assert!(on_443.iter().any(|p| p.id.as_str() == "https"));
+        assert!(on_443.iter().all(|p| p.uses_port(443)));
+        assert!(library.for_port(22).iter().any(|p| p.id.as_str() == "ssh"));
+
+        assert!(library.by_transport(Transport::Udp).iter().all(|p| p.transport != Transport::Tcp));
+        assert!(library.by_tag("tls").iter().any(|p| p.id.as_str() == "tls13"));
+        assert!(!library.for_region("china").iter().any(|p| p.id.as_str() == "quic"));
+        assert!(library.categories().contains(&"web"));
+
+        let filter = ProtocolFilter {
+            category: Some("web".to_string()),
+            max_risk: Some(RiskLevel::Low),
+            transport: Some(Transport::Tcp),
+            ..Default::default()
+        };
+        let web = library.query(&filter);
+        assert_eq!(web[0].id.as_str(), "https");
+        assert!(web.iter().all(|p| p.risk_level() == RiskLevel::Low));
+    }
+
+    #[test]
+    fn test_signed_protocol_files() {
+        use crate::psf::signature::PsfSigner;
+
+        let dir = std::env::temp_dir().join(format!("nooshdaroo-signed-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let signed = dir.join("signedproto.psf");
+        let tampered = dir.join("tamperedproto.psf");
+        let unsigned = dir.join("unsignedproto.psf");
+        for path in [&signed, &tampered, &unsigned] {
+            std::fs::write(path, "# Test\n@SEGMENT.CRYPTO\n  TRANSPORT: TCP;\n").unwrap();
+        }
+
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        signer.sign_file(&signed).unwrap();
+        signer.sign_file(&tampered).unwrap();
+        std::fs::write(&tampered, "# Test\n@SEGMENT.CRYPTO\n  TRANSPORT: UDP;\n").unwrap();
+
+        let mut trust = ProtocolTrustConfig {
+            trusted_keys: vec![signer.public_key_base64()],
+            require_signatures: false,
+        };
+        let library = ProtocolLibrary::load_with_trust(&dir, &trust).unwrap();
+        assert!(library.get(&ProtocolId::from("signedproto")).is_some());
+        assert!(library.get(&ProtocolId::from("tamperedproto")).is_none());
+        assert!(library.get(&ProtocolId::from("unsignedproto")).is_some());
+
+        trust.require_signatures = true;
+        let library = ProtocolLibrary::load_with_trust(&dir, &trust).unwrap();
+        assert!(library.get(&ProtocolId::from("signedproto")).is_some());
+        assert!(library.get(&ProtocolId::from("unsignedproto")).is_none());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_embedded_protocols_without_directory() {
+        let missing = std::env::temp_dir().join(format!("nooshdaroo-missing-{}", rand::random::<u32>()));
+        let library = ProtocolLibrary::load(&missing).unwrap();
+        let embedded = ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap();
+        assert_eq!(library.len(), embedded.len());
+        assert!(library.get(&ProtocolId::from("tls13_complete")).is_some());
+    }
+
+    #[test]
+    fn test_filesystem_overrides_embedded() {
+        let dir = std::env::temp_dir().join(format!("nooshdaroo-override-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(dir.join("ssh")).unwrap();
+        std::fs::write(
+            dir.join("ssh/ssh_override.psf"),
+            "# Override\n@SEGMENT.CRYPTO\n  TRANSPORT: UDP\n  DEFAULT_PORT: 2200\n",
+        )
+        .unwrap();
+        std::fs::create_dir_all(dir.join("tls")).unwrap();
+        std::fs::write(
+            dir.join("tls/tls13_complete.psf"),
+            "# Override\n@SEGMENT.CRYPTO\n  DEFAULT_PORT: 8443\n",
+        )
+        .unwrap();
+
+        let library = ProtocolLibrary::load(&dir).unwrap();
+        assert_eq!(library.get(&ProtocolId::from("tls13_complete")).unwrap().default_port, 8443);
+        assert_eq!(library.get(&ProtocolId::from("ssh_override")).unwrap().default_port, 2200);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn test_evasion_candidates() {
         let library = ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap();
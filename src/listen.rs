@@ -0,0 +1,164 @@
+//! TCP listeners bound to several addresses at once
+//!
+//! `listen_addr` may list several addresses, such as `0.0.0.0:443` and
+//! `[::]:443` for dual-stack, or one address per interface. IPv6 wildcard
+//! sockets also accept IPv4 on Linux and so would clash with an IPv4
+//! listener on the same port; when binding more than one address, IPv6
+//! sockets are made IPv6-only.
+//!
+//! A server started by a [draining](crate::drain) predecessor adopts the
+//! predecessor's sockets instead of binding new ones.
+
+use crate::config::ListenAddrs;
+use futures::future::select_all;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Backlog of each listening socket, as `TcpListener::bind` uses
+const BACKLOG: u32 = 1024;
+
+/// Listeners accepting connections on every address of a [`ListenAddrs`]
+#[derive(Debug)]
+pub struct TcpListeners {
+    listeners: Vec<TcpListener>,
+}
+
+impl TcpListeners {
+    /// Bind every address in `addrs`, or adopt the sockets bound to them
+    /// that a draining server handed over
+    pub async fn bind(addrs: &ListenAddrs) -> io::Result<Self> {
+        if let Some(fds) = crate::drain::inherited_fds()? {
+            return Self::adopt(addrs, &fds);
+        }
+        let listeners = match addrs.len() {
+            1 => vec![TcpListener::bind(addrs.first()).await?],
+            _ => addrs
+                .iter()
+                .map(|&addr| bind_one(addr).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e))))
+                .collect::<io::Result<_>>()?,
+        };
+        Ok(Self { listeners })
+    }
+
+    #[cfg(unix)]
+    fn adopt(addrs: &ListenAddrs, fds: &[i32]) -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+
+        let listeners = fds
+            .iter()
+            .map(|&fd| {
+                // SAFETY: the predecessor handed over these descriptors and
+                // nothing else in the process owns them
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let listeners = Self { listeners };
+        let bound = listeners.local_addrs()?;
+        let matches = bound.len() == addrs.len()
+            && bound.iter().zip(addrs.iter()).all(|(bound, wanted)| {
+                bound.ip() == wanted.ip() && (wanted.port() == 0 || bound.port() == wanted.port())
+            });
+        if !matches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Inherited listeners {:?} do not match listen_addr {}", bound, addrs),
+            ));
+        }
+        Ok(listeners)
+    }
+
+    #[cfg(not(unix))]
+    fn adopt(_addrs: &ListenAddrs, _fds: &[i32]) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "inheriting listeners needs Unix"))
+    }
+
+    /// Descriptors of the listening sockets, to hand to a successor
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Vec<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        self.listeners.iter().map(AsRawFd::as_raw_fd).collect()
+    }
+
+    /// The bound listeners, to set socket options on
+    pub fn iter(&self) -> impl Iterator<Item = &TcpListener> {
+        self.listeners.iter()
+    }
+
+    /// Addresses actually bound, with ports chosen for port 0
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// Accept the next connection on any of the addresses
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        if let [listener] = self.listeners.as_slice() {
+            return listener.accept().await;
+        }
+        // Accepting is cancel safe, so the listeners that lose lose nothing
+        let (result, _, _) = select_all(self.listeners.iter().map(|listener| Box::pin(listener.accept()))).await;
+        result
+    }
+}
+
+fn bind_one(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            #[cfg(unix)]
+            set_v6_only(&socket)?;
+            socket
+        }
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(BACKLOG)
+}
+
+/// Windows sockets are IPv6-only by default; Unix ones follow a sysctl
+#[cfg(unix)]
+fn set_v6_only(socket: &TcpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let on: libc::c_int = 1;
+    // SAFETY: the option value points to a c_int that outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_accept_on_every_address() {
+        let addrs: ListenAddrs = "127.0.0.1:0, 127.0.0.1:0".parse().unwrap();
+        let listeners = TcpListeners::bind(&addrs).await.unwrap();
+        let bound = listeners.local_addrs().unwrap();
+        assert_eq!(bound.len(), 2);
+        assert_ne!(bound[0].port(), bound[1].port());
+
+        for addr in bound.iter().rev() {
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (accepted, peer) = listeners.accept().await.unwrap();
+            assert_eq!(accepted.local_addr().unwrap(), *addr);
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+}
@@ -7,7 +7,7 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use log::{info, warn};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -36,6 +36,19 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Detach from the terminal and run in the background (Unix)
+    #[arg(long)]
+    daemon: bool,
+
+    /// Write the process ID to this file, refusing to start if another
+    /// running instance holds it
+    #[arg(long, value_name = "FILE")]
+    pidfile: Option<PathBuf>,
+
+    /// Append log output to this file when running with --daemon
+    #[arg(long, value_name = "FILE", requires = "daemon")]
+    log_file: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -44,7 +57,7 @@ struct Cli {
 enum Commands {
     /// Run as a client (local proxy)
     Client {
-        /// Local bind address
+        /// Local bind address, or several separated by commas
         #[arg(short, long, default_value = "127.0.0.1:1080")]
         bind: String,
 
@@ -64,18 +77,35 @@ enum Commands {
         #[arg(long)]
         port: Option<u16>,
 
-        /// Use preset profile (corporate, airport, hotel, china, iran, russia)
-        #[arg(long, value_parser = ["corporate", "airport", "hotel", "china", "iran", "russia"])]
+        /// Use preset profile (corporate, airport, hotel, china, iran, russia, or a
+        /// user profile; see `nooshdaroo profiles`), or "auto" to probe the network
+        #[arg(long)]
         profile: Option<String>,
 
         /// Automatically select best protocol by testing all paths
         #[arg(long)]
         auto_protocol: bool,
+
+        /// Write the wrapped tunnel bytes to this pcap file for debugging
+        #[arg(long, value_name = "FILE")]
+        capture: Option<PathBuf>,
+
+        /// Annotate the capture with pre-wrap plaintext lengths
+        #[arg(long, requires = "capture")]
+        capture_plaintext_lengths: bool,
+
+        /// nooshdaroo:// share link with server, key, pattern and protocol
+        #[arg(long, value_name = "LINK", conflicts_with = "profile")]
+        uri: Option<String>,
+
+        /// Point the system proxy settings at the local listener while running
+        #[arg(long)]
+        system_proxy: bool,
     },
 
     /// Run as a server (remote endpoint)
     Server {
-        /// Server bind address
+        /// Server bind address, or several separated by commas
         #[arg(short, long, default_value = "0.0.0.0:8443")]
         bind: String,
 
@@ -92,6 +122,40 @@ enum Commands {
         private_key: Option<String>,
     },
 
+    /// Ask a running server to drain: stop accepting tunnels and exit once
+    /// the open ones finish (see [server.drain])
+    Drain {
+        /// Process ID of the server, or the pidfile it was started with
+        server: String,
+    },
+
+    /// Expose local services through a server from behind NAT (see
+    /// [reverse] in the configuration)
+    Agent,
+
+    /// Connect to another client directly, through UDP hole punching (see
+    /// [peer] in the configuration)
+    Peer,
+
+    /// Relay connections for trusted clients and through them (see [mesh]
+    /// in the configuration)
+    Mesh,
+
+    /// Run as a Tor pluggable transport client (launched by Tor through
+    /// ClientTransportPlugin)
+    TorPt,
+
+    /// Print the torrc lines for using this server as a Tor bridge (see
+    /// [server.tor] in the configuration)
+    TorBridgeLine {
+        /// Public address of the bridge, as host:port
+        address: String,
+
+        /// Protocol Tor's connections to the bridge emulate
+        #[arg(long, default_value = "https")]
+        protocol: String,
+    },
+
     /// Run in socat/relay mode
     Relay {
         /// Local listen address
@@ -126,6 +190,72 @@ enum Commands {
         /// Protocol directory
         #[arg(short, long, default_value = "protocols")]
         dir: PathBuf,
+
+        /// Only protocols in this category (e.g., web, email)
+        #[arg(long)]
+        category: Option<String>,
+
+        /// Only protocols commonly seen on this port
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// Only protocols supporting this transport (tcp, udp)
+        #[arg(long)]
+        transport: Option<String>,
+
+        /// Only protocols carrying this tag
+        #[arg(long)]
+        tag: Option<String>,
+
+        /// Highest acceptable risk level (low, medium, high)
+        #[arg(long)]
+        max_risk: Option<String>,
+
+        /// Only protocols not known to be blocked in this region
+        #[arg(long)]
+        region: Option<String>,
+    },
+
+    /// Sign protocol definition files with an Ed25519 key
+    SignProtocol {
+        /// PSF files to sign (signatures are written next to them as .sig)
+        files: Vec<PathBuf>,
+
+        /// Base64 PKCS#8 signing key
+        #[arg(long, env = "NOOSHDAROO_SIGNING_KEY")]
+        key: Option<String>,
+
+        /// Generate a new signing key and print it with its public key
+        #[arg(long)]
+        generate_key: bool,
+    },
+
+    /// Sign a bootstrap document (JSON) for publishing via [bootstrap] sources
+    SignBootstrap {
+        /// Document listing servers: {"issued_at": ..., "servers": [...]}
+        file: PathBuf,
+
+        /// Base64 PKCS#8 signing key (generate one with sign-protocol --generate-key)
+        #[arg(long, env = "NOOSHDAROO_SIGNING_KEY")]
+        key: String,
+    },
+
+    /// Sign a bundle of protocol files for publishing via [protocol_updates] mirrors
+    SignProtocolBundle {
+        /// PSF files to include, each stored under its directory name (web/https.psf)
+        files: Vec<PathBuf>,
+
+        /// Bundle version; clients only accept bundles newer than theirs
+        #[arg(long)]
+        bundle_version: u64,
+
+        /// How long the bundle stays valid (e.g. 30d)
+        #[arg(long, value_parser = humantime::parse_duration)]
+        expires_in: Option<std::time::Duration>,
+
+        /// Base64 PKCS#8 signing key (generate one with sign-protocol --generate-key)
+        #[arg(long, env = "NOOSHDAROO_SIGNING_KEY")]
+        key: String,
     },
 
     /// Generate Noise protocol keypair (keys only)
@@ -133,6 +263,16 @@ enum Commands {
         /// Output format: text (default), json, or quiet (private key only)
         #[arg(long, default_value = "text")]
         format: String,
+
+        /// Store the private key in a key provider instead of printing it
+        /// (keychain:<name>, secret-service:<name>, dpapi:<path>, file:<path>)
+        #[arg(long, value_name = "REF", conflicts_with = "encrypt")]
+        store: Option<String>,
+
+        /// Write the private key to a passphrase-encrypted file
+        /// (referenced as encrypted:<FILE>)
+        #[arg(long, value_name = "FILE")]
+        encrypt: Option<PathBuf>,
     },
 
     /// Generate configuration files
@@ -168,6 +308,18 @@ enum Commands {
         /// Server public key (base64 encoded, for client config)
         #[arg(long)]
         server_public_key: Option<String>,
+
+        /// Print the client config as a QR code in the terminal
+        #[arg(long)]
+        qr: bool,
+
+        /// Save the client config as a QR code PNG
+        #[arg(long)]
+        qr_png: Option<PathBuf>,
+
+        /// Print a nooshdaroo:// share link for the client (also used for QR codes)
+        #[arg(long)]
+        uri: bool,
     },
 
     /// Test all protocol/port combinations to find best path
@@ -184,39 +336,281 @@ enum Commands {
         #[arg(long, default_value = "protocols")]
         protocol_dir: PathBuf,
     },
+
+    /// Locate the hop where censorship interference happens on the path to a target
+    LocateBlocking {
+        /// Target address (ip:port) reached through the censored path
+        #[arg(short, long)]
+        target: String,
+
+        /// Protocol triggers to probe (https, http, dns)
+        #[arg(short, long, value_delimiter = ',', default_value = "https,http")]
+        protocols: Vec<String>,
+
+        /// Sensitive domain expected to trigger blocking
+        #[arg(short, long)]
+        domain: String,
+
+        /// Benign control domain
+        #[arg(long, default_value = "example.com")]
+        control_domain: String,
+
+        /// Maximum TTL to probe
+        #[arg(long, default_value = "30")]
+        max_hops: u8,
+
+        /// Output format (text, json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+
+    /// List built-in and user-defined preset profiles
+    Profiles,
+
+    /// Probe the local network for censorship and recommend a preset profile
+    DetectProfile {
+        /// Output format (text, json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Benchmark goodput, added latency and wrapping overhead per protocol
+    Bench {
+        /// Protocols to benchmark
+        #[arg(short, long, value_delimiter = ',', default_value = "https,tls13,ssh,http2,websocket")]
+        protocols: Vec<String>,
+
+        /// Traffic pattern: bulk:<size> or rr:<request>:<response>:<rounds>
+        #[arg(long, default_value = "rr:512:8K:200")]
+        pattern: String,
+
+        /// Remote server to benchmark against (default: in-process loopback server)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Bench sink address reachable from the remote server (required with --server)
+        #[arg(long)]
+        sink: Option<String>,
+
+        /// Only run a bench sink on this address (for use next to a remote server)
+        #[arg(long)]
+        serve_sink: Option<String>,
+
+        /// Output format (text, json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Classify flows in a capture with the built-in DPI classifier
+    Analyze {
+        /// Capture file (pcap format)
+        #[arg(long)]
+        pcap: PathBuf,
+
+        /// Protocol every flow is expected to classify as (fails otherwise)
+        #[arg(long)]
+        expect: Option<String>,
+
+        /// Output format (text, json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Measure download, upload and RTT through the tunnel per protocol
+    Speedtest {
+        /// Endpoint: http://host[:port]/path or sink://host:port
+        #[arg(short, long)]
+        endpoint: String,
+
+        /// Test through an already running client's SOCKS5 proxy instead
+        #[arg(long)]
+        proxy: Option<String>,
+
+        /// Protocols to compare (default: top-N evasion candidates)
+        #[arg(short, long, value_delimiter = ',')]
+        protocols: Option<Vec<String>>,
+
+        /// Number of protocols to compare when none are given
+        #[arg(long, default_value = "3")]
+        top: usize,
+
+        /// Bytes to transfer in each direction
+        #[arg(long, default_value = "10M")]
+        bytes: String,
+
+        /// Output format (text, json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Show recorded transfer, rotations, handshake failures and path tests
+    Stats {
+        /// How far back to look (e.g. 24h, 7days)
+        #[arg(long, default_value = "24h")]
+        since: String,
+
+        /// History database (default: [history] path from the config file)
+        #[arg(long, value_name = "FILE")]
+        db: Option<PathBuf>,
+
+        /// Output format (text, json)
+        #[arg(long, default_value = "text")]
+        format: String,
+    },
+
+    /// Install and control nooshdaroo as a system service (systemd, launchd, Windows)
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceAction {
+    /// Install a service running the client or server with the --config file
+    Install {
+        /// What the service runs
+        #[arg(value_parser = ["client", "server"])]
+        role: String,
+
+        /// Service name
+        #[arg(long, default_value = "nooshdaroo")]
+        name: String,
+
+        /// Install for the current user instead of system-wide
+        #[arg(long)]
+        user: bool,
+
+        /// Start the service right away
+        #[arg(long)]
+        start: bool,
+    },
+
+    /// Stop and remove an installed service
+    Uninstall {
+        /// Service name
+        #[arg(long, default_value = "nooshdaroo")]
+        name: String,
+
+        /// The service was installed for the current user
+        #[arg(long)]
+        user: bool,
+    },
+
+    /// Start an installed service
+    Start {
+        /// Service name
+        #[arg(long, default_value = "nooshdaroo")]
+        name: String,
+
+        /// The service was installed for the current user
+        #[arg(long)]
+        user: bool,
+    },
+
+    /// Stop a running service
+    Stop {
+        /// Service name
+        #[arg(long, default_value = "nooshdaroo")]
+        name: String,
+
+        /// The service was installed for the current user
+        #[arg(long)]
+        user: bool,
+    },
+
+    /// Run under the Windows Service Control Manager (what installed services start)
+    #[command(hide = true)]
+    Run {
+        #[arg(value_parser = ["client", "server"])]
+        role: String,
+
+        /// Service name
+        #[arg(long, default_value = "nooshdaroo")]
+        name: String,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    // Initialize logger with multiple verbosity levels
-    let log_level = match cli.verbose {
+    if let Commands::Service { action: ServiceAction::Run { ref role, ref name } } = cli.command {
+        return run_as_service(cli.config.clone(), cli.verbose, role, name);
+    }
+
+    if cli.daemon {
+        // Forking is only sound before the runtime starts its threads
+        nooshdaroo::service::daemonize(cli.log_file.as_deref())?;
+    }
+    let _pidfile = cli.pidfile.as_deref().map(nooshdaroo::service::PidFile::create).transpose()?;
+    init_logging(cli.verbose);
+
+    tokio::runtime::Runtime::new()?.block_on(run(cli))
+}
+
+/// Log level for the number of -v flags
+fn log_level(verbose: u8) -> log::LevelFilter {
+    match verbose {
         0 => log::LevelFilter::Warn,   // Default: warnings and errors only
         1 => log::LevelFilter::Info,   // -v: info level
         2 => log::LevelFilter::Debug,  // -vv: debug level
         3 => log::LevelFilter::Trace,  // -vvv: trace level for nooshdaroo
         _ => log::LevelFilter::Trace,  // -vvvv: trace level for all modules
-    };
+    }
+}
 
+/// Initialize logger with multiple verbosity levels
+fn init_logging(verbose: u8) {
     let mut logger = env_logger::Builder::from_default_env();
 
-    if cli.verbose >= 4 {
+    if verbose >= 4 {
         // Maximum verbosity: trace everything including dependencies
         logger.filter_level(log::LevelFilter::Trace);
-    } else if cli.verbose >= 3 {
+    } else if verbose >= 3 {
         // Trace for our crate only, debug for others
         logger.filter_module("nooshdaroo", log::LevelFilter::Trace);
         logger.filter_level(log::LevelFilter::Debug);
     } else {
-        logger.filter_level(log_level);
+        logger.filter_level(log_level(verbose));
     }
 
     logger
         .format_timestamp_millis()
         .format_module_path(true)
         .init();
+}
+
+/// Run the client or server as the Windows service `name`, answering the
+/// Service Control Manager and logging to the event log
+#[cfg(windows)]
+fn run_as_service(config_path: Option<PathBuf>, verbose: u8, role: &str, name: &str) -> Result<()> {
+    nooshdaroo::service::scm::EventLog::install(name, log_level(verbose))?;
+
+    // The role runs exactly as `nooshdaroo --config <FILE> client|server` would
+    let mut arguments = vec![std::ffi::OsString::from("nooshdaroo")];
+    if let Some(path) = config_path {
+        arguments.push("--config".into());
+        arguments.push(path.into_os_string());
+    }
+    arguments.push(role.into());
+    let cli = Cli::try_parse_from(arguments)?;
+
+    nooshdaroo::service::scm::run(name, move || {
+        tokio::runtime::Runtime::new()?.block_on(async {
+            tokio::select! {
+                result = run(cli) => result,
+                _ = nooshdaroo::system_proxy::shutdown_signal() => Ok(()),
+            }
+        })
+    })
+}
+
+#[cfg(not(windows))]
+fn run_as_service(_config_path: Option<PathBuf>, _verbose: u8, _role: &str, _name: &str) -> Result<()> {
+    anyhow::bail!("`service run` is started by the Windows Service Control Manager; use `service start`")
+}
 
+async fn run(cli: Cli) -> Result<()> {
     match cli.command {
         Commands::Client {
             bind,
@@ -226,6 +620,10 @@ async fn main() -> Result<()> {
             port,
             profile,
             auto_protocol,
+            capture,
+            capture_plaintext_lengths,
+            uri,
+            system_proxy,
         } => {
             run_client(
                 cli.config,
@@ -236,6 +634,10 @@ async fn main() -> Result<()> {
                 port,
                 profile.as_deref(),
                 auto_protocol,
+                capture.as_deref(),
+                capture_plaintext_lengths,
+                uri.as_deref(),
+                system_proxy,
             )
             .await?;
         }
@@ -247,6 +649,48 @@ async fn main() -> Result<()> {
         } => {
             run_server(cli.config, &bind, multi_port, max_ports, private_key.as_deref()).await?;
         }
+        Commands::Drain { server } => {
+            drain_server(&server)?;
+        }
+        Commands::Agent => {
+            let path = cli.config.context("The agent needs --config with a [reverse] section")?;
+            let config = NooshdarooConfig::from_file(&path)?;
+            preload_private_key(&config)?;
+            nooshdaroo::proxy::run_reverse_agent(Arc::new(config)).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+        }
+        Commands::Peer => {
+            let path = cli.config.context("The peer command needs --config with a [peer] section")?;
+            let config = NooshdarooConfig::from_file(&path)?;
+            nooshdaroo::proxy::run_peer(Arc::new(config)).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+        }
+        Commands::Mesh => {
+            let path = cli.config.context("The mesh command needs --config with a [mesh] section")?;
+            let config = NooshdarooConfig::from_file(&path)?;
+            nooshdaroo::mesh::run(&config).await?;
+        }
+        Commands::TorPt => {
+            let config = match cli.config {
+                Some(path) => NooshdarooConfig::from_file(&path)?,
+                None => NooshdarooConfig::default(),
+            };
+            nooshdaroo::proxy::run_tor_pt(Arc::new(config)).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+        }
+        Commands::TorBridgeLine { address, protocol } => {
+            let path = cli.config.context("The tor-bridge-line command needs the server's --config")?;
+            let config = NooshdarooConfig::from_file(&path)?;
+            let tor = config.server.as_ref().and_then(|s| s.tor.as_ref()).context("No [server.tor] section in the configuration")?;
+            let transport = config.transport.as_ref().context("No [transport] section in the configuration")?;
+            let private_key = transport.local_private_key.as_deref().context("The server's [transport] has no local_private_key")?;
+            let private_key = nooshdaroo::noise_transport::NoiseKeypair::decode_private_key(private_key)?;
+            let key = nooshdaroo::tor_bridge::bridge_key(&private_key).map_err(|e| anyhow::anyhow!(e))?;
+            let exe = std::env::current_exe()?;
+            println!("UseBridges 1");
+            println!("ClientTransportPlugin {} exec {} tor-pt", nooshdaroo::tor_bridge::TRANSPORT_NAME, exe.display());
+            println!(
+                "{}",
+                nooshdaroo::tor_bridge::bridge_line(&address, tor.fingerprint.as_deref(), &key, &protocol, transport.elligator)
+            );
+        }
         Commands::Relay {
             listen,
             target,
@@ -260,11 +704,46 @@ async fn main() -> Result<()> {
         Commands::Rotate { client } => {
             rotate_protocol(&client).await?;
         }
-        Commands::Protocols { dir } => {
-            list_protocols(&dir)?;
+        Commands::Protocols {
+            dir,
+            category,
+            port,
+            transport,
+            tag,
+            max_risk,
+            region,
+        } => {
+            let transport = match transport.as_deref().map(str::to_lowercase).as_deref() {
+                None => None,
+                Some("tcp") => Some(nooshdaroo::Transport::Tcp),
+                Some("udp") => Some(nooshdaroo::Transport::Udp),
+                Some(other) => anyhow::bail!("Invalid transport: {} (expected tcp or udp)", other),
+            };
+            let filter = nooshdaroo::ProtocolFilter {
+                category,
+                port,
+                transport,
+                tag,
+                max_risk: max_risk
+                    .map(|r| r.parse::<nooshdaroo::RiskLevel>())
+                    .transpose()
+                    .map_err(|e| anyhow::anyhow!(e))?,
+                region,
+            };
+            list_protocols(&dir, &filter)?;
+        }
+        Commands::SignProtocol { files, key, generate_key } => {
+            sign_protocols(&files, key.as_deref(), generate_key)?;
+        }
+        Commands::SignBootstrap { file, key } => {
+            sign_bootstrap(&file, &key)?;
         }
-        Commands::Genkey { format } => {
-            generate_keypair(&format)?;
+        Commands::SignProtocolBundle { files, bundle_version, expires_in, key } => {
+            sign_protocol_bundle(&files, bundle_version, expires_in, &key)?;
+        }
+        Commands::Genkey { format, store, encrypt } => {
+            let store = store.or_else(|| encrypt.map(|path| format!("encrypted:{}", path.display())));
+            generate_keypair(&format, store.as_deref())?;
         }
         Commands::Genconf {
             server_config,
@@ -275,6 +754,9 @@ async fn main() -> Result<()> {
             pattern,
             server_private_key,
             server_public_key,
+            qr,
+            qr_png,
+            uri,
         } => {
             generate_config_files(
                 server_config,
@@ -285,6 +767,9 @@ async fn main() -> Result<()> {
                 &pattern,
                 server_private_key,
                 server_public_key,
+                qr,
+                qr_png,
+                uri,
             )?;
         }
         Commands::TestPaths {
@@ -292,28 +777,159 @@ async fn main() -> Result<()> {
             format,
             protocol_dir,
         } => {
-            test_all_paths(&server, &format, &protocol_dir).await?;
+            let config = match cli.config {
+                Some(ref path) => NooshdarooConfig::from_file(path)?,
+                None => NooshdarooConfig::default(),
+            };
+            // Test the address DoH gives, not the local resolver's answer
+            let server = nooshdaroo::doh::resolve(&config.doh, &config.hosts, &server)
+                .await
+                .map_err(|e| anyhow::anyhow!(e))?
+                .first()
+                .map_or(server, |ip| ip.to_string());
+            test_all_paths(&server, &format, &protocol_dir, &config.history).await?;
+        }
+        Commands::LocateBlocking {
+            target,
+            protocols,
+            domain,
+            control_domain,
+            max_hops,
+            format,
+        } => {
+            locate_blocking(&target, &protocols, &domain, &control_domain, max_hops, &format).await?;
+        }
+        Commands::Profiles => {
+            list_profiles();
+        }
+        Commands::DetectProfile { format } => {
+            detect_profile(&format).await?;
+        }
+        Commands::Bench {
+            protocols,
+            pattern,
+            server,
+            sink,
+            serve_sink,
+            format,
+        } => {
+            run_bench(
+                cli.config,
+                &protocols,
+                &pattern,
+                server.as_deref(),
+                sink.as_deref(),
+                serve_sink.as_deref(),
+                &format,
+            )
+            .await?;
+        }
+        Commands::Analyze { pcap, expect, format } => {
+            analyze_capture(&pcap, expect.as_deref(), &format)?;
+        }
+        Commands::Speedtest {
+            endpoint,
+            proxy,
+            protocols,
+            top,
+            bytes,
+            format,
+        } => {
+            run_speedtest(cli.config, &endpoint, proxy.as_deref(), protocols, top, &bytes, &format).await?;
+        }
+        Commands::Stats { since, db, format } => {
+            show_history(cli.config, &since, db, &format)?;
+        }
+        Commands::Service { action } => {
+            manage_service(cli.config, action)?;
         }
     }
 
     Ok(())
 }
 
+fn manage_service(config_path: Option<PathBuf>, action: ServiceAction) -> Result<()> {
+    use nooshdaroo::service;
+
+    match action {
+        ServiceAction::Install { role, name, user, start } => {
+            let config_path = config_path.context("service install needs --config <FILE> for the service to run with")?;
+            // Catch mistakes now rather than when the service first starts
+            NooshdarooConfig::from_file(&config_path)
+                .with_context(|| format!("Invalid config file {}", config_path.display()))?;
+            let spec = service::ServiceSpec {
+                name: name.clone(),
+                role: if role == "server" { service::Role::Server } else { service::Role::Client },
+                executable: std::env::current_exe().context("Cannot locate the nooshdaroo binary")?,
+                config: config_path
+                    .canonicalize()
+                    .with_context(|| format!("Cannot resolve {}", config_path.display()))?,
+                user,
+            };
+            let location = service::install(&spec)?;
+            println!("Installed {} service {} ({})", role, name, location);
+            if start {
+                service::start(&name, user)?;
+                println!("Started {}", name);
+            }
+        }
+        ServiceAction::Uninstall { name, user } => {
+            service::uninstall(&name, user)?;
+            println!("Removed service {}", name);
+        }
+        ServiceAction::Start { name, user } => {
+            service::start(&name, user)?;
+            println!("Started {}", name);
+        }
+        ServiceAction::Stop { name, user } => {
+            service::stop(&name, user)?;
+            println!("Stopped {}", name);
+        }
+        // Dispatched from main before the runtime starts
+        ServiceAction::Run { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
 async fn run_client(
     config_path: Option<PathBuf>,
     bind: &str,
     server: Option<&str>,
     proxy_type: &str,
     protocol: Option<&str>,
-    port: Option<u16>,
+    mut port: Option<u16>,
     profile: Option<&str>,
     auto_protocol: bool,
+    capture: Option<&std::path::Path>,
+    capture_plaintext_lengths: bool,
+    uri: Option<&str>,
+    system_proxy: bool,
 ) -> Result<()> {
     info!("Starting Nooshdaroo client on {}", bind);
 
     // Load config from profile, config file, or default (in priority order)
-    let config = if let Some(profile_name) = profile {
+    let mut config = if profile == Some("auto") {
+        info!("Probing network to select a preset profile...");
+        let (_, recommendation) =
+            nooshdaroo::environment::detect_profile(&Default::default()).await;
+        match recommendation.profile {
+            Some(name) => {
+                info!("Selected profile {} ({})", name, recommendation.reasons.join("; "));
+                nooshdaroo::profiles::load_profile(name)?
+            }
+            None => {
+                info!("No preset needed ({})", recommendation.reasons.join("; "));
+                match config_path {
+                    Some(ref path) => NooshdarooConfig::from_file(path)?,
+                    None => NooshdarooConfig::default(),
+                }
+            }
+        }
+    } else if let Some(profile_name) = profile {
         info!("Loading preset profile: {}", profile_name);
+        if port.is_none() {
+            port = nooshdaroo::profiles::preferred_ports(profile_name).first().copied();
+        }
         nooshdaroo::profiles::load_profile(profile_name)?
     } else if let Some(ref path) = config_path {
         NooshdarooConfig::from_file(path)?
@@ -321,12 +937,59 @@ async fn run_client(
         NooshdarooConfig::default()
     };
 
+    if let Some(uri) = uri {
+        let link: nooshdaroo::ShareLink = uri.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+        info!("Using share link for {}", link.name.as_deref().unwrap_or(&link.server));
+        link.apply_to(&mut config);
+    }
+
+    preload_private_key(&config)?;
+
+    // Discover servers out of band when configured
+    if !config.bootstrap.sources.is_empty() {
+        let bootstrapper = nooshdaroo::bootstrap::Bootstrapper::new(&config.bootstrap).map_err(|e| anyhow::anyhow!(e))?;
+        match bootstrapper.fetch().await {
+            Ok(document) => {
+                for server in document.servers {
+                    if !config.socks.servers.iter().any(|s| s.address == server.address) {
+                        config.socks.servers.push(server);
+                    }
+                }
+            }
+            Err(e) if config.socks.server_address.is_some() || server.is_some() || !config.socks.servers.is_empty() => {
+                warn!("Bootstrap failed, using configured servers: {}", e);
+            }
+            Err(e) => anyhow::bail!("Bootstrap failed and no server is configured: {}", e),
+        }
+    }
+
     // Check transport type - UDP requires different code path
     if config.socks.transport == TransportType::Udp {
+        if system_proxy {
+            warn!("--system-proxy is not supported with the UDP transport; configure applications manually");
+        }
         return run_udp_client(config, bind, server, proxy_type, protocol, port).await;
     }
 
     let client = NooshdarooClient::new(config.clone())?;
+    nooshdaroo::blocking::spawn_monitor(client.controller.clone());
+    if config.measurements.enabled {
+        let measurements = nooshdaroo::measurement::MeasurementLog::open(&config.measurements)
+            .with_context(|| format!("Failed to open measurement log {}", config.measurements.path.display()))?;
+        nooshdaroo::measurement::install(measurements);
+        info!("Recording anonymized measurements to {}", config.measurements.path.display());
+    }
+    if !config.protocol_updates.mirrors.is_empty() {
+        nooshdaroo::protocol_update::ProtocolUpdater::new(&config)
+            .map_err(|e| anyhow::anyhow!(e))?
+            .spawn(client.controller.clone());
+    }
+    if config.history.enabled {
+        #[cfg(feature = "history")]
+        spawn_history_recorder(&config.history, client.controller.clone())?;
+        #[cfg(not(feature = "history"))]
+        warn!("[history] is enabled but this build lacks the history feature; not recording");
+    }
     let proxy_type = match proxy_type {
         "socks5" => ProxyType::Socks5,
         "http" => ProxyType::Http,
@@ -339,12 +1002,16 @@ async fn run_client(
         (Some(config_server), _) => {
             // Config file server takes precedence
             info!("Using server address from config: {}", config_server);
-            config_server
+            Some(config_server)
         }
         (None, Some(cli_server)) => {
             // Fallback to CLI argument
             info!("Using server address from CLI: {}", cli_server);
-            cli_server
+            Some(cli_server)
+        }
+        (None, None) if !config.socks.servers.is_empty() => {
+            info!("Using {} upstream servers from config", config.socks.servers.len());
+            None
         }
         (None, None) => {
             anyhow::bail!("No server address specified. Provide --server argument or set server_address in config file under [socks] section");
@@ -352,33 +1019,108 @@ async fn run_client(
     };
 
     // Determine bind address from config or CLI argument
-    let bind_addr: SocketAddr = if config_path.is_some() {
-        config.socks.listen_addr
+    let bind_addr: nooshdaroo::ListenAddrs = if config_path.is_some() {
+        config.socks.listen_addr.clone()
     } else {
         bind.parse()?
     };
 
+    // A chain of servers is entered at its first hop
+    let chain = server_addr_str
+        .filter(|s| nooshdaroo::chain::RelayChain::is_chain(s))
+        .map(nooshdaroo::chain::RelayChain::parse)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!(e))?;
+    if chain.is_some() && !config.socks.servers.is_empty() {
+        anyhow::bail!("A relay chain cannot be combined with upstream servers under [socks]");
+    }
+
     // Parse server address
-    let mut server_addr: SocketAddr = server_addr_str.parse()
-        .context(format!("Invalid server address: {}", server_addr_str))?;
+    let mut server_addr: Option<SocketAddr> = match (chain.as_ref(), server_addr_str) {
+        (Some(chain), _) => Some(chain.entry_addr().map_err(|e| anyhow::anyhow!(e))?),
+        // Host names are resolved over DoH, out of the local network's reach
+        (None, Some(s)) => Some(
+            nooshdaroo::doh::resolve_socket_addr(&config.doh, &config.hosts, s)
+                .await
+                .map_err(|e| anyhow::anyhow!("Invalid server address {}: {}", s, e))?,
+        ),
+        (None, None) => None,
+    };
 
     // Apply port override if specified
-    if let Some(port_override) = port {
+    if let (Some(port_override), Some(addr)) = (port, server_addr.as_mut()) {
         info!("Overriding server port from CLI: {}", port_override);
-        server_addr.set_port(port_override);
+        addr.set_port(port_override);
+    }
+
+    // Build the upstream pool when several servers are configured
+    let upstreams = if config.socks.servers.is_empty() {
+        None
+    } else {
+        let pool = nooshdaroo::UpstreamPool::resolve(&config, server_addr)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+        info!("Load balancing across {} upstream servers", pool.len());
+        Some(Arc::new(pool))
+    };
+
+    // Reuse the last working path from a previous run
+    let session = match config.socks.session_file {
+        Some(ref path) => {
+            let max_age = std::time::Duration::from_secs(config.socks.session_max_age_secs);
+            match nooshdaroo::session::SessionState::load(path, max_age) {
+                // A saved path only applies to the protocol it was found with
+                Ok(session) => session.filter(|s| {
+                    client.library().get(&s.protocol).is_some()
+                        && protocol.is_none_or(|p| p == s.protocol.as_str())
+                }),
+                Err(e) => {
+                    warn!("Ignoring saved session {}: {}", path.display(), e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    if let Some(ref session) = session {
+        info!(
+            "Resuming session saved {} ago",
+            humantime::format_duration(std::time::Duration::from_secs(session.age().as_secs()))
+        );
+        if let Some(ref pool) = upstreams {
+            pool.restore(&session.upstreams);
+        }
+        if port.is_none() {
+            if let Some(addr) = server_addr.as_mut() {
+                if let Some(saved) = session.server_for(*addr) {
+                    addr.set_port(saved.port());
+                }
+            }
+        }
+    }
+
+    if let Some(addr) = server_addr {
+        info!("Server address: {}", addr);
     }
 
-    info!("Server address: {}", server_addr);
+    // Address used for path testing in auto-protocol mode
+    let probe_addr = server_addr.or_else(|| {
+        upstreams.as_ref().and_then(|pool| pool.status().first().map(|s| s.server.addr))
+    });
 
-    // Determine protocol: auto-select, CLI override, or config
-    let protocol_id = if auto_protocol {
+    // Determine protocol: CLI override, saved session, auto-select, or config
+    let protocol_id = if let (None, Some(session)) = (protocol, session.as_ref()) {
+        info!("Using protocol {} from saved session (skipping path discovery)", session.protocol.as_str());
+        session.protocol.clone()
+    } else if let (true, Some(probe_addr)) = (auto_protocol, probe_addr) {
         info!("Auto-protocol mode: testing all paths to find best connection...");
         // Use PathTester to find best protocol
-        let library = Arc::new(nooshdaroo::ProtocolLibrary::load(&PathBuf::from("protocols"))?);
+        let library = Arc::new(nooshdaroo::ProtocolLibrary::load_with_trust(&PathBuf::from("protocols"), &config.protocol_trust)?);
         let tester = nooshdaroo::PathTester::new(library);
         let test_config = nooshdaroo::MultiPortConfig::default();
 
-        let results = tester.test_all_paths(&server_addr.ip().to_string(), &test_config).await;
+        let results = tester.test_all_paths(&probe_addr.ip().to_string(), &test_config).await;
+        record_path_tests(&config.history, &results);
 
         if results.is_empty() {
             warn!("No successful paths found, using default protocol");
@@ -393,7 +1135,9 @@ async fn run_client(
                 best.score()
             );
             // Update server port to the best one found
-            server_addr.set_port(best.addr.port());
+            if let Some(addr) = server_addr.as_mut() {
+                addr.set_port(best.addr.port());
+            }
             best.protocol.clone()
         }
     } else if let Some(proto_name) = protocol {
@@ -406,30 +1150,219 @@ async fn run_client(
         proto
     };
 
+    let mut tunnel_addrs: Vec<SocketAddr> = server_addr.into_iter().collect();
+    if let Some(ref pool) = upstreams {
+        tunnel_addrs.extend(pool.status().iter().map(|s| s.server.addr));
+    }
+    let tunnel_servers: Vec<std::net::IpAddr> = tunnel_addrs.iter().map(|addr| addr.ip()).collect();
+    let dns_guard = protect_dns(&config, &tunnel_servers, Some(client.controller.clone()))?;
+    let kill_switch = arm_kill_switch(&config, &tunnel_addrs)?;
+
+    if let Some(path) = config.socks.session_file.clone() {
+        spawn_session_saver(path, protocol_id.clone(), server_addr, upstreams.clone(), client.controller.clone());
+    }
+
     // Create listener with or without tunneling
     let config_arc = Arc::new(config.clone());
-    let listener = if let Some(noise_config) = config.transport {
-        info!("Tunnel mode enabled - traffic will be encrypted via Noise Protocol");
-        info!("Connecting to server: {}", server_addr);
-        UnifiedProxyListener::new(bind_addr, vec![proxy_type], protocol_id, config_arc.clone())
-            .with_server(server_addr, noise_config)
-            .with_controller(client.controller.clone())
-    } else {
-        warn!("Direct mode - no server tunneling configured");
-        warn!("WARNING: Traffic will bypass proxy and connect directly!");
-        UnifiedProxyListener::new(bind_addr, vec![proxy_type], protocol_id, config_arc.clone())
-            .with_controller(client.controller.clone())
-    };
+    let chain_in_use = chain.is_some();
+    if let (true, false, Some(noise_config)) = (config.path_quality.enabled, chain_in_use, config.transport.as_ref()) {
+        if nooshdaroo::chain::is_udp_protocol(&protocol_id) {
+            warn!("[path_quality] is not supported over {}; not measuring", protocol_id.as_str());
+        } else {
+            for addr in &tunnel_addrs {
+                nooshdaroo::proxy::spawn_path_probe(*addr, noise_config.clone(), protocol_id.clone(), config_arc.clone());
+            }
+        }
+    }
+    let listener = match (config.transport, upstreams, server_addr) {
+        (Some(noise_config), Some(pool), _) => {
+            info!("Tunnel mode enabled - traffic will be encrypted via Noise Protocol");
+            pool.spawn_health_checker(
+                client.library().clone(),
+                protocol_id.clone(),
+                std::time::Duration::from_secs(config.socks.health_check_interval_secs.max(1)),
+            );
+            UnifiedProxyListener::new(bind_addr.clone(), vec![proxy_type], protocol_id, config_arc.clone())
+                .with_upstreams(pool, noise_config)
+                .with_controller(client.controller.clone())
+        }
+        (Some(noise_config), None, Some(server_addr)) => {
+            info!("Tunnel mode enabled - traffic will be encrypted via Noise Protocol");
+            info!("Connecting to server: {}", server_addr);
+            let listener = UnifiedProxyListener::new(bind_addr.clone(), vec![proxy_type], protocol_id.clone(), config_arc.clone())
+                .with_server(server_addr, noise_config)
+                .with_controller(client.controller.clone());
+            match chain {
+                Some(chain) => {
+                    chain.validate(&protocol_id).map_err(|e| anyhow::anyhow!(e))?;
+                    info!("Relaying through {} hops: {}", chain.hops.len(), chain);
+                    listener.with_chain(chain)
+                }
+                None => listener,
+            }
+        }
+        _ => {
+            warn!("Direct mode - no server tunneling configured");
+            warn!("WARNING: Traffic will bypass proxy and connect directly!");
+            UnifiedProxyListener::new(bind_addr.clone(), vec![proxy_type], protocol_id, config_arc.clone())
+                .with_controller(client.controller.clone())
+        }
+    };
+
+    let listener = match capture {
+        Some(path) => {
+            let capture = nooshdaroo::capture::PacketCapture::create(path, capture_plaintext_lengths)
+                .with_context(|| format!("Failed to create capture file {}", path.display()))?;
+            info!("Capturing wrapped tunnel traffic to {}", path.display());
+            listener.with_capture(capture)
+        }
+        None => listener,
+    };
+    let listener = match kill_switch {
+        Some(ref kill_switch) => listener.with_kill_switch(kill_switch.clone()),
+        None => listener,
+    };
+    let listener = match start_experiment(&config_arc, &client, chain_in_use)? {
+        Some(experiment) => listener.with_experiment(experiment),
+        None => listener,
+    };
+
+    // Reach the SOCKS5 listener on loopback when it binds every interface
+    let mut socks_addr = bind_addr.first();
+    if socks_addr.ip().is_unspecified() {
+        socks_addr.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+    }
+    if (config.socks.https.is_some() || config.socks.dns.is_some()) && proxy_type != ProxyType::Socks5 {
+        anyhow::bail!("[socks.https] and [socks.dns] hand connections to the SOCKS5 listener; use proxy type socks5");
+    }
+    if let Some(dns) = config.socks.dns.clone() {
+        let controller = client.controller.clone();
+        tokio::spawn(async move {
+            if let Err(e) = nooshdaroo::dns_cache::serve(&dns, socks_addr, Some(controller)).await {
+                log::error!("Tunneled DNS stopped: {:#}", e);
+            }
+        });
+    }
+    if let Some(https) = config.socks.https.clone() {
+        let credentials = match (config.socks.auth_required, config.socks.username.clone(), config.socks.password.clone()) {
+            (true, Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = nooshdaroo::https_proxy::serve(&https, socks_addr, credentials).await {
+                log::error!("HTTPS proxy stopped: {:#}", e);
+            }
+        });
+    }
 
     info!(
         "Nooshdaroo client ready - proxy type: {:?}",
         proxy_type
     );
 
-    // Start listening for connections
-    listener.listen().await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    if !system_proxy && dns_guard.is_none() && kill_switch.is_none() {
+        // Start listening for connections
+        listener.listen().await.map_err(|e| anyhow::anyhow!("{}", e))?;
+        return Ok(());
+    }
 
-    Ok(())
+    // Restore the system settings however the client stops
+    let system_proxy = system_proxy
+        .then(|| nooshdaroo::system_proxy::SystemProxy::apply(bind_addr.first(), proxy_type))
+        .transpose()?;
+    let result = tokio::select! {
+        result = listener.listen() => result.map_err(|e| anyhow::anyhow!("{}", e)),
+        _ = nooshdaroo::system_proxy::shutdown_signal() => {
+            info!("Shutting down");
+            Ok(())
+        }
+    };
+    if let Some(system_proxy) = system_proxy {
+        system_proxy.restore()?;
+    }
+    drop(dns_guard);
+    if let Some(kill_switch) = kill_switch {
+        kill_switch.disarm().await;
+    }
+    result
+}
+
+/// Block DNS leaking outside the tunnel to `servers` when configured
+fn protect_dns(
+    config: &NooshdarooConfig,
+    servers: &[std::net::IpAddr],
+    controller: Option<Arc<RwLock<nooshdaroo::ShapeShiftController>>>,
+) -> Result<Option<Arc<nooshdaroo::dns_leak::DnsLeakGuard>>> {
+    if !config.socks.dns_leak_protection {
+        return Ok(None);
+    }
+    let guard = nooshdaroo::dns_leak::DnsLeakGuard::install(servers)
+        .context("Cannot enable DNS leak protection (administrator rights are needed)")?;
+    let guard = Arc::new(guard);
+    nooshdaroo::dns_leak::spawn_monitor(&guard, controller);
+    Ok(Some(guard))
+}
+
+/// Arm the kill switch when configured, letting the tunnel `servers` and
+/// the upstream proxies used to reach them through
+fn arm_kill_switch(
+    config: &NooshdarooConfig,
+    servers: &[SocketAddr],
+) -> Result<Option<Arc<nooshdaroo::kill_switch::KillSwitch>>> {
+    use std::net::ToSocketAddrs;
+
+    if !config.socks.kill_switch {
+        return Ok(None);
+    }
+    let mut allowed: Vec<std::net::IpAddr> = servers.iter().map(|addr| addr.ip()).collect();
+    let proxies = servers.iter().filter_map(|server| {
+        config
+            .socks
+            .upstream_proxy
+            .clone()
+            .or_else(|| nooshdaroo::upstream_proxy::from_env(&config.socks, *server))
+    });
+    for proxy in proxies {
+        let addrs = proxy
+            .address
+            .to_socket_addrs()
+            .with_context(|| format!("Cannot resolve upstream proxy {}", proxy.address))?;
+        allowed.extend(addrs.map(|addr| addr.ip()));
+    }
+    allowed.sort();
+    allowed.dedup();
+    let kill_switch = nooshdaroo::kill_switch::KillSwitch::new(allowed)
+        .context("Cannot arm the kill switch (administrator rights are needed)")?;
+    Ok(Some(Arc::new(kill_switch)))
+}
+
+/// Save the working path to `path` whenever new tunnels have been
+/// established with `protocol`
+fn spawn_session_saver(
+    path: PathBuf,
+    protocol: nooshdaroo::ProtocolId,
+    server: Option<SocketAddr>,
+    upstreams: Option<Arc<nooshdaroo::UpstreamPool>>,
+    controller: Arc<RwLock<nooshdaroo::ShapeShiftController>>,
+) {
+    tokio::spawn(async move {
+        let mut saved_connections = 0;
+        // Short interval so a client that crashes soon after starting still resumes
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            ticker.tick().await;
+            let connections = controller.read().await.traffic().protocol(&protocol).snapshot().connections;
+            if connections == saved_connections {
+                continue;
+            }
+            saved_connections = connections;
+
+            let session = nooshdaroo::session::SessionState::new(protocol.clone(), server, upstreams.as_deref());
+            if let Err(e) = session.save(&path) {
+                warn!("Failed to save session to {}: {}", path.display(), e);
+            }
+        }
+    });
 }
 
 /// Run client in UDP DNS tunnel mode
@@ -448,7 +1381,7 @@ async fn run_udp_client(
     info!("Starting Nooshdaroo UDP DNS tunnel client");
 
     // Determine bind address
-    let bind_addr: SocketAddr = config.socks.listen_addr;
+    let bind_addr = config.socks.listen_addr.clone();
 
     // Determine server address
     let server_addr_str = match (config.socks.server_address.as_deref(), server) {
@@ -465,8 +1398,9 @@ async fn run_udp_client(
         }
     };
 
-    let mut server_addr: SocketAddr = server_addr_str.parse()
-        .context(format!("Invalid server address: {}", server_addr_str))?;
+    let mut server_addr = nooshdaroo::doh::resolve_socket_addr(&config.doh, &config.hosts, &server_addr_str)
+        .await
+        .map_err(|e| anyhow::anyhow!("Invalid server address {}: {}", server_addr_str, e))?;
 
     if let Some(port_override) = port {
         info!("Overriding server port: {}", port_override);
@@ -477,24 +1411,38 @@ async fn run_udp_client(
     info!("Proxy type: {}", proxy_type);
 
     // Start local TCP listener for SOCKS5
-    let listener = tokio::net::TcpListener::bind(bind_addr).await?;
+    let listener = nooshdaroo::listen::TcpListeners::bind(&bind_addr).await?;
     info!("UDP DNS tunnel client listening on {} (SOCKS5 via TCP -> UDP DNS tunnel)", bind_addr);
+    let dns_guard = protect_dns(&config, &[server_addr.ip()], None)?;
 
-    loop {
-        match listener.accept().await {
-            Ok((mut client_stream, client_addr)) => {
-                let server = server_addr;
+    let accept_loop = async {
+        loop {
+            match listener.accept().await {
+                Ok((mut client_stream, client_addr)) => {
+                    let server = server_addr;
 
-                tokio::spawn(async move {
-                    if let Err(e) = handle_udp_tunnel_connection(&mut client_stream, client_addr, server).await {
-                        log::error!("UDP tunnel connection error from {}: {}", client_addr, e);
-                    }
-                });
-            }
-            Err(e) => {
-                warn!("Accept error: {}", e);
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_udp_tunnel_connection(&mut client_stream, client_addr, server).await {
+                            log::error!("UDP tunnel connection error from {}: {}", client_addr, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    warn!("Accept error: {}", e);
+                }
             }
         }
+    };
+    if dns_guard.is_none() {
+        return accept_loop.await;
+    }
+    tokio::select! {
+        result = accept_loop => result,
+        _ = nooshdaroo::system_proxy::shutdown_signal() => {
+            info!("Shutting down");
+            drop(dns_guard);
+            Ok(())
+        }
     }
 }
 
@@ -696,12 +1644,12 @@ async fn run_server(
     };
 
     // Use config file's listen_addr if available, otherwise use CLI bind argument
-    let bind_addr = if let Some(ref server_config) = config.server {
+    let bind_addr: nooshdaroo::ListenAddrs = if let Some(ref server_config) = config.server {
         info!("Using listen_addr from config file: {}", server_config.listen_addr);
-        server_config.listen_addr.to_string()
+        server_config.listen_addr.clone()
     } else {
         warn!("No [server] section found in config, using CLI bind argument: {}", bind);
-        bind.to_string()
+        bind.parse().with_context(|| format!("Invalid bind address: {}", bind))?
     };
 
     info!("Starting Nooshdaroo server on {}", bind_addr);
@@ -714,18 +1662,36 @@ async fn run_server(
             config.transport = Some(noise_config);
         }
     }
+    preload_private_key(&config)?;
+    if let Some(audit) = config.server.as_ref().and_then(|s| s.audit.as_ref()) {
+        let log = nooshdaroo::audit::AuditLog::open(audit)
+            .with_context(|| format!("Failed to open audit log directory {}", audit.directory.display()))?;
+        nooshdaroo::audit::install(log);
+        info!("Auditing connections to {}", audit.directory.display());
+    }
+    if let Some(rendezvous) = config.server.as_ref().and_then(|s| s.rendezvous.clone()) {
+        tokio::spawn(async move {
+            if let Err(e) = nooshdaroo::punch::serve_rendezvous(&rendezvous).await {
+                warn!("Rendezvous on {} failed: {}", rendezvous.listen, e);
+            }
+        });
+    }
 
     // If multi-port mode is enabled, use MultiPortServer
     if multi_port {
         info!("Multi-port mode enabled - listening on up to {} ports", max_ports);
-        let library = Arc::new(nooshdaroo::ProtocolLibrary::load(&PathBuf::from("protocols"))?);
+        let library = Arc::new(nooshdaroo::ProtocolLibrary::load_with_trust(&PathBuf::from("protocols"), &config.protocol_trust)?);
 
         let mp_config = nooshdaroo::MultiPortConfig {
             max_ports,
             ..Default::default()
         };
 
-        let mp_server = nooshdaroo::MultiPortServer::new(library, mp_config);
+        let mut mp_server = nooshdaroo::MultiPortServer::new(library, mp_config);
+        if config.relay.port_hopping.enabled {
+            let schedule = nooshdaroo::port_hop::PortHopSchedule::new(&config.relay.port_hopping).map_err(|e| anyhow::anyhow!(e))?;
+            mp_server = mp_server.with_port_hopping(schedule);
+        }
         mp_server.initialize().await.map_err(|e| anyhow::anyhow!("{}", e))?;
 
         info!("Multi-port server initialized on:");
@@ -751,7 +1717,7 @@ async fn run_server(
         info!("UDP DNS tunnel mode enabled on {}", bind_addr);
         info!("This mode is optimized for Iran censorship bypass where only DNS (UDP port 53) passes DPI");
 
-        let bind_addr: std::net::SocketAddr = bind_addr.parse()?;
+        let bind_addr = single_listen_addr(&bind_addr, "the UDP transport")?;
         let udp_server = DnsUdpTunnelServer::new(bind_addr);
 
         // Session map: session_id -> (target TCP stream, read buffer)
@@ -773,8 +1739,10 @@ async fn run_server(
         // - Success: [0x00][response_data...]
         // - Error: [0x01][error_code:1]
         let sessions_clone = sessions.clone();
+        let access = Arc::new(nooshdaroo::acl::AccessPolicy::new(&config.access).map_err(|e| anyhow::anyhow!(e))?);
         udp_server.listen(move |session_id, client_addr, payload| {
             let sessions = sessions_clone.clone();
+            let access = access.clone();
             async move {
                 if payload.is_empty() {
                     return Err("Empty payload".to_string());
@@ -840,7 +1808,7 @@ async fn run_server(
                         log::info!("UDP session {:04x} CONNECT to {} from {}", session_id, target_addr, client_addr);
 
                         // Connect to target
-                        match TcpStream::connect(&target_addr).await {
+                        match access.connect_target(&target_addr).await {
                             Ok(stream) => {
                                 // Store the connection
                                 let mut sessions_lock = sessions.write().await;
@@ -849,6 +1817,10 @@ async fn run_server(
                                 log::info!("UDP session {:04x} connected to {}", session_id, target_addr);
                                 Ok(vec![0x00]) // Success
                             }
+                            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                                log::warn!("UDP session {:04x}: {}", session_id, e);
+                                Ok(vec![0x01, 0x02]) // Error: not allowed
+                            }
                             Err(e) => {
                                 log::error!("UDP session {:04x} connect failed: {}", session_id, e);
                                 Ok(vec![0x01, 0x05]) // Error: connection refused
@@ -981,7 +1953,7 @@ async fn run_server(
         || protocol_id.as_str() == "dns_udp_tunnel";
 
     if needs_udp {
-        let udp_addr = bind_addr.parse()?;
+        let udp_addr = single_listen_addr(&bind_addr, "dns-udp-tunnel")?;
         let noise_cfg = noise_config.clone();
         let cfg = config_arc.clone();
 
@@ -994,19 +1966,209 @@ async fn run_server(
             .map_err(|e| anyhow::anyhow!("UDP DNS server error: {}", e));
     }
 
+    // Reliable UDP mode: the same tunnels over KCP sessions
+    if transport_type == TransportType::Kcp {
+        let game_profile = config_arc.relay.game_netcode.as_deref().and_then(nooshdaroo::app_profiles::ApplicationProfile::get);
+        let voip_codec = config_arc.relay.voip_codec;
+        let kcp_addr = single_listen_addr(&bind_addr, "the KCP transport")?;
+        let mut listener = match (&game_profile, voip_codec) {
+            (Some(profile), _) => nooshdaroo::game_netcode::bind(kcp_addr, profile).await?,
+            (None, Some(codec)) => nooshdaroo::rtp_voip::bind(kcp_addr, codec).await?,
+            (None, None) if config_arc.relay.wireguard => nooshdaroo::wireguard::bind(kcp_addr).await?,
+            (None, None) if config_arc.relay.openvpn => nooshdaroo::openvpn::bind_kcp(kcp_addr).await?,
+            (None, None) if config_arc.relay.coap => nooshdaroo::coap::bind(kcp_addr).await?,
+            (None, None) => nooshdaroo::kcp_transport::KcpListener::bind(kcp_addr).await?,
+        };
+        info!("Accepting KCP sessions on UDP {}", listener.local_addr());
+        if let Some(profile) = game_profile {
+            info!("Framing KCP datagrams as {} netcode", profile.name);
+        } else if let Some(codec) = voip_codec {
+            info!("Carrying KCP datagrams in {:?} voice calls", codec);
+            if config_arc.relay.sip {
+                info!("Answering SIP signaling of the calls on TCP {}", listener.local_addr());
+            }
+        } else if config_arc.relay.wireguard {
+            info!("Framing KCP datagrams as WireGuard messages");
+        } else if config_arc.relay.openvpn {
+            info!("Framing KCP datagrams as OpenVPN packets");
+        } else if config_arc.relay.coap {
+            info!("Framing KCP datagrams as CoAP messages");
+        }
+        // Kept until the server exits
+        let _registrar = match config_arc.relay.sip {
+            true => Some(nooshdaroo::sip::serve(listener.local_addr()).await?),
+            false => None,
+        };
+        let _turn_server = match config_arc.server.as_ref().and_then(|s| s.turn.as_ref()) {
+            Some(turn) => {
+                info!("Relaying TURN allocations on UDP {}", turn.listen);
+                Some(nooshdaroo::turn_relay::serve(turn).await?)
+            }
+            None => None,
+        };
+        let connection_limit = connection_limit(&config_arc);
+        let client_sessions = nooshdaroo::isolation::ClientSessions::new(config_arc.relay.max_sessions_per_client);
+        loop {
+            let permit = match connection_limit {
+                Some(ref limit) => Some(limit.clone().acquire_owned().await?),
+                None => None,
+            };
+            let (stream, addr) = listener.accept().await?;
+            let Some(client) = client_sessions.try_open(addr.ip()) else {
+                warn!("Refusing KCP session from {}: {} sessions open from its address", addr, client_sessions.limit());
+                continue;
+            };
+            info!("New KCP session from {}", addr);
+            let noise_cfg = noise_config.clone();
+            let proto_id = protocol_id.clone();
+            let cfg = config_arc.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let _client = client;
+                if let Err(e) = handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await {
+                    log::error!("Tunnel connection error from {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    if let Some(server) = config_arc.server.as_ref().filter(|s| s.io_uring && transport_type == TransportType::Tcp) {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        return run_uring_server(&single_listen_addr(&bind_addr, "io_uring")?.to_string(), server.io_uring_workers, noise_config, protocol_id, config_arc.clone()).await;
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        warn!(
+            "io_uring requested ({} workers) but this build lacks the io-uring feature; using epoll",
+            server.io_uring_workers
+        );
+    }
+
     // Accept and handle connections (TCP mode for non-DNS protocols)
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
+    let listeners = nooshdaroo::listen::TcpListeners::bind(&bind_addr).await?;
+    let segmentation = config_arc.traffic_shaping.segmentation.clone();
+    // Listener options are inherited by accepted sockets and shape the SYN-ACK
+    for listener in listeners.iter() {
+        nooshdaroo::tcp_fingerprint::apply(listener, &config_arc.traffic_shaping.tcp_fingerprint)?;
+        nooshdaroo::segmentation::apply(listener, &segmentation)?;
+    }
+    let connection_limit = connection_limit(&config_arc);
+    let client_sessions = nooshdaroo::isolation::ClientSessions::new(config_arc.relay.max_sessions_per_client);
+    let drain = config_arc.server.as_ref().map(|s| s.drain.clone()).unwrap_or_default();
 
     loop {
-        match listener.accept().await {
+        // Stop accepting while at the connection limit; clients queue in the backlog
+        let permit = match connection_limit {
+            Some(ref limit) => Some(limit.clone().acquire_owned().await?),
+            None => None,
+        };
+        let accepted = tokio::select! {
+            accepted = async {
+                nooshdaroo::service::resumed().await;
+                listeners.accept().await
+            } => accepted,
+            _ = nooshdaroo::drain::requested() => break,
+        };
+        match accepted {
             Ok((stream, addr)) => {
+                let Some(client) = client_sessions.try_open(addr.ip()) else {
+                    warn!("Refusing connection from {}: {} tunnels open from its address", addr, client_sessions.limit());
+                    continue;
+                };
                 info!("New connection from {}", addr);
+                if let Err(e) = nooshdaroo::segmentation::apply(&stream, &segmentation) {
+                    warn!("Failed to apply segmentation options for {}: {}", addr, e);
+                }
+                let shaping = &config_arc.traffic_shaping;
+                if let Err(e) = nooshdaroo::tcp_fingerprint::apply_ttl(&stream, &shaping.ttl, &shaping.tcp_fingerprint) {
+                    warn!("Failed to set TTL for {}: {}", addr, e);
+                }
                 let noise_cfg = noise_config.clone();
                 let proto_id = protocol_id.clone();
                 let cfg = config_arc.clone();
+                let relay = nooshdaroo::drain::track();
 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await {
+                    let _permit = permit;
+                    let _relay = relay;
+                    let _client = client;
+                    let result = match transport_type {
+                        TransportType::HttpChunked => match nooshdaroo::http_chunked::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Smtp => {
+                            let mut stream = stream;
+                            let hostname = cfg.server.as_ref().and_then(|s| s.smtp_hostname.clone());
+                            let hostname = hostname.as_deref().unwrap_or(nooshdaroo::smtp_transport::DEFAULT_HOSTNAME);
+                            match nooshdaroo::smtp_transport::accept(&mut stream, hostname).await {
+                                Ok(()) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                                Err(e) => Err(e.into()),
+                            }
+                        }
+                        TransportType::Rdp => {
+                            let mut stream = stream;
+                            match nooshdaroo::rdp_transport::accept(&mut stream).await {
+                                Ok(()) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                                Err(e) => Err(e.into()),
+                            }
+                        }
+                        TransportType::Bittorrent => match nooshdaroo::bittorrent_transport::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Grpc => match nooshdaroo::grpc_transport::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Openvpn => match nooshdaroo::openvpn::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Mqtt => match nooshdaroo::mqtt_transport::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Rtsp => match nooshdaroo::rtsp_transport::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Smb => match nooshdaroo::smb_transport::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Syslog => match nooshdaroo::syslog_transport::accept(stream).await {
+                            Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Steam => match nooshdaroo::steam_cdn::accept(stream).await {
+                            Ok(Some(stream)) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                            // The connection joined a download under way
+                            Ok(None) => Ok(()),
+                            Err(e) => Err(e.into()),
+                        },
+                        TransportType::Sip => {
+                            let accepted = match stream.local_addr() {
+                                Ok(local) => nooshdaroo::sip::accept(stream, local).await,
+                                Err(e) => Err(e),
+                            };
+                            match accepted {
+                                Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                                Err(e) => Err(e.into()),
+                            }
+                        }
+                        TransportType::Ftp => {
+                            let passive_address = cfg.server.as_ref().and_then(|s| s.ftp_passive_address);
+                            let accepted = match stream.local_addr() {
+                                Ok(local) => nooshdaroo::ftp_transport::accept(stream, local.ip(), addr.ip(), passive_address).await,
+                                Err(e) => Err(e),
+                            };
+                            match accepted {
+                                Ok(stream) => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                                Err(e) => Err(e.into()),
+                            }
+                        }
+                        _ => handle_tunnel_connection(stream, addr, noise_cfg, proto_id, cfg).await,
+                    };
+                    if let Err(e) = result {
                         log::error!("Tunnel connection error from {}: {}", addr, e);
                     }
                 });
@@ -1016,26 +2178,111 @@ async fn run_server(
             }
         }
     }
+
+    if drain.handoff {
+        #[cfg(unix)]
+        match nooshdaroo::drain::hand_off(&listeners.raw_fds()) {
+            Ok(pid) => info!("Handed the listening sockets to new server process {}", pid),
+            Err(e) => warn!("Listener handoff failed; new connections will be refused: {}", e),
+        }
+        #[cfg(not(unix))]
+        warn!("Listener handoff needs Unix; new connections will be refused");
+    }
+    drop(listeners);
+    info!(
+        "Draining: waiting up to {} for {} open tunnels",
+        humantime::format_duration(drain.timeout),
+        nooshdaroo::drain::active()
+    );
+    if !nooshdaroo::drain::finished(drain.timeout).await {
+        warn!("Drain timed out with {} tunnels still open", nooshdaroo::drain::active());
+    }
+    Ok(())
+}
+
+/// The only address in `addrs`, for modes that bind a single socket
+fn single_listen_addr(addrs: &nooshdaroo::ListenAddrs, mode: &str) -> Result<SocketAddr> {
+    if addrs.len() > 1 {
+        anyhow::bail!("Listening on several addresses ({}) is not supported with {}", addrs, mode);
+    }
+    Ok(addrs.first())
+}
+
+/// Semaphore enforcing `relay.max_connections`, if set
+fn connection_limit(config: &NooshdarooConfig) -> Option<Arc<tokio::sync::Semaphore>> {
+    match config.relay.max_connections {
+        0 => None,
+        max => Some(Arc::new(tokio::sync::Semaphore::new(max))),
+    }
+}
+
+/// Serve TCP tunnels from io_uring worker threads
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+async fn run_uring_server(
+    bind_addr: &str,
+    workers: usize,
+    noise_config: Option<nooshdaroo::NoiseConfig>,
+    protocol_id: nooshdaroo::ProtocolId,
+    config: Arc<nooshdaroo::NooshdarooConfig>,
+) -> Result<()> {
+    let addr: SocketAddr = bind_addr.parse()
+        .context(format!("Invalid bind address: {}", bind_addr))?;
+    let connection_limit = connection_limit(&config);
+
+    tokio::task::spawn_blocking(move || {
+        nooshdaroo::uring::serve(addr, workers, move |stream, peer| {
+            let noise_cfg = noise_config.clone();
+            let proto_id = protocol_id.clone();
+            let cfg = config.clone();
+            let limit = connection_limit.clone();
+            async move {
+                // Workers keep accepting; over-limit connections wait here before the handshake
+                let _permit = match limit {
+                    Some(limit) => limit.acquire_owned().await.ok(),
+                    None => None,
+                };
+                info!("New connection from {}", peer);
+                let shaping = &cfg.traffic_shaping;
+                if let Err(e) = nooshdaroo::segmentation::apply(&stream, &shaping.segmentation) {
+                    warn!("Failed to apply segmentation options for {}: {}", peer, e);
+                }
+                if let Err(e) = nooshdaroo::tcp_fingerprint::apply_ttl(&stream, &shaping.ttl, &shaping.tcp_fingerprint) {
+                    warn!("Failed to set TTL for {}: {}", peer, e);
+                }
+                if let Err(e) = handle_tunnel_connection(stream, peer, noise_cfg, proto_id, cfg).await {
+                    log::error!("Tunnel connection error from {}: {}", peer, e);
+                }
+            }
+        })
+    })
+    .await?
+    .context("io_uring server failed")
 }
 
 /// Handle incoming tunnel connection from client
-async fn handle_tunnel_connection(
-    mut tunnel_stream: tokio::net::TcpStream,
+async fn handle_tunnel_connection<S>(
+    mut tunnel_stream: S,
     peer_addr: std::net::SocketAddr,
     noise_config: Option<nooshdaroo::NoiseConfig>,
     protocol_id: nooshdaroo::ProtocolId,
     config: Arc<nooshdaroo::NooshdarooConfig>,
-) -> Result<()> {
+) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
     use nooshdaroo::{NoiseTransport, ProtocolWrapper};
 
     // If no noise config, reject connection
     let noise_config = noise_config
         .ok_or_else(|| anyhow::anyhow!("Server not configured for encrypted tunnels"))?;
+    let mut audit = nooshdaroo::audit::connection(peer_addr.ip(), &protocol_id);
 
     log::debug!("Performing Noise handshake with {} using protocol {}", peer_addr, protocol_id.as_str());
 
     // Create protocol wrapper for handshake wrapping
-    let mut protocol_wrapper = ProtocolWrapper::new(protocol_id.clone(), nooshdaroo::WrapperRole::Server, None);
+    let payload_encoding = config.detection.payload_encoding(protocol_id.as_str());
+    let mut protocol_wrapper = ProtocolWrapper::new(protocol_id.clone(), nooshdaroo::WrapperRole::Server, None)
+        .with_payload_encoding(payload_encoding);
 
     // Perform server-side Noise handshake with protocol wrapping
     let mut noise_transport = NoiseTransport::server_handshake(&mut tunnel_stream, &noise_config, Some(&mut protocol_wrapper))
@@ -1062,7 +2309,28 @@ async fn handle_tunnel_connection(
         .await
         .context("Failed to read target info")?;
 
-    let target_str = String::from_utf8_lossy(&target_data);
+    // Relay chains send a fixed-size routing header instead of the bare
+    // address, and may ask for padded records from the reply on; clients
+    // also send one to agree on protocol hops or to measure the path
+    let mut protocol_hops = false;
+    let target_str = if nooshdaroo::chain::RoutingHeader::is_header(&target_data) {
+        let header = nooshdaroo::chain::RoutingHeader::decode(&target_data).map_err(|e| anyhow::anyhow!(e))?;
+        if header.pad_records {
+            noise_transport.enable_record_padding(config.relay.chain_padding.record_padding());
+        }
+        if header.ping {
+            log::debug!("Client {} is measuring the path", peer_addr);
+            noise_transport.write(&mut tunnel_stream, b"OK").await?;
+            return nooshdaroo::path_quality::echo(&mut noise_transport, &mut tunnel_stream).await;
+        }
+        protocol_hops = header.protocol_hops;
+        header.target
+    } else {
+        String::from_utf8_lossy(&target_data).into_owned()
+    };
+    if nooshdaroo::reverse::is_reverse_target(&target_str) {
+        return serve_reverse_tunnel(tunnel_stream, noise_transport, &target_str, peer_addr, protocol_id, use_tls_emulation, &config).await;
+    }
     log::info!("Client {} requests connection to: {}", peer_addr, target_str);
 
     // Parse target address (format: "host:port" or "[ipv6]:port")
@@ -1107,9 +2375,13 @@ async fn handle_tunnel_connection(
 
     // Connect to actual target
     log::debug!("Connecting to target {}:{}", target_host, target_port);
-    let target_addr = format!("{}:{}", target_host, target_port);
-
-    let target_stream = match tokio::net::TcpStream::connect(&target_addr).await {
+    let access = nooshdaroo::acl::AccessPolicy::new(&config.access).map_err(|e| anyhow::anyhow!(e))?;
+    let tor = config.server.as_ref().and_then(|server| server.tor.as_ref());
+    let connected = match tor {
+        Some(tor) if target_str == nooshdaroo::tor_bridge::TOR_TARGET => tokio::net::TcpStream::connect(tor.or_port).await,
+        _ => access.connect(target_host, target_port).await,
+    };
+    let target_stream = match connected {
         Ok(stream) => {
             // Enable TCP_NODELAY for low latency (critical for HTTP/2)
             stream.set_nodelay(true)?;
@@ -1119,7 +2391,10 @@ async fn handle_tunnel_connection(
             stream
         }
         Err(e) => {
-            let error_msg = if e.kind() == std::io::ErrorKind::ConnectionRefused {
+            let error_msg = if e.kind() == std::io::ErrorKind::PermissionDenied {
+                log::warn!("Access rules deny {}:{} requested by {}", target_host, target_port, peer_addr);
+                e.to_string()
+            } else if e.kind() == std::io::ErrorKind::ConnectionRefused {
                 format!("Connection refused to {}:{}", target_host, target_port)
             } else if e.kind() == std::io::ErrorKind::TimedOut || e.kind() == std::io::ErrorKind::NotFound {
                 format!("Host unreachable: {}:{}", target_host, target_port)
@@ -1134,37 +2409,108 @@ async fn handle_tunnel_connection(
 
     // Relay data bidirectionally between client tunnel and target
     log::debug!("Starting bidirectional relay for {}:{}", target_host, target_port);
-    if use_tls_emulation {
+    let shaping = nooshdaroo::traffic::RelayShaping::new(&config, protocol_id.as_str());
+    let counters = shaping.counters.clone();
+    if protocol_hops {
+        log::debug!("Using hop session framing for {}", protocol_id.as_str());
+        let session = nooshdaroo::protocol_hop::HopSession::new(
+            nooshdaroo::protocol_hop::HopTarget { protocol: protocol_id.clone(), tls_emulation: use_tls_emulation },
+            nooshdaroo::WrapperRole::Server,
+            payload_encoding,
+        );
+        if let Err(e) = relay_hopping_to_target(tunnel_stream, noise_transport, target_stream, session, shaping).await {
+            log::debug!("Relay ended for {}:{}: {}", target_host, target_port, e);
+        } else {
+            log::debug!("Relay completed for {}:{}", target_host, target_port);
+        }
+    } else if use_tls_emulation {
         // Use NoiseTransport's built-in TLS wrapping (no protocol wrapper)
         log::debug!("Using TLS session emulation (no protocol wrapper)");
-        if let Err(e) = relay_with_noise_only(tunnel_stream, noise_transport, target_stream).await {
+        if let Err(e) = relay_with_noise_only(tunnel_stream, noise_transport, target_stream, shaping).await {
             log::debug!("Relay ended for {}:{}: {}", target_host, target_port, e);
         } else {
             log::debug!("Relay completed for {}:{}", target_host, target_port);
         }
     } else {
         // Use protocol wrapper for obfuscation
-        let wrapper = nooshdaroo::ProtocolWrapper::new(protocol_id.clone(), nooshdaroo::WrapperRole::Server, None);
+        let wrapper = nooshdaroo::ProtocolWrapper::new(protocol_id.clone(), nooshdaroo::WrapperRole::Server, None)
+            .with_payload_encoding(payload_encoding);
         log::debug!("Created {} protocol wrapper for traffic obfuscation", protocol_id.as_str());
-        if let Err(e) = relay_tunnel_to_target(tunnel_stream, noise_transport, target_stream, wrapper).await {
+        if let Err(e) = relay_tunnel_to_target(tunnel_stream, noise_transport, target_stream, wrapper, shaping).await {
             log::debug!("Relay ended for {}:{}: {}", target_host, target_port, e);
         } else {
             log::debug!("Relay completed for {}:{}", target_host, target_port);
         }
     }
 
+    let traffic = counters.snapshot();
+    log::info!(
+        "Connection to {}:{} closed: {} bytes received, {} bytes sent",
+        target_host, target_port, traffic.bytes_received, traffic.bytes_sent
+    );
+    if let Some(audit) = audit.as_mut() {
+        audit.set_traffic(traffic.bytes_received, traffic.bytes_sent);
+    }
+
+    Ok(())
+}
+
+/// Serve a reverse tunnel's control or data tunnel (see
+/// [`nooshdaroo::reverse`])
+async fn serve_reverse_tunnel<S>(
+    mut tunnel_stream: S,
+    mut noise_transport: NoiseTransport,
+    target: &str,
+    peer_addr: std::net::SocketAddr,
+    protocol_id: nooshdaroo::ProtocolId,
+    use_tls_emulation: bool,
+    config: &nooshdaroo::NooshdarooConfig,
+) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let Some(reverse) = config.server.as_ref().and_then(|s| s.reverse.as_ref()) else {
+        noise_transport.write(&mut tunnel_stream, b"Reverse tunnels are not enabled").await?;
+        anyhow::bail!("Client {} asked for a reverse tunnel, which is not enabled", peer_addr);
+    };
+    let Some(id) = nooshdaroo::reverse::parse_data_target(target) else {
+        log::info!("Agent {} is connecting", peer_addr);
+        noise_transport.write(&mut tunnel_stream, b"OK").await?;
+        return nooshdaroo::reverse::serve_control(&mut noise_transport, &mut tunnel_stream, reverse, peer_addr).await;
+    };
+    let Some(public) = nooshdaroo::reverse::take_pending(id) else {
+        noise_transport.write(&mut tunnel_stream, b"Unknown reverse connection").await?;
+        anyhow::bail!("Agent {} opened a data tunnel for unknown connection {}", peer_addr, id);
+    };
+    noise_transport.write(&mut tunnel_stream, b"OK").await?;
+
+    let shaping = nooshdaroo::traffic::RelayShaping::new(config, protocol_id.as_str());
+    let result = if use_tls_emulation {
+        relay_with_noise_only(tunnel_stream, noise_transport, public, shaping).await
+    } else {
+        let wrapper = nooshdaroo::ProtocolWrapper::new(protocol_id.clone(), nooshdaroo::WrapperRole::Server, None)
+            .with_payload_encoding(config.detection.payload_encoding(protocol_id.as_str()));
+        relay_tunnel_to_target(tunnel_stream, noise_transport, public, wrapper, shaping).await
+    };
+    if let Err(e) = result {
+        log::debug!("Reverse connection {} through {} ended: {}", id, peer_addr, e);
+    }
     Ok(())
 }
 
 /// Relay using NoiseTransport only (for TLS session emulation)
-async fn relay_with_noise_only(
-    mut tunnel: tokio::net::TcpStream,
+async fn relay_with_noise_only<S>(
+    mut tunnel: S,
     mut noise: NoiseTransport,
     mut target: tokio::net::TcpStream,
-) -> Result<()> {
+    mut shaping: nooshdaroo::traffic::RelayShaping,
+) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    let mut target_buf = vec![0u8; 8192];
+    let mut target_buf = vec![0u8; shaping.record_size];
     let mut tunnel_closed = false;
     let mut target_closed = false;
 
@@ -1175,20 +2521,21 @@ async fn relay_with_noise_only(
                 match result {
                     Ok(data) if !data.is_empty() => {
                         // NoiseTransport.read() handles TLS unwrapping AND decryption
-                        target.write_all(&data).await?;
+                        shaping.deliver(data.len(), target.write_all(&data)).await?;
+                        shaping.record_received(data.len());
                         target.flush().await?;
                     }
                     Ok(_) => {
-                        // Tunnel closed connection
-                        log::debug!("Tunnel closed connection");
-                        tunnel_closed = true;
-                        if target_closed {
-                            break;
-                        }
+                        // Empty message: the client's keepalive
+                        shaping.keepalive.touch();
                     }
                     Err(e) => {
+                        // Tunnel closed write half - shutdown target write, but keep reading
                         log::debug!("Noise read error: {}", e);
                         tunnel_closed = true;
+                        if let Err(e) = target.shutdown().await {
+                            log::debug!("Failed to shut down target write: {}", e);
+                        }
                         if target_closed {
                             break;
                         }
@@ -1202,23 +2549,154 @@ async fn relay_with_noise_only(
                         // Target closed write half - shutdown tunnel write, but keep reading
                         log::debug!("Target closed connection, shutting down tunnel write");
                         target_closed = true;
+                        if let Err(e) = noise.close(&mut tunnel).await {
+                            log::debug!("Failed to shut down tunnel write: {}", e);
+                        }
                         if tunnel_closed {
                             break;
                         }
                     }
                     Ok(n) => {
+                        let n = shaping.coalesce(&mut target, &mut target_buf, n).await;
                         // NoiseTransport.write() handles encryption AND TLS wrapping
-                        noise.write(&mut tunnel, &target_buf[..n]).await?;
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, noise.write(&mut tunnel, &target_buf[..n])).await?;
+                        shaping.record_sent(n);
                     }
                     Err(e) => {
                         log::debug!("Target read error: {}", e);
                         target_closed = true;
+                        if let Err(e) = noise.close(&mut tunnel).await {
+                            log::debug!("Failed to shut down tunnel write: {}", e);
+                        }
                         if tunnel_closed {
                             break;
                         }
                     }
                 }
             }
+            // Idle tunnel: send an empty TLS application data record
+            _ = shaping.keepalive.due(), if !target_closed => {
+                shaping.deliver(0, noise.write(&mut tunnel, &[])).await?;
+                shaping.keepalive.touch();
+            }
+            // No data for the idle timeout: close both ways and let the tunnel finish
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle connection", shaping.idle.interval().unwrap_or_default());
+                if !target_closed {
+                    if let Err(e) = noise.close(&mut tunnel).await {
+                        log::debug!("Failed to shut down tunnel write: {}", e);
+                    }
+                }
+                if !tunnel_closed {
+                    if let Err(e) = target.shutdown().await {
+                        log::debug!("Failed to shut down target write: {}", e);
+                    }
+                    shaping.drain(&mut tunnel).await;
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Relay data between a tunnel that agreed to protocol hops and the target,
+/// following the client's hops
+async fn relay_hopping_to_target<S>(
+    mut tunnel: S,
+    mut noise: NoiseTransport,
+    mut target: tokio::net::TcpStream,
+    mut session: nooshdaroo::protocol_hop::HopSession,
+    mut shaping: nooshdaroo::traffic::RelayShaping,
+) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    use nooshdaroo::protocol_hop::Inbound;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut target_buf = vec![0u8; shaping.record_size];
+    let mut tunnel_closed = false;
+    let mut target_closed = false;
+
+    loop {
+        tokio::select! {
+            result = session.recv(&mut noise, &mut tunnel), if !tunnel_closed => {
+                match result {
+                    Ok(Inbound::Data(data)) => {
+                        shaping.deliver(data.len(), target.write_all(&data)).await?;
+                        shaping.record_received(data.len());
+                    }
+                    Ok(Inbound::Keepalive) => shaping.keepalive.touch(),
+                    Ok(Inbound::Hopped) => {
+                        // Our writes switch right after the acknowledgement
+                        if !target_closed {
+                            session.acknowledge(&mut noise, &mut tunnel).await?;
+                            let protocol = session.current().protocol.clone();
+                            shaping.jitter.set_protocol(protocol.as_str());
+                            shaping.keepalive.set_protocol(protocol.as_str());
+                        }
+                        shaping.keepalive.touch();
+                    }
+                    result => {
+                        if let Err(e) = result {
+                            log::debug!("Hop session read error: {}", e);
+                        }
+                        log::debug!("Tunnel closed connection, shutting down target write");
+                        tunnel_closed = true;
+                        if let Err(e) = target.shutdown().await {
+                            log::debug!("Failed to shut down target write: {}", e);
+                        }
+                        if target_closed {
+                            break;
+                        }
+                    }
+                }
+            }
+            result = target.read(&mut target_buf), if !target_closed => {
+                match result {
+                    Ok(n) if n > 0 => {
+                        let n = shaping.coalesce(&mut target, &mut target_buf, n).await;
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, session.send(&mut noise, &mut tunnel, &target_buf[..n])).await?;
+                        shaping.record_sent(n);
+                    }
+                    // EOF or error: target closed its write half
+                    result => {
+                        if let Err(e) = result {
+                            log::debug!("Target read error: {}", e);
+                        }
+                        target_closed = true;
+                        if let Err(e) = session.shutdown(&mut tunnel).await {
+                            log::debug!("Failed to shut down tunnel write: {}", e);
+                        }
+                        if tunnel_closed {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = shaping.keepalive.due(), if !target_closed => {
+                shaping.deliver(0, session.keepalive(&mut noise, &mut tunnel)).await?;
+                shaping.keepalive.touch();
+            }
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle connection", shaping.idle.interval().unwrap_or_default());
+                if !target_closed {
+                    if let Err(e) = session.shutdown(&mut tunnel).await {
+                        log::debug!("Failed to shut down tunnel write: {}", e);
+                    }
+                }
+                if !tunnel_closed {
+                    if let Err(e) = target.shutdown().await {
+                        log::debug!("Failed to shut down target write: {}", e);
+                    }
+                    shaping.drain(&mut tunnel).await;
+                }
+                break;
+            }
         }
     }
 
@@ -1226,20 +2704,26 @@ async fn relay_with_noise_only(
 }
 
 /// Relay data between encrypted tunnel and target with protocol wrapping
-async fn relay_tunnel_to_target(
-    mut tunnel: tokio::net::TcpStream,
+async fn relay_tunnel_to_target<S>(
+    mut tunnel: S,
     mut noise: NoiseTransport,
     mut target: tokio::net::TcpStream,
     mut wrapper: nooshdaroo::ProtocolWrapper,
-) -> Result<()> {
+    mut shaping: nooshdaroo::traffic::RelayShaping,
+) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    let mut target_buf = vec![0u8; 8192];
+    let mut target_buf = vec![0u8; shaping.record_size];
+    let mut tunnel_closed = false;
+    let mut target_closed = false;
 
     loop {
         tokio::select! {
             // Read from tunnel (wrapped), unwrap, decrypt, write to target
-            result = noise.read_raw(&mut tunnel) => {
+            result = noise.read_raw(&mut tunnel), if !tunnel_closed => {
                 match result {
                     Ok(wrapped) if !wrapped.is_empty() => {
                         // Unwrap protocol headers
@@ -1249,22 +2733,48 @@ async fn relay_tunnel_to_target(
                         // Decrypt with Noise
                         let data = noise.decrypt(&encrypted)?;
                         log::debug!("Decrypted {} bytes to {} bytes", encrypted.len(), data.len());
+                        shaping.keepalive.touch();
+                        if data.is_empty() {
+                            // The client's keepalive
+                            continue;
+                        }
 
                         // Write to target
-                        target.write_all(&data).await?;
+                        shaping.deliver(data.len(), target.write_all(&data)).await?;
+                        shaping.record_received(data.len());
                     }
-                    Ok(_) => break, // Empty read = EOF
-                    Err(e) => {
-                        log::debug!("Noise read error: {}", e);
-                        break;
+                    // Empty read or error: tunnel closed its write half
+                    result => {
+                        if let Err(e) = result {
+                            log::debug!("Noise read error: {}", e);
+                        }
+                        log::debug!("Tunnel closed connection, shutting down target write");
+                        tunnel_closed = true;
+                        if let Err(e) = target.shutdown().await {
+                            log::debug!("Failed to shut down target write: {}", e);
+                        }
+                        if target_closed {
+                            break;
+                        }
                     }
                 }
             }
             // Read from target, encrypt, wrap, write to tunnel
-            result = target.read(&mut target_buf) => {
+            result = target.read(&mut target_buf), if !target_closed => {
                 match result {
-                    Ok(0) => break, // EOF
+                    Ok(0) => {
+                        // Target closed write half - shutdown tunnel write, but keep reading
+                        log::debug!("Target closed connection, shutting down tunnel write");
+                        target_closed = true;
+                        if let Err(e) = tunnel.shutdown().await {
+                            log::debug!("Failed to shut down tunnel write: {}", e);
+                        }
+                        if tunnel_closed {
+                            break;
+                        }
+                    }
                     Ok(n) => {
+                        let n = shaping.coalesce(&mut target, &mut target_buf, n).await;
                         // Encrypt with Noise
                         let encrypted = noise.encrypt(&target_buf[..n])?;
                         log::debug!("Encrypted {} bytes to {} bytes", n, encrypted.len());
@@ -1274,14 +2784,44 @@ async fn relay_tunnel_to_target(
                         log::debug!("Wrapped {} bytes to {} bytes with protocol obfuscation", encrypted.len(), wrapped.len());
 
                         // Write wrapped data to tunnel
-                        noise.write_raw(&mut tunnel, &wrapped).await?;
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, noise.write_raw(&mut tunnel, &wrapped)).await?;
+                        shaping.record_sent(n);
                     }
                     Err(e) => {
                         log::debug!("Target read error: {}", e);
-                        break;
+                        target_closed = true;
+                        if let Err(e) = tunnel.shutdown().await {
+                            log::debug!("Failed to shut down tunnel write: {}", e);
+                        }
+                        if tunnel_closed {
+                            break;
+                        }
                     }
                 }
             }
+            // Idle tunnel: send an empty message in the protocol's framing
+            _ = shaping.keepalive.due(), if !target_closed => {
+                let wrapped = wrapper.wrap(&noise.encrypt(&[])?)?;
+                shaping.deliver(0, noise.write_raw(&mut tunnel, &wrapped)).await?;
+                shaping.keepalive.touch();
+            }
+            // No data for the idle timeout: close both ways and let the tunnel finish
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle connection", shaping.idle.interval().unwrap_or_default());
+                if !target_closed {
+                    if let Err(e) = tunnel.shutdown().await {
+                        log::debug!("Failed to shut down tunnel write: {}", e);
+                    }
+                }
+                if !tunnel_closed {
+                    if let Err(e) = target.shutdown().await {
+                        log::debug!("Failed to shut down target write: {}", e);
+                    }
+                    shaping.drain(&mut tunnel).await;
+                }
+                break;
+            }
         }
     }
 
@@ -1306,6 +2846,20 @@ async fn run_relay(listen: &str, target: &str, mode: &str) -> Result<()> {
     relay.run().await.map_err(|e| anyhow::anyhow!("{}", e))
 }
 
+fn drain_server(server: &str) -> Result<()> {
+    let pid = match server.parse::<u32>() {
+        Ok(pid) => pid,
+        Err(_) => std::fs::read_to_string(server)
+            .with_context(|| format!("Cannot read pidfile {}", server))?
+            .trim()
+            .parse()
+            .with_context(|| format!("{} does not hold a process ID", server))?,
+    };
+    nooshdaroo::drain::signal(pid).with_context(|| format!("Cannot signal server process {}", pid))?;
+    println!("Server {} is draining", pid);
+    Ok(())
+}
+
 async fn show_status(client: &str) -> Result<()> {
     info!("Querying client status at {}", client);
 
@@ -1334,7 +2888,7 @@ async fn rotate_protocol(client: &str) -> Result<()> {
     Ok(())
 }
 
-fn list_protocols(dir: &PathBuf) -> Result<()> {
+fn list_protocols(dir: &PathBuf, filter: &nooshdaroo::ProtocolFilter) -> Result<()> {
     use nooshdaroo::ProtocolLibrary;
 
     info!("Loading protocols from {:?}", dir);
@@ -1346,24 +2900,164 @@ fn list_protocols(dir: &PathBuf) -> Result<()> {
     println!("===================");
     println!();
 
-    for (id, meta) in library.iter() {
-        println!("ID: {}", id.as_str());
+    let protocols = library.query(filter);
+    for meta in &protocols {
+        let ports: Vec<String> = meta.ports().iter().map(|p| p.to_string()).collect();
+        println!("ID: {}", meta.id.as_str());
         println!("  Name: {}", meta.name);
+        println!("  Category: {}", meta.metadata.category);
         println!("  Transport: {:?}", meta.transport);
-        println!("  Port: {}", meta.default_port);
+        println!("  Ports: {}", ports.join(", "));
+        println!("  Risk: {}", meta.risk_level());
+        if !meta.metadata.tags.is_empty() {
+            println!("  Tags: {}", meta.metadata.tags.join(", "));
+        }
+        if !meta.metadata.blocked_regions.is_empty() {
+            println!("  Blocked in: {}", meta.metadata.blocked_regions.join(", "));
+        }
         println!();
     }
 
-    println!("Total: {} protocols", library.len());
+    println!("Total: {} of {} protocols", protocols.len(), library.len());
 
     Ok(())
 }
 
+/// Sign PSF files, or generate a signing key
+fn sign_protocols(files: &[PathBuf], key: Option<&str>, generate_key: bool) -> Result<()> {
+    use nooshdaroo::psf::signature::PsfSigner;
+
+    if generate_key {
+        let key = PsfSigner::generate_key().map_err(|e| anyhow::anyhow!(e))?;
+        let signer = PsfSigner::from_pkcs8_base64(&key).map_err(|e| anyhow::anyhow!(e))?;
+        println!("Signing key (keep secret): {}", key);
+        println!("Public key (add to [protocol_trust] trusted_keys): {}", signer.public_key_base64());
+        return Ok(());
+    }
+
+    let key = key.ok_or_else(|| anyhow::anyhow!("--key or NOOSHDAROO_SIGNING_KEY is required"))?;
+    let signer = PsfSigner::from_pkcs8_base64(key).map_err(|e| anyhow::anyhow!(e))?;
+    if files.is_empty() {
+        anyhow::bail!("No protocol files given");
+    }
+    for file in files {
+        let sig_path = signer
+            .sign_file(file)
+            .with_context(|| format!("Failed to sign {}", file.display()))?;
+        println!("Signed {} -> {}", file.display(), sig_path.display());
+    }
+
+    Ok(())
+}
+
+/// Print the signed envelope of a bootstrap document
+fn sign_bootstrap(file: &std::path::Path, key: &str) -> Result<()> {
+    use nooshdaroo::psf::signature::PsfSigner;
+
+    let signer = PsfSigner::from_pkcs8_base64(key).map_err(|e| anyhow::anyhow!(e))?;
+    let contents = std::fs::read_to_string(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let document: nooshdaroo::bootstrap::BootstrapDocument =
+        serde_json::from_str(&contents).with_context(|| format!("Invalid bootstrap document {}", file.display()))?;
+    println!("{}", document.seal(&signer).map_err(|e| anyhow::anyhow!(e))?);
+    eprintln!("Signed {} servers; trusted key: {}", document.servers.len(), signer.public_key_base64());
+    Ok(())
+}
+
+/// Compare the configured candidate protocols on live connections
+fn start_experiment(
+    config: &NooshdarooConfig,
+    client: &NooshdarooClient,
+    chain_in_use: bool,
+) -> Result<Option<Arc<nooshdaroo::experiment::Experiment>>> {
+    if !config.experiment.enabled {
+        return Ok(None);
+    }
+    if chain_in_use {
+        anyhow::bail!("[experiment] cannot be combined with a relay chain, whose hops fix their protocols");
+    }
+    if let Some(unknown) = config
+        .experiment
+        .protocols
+        .iter()
+        .find(|p| client.library().get(&nooshdaroo::ProtocolId::from(p.as_str())).is_none())
+    {
+        anyhow::bail!("Unknown protocol '{}' in experiment.protocols", unknown);
+    }
+    let experiment = Arc::new(nooshdaroo::experiment::Experiment::new(&config.experiment));
+    nooshdaroo::experiment::spawn_reporter(&experiment);
+    info!(
+        "Protocol experiment: {:.0}% of connections try {}",
+        config.experiment.fraction * 100.0,
+        config.experiment.protocols.join(", ")
+    );
+    Ok(Some(experiment))
+}
+
+/// Print the signed envelope of a protocol bundle
+fn sign_protocol_bundle(
+    files: &[PathBuf],
+    version: u64,
+    expires_in: Option<std::time::Duration>,
+    key: &str,
+) -> Result<()> {
+    use nooshdaroo::protocol_update::ProtocolBundle;
+    use nooshdaroo::psf::signature::PsfSigner;
+
+    let signer = PsfSigner::from_pkcs8_base64(key).map_err(|e| anyhow::anyhow!(e))?;
+    if files.is_empty() {
+        anyhow::bail!("No protocol files given");
+    }
+    let mut protocols = std::collections::BTreeMap::new();
+    for file in files {
+        let name = file.file_name().and_then(|n| n.to_str()).context("Invalid protocol file name")?;
+        let category = file
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("unknown");
+        let content = std::fs::read_to_string(file).with_context(|| format!("Failed to read {}", file.display()))?;
+        protocols.insert(format!("{}/{}", category, name), content);
+    }
+    let issued_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let bundle = ProtocolBundle {
+        version,
+        issued_at,
+        expires_at: expires_in.map(|d| issued_at + d.as_secs()),
+        protocols,
+    };
+    println!("{}", bundle.seal(&signer).map_err(|e| anyhow::anyhow!(e))?);
+    eprintln!(
+        "Signed bundle {} with {} protocols; trusted key: {}",
+        version,
+        bundle.protocols.len(),
+        signer.public_key_base64()
+    );
+    Ok(())
+}
+
+/// Resolve a key provider reference (keychain, encrypted file, ...) before
+/// serving, so passphrase prompts and lookup failures happen at startup
+fn preload_private_key(config: &NooshdarooConfig) -> Result<()> {
+    if let Some(key) = config.transport.as_ref().and_then(|t| t.local_private_key.as_deref()) {
+        nooshdaroo::key_provider::resolve_private_key(key).context("Failed to load private key")?;
+    }
+    Ok(())
+}
+
 /// Generate Noise protocol keypair (keys only)
-fn generate_keypair(format: &str) -> Result<()> {
+fn generate_keypair(format: &str, store: Option<&str>) -> Result<()> {
     let keypair = nooshdaroo::generate_noise_keypair()
         .context("Failed to generate keypair")?;
 
+    if let Some(reference) = store {
+        let source = nooshdaroo::key_provider::KeySource::parse(reference)?;
+        source.store(&keypair.private_key_base64())?;
+        eprintln!("private key stored in {}", source);
+        eprintln!("config: local_private_key = \"{}\"", reference);
+        println!("{}", keypair.public_key_base64());
+        return Ok(());
+    }
+
     match format {
         "private" => {
             // Only output private key (for piping, like wg genkey)
@@ -1403,6 +3097,9 @@ fn generate_config_files(
     pattern: &str,
     cli_server_private_key: Option<String>,
     cli_server_public_key: Option<String>,
+    qr: bool,
+    qr_png: Option<PathBuf>,
+    uri: bool,
 ) -> Result<()> {
     use std::fs;
 
@@ -1440,6 +3137,8 @@ listen_addr = "{}"
 [transport]
 pattern = "{}"
 local_private_key = "{}"   # 🔒 KEEP SECRET!
+# elligator = true   # Disguise handshake keys as random bytes (set on both sides)
+# handshake_padding = {{ min = 32, max = 480 }}   # Random padding per handshake message
 
 [socks]
 listen_addr = "127.0.0.1:0"
@@ -1472,7 +3171,7 @@ protocol = "https"
     }
 
     // Save client config if requested
-    if let Some(ref path) = client_config_path {
+    if client_config_path.is_some() || qr || qr_png.is_some() || uri {
         let client_config = format!(
             r#"# Nooshdaroo Client Configuration
 # Generated: {}
@@ -1493,6 +3192,8 @@ auth_required = false
 [transport]
 pattern = "{}"
 remote_public_key = "{}"   # Server's public key
+# elligator = true   # Must match the server
+# handshake_padding = {{ min = 32, max = 480 }}   # Random padding per handshake message
 
 [shapeshift.strategy]
 type = "fixed"
@@ -1505,10 +3206,54 @@ protocol = "https"
             server_public_key
         );
 
-        fs::write(path, client_config)
-            .with_context(|| format!("Failed to write client config to {:?}", path))?;
+        if let Some(ref path) = client_config_path {
+            fs::write(path, &client_config)
+                .with_context(|| format!("Failed to write client config to {:?}", path))?;
+
+            println!("✅ Client config saved to: {:?}", path);
+        }
+
+        let share_link = if uri {
+            let link = nooshdaroo::ShareLink {
+                server: remote_server.to_string(),
+                public_key: server_public_key.clone(),
+                pattern: pattern.parse().map_err(|e: String| anyhow::anyhow!(e))?,
+                elligator: false,
+                protocols: vec!["https".to_string()],
+                name: None,
+            }
+            .to_uri();
+            println!("🔗 Client share link:\n   {}", link);
+            println!("   Use with: nooshdaroo client --uri '{}'", link);
+            Some(link)
+        } else {
+            None
+        };
+
+        if qr || qr_png.is_some() {
+            // Comments only cost QR capacity
+            let payload: String = match share_link {
+                Some(link) => link,
+                None => client_config
+                    .lines()
+                    .map(|line| line.split(" #").next().unwrap_or(line).trim_end())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| format!("{}\n", line))
+                    .collect(),
+            };
+            let code = nooshdaroo::qr::QrCode::encode(payload.as_bytes(), nooshdaroo::qr::EcLevel::Medium)
+                .map_err(|e| anyhow::anyhow!(e))?;
 
-        println!("✅ Client config saved to: {:?}", path);
+            if qr {
+                println!("\n📱 Scan to import the client config:\n");
+                print!("{}", code.to_terminal());
+            }
+            if let Some(ref path) = qr_png {
+                code.save_png(path, 8)
+                    .with_context(|| format!("Failed to write QR code to {:?}", path))?;
+                println!("✅ Client config QR code saved to: {:?}", path);
+            }
+        }
     }
 
     println!();
@@ -1527,7 +3272,140 @@ protocol = "https"
 }
 
 /// Test all protocol/port combinations to find best path
-async fn test_all_paths(server: &str, format: &str, protocol_dir: &PathBuf) -> Result<()> {
+/// Sample the controller's statistics into the history database until exit
+#[cfg(feature = "history")]
+fn spawn_history_recorder(
+    history: &nooshdaroo::config::HistoryConfig,
+    controller: Arc<RwLock<nooshdaroo::ShapeShiftController>>,
+) -> Result<()> {
+    use nooshdaroo::history::{HistoryRecorder, HistoryStore};
+
+    let store = HistoryStore::open(&history.path)
+        .with_context(|| format!("Failed to open history database {}", history.path.display()))?;
+    info!("Recording history to {}", history.path.display());
+
+    let sample_interval = history.sample_interval;
+    let retention = history.retention;
+    tokio::spawn(async move {
+        let mut recorder = HistoryRecorder::new();
+        let mut ticker = tokio::time::interval(sample_interval);
+        loop {
+            ticker.tick().await;
+            let stats = controller.read().await.stats();
+            let now = std::time::SystemTime::now();
+            if let Err(e) = recorder.sample(&store, &stats, now) {
+                warn!("Failed to record history: {}", e);
+            }
+            if let Some(cutoff) = now.checked_sub(retention) {
+                if let Err(e) = store.prune(cutoff) {
+                    warn!("Failed to prune history: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Append path-test results to the history database when recording is enabled
+fn record_path_tests(history: &nooshdaroo::config::HistoryConfig, results: &[nooshdaroo::netflow_evasion::PathTestResult]) {
+    if !history.enabled {
+        return;
+    }
+    #[cfg(feature = "history")]
+    {
+        let recorded = nooshdaroo::history::HistoryStore::open(&history.path)
+            .and_then(|store| store.record_path_tests(std::time::SystemTime::now(), results));
+        if let Err(e) = recorded {
+            warn!("Failed to record path tests in {}: {}", history.path.display(), e);
+        }
+    }
+    #[cfg(not(feature = "history"))]
+    {
+        let _ = results;
+        warn!("[history] is enabled but this build lacks the history feature; not recording");
+    }
+}
+
+/// Print what clients with `[history]` enabled have recorded
+fn show_history(config_path: Option<PathBuf>, since: &str, db: Option<PathBuf>, format: &str) -> Result<()> {
+    let since = humantime::parse_duration(since).with_context(|| format!("Invalid duration: {}", since))?;
+    let path = match (db, config_path) {
+        (Some(db), _) => db,
+        (None, Some(config_path)) => NooshdarooConfig::from_file(&config_path)?.history.path,
+        (None, None) => nooshdaroo::config::HistoryConfig::default().path,
+    };
+
+    #[cfg(not(feature = "history"))]
+    {
+        let _ = (since, path, format);
+        anyhow::bail!("This build lacks the history feature; rebuild with `--features history`");
+    }
+
+    #[cfg(feature = "history")]
+    {
+        if !path.exists() {
+            anyhow::bail!("No history database at {} (enable [history] in the client config)", path.display());
+        }
+        let store = nooshdaroo::history::HistoryStore::open(&path)
+            .with_context(|| format!("Failed to open history database {}", path.display()))?;
+        let summary = store.summary(std::time::SystemTime::now() - since)?;
+
+        if format == "json" {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+            return Ok(());
+        }
+
+        let local = |secs: i64| {
+            chrono::DateTime::from_timestamp(secs, 0)
+                .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        };
+
+        println!("\n📈 History for the last {} ({})\n", humantime::format_duration(since), path.display());
+        println!("{:<17} {:<15} {:>6} {:>14} {:>14} {:>10} {:>10}",
+            "HOUR", "PROTOCOL", "CONNS", "SENT", "RECEIVED", "PACKETS", "HS FAILS");
+        println!("{}", "-".repeat(92));
+        for hour in &summary.transfer {
+            println!("{:<17} {:<15} {:>6} {:>14} {:>14} {:>10} {:>10}",
+                local(hour.hour),
+                hour.protocol,
+                hour.connections,
+                hour.bytes_sent,
+                hour.bytes_received,
+                hour.packets,
+                hour.handshake_failures
+            );
+        }
+
+        println!("\n🔄 ROTATIONS: {}", summary.rotations.len());
+        for rotation in &summary.rotations {
+            println!("  {}  {} → {}", local(rotation.at), rotation.from, rotation.to);
+        }
+
+        println!("\n🔍 PATH TESTS:");
+        let mut paths: std::collections::BTreeMap<(&str, u16), (usize, usize)> = Default::default();
+        for test in &summary.path_tests {
+            let entry = paths.entry((test.protocol.as_str(), test.port)).or_default();
+            entry.0 += test.success as usize;
+            entry.1 += 1;
+        }
+        for ((protocol, port), (ok, total)) in &paths {
+            println!("  {:<15} {:<6} {}/{} succeeded", protocol, port, ok, total);
+        }
+        if paths.is_empty() {
+            println!("  none");
+        }
+
+        Ok(())
+    }
+}
+
+async fn test_all_paths(
+    server: &str,
+    format: &str,
+    protocol_dir: &Path,
+    history: &nooshdaroo::config::HistoryConfig,
+) -> Result<()> {
     info!("Testing all paths to {}...", server);
 
     // Load protocol library
@@ -1537,6 +3415,7 @@ async fn test_all_paths(server: &str, format: &str, protocol_dir: &PathBuf) -> R
 
     // Run tests
     let results = tester.test_all_paths(server, &config).await;
+    record_path_tests(history, &results);
 
     if results.is_empty() {
         warn!("No successful paths found!");
@@ -1588,3 +3467,509 @@ async fn test_all_paths(server: &str, format: &str, protocol_dir: &PathBuf) -> R
 
     Ok(())
 }
+
+async fn locate_blocking(
+    target: &str,
+    protocols: &[String],
+    domain: &str,
+    control_domain: &str,
+    max_hops: u8,
+    format: &str,
+) -> Result<()> {
+    use nooshdaroo::traceroute::{auto_config, Traceroute};
+
+    let target: SocketAddr = target.parse()
+        .context(format!("Invalid target address: {}", target))?;
+
+    let mut config = auto_config();
+    config.max_hops = max_hops;
+    let tracer = Traceroute::new(config);
+
+    let mut reports = Vec::new();
+    for protocol in protocols {
+        info!("Probing {} with {} trigger for {}...", target, protocol, domain);
+        reports.push(tracer.locate_interference(target, protocol, domain, control_domain).await);
+    }
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+
+    println!("\n🔍 Locating interference on path to {} (domain: {})\n", target, domain);
+    println!("{:<12} {:<12} {:<14} {:<12} ADDRESS",
+        "PROTOCOL", "SERVER HOP", "INTERFERENCE", "AT HOP");
+    println!("{}", "-".repeat(70));
+
+    for report in &reports {
+        let server_hop = report.server_hop.map(|h| h.to_string()).unwrap_or_else(|| "?".to_string());
+        let hop = report.interference_hop.map(|h| h.to_string()).unwrap_or_else(|| "-".to_string());
+        let addr = report.interference_address.map(|a| a.to_string()).unwrap_or_else(|| "-".to_string());
+        println!("{:<12} {:<12} {:<14} {:<12} {}",
+            report.protocol,
+            server_hop,
+            format!("{:?}", report.interference),
+            hop,
+            addr
+        );
+    }
+
+    let clean: Vec<&str> = reports.iter()
+        .filter(|r| !r.is_blocked())
+        .map(|r| r.protocol.as_str())
+        .collect();
+    if !clean.is_empty() {
+        println!("\n💡 Triggers passing cleanly: {}", clean.join(", "));
+    }
+    if let Some(ttl) = reports.iter().filter_map(|r| r.suggested_ttl()).min() {
+        println!("💡 TTL reaching the server but no further: [traffic_shaping.ttl] ttl = {}", ttl);
+    }
+
+    Ok(())
+}
+
+fn list_profiles() {
+    use nooshdaroo::profiles::{list_user_profiles, profile_dir, BUILTIN_PROFILES};
+
+    println!("\n📋 Built-in profiles:");
+    for name in BUILTIN_PROFILES {
+        println!("   {}", name);
+    }
+
+    let dir = profile_dir();
+    let user = list_user_profiles();
+    match dir {
+        Some(ref dir) => println!("\n👤 User profiles ({}):", dir.display()),
+        None => println!("\n👤 User profiles:"),
+    }
+    if user.is_empty() {
+        println!("   (none)");
+    }
+    for (name, profile) in &user {
+        let shadowed = if BUILTIN_PROFILES.contains(&name.to_lowercase().as_str()) {
+            " [shadowed by built-in]"
+        } else {
+            ""
+        };
+        let ports = if profile.ports.is_empty() {
+            String::new()
+        } else {
+            format!(" ports {:?}", profile.ports)
+        };
+        println!("   {:<16} {}{}{}", name, profile.description.as_deref().unwrap_or(""), ports, shadowed);
+    }
+    println!("\nUse with: nooshdaroo client --profile <name>\n");
+}
+
+async fn detect_profile(format: &str) -> Result<()> {
+    use nooshdaroo::environment::{detect_profile, ProbeConfig};
+
+    info!("Probing network environment...");
+    let (report, recommendation) = detect_profile(&ProbeConfig::default()).await;
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&serde_json::json!({
+            "report": report,
+            "recommendation": recommendation,
+        }))?);
+        return Ok(());
+    }
+
+    println!("\n🌐 Network environment\n");
+    for probe in &report.dns {
+        let answers: Vec<String> = probe.answers.iter().map(|a| a.to_string()).collect();
+        println!("   DNS  {:<22} injected: {:<5} poisoned: {:<5} {}",
+            probe.domain, probe.injected, probe.poisoned, answers.join(", "));
+    }
+    if report.sni_control_answered {
+        for probe in &report.sni {
+            println!("   SNI  {:<22} {}", probe.domain, if probe.answered { "ok" } else { "BLOCKED" });
+        }
+    } else {
+        println!("   SNI  control domain unreachable, test skipped");
+    }
+    println!("   Open ports:   {:?}", report.open_ports);
+    println!("   Closed ports: {:?}", report.closed_ports);
+
+    println!();
+    match recommendation.profile {
+        Some(name) => println!("💡 Recommended profile: {} (nooshdaroo client --profile {})", name, name),
+        None => println!("💡 No preset profile needed"),
+    }
+    for reason in &recommendation.reasons {
+        println!("   - {}", reason);
+    }
+
+    Ok(())
+}
+
+fn analyze_capture(path: &std::path::Path, expect: Option<&str>, format: &str) -> Result<()> {
+    use nooshdaroo::analysis::{flows_from_packets, Classifier};
+    use nooshdaroo::pcap::PcapFile;
+
+    let capture = PcapFile::open(path)
+        .context(format!("Failed to read capture: {}", path.display()))?;
+    let flows = flows_from_packets(&capture.packets());
+    let classifier = Classifier::new();
+    let results = classifier.classify_all(&flows);
+
+    if format == "json" {
+        let output: Vec<serde_json::Value> = flows.iter().zip(&results)
+            .map(|(flow, class)| serde_json::json!({
+                "client": flow.client.to_string(),
+                "server": flow.server.to_string(),
+                "packets": flow.packets.len(),
+                "bytes": flow.total_bytes(),
+                "classification": class,
+            }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!("\n🔬 Analyzing {} ({} flows)\n", path.display(), flows.len());
+        println!("{:<24} {:<24} {:<18} {:<8} EVIDENCE",
+            "CLIENT", "SERVER", "PROTOCOL", "CONF");
+        println!("{}", "-".repeat(100));
+
+        for (flow, class) in flows.iter().zip(&results) {
+            println!("{:<24} {:<24} {:<18} {:<8.2} {}",
+                flow.client.to_string(),
+                flow.server.to_string(),
+                class.protocol,
+                class.confidence,
+                class.evidence.join("; ")
+            );
+        }
+    }
+
+    if let Some(expected) = expect {
+        let mismatches = results.iter().filter(|c| !c.matches(expected)).count();
+        if mismatches > 0 {
+            anyhow::bail!("{} of {} flows did not classify as {}", mismatches, results.len(), expected);
+        }
+        println!("\n✓ All {} flows classify as {}", results.len(), expected);
+    }
+
+    Ok(())
+}
+
+async fn run_bench(
+    config_path: Option<PathBuf>,
+    protocols: &[String],
+    pattern: &str,
+    server: Option<&str>,
+    sink: Option<&str>,
+    serve_sink: Option<&str>,
+    format: &str,
+) -> Result<()> {
+    use nooshdaroo::bench::{run_sink_server, TrafficPattern};
+    use tokio::net::TcpListener;
+
+    if let Some(addr) = serve_sink {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Bench sink listening on {}", listener.local_addr()?);
+        run_sink_server(listener).await?;
+        return Ok(());
+    }
+
+    let pattern: TrafficPattern = pattern.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+
+    // Remote mode uses the configured client keys; loopback generates a fresh pair
+    let remote = match server {
+        Some(server) => {
+            let config = match config_path {
+                Some(ref path) => NooshdarooConfig::from_file(path)?,
+                None => anyhow::bail!("--server requires --config with a [transport] section"),
+            };
+            let noise = config.transport.clone()
+                .ok_or_else(|| anyhow::anyhow!("Config has no [transport] section"))?;
+            let sink: SocketAddr = sink
+                .ok_or_else(|| anyhow::anyhow!("--server requires --sink"))?
+                .parse()
+                .context("Invalid sink address")?;
+            let server: SocketAddr = server.parse().context("Invalid server address")?;
+            Some((server, sink, noise, config))
+        }
+        None => None,
+    };
+
+    let mut results = Vec::new();
+    for protocol in protocols {
+        info!("Benchmarking {}...", protocol);
+        let result = match bench_protocol(protocol, &pattern, remote.clone()).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Benchmark for {} failed: {}", protocol, e);
+                nooshdaroo::bench::BenchResult::failed(protocol, e)
+            }
+        };
+        results.push(result);
+    }
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+
+    println!("\n⏱  Benchmark ({:?})\n", pattern);
+    println!("{:<12} {:>10} {:>12} {:>12} {:>10} {:>10} {:>12} {:>10}",
+        "PROTOCOL", "SETUP", "GOODPUT", "BASELINE", "RTT", "P99", "ADDED", "OVERHEAD");
+    println!("{}", "-".repeat(96));
+    for r in &results {
+        if let Some(ref e) = r.error {
+            println!("{:<12} failed: {}", r.protocol, e);
+            continue;
+        }
+        println!("{:<12} {:>8.1}ms {:>7.2}Mbps {:>7.2}Mbps {:>8.2}ms {:>8.2}ms {:>10.2}ms {:>9.1}%",
+            r.protocol, r.setup_ms, r.goodput_mbps, r.baseline_goodput_mbps,
+            r.rtt_mean_ms, r.rtt_p99_ms, r.added_latency_ms, r.overhead_pct);
+    }
+
+    Ok(())
+}
+
+async fn run_speedtest(
+    config_path: Option<PathBuf>,
+    endpoint: &str,
+    proxy: Option<&str>,
+    protocols: Option<Vec<String>>,
+    top: usize,
+    bytes: &str,
+    format: &str,
+) -> Result<()> {
+    use nooshdaroo::speedtest::{SpeedtestEndpoint, SpeedtestResult};
+
+    let endpoint: SpeedtestEndpoint = endpoint.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+    let bytes = nooshdaroo::bench::parse_size(bytes).map_err(|e| anyhow::anyhow!(e))?;
+
+    let mut results = Vec::new();
+    if let Some(proxy) = proxy {
+        // Measure whatever the running client is currently using
+        let proxy: SocketAddr = proxy.parse().context("Invalid proxy address")?;
+        info!("Speed testing through {}...", proxy);
+        let result = match nooshdaroo::speedtest::run_speedtest(proxy, &endpoint, bytes, "active").await {
+            Ok(result) => result,
+            Err(e) => SpeedtestResult::failed("active", e),
+        };
+        results.push(result);
+    } else {
+        let config = match config_path {
+            Some(ref path) => NooshdarooConfig::from_file(path)?,
+            None => anyhow::bail!("speedtest requires --config with a server (or --proxy)"),
+        };
+        let server: SocketAddr = config.socks.server_address.as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Config has no server_address under [socks]"))?
+            .parse()
+            .context("Invalid server address")?;
+        let noise = config.transport.clone()
+            .ok_or_else(|| anyhow::anyhow!("Config has no [transport] section"))?;
+
+        let protocols = match protocols {
+            Some(protocols) => protocols,
+            None => {
+                let library = nooshdaroo::ProtocolLibrary::load(&PathBuf::from("protocols"))?;
+                library
+                    .evasion_candidates(0.0)
+                    .into_iter()
+                    .filter(|p| p.transport != nooshdaroo::Transport::Udp)
+                    .take(top)
+                    .map(|p| p.id.as_str().to_string())
+                    .collect()
+            }
+        };
+
+        for protocol in &protocols {
+            info!("Speed testing {}...", protocol);
+            let result = match speedtest_protocol(protocol, server, &noise, &config, &endpoint, bytes).await {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Speed test for {} failed: {}", protocol, e);
+                    SpeedtestResult::failed(protocol, e)
+                }
+            };
+            results.push(result);
+        }
+    }
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+
+    println!("\n🚀 Speed test ({} bytes each way)\n", bytes);
+    println!("{:<12} {:>14} {:>14} {:>10}", "PROTOCOL", "DOWNLOAD", "UPLOAD", "RTT");
+    println!("{}", "-".repeat(53));
+    for r in &results {
+        if let Some(ref e) = r.error {
+            println!("{:<12} failed: {}", r.protocol, e);
+            continue;
+        }
+        println!("{:<12} {:>10.2}Mbps {:>10.2}Mbps {:>8.1}ms",
+            r.protocol, r.download_mbps, r.upload_mbps, r.rtt_ms);
+    }
+
+    if let Some(best) = results
+        .iter()
+        .filter(|r| r.error.is_none())
+        .max_by(|a, b| a.download_mbps.partial_cmp(&b.download_mbps).unwrap_or(std::cmp::Ordering::Equal))
+    {
+        println!("\nFastest download: {}", best.protocol);
+    }
+
+    Ok(())
+}
+
+/// Run a speed test through a temporary client using `protocol`
+async fn speedtest_protocol(
+    protocol: &str,
+    server: SocketAddr,
+    noise: &nooshdaroo::NoiseConfig,
+    config: &NooshdarooConfig,
+    endpoint: &nooshdaroo::speedtest::SpeedtestEndpoint,
+    bytes: usize,
+) -> Result<nooshdaroo::speedtest::SpeedtestResult> {
+    use tokio::net::{TcpListener, TcpStream};
+
+    let client_addr = {
+        let probe = TcpListener::bind("127.0.0.1:0").await?;
+        probe.local_addr()?
+    };
+    let listener = UnifiedProxyListener::new(
+        client_addr,
+        vec![ProxyType::Socks5],
+        nooshdaroo::ProtocolId::from(protocol),
+        Arc::new(config.clone()),
+    )
+    .with_server(server, noise.clone());
+    let task = tokio::spawn(async move {
+        if let Err(e) = listener.listen().await {
+            log::debug!("Speedtest client listener ended: {}", e);
+        }
+    });
+
+    let run = async {
+        for _ in 0..50 {
+            if TcpStream::connect(client_addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        tokio::time::timeout(
+            std::time::Duration::from_secs(120),
+            nooshdaroo::speedtest::run_speedtest(client_addr, endpoint, bytes, protocol),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("speed test timed out"))?
+        .map_err(anyhow::Error::from)
+    };
+    let result = run.await;
+    task.abort();
+
+    result
+}
+
+/// Run one protocol benchmark through a client (and, in loopback mode, a server)
+async fn bench_protocol(
+    protocol: &str,
+    pattern: &nooshdaroo::bench::TrafficPattern,
+    remote: Option<(SocketAddr, SocketAddr, nooshdaroo::NoiseConfig, NooshdarooConfig)>,
+) -> Result<nooshdaroo::bench::BenchResult> {
+    use nooshdaroo::bench::{run_counting_relay, run_pattern, run_sink_server, socks5_connect, BenchResult, ByteCounter};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let protocol_id = nooshdaroo::ProtocolId::from(protocol);
+    let mut tasks = Vec::new();
+
+    let (server_addr, sink_addr, client_noise, config) = match remote {
+        Some(remote) => remote,
+        None => {
+            let keypair = nooshdaroo::NoiseKeypair::generate()?;
+            let server_noise = nooshdaroo::NoiseConfig {
+                local_private_key: Some(keypair.private_key_base64()),
+                ..Default::default()
+            };
+            let client_noise = nooshdaroo::NoiseConfig {
+                remote_public_key: Some(keypair.public_key_base64()),
+                ..Default::default()
+            };
+            let config = NooshdarooConfig::default();
+
+            let sink = TcpListener::bind("127.0.0.1:0").await?;
+            let sink_addr = sink.local_addr()?;
+            tasks.push(tokio::spawn(async move {
+                let _ = run_sink_server(sink).await;
+            }));
+
+            let server = TcpListener::bind("127.0.0.1:0").await?;
+            let server_addr = server.local_addr()?;
+            let server_config = Arc::new(config.clone());
+            let proto_id = protocol_id.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Ok((stream, addr)) = server.accept().await {
+                    let noise = Some(server_noise.clone());
+                    let proto = proto_id.clone();
+                    let cfg = server_config.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_tunnel_connection(stream, addr, noise, proto, cfg).await {
+                            log::debug!("Bench server connection ended: {}", e);
+                        }
+                    });
+                }
+            }));
+
+            (server_addr, sink_addr, client_noise, config)
+        }
+    };
+
+    // Count bytes between client and server to measure wrapping overhead
+    let counter = Arc::new(ByteCounter::default());
+    let relay = TcpListener::bind("127.0.0.1:0").await?;
+    let relay_addr = relay.local_addr()?;
+    let relay_counter = counter.clone();
+    tasks.push(tokio::spawn(async move {
+        let _ = run_counting_relay(relay, server_addr, relay_counter).await;
+    }));
+
+    // Local client on a free port
+    let client_addr = {
+        let probe = TcpListener::bind("127.0.0.1:0").await?;
+        probe.local_addr()?
+    };
+    let listener = UnifiedProxyListener::new(client_addr, vec![ProxyType::Socks5], protocol_id.clone(), Arc::new(config))
+        .with_server(relay_addr, client_noise);
+    tasks.push(tokio::spawn(async move {
+        if let Err(e) = listener.listen().await {
+            log::debug!("Bench client listener ended: {}", e);
+        }
+    }));
+
+    let run = async {
+        // Wait for the client listener to come up
+        for _ in 0..50 {
+            if TcpStream::connect(client_addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        let mut direct = TcpStream::connect(sink_addr).await?;
+        direct.set_nodelay(true)?;
+        let baseline = run_pattern(&mut direct, pattern).await?;
+
+        counter.reset();
+        let start = std::time::Instant::now();
+        let mut tunnel = socks5_connect(client_addr, &sink_addr.ip().to_string(), sink_addr.port()).await?;
+        let setup = start.elapsed();
+        let stats = tokio::time::timeout(std::time::Duration::from_secs(120), run_pattern(&mut tunnel, pattern))
+            .await
+            .map_err(|_| anyhow::anyhow!("pattern timed out"))??;
+
+        Ok::<_, anyhow::Error>(BenchResult::new(protocol, setup, &stats, &baseline, counter.total()))
+    };
+    let result = run.await;
+
+    for task in tasks {
+        task.abort();
+    }
+
+    result
+}
@@ -0,0 +1,239 @@
+//! OONI-compatible connectivity measurements
+//!
+//! Every client sees first hand which protocols get through its network
+//! and how the others fail. With `[measurements] enabled = true` each
+//! tunnel attempt is appended to a JSON lines file as a measurement in
+//! OONI's base data format, so users can contribute them to the OONI
+//! censorship measurement dataset after reviewing them:
+//!
+//! - `input` is the protocol tried and `test_keys` holds whether the
+//!   tunnel came up, the failure in OONI's vocabulary
+//!   (`connection_reset`, `generic_timeout_error`, ...) and the handshake
+//!   time
+//! - `probe_ip` and `resolver_ip` are always `127.0.0.1`, OONI's value for
+//!   a withheld address, and no server or destination is written
+//! - `probe_cc` and `probe_asn` are whatever the user chose to disclose
+//!
+//! Attempts are recorded once [`install`] has set up the log.
+
+use crate::config::MeasurementsConfig;
+use crate::protocol::ProtocolId;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+pub const TEST_NAME: &str = "nooshdaroo";
+pub const TEST_VERSION: &str = "0.1.0";
+const DATA_FORMAT_VERSION: &str = "0.2.0";
+
+/// OONI's value for an address that is not disclosed
+const REDACTED_IP: &str = "127.0.0.1";
+
+/// OONI timestamps: UTC without a zone suffix
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// One measurement in OONI's base data format
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    pub annotations: BTreeMap<String, String>,
+    pub data_format_version: String,
+    pub input: String,
+    pub measurement_start_time: String,
+    pub probe_asn: String,
+    pub probe_cc: String,
+    pub probe_ip: String,
+    pub probe_network_name: String,
+    pub report_id: String,
+    pub resolver_asn: String,
+    pub resolver_ip: String,
+    pub resolver_network_name: String,
+    pub software_name: String,
+    pub software_version: String,
+    pub test_keys: TestKeys,
+    pub test_name: String,
+    /// Seconds
+    pub test_runtime: f64,
+    pub test_start_time: String,
+    pub test_version: String,
+}
+
+/// Results of one tunnel attempt
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestKeys {
+    pub protocol: String,
+    pub success: bool,
+    /// OONI failure string, null on success
+    pub failure: Option<String>,
+    /// Seconds the tunnel handshake took, null on failure
+    pub handshake_time: Option<f64>,
+}
+
+/// Appends measurements to the configured file
+#[derive(Debug)]
+pub struct MeasurementLog {
+    file: Mutex<File>,
+    probe_cc: String,
+    probe_asn: String,
+    report_id: String,
+    test_start_time: String,
+}
+
+impl MeasurementLog {
+    pub fn open(config: &MeasurementsConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let now = chrono::Utc::now();
+        let nonce: String = rand::thread_rng().sample_iter(&Alphanumeric).take(50).map(char::from).collect();
+        let report_id = format!(
+            "{}_{}_{}_{}_n1_{}",
+            now.format("%Y%m%dT%H%M%SZ"),
+            TEST_NAME,
+            config.probe_cc,
+            config.probe_asn.trim_start_matches("AS"),
+            nonce
+        );
+        Ok(Self {
+            file: Mutex::new(file),
+            probe_cc: config.probe_cc.clone(),
+            probe_asn: config.probe_asn.clone(),
+            report_id,
+            test_start_time: now.format(TIME_FORMAT).to_string(),
+        })
+    }
+
+    /// Measurement of a tunnel attempt over `protocol` started at `started`,
+    /// with its handshake time or the error it failed with
+    pub fn measurement(&self, protocol: &ProtocolId, started: SystemTime, outcome: Result<Duration, &str>) -> Measurement {
+        let runtime = started.elapsed().unwrap_or_default();
+        let (failure, handshake_time) = match outcome {
+            Ok(handshake) => (None, Some(handshake.as_secs_f64())),
+            Err(error) => (Some(classify_failure(error).to_string()), None),
+        };
+        Measurement {
+            annotations: BTreeMap::from([("platform".to_string(), std::env::consts::OS.to_string())]),
+            data_format_version: DATA_FORMAT_VERSION.to_string(),
+            input: protocol.to_string(),
+            measurement_start_time: chrono::DateTime::<chrono::Utc>::from(started).format(TIME_FORMAT).to_string(),
+            probe_asn: self.probe_asn.clone(),
+            probe_cc: self.probe_cc.clone(),
+            probe_ip: REDACTED_IP.to_string(),
+            probe_network_name: String::new(),
+            report_id: self.report_id.clone(),
+            resolver_asn: "AS0".to_string(),
+            resolver_ip: REDACTED_IP.to_string(),
+            resolver_network_name: String::new(),
+            software_name: "nooshdaroo".to_string(),
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            test_keys: TestKeys {
+                protocol: protocol.to_string(),
+                success: failure.is_none(),
+                failure,
+                handshake_time,
+            },
+            test_name: TEST_NAME.to_string(),
+            test_runtime: runtime.as_secs_f64(),
+            test_start_time: self.test_start_time.clone(),
+            test_version: TEST_VERSION.to_string(),
+        }
+    }
+
+    /// Append a measurement to the file
+    pub fn write(&self, measurement: &Measurement) -> io::Result<()> {
+        let mut line = serde_json::to_vec(measurement)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+/// OONI failure string for a tunnel setup error. Anything unrecognised is
+/// `unknown_failure` without the message, which may name the server.
+pub fn classify_failure(error: &str) -> &'static str {
+    let error = error.to_ascii_lowercase();
+    if error.contains("timed out") || error.contains("timeout") {
+        "generic_timeout_error"
+    } else if error.contains("reset") {
+        "connection_reset"
+    } else if error.contains("refused") {
+        "connection_refused"
+    } else if error.contains("host unreachable") || error.contains("no route") {
+        "host_unreachable"
+    } else if error.contains("network unreachable") || error.contains("network is unreachable") {
+        "network_unreachable"
+    } else if error.contains("eof") || error.contains("closed") || error.contains("broken pipe") {
+        "eof_error"
+    } else if error.contains("resolve") || error.contains("lookup") {
+        "dns_lookup_error"
+    } else {
+        "unknown_failure"
+    }
+}
+
+static LOG: OnceLock<MeasurementLog> = OnceLock::new();
+
+/// Start recording tunnel attempts to `measurements`
+pub fn install(measurements: MeasurementLog) {
+    if LOG.set(measurements).is_err() {
+        log::warn!("Measurement log already installed");
+    }
+}
+
+/// Record a tunnel attempt, if measurements are enabled
+pub fn record_tunnel(protocol: &ProtocolId, started: SystemTime, outcome: Result<Duration, &str>) {
+    let Some(measurements) = LOG.get() else {
+        return;
+    };
+    if let Err(e) = measurements.write(&measurements.measurement(protocol, started, outcome)) {
+        log::warn!("Failed to write measurement: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ooni_measurement() {
+        let path = std::env::temp_dir().join(format!("nooshdaroo-measurements-{}.jsonl", rand::random::<u32>()));
+        let config = MeasurementsConfig {
+            enabled: true,
+            path: path.clone(),
+            probe_cc: "IR".to_string(),
+            probe_asn: "AS197207".to_string(),
+        };
+        let log = MeasurementLog::open(&config).unwrap();
+        let started = SystemTime::now() - Duration::from_secs(2);
+        let https = ProtocolId::from("https");
+        log.write(&log.measurement(&https, started, Ok(Duration::from_millis(250)))).unwrap();
+        log.write(&log.measurement(
+            &https,
+            started,
+            Err("Noise handshake failed: Connection reset by peer (os error 104) from 203.0.113.9:443"),
+        ))
+        .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["test_name"], "nooshdaroo");
+        assert_eq!(lines[0]["probe_cc"], "IR");
+        assert_eq!(lines[0]["probe_ip"], "127.0.0.1");
+        assert_eq!(lines[0]["input"], "https");
+        assert_eq!(lines[0]["test_keys"]["handshake_time"], 0.25);
+        assert!(lines[0]["test_keys"]["failure"].is_null());
+        assert_eq!(lines[1]["test_keys"]["failure"], "connection_reset");
+        assert!(lines[1]["test_runtime"].as_f64().unwrap() >= 2.0);
+        assert!(lines[0]["report_id"].as_str().unwrap().contains("_nooshdaroo_IR_197207_n1_"));
+        // Nothing identifies the server
+        assert!(!contents.contains("203.0.113.9"));
+
+        assert_eq!(classify_failure("tunnel setup timed out after 10s"), "generic_timeout_error");
+        assert_eq!(classify_failure("Failed to connect to 192.0.2.1:443: Connection refused"), "connection_refused");
+        assert_eq!(classify_failure("bad key 192.0.2.1"), "unknown_failure");
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,436 @@
+//! Friend-to-friend relaying between trusted clients
+//!
+//! When only some users in a censored region have a working way out, a
+//! mesh lets the others share it. Clients that have exchanged Noise public
+//! keys list each other under `[mesh]`, and `nooshdaroo mesh` keeps every
+//! node reachable by its peers through hole punching (see
+//! [`crate::punch`]). Each connection to a peer is a new punched session,
+//! authenticated with Noise KK, that starts with a
+//! [`MeshMessage::Connect`] for its destination:
+//!
+//! - a node with `exit = true` opens the connection itself, directly under
+//!   the rules of `[access]` or through the SOCKS5 proxy of `exit_proxy`,
+//!   such as its own nooshdaroo client
+//! - any other node passes it on to a peer marked `exit`, never back to the
+//!   one it came from, while the request's hop budget lasts
+//!
+//! Connections to the node's `socks` port take the same route, trying the
+//! peers marked `exit` in order. A peer's `bandwidth` caps the bytes per
+//! second its connections through this node may use, both ways together.
+
+use crate::acl::AccessPolicy;
+use crate::config::{MeshConfig, NooshdarooConfig};
+use crate::noise_transport::{NoiseConfig, NoiseKeypair, NoisePattern, NoiseTransport};
+use crate::punch::{self, Meeting, Role};
+use crate::socks5::{Command, ReplyCode, TargetAddr};
+use crate::traffic::BandwidthLimiter;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+/// Times a connection from the `socks` port may be passed on after the
+/// first peer
+const MAX_HOPS: u8 = 2;
+
+/// Largest chunk relayed at once
+const RECORD_SIZE: usize = 16 * 1024;
+
+/// First message of a session and its answer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MeshMessage {
+    /// Open a connection to `target` ("host:port"), passing it on to
+    /// another peer at most `hops` times
+    Connect { target: String, hops: u8 },
+    /// The connection is open; data follows
+    Connected,
+    Refused { message: String },
+}
+
+async fn send<S>(noise: &mut NoiseTransport, stream: &mut S, message: &MeshMessage) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    noise.write(stream, &serde_json::to_vec(message)?).await
+}
+
+async fn recv<S>(noise: &mut NoiseTransport, stream: &mut S) -> anyhow::Result<MeshMessage>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Ok(serde_json::from_slice(&noise.read(stream).await?)?)
+}
+
+/// Bandwidth cap shared by all of a peer's connections
+struct Limit {
+    /// Bytes per second, with its bucket; `None` = unlimited
+    bucket: Option<(u64, Mutex<BandwidthLimiter>)>,
+}
+
+impl Limit {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self { bucket: (bytes_per_sec > 0).then(|| (bytes_per_sec, Mutex::new(BandwidthLimiter::new(bytes_per_sec)))) }
+    }
+
+    /// Wait until `bytes` more are within the cap
+    async fn take(&self, bytes: usize) {
+        let Some((rate, bucket)) = &self.bucket else {
+            return;
+        };
+        // The bucket holds at most a second's worth
+        let mut remaining = bytes as u64;
+        while remaining > 0 {
+            let chunk = remaining.min(*rate);
+            bucket.lock().await.wait_for(chunk).await;
+            remaining -= chunk;
+        }
+    }
+}
+
+/// Plain side of a relayed connection
+trait Plain: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Plain for T {}
+
+struct Peer {
+    name: String,
+    exit: bool,
+    /// Meeting tags for sessions to and from the peer
+    dial_tag: [u8; 32],
+    answer_tag: [u8; 32],
+    noise: NoiseConfig,
+    limit: Limit,
+}
+
+struct Node {
+    mesh: MeshConfig,
+    access: AccessPolicy,
+    peers: Vec<Peer>,
+}
+
+/// Run the mesh node of `[mesh]`
+pub async fn run(config: &NooshdarooConfig) -> anyhow::Result<()> {
+    let mesh = config.mesh.clone().context("No [mesh] section in the configuration")?;
+    let access = AccessPolicy::new(&config.access).map_err(anyhow::Error::msg)?;
+    let private_key = NoiseKeypair::decode_private_key(&mesh.private_key)?;
+    let peers = mesh
+        .peers
+        .iter()
+        .map(|peer| {
+            let public_key = NoiseKeypair::decode_public_key(&peer.public_key)?;
+            let dial_tag = punch::meeting_tag(&private_key, &public_key, Role::Dial)?;
+            let answer_tag = punch::meeting_tag(&private_key, &public_key, Role::Answer)?;
+            let noise = NoiseConfig {
+                pattern: NoisePattern::KK,
+                local_private_key: Some(mesh.private_key.clone()),
+                remote_public_key: Some(peer.public_key.clone()),
+                elligator: true,
+                ..Default::default()
+            };
+            Ok(Peer {
+                name: peer.name.clone(),
+                exit: peer.exit,
+                dial_tag,
+                answer_tag,
+                noise,
+                limit: Limit::new(peer.bandwidth),
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let node = Arc::new(Node { mesh, access, peers });
+
+    for index in 0..node.peers.len() {
+        tokio::spawn(Arc::clone(&node).answer(index));
+    }
+    log::info!(
+        "Mesh node with {} peers{}",
+        node.peers.len(),
+        if node.mesh.exit { ", carrying connections to their destinations" } else { "" }
+    );
+    match node.mesh.socks {
+        Some(addr) => node.serve_socks(addr).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Node {
+    /// Wait at the rendezvous for connections from peer `index`
+    async fn answer(self: Arc<Self>, index: usize) {
+        let peer = &self.peers[index];
+        loop {
+            let meeting = match punch::meet(self.mesh.rendezvous, peer.answer_tag, Role::Answer).await {
+                Ok(meeting) => meeting,
+                Err(e) => {
+                    log::warn!("Rendezvous for {} failed: {}", peer.name, e);
+                    tokio::time::sleep(self.mesh.punch_timeout).await;
+                    continue;
+                }
+            };
+            let node = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = node.serve_peer(index, meeting).await {
+                    log::warn!("Connection from {} failed: {}", node.peers[index].name, e);
+                }
+            });
+        }
+    }
+
+    /// Carry a connection for peer `index`
+    async fn serve_peer(&self, index: usize, meeting: Meeting) -> anyhow::Result<()> {
+        let peer = &self.peers[index];
+        let mut session = punch::answer(meeting, self.mesh.punch_timeout).await?;
+        let path = session.path;
+        let mut noise = NoiseTransport::server_handshake(&mut session.stream, &peer.noise, None).await?;
+        let mut stream = BufReader::new(&mut session.stream);
+        let MeshMessage::Connect { target, hops } = recv(&mut noise, &mut stream).await? else {
+            bail!("{} did not say where to connect", peer.name);
+        };
+        log::info!("{} connected {} asks for {}", peer.name, path, target);
+        match self.open(&target, hops, Some(index)).await {
+            Ok(outbound) => {
+                send(&mut noise, &mut stream, &MeshMessage::Connected).await?;
+                relay(outbound, &mut stream, &mut noise, &peer.limit).await
+            }
+            Err(e) => {
+                send(&mut noise, &mut stream, &MeshMessage::Refused { message: e.to_string() }).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Open a connection to `target`, as the exit or through a peer marked
+    /// exit other than `from`
+    async fn open(&self, target: &str, hops: u8, from: Option<usize>) -> anyhow::Result<Box<dyn Plain>> {
+        if self.mesh.exit {
+            return Ok(Box::new(self.connect_exit(target).await?));
+        }
+        if hops == 0 {
+            bail!("no hops left to reach {}", target);
+        }
+        let mut last_error = None;
+        for (index, peer) in self.peers.iter().enumerate() {
+            if !peer.exit || Some(index) == from {
+                continue;
+            }
+            match self.dial(index, target, hops - 1).await {
+                Ok(channel) => return Ok(Box::new(channel)),
+                Err(e) => {
+                    log::debug!("Route to {} through {} failed: {}", target, peer.name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no peer offers an exit to {}", target)))
+    }
+
+    async fn connect_exit(&self, target: &str) -> anyhow::Result<TcpStream> {
+        let Some(proxy) = self.mesh.exit_proxy else {
+            return Ok(self.access.connect_target(target).await?);
+        };
+        let (host, port) = crate::acl::split_target(target)?;
+        if !self.access.allows(host, port) {
+            bail!("Connection to {} not allowed", target);
+        }
+        let mut stream = TcpStream::connect(proxy).await?;
+        crate::socks5::client_connect(&mut stream, host, port, None).await?;
+        Ok(stream)
+    }
+
+    /// Ask peer `index` to open a connection to `target`, returning the
+    /// plain end of it
+    async fn dial(&self, index: usize, target: &str, hops: u8) -> anyhow::Result<tokio::io::DuplexStream> {
+        let peer = &self.peers[index];
+        let meeting = punch::meet(self.mesh.rendezvous, peer.dial_tag, Role::Dial).await?;
+        let mut session = punch::dial(meeting, self.mesh.punch_timeout, self.mesh.relay).await?;
+        let mut noise = NoiseTransport::client_handshake(&mut session.stream, &peer.noise, None).await?;
+        let connect = MeshMessage::Connect { target: target.to_string(), hops };
+        send(&mut noise, &mut session.stream, &connect).await?;
+        match recv(&mut noise, &mut session.stream).await? {
+            MeshMessage::Connected => log::debug!("Connected to {} through {} {}", target, peer.name, session.path),
+            MeshMessage::Refused { message } => bail!("{} refused: {}", peer.name, message),
+            other => bail!("Unexpected answer from {}: {:?}", peer.name, other),
+        }
+
+        let (channel, far) = tokio::io::duplex(RECORD_SIZE * 4);
+        let name = peer.name.clone();
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(&mut session.stream);
+            if let Err(e) = relay(far, &mut stream, &mut noise, &Limit::new(0)).await {
+                log::debug!("Connection through {} ended: {}", name, e);
+            }
+        });
+        Ok(channel)
+    }
+
+    async fn serve_socks(self: Arc<Self>, addr: SocketAddr) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("Mesh SOCKS5 proxy on {}", addr);
+        loop {
+            let (stream, client) = listener.accept().await?;
+            let node = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = node.serve_socks_connection(stream).await {
+                    log::debug!("Mesh SOCKS5 connection from {} failed: {}", client, e);
+                }
+            });
+        }
+    }
+
+    async fn serve_socks_connection(&self, mut client: TcpStream) -> anyhow::Result<()> {
+        let (command, target) = crate::socks5::socks5_handshake(&mut client).await?;
+        let unspecified = TargetAddr { host: "0.0.0.0".to_string(), port: 0 };
+        if command != Command::Connect {
+            crate::socks5::send_reply(&mut client, ReplyCode::CommandNotSupported, &unspecified).await?;
+            bail!("Only CONNECT is carried through the mesh");
+        }
+        let target = if target.host.contains(':') {
+            format!("[{}]:{}", target.host, target.port)
+        } else {
+            format!("{}:{}", target.host, target.port)
+        };
+        match self.open(&target, MAX_HOPS, None).await {
+            Ok(mut outbound) => {
+                crate::socks5::send_reply(&mut client, ReplyCode::Succeeded, &unspecified).await?;
+                client.set_nodelay(true)?;
+                tokio::io::copy_bidirectional(&mut client, &mut outbound).await?;
+                Ok(())
+            }
+            Err(e) => {
+                crate::socks5::send_reply(&mut client, ReplyCode::HostUnreachable, &unspecified).await?;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Relay `plain` and a peer session until both have closed, holding data
+/// either way to `limit`
+async fn relay<P, S>(mut plain: P, session: &mut BufReader<S>, noise: &mut NoiseTransport, limit: &Limit) -> anyhow::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RECORD_SIZE];
+    let (mut plain_closed, mut session_closed) = (false, false);
+    while !(plain_closed && session_closed) {
+        tokio::select! {
+            read = plain.read(&mut buf), if !plain_closed => match read {
+                Ok(0) | Err(_) => {
+                    plain_closed = true;
+                    noise.close(session).await.ok();
+                }
+                Ok(n) => {
+                    limit.take(n).await;
+                    noise.write(session, &buf[..n]).await?;
+                }
+            },
+            // A message is read whole once it starts arriving, so a read
+            // from `plain` finishing first cannot cut one short
+            readable = crate::reverse::readable(session), if !session_closed => {
+                let data = if readable? { noise.read(session).await.ok() } else { None };
+                match data {
+                    Some(data) => {
+                        limit.take(data.len()).await;
+                        plain.write_all(&data).await?;
+                    }
+                    None => {
+                        session_closed = true;
+                        plain.shutdown().await.ok();
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MeshPeerConfig;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_bandwidth_limit() {
+        let unlimited = Limit::new(0);
+        let started = Instant::now();
+        unlimited.take(10_000_000).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // A second's worth passes at once, the rest at the rate
+        let limit = Limit::new(20_000);
+        let started = Instant::now();
+        limit.take(30_000).await;
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        let message: MeshMessage = serde_json::from_str(r#"{"type":"connect","target":"example.com:443","hops":1}"#).unwrap();
+        assert_eq!(message, MeshMessage::Connect { target: "example.com:443".to_string(), hops: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_mesh_exit() {
+        // Rendezvous
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rendezvous = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut state = punch::Rendezvous::new(true);
+            let mut buf = [0u8; 2048];
+            while let Ok((n, from)) = server.recv_from(&mut buf).await {
+                for (datagram, to) in state.handle(&buf[..n], from) {
+                    server.send_to(&datagram, to).await.unwrap();
+                }
+            }
+        });
+
+        // Destination, echoing what it gets
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await.ok();
+                });
+            }
+        });
+
+        let (inside, exit) = (NoiseKeypair::generate().unwrap(), NoiseKeypair::generate().unwrap());
+        let node = |keypair: &NoiseKeypair, peer: &NoiseKeypair, name: &str, socks: Option<SocketAddr>, is_exit: bool| {
+            let mut config = NooshdarooConfig::default();
+            config.access.allow_private = vec!["127.0.0.0/8".to_string()];
+            config.mesh = Some(MeshConfig {
+                rendezvous,
+                private_key: keypair.private_key_base64(),
+                socks,
+                exit: is_exit,
+                exit_proxy: None,
+                peers: vec![MeshPeerConfig {
+                    name: name.to_string(),
+                    public_key: peer.public_key_base64(),
+                    exit: !is_exit,
+                    bandwidth: 0,
+                }],
+                punch_timeout: Duration::from_secs(2),
+                relay: true,
+            });
+            config
+        };
+        let socks = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let exit_config = node(&exit, &inside, "inside", None, true);
+        let inside_config = node(&inside, &exit, "exit", Some(socks), false);
+        tokio::spawn(async move { run(&exit_config).await });
+        tokio::spawn(async move { run(&inside_config).await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut client = TcpStream::connect(socks).await.unwrap();
+        crate::socks5::client_connect(&mut client, "127.0.0.1", destination.port(), None).await.unwrap();
+        client.write_all(b"through the mesh").await.unwrap();
+        let mut buf = [0u8; 16];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"through the mesh");
+    }
+}
@@ -0,0 +1,320 @@
+//! Swift and Kotlin bindings of the mobile API (feature "uniffi")
+//!
+//! UniFFI generates idiomatic wrappers for these types, so iOS and Android
+//! apps get classes, records, enums and exceptions instead of the raw
+//! pointers and status codes of [`crate::mobile`]. Build the library for the
+//! device, then generate the wrappers from it:
+//!
+//! ```text
+//! cargo rustc --release --lib --features uniffi --crate-type cdylib
+//! cargo run --features uniffi --bin uniffi-bindgen -- generate \
+//!     --library target/release/libnooshdaroo.so --language swift --out-dir bindings/swift
+//! cargo run --features uniffi --bin uniffi-bindgen -- generate \
+//!     --library target/release/libnooshdaroo.so --language kotlin --out-dir bindings/kotlin
+//! ```
+//!
+//! In Kotlin, for instance:
+//!
+//! ```kotlin
+//! val client = MobileClient(MobileConfig(
+//!     serverAddr = "vpn.example.org:443", serverPublicKey = key,
+//!     listenAddr = null, transport = null, protocol = "https", shapingProfile = "iran"))
+//! client.setEventListener(object : MobileEventListener {
+//!     override fun onEvent(event: MobileEvent) { Log.i("nooshdaroo", event.toString()) }
+//! })
+//! client.start()
+//! ```
+
+use crate::config::{NooshdarooConfig, TransportType};
+use crate::events::ClientEvent;
+use crate::strategy::{FixedStrategy, StrategyType};
+use crate::NooshdarooClient;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+/// Settings for a [`MobileClient`]; unset options keep their defaults
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MobileConfig {
+    /// Server to tunnel through ("host:port")
+    pub server_addr: String,
+    /// The server's static Noise public key (base64)
+    pub server_public_key: String,
+    /// Local SOCKS5 listen address(es), comma-separated; "127.0.0.1:1080"
+    /// by default
+    pub listen_addr: Option<String>,
+    /// Transport carrying the tunnel ("tcp", "kcp", ...)
+    pub transport: Option<String>,
+    /// Protocol to emulate, overriding the shape-shifting strategy
+    pub protocol: Option<String>,
+    /// Preset traffic shaping profile (see [`crate::profiles`])
+    pub shaping_profile: Option<String>,
+}
+
+impl MobileConfig {
+    fn into_config(self) -> Result<NooshdarooConfig, MobileError> {
+        let mut builder = NooshdarooClient::builder().server(self.server_addr).server_public_key(self.server_public_key);
+        if let Some(listen_addr) = self.listen_addr {
+            let listen_addr = listen_addr
+                .parse()
+                .map_err(|e| MobileError::InvalidConfig { message: format!("listen_addr {}: {}", listen_addr, e) })?;
+            builder = builder.configure(|config| config.socks.listen_addr = listen_addr);
+        }
+        if let Some(transport) = self.transport {
+            let transport = serde_json::from_value::<TransportType>(serde_json::Value::from(transport))
+                .map_err(|e| MobileError::InvalidConfig { message: e.to_string() })?;
+            builder = builder.transport(transport);
+        }
+        if let Some(protocol) = self.protocol {
+            builder = builder.strategy(StrategyType::Fixed(FixedStrategy::new(protocol.into())));
+        }
+        if let Some(profile) = self.shaping_profile {
+            builder = builder.shaping_profile(&profile)?;
+        }
+        Ok(builder.into_config())
+    }
+}
+
+/// Errors thrown to Swift and Kotlin
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum MobileError {
+    #[error("Invalid configuration: {message}")]
+    InvalidConfig { message: String },
+    #[error("The client is already running")]
+    AlreadyRunning,
+    #[error("The client is not running")]
+    NotRunning,
+    #[error("{message}")]
+    Failed { message: String },
+}
+
+impl From<crate::NooshdarooError> for MobileError {
+    fn from(e: crate::NooshdarooError) -> Self {
+        match e {
+            crate::NooshdarooError::InvalidConfig(message) => MobileError::InvalidConfig { message },
+            e => MobileError::Failed { message: e.to_string() },
+        }
+    }
+}
+
+/// Client statistics (see [`crate::ProtocolStats`])
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MobileStats {
+    pub current_protocol: String,
+    pub total_switches: u64,
+    pub bytes_transferred: u64,
+    pub packets_transferred: u64,
+    pub uptime_secs: u64,
+    pub total_failovers: u64,
+    pub dns_leaks_blocked: u64,
+    pub blocking_events: u64,
+}
+
+/// Client events (see [`ClientEvent`])
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum MobileEvent {
+    ProtocolRotated { from: String, to: String },
+    TunnelDown { server: String, protocol: String, reason: String },
+    /// `working_paths` of the paths tested on `server` connected
+    PathTestCompleted { server: String, working_paths: u32 },
+    QuotaWarning { used: u64, limit: u64 },
+}
+
+impl From<ClientEvent> for MobileEvent {
+    fn from(event: ClientEvent) -> Self {
+        match event {
+            ClientEvent::ProtocolRotated { from, to } => {
+                MobileEvent::ProtocolRotated { from: from.to_string(), to: to.to_string() }
+            }
+            ClientEvent::TunnelDown { server, protocol, reason } => {
+                MobileEvent::TunnelDown { server: server.to_string(), protocol: protocol.to_string(), reason }
+            }
+            ClientEvent::PathTestCompleted { server, results } => MobileEvent::PathTestCompleted {
+                server,
+                working_paths: results.iter().filter(|result| result.success).count() as u32,
+            },
+            ClientEvent::QuotaWarning { used, limit } => MobileEvent::QuotaWarning { used, limit },
+        }
+    }
+}
+
+/// Receives client events, implemented in Swift or Kotlin
+#[uniffi::export(callback_interface)]
+pub trait MobileEventListener: Send + Sync {
+    /// Called on a library thread
+    fn on_event(&self, event: MobileEvent);
+}
+
+/// A Noise keypair, base64
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct MobileKeypair {
+    pub private_key: String,
+    pub public_key: String,
+}
+
+/// A client with its own runtime, running the SOCKS5 listener the app's
+/// VPN service or proxy settings point at
+#[derive(uniffi::Object)]
+pub struct MobileClient {
+    runtime: Runtime,
+    client: NooshdarooClient,
+    listener: Mutex<Option<JoinHandle<()>>>,
+    events: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MobileClient {
+    fn with_config(config: NooshdarooConfig) -> Result<Arc<Self>, MobileError> {
+        config.validate().map_err(|message| MobileError::InvalidConfig { message })?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| MobileError::Failed { message: e.to_string() })?;
+        let client = {
+            let _guard = runtime.enter();
+            NooshdarooClient::new(config)?
+        };
+        Ok(Arc::new(Self { runtime, client, listener: Mutex::new(None), events: Mutex::new(None) }))
+    }
+}
+
+#[uniffi::export]
+impl MobileClient {
+    #[uniffi::constructor]
+    pub fn new(config: MobileConfig) -> Result<Arc<Self>, MobileError> {
+        Self::with_config(config.into_config()?)
+    }
+
+    /// Client for a full configuration in TOML
+    #[uniffi::constructor]
+    pub fn from_toml(toml: String) -> Result<Arc<Self>, MobileError> {
+        let config = toml::from_str(&toml).map_err(|e| MobileError::InvalidConfig { message: e.to_string() })?;
+        Self::with_config(config)
+    }
+
+    /// Start the SOCKS5 listener, tunneling to the server
+    pub fn start(&self) -> Result<(), MobileError> {
+        let mut running = self.listener.lock().unwrap();
+        if running.as_ref().is_some_and(|listener| !listener.is_finished()) {
+            return Err(MobileError::AlreadyRunning);
+        }
+        let listener = self.runtime.block_on(self.client.socks_listener())?;
+        log::info!("Starting SOCKS5 listener on {}", self.client.config().socks.listen_addr);
+        *running = Some(self.runtime.spawn(async move {
+            if let Err(e) = listener.listen().await {
+                log::error!("SOCKS5 listener failed: {}", e);
+            }
+        }));
+        Ok(())
+    }
+
+    /// Stop the SOCKS5 listener; tunnels already open are not interrupted
+    pub fn stop(&self) -> Result<(), MobileError> {
+        let listener = self.listener.lock().unwrap().take().ok_or(MobileError::NotRunning)?;
+        listener.abort();
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.listener.lock().unwrap().as_ref().is_some_and(|listener| !listener.is_finished())
+    }
+
+    /// Protocol new tunnels use
+    pub fn current_protocol(&self) -> String {
+        self.runtime.block_on(self.client.current_protocol()).to_string()
+    }
+
+    /// Switch to `protocol`, overriding the strategy
+    pub fn set_protocol(&self, protocol: String) -> Result<(), MobileError> {
+        Ok(self.runtime.block_on(self.client.set_protocol(protocol.into()))?)
+    }
+
+    /// Rotate to the next protocol of the strategy
+    pub fn rotate(&self) -> Result<(), MobileError> {
+        Ok(self.runtime.block_on(self.client.rotate())?)
+    }
+
+    pub fn stats(&self) -> MobileStats {
+        let stats = self.runtime.block_on(self.client.stats());
+        MobileStats {
+            current_protocol: stats.current_protocol.to_string(),
+            total_switches: stats.total_switches,
+            bytes_transferred: stats.bytes_transferred,
+            packets_transferred: stats.packets_transferred,
+            uptime_secs: stats.uptime.as_secs(),
+            total_failovers: stats.total_failovers,
+            dns_leaks_blocked: stats.dns_leaks_blocked,
+            blocking_events: stats.blocking_events.len() as u64,
+        }
+    }
+
+    /// Deliver every event from now on to `listener`, replacing any earlier
+    /// listener; `None` stops the deliveries
+    pub fn set_event_listener(&self, listener: Option<Box<dyn MobileEventListener>>) {
+        let forwarder = listener.map(|listener| {
+            let mut events = self.client.events();
+            self.runtime.spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => listener.on_event(event.into()),
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("{} client events dropped before the listener ran", missed);
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                    }
+                }
+            })
+        });
+        let previous = std::mem::replace(&mut *self.events.lock().unwrap(), forwarder);
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+    }
+}
+
+impl Drop for MobileClient {
+    fn drop(&mut self) {
+        let handles = [self.listener.get_mut(), self.events.get_mut()];
+        for handle in handles.into_iter().filter_map(|handle| handle.ok()?.take()) {
+            handle.abort();
+        }
+    }
+}
+
+/// A new Noise keypair, e.g. for a client the server authenticates
+#[uniffi::export]
+pub fn generate_keypair() -> Result<MobileKeypair, MobileError> {
+    let keypair =
+        crate::noise_transport::NoiseKeypair::generate().map_err(|e| MobileError::Failed { message: e.to_string() })?;
+    Ok(MobileKeypair { private_key: keypair.private_key_base64(), public_key: keypair.public_key_base64() })
+}
+
+/// Library version
+#[uniffi::export]
+pub fn version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mobile_client() {
+        let key = generate_keypair().unwrap().public_key;
+        let config = MobileConfig {
+            server_addr: "192.0.2.7:443".to_string(),
+            server_public_key: key,
+            listen_addr: Some("127.0.0.1:0".to_string()),
+            transport: Some("kcp".to_string()),
+            protocol: Some("https".to_string()),
+            shaping_profile: None,
+        };
+        let client = MobileClient::new(config.clone()).unwrap();
+        assert_eq!(client.current_protocol(), "https");
+        assert_eq!(client.stats().current_protocol, "https");
+        assert!(matches!(client.stop(), Err(MobileError::NotRunning)));
+
+        let config = MobileConfig { transport: Some("carrier-pigeon".to_string()), ..config };
+        assert!(matches!(MobileClient::new(config), Err(MobileError::InvalidConfig { .. })));
+    }
+}
@@ -0,0 +1,468 @@
+//! Tunnel stream carried as an MQTT session
+//!
+//! Telemetry from IoT devices to their brokers is a traffic class networks
+//! rarely look at twice. This transport opens the tunnel the way a device
+//! does: a `CONNECT` with a device-style client ID and keep alive, the
+//! broker's `CONNACK`, a `SUBSCRIBE` to the device's command topic and its
+//! `SUBACK`. Tunnel data then travels as QoS 0 `PUBLISH`es, to the
+//! telemetry topic upstream and to the command topic downstream, and an
+//! idle client sends `PINGREQ` once per keep alive period, which the broker
+//! answers with `PINGRESP`.
+//!
+//! ```toml
+//! [socks]
+//! transport = "mqtt"
+//! mqtt_version = "5"        # "3.1.1" by default
+//!
+//! [server]
+//! transport = "mqtt"
+//! ```
+//!
+//! The server speaks whichever version the client asks for and refuses
+//! other protocol levels with an "unacceptable protocol version" `CONNACK`;
+//! like a broker, it drops clients silent for one and a half keep alive
+//! periods. This is MQTT as on port 1883: on 8883 the session runs inside TLS and
+//! only the TLS handshake is visible, which `tls_handshake` covers with the
+//! `tcp` transport.
+
+use crate::config::MqttVersion;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+
+/// Buffer between the tunnel and the session
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest tunnel chunk published at once
+pub const MAX_PAYLOAD: usize = 4096;
+
+/// Largest packet accepted
+const MAX_PACKET: usize = 64 * 1024;
+
+/// Control packet types
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Protocol levels
+const LEVEL_311: u8 = 4;
+const LEVEL_5: u8 = 5;
+
+/// CONNACK return code for an unsupported protocol level
+const UNACCEPTABLE_VERSION: u8 = 0x01;
+
+/// Keep alive periods device SDKs default to, in seconds
+const KEEP_ALIVE: &[u16] = &[30, 60];
+
+/// Client ID prefixes of common device firmware, followed by a MAC address
+const CLIENT_ID_PREFIXES: &[&str] = &["esp32-", "ESP8266-", "tasmota_", "shellyplus1pm-"];
+
+/// How often keep alive is checked
+const TIMER_TICK: Duration = Duration::from_secs(1);
+
+/// Start the client side over `stream`; the returned stream carries the tunnel
+pub async fn connect<S>(mut stream: S, version: MqttVersion) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let client_id = client_id();
+    let keep_alive = *KEEP_ALIVE.choose(&mut rand::thread_rng()).unwrap();
+    stream.write_all(&connect_packet(version, &client_id, keep_alive)).await?;
+    stream.flush().await?;
+    let connack = read_packet(&mut stream).await?;
+    match (connack.kind, connack.body.get(1)) {
+        (CONNACK, Some(0)) => {}
+        (CONNACK, Some(code)) => return Err(invalid(format!("MQTT broker refused the connection ({:#04x})", code))),
+        (kind, _) => return Err(invalid(format!("Expected CONNACK, got MQTT packet type {}", kind))),
+    }
+
+    let commands = format!("devices/{}/commands", client_id);
+    stream.write_all(&subscribe_packet(version, 1, &commands)).await?;
+    stream.flush().await?;
+    let suback = read_packet(&mut stream).await?;
+    if suback.kind != SUBACK {
+        return Err(invalid(format!("Expected SUBACK, got MQTT packet type {}", suback.kind)));
+    }
+    log::debug!("MQTT session open as {}", client_id);
+
+    let telemetry = format!("devices/{}/telemetry", client_id);
+    Ok(spawn_session(stream, version, telemetry, Role::Client(Duration::from_secs(keep_alive.into()))))
+}
+
+/// Start the server side over an accepted `stream`; the returned stream
+/// carries the tunnel
+pub async fn accept<S>(mut stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let connect = read_packet(&mut stream).await?;
+    if connect.kind != CONNECT {
+        return Err(invalid(format!("Expected CONNECT, got MQTT packet type {}", connect.kind)));
+    }
+    let mut reader = Reader(&connect.body);
+    if reader.string()? != b"MQTT" {
+        return Err(invalid("Not an MQTT CONNECT".to_string()));
+    }
+    let version = match reader.take(1)?[0] {
+        LEVEL_311 => MqttVersion::V311,
+        LEVEL_5 => MqttVersion::V5,
+        level => {
+            // Refused the 3.1.1 way, which older clients understand
+            stream.write_all(&[CONNACK << 4, 2, 0, UNACCEPTABLE_VERSION]).await?;
+            stream.flush().await?;
+            return Err(invalid(format!("Unsupported MQTT protocol level {}", level)));
+        }
+    };
+    reader.take(1)?;
+    let keep_alive = u16::from_be_bytes(reader.take(2)?.try_into().unwrap());
+    stream.write_all(&connack_packet(version)).await?;
+    stream.flush().await?;
+
+    let subscribe = read_packet(&mut stream).await?;
+    if subscribe.kind != SUBSCRIBE {
+        return Err(invalid(format!("Expected SUBSCRIBE, got MQTT packet type {}", subscribe.kind)));
+    }
+    let mut reader = Reader(&subscribe.body);
+    let packet_id = u16::from_be_bytes(reader.take(2)?.try_into().unwrap());
+    if version == MqttVersion::V5 {
+        reader.properties()?;
+    }
+    let commands = String::from_utf8_lossy(reader.string()?).into_owned();
+    stream.write_all(&suback_packet(version, packet_id)).await?;
+    stream.flush().await?;
+
+    Ok(spawn_session(stream, version, commands, Role::Server(Duration::from_secs(keep_alive.into()))))
+}
+
+/// Keep alive duty of a side
+#[derive(Clone, Copy)]
+enum Role {
+    /// Ping after this long without sending
+    Client(Duration),
+    /// Drop the client after one and a half times this long without
+    /// hearing from it; zero disables the check
+    Server(Duration),
+}
+
+fn spawn_session<S>(stream: S, version: MqttVersion, topic: String, role: Role) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = run_session(stream, version, &topic, role, local).await {
+            log::debug!("MQTT session ended: {}", e);
+        }
+    });
+    tunnel
+}
+
+/// Move tunnel bytes between `local` and `PUBLISH`es to `topic`, keeping
+/// the session alive
+async fn run_session<S>(stream: S, version: MqttVersion, topic: &str, role: Role, local: DuplexStream) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+
+    // Packets are read in their own task, as a partly read packet must not
+    // be dropped when another branch wins
+    let (packets_tx, mut packets) = mpsc::channel(64);
+    let reader = tokio::spawn(async move {
+        while let Ok(packet) = read_packet(&mut reader).await {
+            if packets_tx.send(packet).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let result = async {
+        let mut timer = tokio::time::interval(TIMER_TICK);
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        let (mut last_sent, mut last_received) = (Instant::now(), Instant::now());
+        let mut app_open = true;
+        loop {
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else {
+                        return app_write.shutdown().await;
+                    };
+                    last_received = Instant::now();
+                    match packet.kind {
+                        PUBLISH => app_write.write_all(publish_payload(version, &packet)?).await?,
+                        PINGREQ => {
+                            writer.write_all(&[PINGRESP << 4, 0]).await?;
+                            last_sent = Instant::now();
+                        }
+                        DISCONNECT => return app_write.shutdown().await,
+                        _ => {}
+                    }
+                }
+                n = app_read.read(&mut buf), if app_open => {
+                    let n = n?;
+                    if n == 0 {
+                        app_open = false;
+                        if matches!(role, Role::Client(_)) {
+                            writer.write_all(&[DISCONNECT << 4, 0]).await?;
+                        }
+                        writer.shutdown().await?;
+                        continue;
+                    }
+                    writer.write_all(&publish_packet(version, topic, &buf[..n])).await?;
+                    last_sent = Instant::now();
+                }
+                _ = timer.tick() => match role {
+                    Role::Client(keep_alive) if app_open && last_sent.elapsed() >= keep_alive => {
+                        writer.write_all(&[PINGREQ << 4, 0]).await?;
+                        last_sent = Instant::now();
+                    }
+                    Role::Server(keep_alive) if !keep_alive.is_zero() && last_received.elapsed() >= keep_alive * 3 / 2 => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "MQTT client keep alive expired"));
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    result
+}
+
+/// A control packet: type, flags and everything after the fixed header
+struct Packet {
+    kind: u8,
+    flags: u8,
+    body: Vec<u8>,
+}
+
+async fn read_packet<R>(reader: &mut R) -> io::Result<Packet>
+where
+    R: AsyncRead + Unpin,
+{
+    let first = reader.read_u8().await?;
+    let mut length = 0usize;
+    for i in 0..4 {
+        let byte = reader.read_u8().await?;
+        length |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if i == 3 {
+            return Err(invalid("Malformed MQTT remaining length".to_string()));
+        }
+    }
+    if length > MAX_PACKET {
+        return Err(invalid(format!("MQTT packet of {} bytes", length)));
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Packet { kind: first >> 4, flags: first & 0x0f, body })
+}
+
+/// Fixed header and `body`
+fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(5 + body.len());
+    packet.push(first);
+    put_varint(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// CONNECT with a clean session and no credentials, as devices on a
+/// private broker send it
+fn connect_packet(version: MqttVersion, client_id: &str, keep_alive: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, b"MQTT");
+    body.push(match version {
+        MqttVersion::V311 => LEVEL_311,
+        MqttVersion::V5 => LEVEL_5,
+    });
+    // Clean session / clean start
+    body.push(0x02);
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    if version == MqttVersion::V5 {
+        // Session expiry interval 0, receive maximum 20
+        put_properties(&mut body, &[0x11, 0, 0, 0, 0, 0x21, 0, 20]);
+    }
+    put_string(&mut body, client_id.as_bytes());
+    packet(CONNECT << 4, &body)
+}
+
+/// CONNACK accepting the connection
+fn connack_packet(version: MqttVersion) -> Vec<u8> {
+    match version {
+        MqttVersion::V311 => packet(CONNACK << 4, &[0, 0]),
+        MqttVersion::V5 => {
+            let mut body = vec![0, 0];
+            // Topic alias maximum 10, as Mosquitto sends it
+            put_properties(&mut body, &[0x22, 0, 10]);
+            packet(CONNACK << 4, &body)
+        }
+    }
+}
+
+/// SUBSCRIBE to `topic` at QoS 0
+fn subscribe_packet(version: MqttVersion, packet_id: u16, topic: &str) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    if version == MqttVersion::V5 {
+        put_properties(&mut body, &[]);
+    }
+    put_string(&mut body, topic.as_bytes());
+    body.push(0);
+    packet(SUBSCRIBE << 4 | 0x02, &body)
+}
+
+/// SUBACK granting QoS 0
+fn suback_packet(version: MqttVersion, packet_id: u16) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    if version == MqttVersion::V5 {
+        put_properties(&mut body, &[]);
+    }
+    body.push(0);
+    packet(SUBACK << 4, &body)
+}
+
+/// QoS 0 PUBLISH of `payload` to `topic`
+fn publish_packet(version: MqttVersion, topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 3);
+    put_string(&mut body, topic.as_bytes());
+    if version == MqttVersion::V5 {
+        put_properties(&mut body, &[]);
+    }
+    body.extend_from_slice(payload);
+    packet(PUBLISH << 4, &body)
+}
+
+/// Application message of a PUBLISH
+fn publish_payload(version: MqttVersion, publish: &Packet) -> io::Result<&[u8]> {
+    let mut reader = Reader(&publish.body);
+    reader.string()?;
+    if (publish.flags >> 1) & 0x03 != 0 {
+        // Packet identifier of QoS 1 and 2
+        reader.take(2)?;
+    }
+    if version == MqttVersion::V5 {
+        reader.properties()?;
+    }
+    Ok(reader.0)
+}
+
+/// Device-style client ID: a firmware prefix and a MAC address
+fn client_id() -> String {
+    let mut rng = rand::thread_rng();
+    let prefix = CLIENT_ID_PREFIXES.choose(&mut rng).unwrap();
+    let mac: [u8; 6] = rng.gen();
+    let mac: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", prefix, mac)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, string: &[u8]) {
+    out.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    out.extend_from_slice(string);
+}
+
+/// MQTT 5 property list
+fn put_properties(out: &mut Vec<u8>, properties: &[u8]) {
+    put_varint(out, properties.len());
+    out.extend_from_slice(properties);
+}
+
+/// Cursor over a packet body
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("Truncated MQTT packet".to_string()));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let length = u16::from_be_bytes(self.take(2)?.try_into().unwrap());
+        self.take(length.into())
+    }
+
+    /// Skip an MQTT 5 property list
+    fn properties(&mut self) -> io::Result<()> {
+        let mut length = 0usize;
+        for i in 0..4 {
+            let byte = self.take(1)?[0];
+            length |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        self.take(length).map(|_| ())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mqtt_session_then_tunnel() {
+        let packet = connect_packet(MqttVersion::V311, "esp32-0a1b2c3d4e5f", 60);
+        assert_eq!(&packet[..10], &[0x10, 30, 0, 4, b'M', b'Q', b'T', b'T', 4, 2]);
+
+        for version in [MqttVersion::V311, MqttVersion::V5] {
+            let (client_side, server_side) = tokio::io::duplex(BUFFER_SIZE);
+            let server = tokio::spawn(async move {
+                let mut tunnel = accept(server_side).await.unwrap();
+                let mut request = vec![0u8; 10_000];
+                tunnel.read_exact(&mut request).await.unwrap();
+                assert!(request.iter().enumerate().all(|(i, b)| *b == i as u8));
+                tunnel.write_all(b"response").await.unwrap();
+            });
+            let mut tunnel = connect(client_side, version).await.unwrap();
+            let request: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+            tunnel.write_all(&request).await.unwrap();
+            let mut response = [0u8; 8];
+            tunnel.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"response");
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_version_refused() {
+        let (mut client, server_side) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(accept(server_side));
+        let mut connect = connect_packet(MqttVersion::V311, "client", 60);
+        // MQTT 3.1's protocol level
+        connect[8] = 3;
+        client.write_all(&connect).await.unwrap();
+        let connack = read_packet(&mut client).await.unwrap();
+        assert_eq!((connack.kind, connack.body), (CONNACK, vec![0, UNACCEPTABLE_VERSION]));
+        assert!(server.await.unwrap().is_err());
+    }
+}
@@ -4,9 +4,12 @@
 //! - Listens on multiple ports simultaneously
 //! - Maps each port to appropriate protocol emulation
 //! - Provides realistic traffic patterns for netflow evasion
+//! - Optionally follows a [port hopping](crate::port_hop) schedule instead,
+//!   listening only on the ports of the current time slots
 
 use crate::library::ProtocolLibrary;
 use crate::netflow_evasion::MultiPortConfig;
+use crate::port_hop::PortHopSchedule;
 use crate::protocol::{ProtocolId, ProtocolMeta};
 use std::collections::HashMap;
 use std::net::SocketAddr;
@@ -41,6 +44,9 @@ pub struct MultiPortServer {
 
     /// Connection statistics per port
     stats: Arc<RwLock<HashMap<u16, PortStats>>>,
+
+    /// Listen on scheduled ports instead of fixed ones
+    hop: Option<PortHopSchedule>,
 }
 
 /// Statistics for a port
@@ -65,9 +71,17 @@ impl MultiPortServer {
             bindings: Arc::new(RwLock::new(Vec::new())),
             port_protocols: Arc::new(RwLock::new(HashMap::new())),
             stats: Arc::new(RwLock::new(HashMap::new())),
+            hop: None,
         }
     }
 
+    /// Listen on the ports `schedule` derives for the current time slots,
+    /// moving as slots pass
+    pub fn with_port_hopping(mut self, schedule: PortHopSchedule) -> Self {
+        self.hop = Some(schedule);
+        self
+    }
+
     /// Initialize port bindings based on configuration
     pub async fn initialize(&self) -> Result<(), Box<dyn std::error::Error>> {
         let mut bindings = Vec::new();
@@ -78,6 +92,18 @@ impl MultiPortServer {
 
         log::info!("Loaded {} protocols from library", loaded_protocols.len());
 
+        // Scheduled ports replace the fixed ones
+        if let Some(ref schedule) = self.hop {
+            for port in schedule.listening_ports(std::time::SystemTime::now()) {
+                if let Some(binding) = self.hop_binding(port, &mut port_protocols) {
+                    bindings.push(binding);
+                }
+            }
+            *self.bindings.write().await = bindings;
+            *self.port_protocols.write().await = port_protocols;
+            return Ok(());
+        }
+
         // Bind standard protocol ports using loaded protocols
         if self.config.use_standard_ports {
             for proto in loaded_protocols.iter() {
@@ -143,8 +169,22 @@ impl MultiPortServer {
         Ok(())
     }
 
+    /// Binding for scheduled `port`, serving a random protocol
+    fn hop_binding(&self, port: u16, port_protocols: &mut HashMap<u16, Vec<ProtocolId>>) -> Option<PortBinding> {
+        let proto = self.select_random_protocol()?;
+        port_protocols.insert(port, vec![proto.id.clone()]);
+        Some(PortBinding {
+            port,
+            protocols: vec![proto.id.clone()],
+            bind_addr: SocketAddr::new(self.config.bind_addr.parse().ok()?, port),
+        })
+    }
+
     /// Start listening on all configured ports
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(ref schedule) = self.hop {
+            return self.start_hopping(schedule).await;
+        }
         let bindings = self.bindings.read().await.clone();
 
         log::info!("Starting multi-port server on {} ports", bindings.len());
@@ -172,6 +212,56 @@ impl MultiPortServer {
         Ok(())
     }
 
+    /// Follow the port schedule: open the ports of new slots and close
+    /// those of past ones
+    async fn start_hopping(&self, schedule: &PortHopSchedule) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tasks: HashMap<u16, tokio::task::JoinHandle<()>> = HashMap::new();
+
+        loop {
+            let now = std::time::SystemTime::now();
+            let ports = schedule.listening_ports(now);
+            tasks.retain(|port, task| {
+                let keep = ports.contains(port);
+                if !keep {
+                    log::debug!("Port hopping: closing port {}", port);
+                    task.abort();
+                }
+                keep
+            });
+
+            let mut bindings = self.bindings.write().await;
+            let mut port_protocols = self.port_protocols.write().await;
+            bindings.retain(|b| ports.contains(&b.port));
+            port_protocols.retain(|port, _| ports.contains(port));
+            for port in ports {
+                if tasks.contains_key(&port) {
+                    continue;
+                }
+                let binding = match bindings.iter().find(|b| b.port == port) {
+                    Some(binding) => binding.clone(),
+                    None => match self.hop_binding(port, &mut port_protocols) {
+                        Some(binding) => {
+                            bindings.push(binding.clone());
+                            binding
+                        }
+                        None => continue,
+                    },
+                };
+                let stats = Arc::clone(&self.stats);
+                let port_protocols = Arc::clone(&self.port_protocols);
+                tasks.insert(port, tokio::spawn(async move {
+                    if let Err(e) = Self::listen_on_port(binding.clone(), port_protocols, stats).await {
+                        log::error!("Error on port {}: {}", binding.port, e);
+                    }
+                }));
+            }
+            drop(bindings);
+            drop(port_protocols);
+
+            tokio::time::sleep(schedule.until_next_slot(now)).await;
+        }
+    }
+
     /// Listen on a single port
     async fn listen_on_port(
         binding: PortBinding,
@@ -0,0 +1,125 @@
+//! NAT keepalive interval estimation for UDP transports
+//!
+//! Stateful NATs and firewalls forget a UDP mapping after a period of
+//! silence, anywhere from under 30 seconds to several minutes. The next
+//! query then leaves from a new port and the server, which keys tunnel
+//! sessions by client address, no longer recognises it. UDP transports
+//! therefore send a keepalive query before the path has been quiet for
+//! [`NatKeepalive::interval`].
+//!
+//! The interval is learned per server. Every answered query tells how long
+//! a silence the mapping survived, letting the interval grow past it; a
+//! query after a long silence that goes unanswered caps the interval at
+//! half that silence.
+
+use crate::config::NatKeepaliveConfig;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// Adaptive keepalive interval for one UDP path
+#[derive(Debug)]
+pub struct NatKeepalive {
+    config: NatKeepaliveConfig,
+    estimate: Mutex<Estimate>,
+}
+
+#[derive(Debug, Default)]
+struct Estimate {
+    /// Longest silence after which a query was still answered
+    survived: Duration,
+    /// Shortest silence after which a query went unanswered
+    expired: Option<Duration>,
+}
+
+impl NatKeepalive {
+    /// Estimator starting from the configured initial interval
+    pub fn new(config: &NatKeepaliveConfig) -> Self {
+        Self {
+            config: config.clone(),
+            estimate: Mutex::default(),
+        }
+    }
+
+    /// Estimator shared by all tunnels to `server`, so what one tunnel
+    /// learns about the path carries over to the next
+    pub fn for_server(server: SocketAddr, config: &NatKeepaliveConfig) -> Arc<Self> {
+        static PATHS: OnceLock<Mutex<HashMap<SocketAddr, Arc<NatKeepalive>>>> = OnceLock::new();
+        let mut paths = PATHS.get_or_init(Mutex::default).lock().unwrap();
+        Arc::clone(paths.entry(server).or_insert_with(|| Arc::new(Self::new(config))))
+    }
+
+    /// Longest silence to allow before a keepalive (None when disabled)
+    pub fn interval(&self) -> Option<Duration> {
+        if !self.config.enabled {
+            return None;
+        }
+        let estimate = self.estimate.lock().unwrap();
+        let grown = estimate.survived.mul_f64(1.5).max(self.config.initial_interval);
+        let ceiling = match estimate.expired {
+            Some(expired) => (expired / 2).min(self.config.max_interval),
+            None => self.config.max_interval,
+        };
+        Some(grown.min(ceiling).max(self.config.min_interval))
+    }
+
+    /// A query sent after `silence` was answered
+    pub fn record_answered(&self, silence: Duration) {
+        let mut estimate = self.estimate.lock().unwrap();
+        estimate.survived = estimate.survived.max(silence);
+        // The path now holds longer than the earlier loss suggested
+        if estimate.expired.is_some_and(|expired| silence >= expired) {
+            estimate.expired = None;
+        }
+    }
+
+    /// A query sent after `silence` was never answered
+    pub fn record_unanswered(&self, silence: Duration) {
+        // Losses after short silences are not the NAT timing out
+        if silence < self.config.min_interval {
+            return;
+        }
+        let mut estimate = self.estimate.lock().unwrap();
+        estimate.expired = Some(estimate.expired.map_or(silence, |expired| expired.min(silence)));
+        estimate.survived = estimate.survived.min(silence / 2);
+        log::info!("UDP path lost its NAT mapping after {:?} of silence", silence);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_adapts() {
+        let config = NatKeepaliveConfig {
+            enabled: true,
+            initial_interval: Duration::from_secs(10),
+            min_interval: Duration::from_secs(2),
+            max_interval: Duration::from_secs(60),
+        };
+        let nat = NatKeepalive::new(&config);
+        assert_eq!(nat.interval(), Some(Duration::from_secs(10)));
+
+        // Short gaps between data queries teach nothing
+        nat.record_answered(Duration::from_millis(50));
+        nat.record_unanswered(Duration::from_millis(50));
+        assert_eq!(nat.interval(), Some(Duration::from_secs(10)));
+
+        // Surviving keepalive gaps lets the interval grow up to the maximum
+        nat.record_answered(Duration::from_secs(10));
+        assert_eq!(nat.interval(), Some(Duration::from_secs(15)));
+        nat.record_answered(Duration::from_secs(50));
+        assert_eq!(nat.interval(), Some(Duration::from_secs(60)));
+
+        // A loss caps it at half the silence that broke the mapping
+        nat.record_unanswered(Duration::from_secs(30));
+        assert_eq!(nat.interval(), Some(Duration::from_secs(15)));
+        nat.record_answered(Duration::from_secs(15));
+        assert_eq!(nat.interval(), Some(Duration::from_secs(15)));
+
+        let disabled = NatKeepaliveConfig { enabled: false, ..config };
+        assert_eq!(NatKeepalive::new(&disabled).interval(), None);
+    }
+}
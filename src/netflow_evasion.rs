@@ -7,6 +7,7 @@
 //! - Using DNS on port 53 as fallback
 //! - Randomizing protocol usage patterns to avoid statistical detection
 
+use crate::events::{ClientEvent, EventSender};
 use crate::protocol::{ProtocolId, ProtocolMeta, Transport};
 use crate::library::ProtocolLibrary;
 use serde::{Deserialize, Serialize};
@@ -221,6 +222,9 @@ pub struct PathTester {
 
     /// Number of test iterations per path
     test_iterations: usize,
+
+    /// Client notified when a round of tests completes
+    events: Option<EventSender>,
 }
 
 impl PathTester {
@@ -229,9 +233,17 @@ impl PathTester {
             library,
             timeout_ms: 5000,
             test_iterations: 3,
+            events: None,
         }
     }
 
+    /// Emit [`ClientEvent::PathTestCompleted`] on `events` after
+    /// [`test_all_paths`](Self::test_all_paths)
+    pub fn with_events(mut self, events: EventSender) -> Self {
+        self.events = Some(events);
+        self
+    }
+
     /// Test connection to a specific server:port with protocol
     pub async fn test_path(
         &self,
@@ -307,6 +319,12 @@ impl PathTester {
 
         // Sort by score (best first)
         results.sort_by(|a, b| b.score().partial_cmp(&a.score()).unwrap());
+        if let Some(ref events) = self.events {
+            events.emit(ClientEvent::PathTestCompleted {
+                server: server_host.to_string(),
+                results: results.clone(),
+            });
+        }
         results
     }
 
@@ -0,0 +1,216 @@
+//! Node.js addon (feature "node")
+//!
+//! Lets Electron and other Node.js frontends embed the client instead of
+//! spawning the CLI. Build the addon and load it with `require`:
+//!
+//! ```text
+//! cargo rustc --release --lib --features node --crate-type cdylib
+//! cp target/release/libnooshdaroo.so nooshdaroo.node   # .dylib on macOS, .dll on Windows
+//! ```
+//!
+//! ```javascript
+//! const { Client } = require('./nooshdaroo.node');
+//!
+//! const client = Client.fromFile('client.toml');        // or new Client(tomlText)
+//! client.onEvent((event) => console.log(event.type, event));
+//! await client.start();                                 // SOCKS5 listener of [socks]
+//! console.log((await client.stats()).current_protocol);
+//! await client.rotate();
+//! client.stop();
+//! ```
+//!
+//! Methods that wait on the client return promises, resolved on the addon's
+//! runtime. Events are [`ClientEvent`](crate::ClientEvent)s as objects with
+//! a `type` field.
+
+use crate::config::NooshdarooConfig;
+use crate::{ClientEvent, NooshdarooClient};
+use napi::bindgen_prelude::{spawn, within_runtime_if_available};
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
+use napi::{Error, Result};
+use napi_derive::napi;
+use std::sync::Mutex;
+use tokio::task::JoinHandle;
+
+fn napi_error(e: impl ToString) -> Error {
+    Error::from_reason(e.to_string())
+}
+
+/// Validated configuration from TOML text
+fn parse_config(config: &str) -> Result<NooshdarooConfig> {
+    let config: NooshdarooConfig = toml::from_str(config).map_err(napi_error)?;
+    config.validate().map_err(napi_error)?;
+    Ok(config)
+}
+
+/// Validated configuration from the file at `path`
+fn load_config(path: &str) -> Result<NooshdarooConfig> {
+    let config = NooshdarooConfig::from_file(std::path::Path::new(path)).map_err(napi_error)?;
+    config.validate().map_err(napi_error)?;
+    Ok(config)
+}
+
+/// `event` as the object handed to JavaScript, with a `type` field
+fn event_value(event: &ClientEvent) -> Option<serde_json::Value> {
+    serde_json::to_value(event).ok()
+}
+
+/// A client, as the JavaScript class `Client`
+#[napi(js_name = "Client")]
+pub struct NodeClient {
+    client: NooshdarooClient,
+    listener: Mutex<Option<JoinHandle<()>>>,
+    events: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[napi]
+impl NodeClient {
+    /// Client for a configuration in TOML
+    #[napi(constructor)]
+    pub fn new(config: String) -> Result<Self> {
+        Self::with_config(parse_config(&config)?)
+    }
+
+    /// Client for a configuration file
+    #[napi(factory)]
+    pub fn from_file(path: String) -> Result<Self> {
+        Self::with_config(load_config(&path)?)
+    }
+
+    fn with_config(config: NooshdarooConfig) -> Result<Self> {
+        // Inside the runtime, so the client can start its background tasks
+        let client = within_runtime_if_available(|| NooshdarooClient::new(config)).map_err(napi_error)?;
+        Ok(Self { client, listener: Mutex::new(None), events: Mutex::new(None) })
+    }
+
+    /// Start the SOCKS5 listener of `[socks]`, tunneling to its server
+    #[napi]
+    pub async fn start(&self) -> Result<()> {
+        if self.is_running() {
+            return Err(napi_error("The client is already running"));
+        }
+        let listener = self.client.socks_listener().await.map_err(napi_error)?;
+        log::info!("Starting SOCKS5 listener on {}", self.client.config().socks.listen_addr);
+        let handle = spawn(async move {
+            if let Err(e) = listener.listen().await {
+                log::error!("SOCKS5 listener failed: {}", e);
+            }
+        });
+        *self.listener.lock().unwrap() = Some(handle);
+        Ok(())
+    }
+
+    /// Stop the SOCKS5 listener; tunnels already open are not interrupted
+    #[napi]
+    pub fn stop(&self) -> Result<()> {
+        match self.listener.lock().unwrap().take() {
+            Some(listener) => {
+                listener.abort();
+                Ok(())
+            }
+            None => Err(napi_error("The client is not running")),
+        }
+    }
+
+    /// Whether the SOCKS5 listener is running
+    #[napi(getter)]
+    pub fn is_running(&self) -> bool {
+        self.listener.lock().unwrap().as_ref().is_some_and(|listener| !listener.is_finished())
+    }
+
+    /// Protocol new tunnels use
+    #[napi]
+    pub async fn current_protocol(&self) -> String {
+        self.client.current_protocol().await.to_string()
+    }
+
+    /// Switch to `protocol`, overriding the strategy
+    #[napi]
+    pub async fn set_protocol(&self, protocol: String) -> Result<()> {
+        self.client.set_protocol(protocol.into()).await.map_err(napi_error)
+    }
+
+    /// Rotate to the next protocol of the strategy
+    #[napi]
+    pub async fn rotate(&self) -> Result<()> {
+        self.client.rotate().await.map_err(napi_error)
+    }
+
+    /// Statistics as an object
+    #[napi]
+    pub async fn stats(&self) -> serde_json::Value {
+        self.client.stats().await.to_json()
+    }
+
+    /// Call `callback` with every client event from now on, as an object
+    /// with a `type` field, replacing any earlier callback
+    #[napi(ts_args_type = "callback: (event: { type: string }) => void")]
+    pub fn on_event(&self, callback: ThreadsafeFunction<serde_json::Value, ErrorStrategy::Fatal>) -> Result<()> {
+        let mut events = self.client.events();
+        let forwarder = spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let Some(event) = event_value(&event) else { continue };
+                        callback.call(event, ThreadsafeFunctionCallMode::NonBlocking);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                        log::warn!("{} client events dropped before the callback ran", missed);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+        if let Some(previous) = self.events.lock().unwrap().replace(forwarder) {
+            previous.abort();
+        }
+        Ok(())
+    }
+
+    /// Stop calling the event callback
+    #[napi]
+    pub fn off_event(&self) {
+        if let Some(forwarder) = self.events.lock().unwrap().take() {
+            forwarder.abort();
+        }
+    }
+}
+
+impl Drop for NodeClient {
+    fn drop(&mut self) {
+        let handles = [self.listener.get_mut(), self.events.get_mut()];
+        for handle in handles.into_iter().filter_map(|handle| handle.ok()?.take()) {
+            handle.abort();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_errors() {
+        let config = parse_config(&toml::to_string(&NooshdarooConfig::default()).unwrap()).unwrap();
+        assert_eq!(config.socks.listen_addr, NooshdarooConfig::default().socks.listen_addr);
+
+        // Syntax errors and invalid settings both reach JavaScript as the
+        // error's message
+        assert!(!parse_config("mode = ").unwrap_err().reason.is_empty());
+        let mut invalid = NooshdarooConfig::default();
+        invalid.relay.buffer_size = 1;
+        let error = parse_config(&toml::to_string(&invalid).unwrap()).unwrap_err();
+        assert_eq!(error.reason, invalid.validate().unwrap_err());
+
+        let missing = std::env::temp_dir().join("nooshdaroo-node-missing.toml");
+        assert!(!load_config(&missing.to_string_lossy()).unwrap_err().reason.is_empty());
+        assert_eq!(napi_error("The client is not running").reason, "The client is not running");
+    }
+
+    #[test]
+    fn test_event_values() {
+        let value = event_value(&ClientEvent::QuotaWarning { used: 850, limit: 1000 }).unwrap();
+        assert_eq!(value, serde_json::json!({ "type": "QuotaWarning", "used": 850, "limit": 1000 }));
+    }
+}
@@ -12,6 +12,8 @@ use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
 use snow::{params::NoiseParams, Builder, HandshakeState, TransportState};
 use std::io;
+use std::str::FromStr;
+use std::time::SystemTime;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Maximum message size for Noise protocol (64 KB)
@@ -68,6 +70,32 @@ impl Default for NoisePattern {
     }
 }
 
+impl std::str::FromStr for NoisePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nk" => Ok(NoisePattern::NK),
+            "xx" => Ok(NoisePattern::XX),
+            "kk" => Ok(NoisePattern::KK),
+            "ik" => Ok(NoisePattern::IK),
+            _ => Err(format!("Unknown Noise pattern: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for NoisePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NoisePattern::NK => "nk",
+            NoisePattern::XX => "xx",
+            NoisePattern::KK => "kk",
+            NoisePattern::IK => "ik",
+        };
+        f.write_str(name)
+    }
+}
+
 /// Noise transport configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NoiseConfig {
@@ -82,6 +110,28 @@ pub struct NoiseConfig {
     /// Remote public key (base64-encoded)
     /// Required for: client (NK, KK), server (KK)
     pub remote_public_key: Option<String>,
+
+    /// Additional acceptable server keys with validity windows (client only)
+    ///
+    /// Lets operators roll out a new server key before switching to it, and
+    /// revoke compromised ones. Valid keys are tried newest first, then
+    /// `remote_public_key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remote_public_keys: Vec<PinnedKey>,
+
+    /// Encode handshake ephemeral keys with Elligator2 so they look like
+    /// random bytes (must match on client and server)
+    #[serde(default)]
+    pub elligator: bool,
+
+    /// Random padding carried in each handshake message's payload
+    #[serde(default)]
+    pub handshake_padding: HandshakePadding,
+
+    /// Carry the handshake inside TLS 1.3 hello messages (must match on
+    /// client and server)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_handshake: Option<TlsHandshakeConfig>,
 }
 
 impl Default for NoiseConfig {
@@ -90,17 +140,219 @@ impl Default for NoiseConfig {
             pattern: NoisePattern::NK,
             local_private_key: None,
             remote_public_key: None,
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
+        }
+    }
+}
+
+/// Largest padding allowed per handshake message, keeping each flight within
+/// what a TLS ClientHello or SSH KEXINIT plausibly weighs
+pub const MAX_HANDSHAKE_PADDING: usize = 1200;
+
+/// Range of random padding bytes added to each handshake message
+///
+/// Peers always accept padded handshakes; only the sender needs this set.
+/// Disabled (`0..0`) by default so older servers keep accepting handshakes.
+///
+/// ```toml
+/// [transport]
+/// handshake_padding = { min = 32, max = 480 }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakePadding {
+    /// Minimum padding in bytes
+    pub min: usize,
+    /// Maximum padding in bytes (inclusive)
+    pub max: usize,
+}
+
+impl HandshakePadding {
+    /// Draw a padding length, clamped to [`MAX_HANDSHAKE_PADDING`]
+    pub fn sample(&self) -> usize {
+        use rand::Rng;
+
+        let max = self.max.min(MAX_HANDSHAKE_PADDING);
+        let min = self.min.min(max);
+        rand::thread_rng().gen_range(min..=max)
+    }
+
+    /// Random padding bytes for one handshake message
+    fn generate(&self) -> Vec<u8> {
+        let mut padding = vec![0u8; self.sample()];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut padding);
+        padding
+    }
+}
+
+/// Handshake flights dressed as a TLS 1.3 exchange (see [`crate::tls_handshake`])
+///
+/// Replaces the protocol wrapper's fake hellos; tunnel data is framed as
+/// before.
+///
+/// ```toml
+/// [transport.tls_handshake]
+/// server_name = "www.example.com"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsHandshakeConfig {
+    /// Server name sent in the ClientHello
+    #[serde(default = "default_tls_server_name")]
+    pub server_name: String,
+}
+
+fn default_tls_server_name() -> String {
+    "www.google.com".to_string()
+}
+
+impl Default for TlsHandshakeConfig {
+    fn default() -> Self {
+        Self {
+            server_name: default_tls_server_name(),
+        }
+    }
+}
+
+/// A pinned server public key, accepted only within its validity window
+///
+/// In TOML:
+///
+/// ```toml
+/// [[transport.remote_public_keys]]
+/// key = "base64..."
+/// not_before = "2025-06-01T00:00:00Z"
+/// not_after = "2026-06-01T00:00:00Z"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PinnedKey {
+    /// Server public key (base64-encoded)
+    pub key: String,
+
+    /// Not accepted before this time (RFC 3339)
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<SystemTime>,
+
+    /// Not accepted after this time (RFC 3339)
+    #[serde(default, with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<SystemTime>,
+
+    /// Key is compromised and must never be used, even as `remote_public_key`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub revoked: bool,
+}
+
+impl PinnedKey {
+    /// A key with no validity window
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            not_before: None,
+            not_after: None,
+            revoked: false,
+        }
+    }
+
+    /// Whether the key may be used at `now`
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        !self.revoked
+            && self.not_before.is_none_or(|t| now >= t)
+            && self.not_after.is_none_or(|t| now <= t)
+    }
+}
+
+/// Parses `KEY[@[NOT_BEFORE]..[NOT_AFTER]]`, e.g. `abc=@2025-06-01T00:00:00Z..`
+impl FromStr for PinnedKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, window) = match s.trim().split_once('@') {
+            Some((key, window)) => (key, Some(window)),
+            None => (s.trim(), None),
+        };
+        if key.is_empty() {
+            return Err(anyhow!("Pinned key is empty: {}", s));
+        }
+
+        let mut pinned = PinnedKey::new(key);
+        if let Some(window) = window {
+            let (from, to) = window
+                .split_once("..")
+                .ok_or_else(|| anyhow!("Invalid key validity window (expected FROM..TO): {}", window))?;
+            let parse = |t: &str| -> Result<Option<SystemTime>> {
+                if t.is_empty() {
+                    return Ok(None);
+                }
+                humantime::parse_rfc3339_weak(t)
+                    .map(Some)
+                    .map_err(|e| anyhow!("Invalid key validity time '{}': {}", t, e))
+            };
+            pinned.not_before = parse(from)?;
+            pinned.not_after = parse(to)?;
         }
+        Ok(pinned)
     }
 }
 
 impl NoiseConfig {
+    /// Server keys the client may use at `now`, in the order to try them
+    ///
+    /// Valid pinned keys come first (latest `not_before` first), followed by
+    /// `remote_public_key` unless a pinned entry revokes it.
+    pub fn candidate_remote_keys(&self, now: SystemTime) -> Vec<String> {
+        let mut pinned: Vec<&PinnedKey> = self
+            .remote_public_keys
+            .iter()
+            .filter(|k| k.is_valid_at(now))
+            .collect();
+        pinned.sort_by_key(|k| std::cmp::Reverse(k.not_before));
+
+        let mut keys: Vec<String> = Vec::new();
+        for pinned in pinned {
+            if !keys.contains(&pinned.key) {
+                keys.push(pinned.key.clone());
+            }
+        }
+        if let Some(ref key) = self.remote_public_key {
+            let revoked = self.remote_public_keys.iter().any(|k| k.revoked && &k.key == key);
+            if !revoked && !keys.contains(key) {
+                keys.push(key.clone());
+            }
+        }
+        keys
+    }
+
+    /// Copy of this config pinned to a single server key
+    pub fn with_remote_key(&self, key: &str) -> Self {
+        Self {
+            remote_public_key: Some(key.to_string()),
+            remote_public_keys: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// snow builder for this pattern, with Elligator2 ephemerals if enabled
+    fn builder(&self) -> Result<Builder<'static>> {
+        let params: NoiseParams = self.pattern.protocol_name().parse()?;
+        Ok(if self.elligator {
+            Builder::with_resolver(params, Box::new(crate::elligator::ElligatorResolver))
+        } else {
+            Builder::new(params)
+        })
+    }
+
+    /// Whether any configured server key is currently usable
+    fn has_remote_key(&self) -> bool {
+        !self.candidate_remote_keys(SystemTime::now()).is_empty()
+    }
+
     /// Validate configuration for client role
     pub fn validate_client(&self) -> Result<()> {
         match self.pattern {
             NoisePattern::NK => {
-                if self.remote_public_key.is_none() {
-                    return Err(anyhow!("NK pattern requires remote_public_key for client"));
+                if !self.has_remote_key() {
+                    return Err(anyhow!("NK pattern requires a valid remote_public_key for client"));
                 }
             }
             NoisePattern::XX => {
@@ -113,14 +365,14 @@ impl NoiseConfig {
                 if self.local_private_key.is_none() {
                     return Err(anyhow!("KK pattern requires local_private_key for client"));
                 }
-                if self.remote_public_key.is_none() {
-                    return Err(anyhow!("KK pattern requires remote_public_key for client"));
+                if !self.has_remote_key() {
+                    return Err(anyhow!("KK pattern requires a valid remote_public_key for client"));
                 }
             }
             NoisePattern::IK => {
                 // IK pattern: client knows server's static public key
-                if self.remote_public_key.is_none() {
-                    return Err(anyhow!("IK pattern requires remote_public_key for client"));
+                if !self.has_remote_key() {
+                    return Err(anyhow!("IK pattern requires a valid remote_public_key for client"));
                 }
                 if self.local_private_key.is_none() {
                     return Err(anyhow!("IK pattern requires local_private_key for client"));
@@ -192,9 +444,11 @@ impl NoiseKeypair {
         BASE64.encode(&self.public_key)
     }
 
-    /// Decode private key from base64
+    /// Decode private key from base64, or load it from a key provider
+    /// reference such as `keychain:nooshdaroo` (see [`crate::key_provider`])
     pub fn decode_private_key(s: &str) -> Result<Vec<u8>> {
-        BASE64.decode(s).map_err(|e| anyhow!("Invalid base64 private key: {}", e))
+        let key = crate::key_provider::resolve_private_key(s)?;
+        BASE64.decode(key).map_err(|e| anyhow!("Invalid base64 private key: {}", e))
     }
 
     /// Decode public key from base64
@@ -203,6 +457,50 @@ impl NoiseKeypair {
     }
 }
 
+/// Largest plaintext one transport message can carry
+const MAX_PLAINTEXT_SIZE: usize = MAX_MESSAGE_SIZE - 16;
+
+/// Length-hiding padding inside transport messages
+///
+/// A padded plaintext is `[u16 length][data][zeros]`, rounded up to a
+/// multiple of `block` bytes plus up to `max_extra_blocks` random blocks.
+/// The receiver needs only the length, so each side may pad differently,
+/// but both must agree that messages are padded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordPadding {
+    pub block: usize,
+    pub max_extra_blocks: usize,
+}
+
+impl RecordPadding {
+    /// Frame and pad `data`
+    pub fn pad(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let framed = data.len() + 2;
+        if framed > MAX_PLAINTEXT_SIZE {
+            return Err(anyhow!("Message too large to pad: {} > {}", data.len(), MAX_PLAINTEXT_SIZE - 2));
+        }
+        let block = self.block.max(1);
+        let extra = rand::Rng::gen_range(&mut rand::thread_rng(), 0..=self.max_extra_blocks);
+        let len = ((framed.div_ceil(block) + extra) * block).min(MAX_PLAINTEXT_SIZE);
+        let mut padded = Vec::with_capacity(len);
+        padded.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        padded.extend_from_slice(data);
+        padded.resize(len, 0);
+        Ok(padded)
+    }
+
+    /// Recover the data from a padded plaintext
+    pub fn unpad(padded: &[u8]) -> Result<&[u8]> {
+        if padded.len() < 2 {
+            return Err(anyhow!("Padded message too short: {} bytes", padded.len()));
+        }
+        let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+        padded
+            .get(2..2 + len)
+            .ok_or_else(|| anyhow!("Padded message length {} exceeds record of {} bytes", len, padded.len()))
+    }
+}
+
 /// Encrypted Noise transport wrapper
 pub struct NoiseTransport {
     transport: TransportState,
@@ -211,6 +509,9 @@ pub struct NoiseTransport {
 
     /// Optional TLS record layer for full session emulation
     tls_layer: Option<crate::tls_record_layer::TlsRecordLayer>,
+
+    /// Padding of every message once both peers have agreed to it
+    record_padding: Option<RecordPadding>,
 }
 
 impl NoiseTransport {
@@ -225,8 +526,7 @@ impl NoiseTransport {
     {
         config.validate_client()?;
 
-        let params: NoiseParams = config.pattern.protocol_name().parse()?;
-        let mut builder = Builder::new(params);
+        let mut builder = config.builder()?;
 
         // Decode keys first to extend their lifetime
         let local_key = config
@@ -236,8 +536,8 @@ impl NoiseTransport {
             .transpose()?;
 
         let remote_key = config
-            .remote_public_key
-            .as_ref()
+            .candidate_remote_keys(SystemTime::now())
+            .first()
             .map(|k| NoiseKeypair::decode_public_key(k))
             .transpose()?;
 
@@ -254,13 +554,14 @@ impl NoiseTransport {
         let mut noise = builder.build_initiator()?;
 
         // Perform handshake
-        let transport = Self::perform_handshake(stream, noise, true, protocol_wrapper).await?;
+        let transport = Self::perform_handshake(stream, noise, true, config, protocol_wrapper).await?;
 
         Ok(Self {
             transport,
             read_buffer: vec![0u8; MAX_MESSAGE_SIZE],
             write_buffer: vec![0u8; MAX_MESSAGE_SIZE + 16], // +16 for AEAD tag
             tls_layer: None, // TLS wrapping disabled by default
+            record_padding: None,
         })
     }
 
@@ -275,8 +576,7 @@ impl NoiseTransport {
     {
         config.validate_server()?;
 
-        let params: NoiseParams = config.pattern.protocol_name().parse()?;
-        let mut builder = Builder::new(params);
+        let mut builder = config.builder()?;
 
         // Decode keys first to extend their lifetime
         let local_key = config
@@ -304,13 +604,14 @@ impl NoiseTransport {
         let mut noise = builder.build_responder()?;
 
         // Perform handshake
-        let transport = Self::perform_handshake(stream, noise, false, protocol_wrapper).await?;
+        let transport = Self::perform_handshake(stream, noise, false, config, protocol_wrapper).await?;
 
         Ok(Self {
             transport,
             read_buffer: vec![0u8; MAX_MESSAGE_SIZE],
             write_buffer: vec![0u8; MAX_MESSAGE_SIZE + 16],
             tls_layer: None, // TLS wrapping disabled by default
+            record_padding: None,
         })
     }
 
@@ -319,12 +620,18 @@ impl NoiseTransport {
         stream: &mut S,
         mut noise: HandshakeState,
         is_initiator: bool,
+        config: &NoiseConfig,
         mut protocol_wrapper: Option<&mut crate::protocol_wrapper::ProtocolWrapper>,
     ) -> Result<TransportState>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
+        if let Some(ref tls) = config.tls_handshake {
+            return Self::perform_tls_handshake(stream, noise, is_initiator, config, tls).await;
+        }
+
         let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let elligator = config.elligator;
 
         // STEP 1: Exchange fake protocol handshakes (if protocol wrapper supports it)
         // This makes DPI think we're doing a real TLS/SSH/etc handshake
@@ -377,7 +684,10 @@ impl NoiseTransport {
         // STEP 2: Perform real Noise handshake (wrapped in DATA frames if protocol wrapper exists)
         if is_initiator {
             // Initiator sends first message
-            let len = noise.write_message(&[], &mut buf)?;
+            let len = noise.write_message(&config.handshake_padding.generate(), &mut buf)?;
+            if elligator {
+                crate::elligator::encode_in_place(&mut buf[..len])?;
+            }
             let noise_handshake = &buf[..len];
 
             // Wrap handshake in protocol format if wrapper provided
@@ -395,7 +705,7 @@ impl NoiseTransport {
             let received = Self::read_message(stream, &mut buf).await?;
 
             // Unwrap if wrapper provided
-            let msg = if let Some(wrapper) = protocol_wrapper.as_deref() {
+            let mut msg = if let Some(wrapper) = protocol_wrapper.as_deref() {
                 let unwrapped = wrapper.unwrap(received)
                     .map_err(|e| anyhow!("Failed to unwrap server handshake: {}", e))?;
                 log::debug!("Client: Received wrapped Noise handshake message 2 ({} -> {} bytes)", received.len(), unwrapped.len());
@@ -404,12 +714,16 @@ impl NoiseTransport {
                 log::debug!("Client: Received raw Noise handshake message 2 ({} bytes)", received.len());
                 received.to_vec()
             };
+            if elligator {
+                crate::elligator::decode_in_place(&mut msg)?;
+            }
 
-            noise.read_message(&msg, &mut [])?;
+            // Any payload is handshake padding and is discarded
+            noise.read_message(&msg, &mut buf)?;
 
             // If XX pattern, send final message
             if !noise.is_handshake_finished() {
-                let len = noise.write_message(&[], &mut buf)?;
+                let len = noise.write_message(&config.handshake_padding.generate(), &mut buf)?;
                 let noise_handshake = &buf[..len];
 
                 if let Some(wrapper) = protocol_wrapper.as_deref_mut() {
@@ -427,7 +741,7 @@ impl NoiseTransport {
             let received = Self::read_message(stream, &mut buf).await?;
 
             // Unwrap if wrapper provided
-            let msg = if let Some(wrapper) = protocol_wrapper.as_deref() {
+            let mut msg = if let Some(wrapper) = protocol_wrapper.as_deref() {
                 let unwrapped = wrapper.unwrap(received)
                     .map_err(|e| anyhow!("Failed to unwrap client handshake: {}", e))?;
                 log::debug!("Server: Received wrapped Noise handshake message 1 ({} -> {} bytes)", received.len(), unwrapped.len());
@@ -436,11 +750,17 @@ impl NoiseTransport {
                 log::debug!("Server: Received raw Noise handshake message 1 ({} bytes)", received.len());
                 received.to_vec()
             };
+            if elligator {
+                crate::elligator::decode_in_place(&mut msg)?;
+            }
 
-            noise.read_message(&msg, &mut [])?;
+            noise.read_message(&msg, &mut buf)?;
 
             // Send response
-            let len = noise.write_message(&[], &mut buf)?;
+            let len = noise.write_message(&config.handshake_padding.generate(), &mut buf)?;
+            if elligator {
+                crate::elligator::encode_in_place(&mut buf[..len])?;
+            }
             let noise_handshake = &buf[..len];
 
             if let Some(wrapper) = protocol_wrapper.as_deref_mut() {
@@ -467,7 +787,75 @@ impl NoiseTransport {
                     received.to_vec()
                 };
 
-                noise.read_message(&msg, &mut [])?;
+                noise.read_message(&msg, &mut buf)?;
+            }
+        }
+
+        if !noise.is_handshake_finished() {
+            return Err(anyhow!("Handshake not completed"));
+        }
+
+        Ok(noise.into_transport_mode()?)
+    }
+
+    /// Perform the Noise handshake inside TLS 1.3 hellos (see [`crate::tls_handshake`])
+    async fn perform_tls_handshake<S>(
+        stream: &mut S,
+        mut noise: HandshakeState,
+        is_initiator: bool,
+        config: &NoiseConfig,
+        tls: &TlsHandshakeConfig,
+    ) -> Result<TransportState>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        use crate::tls_handshake;
+
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let elligator = config.elligator;
+
+        if is_initiator {
+            let len = noise.write_message(&config.handshake_padding.generate(), &mut buf)?;
+            if elligator {
+                crate::elligator::encode_in_place(&mut buf[..len])?;
+            }
+            let (hello, session_id) = tls_handshake::client_hello(&tls.server_name, &buf[..len])?;
+            stream.write_all(&hello).await?;
+            stream.flush().await?;
+            log::debug!("Client: Sent Noise handshake message 1 in ClientHello ({} -> {} bytes)", len, hello.len());
+
+            let mut msg = tls_handshake::read_server_flight(stream, &session_id).await?;
+            if elligator {
+                crate::elligator::decode_in_place(&mut msg)?;
+            }
+            noise.read_message(&msg, &mut buf)?;
+
+            // NK ends here; the Finished record then carries only filler
+            let len = if noise.is_handshake_finished() {
+                0
+            } else {
+                noise.write_message(&config.handshake_padding.generate(), &mut buf)?
+            };
+            stream.write_all(&tls_handshake::client_finished(&buf[..len])?).await?;
+            stream.flush().await?;
+        } else {
+            let (mut msg, session_id) = tls_handshake::read_client_hello(stream).await?;
+            if elligator {
+                crate::elligator::decode_in_place(&mut msg)?;
+            }
+            noise.read_message(&msg, &mut buf)?;
+            log::debug!("Server: Received Noise handshake message 1 in ClientHello ({} bytes)", msg.len());
+
+            let len = noise.write_message(&config.handshake_padding.generate(), &mut buf)?;
+            if elligator {
+                crate::elligator::encode_in_place(&mut buf[..len])?;
+            }
+            stream.write_all(&tls_handshake::server_flight(&session_id, &buf[..len])?).await?;
+            stream.flush().await?;
+
+            let msg = tls_handshake::read_client_finished(stream).await?;
+            if !noise.is_handshake_finished() {
+                noise.read_message(&msg, &mut buf)?;
             }
         }
 
@@ -495,6 +883,38 @@ impl NoiseTransport {
         self.tls_layer.is_some()
     }
 
+    /// Pad every message from now on; the peer must do the same for the
+    /// messages after the one that agreed on it
+    pub fn enable_record_padding(&mut self, padding: RecordPadding) {
+        self.record_padding = Some(padding);
+    }
+
+    /// Check if record padding is enabled
+    pub fn is_record_padding_enabled(&self) -> bool {
+        self.record_padding.is_some()
+    }
+
+    /// Encrypt one message into the write buffer, returning its length
+    fn seal(&mut self, data: &[u8]) -> Result<usize> {
+        match self.record_padding {
+            Some(ref padding) => {
+                let padded = padding.pad(data)?;
+                Ok(self.transport.write_message(&padded, &mut self.write_buffer)?)
+            }
+            None => Ok(self.transport.write_message(data, &mut self.write_buffer)?),
+        }
+    }
+
+    /// Decrypt one message
+    fn open(&mut self, encrypted: &[u8]) -> Result<Vec<u8>> {
+        let len = self.transport.read_message(encrypted, &mut self.write_buffer)?;
+        let plaintext = &self.write_buffer[..len];
+        match self.record_padding {
+            Some(_) => Ok(RecordPadding::unpad(plaintext)?.to_vec()),
+            None => Ok(plaintext.to_vec()),
+        }
+    }
+
     /// Read encrypted message from stream
     pub async fn read<S>(&mut self, stream: &mut S) -> Result<Vec<u8>>
     where
@@ -511,8 +931,7 @@ impl NoiseTransport {
         };
 
         // Decrypt Noise payload
-        let len = self.transport.read_message(&encrypted, &mut self.write_buffer)?;
-        Ok(self.write_buffer[..len].to_vec())
+        self.open(&encrypted)
     }
 
     /// Write encrypted message to stream
@@ -525,7 +944,7 @@ impl NoiseTransport {
         }
 
         // Encrypt with Noise
-        let len = self.transport.write_message(data, &mut self.write_buffer)?;
+        let len = self.seal(data)?;
         let noise_payload = &self.write_buffer[..len];
 
         // If TLS wrapping is enabled, wrap in TLS Application Data record
@@ -538,6 +957,19 @@ impl NoiseTransport {
         }
     }
 
+    /// Close the write half the way the emulated protocol does: a TLS
+    /// close_notify alert before the FIN when TLS wrapping is enabled
+    pub async fn close<S>(&mut self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if let Some(ref tls) = self.tls_layer {
+            tls.send_close_notify(stream).await
+                .map_err(|e| anyhow!("Failed to send TLS close_notify: {}", e))?;
+        }
+        stream.shutdown().await?;
+        Ok(())
+    }
 
     /// Read length-prefixed message (2-byte big-endian length + payload)
     async fn read_message<'a, S>(stream: &mut S, buf: &'a mut [u8]) -> Result<&'a [u8]>
@@ -576,17 +1008,42 @@ impl NoiseTransport {
             return Err(anyhow!("Message too large: {}", data.len()));
         }
 
-        // Write length prefix (2 bytes, big-endian)
-        let len = data.len() as u16;
-        stream.write_all(&len.to_be_bytes()).await?;
-
-        // Write payload
-        stream.write_all(data).await?;
+        // Length prefix (2 bytes, big-endian) and payload in one vectored write
+        let len = (data.len() as u16).to_be_bytes();
+        write_all_vectored(stream, &[&len, data]).await?;
         stream.flush().await?;
 
         Ok(())
     }
 
+    /// Encrypt several messages and send them with a single write and flush
+    ///
+    /// Framing is the same as [`write`](Self::write), so the peer reads them
+    /// as separate messages.
+    pub async fn write_batch<S>(&mut self, stream: &mut S, messages: &[&[u8]]) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut frames = Vec::new();
+        for data in messages {
+            if data.len() > MAX_MESSAGE_SIZE {
+                return Err(anyhow!("Message too large: {} > {}", data.len(), MAX_MESSAGE_SIZE));
+            }
+            let len = self.seal(data)?;
+            let noise_payload = &self.write_buffer[..len];
+            match self.tls_layer {
+                Some(ref tls) => frames.extend(tls.fragment_and_wrap(noise_payload).concat()),
+                None => {
+                    frames.extend_from_slice(&(len as u16).to_be_bytes());
+                    frames.extend_from_slice(noise_payload);
+                }
+            }
+        }
+        stream.write_all(&frames).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
     /// Encrypt data and return raw Noise-encrypted bytes (for use with protocol wrapper)
     /// Returns the encrypted data without length prefix
     pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
@@ -594,7 +1051,7 @@ impl NoiseTransport {
             return Err(anyhow!("Message too large: {} > {}", data.len(), MAX_MESSAGE_SIZE));
         }
 
-        let len = self.transport.write_message(data, &mut self.write_buffer)?;
+        let len = self.seal(data)?;
         Ok(self.write_buffer[..len].to_vec())
     }
 
@@ -605,8 +1062,7 @@ impl NoiseTransport {
             return Err(anyhow!("Encrypted data too large: {} > {}", encrypted.len(), self.read_buffer.len()));
         }
 
-        let len = self.transport.read_message(encrypted, &mut self.write_buffer)?;
-        Ok(self.write_buffer[..len].to_vec())
+        self.open(encrypted)
     }
 
     /// Write raw bytes to stream with length prefix (for protocol wrapper)
@@ -620,7 +1076,7 @@ impl NoiseTransport {
         }
 
         // Encrypt with Noise
-        let len = self.transport.write_message(data, &mut self.write_buffer)?;
+        let len = self.seal(data)?;
         let noise_payload = &self.write_buffer[..len];
 
         // Write raw encrypted data WITHOUT length prefix (for UDP/DNS)
@@ -647,8 +1103,7 @@ impl NoiseTransport {
         let encrypted = &buf[..n];
 
         // Decrypt with Noise
-        let len = self.transport.read_message(encrypted, &mut self.write_buffer)?;
-        Ok(self.write_buffer[..len].to_vec())
+        self.open(encrypted)
     }
 
     /// Check if transport is in valid state
@@ -657,6 +1112,24 @@ impl NoiseTransport {
     }
 }
 
+/// Write all of `bufs`, letting the stream gather them into as few
+/// syscalls as it supports
+async fn write_all_vectored<S>(stream: &mut S, bufs: &[&[u8]]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut slices: Vec<io::IoSlice<'_>> = bufs.iter().map(|b| io::IoSlice::new(b)).collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let n = stream.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        io::IoSlice::advance_slices(&mut slices, n);
+    }
+    Ok(())
+}
+
 /// Generate a new X25519 keypair and print as base64
 pub fn generate_keypair() -> Result<NoiseKeypair> {
     NoiseKeypair::generate()
@@ -707,6 +1180,10 @@ mod tests {
             pattern: NoisePattern::NK,
             local_private_key: None,
             remote_public_key: None,
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
         };
         assert!(config.validate_client().is_err());
 
@@ -718,11 +1195,76 @@ mod tests {
             pattern: NoisePattern::XX,
             local_private_key: None,
             remote_public_key: None,
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
         };
         assert!(config.validate_client().is_err());
         assert!(config.validate_server().is_err());
     }
 
+    #[test]
+    fn test_pinned_key_rotation() {
+        use std::time::Duration;
+
+        let now = SystemTime::now();
+        let day = Duration::from_secs(86400);
+        let config = NoiseConfig {
+            remote_public_key: Some("old".into()),
+            remote_public_keys: vec![
+                PinnedKey {
+                    not_before: Some(now - day),
+                    not_after: Some(now + day),
+                    ..PinnedKey::new("new")
+                },
+                PinnedKey {
+                    not_before: Some(now + day),
+                    ..PinnedKey::new("future")
+                },
+                PinnedKey {
+                    not_after: Some(now - day),
+                    ..PinnedKey::new("expired")
+                },
+                PinnedKey::new("unbounded"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(config.candidate_remote_keys(now), vec!["new", "unbounded", "old"]);
+        assert_eq!(config.candidate_remote_keys(now + day * 2), vec!["future", "unbounded", "old"]);
+        assert!(config.validate_client().is_ok());
+
+        // Revoking the primary key drops it everywhere
+        let mut revoked = config.clone();
+        revoked.remote_public_keys = vec![PinnedKey {
+            revoked: true,
+            ..PinnedKey::new("old")
+        }];
+        assert!(revoked.candidate_remote_keys(now).is_empty());
+        assert!(revoked.validate_client().is_err());
+
+        let single = config.with_remote_key("new");
+        assert_eq!(single.candidate_remote_keys(now), vec!["new"]);
+
+        let parsed: PinnedKey = "abc=@2025-06-01T00:00:00Z..".parse().unwrap();
+        assert_eq!(parsed.key, "abc=");
+        assert_eq!(parsed.not_before, Some(humantime::parse_rfc3339("2025-06-01T00:00:00Z").unwrap()));
+        assert_eq!(parsed.not_after, None);
+        assert_eq!("abc=".parse::<PinnedKey>().unwrap(), PinnedKey::new("abc="));
+        assert!("abc=@2025-06-01".parse::<PinnedKey>().is_err());
+
+        let toml_config: NoiseConfig = toml::from_str(
+            r#"
+            remote_public_key = "old"
+            [[remote_public_keys]]
+            key = "new"
+            not_before = "2025-06-01T00:00:00Z"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(toml_config.remote_public_keys[0].not_before, parsed.not_before);
+    }
+
     #[tokio::test]
     async fn test_noise_handshake_nk() {
         // Generate server keypair
@@ -732,12 +1274,20 @@ mod tests {
             pattern: NoisePattern::NK,
             local_private_key: Some(server_keypair.private_key_base64()),
             remote_public_key: None,
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
         };
 
         let client_config = NoiseConfig {
             pattern: NoisePattern::NK,
             local_private_key: None,
             remote_public_key: Some(server_keypair.public_key_base64()),
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
         };
 
         // Create duplex stream (simulates network connection)
@@ -778,6 +1328,185 @@ mod tests {
         assert_eq!(received, response);
     }
 
+    #[tokio::test]
+    async fn test_noise_handshake_elligator() {
+        let server_keypair = NoiseKeypair::generate().unwrap();
+
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            elligator: true,
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            elligator: true,
+            ..Default::default()
+        };
+
+        let (mut client_stream, mut server_stream) = duplex(8192);
+        let client_handle = tokio::spawn(async move {
+            NoiseTransport::client_handshake(&mut client_stream, &client_config, None).await
+        });
+        let server_handle = tokio::spawn(async move {
+            NoiseTransport::server_handshake(&mut server_stream, &server_config, None).await
+        });
+        let mut client_transport = client_handle.await.unwrap().unwrap();
+        let mut server_transport = server_handle.await.unwrap().unwrap();
+
+        let (mut client_stream, mut server_stream) = duplex(8192);
+        client_transport.write(&mut client_stream, b"hidden").await.unwrap();
+        assert_eq!(server_transport.read(&mut server_stream).await.unwrap(), b"hidden");
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            ..Default::default()
+        };
+
+        let (mut client_stream, mut server_stream) = duplex(8192);
+        let client_handle = tokio::spawn(async move {
+            NoiseTransport::client_handshake(&mut client_stream, &client_config, None).await
+        });
+        let server_handle = tokio::spawn(async move {
+            NoiseTransport::server_handshake(&mut server_stream, &server_config, None).await
+        });
+        let mut client_transport = client_handle.await.unwrap().unwrap();
+        let mut server_transport = server_handle.await.unwrap().unwrap();
+
+        let (mut client_stream, mut server_stream) = duplex(8192);
+        client_transport
+            .write_batch(&mut client_stream, &[b"one", b"two", b"three"])
+            .await
+            .unwrap();
+        client_transport.write(&mut client_stream, b"four").await.unwrap();
+        for expected in [&b"one"[..], b"two", b"three", b"four"] {
+            assert_eq!(server_transport.read(&mut server_stream).await.unwrap(), expected);
+        }
+
+        client_transport.enable_tls_wrapping();
+        server_transport.enable_tls_wrapping();
+        client_transport.write_batch(&mut client_stream, &[b"five", b"six"]).await.unwrap();
+        assert_eq!(server_transport.read(&mut server_stream).await.unwrap(), b"five");
+        assert_eq!(server_transport.read(&mut server_stream).await.unwrap(), b"six");
+    }
+
+    #[tokio::test]
+    async fn test_record_padding() {
+        let padding = RecordPadding { block: 64, max_extra_blocks: 2 };
+        for _ in 0..20 {
+            let padded = padding.pad(b"hello").unwrap();
+            assert_eq!(padded.len() % 64, 0);
+            assert!(padded.len() <= 192);
+            assert_eq!(RecordPadding::unpad(&padded).unwrap(), b"hello");
+        }
+        assert!(RecordPadding::unpad(&[0, 9, 1, 2]).is_err());
+        assert!(padding.pad(&vec![0u8; MAX_PLAINTEXT_SIZE]).is_err());
+
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            ..Default::default()
+        };
+
+        let (mut client_stream, mut server_stream) = duplex(8192);
+        let (client_transport, server_transport) = tokio::join!(
+            NoiseTransport::client_handshake(&mut client_stream, &client_config, None),
+            NoiseTransport::server_handshake(&mut server_stream, &server_config, None),
+        );
+        let (mut client_transport, mut server_transport) = (client_transport.unwrap(), server_transport.unwrap());
+        client_transport.enable_record_padding(padding);
+        server_transport.enable_record_padding(RecordPadding { block: 16, max_extra_blocks: 0 });
+
+        // Each side pads its own way; both read the other's records
+        client_transport.write(&mut client_stream, b"ping").await.unwrap();
+        assert_eq!(server_transport.read(&mut server_stream).await.unwrap(), b"ping");
+        let sealed = server_transport.encrypt(b"").unwrap();
+        assert_eq!(sealed.len(), 16 + 16);
+        assert_eq!(client_transport.decrypt(&sealed).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn test_noise_handshake_padding() {
+        let padding = HandshakePadding { min: 40, max: 200 };
+        for _ in 0..100 {
+            assert!((40..=200).contains(&padding.sample()));
+        }
+        assert_eq!(HandshakePadding { min: 5000, max: 9000 }.sample(), MAX_HANDSHAKE_PADDING);
+        assert_eq!(HandshakePadding::default().sample(), 0);
+
+        // XX has three flights; only the client pads, the server accepts it anyway
+        let client_keypair = NoiseKeypair::generate().unwrap();
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            pattern: NoisePattern::XX,
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            pattern: NoisePattern::XX,
+            local_private_key: Some(client_keypair.private_key_base64()),
+            handshake_padding: padding,
+            ..Default::default()
+        };
+
+        let (mut client_stream, mut server_stream) = duplex(8192);
+        let client_handle = tokio::spawn(async move {
+            NoiseTransport::client_handshake(&mut client_stream, &client_config, None).await
+        });
+        let server_handle = tokio::spawn(async move {
+            NoiseTransport::server_handshake(&mut server_stream, &server_config, None).await
+        });
+        assert!(client_handle.await.unwrap().unwrap().is_valid());
+        assert!(server_handle.await.unwrap().unwrap().is_valid());
+    }
+
+    #[tokio::test]
+    async fn test_noise_handshake_in_tls_hellos() {
+        // XX at full padding needs all three flights; the client speaks first in a ClientHello
+        let client_keypair = NoiseKeypair::generate().unwrap();
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let tls = Some(TlsHandshakeConfig::default());
+        let padding = HandshakePadding { min: MAX_HANDSHAKE_PADDING, max: MAX_HANDSHAKE_PADDING };
+        let server_config = NoiseConfig {
+            pattern: NoisePattern::XX,
+            local_private_key: Some(server_keypair.private_key_base64()),
+            elligator: true,
+            handshake_padding: padding,
+            tls_handshake: tls.clone(),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            pattern: NoisePattern::XX,
+            local_private_key: Some(client_keypair.private_key_base64()),
+            elligator: true,
+            handshake_padding: padding,
+            tls_handshake: tls,
+            ..Default::default()
+        };
+
+        let (mut client_stream, mut server_stream) = duplex(16384);
+        let server_handle = tokio::spawn(async move {
+            let mut transport = NoiseTransport::server_handshake(&mut server_stream, &server_config, None).await.unwrap();
+            let message = transport.read(&mut server_stream).await.unwrap();
+            transport.write(&mut server_stream, &message).await.unwrap();
+        });
+        let mut transport = NoiseTransport::client_handshake(&mut client_stream, &client_config, None).await.unwrap();
+        transport.write(&mut client_stream, b"hello").await.unwrap();
+        assert_eq!(transport.read(&mut client_stream).await.unwrap(), b"hello");
+        server_handle.await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_noise_handshake_xx() {
         // XX pattern exchanges keys during handshake, but snow still requires local keys
@@ -788,12 +1517,20 @@ mod tests {
             pattern: NoisePattern::XX,
             local_private_key: Some(server_keypair.private_key_base64()),
             remote_public_key: None, // Not pre-shared
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
         };
 
         let client_config = NoiseConfig {
             pattern: NoisePattern::XX,
             local_private_key: Some(client_keypair.private_key_base64()),
             remote_public_key: None, // Not pre-shared
+            remote_public_keys: Vec::new(),
+            elligator: false,
+            handshake_padding: HandshakePadding::default(),
+            tls_handshake: None,
         };
 
         let (mut client_stream, mut server_stream) = duplex(8192);
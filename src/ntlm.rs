@@ -0,0 +1,310 @@
+//! NTLMv2 authentication for upstream HTTP proxies
+//!
+//! Corporate proxies commonly refuse Basic authentication and ask for NTLM
+//! or Negotiate instead. This implements the client side of NTLMv2
+//! (MS-NLMP): the NEGOTIATE message, and the AUTHENTICATE message answering
+//! the proxy's CHALLENGE. Without a Kerberos ticket, Windows sends the same
+//! NTLM tokens under the `Negotiate` scheme, and so does the client (see
+//! `kerberos` for the ticket case).
+//! The CHALLENGE itself is built for the server side of the `smb` transport.
+
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use rand::RngCore;
+use std::io;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const TARGET_TYPE_DOMAIN: u32 = 0x0001_0000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_VERSION: u32 = 0x0200_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// AV pairs of a CHALLENGE's target info
+const MSV_AV_NB_COMPUTER_NAME: u16 = 1;
+const MSV_AV_NB_DOMAIN_NAME: u16 = 2;
+const MSV_AV_DNS_COMPUTER_NAME: u16 = 3;
+const MSV_AV_DNS_DOMAIN_NAME: u16 = 4;
+/// The server's time
+const MSV_AV_TIMESTAMP: u16 = 7;
+
+/// Version of Windows Server 2022 (10.0.20348, NTLM revision 15)
+const SERVER_VERSION: [u8; 8] = [10, 0, 0x7c, 0x4f, 0, 0, 0, 0x0f];
+
+/// 100 ns intervals between 1601-01-01 and the Unix epoch
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// NEGOTIATE message opening the handshake
+pub fn negotiate_message() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    // Empty domain and workstation
+    message.extend_from_slice(&[0u8; 16]);
+    message
+}
+
+/// AUTHENTICATE message answering `challenge` for `username`, which may be
+/// given as `DOMAIN\user`
+pub fn authenticate_message(challenge: &[u8], username: &str, password: &str) -> io::Result<Vec<u8>> {
+    let challenge = Challenge::parse(challenge)?;
+    let (domain, user) = username.split_once('\\').unwrap_or(("", username));
+    let timestamp = challenge.timestamp().unwrap_or_else(filetime_now);
+    let mut client_challenge = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut client_challenge);
+    Ok(authenticate(&challenge, domain, user, password, client_challenge, timestamp))
+}
+
+/// CHALLENGE message of the server `computer` in the Active Directory
+/// domain `domain`, answering a NEGOTIATE
+pub fn challenge_message(domain: &str, computer: &str) -> Vec<u8> {
+    let dns_domain = format!("{}.local", domain.to_lowercase());
+    let dns_computer = format!("{}.{}", computer.to_lowercase(), dns_domain);
+    let mut target_info = Vec::new();
+    for (id, value) in [
+        (MSV_AV_NB_DOMAIN_NAME, utf16le(domain)),
+        (MSV_AV_NB_COMPUTER_NAME, utf16le(computer)),
+        (MSV_AV_DNS_DOMAIN_NAME, utf16le(&dns_domain)),
+        (MSV_AV_DNS_COMPUTER_NAME, utf16le(&dns_computer)),
+        (MSV_AV_TIMESTAMP, filetime_now().to_le_bytes().to_vec()),
+        // MsvAvEOL
+        (0, Vec::new()),
+    ] {
+        target_info.extend_from_slice(&id.to_le_bytes());
+        target_info.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        target_info.extend_from_slice(&value);
+    }
+    let target_name = utf16le(domain);
+    let flags = NEGOTIATE_FLAGS & !NEGOTIATE_OEM | TARGET_TYPE_DOMAIN | NEGOTIATE_TARGET_INFO | NEGOTIATE_VERSION;
+    let mut server_challenge = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut server_challenge);
+
+    // Length, maximum length and offset of the target name and target info
+    let field = |length: usize, offset: usize| {
+        let mut field = [(length as u16).to_le_bytes(), (length as u16).to_le_bytes()].concat();
+        field.extend_from_slice(&(offset as u32).to_le_bytes());
+        field
+    };
+    let mut message = Vec::with_capacity(56 + target_name.len() + target_info.len());
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&2u32.to_le_bytes());
+    message.extend_from_slice(&field(target_name.len(), 56));
+    message.extend_from_slice(&flags.to_le_bytes());
+    message.extend_from_slice(&server_challenge);
+    message.extend_from_slice(&[0u8; 8]);
+    message.extend_from_slice(&field(target_info.len(), 56 + target_name.len()));
+    message.extend_from_slice(&SERVER_VERSION);
+    message.extend_from_slice(&target_name);
+    message.extend_from_slice(&target_info);
+    message
+}
+
+/// The parts of a CHALLENGE message the response depends on
+struct Challenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    fn parse(message: &[u8]) -> io::Result<Self> {
+        if message.len() < 32 || &message[..8] != SIGNATURE || u32_at(message, 8) != 2 {
+            return Err(invalid("Not an NTLM CHALLENGE message".to_string()));
+        }
+        let target_info = if message.len() >= 48 {
+            let length = u16::from_le_bytes([message[40], message[41]]) as usize;
+            let offset = u32_at(message, 44) as usize;
+            message
+                .get(offset..offset + length)
+                .ok_or_else(|| invalid(format!("NTLM target info out of bounds ({} bytes at {})", length, offset)))?
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            flags: u32_at(message, 20),
+            server_challenge: message[24..32].try_into().unwrap(),
+            target_info,
+        })
+    }
+
+    /// Server time from the target info, which the response must use
+    fn timestamp(&self) -> Option<u64> {
+        let mut pairs = self.target_info.as_slice();
+        while pairs.len() >= 4 {
+            let id = u16::from_le_bytes([pairs[0], pairs[1]]);
+            let length = u16::from_le_bytes([pairs[2], pairs[3]]) as usize;
+            let value = pairs.get(4..4 + length)?;
+            if id == MSV_AV_TIMESTAMP && length == 8 {
+                return Some(u64::from_le_bytes(value.try_into().unwrap()));
+            }
+            pairs = &pairs[4 + length..];
+        }
+        None
+    }
+
+    fn encode(&self, s: &str) -> Vec<u8> {
+        if self.flags & NEGOTIATE_UNICODE != 0 {
+            utf16le(s)
+        } else {
+            s.as_bytes().to_vec()
+        }
+    }
+}
+
+fn authenticate(
+    challenge: &Challenge,
+    domain: &str,
+    user: &str,
+    password: &str,
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> Vec<u8> {
+    let key = ntowf_v2(domain, user, password);
+
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0u8; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0u8; 4]);
+    let mut nt_response = hmac_md5(&key, &[&challenge.server_challenge, &blob]).to_vec();
+    nt_response.extend_from_slice(&blob);
+
+    let mut lm_response = hmac_md5(&key, &[&challenge.server_challenge, &client_challenge]).to_vec();
+    lm_response.extend_from_slice(&client_challenge);
+
+    let fields = [
+        lm_response,
+        nt_response,
+        challenge.encode(domain),
+        challenge.encode(user),
+        // Workstation and session key
+        Vec::new(),
+        Vec::new(),
+    ];
+    let mut header = Vec::with_capacity(64);
+    header.extend_from_slice(SIGNATURE);
+    header.extend_from_slice(&3u32.to_le_bytes());
+    let mut payload = Vec::new();
+    let mut offset = 64u32;
+    for field in &fields {
+        header.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        header.extend_from_slice(&offset.to_le_bytes());
+        offset += field.len() as u32;
+        payload.extend_from_slice(field);
+    }
+    let flags = NEGOTIATE_FLAGS & (challenge.flags | !(NEGOTIATE_UNICODE | NEGOTIATE_OEM));
+    header.extend_from_slice(&flags.to_le_bytes());
+    header.extend_from_slice(&payload);
+    header
+}
+
+/// NTOWFv2: HMAC-MD5 keyed with the NT hash over the upper-cased user name
+/// and the domain
+fn ntowf_v2(domain: &str, user: &str, password: &str) -> [u8; 16] {
+    let nt_hash: [u8; 16] = Md4::digest(utf16le(password)).into();
+    hmac_md5(&nt_hash, &[&utf16le(&(user.to_uppercase() + domain))])
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Current time as a FILETIME
+pub(crate) fn filetime_now() -> u64 {
+    let unix = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    FILETIME_UNIX_EPOCH + (unix.as_nanos() / 100) as u64
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn u32_at(message: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(message[offset..offset + 4].try_into().unwrap())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        // MS-NLMP 4.2.4.1.1
+        assert_eq!(hex::encode(ntowf_v2("Domain", "User", "Password")), "0c868a403bfd7a93a3001ef22ef02e3f");
+    }
+
+    #[test]
+    fn test_ntlmv2_response() {
+        // The CHALLENGE of MS-NLMP 4.2.4.3
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&2u32.to_le_bytes());
+        message.extend_from_slice(&[12, 0, 12, 0, 56, 0, 0, 0]);
+        message.extend_from_slice(&0xe28a_8233u32.to_le_bytes());
+        message.extend_from_slice(&hex::decode("0123456789abcdef").unwrap());
+        message.extend_from_slice(&[0u8; 8]);
+        message.extend_from_slice(&[36, 0, 36, 0, 68, 0, 0, 0]);
+        message.extend_from_slice(&[0u8; 8]);
+        message.extend_from_slice(&utf16le("Server"));
+        message.extend_from_slice(&[2, 0, 12, 0]);
+        message.extend_from_slice(&utf16le("Domain"));
+        message.extend_from_slice(&[1, 0, 12, 0]);
+        message.extend_from_slice(&utf16le("Server"));
+        message.extend_from_slice(&[0u8; 4]);
+
+        let challenge = Challenge::parse(&message).unwrap();
+        assert_eq!(challenge.target_info.len(), 36);
+        assert_eq!(challenge.timestamp(), None);
+        let response = authenticate(&challenge, "Domain", "User", "Password", [0xaa; 8], 0);
+
+        let field = |offset: usize| {
+            let length = u16::from_le_bytes([response[offset], response[offset + 1]]) as usize;
+            let start = u32_at(&response, offset + 4) as usize;
+            &response[start..start + length]
+        };
+        assert_eq!(hex::encode(field(12)), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+        assert_eq!(hex::encode(&field(20)[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+        assert_eq!(field(28), utf16le("Domain").as_slice());
+        assert_eq!(field(36), utf16le("User").as_slice());
+        assert!(authenticate_message(&negotiate_message(), "Domain\\User", "Password").is_err());
+    }
+
+    #[test]
+    fn test_challenge_message() {
+        let challenge = Challenge::parse(&challenge_message("CORP", "FS01")).unwrap();
+        assert_eq!(challenge.flags & NEGOTIATE_UNICODE, NEGOTIATE_UNICODE);
+        let timestamp = challenge.timestamp().unwrap();
+        assert!(timestamp.abs_diff(filetime_now()) < 10_000_000);
+        assert!(authenticate_message(&challenge_message("CORP", "FS01"), "CORP\\jsmith", "").is_ok());
+    }
+}
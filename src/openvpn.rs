@@ -0,0 +1,919 @@
+//! Tunnels framed as OpenVPN sessions
+//!
+//! OpenVPN is still what many corporate networks and their users run, and
+//! its wire protocol is well documented. This module frames the tunnel as
+//! a TLS-mode OpenVPN session, over TCP as a transport of its own or over
+//! UDP as a framing of `kcp` datagrams:
+//!
+//! ```toml
+//! [socks]
+//! transport = "openvpn"      # or "kcp" with relay.openvpn below
+//!
+//! [server]
+//! transport = "openvpn"
+//!
+//! # [relay]
+//! # openvpn = true
+//! ```
+//!
+//! Each session starts the way OpenVPN 2.4 and later start one: the client
+//! sends `P_CONTROL_HARD_RESET_CLIENT_V2`, the server answers with
+//! `P_CONTROL_HARD_RESET_SERVER_V2`, and `P_CONTROL_V1` packets then carry
+//! a TLS handshake (from [`crate::tls_handshake`]), the key method exchange
+//! and the `PUSH_REQUEST`/`PUSH_REPLY` as TLS application data, each packet
+//! acknowledged by packet ID, piggybacked or in `P_ACK_V1`, and tagged with
+//! both 8-byte session IDs. Tunnel bytes then travel in `P_DATA_V2`
+//! packets sealed with ChaCha20-Poly1305 in OpenVPN's AEAD layout, with a
+//! ping every [`PING_INTERVAL`] when nothing else is sent. Over TCP every
+//! packet has OpenVPN's 2-byte length prefix; over UDP control packets are
+//! retransmitted with backoff until acknowledged, for up to
+//! [`HAND_WINDOW`].
+//!
+//! The data channel keys are derived from the session IDs: they disguise
+//! the tunnel, the Noise session inside protects it.
+
+use crate::config::TurnConfig;
+use crate::kcp_transport::{self, KcpListener, Socket};
+use async_trait::async_trait;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, Tag, UnboundKey, CHACHA20_POLY1305};
+use ring::digest;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Opcodes, in the high five bits of a packet's first byte; the low three
+/// hold the key ID, always 0 here
+const P_CONTROL_V1: u8 = 4;
+const P_ACK_V1: u8 = 5;
+const P_CONTROL_HARD_RESET_CLIENT_V2: u8 = 7;
+const P_CONTROL_HARD_RESET_SERVER_V2: u8 = 8;
+const P_DATA_V2: u8 = 9;
+
+/// Packet IDs acknowledged in one packet
+const MAX_ACKS: usize = 4;
+
+/// TLS bytes per control packet, keeping packets within the default
+/// `tls-mtu` of 1250
+const MAX_CONTROL_PAYLOAD: usize = 1200;
+
+/// Tunnel bytes per data packet, about what a tun device with the default
+/// MTU hands over
+const MAX_DATA: usize = 1400;
+
+/// Opcode and peer ID, then packet ID
+const DATA_HEADER_SIZE: usize = 8;
+
+const TAG_SIZE: usize = 16;
+
+/// Largest packet accepted over TCP
+const MAX_PACKET: usize = 2048;
+
+/// Records in each TLS flight a side waits for before sending its next
+const CLIENT_AWAITS: [usize; 3] = [3, 1, 1];
+const SERVER_AWAITS: [usize; 3] = [1, 3, 1];
+
+/// Plaintext of OpenVPN's keepalive ping
+const PING: [u8; 16] = [
+    0x2a, 0x18, 0x7b, 0xf3, 0x64, 0x1e, 0xb4, 0xcb, 0x07, 0xed, 0x2d, 0x0a, 0x98, 0x1f, 0xc7, 0x48,
+];
+
+/// `keepalive 10 120`, as OpenVPN servers commonly push
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+pub const PING_RESTART: Duration = Duration::from_secs(120);
+
+/// How long a handshake may take
+pub const HAND_WINDOW: Duration = Duration::from_secs(60);
+
+/// Control packet retransmission over UDP
+const INITIAL_RETRANSMIT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: Duration = Duration::from_secs(8);
+
+/// How often the timers are checked
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+/// Buffer between the tunnel and a TCP connection
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Bytes of datagrams queued for a handshake to finish; more are dropped
+/// like a full socket buffer and KCP retransmits them
+const QUEUE_BYTES: usize = 64 * 1024;
+
+/// Received datagrams waiting for `recv_from`
+const RECEIVE_QUEUE: usize = 1024;
+
+/// Start the client side over `stream`, sending `server_name` in the TLS
+/// handshake; the returned stream carries the tunnel
+pub async fn connect<S>(mut stream: S, server_name: &str) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut session = Session::new(Some(server_name.to_string()));
+    write_packet(&mut stream, &session.hard_reset()).await?;
+    let early = handshake(&mut stream, &mut session).await?;
+    Ok(spawn_pump(stream, session, early))
+}
+
+/// Start the server side over an accepted `stream`; the returned stream
+/// carries the tunnel
+pub async fn accept<S>(mut stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut session = Session::new(None);
+    let early = handshake(&mut stream, &mut session).await?;
+    Ok(spawn_pump(stream, session, early))
+}
+
+/// Open a KCP session to `server_addr` carried in OpenVPN UDP packets,
+/// through a relay allocated on `turn` if given
+pub async fn connect_kcp(
+    server_addr: SocketAddr,
+    server_name: &str,
+    turn: Option<&TurnConfig>,
+) -> io::Result<DuplexStream> {
+    let server_name = Some(server_name.to_string());
+    match turn {
+        Some(turn) => {
+            let (relay, client) = crate::turn_relay::allocate(turn).await?;
+            Ok(kcp_transport::connect_over(Arc::new(OpenVpnConn::new(relay, server_name)), server_addr, client))
+        }
+        None => {
+            let udp = Arc::new(UdpSocket::bind(kcp_transport::any_local_addr(server_addr)).await?);
+            Ok(kcp_transport::connect_over(Arc::new(OpenVpnConn::new(udp, server_name)), server_addr, ()))
+        }
+    }
+}
+
+/// Accept KCP sessions on `addr` carried in OpenVPN UDP packets
+pub async fn bind_kcp(addr: SocketAddr) -> io::Result<KcpListener> {
+    let udp = Arc::new(UdpSocket::bind(addr).await?);
+    KcpListener::bind_over(Arc::new(OpenVpnConn::new(udp, None)))
+}
+
+/// Exchange packets over `stream` until the session is ready; returns
+/// tunnel bytes that arrived meanwhile
+async fn handshake<S>(stream: &mut S, session: &mut Session) -> io::Result<Vec<Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut early = Vec::new();
+    while !session.ready {
+        let packet = read_packet(stream).await?;
+        let (replies, data) = session.receive(&packet)?;
+        for reply in replies {
+            write_packet(stream, &reply).await?;
+        }
+        stream.flush().await?;
+        early.extend(data);
+    }
+    Ok(early)
+}
+
+fn spawn_pump<S>(stream: S, session: Session, early: Vec<Vec<u8>>) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = pump(stream, session, local, early).await {
+            log::debug!("OpenVPN connection ended: {}", e);
+        }
+    });
+    tunnel
+}
+
+/// Move tunnel bytes between `local` and data packets on `stream`
+async fn pump<S>(stream: S, mut session: Session, local: DuplexStream, early: Vec<Vec<u8>>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+    for data in early {
+        app_write.write_all(&data).await?;
+    }
+
+    // Packets are read in their own task, as a partly read packet must not
+    // be dropped when another branch wins
+    let (packets_tx, mut packets) = mpsc::channel(64);
+    let reader = tokio::spawn(async move {
+        while let Ok(packet) = read_packet(&mut reader).await {
+            if packets_tx.send(packet).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let result = async {
+        let mut timers = tokio::time::interval(TIMER_TICK);
+        let mut buf = vec![0u8; MAX_DATA];
+        let mut app_open = true;
+        loop {
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else {
+                        return app_write.shutdown().await;
+                    };
+                    let (replies, data) = session.receive(&packet)?;
+                    for reply in replies {
+                        write_packet(&mut writer, &reply).await?;
+                    }
+                    if let Some(data) = data {
+                        app_write.write_all(&data).await?;
+                    }
+                }
+                n = app_read.read(&mut buf), if app_open => {
+                    let n = n?;
+                    if n == 0 {
+                        app_open = false;
+                        writer.shutdown().await?;
+                        continue;
+                    }
+                    let packet = session.seal(&buf[..n]);
+                    write_packet(&mut writer, &packet).await?;
+                }
+                _ = timers.tick(), if app_open => {
+                    for packet in session.tick(false) {
+                        write_packet(&mut writer, &packet).await?;
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    result
+}
+
+/// Write `packet` with its length prefix
+async fn write_packet<W>(writer: &mut W, packet: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut framed = Vec::with_capacity(2 + packet.len());
+    framed.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    framed.extend_from_slice(packet);
+    writer.write_all(&framed).await
+}
+
+/// Read one length-prefixed packet
+async fn read_packet<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let length = reader.read_u16().await? as usize;
+    if length == 0 || length > MAX_PACKET {
+        return Err(invalid(format!("Bad OpenVPN packet length {}", length)));
+    }
+    let mut packet = vec![0u8; length];
+    reader.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+/// Datagram socket sending OpenVPN UDP packets over `inner`, as the client
+/// if it has the `server_name` to send, or the server
+pub struct OpenVpnConn {
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    server_name: Option<String>,
+    received: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl OpenVpnConn {
+    pub fn new(inner: Socket, server_name: Option<String>) -> Self {
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, received) = mpsc::channel(RECEIVE_QUEUE);
+        let tasks = [
+            tokio::spawn(run_timers(Arc::clone(&inner), Arc::clone(&peers))),
+            tokio::spawn(receive(Arc::clone(&inner), Arc::clone(&peers), server_name.is_some(), tx)),
+        ];
+        Self {
+            inner,
+            peers,
+            server_name,
+            received: tokio::sync::Mutex::new(received),
+            tasks,
+        }
+    }
+}
+
+impl Drop for OpenVpnConn {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl webrtc::util::Conn for OpenVpnConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(unsupported())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(webrtc::util::Error::ErrUseClosedNetworkConn)?;
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// Send `buf` to `target` in a data packet, or queue it until the
+    /// session is ready; a client starts one if there is none
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let packets = {
+            let mut peers = self.peers.lock().unwrap();
+            let mut packets = Vec::new();
+            let peer = match (peers.get_mut(&target), &self.server_name) {
+                (Some(peer), _) => peer,
+                (None, Some(server_name)) => {
+                    let mut session = Session::new(Some(server_name.clone()));
+                    packets.push(session.hard_reset());
+                    peers.entry(target).or_insert(Peer::new(session))
+                }
+                // Servers only answer clients
+                (None, None) => return Ok(buf.len()),
+            };
+            packets.extend(peer.send(buf.to_vec()));
+            packets
+        };
+        for packet in packets {
+            self.inner.send_to(&packet, target).await?;
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// Session with one UDP peer, and the datagrams waiting for it
+struct Peer {
+    session: Session,
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+}
+
+impl Peer {
+    fn new(session: Session) -> Self {
+        Self { session, queue: VecDeque::new(), queued: 0 }
+    }
+
+    fn send(&mut self, datagram: Vec<u8>) -> Option<Vec<u8>> {
+        if self.session.ready {
+            return Some(self.session.seal(&datagram));
+        }
+        if self.queued + datagram.len() <= QUEUE_BYTES {
+            self.queued += datagram.len();
+            self.queue.push_back(datagram);
+        }
+        None
+    }
+
+    /// Data packets of the queued datagrams, once the session is ready
+    fn flush(&mut self) -> Vec<Vec<u8>> {
+        if !self.session.ready {
+            return Vec::new();
+        }
+        self.queued = 0;
+        let session = &mut self.session;
+        self.queue.drain(..).map(|datagram| session.seal(&datagram)).collect()
+    }
+}
+
+/// Retransmit control packets, ping and forget expired sessions
+async fn run_timers(inner: Socket, peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>) {
+    let mut interval = tokio::time::interval(TIMER_TICK);
+    loop {
+        interval.tick().await;
+        let packets: Vec<(Vec<u8>, SocketAddr)> = {
+            let mut peers = peers.lock().unwrap();
+            peers.retain(|_, peer| !peer.session.expired());
+            peers
+                .iter_mut()
+                .flat_map(|(addr, peer)| peer.session.tick(true).into_iter().map(|packet| (packet, *addr)))
+                .collect()
+        };
+        for (packet, addr) in packets {
+            if let Err(e) = inner.send_to(&packet, addr).await {
+                log::debug!("OpenVPN: send to {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// Handle received packets, passing on the datagrams they carry
+async fn receive(
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    client: bool,
+    received: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, from) = match inner.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(webrtc::util::Error::ErrUseClosedNetworkConn) => return,
+            Err(e) => {
+                log::debug!("OpenVPN: receive error: {}", e);
+                continue;
+            }
+        };
+        let packet = &buf[..n];
+        let (replies, datagram) = {
+            let mut peers = peers.lock().unwrap();
+            // A server starts a session for each client hard reset, replacing
+            // the session of a client that restarted
+            let hard_reset = !client && packet.len() >= 9 && packet[0] >> 3 == P_CONTROL_HARD_RESET_CLIENT_V2;
+            let restarted = |peer: &Peer| peer.session.remote_id.is_some_and(|id| id[..] != packet[1..9]);
+            if hard_reset && peers.get(&from).is_none_or(restarted) {
+                peers.insert(from, Peer::new(Session::new(None)));
+            }
+            let Some(peer) = peers.get_mut(&from) else { continue };
+            match peer.session.receive(packet) {
+                Ok((mut replies, datagram)) => {
+                    replies.extend(peer.flush());
+                    (replies, datagram)
+                }
+                Err(e) => {
+                    log::debug!("OpenVPN: packet from {} dropped: {}", from, e);
+                    if !peer.session.started() {
+                        peers.remove(&from);
+                    }
+                    continue;
+                }
+            }
+        };
+        for reply in replies {
+            if let Err(e) = inner.send_to(&reply, from).await {
+                log::debug!("OpenVPN: send to {} failed: {}", from, e);
+            }
+        }
+        if let Some(datagram) = datagram {
+            match received.try_send((datagram, from)) {
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => log::debug!("OpenVPN: receive queue full, dropping datagram"),
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// Packets to send back, and the tunnel bytes of the packet received
+type Received = (Vec<Vec<u8>>, Option<Vec<u8>>);
+
+/// Control packet sent and not acknowledged yet
+struct Unacked {
+    packet_id: u32,
+    packet: Vec<u8>,
+    sent: Instant,
+    timeout: Duration,
+}
+
+/// Data channel keys of one direction
+struct DataKey {
+    key: LessSafeKey,
+    /// Implicit part of the nonce, after the packet ID
+    iv: [u8; 8],
+}
+
+/// One OpenVPN session, without I/O: packets in, packets out
+struct Session {
+    /// Server name for a client, `None` for the server
+    server_name: Option<String>,
+    local_id: [u8; 8],
+    remote_id: Option<[u8; 8]>,
+    next_packet_id: u32,
+    unacked: Vec<Unacked>,
+    acks: VecDeque<u32>,
+    /// Next control packet ID expected from the peer
+    expected: u32,
+    /// Control packets received ahead of `expected`
+    early: BTreeMap<u32, (u8, Vec<u8>)>,
+    /// TLS bytes of the flight being received
+    records: Vec<u8>,
+    stage: usize,
+    ready: bool,
+    peer_id: u32,
+    send_key: Option<DataKey>,
+    receive_key: Option<DataKey>,
+    data_packet_id: u32,
+    started: Instant,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl Session {
+    fn new(server_name: Option<String>) -> Self {
+        let now = Instant::now();
+        Self {
+            server_name,
+            local_id: rand::thread_rng().gen(),
+            remote_id: None,
+            next_packet_id: 0,
+            unacked: Vec::new(),
+            acks: VecDeque::new(),
+            expected: 0,
+            early: BTreeMap::new(),
+            records: Vec::new(),
+            stage: 0,
+            ready: false,
+            peer_id: 0,
+            send_key: None,
+            receive_key: None,
+            data_packet_id: 1,
+            started: now,
+            last_sent: now,
+            last_received: now,
+        }
+    }
+
+    fn client(&self) -> bool {
+        self.server_name.is_some()
+    }
+
+    /// Whether the peer has been heard from
+    fn started(&self) -> bool {
+        self.remote_id.is_some()
+    }
+
+    fn expired(&self) -> bool {
+        (!self.ready && self.started.elapsed() >= HAND_WINDOW) || self.last_received.elapsed() >= PING_RESTART
+    }
+
+    /// The client's first packet
+    fn hard_reset(&mut self) -> Vec<u8> {
+        self.control(P_CONTROL_HARD_RESET_CLIENT_V2, &[])
+    }
+
+    /// Control packet with `payload`, acknowledging what is pending
+    fn control(&mut self, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(32 + payload.len());
+        packet.push(opcode << 3);
+        packet.extend_from_slice(&self.local_id);
+        self.put_acks(&mut packet);
+        packet.extend_from_slice(&self.next_packet_id.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.unacked.push(Unacked {
+            packet_id: self.next_packet_id,
+            packet: packet.clone(),
+            sent: Instant::now(),
+            timeout: INITIAL_RETRANSMIT,
+        });
+        self.next_packet_id += 1;
+        packet
+    }
+
+    /// Ack array and, if it is not empty, the remote session ID
+    fn put_acks(&mut self, packet: &mut Vec<u8>) {
+        let count = self.acks.len().min(MAX_ACKS);
+        packet.push(count as u8);
+        for packet_id in self.acks.drain(..count) {
+            packet.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        if count > 0 {
+            packet.extend_from_slice(&self.remote_id.unwrap_or_default());
+        }
+    }
+
+    /// Control packets carrying a TLS `flight`
+    fn send_flight(&mut self, flight: &[u8]) -> Vec<Vec<u8>> {
+        flight.chunks(MAX_CONTROL_PAYLOAD).map(|chunk| self.control(P_CONTROL_V1, chunk)).collect()
+    }
+
+    /// Handle a received `packet`: packets to send back, and the tunnel
+    /// bytes it carried, if any
+    fn receive(&mut self, packet: &[u8]) -> io::Result<Received> {
+        let opcode = packet.first().ok_or_else(|| invalid("Empty OpenVPN packet".to_string()))? >> 3;
+        if opcode == P_DATA_V2 {
+            return Ok((Vec::new(), self.open(packet)));
+        }
+
+        if packet.len() < 10 {
+            return Err(invalid(format!("OpenVPN control packet of {} bytes", packet.len())));
+        }
+        let session_id: [u8; 8] = packet[1..9].try_into().unwrap();
+        let count = packet[9] as usize;
+        let mut rest = &packet[10..];
+        let acks_len = count * 4 + if count > 0 { 8 } else { 0 };
+        if count > 8 || rest.len() < acks_len {
+            return Err(invalid("Malformed OpenVPN ack array".to_string()));
+        }
+        if count > 0 && rest[count * 4..acks_len] != self.local_id {
+            return Err(invalid("OpenVPN ack for another session".to_string()));
+        }
+        match (self.remote_id, opcode, self.client()) {
+            (Some(id), _, _) if id != session_id => return Err(invalid("OpenVPN session ID changed".to_string())),
+            (Some(_), _, _) => {}
+            (None, P_CONTROL_HARD_RESET_CLIENT_V2, false) | (None, P_CONTROL_HARD_RESET_SERVER_V2, true) => {
+                self.remote_id = Some(session_id);
+                self.derive_keys();
+            }
+            (None, opcode, _) => return Err(invalid(format!("Unexpected OpenVPN opcode {} before the hard reset", opcode))),
+        }
+        for acked in rest[..count * 4].chunks(4) {
+            let acked = u32::from_be_bytes(acked.try_into().unwrap());
+            self.unacked.retain(|unacked| unacked.packet_id != acked);
+        }
+        rest = &rest[acks_len..];
+        self.last_received = Instant::now();
+        if opcode == P_ACK_V1 {
+            return Ok((Vec::new(), None));
+        }
+
+        if rest.len() < 4 {
+            return Err(invalid("OpenVPN control packet without a packet ID".to_string()));
+        }
+        let packet_id = u32::from_be_bytes(rest[..4].try_into().unwrap());
+        // Duplicates are acknowledged again, as the first ack may be lost
+        self.acks.push_back(packet_id);
+        if packet_id >= self.expected {
+            self.early.insert(packet_id, (opcode, rest[4..].to_vec()));
+        }
+        let mut replies = Vec::new();
+        while let Some((opcode, payload)) = self.early.remove(&self.expected) {
+            self.expected += 1;
+            replies.extend(self.deliver(opcode, &payload)?);
+        }
+        while !self.acks.is_empty() {
+            let mut ack = vec![P_ACK_V1 << 3];
+            ack.extend_from_slice(&self.local_id);
+            self.put_acks(&mut ack);
+            replies.push(ack);
+        }
+        Ok((replies, None))
+    }
+
+    /// Handle the next control packet in order
+    fn deliver(&mut self, opcode: u8, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        match (opcode, self.client()) {
+            (P_CONTROL_HARD_RESET_CLIENT_V2, false) => Ok(vec![self.control(P_CONTROL_HARD_RESET_SERVER_V2, &[])]),
+            (P_CONTROL_HARD_RESET_SERVER_V2, true) => {
+                let server_name = self.server_name.clone().unwrap_or_default();
+                let (hello, _) =
+                    crate::tls_handshake::client_hello(&server_name, &random_bytes(32)).map_err(io::Error::other)?;
+                Ok(self.send_flight(&hello))
+            }
+            (P_CONTROL_V1, _) if !self.ready => {
+                self.records.extend_from_slice(payload);
+                let awaited = if self.client() { CLIENT_AWAITS } else { SERVER_AWAITS };
+                if count_records(&self.records) < awaited[self.stage] {
+                    return Ok(Vec::new());
+                }
+                let session_id = self.records.get(44..76).map(<[u8]>::to_vec).unwrap_or_default();
+                self.records.clear();
+                let flight = self.next_flight(&session_id)?;
+                Ok(self.send_flight(&flight))
+            }
+            (P_CONTROL_V1, _) => Ok(Vec::new()),
+            (opcode, _) => Err(invalid(format!("Unexpected OpenVPN opcode {}", opcode))),
+        }
+    }
+
+    /// TLS flight answering the one just received, moving to the next
+    /// stage; empty once the handshake is done
+    fn next_flight(&mut self, client_hello_session_id: &[u8]) -> io::Result<Vec<u8>> {
+        let mut rng = rand::thread_rng();
+        self.stage += 1;
+        let flight = match (self.client(), self.stage) {
+            // Finished, then the key method 2 message
+            (true, 1) => {
+                let mut flight = crate::tls_handshake::client_finished(&[]).map_err(io::Error::other)?;
+                flight.extend_from_slice(&application_data(rng.gen_range(260..340)));
+                flight
+            }
+            // "PUSH_REQUEST"
+            (true, 2) => application_data(13 + 17),
+            (true, _) => Vec::new(),
+            (false, 1) => {
+                crate::tls_handshake::server_flight(client_hello_session_id, &random_bytes(32)).map_err(io::Error::other)?
+            }
+            // Key method 2 message
+            (false, 2) => application_data(rng.gen_range(260..340)),
+            // "PUSH_REPLY" with routes, DNS servers and the peer ID
+            (false, _) => application_data(rng.gen_range(300..500)),
+        };
+        self.ready = self.stage == 3;
+        Ok(flight)
+    }
+
+    /// Keys of both directions and the peer ID, from the session IDs
+    fn derive_keys(&mut self) {
+        let remote_id = self.remote_id.unwrap_or_default();
+        let (client_id, server_id) = if self.client() { (self.local_id, remote_id) } else { (remote_id, self.local_id) };
+        let derive = |direction: u8| {
+            let mut context = digest::Context::new(&digest::SHA256);
+            context.update(b"nooshdaroo openvpn framing");
+            context.update(&[direction]);
+            context.update(&client_id);
+            context.update(&server_id);
+            context.finish()
+        };
+        let key = |direction: u8| {
+            let key = UnboundKey::new(&CHACHA20_POLY1305, derive(direction).as_ref()).expect("32-byte key");
+            let iv = derive(direction + 2).as_ref()[..8].try_into().unwrap();
+            DataKey { key: LessSafeKey::new(key), iv }
+        };
+        let (send, receive) = if self.client() { (1, 2) } else { (2, 1) };
+        self.send_key = Some(key(send));
+        self.receive_key = Some(key(receive));
+        // Servers hand out small peer IDs
+        self.peer_id = u32::from(server_id[7]);
+    }
+
+    /// `P_DATA_V2` packet carrying `data`
+    fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(DATA_HEADER_SIZE + TAG_SIZE + data.len());
+        packet.extend_from_slice(&((u32::from(P_DATA_V2 << 3) << 24) | self.peer_id).to_be_bytes());
+        packet.extend_from_slice(&self.data_packet_id.to_be_bytes());
+        let key = self.send_key.as_ref().expect("keys derived before data is sent");
+        let mut ciphertext = data.to_vec();
+        let tag = key
+            .key
+            .seal_in_place_separate_tag(nonce(&packet[4..8], &key.iv), Aad::from(&packet[..]), &mut ciphertext)
+            .expect("data fits an OpenVPN packet");
+        packet.extend_from_slice(tag.as_ref());
+        packet.extend_from_slice(&ciphertext);
+        self.data_packet_id += 1;
+        self.last_sent = Instant::now();
+        packet
+    }
+
+    /// Tunnel bytes of a `P_DATA_V2` packet; `None` for a ping or a packet
+    /// that does not authenticate
+    fn open(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < DATA_HEADER_SIZE + TAG_SIZE {
+            return None;
+        }
+        let key = self.receive_key.as_ref()?;
+        let (header, rest) = packet.split_at(DATA_HEADER_SIZE);
+        let (tag, ciphertext) = rest.split_at(TAG_SIZE);
+        let tag = Tag::try_from(tag).ok()?;
+        let mut data = ciphertext.to_vec();
+        key.key.open_in_place_separate_tag(nonce(&header[4..], &key.iv), Aad::from(header), tag, &mut data, 0..).ok()?;
+        self.last_received = Instant::now();
+        Some(data).filter(|data| data[..] != PING)
+    }
+
+    /// Packets the timers send now: control packet retransmissions over
+    /// UDP and pings
+    fn tick(&mut self, retransmit: bool) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        if retransmit {
+            for unacked in self.unacked.iter_mut().filter(|unacked| unacked.sent.elapsed() >= unacked.timeout) {
+                packets.push(unacked.packet.clone());
+                unacked.sent = Instant::now();
+                unacked.timeout = (unacked.timeout * 2).min(MAX_RETRANSMIT);
+            }
+        }
+        if self.ready && self.last_sent.elapsed() >= PING_INTERVAL {
+            packets.push(self.seal(&PING));
+        }
+        packets
+    }
+}
+
+/// Complete TLS records at the start of `bytes`
+fn count_records(bytes: &[u8]) -> usize {
+    let (mut count, mut pos) = (0, 0);
+    while pos + 5 <= bytes.len() {
+        let length = u16::from_be_bytes([bytes[pos + 3], bytes[pos + 4]]) as usize;
+        if pos + 5 + length > bytes.len() {
+            break;
+        }
+        count += 1;
+        pos += 5 + length;
+    }
+    count
+}
+
+/// TLS 1.3 application data record with `length` bytes of ciphertext
+fn application_data(length: usize) -> Vec<u8> {
+    let mut record = vec![0x17, 0x03, 0x03];
+    record.extend_from_slice(&(length as u16).to_be_bytes());
+    record.extend_from_slice(&random_bytes(length));
+    record
+}
+
+/// OpenVPN's AEAD nonce: the packet ID, then the implicit IV
+fn nonce(packet_id: &[u8], iv: &[u8; 8]) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(packet_id);
+    nonce[4..].copy_from_slice(iv);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unsupported() -> webrtc::util::Error {
+    webrtc::util::Error::Other("OpenVPN sockets are unconnected".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tcp_session_then_tunnel() {
+        let mut session = Session::new(Some("vpn.example.com".to_string()));
+        let reset = session.hard_reset();
+        assert_eq!(reset.len(), 1 + 8 + 1 + 4);
+        assert_eq!((reset[0], &reset[9..]), (P_CONTROL_HARD_RESET_CLIENT_V2 << 3, &[0u8, 0, 0, 0, 0][..]));
+
+        let (client_side, server_side) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(async move {
+            let mut tunnel = accept(server_side).await.unwrap();
+            let mut request = vec![0u8; 5000];
+            tunnel.read_exact(&mut request).await.unwrap();
+            assert!(request.iter().enumerate().all(|(i, b)| *b == i as u8));
+            tunnel.write_all(b"response").await.unwrap();
+        });
+        let mut tunnel = connect(client_side, "vpn.example.com").await.unwrap();
+        let request: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        tunnel.write_all(&request).await.unwrap();
+        let mut response = [0u8; 8];
+        tunnel.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"response");
+        server.await.unwrap();
+
+        // Scanners without a hard reset are refused
+        let (mut scanner, server_side) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(accept(server_side));
+        write_packet(&mut scanner, &[P_CONTROL_V1 << 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kcp_over_openvpn() {
+        let mut listener = bind_kcp("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = connect_kcp(listener.local_addr(), "vpn.example.com", None).await.unwrap();
+
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = vec![0u8; data.len()];
+        tokio::time::timeout(Duration::from_secs(5), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"bye").await.unwrap();
+        let mut buf = [0u8; 3];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"bye");
+    }
+}
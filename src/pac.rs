@@ -0,0 +1,1117 @@
+//! Proxy auto-config (PAC) scripts
+//!
+//! Managed machines are often told which proxy to use by a PAC script, a
+//! JavaScript file defining `FindProxyForURL(url, host)`. Browsers run it
+//! with a full JavaScript engine; PAC scripts in practice stick to a small
+//! subset, which this module evaluates itself: `var` declarations,
+//! functions, `if`/`else`, `return`, the usual operators on strings,
+//! numbers and booleans, a few string methods, and the PAC helpers such as
+//! `shExpMatch`, `dnsDomainIs` and `isInNet`. Scripts using anything else,
+//! such as loops or regular expressions, are rejected when parsed.
+//!
+//! Many corporate PAC files go beyond that subset. With the `pac-js` feature
+//! such scripts run in an embedded QuickJS engine instead, with the same
+//! helpers; without it they are rejected, and connections that depend on
+//! them fail rather than going direct.
+
+use chrono::{Datelike, Timelike};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Largest PAC script fetched
+const MAX_SCRIPT: usize = 1024 * 1024;
+
+/// Time allowed for fetching a script over HTTP
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Deepest nesting of function calls, bounding recursive scripts
+const MAX_CALL_DEPTH: usize = 32;
+
+/// Deepest nesting of statements and expressions, so a script such as
+/// `((((…` cannot exhaust the stack of the parser or the interpreter
+const MAX_NESTING: usize = 64;
+
+const PUNCTUATION: &[&str] = &[
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ",", ";", ".", "!", "<", ">", "+", "-", "*",
+    "/", "%", "=", "?", ":",
+];
+
+/// A proxy `FindProxyForURL` returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacProxy {
+    Direct,
+    /// `PROXY host:port`
+    Http(String),
+    /// `SOCKS host:port` or `SOCKS5 host:port`
+    Socks(String),
+}
+
+/// Proxies in the order to try them, from a result such as
+/// `PROXY proxy.corp:8080; DIRECT`; entries of other kinds are skipped
+pub fn parse_proxies(result: &str) -> Vec<PacProxy> {
+    let mut proxies: Vec<PacProxy> = result
+        .split(';')
+        .filter_map(|entry| {
+            let mut words = entry.split_whitespace();
+            let kind = words.next()?.to_ascii_uppercase();
+            match (kind.as_str(), words.next()) {
+                ("DIRECT", _) => Some(PacProxy::Direct),
+                ("PROXY", Some(address)) => Some(PacProxy::Http(address.to_string())),
+                ("SOCKS" | "SOCKS5", Some(address)) => Some(PacProxy::Socks(address.to_string())),
+                _ => {
+                    log::debug!("Skipping unsupported PAC proxy {:?}", entry.trim());
+                    None
+                }
+            }
+        })
+        .collect();
+    if result.trim().is_empty() {
+        proxies.push(PacProxy::Direct);
+    }
+    proxies
+}
+
+/// Fetch the script at `location`: an `http://` URL, or a file path
+/// (optionally as a `file://` URL)
+pub async fn fetch(location: &str) -> io::Result<String> {
+    match location.strip_prefix("http://") {
+        Some(rest) => tokio::time::timeout(FETCH_TIMEOUT, fetch_http(rest))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("Timed out fetching {}", location)))?,
+        None => tokio::fs::read_to_string(location.strip_prefix("file://").unwrap_or(location)).await,
+    }
+}
+
+async fn fetch_http(rest: &str) -> io::Result<String> {
+    let (authority, path) = match rest.split_once('/') {
+        Some((authority, path)) => (authority, format!("/{}", path)),
+        None => (rest, "/".to_string()),
+    };
+    let has_port = authority.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']'));
+    let address = if has_port { authority.to_string() } else { format!("{}:80", authority) };
+
+    let mut stream = tokio::net::TcpStream::connect(&address).await?;
+    // HTTP/1.0 keeps the body unchunked
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/x-ns-proxy-autoconfig, */*\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.take((MAX_SCRIPT + 8192) as u64).read_to_end(&mut response).await?;
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("PAC server sent no response head".to_string()))?;
+    let status_line = String::from_utf8_lossy(&response[..head_end]).lines().next().unwrap_or("").to_string();
+    if status_line.split(' ').nth(1) != Some("200") {
+        return Err(io::Error::other(format!("PAC server answered {:?}", status_line)));
+    }
+    let body = &response[head_end + 4..];
+    if body.len() > MAX_SCRIPT {
+        return Err(invalid(format!("PAC script larger than {} bytes", MAX_SCRIPT)));
+    }
+    Ok(String::from_utf8_lossy(body).into_owned())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A parsed PAC script
+#[derive(Debug)]
+pub struct PacScript {
+    body: Vec<Stmt>,
+    functions: HashMap<String, Function>,
+    /// Source of a script outside the subset, run by QuickJS instead
+    #[cfg(feature = "pac-js")]
+    js: Option<String>,
+}
+
+impl PacScript {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        match Self::parse_subset(source) {
+            #[cfg(feature = "pac-js")]
+            Err(e) => {
+                log::debug!("PAC script is outside the built-in subset ({}), running it in QuickJS", e);
+                quickjs::check(source)?;
+                Ok(Self {
+                    body: Vec::new(),
+                    functions: HashMap::new(),
+                    js: Some(source.to_string()),
+                })
+            }
+            result => result,
+        }
+    }
+
+    fn parse_subset(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            depth: 0,
+            functions: HashMap::new(),
+        };
+        let mut body = Vec::new();
+        while parser.pos < parser.tokens.len() {
+            body.push(parser.statement()?);
+        }
+        if !parser.functions.contains_key("FindProxyForURL") {
+            return Err("PAC script does not define FindProxyForURL".to_string());
+        }
+        Ok(Self {
+            body,
+            functions: parser.functions,
+            #[cfg(feature = "pac-js")]
+            js: None,
+        })
+    }
+
+    /// Run `FindProxyForURL(url, host)`; `my_ip` is what `myIpAddress()`
+    /// returns. Name lookups in the script block the calling thread.
+    pub fn find_proxy(&self, url: &str, host: &str, my_ip: IpAddr) -> Result<String, String> {
+        #[cfg(feature = "pac-js")]
+        if let Some(ref source) = self.js {
+            return quickjs::find_proxy(source, url, host, my_ip);
+        }
+        let mut interpreter = Interpreter {
+            script: self,
+            frames: vec![HashMap::new()],
+            my_ip,
+        };
+        for stmt in &self.body {
+            interpreter.exec(stmt)?;
+        }
+        match interpreter.call("FindProxyForURL", vec![Value::Str(url.to_string()), Value::Str(host.to_string())])? {
+            Value::Str(result) => Ok(result),
+            other => Err(format!("FindProxyForURL returned {:?}, not a string", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Num(f64),
+    Str(String),
+    Punct(&'static str),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            let end = (i + 2..chars.len().saturating_sub(1))
+                .find(|&j| chars[j] == '*' && chars[j + 1] == '/')
+                .ok_or("Unterminated comment in PAC script")?;
+            i = end + 2;
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '$') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let number: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(number.parse().map_err(|_| format!("Bad number {:?} in PAC script", number))?));
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("Unterminated string in PAC script".to_string()),
+                    Some(&end) if end == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        s.push(match chars.get(i) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some(&escaped) => escaped,
+                            None => return Err("Unterminated string in PAC script".to_string()),
+                        });
+                    }
+                    Some(&ch) => s.push(ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(s));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+            let punct = PUNCTUATION
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .ok_or_else(|| format!("Unexpected {:?} in PAC script", c))?;
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Stmt {
+    Var(Vec<(String, Option<Expr>)>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Return(Option<Expr>),
+    Block(Vec<Stmt>),
+    Expr(Expr),
+    Empty,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Ident(String),
+    Call(Box<Expr>, Vec<Expr>),
+    Member(Box<Expr>, String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    Assign(String, Box<Expr>),
+}
+
+#[derive(Debug)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+/// Binary operators from lowest to highest precedence
+const BINARY_LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["===", "!==", "==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Statements and expressions being parsed inside one another
+    depth: usize,
+    functions: HashMap<String, Function>,
+}
+
+impl Parser {
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        let found = self.is_punct(punct);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<(), String> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            Err(format!("Expected {:?} in PAC script, found {:?}", punct, self.tokens.get(self.pos)))
+        }
+    }
+
+    fn keyword(&self) -> Option<&str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Ident(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(name.clone())
+            }
+            other => Err(format!("Expected a name in PAC script, found {:?}", other)),
+        }
+    }
+
+    /// Run `parse` one level deeper, failing past [`MAX_NESTING`]
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= MAX_NESTING {
+            return Err(format!("PAC script nests deeper than {} levels", MAX_NESTING));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        self.expect_punct("{")?;
+        let mut stmts = Vec::new();
+        while !self.eat_punct("}") {
+            if self.pos >= self.tokens.len() {
+                return Err("Unterminated block in PAC script".to_string());
+            }
+            stmts.push(self.statement()?);
+        }
+        Ok(stmts)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, String> {
+        self.nested(Self::simple_statement)
+    }
+
+    fn simple_statement(&mut self) -> Result<Stmt, String> {
+        if self.is_punct("{") {
+            return Ok(Stmt::Block(self.block()?));
+        }
+        if self.eat_punct(";") {
+            return Ok(Stmt::Empty);
+        }
+        let stmt = match self.keyword() {
+            Some("var" | "let" | "const") => {
+                self.pos += 1;
+                let mut declarations = Vec::new();
+                loop {
+                    let name = self.ident()?;
+                    let init = if self.eat_punct("=") { Some(self.expression()?) } else { None };
+                    declarations.push((name, init));
+                    if !self.eat_punct(",") {
+                        break;
+                    }
+                }
+                Stmt::Var(declarations)
+            }
+            Some("if") => {
+                self.pos += 1;
+                self.expect_punct("(")?;
+                let condition = self.expression()?;
+                self.expect_punct(")")?;
+                let then = Box::new(self.statement()?);
+                let otherwise = if self.keyword() == Some("else") {
+                    self.pos += 1;
+                    Some(Box::new(self.statement()?))
+                } else {
+                    None
+                };
+                return Ok(Stmt::If(condition, then, otherwise));
+            }
+            Some("return") => {
+                self.pos += 1;
+                if self.is_punct(";") || self.is_punct("}") {
+                    Stmt::Return(None)
+                } else {
+                    Stmt::Return(Some(self.expression()?))
+                }
+            }
+            Some("function") => {
+                self.pos += 1;
+                let name = self.ident()?;
+                self.expect_punct("(")?;
+                let mut params = Vec::new();
+                while !self.eat_punct(")") {
+                    params.push(self.ident()?);
+                    if !self.is_punct(")") {
+                        self.expect_punct(",")?;
+                    }
+                }
+                let body = self.block()?;
+                self.functions.insert(name, Function { params, body });
+                return Ok(Stmt::Empty);
+            }
+            Some(
+                keyword @ ("for" | "while" | "do" | "switch" | "try" | "throw" | "new" | "break" | "continue" | "with"),
+            ) => return Err(format!("PAC scripts using {:?} are not supported", keyword)),
+            _ => Stmt::Expr(self.expression()?),
+        };
+        self.eat_punct(";");
+        Ok(stmt)
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        self.nested(Self::assignment)
+    }
+
+    fn assignment(&mut self) -> Result<Expr, String> {
+        let target = self.conditional()?;
+        if !self.eat_punct("=") {
+            return Ok(target);
+        }
+        match target {
+            Expr::Ident(name) => Ok(Expr::Assign(name, Box::new(self.expression()?))),
+            other => Err(format!("Cannot assign to {:?} in PAC script", other)),
+        }
+    }
+
+    fn conditional(&mut self) -> Result<Expr, String> {
+        let condition = self.binary(0)?;
+        if !self.eat_punct("?") {
+            return Ok(condition);
+        }
+        let then = self.expression()?;
+        self.expect_punct(":")?;
+        let otherwise = self.expression()?;
+        Ok(Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(operators) = BINARY_LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = operators.iter().find(|op| self.is_punct(op)) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        for op in ["!", "-", "+"] {
+            if self.eat_punct(op) {
+                return Ok(Expr::Unary(op, Box::new(self.nested(Self::unary)?)));
+            }
+        }
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat_punct("(") {
+                let mut args = Vec::new();
+                while !self.eat_punct(")") {
+                    args.push(self.expression()?);
+                    if !self.is_punct(")") {
+                        self.expect_punct(",")?;
+                    }
+                }
+                expr = Expr::Call(Box::new(expr), args);
+            } else if self.eat_punct(".") {
+                expr = Expr::Member(Box::new(expr), self.ident()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        Ok(match token {
+            Some(Token::Num(n)) => Expr::Literal(Value::Num(n)),
+            Some(Token::Str(s)) => Expr::Literal(Value::Str(s)),
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                "null" => Expr::Literal(Value::Null),
+                "undefined" => Expr::Literal(Value::Undefined),
+                _ => Expr::Ident(name),
+            },
+            Some(Token::Punct("(")) => {
+                let expr = self.expression()?;
+                self.expect_punct(")")?;
+                expr
+            }
+            other => return Err(format!("Unexpected {:?} in PAC script", other)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Undefined,
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Undefined | Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Num(n) => *n != 0.0 && !n.is_nan(),
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    fn to_num(&self) -> f64 {
+        match self {
+            Value::Undefined => f64::NAN,
+            Value::Null => 0.0,
+            Value::Bool(b) => *b as u8 as f64,
+            Value::Num(n) => *n,
+            Value::Str(s) if s.trim().is_empty() => 0.0,
+            Value::Str(s) => s.trim().parse().unwrap_or(f64::NAN),
+        }
+    }
+
+    fn to_str(&self) -> String {
+        match self {
+            Value::Undefined => "undefined".to_string(),
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => format!("{}", *n as i64),
+            Value::Num(n) if n.is_nan() => "NaN".to_string(),
+            Value::Num(n) => n.to_string(),
+            Value::Str(s) => s.clone(),
+        }
+    }
+
+    fn loose_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Undefined | Value::Null, Value::Undefined | Value::Null) => true,
+            (Value::Undefined | Value::Null, _) | (_, Value::Undefined | Value::Null) => false,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (a, b) => a.to_num() == b.to_num(),
+        }
+    }
+}
+
+struct Interpreter<'a> {
+    script: &'a PacScript,
+    /// Globals, then the locals of the function running
+    frames: Vec<HashMap<String, Value>>,
+    my_ip: IpAddr,
+}
+
+impl Interpreter<'_> {
+    /// Run `stmt`, giving the value it returns, if it returns
+    fn exec(&mut self, stmt: &Stmt) -> Result<Option<Value>, String> {
+        match stmt {
+            Stmt::Var(declarations) => {
+                for (name, init) in declarations {
+                    let value = match init {
+                        Some(init) => self.eval(init)?,
+                        None => Value::Undefined,
+                    };
+                    self.frames.last_mut().unwrap().insert(name.clone(), value);
+                }
+            }
+            Stmt::If(condition, then, otherwise) => {
+                if self.eval(condition)?.truthy() {
+                    return self.exec(then);
+                } else if let Some(otherwise) = otherwise {
+                    return self.exec(otherwise);
+                }
+            }
+            Stmt::Return(value) => {
+                return Ok(Some(match value {
+                    Some(value) => self.eval(value)?,
+                    None => Value::Undefined,
+                }))
+            }
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    if let Some(value) = self.exec(stmt)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+            Stmt::Expr(expr) => {
+                self.eval(expr)?;
+            }
+            Stmt::Empty => {}
+        }
+        Ok(None)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        Ok(match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Ident(name) => self
+                .frames
+                .last()
+                .and_then(|locals| locals.get(name))
+                .or_else(|| self.frames[0].get(name))
+                .cloned()
+                .ok_or_else(|| format!("{} is not defined in PAC script", name))?,
+            Expr::Assign(name, value) => {
+                let value = self.eval(value)?;
+                let frame = if self.frames.last().unwrap().contains_key(name) { self.frames.len() - 1 } else { 0 };
+                self.frames[frame].insert(name.clone(), value.clone());
+                value
+            }
+            Expr::Call(callee, args) => {
+                let this = match callee.as_ref() {
+                    Expr::Member(object, _) => Some(self.eval(object)?),
+                    _ => None,
+                };
+                let args = args.iter().map(|arg| self.eval(arg)).collect::<Result<Vec<_>, _>>()?;
+                match (callee.as_ref(), this) {
+                    (Expr::Ident(name), _) => self.call(name, args)?,
+                    (Expr::Member(_, method), Some(this)) => string_method(&this.to_str(), method, &args)?,
+                    (other, _) => return Err(format!("Cannot call {:?} in PAC script", other)),
+                }
+            }
+            Expr::Member(object, property) => match (self.eval(object)?, property.as_str()) {
+                (Value::Str(s), "length") => Value::Num(s.chars().count() as f64),
+                (value, property) => return Err(format!("Unsupported property {}.{} in PAC script", value.to_str(), property)),
+            },
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                match *op {
+                    "!" => Value::Bool(!value.truthy()),
+                    "-" => Value::Num(-value.to_num()),
+                    _ => Value::Num(value.to_num()),
+                }
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                if self.eval(condition)?.truthy() {
+                    self.eval(then)?
+                } else {
+                    self.eval(otherwise)?
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                match *op {
+                    "||" if lhs.truthy() => return Ok(lhs),
+                    "&&" if !lhs.truthy() => return Ok(lhs),
+                    "||" | "&&" => return self.eval(rhs),
+                    _ => {}
+                }
+                let rhs = self.eval(rhs)?;
+                match *op {
+                    "==" => Value::Bool(lhs.loose_eq(&rhs)),
+                    "!=" => Value::Bool(!lhs.loose_eq(&rhs)),
+                    "===" => Value::Bool(lhs == rhs),
+                    "!==" => Value::Bool(lhs != rhs),
+                    "+" => match (&lhs, &rhs) {
+                        (Value::Str(_), _) | (_, Value::Str(_)) => Value::Str(lhs.to_str() + &rhs.to_str()),
+                        _ => Value::Num(lhs.to_num() + rhs.to_num()),
+                    },
+                    "-" => Value::Num(lhs.to_num() - rhs.to_num()),
+                    "*" => Value::Num(lhs.to_num() * rhs.to_num()),
+                    "/" => Value::Num(lhs.to_num() / rhs.to_num()),
+                    "%" => Value::Num(lhs.to_num() % rhs.to_num()),
+                    op => {
+                        let ordering = match (&lhs, &rhs) {
+                            (Value::Str(a), Value::Str(b)) => Some(a.cmp(b)),
+                            _ => lhs.to_num().partial_cmp(&rhs.to_num()),
+                        };
+                        Value::Bool(ordering.is_some_and(|ordering| match op {
+                            "<" => ordering.is_lt(),
+                            ">" => ordering.is_gt(),
+                            "<=" => ordering.is_le(),
+                            _ => ordering.is_ge(),
+                        }))
+                    }
+                }
+            }
+        })
+    }
+
+    /// Call the script's function `name`, or else the PAC helper
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let Some(function) = self.script.functions.get(name) else {
+            return builtin(name, &args, self.my_ip);
+        };
+        if self.frames.len() > MAX_CALL_DEPTH {
+            return Err("PAC script recursed too deeply".to_string());
+        }
+        let mut locals: HashMap<String, Value> = function.params.iter().cloned().zip(args).collect();
+        for param in &function.params {
+            locals.entry(param.clone()).or_insert(Value::Undefined);
+        }
+        self.frames.push(locals);
+        let mut result = Ok(Value::Undefined);
+        for stmt in &function.body {
+            match self.exec(stmt) {
+                Ok(Some(value)) => {
+                    result = Ok(value);
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.frames.pop();
+        result
+    }
+}
+
+/// The PAC helper `name`; `my_ip` is what `myIpAddress()` returns
+fn builtin(name: &str, args: &[Value], my_ip: IpAddr) -> Result<Value, String> {
+    let arg = |i: usize| args.get(i).map(Value::to_str).unwrap_or_default();
+    Ok(match name {
+        "isPlainHostName" => Value::Bool(!arg(0).contains('.')),
+        "dnsDomainIs" => Value::Bool(arg(0).to_ascii_lowercase().ends_with(&arg(1).to_ascii_lowercase())),
+        "localHostOrDomainIs" => {
+            let (host, qualified) = (arg(0).to_ascii_lowercase(), arg(1).to_ascii_lowercase());
+            Value::Bool(host == qualified || (!host.contains('.') && qualified.starts_with(&format!("{}.", host))))
+        }
+        "isResolvable" => Value::Bool(resolve(&arg(0)).is_some()),
+        "dnsResolve" => resolve(&arg(0)).map_or(Value::Null, |ip| Value::Str(ip.to_string())),
+        "myIpAddress" => Value::Str(my_ip.to_string()),
+        "dnsDomainLevels" => Value::Num(arg(0).matches('.').count() as f64),
+        "isInNet" => {
+            let ip = match resolve(&arg(0)) {
+                Some(IpAddr::V4(ip)) => u32::from(ip),
+                _ => return Ok(Value::Bool(false)),
+            };
+            let pattern = arg(1).parse::<Ipv4Addr>().map_err(|_| format!("isInNet: bad pattern {:?}", arg(1)))?;
+            let mask = arg(2).parse::<Ipv4Addr>().map_err(|_| format!("isInNet: bad mask {:?}", arg(2)))?;
+            Value::Bool(ip & u32::from(mask) == u32::from(pattern) & u32::from(mask))
+        }
+        "convert_addr" => Value::Num(arg(0).parse::<Ipv4Addr>().map_or(0, u32::from) as f64),
+        "shExpMatch" => {
+            let text: Vec<char> = arg(0).chars().collect();
+            let pattern: Vec<char> = arg(1).chars().collect();
+            Value::Bool(shell_match(&text, &pattern))
+        }
+        "weekdayRange" => {
+            let (args, gmt) = split_gmt(args);
+            let day = |value: &Value| {
+                ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"]
+                    .iter()
+                    .position(|day| value.to_str().eq_ignore_ascii_case(day))
+                    .ok_or_else(|| format!("weekdayRange: bad day {:?}", value.to_str()))
+            };
+            let today = if gmt {
+                chrono::Utc::now().weekday()
+            } else {
+                chrono::Local::now().weekday()
+            }
+            .num_days_from_sunday() as usize;
+            match args {
+                [first] => Value::Bool(today == day(first)?),
+                [first, last] => Value::Bool(in_range(today, day(first)?, day(last)?)),
+                _ => return Err("weekdayRange: expected one or two days".to_string()),
+            }
+        }
+        "timeRange" => {
+            let (args, gmt) = split_gmt(args);
+            let hour = if gmt { chrono::Utc::now().hour() } else { chrono::Local::now().hour() } as usize;
+            match args {
+                [first] => Value::Bool(hour == first.to_num() as usize),
+                [first, last] => Value::Bool(in_range(hour, first.to_num() as usize, last.to_num() as usize)),
+                _ => return Err("timeRange: only whole hours are supported".to_string()),
+            }
+        }
+        "alert" => {
+            log::debug!("PAC script: {}", arg(0));
+            Value::Undefined
+        }
+        _ => return Err(format!("{} is not defined in PAC script", name)),
+    })
+}
+
+fn string_method(s: &str, method: &str, args: &[Value]) -> Result<Value, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let index = |i: usize, default: usize| {
+        args.get(i).map_or(default, |value| value.to_num().max(0.0) as usize).min(chars.len())
+    };
+    Ok(match method {
+        "toLowerCase" => Value::Str(s.to_lowercase()),
+        "toUpperCase" => Value::Str(s.to_uppercase()),
+        "indexOf" => {
+            let needle = args.first().map(Value::to_str).unwrap_or_default();
+            Value::Num(s.find(&needle).map_or(-1.0, |byte| s[..byte].chars().count() as f64))
+        }
+        "substring" => {
+            let (start, end) = (index(0, 0), index(1, chars.len()));
+            Value::Str(chars[start.min(end)..start.max(end)].iter().collect())
+        }
+        "charAt" => Value::Str(chars.get(index(0, 0)).map(char::to_string).unwrap_or_default()),
+        _ => return Err(format!("Unsupported string method {} in PAC script", method)),
+    })
+}
+
+fn resolve(host: &str) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse() {
+        return Some(ip);
+    }
+    let addrs: Vec<_> = (host, 0).to_socket_addrs().ok()?.collect();
+    // Prefer IPv4, as browsers' dnsResolve does
+    addrs.iter().find(|addr| addr.is_ipv4()).or(addrs.first()).map(|addr| addr.ip())
+}
+
+/// `shExpMatch` pattern matching, with `*` and `?` wildcards
+///
+/// On a mismatch only the last `*` is widened by one character, which is
+/// enough for a glob and keeps the match linear in the pattern's stars.
+fn shell_match(text: &[char], pattern: &[char]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    // Pattern position just past the last `*`, and the text it resumes at
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                t += 1;
+                p += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    star = Some((star_p, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// PAC scripts outside the subset, run by QuickJS (feature `pac-js`)
+///
+/// Every evaluation gets a fresh runtime with bounded memory, stack and
+/// running time, so a script can neither keep state between calls nor hang
+/// or crash the client.
+#[cfg(feature = "pac-js")]
+mod quickjs {
+    use super::{builtin, Value};
+    use rquickjs::context::EvalOptions;
+    use rquickjs::function::Rest;
+    use rquickjs::{CatchResultExt, Coerced, Context, Ctx, Function, Runtime};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    /// Memory one evaluation may allocate
+    const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+    /// Stack one evaluation may use; QuickJS raises an exception past it
+    const STACK_LIMIT: usize = 256 * 1024;
+
+    /// Time one evaluation may run
+    const TIME_LIMIT: Duration = Duration::from_secs(2);
+
+    /// The PAC helpers, each forwarding to the subset's implementation
+    const HELPERS: &[&str] = &[
+        "isPlainHostName",
+        "dnsDomainIs",
+        "localHostOrDomainIs",
+        "isResolvable",
+        "dnsResolve",
+        "myIpAddress",
+        "dnsDomainLevels",
+        "isInNet",
+        "convert_addr",
+        "shExpMatch",
+        "weekdayRange",
+        "timeRange",
+        "alert",
+    ];
+
+    /// Check that `source` runs and defines `FindProxyForURL`
+    pub fn check(source: &str) -> Result<(), String> {
+        run(source, IpAddr::from([127, 0, 0, 1]), |ctx| {
+            let defined: rquickjs::Value = ctx.globals().get("FindProxyForURL")?;
+            Ok(defined.is_function())
+        })
+        .and_then(|defined| match defined {
+            true => Ok(()),
+            false => Err("PAC script does not define FindProxyForURL".to_string()),
+        })
+    }
+
+    pub fn find_proxy(source: &str, url: &str, host: &str, my_ip: IpAddr) -> Result<String, String> {
+        run(source, my_ip, |ctx| {
+            let find: Function = ctx.globals().get("FindProxyForURL")?;
+            let result: rquickjs::Value = find.call((url, host))?;
+            Ok(result.as_string().map(|result| result.to_string()))
+        })
+        .and_then(|result| match result {
+            Some(Ok(result)) => Ok(result),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Err("FindProxyForURL did not return a string".to_string()),
+        })
+    }
+
+    /// Run `source` with the PAC helpers defined, then `then` in the same context
+    fn run<T>(
+        source: &str,
+        my_ip: IpAddr,
+        then: impl for<'js> FnOnce(&Ctx<'js>) -> rquickjs::Result<T>,
+    ) -> Result<T, String> {
+        let runtime = Runtime::new().map_err(|e| e.to_string())?;
+        runtime.set_memory_limit(MEMORY_LIMIT);
+        runtime.set_max_stack_size(STACK_LIMIT);
+        let deadline = Instant::now() + TIME_LIMIT;
+        runtime.set_interrupt_handler(Some(Box::new(move || Instant::now() > deadline)));
+        let context = Context::full(&runtime).map_err(|e| e.to_string())?;
+
+        context.with(|ctx| {
+            let result = (|| {
+                let globals = ctx.globals();
+                for name in HELPERS {
+                    let helper = Function::new(ctx.clone(), move |ctx: Ctx<'_>, args: Rest<Coerced<String>>| {
+                        let args: Vec<Value> = args.0.into_iter().map(|arg| Value::Str(arg.0)).collect();
+                        builtin(name, &args, my_ip).map_err(|e| rquickjs::Exception::throw_message(&ctx, &e))
+                    })?;
+                    globals.set(*name, helper)?;
+                }
+                let mut options = EvalOptions::default();
+                options.strict = false;
+                ctx.eval_with_options::<(), _>(source, options)?;
+                then(&ctx)
+            })();
+            result.catch(&ctx).map_err(|e| format!("PAC script failed: {}", e))
+        })
+    }
+
+    impl<'js> rquickjs::IntoJs<'js> for Value {
+        fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<rquickjs::Value<'js>> {
+            Ok(match self {
+                Value::Undefined => rquickjs::Value::new_undefined(ctx.clone()),
+                Value::Null => rquickjs::Value::new_null(ctx.clone()),
+                Value::Bool(b) => rquickjs::Value::new_bool(ctx.clone(), b),
+                Value::Num(n) => rquickjs::Value::new_number(ctx.clone(), n),
+                Value::Str(s) => rquickjs::String::from_str(ctx.clone(), &s)?.into_value(),
+            })
+        }
+    }
+}
+
+/// Arguments without a trailing `"GMT"`, and whether it was there
+fn split_gmt(args: &[Value]) -> (&[Value], bool) {
+    match args.split_last() {
+        Some((Value::Str(last), rest)) if last.eq_ignore_ascii_case("GMT") => (rest, true),
+        _ => (args, false),
+    }
+}
+
+/// Whether `value` is within `first..=last`, wrapping past the end
+fn in_range(value: usize, first: usize, last: usize) -> bool {
+    if first <= last {
+        (first..=last).contains(&value)
+    } else {
+        value >= first || value <= last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        // Typical corporate PAC file
+        var proxy = "PROXY proxy.corp.example:8080";
+
+        function isInternal(host) {
+            return dnsDomainIs(host, ".corp.example") || shExpMatch(host, "*.intranet.*");
+        }
+
+        function FindProxyForURL(url, host) {
+            host = host.toLowerCase();
+            if (isPlainHostName(host) || isInternal(host))
+                return "DIRECT";
+            if (url.substring(0, 5) == "http:")
+                return dnsDomainLevels(host) > 2 ? "SOCKS socks.corp.example:1080" : proxy;
+            /* Private ranges stay direct */
+            if (isInNet(host, "10.0.0.0", "255.0.0.0") || isInNet(dnsResolve(host), "192.168.0.0", "255.255.0.0")) {
+                return "DIRECT";
+            } else if (myIpAddress() != '127.0.0.1') {
+                return proxy + "; SOCKS5 socks.corp.example:1080; DIRECT";
+            }
+            return proxy;
+        }
+    "#;
+
+    #[test]
+    fn test_find_proxy() {
+        let script = PacScript::parse(SCRIPT).unwrap();
+        let my_ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let find = |url: &str, host: &str| script.find_proxy(url, host, my_ip).unwrap();
+
+        assert_eq!(find("https://wiki/", "wiki"), "DIRECT");
+        assert_eq!(find("https://Build.CORP.example/", "Build.CORP.example"), "DIRECT");
+        assert_eq!(find("http://www.intranet.example/", "www.intranet.example"), "DIRECT");
+        assert_eq!(find("https://10.9.8.7:443/", "10.9.8.7"), "DIRECT");
+        assert_eq!(find("https://192.168.4.4:443/", "192.168.4.4"), "DIRECT");
+        let result = find("https://203.0.113.9:443/", "203.0.113.9");
+        assert_eq!(
+            parse_proxies(&result),
+            vec![
+                PacProxy::Http("proxy.corp.example:8080".to_string()),
+                PacProxy::Socks("socks.corp.example:1080".to_string()),
+                PacProxy::Direct,
+            ]
+        );
+        assert_eq!(find("http://a.b.c.example/", "a.b.c.example"), "SOCKS socks.corp.example:1080");
+        assert_eq!(find("http://b.example/", "b.example"), "PROXY proxy.corp.example:8080");
+    }
+
+    #[test]
+    fn test_rejected_scripts() {
+        assert!(PacScript::parse("function f() { return 'DIRECT'; }").is_err());
+        assert!(PacScript::parse_subset("function FindProxyForURL(u, h) { for (;;) {} }").is_err());
+        assert!(PacScript::parse("function FindProxyForURL(u, h) { return 'DIRECT' ").is_err());
+        let script = PacScript::parse("function FindProxyForURL(u, h) { return FindProxyForURL(u, h); }").unwrap();
+        assert!(script.find_proxy("https://x/", "x", IpAddr::from([127, 0, 0, 1])).is_err());
+        assert_eq!(parse_proxies(" "), vec![PacProxy::Direct]);
+        assert_eq!(parse_proxies("HTTPS secure:443; PROXY p:3128"), vec![PacProxy::Http("p:3128".to_string())]);
+    }
+
+    #[test]
+    fn test_hostile_scripts() {
+        let nested = format!("function FindProxyForURL(u, h) {{ return {}'DIRECT'{}; }}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(PacScript::parse_subset(&nested).unwrap_err().contains("nests deeper"));
+        assert!(PacScript::parse(&nested).is_err());
+        let negated = format!("function FindProxyForURL(u, h) {{ return {}'DIRECT'; }}", "!".repeat(100_000));
+        assert!(PacScript::parse_subset(&negated).unwrap_err().contains("nests deeper"));
+        let deep = format!("function FindProxyForURL(u, h) {{ return {}'DIRECT'{}; }}", "(".repeat(50), ")".repeat(50));
+        assert!(PacScript::parse(&deep).is_ok());
+
+        let text: Vec<char> = "a".repeat(64).chars().collect();
+        let pattern: Vec<char> = "*a*a*a*a*a*a*a*a*a*a*b".chars().collect();
+        assert!(!shell_match(&text, &pattern));
+        let matches = |text: &str, pattern: &str| {
+            shell_match(&text.chars().collect::<Vec<_>>(), &pattern.chars().collect::<Vec<_>>())
+        };
+        assert!(matches("www.intranet.example", "*.intranet.*"));
+        assert!(matches("abcbd", "a*b?"));
+        assert!(matches("", "**"));
+        assert!(!matches("abc", "a*d"));
+        assert!(!matches("ab", "a?b"));
+    }
+
+    #[cfg(feature = "pac-js")]
+    #[test]
+    fn test_quickjs_scripts() {
+        let script = PacScript::parse(
+            r#"
+            var internal = [/\.corp\.example$/, /^10\./];
+            function FindProxyForURL(url, host) {
+                for (var i = 0; i < internal.length; i++) {
+                    if (internal[i].test(host)) return "DIRECT";
+                }
+                return shExpMatch(host, "*.example") ? "PROXY proxy:8080" : "SOCKS socks:1080";
+            }
+        "#,
+        )
+        .unwrap();
+        let my_ip = IpAddr::from([127, 0, 0, 1]);
+        assert_eq!(script.find_proxy("https://a.corp.example/", "a.corp.example", my_ip).unwrap(), "DIRECT");
+        assert_eq!(script.find_proxy("https://b.example/", "b.example", my_ip).unwrap(), "PROXY proxy:8080");
+        assert_eq!(script.find_proxy("https://c.test/", "c.test", my_ip).unwrap(), "SOCKS socks:1080");
+
+        let endless = PacScript::parse("function FindProxyForURL(u, h) { while (true) {} }").unwrap();
+        assert!(endless.find_proxy("https://x/", "x", my_ip).is_err());
+        assert!(PacScript::parse("for (var i = 0; i < 3; i++) {}").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Tunnel round-trip time, jitter and loss
+//!
+//! The handshake RTT says little about a tunnel that has been up for an
+//! hour, and relayed traffic is too bursty to time. With `[path_quality]
+//! enabled = true` the client keeps a tunnel to each server that carries
+//! nothing but small ping frames, encrypted like any other record, which
+//! the server echoes back. Over the last `window` pings it reports:
+//!
+//! - `rtt`: mean round trip of the echoed pings
+//! - `jitter`: mean difference between consecutive round trips
+//! - `loss`: fraction of pings not echoed within `timeout`
+//!
+//! The tunnel asks for pings with a flag in its
+//! [routing header](crate::chain::RoutingHeader), so it looks like any other
+//! tunnel on the wire and the server never opens a target. A ping that goes
+//! unanswered ends the tunnel, since a stalled stream would hold up every
+//! later ping too, and the next ping opens a new one.
+//!
+//! Figures per server are listed in the client's
+//! [`crate::ProtocolStats::path_quality`].
+
+use crate::noise_transport::NoiseTransport;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Target of the probe tunnel's routing header; the server never opens it
+pub const PROBE_TARGET: &str = "path-probe.invalid:0";
+
+/// Ping frames are a big-endian sequence number
+const PING_SIZE: usize = 8;
+
+/// The server closes probe tunnels without a ping for this long
+const ECHO_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Path quality over the last pings to a server
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PathQuality {
+    /// Mean round trip, unknown until a ping is echoed
+    pub rtt: Option<Duration>,
+    /// Mean difference between consecutive round trips, unknown until two
+    /// pings are echoed
+    pub jitter: Option<Duration>,
+    /// Fraction of pings lost (0.0 - 1.0)
+    pub loss: f64,
+    /// Pings the figures are computed over
+    pub samples: usize,
+}
+
+/// Keeps the outcome of the last pings to a server
+#[derive(Debug)]
+pub struct PathMonitor {
+    window: usize,
+    /// Round trip of each ping, `None` when lost; newest last
+    results: VecDeque<Option<Duration>>,
+}
+
+impl PathMonitor {
+    pub fn new(window: usize) -> Self {
+        Self { window: window.max(1), results: VecDeque::new() }
+    }
+
+    /// Record a ping's round trip, or `None` when it was lost
+    pub fn record(&mut self, rtt: Option<Duration>) {
+        if self.results.len() == self.window {
+            self.results.pop_front();
+        }
+        self.results.push_back(rtt);
+    }
+
+    pub fn quality(&self) -> PathQuality {
+        let echoed: Vec<Duration> = self.results.iter().flatten().copied().collect();
+        let lost = self.results.len() - echoed.len();
+        let rtt = (!echoed.is_empty()).then(|| echoed.iter().sum::<Duration>() / echoed.len() as u32);
+        let jitter = (echoed.len() >= 2).then(|| {
+            let differences: Duration = echoed.windows(2).map(|pair| pair[0].abs_diff(pair[1])).sum();
+            differences / (echoed.len() - 1) as u32
+        });
+        PathQuality {
+            rtt,
+            jitter,
+            loss: match self.results.len() {
+                0 => 0.0,
+                n => lost as f64 / n as f64,
+            },
+            samples: self.results.len(),
+        }
+    }
+}
+
+static QUALITY: Mutex<BTreeMap<SocketAddr, PathQuality>> = Mutex::new(BTreeMap::new());
+
+/// Publish the latest figures for `server`
+pub fn update(server: SocketAddr, quality: PathQuality) {
+    QUALITY.lock().unwrap().insert(server, quality);
+}
+
+/// Latest figures for every server being measured
+pub fn snapshot() -> HashMap<SocketAddr, PathQuality> {
+    QUALITY.lock().unwrap().iter().map(|(server, quality)| (*server, *quality)).collect()
+}
+
+/// Send ping `seq` and wait up to `timeout` for its echo, returning the
+/// round trip or `None` when it was lost. After a loss the tunnel must not
+/// be used again: the echo may still be on its way.
+pub async fn ping<S>(noise: &mut NoiseTransport, stream: &mut S, seq: u64, timeout: Duration) -> anyhow::Result<Option<Duration>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let sent = Instant::now();
+    noise.write(stream, &seq.to_be_bytes()).await?;
+    let echo = match tokio::time::timeout(timeout, noise.read(stream)).await {
+        Ok(echo) => echo?,
+        Err(_) => return Ok(None),
+    };
+    if echo != seq.to_be_bytes() {
+        anyhow::bail!("Echo does not match ping {}", seq);
+    }
+    Ok(Some(sent.elapsed()))
+}
+
+/// Server side: echo ping frames until the client closes the tunnel or
+/// stops pinging
+pub async fn echo<S>(noise: &mut NoiseTransport, stream: &mut S) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let frame = match tokio::time::timeout(ECHO_IDLE_TIMEOUT, noise.read(stream)).await {
+            Ok(Ok(frame)) => frame,
+            // Closed or gone quiet
+            Ok(Err(_)) | Err(_) => return Ok(()),
+        };
+        if frame.len() != PING_SIZE {
+            anyhow::bail!("Malformed ping frame of {} bytes", frame.len());
+        }
+        noise.write(stream, &frame).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise_transport::{NoiseConfig, NoiseKeypair};
+
+    #[test]
+    fn test_path_monitor() {
+        let mut monitor = PathMonitor::new(4);
+        assert_eq!(monitor.quality(), PathQuality::default());
+
+        monitor.record(Some(Duration::from_millis(100)));
+        assert_eq!(monitor.quality().jitter, None);
+        monitor.record(Some(Duration::from_millis(140)));
+        monitor.record(None);
+        monitor.record(Some(Duration::from_millis(120)));
+        let quality = monitor.quality();
+        assert_eq!(quality.rtt, Some(Duration::from_millis(120)));
+        assert_eq!(quality.jitter, Some(Duration::from_millis(30)));
+        assert_eq!(quality.loss, 0.25);
+        assert_eq!(quality.samples, 4);
+
+        // Only the window counts
+        for _ in 0..4 {
+            monitor.record(None);
+        }
+        let quality = monitor.quality();
+        assert_eq!((quality.rtt, quality.loss), (None, 1.0));
+    }
+
+    #[tokio::test]
+    async fn test_ping_echo() {
+        let keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(keypair.public_key_base64()),
+            ..Default::default()
+        };
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(8192);
+        let server = tokio::spawn(async move {
+            let mut noise = NoiseTransport::server_handshake(&mut server_stream, &server_config, None).await.unwrap();
+            echo(&mut noise, &mut server_stream).await
+        });
+        let mut noise = NoiseTransport::client_handshake(&mut client_stream, &client_config, None).await.unwrap();
+
+        let mut monitor = PathMonitor::new(8);
+        for seq in 1..=3 {
+            let rtt = ping(&mut noise, &mut client_stream, seq, Duration::from_secs(5)).await.unwrap();
+            assert!(rtt.is_some());
+            monitor.record(rtt);
+        }
+        let server_addr: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        update(server_addr, monitor.quality());
+        assert_eq!(snapshot()[&server_addr].samples, 3);
+        assert_eq!(snapshot()[&server_addr].loss, 0.0);
+
+        // The server only echoes ping frames
+        noise.write(&mut client_stream, b"not a ping").await.unwrap();
+        assert!(server.await.unwrap().is_err());
+    }
+}
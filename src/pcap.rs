@@ -0,0 +1,421 @@
+//! Minimal libpcap file support
+//!
+//! Reads classic (non-pcapng) capture files and decodes the IPv4/IPv6 TCP and
+//! UDP packets inside them, and writes raw-IP captures of synthesized packets.
+//! Only what the traffic analysis and debug capture tools need is implemented;
+//! no external capture library is required.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+/// Magic number of a microsecond-resolution pcap file
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// Magic number of a nanosecond-resolution pcap file
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+/// Ethernet link type
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// Raw IP link type (no link-layer header)
+pub const LINKTYPE_RAW: u32 = 101;
+
+/// A raw packet record from a pcap file
+#[derive(Debug, Clone)]
+pub struct PcapRecord {
+    /// Capture timestamp (since the Unix epoch)
+    pub timestamp: Duration,
+    /// Captured bytes
+    pub data: Vec<u8>,
+    /// Original length on the wire
+    pub orig_len: u32,
+}
+
+/// Transport protocol of a decoded packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpProtocol {
+    Tcp,
+    Udp,
+}
+
+/// A decoded TCP/UDP packet
+#[derive(Debug, Clone)]
+pub struct DecodedPacket {
+    /// Capture timestamp
+    pub timestamp: Duration,
+    /// Transport protocol
+    pub protocol: IpProtocol,
+    /// Source endpoint
+    pub src: SocketAddr,
+    /// Destination endpoint
+    pub dst: SocketAddr,
+    /// TCP flags (0 for UDP)
+    pub tcp_flags: u8,
+    /// Transport payload
+    pub payload: Vec<u8>,
+}
+
+/// Parsed pcap file
+#[derive(Debug, Clone)]
+pub struct PcapFile {
+    /// Link-layer type
+    pub linktype: u32,
+    /// Packet records
+    pub records: Vec<PcapRecord>,
+}
+
+impl PcapFile {
+    /// Read a pcap file from disk
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Self::parse(&data)
+    }
+
+    /// Parse pcap bytes
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        if data.len() < 24 {
+            return Err(invalid("pcap file too short"));
+        }
+
+        let magic_le = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let magic_be = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let (big_endian, nanos) = match (magic_le, magic_be) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => return Err(invalid("not a pcap file (pcapng is not supported)")),
+        };
+
+        let read_u32 = |b: &[u8]| {
+            let bytes = [b[0], b[1], b[2], b[3]];
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+
+        let linktype = read_u32(&data[20..24]);
+        let mut records = Vec::new();
+        let mut offset = 24;
+
+        while offset + 16 <= data.len() {
+            let ts_sec = read_u32(&data[offset..]);
+            let ts_frac = read_u32(&data[offset + 4..]);
+            let incl_len = read_u32(&data[offset + 8..]) as usize;
+            let orig_len = read_u32(&data[offset + 12..]);
+            offset += 16;
+
+            if offset + incl_len > data.len() {
+                return Err(invalid("truncated pcap record"));
+            }
+
+            let frac = if nanos {
+                Duration::from_nanos(ts_frac as u64)
+            } else {
+                Duration::from_micros(ts_frac as u64)
+            };
+
+            records.push(PcapRecord {
+                timestamp: Duration::from_secs(ts_sec as u64) + frac,
+                data: data[offset..offset + incl_len].to_vec(),
+                orig_len,
+            });
+            offset += incl_len;
+        }
+
+        Ok(Self { linktype, records })
+    }
+
+    /// Decode every TCP/UDP packet in the file, skipping anything else
+    pub fn packets(&self) -> Vec<DecodedPacket> {
+        self.records
+            .iter()
+            .filter_map(|record| decode_packet(self.linktype, record))
+            .collect()
+    }
+}
+
+/// Decode a record into a TCP/UDP packet
+pub fn decode_packet(linktype: u32, record: &PcapRecord) -> Option<DecodedPacket> {
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            if record.data.len() < 14 {
+                return None;
+            }
+            let mut ethertype = u16::from_be_bytes([record.data[12], record.data[13]]);
+            let mut offset = 14;
+            // Skip a single 802.1Q VLAN tag
+            if ethertype == 0x8100 && record.data.len() >= 18 {
+                ethertype = u16::from_be_bytes([record.data[16], record.data[17]]);
+                offset = 18;
+            }
+            if ethertype != 0x0800 && ethertype != 0x86dd {
+                return None;
+            }
+            &record.data[offset..]
+        }
+        LINKTYPE_RAW => &record.data[..],
+        _ => return None,
+    };
+
+    let (src_ip, dst_ip, proto, transport) = match ip.first()? >> 4 {
+        4 => {
+            if ip.len() < 20 {
+                return None;
+            }
+            let ihl = ((ip[0] & 0x0f) as usize) * 4;
+            let total = (u16::from_be_bytes([ip[2], ip[3]]) as usize).min(ip.len());
+            if ihl < 20 || total < ihl {
+                return None;
+            }
+            let src = IpAddr::V4(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]));
+            let dst = IpAddr::V4(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]));
+            (src, dst, ip[9], &ip[ihl..total])
+        }
+        6 => {
+            if ip.len() < 40 {
+                return None;
+            }
+            let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+            let end = (40 + payload_len).min(ip.len());
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&ip[8..24]);
+            dst.copy_from_slice(&ip[24..40]);
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                ip[6],
+                &ip[40..end],
+            )
+        }
+        _ => return None,
+    };
+
+    match proto {
+        6 => {
+            if transport.len() < 20 {
+                return None;
+            }
+            let data_offset = ((transport[12] >> 4) as usize) * 4;
+            if data_offset < 20 || data_offset > transport.len() {
+                return None;
+            }
+            Some(DecodedPacket {
+                timestamp: record.timestamp,
+                protocol: IpProtocol::Tcp,
+                src: SocketAddr::new(src_ip, u16::from_be_bytes([transport[0], transport[1]])),
+                dst: SocketAddr::new(dst_ip, u16::from_be_bytes([transport[2], transport[3]])),
+                tcp_flags: transport[13],
+                payload: transport[data_offset..].to_vec(),
+            })
+        }
+        17 => {
+            if transport.len() < 8 {
+                return None;
+            }
+            Some(DecodedPacket {
+                timestamp: record.timestamp,
+                protocol: IpProtocol::Udp,
+                src: SocketAddr::new(src_ip, u16::from_be_bytes([transport[0], transport[1]])),
+                dst: SocketAddr::new(dst_ip, u16::from_be_bytes([transport[2], transport[3]])),
+                tcp_flags: 0,
+                payload: transport[8..].to_vec(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Writer for raw-IP (`LINKTYPE_RAW`) pcap files
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the file header and return the writer
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&[0u8; 8]);
+        header.extend_from_slice(&65535u32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(Self { out })
+    }
+
+    /// Append one packet (an IP datagram) captured at `timestamp`
+    pub fn write_packet(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(packet);
+        self.out.write_all(&record)
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Build an IP header for `payload_len` bytes of transport data
+fn ip_header(src: IpAddr, dst: IpAddr, proto: u8, payload_len: usize) -> Option<Vec<u8>> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total = 20 + payload_len;
+            if total > u16::MAX as usize {
+                return None;
+            }
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(&(total as u16).to_be_bytes());
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, proto, 0, 0]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let checksum = internet_checksum(&ip);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            Some(ip)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            if payload_len > u16::MAX as usize {
+                return None;
+            }
+            let mut ip = vec![0x60, 0, 0, 0];
+            ip.extend_from_slice(&(payload_len as u16).to_be_bytes());
+            ip.extend_from_slice(&[proto, 64]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            Some(ip)
+        }
+        _ => None,
+    }
+}
+
+/// RFC 1071 one's complement checksum
+pub(crate) fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build an IP/TCP packet. Transport checksums are left zero.
+pub fn build_tcp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let mut packet = ip_header(src.ip(), dst.ip(), 6, 20 + payload.len())?;
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+    Some(packet)
+}
+
+/// Build an IP/UDP packet. The UDP checksum is left zero.
+pub fn build_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let mut packet = ip_header(src.ip(), dst.ip(), 17, 8 + payload.len())?;
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a raw-IP pcap containing one IPv4/TCP packet
+    fn sample_pcap(payload: &[u8]) -> Vec<u8> {
+        let mut tcp = vec![0u8; 20];
+        tcp[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&443u16.to_be_bytes());
+        tcp[12] = 5 << 4;
+        tcp[13] = 0x18; // PSH|ACK
+        tcp.extend_from_slice(payload);
+
+        let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2];
+        let total = (ip.len() + tcp.len()) as u16;
+        ip[2..4].copy_from_slice(&total.to_be_bytes());
+        ip.extend_from_slice(&tcp);
+
+        let mut file = Vec::new();
+        file.extend_from_slice(&MAGIC_MICROS.to_le_bytes());
+        file.extend_from_slice(&2u16.to_le_bytes());
+        file.extend_from_slice(&4u16.to_le_bytes());
+        file.extend_from_slice(&[0u8; 8]);
+        file.extend_from_slice(&65535u32.to_le_bytes());
+        file.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        file.extend_from_slice(&500u32.to_le_bytes());
+        file.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+        file.extend_from_slice(&ip);
+        file
+    }
+
+    #[test]
+    fn test_parse_and_decode() {
+        let pcap = PcapFile::parse(&sample_pcap(b"hello")).unwrap();
+        assert_eq!(pcap.linktype, LINKTYPE_RAW);
+        assert_eq!(pcap.records.len(), 1);
+
+        let packets = pcap.packets();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].protocol, IpProtocol::Tcp);
+        assert_eq!(packets[0].dst.port(), 443);
+        assert_eq!(packets[0].payload, b"hello");
+        assert_eq!(packets[0].timestamp, Duration::from_secs(1) + Duration::from_micros(500));
+    }
+
+    #[test]
+    fn test_writer_round_trip() {
+        let src: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:443".parse().unwrap();
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let tcp = build_tcp_packet(src, dst, 1, 0, 0x18, b"wrapped").unwrap();
+        writer.write_packet(Duration::from_millis(1500), &tcp).unwrap();
+        let udp = build_udp_packet(src, dst, b"meta").unwrap();
+        writer.write_packet(Duration::from_secs(2), &udp).unwrap();
+
+        let pcap = PcapFile::parse(&writer.out).unwrap();
+        let packets = pcap.packets();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].protocol, IpProtocol::Tcp);
+        assert_eq!(packets[0].src, src);
+        assert_eq!(packets[0].payload, b"wrapped");
+        assert_eq!(packets[0].timestamp, Duration::from_millis(1500));
+        assert_eq!(packets[1].protocol, IpProtocol::Udp);
+        assert_eq!(packets[1].payload, b"meta");
+        assert_eq!(internet_checksum(&tcp[..20]), 0);
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(PcapFile::parse(&[0u8; 10]).is_err());
+        assert!(PcapFile::parse(&[0xffu8; 32]).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Scheduled port hopping for UDP transports
+//!
+//! Client and server share a secret and derive the server port of each time
+//! slot from it, `HMAC-SHA256(key, slot)` mapped into a port range. The
+//! client sends every datagram to the port of the current slot; the server
+//! listens on the ports of the previous, current and next slot, so clocks
+//! may be a slot apart, and answers from the port a query arrived on.
+//! Blocking a port therefore only lasts until the next slot.
+//!
+//! ```toml
+//! [relay.port_hopping]
+//! enabled = true
+//! key = "a shared secret"
+//! port_range = [20000, 60000]
+//! slot = "60s"
+//! ```
+
+use crate::config::PortHoppingConfig;
+use ring::hmac;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Datagrams received on any hop port waiting for [`PortHopListener::recv_from`]
+const INCOMING_QUEUE: usize = 1024;
+
+/// Largest datagram a hop port accepts
+const MAX_DATAGRAM_SIZE: usize = 4096;
+
+/// A datagram, its source and the hop port it arrived on
+type Datagram = (Vec<u8>, SocketAddr, u16);
+
+/// Open hop ports with their receive tasks
+type HopPorts = HashMap<u16, (Arc<UdpSocket>, JoinHandle<()>)>;
+
+/// Port of every time slot
+#[derive(Clone)]
+pub struct PortHopSchedule {
+    key: hmac::Key,
+    first_port: u16,
+    span: u32,
+    slot: Duration,
+}
+
+impl PortHopSchedule {
+    pub fn new(config: &PortHoppingConfig) -> Result<Self, String> {
+        let key = config.key.as_deref().filter(|k| !k.is_empty()).ok_or("relay.port_hopping.key is required")?;
+        let (first, last) = config.port_range;
+        if first == 0 || first > last {
+            return Err(format!("Invalid relay.port_hopping.port_range {}-{}", first, last));
+        }
+        if config.slot.as_secs() == 0 {
+            return Err("relay.port_hopping.slot must be at least 1s".to_string());
+        }
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()),
+            first_port: first,
+            span: u32::from(last - first) + 1,
+            slot: config.slot,
+        })
+    }
+
+    /// Slot `time` falls in
+    pub fn slot_at(&self, time: SystemTime) -> u64 {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.as_secs() / self.slot.as_secs()
+    }
+
+    /// Server port of `slot`
+    pub fn port(&self, slot: u64) -> u16 {
+        let tag = hmac::sign(&self.key, &slot.to_be_bytes());
+        let value = u32::from_be_bytes([tag.as_ref()[0], tag.as_ref()[1], tag.as_ref()[2], tag.as_ref()[3]]);
+        self.first_port + (value % self.span) as u16
+    }
+
+    /// Port the client sends to now
+    pub fn current_port(&self) -> u16 {
+        self.port(self.slot_at(SystemTime::now()))
+    }
+
+    /// Ports the server listens on at `time`: those of the previous,
+    /// current and next slot
+    pub fn listening_ports(&self, time: SystemTime) -> Vec<u16> {
+        let slot = self.slot_at(time);
+        let mut ports = Vec::with_capacity(3);
+        for slot in [slot.saturating_sub(1), slot, slot + 1] {
+            let port = self.port(slot);
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+        ports
+    }
+
+    /// Time from `time` to the start of the next slot
+    pub fn until_next_slot(&self, time: SystemTime) -> Duration {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let next = (self.slot_at(time) + 1) * self.slot.as_secs();
+        Duration::from_secs(next).saturating_sub(since_epoch)
+    }
+}
+
+/// UDP sockets on the hop ports around the current slot
+///
+/// Sockets are opened and closed as slots pass; datagrams from all of them
+/// arrive through one [`recv_from`](Self::recv_from), tagged with the port
+/// they arrived on so replies can leave from it.
+pub struct PortHopListener {
+    ip: IpAddr,
+    schedule: PortHopSchedule,
+    sockets: Mutex<HopPorts>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<Datagram>>,
+    tx: mpsc::Sender<Datagram>,
+}
+
+impl PortHopListener {
+    /// Listen on `ip` at the ports of the current slots, following the
+    /// schedule until dropped
+    pub async fn bind(ip: IpAddr, schedule: PortHopSchedule) -> io::Result<Arc<Self>> {
+        let (tx, rx) = mpsc::channel(INCOMING_QUEUE);
+        let listener = Arc::new(Self {
+            ip,
+            schedule,
+            sockets: Mutex::new(HashMap::new()),
+            incoming: tokio::sync::Mutex::new(rx),
+            tx,
+        });
+        listener.rebind(SystemTime::now()).await?;
+
+        let weak = Arc::downgrade(&listener);
+        tokio::spawn(async move {
+            loop {
+                let wait = match weak.upgrade() {
+                    Some(listener) => listener.schedule.until_next_slot(SystemTime::now()),
+                    None => return,
+                };
+                tokio::time::sleep(wait).await;
+                let Some(listener) = weak.upgrade() else {
+                    return;
+                };
+                if let Err(e) = listener.rebind(SystemTime::now()).await {
+                    log::warn!("Port hopping: {}", e);
+                }
+            }
+        });
+        Ok(listener)
+    }
+
+    /// Ports currently listened on
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.sockets.lock().unwrap().keys().copied().collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Next datagram from any hop port, with its source and the port it
+    /// arrived on
+    pub async fn recv_from(&self) -> io::Result<Datagram> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "Port hop listener closed"))
+    }
+
+    /// Send from `port`, or from the current slot's port once `port` has
+    /// been closed
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr, port: u16) -> io::Result<usize> {
+        let socket = {
+            let sockets = self.sockets.lock().unwrap();
+            sockets
+                .get(&port)
+                .or_else(|| sockets.get(&self.schedule.current_port()))
+                .or_else(|| sockets.values().next())
+                .map(|(socket, _)| Arc::clone(socket))
+        };
+        match socket {
+            Some(socket) => socket.send_to(buf, target).await,
+            None => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "No hop port is open")),
+        }
+    }
+
+    /// Open the ports of the slots around `time` and close the others
+    async fn rebind(&self, time: SystemTime) -> io::Result<()> {
+        let ports = self.schedule.listening_ports(time);
+        let missing: Vec<u16> = {
+            let sockets = self.sockets.lock().unwrap();
+            ports.iter().copied().filter(|port| !sockets.contains_key(port)).collect()
+        };
+        let mut opened = Vec::new();
+        for port in missing {
+            match UdpSocket::bind(SocketAddr::new(self.ip, port)).await {
+                Ok(socket) => opened.push((port, Arc::new(socket))),
+                Err(e) => log::warn!("Port hopping: cannot listen on {}:{}: {}", self.ip, port, e),
+            }
+        }
+
+        let mut sockets = self.sockets.lock().unwrap();
+        sockets.retain(|port, (_, task)| {
+            let keep = ports.contains(port);
+            if !keep {
+                log::debug!("Port hopping: closing port {}", port);
+                task.abort();
+            }
+            keep
+        });
+        for (port, socket) in opened {
+            log::info!("Port hopping: listening on {}:{}", self.ip, port);
+            let task = tokio::spawn(Self::receive(Arc::clone(&socket), port, self.tx.clone()));
+            sockets.insert(port, (socket, task));
+        }
+        if sockets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "No hop port could be opened"));
+        }
+        Ok(())
+    }
+
+    async fn receive(socket: Arc<UdpSocket>, port: u16, tx: mpsc::Sender<Datagram>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((n, src)) => {
+                    if tx.send((buf[..n].to_vec(), src, port)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => log::debug!("Port hopping: receive error on port {}: {}", port, e),
+            }
+        }
+    }
+}
+
+impl Drop for PortHopListener {
+    fn drop(&mut self) {
+        for (_, task) in self.sockets.lock().unwrap().values() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(key: &str) -> PortHoppingConfig {
+        PortHoppingConfig {
+            enabled: true,
+            key: Some(key.to_string()),
+            port_range: (40000, 40999),
+            slot: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_port_schedule() {
+        let schedule = PortHopSchedule::new(&config("secret")).unwrap();
+        let ports: Vec<u16> = (0..50).map(|slot| schedule.port(slot)).collect();
+        assert!(ports.iter().all(|port| (40000..=40999).contains(port)));
+        // Both ends derive the same ports, and they move between slots
+        let peer = PortHopSchedule::new(&config("secret")).unwrap();
+        assert_eq!(ports, (0..50).map(|slot| peer.port(slot)).collect::<Vec<_>>());
+        assert!(ports.windows(2).any(|pair| pair[0] != pair[1]));
+        let other = PortHopSchedule::new(&config("another secret")).unwrap();
+        assert_ne!(ports, (0..50).map(|slot| other.port(slot)).collect::<Vec<_>>());
+
+        let time = UNIX_EPOCH + Duration::from_secs(30 * 100 + 12);
+        assert_eq!(schedule.slot_at(time), 100);
+        assert_eq!(schedule.until_next_slot(time), Duration::from_secs(18));
+        let listening = schedule.listening_ports(time);
+        assert!(listening.contains(&schedule.port(99)));
+        assert!(listening.contains(&schedule.port(101)));
+
+        assert!(PortHopSchedule::new(&PortHoppingConfig { key: None, ..config("") }).is_err());
+        assert!(PortHopSchedule::new(&PortHoppingConfig { port_range: (2000, 1000), ..config("k") }).is_err());
+    }
+}
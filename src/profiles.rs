@@ -2,23 +2,209 @@
 //!
 //! This module provides preset configurations optimized for specific
 //! censorship environments and network conditions.
+//!
+//! Besides the built-in presets, users can define their own in
+//! `~/.config/nooshdaroo/profiles/<name>.toml` (or `$NOOSHDAROO_PROFILE_DIR`):
+//!
+//! ```toml
+//! description = "University network"
+//! base = "corporate"             # built-in preset to start from (optional)
+//! protocols = ["https", "dns"]   # rotated every `rotation_interval`
+//! rotation_interval = "10m"
+//! ports = [443, 8443]            # preferred server ports, best first
+//!
+//! [traffic_shaping]              # optional, replaces the base section
+//! # ...
+//!
+//! [fec]                          # forward error correction on UDP transports
+//! enabled = true
+//! redundancy = 0.5
+//! ```
+//!
+//! `shapeshift` and `detection` sections may be given the same way. Built-in
+//! names take precedence over files with the same name.
 
 use crate::{NooshdarooConfig, ProtocolId, ShapeShiftConfig, StrategyType, TrafficShapingConfig};
-use crate::config::DistributionType;
+use crate::config::{DetectionConfig, DistributionType, FecConfig, JitterConfig, KeepaliveConfig, SegmentationConfig, TcpFingerprintConfig, TtlConfig};
 use crate::strategy::{FixedStrategy, TimeBasedStrategy};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+
+/// Names of the built-in presets
+pub const BUILTIN_PROFILES: &[&str] = &["corporate", "airport", "hotel", "china", "iran", "russia"];
 
-/// Load a preset profile by name
+/// Environment variable overriding the user profile directory
+pub const PROFILE_DIR_ENV: &str = "NOOSHDAROO_PROFILE_DIR";
+
+/// Default rotation interval for user profiles listing several protocols
+const DEFAULT_ROTATION_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Load a preset profile by name, built-in or from the user profile directory
 pub fn load_profile(name: &str) -> Result<NooshdarooConfig> {
+    if let Some(config) = builtin_profile(name) {
+        return Ok(config);
+    }
+    if let Some(dir) = profile_dir() {
+        let path = dir.join(format!("{}.toml", name));
+        if path.exists() {
+            return UserProfile::from_file(&path)?.to_config();
+        }
+    }
+    let mut available: Vec<String> = BUILTIN_PROFILES.iter().map(|n| n.to_string()).collect();
+    available.extend(list_user_profiles().into_iter().map(|(name, _)| name));
+    bail!("Unknown profile: {}. Available profiles: {}", name, available.join(", "))
+}
+
+/// Preferred server ports of a user profile (empty for built-in presets)
+pub fn preferred_ports(name: &str) -> Vec<u16> {
+    if builtin_profile(name).is_some() {
+        return Vec::new();
+    }
+    profile_dir()
+        .map(|dir| dir.join(format!("{}.toml", name)))
+        .and_then(|path| UserProfile::from_file(&path).ok())
+        .map(|profile| profile.ports)
+        .unwrap_or_default()
+}
+
+fn builtin_profile(name: &str) -> Option<NooshdarooConfig> {
     match name.to_lowercase().as_str() {
-        "corporate" => Ok(corporate_profile()),
-        "airport" => Ok(airport_profile()),
-        "hotel" => Ok(hotel_profile()),
-        "china" => Ok(china_profile()),
-        "iran" => Ok(iran_profile()),
-        "russia" => Ok(russia_profile()),
-        _ => bail!("Unknown profile: {}. Available profiles: corporate, airport, hotel, china, iran, russia", name),
+        "corporate" => Some(corporate_profile()),
+        "airport" => Some(airport_profile()),
+        "hotel" => Some(hotel_profile()),
+        "china" => Some(china_profile()),
+        "iran" => Some(iran_profile()),
+        "russia" => Some(russia_profile()),
+        _ => None,
+    }
+}
+
+/// Directory holding user-defined profiles
+pub fn profile_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(PROFILE_DIR_ENV) {
+        return Some(PathBuf::from(dir));
+    }
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(config_home.join("nooshdaroo").join("profiles"))
+}
+
+/// User profiles found in [`profile_dir`], sorted by name
+pub fn list_user_profiles() -> Vec<(String, UserProfile)> {
+    profile_dir().map(|dir| list_profiles_in(&dir)).unwrap_or_default()
+}
+
+/// Profiles defined by `*.toml` files in `dir`; unreadable files are skipped with a warning
+pub fn list_profiles_in(dir: &Path) -> Vec<(String, UserProfile)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut profiles: Vec<(String, UserProfile)> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_string_lossy().into_owned();
+            match UserProfile::from_file(&path) {
+                Ok(profile) => Some((name, profile)),
+                Err(e) => {
+                    log::warn!("Skipping profile {}: {:#}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+    profiles.sort_by(|a, b| a.0.cmp(&b.0));
+    profiles
+}
+
+/// A preset defined in a TOML file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserProfile {
+    /// Short description shown by `nooshdaroo profiles`
+    #[serde(default)]
+    pub description: Option<String>,
+
+    /// Built-in preset to start from
+    #[serde(default)]
+    pub base: Option<String>,
+
+    /// Protocols to use; one is fixed, several rotate
+    #[serde(default)]
+    pub protocols: Vec<String>,
+
+    /// Rotation interval when several protocols are listed
+    #[serde(default, with = "humantime_serde")]
+    pub rotation_interval: Option<Duration>,
+
+    /// Preferred server ports, best first
+    #[serde(default)]
+    pub ports: Vec<u16>,
+
+    /// Full strategy, overriding `protocols`
+    #[serde(default)]
+    pub shapeshift: Option<ShapeShiftConfig>,
+
+    /// Traffic shaping settings
+    #[serde(default)]
+    pub traffic_shaping: Option<TrafficShapingConfig>,
+
+    /// Detection resistance settings
+    #[serde(default)]
+    pub detection: Option<DetectionConfig>,
+
+    /// Forward error correction on UDP transports
+    #[serde(default)]
+    pub fec: Option<FecConfig>,
+}
+
+impl UserProfile {
+    /// Parse a profile file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read profile {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid profile {}", path.display()))
+    }
+
+    /// Build the configuration this profile describes
+    pub fn to_config(&self) -> Result<NooshdarooConfig> {
+        let mut config = match self.base {
+            Some(ref base) => match builtin_profile(base) {
+                Some(config) => config,
+                None => bail!("Unknown base profile: {}. Built-in profiles: {}", base, BUILTIN_PROFILES.join(", ")),
+            },
+            None => NooshdarooConfig::default(),
+        };
+
+        if let Some(ref shapeshift) = self.shapeshift {
+            config.shapeshift = shapeshift.clone();
+        } else if let [protocol] = self.protocols.as_slice() {
+            config.shapeshift.strategy = StrategyType::Fixed(FixedStrategy {
+                protocol: ProtocolId::from(protocol.as_str()),
+            });
+        } else if !self.protocols.is_empty() {
+            config.shapeshift.strategy = StrategyType::TimeBased(TimeBasedStrategy::new(
+                self.rotation_interval.unwrap_or(DEFAULT_ROTATION_INTERVAL),
+                self.protocols.iter().map(|p| ProtocolId::from(p.as_str())).collect(),
+            ));
+        }
+
+        if let Some(ref shaping) = self.traffic_shaping {
+            config.traffic_shaping = shaping.clone();
+        }
+        if let Some(ref detection) = self.detection {
+            config.detection = detection.clone();
+        }
+        if let Some(ref fec) = self.fec {
+            config.relay.fec = fec.clone();
+        }
+
+        Ok(config)
     }
 }
 
@@ -42,6 +228,7 @@ fn corporate_profile() -> NooshdarooConfig {
             Duration::from_secs(300), // Rotate every 5 minutes
             protocols,
         )),
+        ..Default::default()
     };
 
     // Enable traffic shaping to mimic normal browsing
@@ -55,6 +242,11 @@ fn corporate_profile() -> NooshdarooConfig {
         enable_bursts: false,
         burst_size: 5,
         burst_probability: 0.0,
+        jitter: JitterConfig::default(),
+        segmentation: SegmentationConfig::default(),
+        tcp_fingerprint: TcpFingerprintConfig::default(),
+        ttl: TtlConfig::default(),
+        keepalive: KeepaliveConfig::default(),
     };
 
     config
@@ -73,6 +265,7 @@ fn airport_profile() -> NooshdarooConfig {
         strategy: StrategyType::Fixed(FixedStrategy {
             protocol: ProtocolId::from("dns"),
         }),
+        ..Default::default()
     };
 
     // Minimal traffic shaping - be invisible
@@ -86,6 +279,11 @@ fn airport_profile() -> NooshdarooConfig {
         enable_bursts: false,
         burst_size: 3,
         burst_probability: 0.0,
+        jitter: JitterConfig::default(),
+        segmentation: SegmentationConfig::default(),
+        tcp_fingerprint: TcpFingerprintConfig::default(),
+        ttl: TtlConfig::default(),
+        keepalive: KeepaliveConfig::default(),
     };
 
     config
@@ -120,6 +318,7 @@ fn china_profile() -> NooshdarooConfig {
             Duration::from_secs(180), // Rotate every 3 minutes
             protocols,
         )),
+        ..Default::default()
     };
 
     // Aggressive traffic shaping to mimic real applications
@@ -133,6 +332,14 @@ fn china_profile() -> NooshdarooConfig {
         enable_bursts: true,
         burst_size: 10,
         burst_probability: 0.2,
+        jitter: JitterConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        segmentation: SegmentationConfig::default(),
+        tcp_fingerprint: TcpFingerprintConfig::default(),
+        ttl: TtlConfig::default(),
+        keepalive: KeepaliveConfig::default(),
     };
 
     config
@@ -159,6 +366,7 @@ fn iran_profile() -> NooshdarooConfig {
             Duration::from_secs(240), // Rotate every 4 minutes
             protocols,
         )),
+        ..Default::default()
     };
 
     // Moderate traffic shaping - balance between speed and stealth
@@ -172,6 +380,14 @@ fn iran_profile() -> NooshdarooConfig {
         enable_bursts: true,
         burst_size: 7,
         burst_probability: 0.15,
+        jitter: JitterConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        segmentation: SegmentationConfig::default(),
+        tcp_fingerprint: TcpFingerprintConfig::default(),
+        ttl: TtlConfig::default(),
+        keepalive: KeepaliveConfig::default(),
     };
 
     config
@@ -198,6 +414,7 @@ fn russia_profile() -> NooshdarooConfig {
             Duration::from_secs(200), // Rotate every 3.3 minutes
             protocols,
         )),
+        ..Default::default()
     };
 
     // Traffic shaping to mimic cloud service API calls
@@ -211,6 +428,14 @@ fn russia_profile() -> NooshdarooConfig {
         enable_bursts: true,
         burst_size: 6,
         burst_probability: 0.1,
+        jitter: JitterConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        segmentation: SegmentationConfig::default(),
+        tcp_fingerprint: TcpFingerprintConfig::default(),
+        ttl: TtlConfig::default(),
+        keepalive: KeepaliveConfig::default(),
     };
 
     config
@@ -236,6 +461,54 @@ mod tests {
         assert!(load_profile("invalid").is_err());
     }
 
+    #[test]
+    fn test_user_profiles() {
+        let dir = std::env::temp_dir().join(format!("nooshdaroo-profiles-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("campus.toml"), r#"
+description = "University network"
+base = "airport"
+protocols = ["https", "dns"]
+rotation_interval = "10m"
+ports = [443, 8443]
+
+[fec]
+enabled = true
+redundancy = 0.5
+"#).unwrap();
+        std::fs::write(dir.join("fixed.toml"), "protocols = [\"ssh\"]\n").unwrap();
+        std::fs::write(dir.join("broken.toml"), "protocols = 42\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+        let profiles = list_profiles_in(&dir);
+        let names: Vec<&str> = profiles.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["campus", "fixed"]);
+
+        let campus = &profiles[0].1;
+        assert_eq!(campus.description.as_deref(), Some("University network"));
+        assert_eq!(campus.ports, vec![443, 8443]);
+        let config = campus.to_config().unwrap();
+        match config.shapeshift.strategy {
+            StrategyType::TimeBased(strategy) => {
+                assert_eq!(strategy.interval, Duration::from_secs(600));
+                assert_eq!(strategy.sequence.len(), 2);
+            }
+            other => panic!("expected time-based strategy, got {:?}", other),
+        }
+        // Shaping inherited from the airport base
+        assert_eq!(config.traffic_shaping.mean_packet_size, airport_profile().traffic_shaping.mean_packet_size);
+        assert!(config.relay.fec.enabled);
+        assert_eq!(config.relay.fec.parity_shards(), 4);
+
+        let fixed = profiles[1].1.to_config().unwrap();
+        assert!(matches!(fixed.shapeshift.strategy, StrategyType::Fixed(_)));
+
+        let unknown_base = UserProfile { base: Some("mars".to_string()), ..Default::default() };
+        assert!(unknown_base.to_config().is_err());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn test_corporate_profile() {
         let config = corporate_profile();
@@ -50,6 +50,38 @@ pub enum Transport {
     Both,
 }
 
+/// Risk of drawing attention when using a protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskLevel {
+    Low,
+    Medium,
+    High,
+}
+
+impl std::str::FromStr for RiskLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            _ => Err(format!("Invalid risk level: {} (expected low, medium or high)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for RiskLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Low => write!(f, "low"),
+            Self::Medium => write!(f, "medium"),
+            Self::High => write!(f, "high"),
+        }
+    }
+}
+
 /// Detection resistance scoring
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DetectionScore {
@@ -130,6 +162,22 @@ pub struct ProtocolMetadata {
 
     /// Notes about implementation
     pub notes: Option<String>,
+
+    /// Free-form tags (e.g., "tls", "browser")
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Ports the protocol is commonly seen on besides the default port
+    #[serde(default)]
+    pub ports: Vec<u16>,
+
+    /// Risk level override (derived from the detection score when unset)
+    #[serde(default)]
+    pub risk: Option<RiskLevel>,
+
+    /// Regions where the protocol is known to be blocked or throttled
+    #[serde(default)]
+    pub blocked_regions: Vec<String>,
 }
 
 impl ProtocolMeta {
@@ -193,6 +241,52 @@ impl ProtocolMeta {
     pub fn evasion_score(&self) -> f64 {
         self.detection.resistance_score()
     }
+
+    /// All ports the protocol is commonly seen on, default port first
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports = vec![self.default_port];
+        for port in &self.metadata.ports {
+            if !ports.contains(port) {
+                ports.push(*port);
+            }
+        }
+        ports
+    }
+
+    /// Whether the protocol is commonly seen on `port`
+    pub fn uses_port(&self, port: u16) -> bool {
+        self.default_port == port || self.metadata.ports.contains(&port)
+    }
+
+    /// Whether the protocol carries `tag` (case-insensitive)
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.metadata.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    /// Whether the protocol can be carried over `transport`
+    pub fn supports_transport(&self, transport: Transport) -> bool {
+        self.transport == transport || self.transport == Transport::Both
+    }
+
+    /// Risk level, from the explicit override or the suspicion score
+    pub fn risk_level(&self) -> RiskLevel {
+        self.metadata.risk.unwrap_or(if self.detection.suspicion < 0.15 {
+            RiskLevel::Low
+        } else if self.detection.suspicion < 0.3 {
+            RiskLevel::Medium
+        } else {
+            RiskLevel::High
+        })
+    }
+
+    /// Whether the protocol is not known to be blocked in `region`
+    pub fn suitable_for_region(&self, region: &str) -> bool {
+        !self
+            .metadata
+            .blocked_regions
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(region))
+    }
 }
 
 /// Protocol builder for easier construction
@@ -260,6 +354,26 @@ impl ProtocolBuilder {
         self
     }
 
+    pub fn tags(mut self, tags: &[&str]) -> Self {
+        self.meta.metadata.tags = tags.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    pub fn ports(mut self, ports: Vec<u16>) -> Self {
+        self.meta.metadata.ports = ports;
+        self
+    }
+
+    pub fn risk(mut self, risk: RiskLevel) -> Self {
+        self.meta.metadata.risk = Some(risk);
+        self
+    }
+
+    pub fn blocked_in(mut self, regions: &[&str]) -> Self {
+        self.meta.metadata.blocked_regions = regions.iter().map(|r| r.to_string()).collect();
+        self
+    }
+
     pub fn psf_path(mut self, path: PathBuf) -> Self {
         self.meta.psf_path = path;
         self
@@ -293,6 +407,27 @@ mod tests {
         assert!(proto.encryption_native);
     }
 
+    #[test]
+    fn test_protocol_tags_and_risk() {
+        let proto = ProtocolBuilder::new("quic", "QUIC")
+            .port(443)
+            .ports(vec![443, 8443])
+            .transport(Transport::Udp)
+            .detection(0.75, 0.2, 0.6)
+            .tags(&["udp", "browser"])
+            .blocked_in(&["china"])
+            .build();
+
+        assert_eq!(proto.ports(), vec![443, 8443]);
+        assert!(proto.uses_port(8443));
+        assert!(proto.has_tag("Browser"));
+        assert!(!proto.supports_transport(Transport::Tcp));
+        assert_eq!(proto.risk_level(), RiskLevel::Medium);
+        assert!(!proto.suitable_for_region("China"));
+        assert!(proto.suitable_for_region("iran"));
+        assert_eq!("HIGH".parse::<RiskLevel>().unwrap(), RiskLevel::High);
+    }
+
     #[test]
     fn test_detection_score() {
         let score = DetectionScore {
@@ -0,0 +1,479 @@
+//! In-connection protocol hopping
+//!
+//! Rotation only changes the protocol of new connections, so a long
+//! transfer keeps one appearance for its whole life. With hopping
+//! negotiated (see [`RoutingHeader`](crate::chain::RoutingHeader)), every
+//! message of the tunnel carries a record type, and the client can send a
+//! hop record naming another protocol and whether to use TLS session
+//! emulation:
+//!
+//! ```text
+//! client ── hop (old) ──▶ server        client switches its writes
+//!                                       server switches its reads,
+//! client ◀── ack (old) ── server        then acks and switches its writes
+//! client switches its reads
+//! ```
+//!
+//! Each direction changes at the message boundary right after the hop or
+//! its acknowledgement, so neither end ever parses a message with the wrong
+//! framing. Messages are framed like the tunnel handshake: a length prefix
+//! and the protocol wrapper's frame, or TLS Application Data records.
+
+use crate::config::NooshdarooConfig;
+use crate::entropy::PayloadEncoding;
+use crate::noise_transport::NoiseTransport;
+use crate::protocol::ProtocolId;
+use crate::protocol_wrapper::{ProtocolWrapper, WrapperRole};
+use crate::tls_record_layer::{TlsContentType, TlsRecordLayer};
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Relayed data
+const RECORD_DATA: u8 = 0x00;
+/// Switch to the [`HopTarget`] that follows
+const RECORD_HOP: u8 = 0x01;
+/// The peer switched after reading our hop
+const RECORD_HOP_ACK: u8 = 0x02;
+
+/// Hop flag: use TLS session emulation
+const FLAG_TLS_EMULATION: u8 = 0x01;
+
+/// Size of a TLS record header
+const TLS_HEADER_SIZE: usize = 5;
+
+/// Bytes read from the stream at once
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Whether `protocol` is carried over TLS, and so may use TLS session
+/// emulation
+pub fn is_tls_protocol(protocol: &ProtocolId) -> bool {
+    let id = protocol.as_str();
+    id.starts_with("https") || id.starts_with("tls") || id == "dns" || id == "dns-google"
+}
+
+/// Appearance of one direction of the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HopTarget {
+    pub protocol: ProtocolId,
+    /// Frame messages as TLS Application Data records instead of with the
+    /// protocol wrapper
+    pub tls_emulation: bool,
+}
+
+impl HopTarget {
+    /// The appearance `config` gives `protocol`
+    pub fn for_protocol(protocol: ProtocolId, config: &NooshdarooConfig) -> Self {
+        let tls_emulation = config.detection.enable_tls_session_emulation && is_tls_protocol(&protocol);
+        Self { protocol, tls_emulation }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let id = self.protocol.as_str();
+        if id.is_empty() || id.len() > u8::MAX as usize {
+            return Err(anyhow!("Protocol id '{}' must be 1 to 255 bytes", id));
+        }
+        let mut record = vec![RECORD_HOP, if self.tls_emulation { FLAG_TLS_EMULATION } else { 0 }, id.len() as u8];
+        record.extend_from_slice(id.as_bytes());
+        Ok(record)
+    }
+
+    fn decode(body: &[u8]) -> Result<Self> {
+        let (flags, id) = match body {
+            [flags, len, id @ ..] if id.len() == *len as usize => (*flags, id),
+            _ => return Err(anyhow!("Malformed hop record")),
+        };
+        let id = std::str::from_utf8(id).map_err(|_| anyhow!("Hop protocol is not UTF-8"))?;
+        Ok(Self {
+            protocol: ProtocolId::from(id),
+            tls_emulation: flags & FLAG_TLS_EMULATION != 0,
+        })
+    }
+}
+
+/// Framing of the messages of one direction
+enum Disguise {
+    Wrapped(Box<ProtocolWrapper>),
+    Tls(TlsRecordLayer),
+}
+
+impl Disguise {
+    fn new(target: &HopTarget, role: WrapperRole, encoding: PayloadEncoding) -> Self {
+        if target.tls_emulation {
+            Self::Tls(TlsRecordLayer::new())
+        } else {
+            Self::Wrapped(Box::new(
+                ProtocolWrapper::new(target.protocol.clone(), role, None).with_payload_encoding(encoding),
+            ))
+        }
+    }
+
+    /// Wire bytes of one Noise message
+    fn frame(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Wrapped(wrapper) => {
+                let wrapped = wrapper.wrap(message)?;
+                Ok(length_prefixed(&wrapped)?)
+            }
+            Self::Tls(tls) => Ok(tls.fragment_and_wrap(&length_prefixed(message)?).concat()),
+        }
+    }
+}
+
+fn length_prefixed(data: &[u8]) -> Result<Vec<u8>> {
+    let len = u16::try_from(data.len()).map_err(|_| anyhow!("Message too large: {}", data.len()))?;
+    let mut framed = Vec::with_capacity(2 + data.len());
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(data);
+    Ok(framed)
+}
+
+/// Remove one length-prefixed message from the front of `buf`
+fn take_length_prefixed(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+    if buf.len() < 2 + len {
+        return None;
+    }
+    let message = buf[2..2 + len].to_vec();
+    buf.drain(..2 + len);
+    Some(message)
+}
+
+/// What [`HopSession::recv`] read
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inbound {
+    Data(Vec<u8>),
+    /// The peer's idle keepalive
+    Keepalive,
+    /// The peer's hop, to [acknowledge](HopSession::acknowledge), or its
+    /// acknowledgement of ours
+    Hopped,
+    /// The peer closed its write half
+    Closed,
+}
+
+/// Both directions of a tunnel that negotiated protocol hops
+///
+/// Reads are buffered in the session, so [`recv`](Self::recv) can be
+/// cancelled in a `select!` without losing a partly read message.
+pub struct HopSession {
+    role: WrapperRole,
+    encoding: PayloadEncoding,
+    current: HopTarget,
+    outbound: Disguise,
+    inbound: Disguise,
+    /// Our hop the peer has not acknowledged yet
+    pending: Option<HopTarget>,
+    /// The peer's hop we have not acknowledged yet
+    unacknowledged: Option<HopTarget>,
+    /// Read but not yet parsed
+    received: Vec<u8>,
+    /// Application data of TLS records not yet parsed into messages
+    records: Vec<u8>,
+    /// The peer sent a TLS alert (close_notify) instead of a record
+    alerted: bool,
+    chunk: Box<[u8]>,
+}
+
+impl HopSession {
+    pub fn new(target: HopTarget, role: WrapperRole, encoding: PayloadEncoding) -> Self {
+        Self {
+            role,
+            encoding,
+            outbound: Disguise::new(&target, role, encoding),
+            inbound: Disguise::new(&target, role, encoding),
+            current: target,
+            pending: None,
+            unacknowledged: None,
+            received: Vec::new(),
+            records: Vec::new(),
+            alerted: false,
+            chunk: vec![0u8; READ_CHUNK_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Appearance of our writes
+    pub fn current(&self) -> &HopTarget {
+        &self.current
+    }
+
+    /// Whether a hop is waiting for the peer's acknowledgement
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Send relayed data
+    pub async fn send<S>(&mut self, noise: &mut NoiseTransport, stream: &mut S, data: &[u8]) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut record = Vec::with_capacity(1 + data.len());
+        record.push(RECORD_DATA);
+        record.extend_from_slice(data);
+        self.write_record(noise, stream, &record).await
+    }
+
+    /// Send an idle keepalive (an empty message)
+    pub async fn keepalive<S>(&mut self, noise: &mut NoiseTransport, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        self.write_record(noise, stream, &[]).await
+    }
+
+    /// Move the tunnel to `target`; our writes switch right away, our reads
+    /// once the peer acknowledges
+    pub async fn hop<S>(&mut self, noise: &mut NoiseTransport, stream: &mut S, target: HopTarget) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if self.pending.is_some() {
+            return Err(anyhow!("A protocol hop is already in progress"));
+        }
+        self.write_record(noise, stream, &target.encode()?).await?;
+        self.outbound = Disguise::new(&target, self.role, self.encoding);
+        self.current = target.clone();
+        self.pending = Some(target);
+        Ok(())
+    }
+
+    /// Read the next message
+    ///
+    /// After [`Inbound::Hopped`] the caller must [`acknowledge`](Self::acknowledge)
+    /// the hop; it is not sent from here so that `recv` never stops halfway
+    /// through a write when cancelled.
+    pub async fn recv<S>(&mut self, noise: &mut NoiseTransport, stream: &mut S) -> Result<Inbound>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let message = loop {
+            match self.take_message()? {
+                Some(message) => break message,
+                None if self.alerted => return Ok(Inbound::Closed),
+                None => {
+                    let n = stream.read(&mut self.chunk).await?;
+                    if n == 0 {
+                        return Ok(Inbound::Closed);
+                    }
+                    self.received.extend_from_slice(&self.chunk[..n]);
+                }
+            }
+        };
+
+        let record = noise.decrypt(&message)?;
+        match record.split_first() {
+            None => Ok(Inbound::Keepalive),
+            Some((&RECORD_DATA, data)) => Ok(Inbound::Data(data.to_vec())),
+            Some((&RECORD_HOP, body)) => {
+                // Everything the peer sends after the hop uses the new framing
+                let target = HopTarget::decode(body)?;
+                log::info!("Peer hopped to {} (TLS emulation: {})", target.protocol.as_str(), target.tls_emulation);
+                self.inbound = Disguise::new(&target, self.role, self.encoding);
+                self.unacknowledged = Some(target);
+                Ok(Inbound::Hopped)
+            }
+            Some((&RECORD_HOP_ACK, _)) => {
+                let target = self.pending.take().ok_or_else(|| anyhow!("Unexpected hop acknowledgement"))?;
+                self.inbound = Disguise::new(&target, self.role, self.encoding);
+                Ok(Inbound::Hopped)
+            }
+            Some((kind, _)) => Err(anyhow!("Unknown record type 0x{:02x}", kind)),
+        }
+    }
+
+    /// Acknowledge the peer's hop, if any, and switch our writes to it
+    pub async fn acknowledge<S>(&mut self, noise: &mut NoiseTransport, stream: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if let Some(target) = self.unacknowledged.take() {
+            self.write_record(noise, stream, &[RECORD_HOP_ACK]).await?;
+            self.outbound = Disguise::new(&target, self.role, self.encoding);
+            self.current = target;
+        }
+        Ok(())
+    }
+
+    /// Close our write half, with a TLS close_notify when emulating TLS
+    pub async fn shutdown<S>(&self, stream: &mut S) -> std::io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if let Disguise::Tls(ref tls) = self.outbound {
+            tls.send_close_notify(stream).await?;
+        }
+        stream.shutdown().await
+    }
+
+    async fn write_record<S>(&mut self, noise: &mut NoiseTransport, stream: &mut S, record: &[u8]) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let message = noise.encrypt(record)?;
+        let frame = self.outbound.frame(&message)?;
+        stream.write_all(&frame).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Next complete Noise message in the read buffers, if any
+    fn take_message(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.inbound {
+            Disguise::Wrapped(ref wrapper) => match take_length_prefixed(&mut self.received) {
+                Some(wrapped) => Ok(Some(wrapper.unwrap(&wrapped)?)),
+                None => Ok(None),
+            },
+            // Only as many records as the message needs: the bytes after a
+            // hop may already use another framing
+            Disguise::Tls(_) => loop {
+                if let Some(message) = take_length_prefixed(&mut self.records) {
+                    return Ok(Some(message));
+                }
+                if self.received.len() < TLS_HEADER_SIZE {
+                    return Ok(None);
+                }
+                let len = u16::from_be_bytes([self.received[3], self.received[4]]) as usize;
+                if self.received.len() < TLS_HEADER_SIZE + len {
+                    return Ok(None);
+                }
+                if self.received[0] == TlsContentType::Alert as u8 {
+                    self.alerted = true;
+                    return Ok(None);
+                }
+                if self.received[0] != TlsContentType::ApplicationData as u8 {
+                    return Err(anyhow!("Unexpected TLS record type 0x{:02x}", self.received[0]));
+                }
+                self.records.extend_from_slice(&self.received[TLS_HEADER_SIZE..TLS_HEADER_SIZE + len]);
+                self.received.drain(..TLS_HEADER_SIZE + len);
+            },
+        }
+    }
+}
+
+/// When and where the client hops
+pub struct HopSchedule {
+    interval_bytes: u64,
+    protocols: Vec<ProtocolId>,
+    tls_emulation: bool,
+    relayed: u64,
+}
+
+impl HopSchedule {
+    pub fn new(config: &NooshdarooConfig) -> Self {
+        let hopping = &config.shapeshift.hopping;
+        Self {
+            interval_bytes: hopping.interval_bytes,
+            protocols: hopping.protocols.iter().map(|p| ProtocolId::from(p.as_str())).collect(),
+            tls_emulation: config.detection.enable_tls_session_emulation,
+            relayed: 0,
+        }
+    }
+
+    /// Account for `bytes` relayed in either direction
+    pub fn record(&mut self, bytes: usize) {
+        self.relayed += bytes as u64;
+    }
+
+    /// Where to hop from `current`, once `interval_bytes` have been relayed
+    /// since the last hop
+    ///
+    /// Without configured protocols, TLS protocols hop between TLS session
+    /// emulation and their protocol wrapper.
+    pub fn next(&mut self, current: &HopTarget) -> Option<HopTarget> {
+        if self.relayed < self.interval_bytes {
+            return None;
+        }
+        self.relayed = 0;
+        let candidates: Vec<&ProtocolId> = self.protocols.iter().filter(|p| **p != current.protocol).collect();
+        match candidates.choose(&mut rand::thread_rng()) {
+            Some(protocol) => Some(HopTarget {
+                protocol: (*protocol).clone(),
+                tls_emulation: self.tls_emulation && is_tls_protocol(protocol),
+            }),
+            None if self.protocols.is_empty() && is_tls_protocol(&current.protocol) => Some(HopTarget {
+                protocol: current.protocol.clone(),
+                tls_emulation: !current.tls_emulation,
+            }),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise_transport::{NoiseConfig, NoiseKeypair};
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_hop_session() {
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            ..Default::default()
+        };
+        let (mut client_stream, mut server_stream) = duplex(64 * 1024);
+        let (client_noise, server_noise) = tokio::join!(
+            NoiseTransport::client_handshake(&mut client_stream, &client_config, None),
+            NoiseTransport::server_handshake(&mut server_stream, &server_config, None),
+        );
+        let (mut client_noise, mut server_noise) = (client_noise.unwrap(), server_noise.unwrap());
+
+        let https = HopTarget { protocol: ProtocolId::from("https"), tls_emulation: true };
+        let mut client = HopSession::new(https.clone(), WrapperRole::Client, PayloadEncoding::Raw);
+        let mut server = HopSession::new(https, WrapperRole::Server, PayloadEncoding::Raw);
+
+        // The hop and the data after it arrive in one read
+        let http = HopTarget { protocol: ProtocolId::from("http"), tls_emulation: false };
+        client.send(&mut client_noise, &mut client_stream, b"before").await.unwrap();
+        client.hop(&mut client_noise, &mut client_stream, http.clone()).await.unwrap();
+        client.send(&mut client_noise, &mut client_stream, b"after").await.unwrap();
+        assert!(client.is_pending());
+
+        // The server's reply before its ack still uses TLS records
+        server.send(&mut server_noise, &mut server_stream, b"reply").await.unwrap();
+        for expected in [Inbound::Data(b"before".to_vec()), Inbound::Hopped, Inbound::Data(b"after".to_vec())] {
+            assert_eq!(server.recv(&mut server_noise, &mut server_stream).await.unwrap(), expected);
+        }
+        server.acknowledge(&mut server_noise, &mut server_stream).await.unwrap();
+        assert_eq!(server.current(), &http);
+        server.keepalive(&mut server_noise, &mut server_stream).await.unwrap();
+
+        for expected in [Inbound::Data(b"reply".to_vec()), Inbound::Hopped, Inbound::Keepalive] {
+            assert_eq!(client.recv(&mut client_noise, &mut client_stream).await.unwrap(), expected);
+        }
+        assert!(!client.is_pending());
+
+        drop(server_stream);
+        assert_eq!(client.recv(&mut client_noise, &mut client_stream).await.unwrap(), Inbound::Closed);
+    }
+
+    #[test]
+    fn test_hop_schedule() {
+        let mut config = NooshdarooConfig::default();
+        config.shapeshift.hopping.interval_bytes = 1000;
+        let mut schedule = HopSchedule::new(&config);
+        let https = HopTarget::for_protocol(ProtocolId::from("https"), &config);
+        assert!(https.tls_emulation);
+
+        schedule.record(999);
+        assert_eq!(schedule.next(&https), None);
+        schedule.record(1);
+        let next = schedule.next(&https).unwrap();
+        assert_eq!(next.protocol, https.protocol);
+        assert!(!next.tls_emulation);
+        assert_eq!(schedule.next(&next), None);
+
+        config.shapeshift.hopping.protocols = vec!["https".to_string(), "ssh".to_string()];
+        let mut schedule = HopSchedule::new(&config);
+        schedule.record(1000);
+        assert_eq!(
+            schedule.next(&https),
+            Some(HopTarget { protocol: ProtocolId::from("ssh"), tls_emulation: false })
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Over-the-air protocol library updates
+//!
+//! New protocol definitions and evasion techniques should reach users
+//! without a new release. An operator publishes a signed bundle of PSF
+//! files on mirrors that are costly to block (`[protocol_updates]
+//! mirrors`); clients fetch it periodically, check that a key in
+//! `[protocol_trust] trusted_keys` signed it, and swap the library the
+//! shape-shift controller picks protocols from while running.
+//!
+//! A bundle travels in the same `<signature>.<payload>` envelope as
+//! bootstrap documents (see [`crate::bootstrap`]), wrapping a JSON
+//! [`ProtocolBundle`]. Bundles are numbered and clients only ever move to
+//! a higher version, so a mirror cannot roll them back to protocols that
+//! are known to be blocked. `nooshdaroo sign-protocol-bundle` produces
+//! envelopes.
+
+use crate::bootstrap::{https_get, https_host, is_allowed_host, open_json, seal_json};
+use crate::config::{NooshdarooConfig, ProtocolTrustConfig, ProtocolUpdatesConfig};
+use crate::library::ProtocolLibrary;
+use crate::psf::signature::{PsfSigner, PsfVerifier};
+use crate::shapeshift::ShapeShiftController;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+/// Largest bundle accepted
+const MAX_BUNDLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Protocol definitions published by an operator
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolBundle {
+    /// Increases with every bundle published
+    pub version: u64,
+    /// Unix seconds when the bundle was signed
+    pub issued_at: u64,
+    /// Unix seconds after which the bundle must not be used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// PSF files by path relative to the protocol directory, such as
+    /// `web/https.psf`
+    pub protocols: BTreeMap<String, String>,
+}
+
+impl ProtocolBundle {
+    /// Sign the bundle, returning its envelope
+    pub fn seal(&self, signer: &PsfSigner) -> Result<String, String> {
+        let json = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        Ok(seal_json(&json, signer))
+    }
+
+    /// Verify an envelope and decode the bundle if it is current at `now`
+    pub fn open(envelope: &str, verifier: &PsfVerifier, now: SystemTime) -> Result<Self, String> {
+        let json = open_json(envelope, verifier)?;
+        let bundle: Self =
+            serde_json::from_slice(&json).map_err(|e| format!("Invalid protocol bundle: {}", e))?;
+
+        let now = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        if bundle.expires_at.is_some_and(|expires| expires <= now) {
+            return Err("Protocol bundle has expired".to_string());
+        }
+        if let Some(path) = bundle.protocols.keys().find(|path| !is_bundle_path(path)) {
+            return Err(format!("Protocol bundle has invalid path '{}'", path));
+        }
+        Ok(bundle)
+    }
+}
+
+/// Whether `path` names a PSF file inside the protocol directory
+fn is_bundle_path(path: &str) -> bool {
+    let path = Path::new(path);
+    path.extension().is_some_and(|ext| ext == "psf")
+        && path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Where a protocol bundle can be fetched from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mirror {
+    Https(String),
+    File(PathBuf),
+}
+
+impl FromStr for Mirror {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            https_host(s)?;
+            Ok(Self::Https(s.to_string()))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(Self::File(PathBuf::from(path)))
+        } else {
+            Err(format!("Unknown protocol mirror '{}' (expected https:// or file:)", s))
+        }
+    }
+}
+
+impl std::fmt::Display for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Https(url) => f.write_str(url),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// Parse the configured mirrors, rejecting HTTPS hosts that are not allowed
+pub fn parse_mirrors(config: &ProtocolUpdatesConfig) -> Result<Vec<Mirror>, String> {
+    config
+        .mirrors
+        .iter()
+        .map(|mirror| {
+            let mirror: Mirror = mirror.parse()?;
+            if let Mirror::Https(ref url) = mirror {
+                let host = https_host(url)?;
+                if !is_allowed_host(host, &config.allowed_hosts) {
+                    return Err(format!("Protocol mirror {} is not in protocol_updates.allowed_hosts", host));
+                }
+            }
+            Ok(mirror)
+        })
+        .collect()
+}
+
+/// Fetches protocol bundles and builds updated libraries from them
+pub struct ProtocolUpdater {
+    mirrors: Vec<Mirror>,
+    verifier: PsfVerifier,
+    timeout: Duration,
+    interval: Duration,
+    cache: Option<PathBuf>,
+    protocol_dir: PathBuf,
+    trust: ProtocolTrustConfig,
+    /// Version of the bundle in use, 0 before any
+    version: u64,
+}
+
+impl ProtocolUpdater {
+    pub fn new(config: &NooshdarooConfig) -> Result<Self, String> {
+        let updates = &config.protocol_updates;
+        Ok(Self {
+            mirrors: parse_mirrors(updates)?,
+            verifier: PsfVerifier::new(&config.protocol_trust.trusted_keys)?,
+            timeout: updates.timeout,
+            interval: updates.interval,
+            cache: updates.cache.clone(),
+            protocol_dir: config.protocol_dir.clone(),
+            trust: config.protocol_trust.clone(),
+            version: 0,
+        })
+    }
+
+    /// Version of the bundle in use, 0 before any
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Library with the bundle cached by a previous run, if still valid
+    pub fn load_cached(&mut self) -> Option<ProtocolLibrary> {
+        let path = self.cache.as_ref()?;
+        let envelope = std::fs::read_to_string(path).ok()?;
+        match ProtocolBundle::open(&envelope, &self.verifier, SystemTime::now()) {
+            Ok(bundle) => self.apply(&bundle).map_err(|e| log::warn!("{}", e)).ok(),
+            Err(e) => {
+                log::warn!("Ignoring cached protocol bundle {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Library with the newest bundle on the mirrors, if newer than the one
+    /// in use
+    pub async fn check(&mut self) -> Result<Option<ProtocolLibrary>, String> {
+        let Some((envelope, bundle)) = self.fetch().await? else {
+            return Ok(None);
+        };
+        let library = self.apply(&bundle)?;
+        if let Some(ref path) = self.cache {
+            if let Err(e) = tokio::fs::write(path, envelope).await {
+                log::warn!("Failed to cache protocol bundle in {}: {}", path.display(), e);
+            }
+        }
+        Ok(Some(library))
+    }
+
+    /// Check for updates every interval, swapping them into `controller`
+    pub fn spawn(mut self, controller: Arc<RwLock<ShapeShiftController>>) {
+        tokio::spawn(async move {
+            if let Some(library) = self.load_cached() {
+                controller.write().await.set_library(Arc::new(library));
+            }
+            loop {
+                match self.check().await {
+                    Ok(Some(library)) => controller.write().await.set_library(Arc::new(library)),
+                    Ok(None) => log::debug!("Protocol library is up to date (bundle {})", self.version),
+                    Err(e) => log::warn!("Protocol update failed: {}", e),
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        });
+    }
+
+    /// Library with `bundle` applied, which then counts as in use
+    fn apply(&mut self, bundle: &ProtocolBundle) -> Result<ProtocolLibrary, String> {
+        let library = ProtocolLibrary::load_with_updates(&self.protocol_dir, &self.trust, &bundle.protocols)
+            .map_err(|e| format!("Failed to apply protocol bundle {}: {}", bundle.version, e))?;
+        self.version = bundle.version;
+        log::info!(
+            "Protocol library updated to bundle {} ({} protocols, {} total)",
+            bundle.version,
+            bundle.protocols.len(),
+            library.len()
+        );
+        Ok(library)
+    }
+
+    /// First bundle newer than the one in use, with its envelope, from the
+    /// mirrors tried in order
+    async fn fetch(&self) -> Result<Option<(String, ProtocolBundle)>, String> {
+        let mut errors = Vec::new();
+        for mirror in &self.mirrors {
+            let result = match tokio::time::timeout(self.timeout, self.fetch_envelope(mirror)).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("timed out after {:?}", self.timeout)),
+            };
+            let bundle = result.and_then(|envelope| {
+                ProtocolBundle::open(&envelope, &self.verifier, SystemTime::now()).map(|bundle| (envelope, bundle))
+            });
+            match bundle {
+                Ok((envelope, bundle)) if bundle.version > self.version => return Ok(Some((envelope, bundle))),
+                Ok((_, bundle)) => {
+                    log::debug!("Protocol mirror {} has bundle {}, not newer", mirror, bundle.version);
+                    return Ok(None);
+                }
+                Err(e) => {
+                    log::warn!("Protocol mirror {} failed: {}", mirror, e);
+                    errors.push(format!("{}: {}", mirror, e));
+                }
+            }
+        }
+        Err(format!("No protocol mirror yielded a bundle ({})", errors.join("; ")))
+    }
+
+    async fn fetch_envelope(&self, mirror: &Mirror) -> Result<String, String> {
+        match mirror {
+            Mirror::File(path) => tokio::fs::read_to_string(path).await.map_err(|e| e.to_string()),
+            Mirror::Https(url) => {
+                let url = url.clone();
+                let timeout = self.timeout;
+                let body = tokio::task::spawn_blocking(move || https_get(&url, timeout, MAX_BUNDLE_SIZE))
+                    .await
+                    .map_err(|e| e.to_string())??;
+                String::from_utf8(body).map_err(|_| "Response is not text".to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolId;
+
+    fn bundle(version: u64) -> ProtocolBundle {
+        ProtocolBundle {
+            version,
+            issued_at: 1_700_000_000,
+            expires_at: None,
+            protocols: BTreeMap::from([(
+                "web/otaproto.psf".to_string(),
+                "# Test\n@SEGMENT.CRYPTO\n  TRANSPORT: TCP;\n  DEFAULT_PORT: 8443\n".to_string(),
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_protocol_bundle_envelope() {
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        let verifier = PsfVerifier::new(&[signer.public_key_base64()]).unwrap();
+        let envelope = bundle(1).seal(&signer).unwrap();
+        assert_eq!(ProtocolBundle::open(&envelope, &verifier, SystemTime::now()).unwrap(), bundle(1));
+
+        // Foreign and expired bundles, and files outside the directory, are rejected
+        let stranger = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        assert!(ProtocolBundle::open(&bundle(1).seal(&stranger).unwrap(), &verifier, SystemTime::now()).is_err());
+        let expired = ProtocolBundle { expires_at: Some(1_700_000_100), ..bundle(1) };
+        assert!(ProtocolBundle::open(&expired.seal(&signer).unwrap(), &verifier, SystemTime::now()).is_err());
+        for path in ["../evil.psf", "/etc/evil.psf", "web/evil.txt"] {
+            let mut escaping = bundle(1);
+            escaping.protocols.insert(path.to_string(), String::new());
+            assert!(ProtocolBundle::open(&escaping.seal(&signer).unwrap(), &verifier, SystemTime::now()).is_err());
+        }
+
+        let config = ProtocolUpdatesConfig {
+            mirrors: vec!["https://cdn.jsdelivr.net/gh/x/bundle".to_string(), "file:/tmp/bundle".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(parse_mirrors(&config).unwrap().len(), 2);
+        assert!("dns:_nd.example.org".parse::<Mirror>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_from_mirror() {
+        let dir = std::env::temp_dir().join(format!("nooshdaroo-ota-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mirror = dir.join("bundle");
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+
+        let mut config = NooshdarooConfig {
+            protocol_dir: dir.join("protocols"),
+            ..Default::default()
+        };
+        config.protocol_trust.trusted_keys = vec![signer.public_key_base64()];
+        config.protocol_updates.mirrors = vec![format!("file:{}", mirror.display())];
+        config.protocol_updates.cache = Some(dir.join("cache"));
+        let mut updater = ProtocolUpdater::new(&config).unwrap();
+
+        std::fs::write(&mirror, bundle(2).seal(&signer).unwrap()).unwrap();
+        let library = updater.check().await.unwrap().unwrap();
+        assert_eq!(library.get(&ProtocolId::from("otaproto")).unwrap().default_port, 8443);
+        assert_eq!(updater.version(), 2);
+        assert!(updater.check().await.unwrap().is_none());
+
+        // Older bundles are not rolled back to
+        std::fs::write(&mirror, bundle(1).seal(&signer).unwrap()).unwrap();
+        assert!(updater.check().await.unwrap().is_none());
+
+        // The next run starts from the cached bundle
+        let mut restarted = ProtocolUpdater::new(&config).unwrap();
+        assert!(restarted.load_cached().unwrap().get(&ProtocolId::from("otaproto")).is_some());
+        assert_eq!(restarted.version(), 2);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -4,6 +4,7 @@
 //! headers to make traffic appear as legitimate HTTPS, DNS, SSH, etc.
 
 use std::io::{Error, ErrorKind};
+use crate::entropy::PayloadEncoding;
 use crate::protocol::ProtocolId;
 use crate::traffic::TrafficShaper;
 use crate::psf::{PsfInterpreter, ProtocolFrame};
@@ -57,6 +58,7 @@ pub struct ProtocolWrapper {
     server_frame: Option<ProtocolFrame>,
     client_handshake_frame: Option<ProtocolFrame>,
     server_handshake_frame: Option<ProtocolFrame>,
+    payload_encoding: PayloadEncoding,
 }
 
 /// Map protocol name to embedded PSF content
@@ -132,14 +134,54 @@ impl ProtocolWrapper {
             server_frame,
             client_handshake_frame,
             server_handshake_frame,
+            payload_encoding: PayloadEncoding::Raw,
+        }
+    }
+
+    /// Encode payloads before framing to match the cover protocol's entropy
+    ///
+    /// Both ends of the tunnel must use the same encoding.
+    pub fn with_payload_encoding(mut self, encoding: PayloadEncoding) -> Self {
+        self.payload_encoding = encoding;
+        self
+    }
+
+    /// Payload encoding in use
+    pub fn payload_encoding(&self) -> PayloadEncoding {
+        self.payload_encoding
+    }
+
+    /// PSF data frame used for frames this side sends, if one was loaded
+    pub fn outbound_frame(&self) -> Option<&ProtocolFrame> {
+        match self.role {
+            WrapperRole::Client => self.client_frame.as_ref(),
+            WrapperRole::Server => self.server_frame.as_ref(),
         }
     }
 
     /// Wrap Noise encrypted data with protocol headers
     ///
-    /// Takes raw Noise encrypted data (payload + 16-byte Poly1305 MAC)
-    /// and wraps it with protocol-specific headers using PSF
+    /// Takes raw Noise encrypted data (payload + 16-byte Poly1305 MAC),
+    /// applies the payload encoding, and wraps it with protocol-specific
+    /// headers using PSF
     pub fn wrap(&mut self, noise_data: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.payload_encoding == PayloadEncoding::Raw {
+            return self.wrap_frame(noise_data);
+        }
+        let encoded = self.payload_encoding.encode(noise_data);
+        self.wrap_frame(&encoded)
+    }
+
+    /// Unwrap protocol headers and payload encoding to get raw Noise encrypted data
+    pub fn unwrap(&self, wrapped_data: &[u8]) -> Result<Vec<u8>, Error> {
+        let payload = self.unwrap_frame(wrapped_data)?;
+        match self.payload_encoding {
+            PayloadEncoding::Raw => Ok(payload),
+            encoding => encoding.decode(&payload),
+        }
+    }
+
+    fn wrap_frame(&mut self, noise_data: &[u8]) -> Result<Vec<u8>, Error> {
         // For HTTPS/TLS, use hardcoded implementation for compatibility
         // (PSF files define separate auth_tag which conflicts with Noise's built-in MAC)
         match self.protocol_id.as_str() {
@@ -187,8 +229,7 @@ impl ProtocolWrapper {
         Ok(noise_data.to_vec())
     }
 
-    /// Unwrap protocol headers to get raw Noise encrypted data
-    pub fn unwrap(&self, wrapped_data: &[u8]) -> Result<Vec<u8>, Error> {
+    fn unwrap_frame(&self, wrapped_data: &[u8]) -> Result<Vec<u8>, Error> {
         // For HTTPS/TLS, use hardcoded implementation for compatibility
         // (PSF files define separate auth_tag which conflicts with Noise's built-in MAC)
         match self.protocol_id.as_str() {
@@ -501,4 +542,16 @@ mod tests {
         let result = wrapper.unwrap(&bad_data);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_payload_encoding_roundtrip() {
+        let mut wrapper = ProtocolWrapper::new(ProtocolId::from("https"), WrapperRole::Client, None)
+            .with_payload_encoding(PayloadEncoding::Base64);
+        let noise_data: Vec<u8> = (0..=255).collect();
+
+        let wrapped = wrapper.wrap(&noise_data).unwrap();
+        assert_eq!(wrapped.len(), 5 + PayloadEncoding::Base64.encoded_len(noise_data.len()));
+        assert!(wrapped[5..].iter().all(u8::is_ascii_graphic));
+        assert_eq!(wrapper.unwrap(&wrapped).unwrap(), noise_data);
+    }
 }
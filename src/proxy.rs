@@ -25,6 +25,28 @@ enum ServerStream {
     Tcp(TcpStream),
     Dns(DnsStream),
     DnsWithKcp(crate::reliable_transport::ReliableTransport<DnsStream>),
+    Captured(crate::capture::CaptureStream<TcpStream>),
+    /// Tunnel nested inside the tunnel to the previous hop of a relay chain
+    Hop(tokio::io::DuplexStream),
+    /// KCP session over plain UDP
+    Kcp(tokio::io::DuplexStream),
+    /// Chunked HTTP/1.1 bodies over TCP
+    Http(tokio::io::DuplexStream),
+    /// BitTorrent peer connection over TCP
+    Bittorrent(tokio::io::DuplexStream),
+    /// FTP session with passive data connections
+    Ftp(tokio::io::DuplexStream),
+    /// Bidirectional gRPC stream over HTTP/2
+    Grpc(tokio::io::DuplexStream),
+    /// OpenVPN session over TCP
+    Openvpn(tokio::io::DuplexStream),
+    /// MQTT session over TCP
+    Mqtt(tokio::io::DuplexStream),
+    Sip(tokio::io::DuplexStream),
+    Rtsp(tokio::io::DuplexStream),
+    Smb(tokio::io::DuplexStream),
+    Syslog(tokio::io::DuplexStream),
+    Steam(tokio::io::DuplexStream),
 }
 
 impl tokio::io::AsyncRead for ServerStream {
@@ -37,6 +59,20 @@ impl tokio::io::AsyncRead for ServerStream {
             ServerStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
             ServerStream::Dns(stream) => Pin::new(stream).poll_read(cx, buf),
             ServerStream::DnsWithKcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Captured(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Hop(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Kcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Http(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Bittorrent(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Ftp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Grpc(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Openvpn(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Mqtt(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Sip(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Rtsp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Smb(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Syslog(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Steam(stream) => Pin::new(stream).poll_read(cx, buf),
         }
     }
 }
@@ -51,6 +87,20 @@ impl tokio::io::AsyncWrite for ServerStream {
             ServerStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
             ServerStream::Dns(stream) => Pin::new(stream).poll_write(cx, buf),
             ServerStream::DnsWithKcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Captured(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Hop(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Kcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Http(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Bittorrent(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Ftp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Grpc(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Openvpn(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Mqtt(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Sip(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Rtsp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Smb(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Syslog(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Steam(stream) => Pin::new(stream).poll_write(cx, buf),
         }
     }
 
@@ -59,6 +109,20 @@ impl tokio::io::AsyncWrite for ServerStream {
             ServerStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
             ServerStream::Dns(stream) => Pin::new(stream).poll_flush(cx),
             ServerStream::DnsWithKcp(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Captured(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Hop(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Kcp(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Http(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Bittorrent(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Ftp(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Grpc(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Openvpn(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Mqtt(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Sip(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Rtsp(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Smb(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Syslog(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Steam(stream) => Pin::new(stream).poll_flush(cx),
         }
     }
 
@@ -67,6 +131,20 @@ impl tokio::io::AsyncWrite for ServerStream {
             ServerStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
             ServerStream::Dns(stream) => Pin::new(stream).poll_shutdown(cx),
             ServerStream::DnsWithKcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Captured(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Hop(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Kcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Http(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Bittorrent(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Ftp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Grpc(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Openvpn(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Mqtt(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Sip(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Rtsp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Smb(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Syslog(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Steam(stream) => Pin::new(stream).poll_shutdown(cx),
         }
     }
 }
@@ -86,26 +164,36 @@ pub enum ProxyType {
 /// Unified proxy listener that handles multiple proxy protocols
 #[allow(dead_code)]
 pub struct UnifiedProxyListener {
-    listen_addr: SocketAddr,
+    listen_addr: crate::config::ListenAddrs,
     proxy_types: Vec<ProxyType>,
     server_addr: Option<SocketAddr>,
     noise_config: Option<crate::noise_transport::NoiseConfig>,
     protocol_id: crate::ProtocolId,
     controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
     config: Arc<crate::NooshdarooConfig>,
+    upstreams: Option<Arc<crate::upstream::UpstreamPool>>,
+    capture: Option<crate::capture::PacketCapture>,
+    chain: Option<Arc<crate::chain::RelayChain>>,
+    kill_switch: Option<Arc<crate::kill_switch::KillSwitch>>,
+    experiment: Option<Arc<crate::experiment::Experiment>>,
 }
 
 impl UnifiedProxyListener {
-    /// Create new unified proxy listener
-    pub fn new(listen_addr: SocketAddr, proxy_types: Vec<ProxyType>, protocol_id: crate::ProtocolId, config: Arc<crate::NooshdarooConfig>) -> Self {
+    /// Create new unified proxy listener on one or more addresses
+    pub fn new(listen_addr: impl Into<crate::config::ListenAddrs>, proxy_types: Vec<ProxyType>, protocol_id: crate::ProtocolId, config: Arc<crate::NooshdarooConfig>) -> Self {
         Self {
-            listen_addr,
+            listen_addr: listen_addr.into(),
             proxy_types,
             server_addr: None,
             noise_config: None,
             protocol_id,
             controller: None,
             config,
+            upstreams: None,
+            capture: None,
+            chain: None,
+            kill_switch: None,
+            experiment: None,
         }
     }
 
@@ -116,6 +204,40 @@ impl UnifiedProxyListener {
         self
     }
 
+    /// Distribute connections across a pool of upstream servers.
+    /// Each server's Noise config is derived from `noise_config`.
+    pub fn with_upstreams(mut self, pool: Arc<crate::upstream::UpstreamPool>, noise_config: crate::noise_transport::NoiseConfig) -> Self {
+        self.upstreams = Some(pool);
+        self.noise_config = Some(noise_config);
+        self
+    }
+
+    /// Record the wrapped bytes of every TCP tunnel into a pcap file
+    pub fn with_capture(mut self, capture: crate::capture::PacketCapture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Reach targets through a chain of servers, entering it at the address
+    /// set with [`with_server`](Self::with_server)
+    pub fn with_chain(mut self, chain: crate::chain::RelayChain) -> Self {
+        self.chain = Some(Arc::new(chain));
+        self
+    }
+
+    /// Block traffic outside the tunnel while it is down
+    pub fn with_kill_switch(mut self, kill_switch: Arc<crate::kill_switch::KillSwitch>) -> Self {
+        self.kill_switch = Some(kill_switch);
+        self
+    }
+
+    /// Spread part of the connections across candidate protocols to find
+    /// the best one
+    pub fn with_experiment(mut self, experiment: Arc<crate::experiment::Experiment>) -> Self {
+        self.experiment = Some(experiment);
+        self
+    }
+
     /// Set ShapeShiftController for dynamic protocol rotation
     pub fn with_controller(mut self, controller: Arc<RwLock<crate::ShapeShiftController>>) -> Self {
         self.controller = Some(controller);
@@ -132,8 +254,8 @@ impl UnifiedProxyListener {
         // Spawn UDP listener if needed (SERVER MODE ONLY)
         // If server_addr is Some(), we're in CLIENT mode - don't start UDP server
         // If server_addr is None, we're in SERVER mode - start UDP server
-        if needs_udp && self.server_addr.is_none() {
-            let udp_addr = self.listen_addr;
+        if needs_udp && self.server_addr.is_none() && self.upstreams.is_none() {
+            let udp_addr = self.listen_addr.first();
             let noise_config = self.noise_config.clone();
             let config = self.config.clone();
 
@@ -143,15 +265,17 @@ impl UnifiedProxyListener {
                 }
             });
 
-            log::info!("Nooshdaroo server listening on {} (TCP + UDP for DNS tunnel)", self.listen_addr);
+            log::info!("Nooshdaroo server listening on {} (TCP + UDP on {} for DNS tunnel)", self.listen_addr, udp_addr);
         } else {
             log::info!("Nooshdaroo unified proxy listening on {} (TCP only)", self.listen_addr);
         }
 
         // Start TCP listener (always needed for backward compatibility)
-        let listener = TcpListener::bind(self.listen_addr).await?;
+        let listener = crate::listen::TcpListeners::bind(&self.listen_addr).await?;
 
         loop {
+            // Connections queue in the backlog while a service is paused
+            crate::service::resumed().await;
             let (socket, peer_addr) = listener.accept().await?;
             // Enable TCP_NODELAY on client socket to prevent buffering delays (critical for HTTP/2)
             socket.set_nodelay(true)?;
@@ -160,15 +284,26 @@ impl UnifiedProxyListener {
             let proxy_types = self.proxy_types.clone();
             let server_addr = self.server_addr;
             let noise_config = self.noise_config.clone();
-            let protocol_id = self.protocol_id.clone();
+            let (protocol_id, trial) = match self.experiment {
+                Some(ref experiment) => experiment.assign(&self.protocol_id),
+                None => (self.protocol_id.clone(), false),
+            };
+            let experiment = self.experiment.clone().filter(|_| trial);
+            let upstreams = self.upstreams.clone();
+            let capture = self.capture.clone();
+            let chain = self.chain.clone();
+            let kill_switch = self.kill_switch.clone();
 
             let controller_clone = self.controller.clone();
             let config = self.config.clone();
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(socket, peer_addr, proxy_types, server_addr, noise_config, protocol_id, controller_clone, config).await {
+            let task = tokio::spawn(async move {
+                if let Err(e) = handle_connection(socket, peer_addr, proxy_types, server_addr, upstreams, chain, capture, noise_config, protocol_id, controller_clone, config, kill_switch, experiment).await {
                     log::error!("TCP connection error from {}: {}", peer_addr, e);
                 }
             });
+            if let Some(ref kill_switch) = self.kill_switch {
+                kill_switch.track(task.abort_handle());
+            }
         }
     }
 }
@@ -179,10 +314,15 @@ async fn handle_connection(
     peer_addr: SocketAddr,
     supported_types: Vec<ProxyType>,
     server_addr: Option<SocketAddr>,
+    upstreams: Option<Arc<crate::upstream::UpstreamPool>>,
+    chain: Option<Arc<crate::chain::RelayChain>>,
+    capture: Option<crate::capture::PacketCapture>,
     noise_config: Option<crate::noise_transport::NoiseConfig>,
     protocol_id: crate::ProtocolId,
     controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
     config: Arc<crate::NooshdarooConfig>,
+    kill_switch: Option<Arc<crate::kill_switch::KillSwitch>>,
+    experiment: Option<Arc<crate::experiment::Experiment>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Peek at first bytes to detect protocol
     let mut buf = BytesMut::with_capacity(4096);
@@ -213,7 +353,7 @@ async fn handle_connection(
     log::debug!("Detected {:?} proxy from {}", proxy_type, peer_addr);
 
     match proxy_type {
-        ProxyType::Socks5 => handle_socks5(socket, buf, peer_addr, server_addr, noise_config, protocol_id, controller, config).await,
+        ProxyType::Socks5 => handle_socks5(socket, buf, peer_addr, server_addr, upstreams, chain, capture, noise_config, protocol_id, controller, config, kill_switch, experiment).await,
         ProxyType::Http => handle_http(socket, buf, peer_addr).await,
         ProxyType::Transparent => handle_transparent(socket, buf, peer_addr).await,
     }
@@ -229,8 +369,8 @@ fn detect_proxy_type(
     }
 
     // SOCKS5: First byte is 0x05 (version)
-    // Only need 1 byte to detect SOCKS5
-    if buf[0] == 0x05 && supported.contains(&ProxyType::Socks5) {
+    // Only need 1 byte to detect SOCKS5; SOCKS4/4a (0x04) share its handler
+    if (buf[0] == 0x05 || buf[0] == 0x04) && supported.contains(&ProxyType::Socks5) {
         return Ok(ProxyType::Socks5);
     }
 
@@ -255,236 +395,185 @@ fn detect_proxy_type(
     Err("Unable to detect supported proxy protocol".into())
 }
 
-/// Handle SOCKS5 proxy connection with complete RFC 1928 implementation
+/// Handle SOCKS5 proxy connection with complete RFC 1928 implementation,
+/// also accepting SOCKS4 and SOCKS4a clients
 async fn handle_socks5(
     socket: TcpStream,
     buf: BytesMut,
     peer_addr: SocketAddr,
     server_addr: Option<SocketAddr>,
+    upstreams: Option<Arc<crate::upstream::UpstreamPool>>,
+    chain: Option<Arc<crate::chain::RelayChain>>,
+    capture: Option<crate::capture::PacketCapture>,
     noise_config: Option<crate::noise_transport::NoiseConfig>,
     protocol_id: crate::ProtocolId,
     controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
     config: Arc<crate::NooshdarooConfig>,
+    kill_switch: Option<Arc<crate::kill_switch::KillSwitch>>,
+    experiment: Option<Arc<crate::experiment::Experiment>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    use crate::socks5::{socks5_handshake, connect_target, send_reply, copy_bidirectional, Command, ReplyCode, PrefixedStream};
-    use crate::noise_transport::NoiseTransport;
-    use crate::protocol_wrapper::ProtocolWrapper;
+    use crate::socks5::{socks4_handshake, socks5_handshake, send_versioned_reply, Command, ReplyCode, PrefixedStream, SOCKS4_VERSION};
 
-    log::debug!("SOCKS5 connection from {}", peer_addr);
+    // SOCKS4 and SOCKS4a clients take the same path, only their handshake
+    // and replies differ
+    let version = buf[0];
+    log::debug!("SOCKS{} connection from {}", version, peer_addr);
 
     // Wrap socket with already-read data
     let mut socket = PrefixedStream::new(socket, buf);
 
-    // Perform complete SOCKS5 handshake
-    let (command, target) = match socks5_handshake(&mut socket).await {
+    // Perform complete SOCKS handshake
+    let handshake = if version == SOCKS4_VERSION {
+        socks4_handshake(&mut socket).await
+    } else {
+        socks5_handshake(&mut socket).await
+    };
+    let (command, target) = match handshake {
         Ok(result) => result,
         Err(e) => {
-            log::error!("SOCKS5 handshake failed from {}: {}", peer_addr, e);
+            log::error!("SOCKS{} handshake failed from {}: {}", version, peer_addr, e);
             return Err(e.into());
         }
     };
 
-    log::info!("SOCKS5 {:?} request to {}:{} from {}", command, target.host, target.port, peer_addr);
+    log::info!("SOCKS{} {:?} request to {}:{} from {}", version, command, target.host, target.port, peer_addr);
 
     match command {
         Command::Connect => {
-            // Check if we should tunnel through server or connect directly
-            if let (Some(server_addr), Some(noise_config)) = (server_addr, noise_config) {
-                // TUNNEL MODE: Connect to server via Noise encryption
-                log::info!("Tunneling to {}:{} via server {}", target.host, target.port, server_addr);
-
-                // Connect to server - use DNS tunnel if protocol is dns-udp-tunnel
-                let mut server_stream = if protocol_id.as_str() == "dns-udp-tunnel"
-                    || protocol_id.as_str() == "dns_udp_tunnel"
-                    || protocol_id.as_str() == "dnsudptunnel" {
-                    // DNS UDP Tunnel mode
-                    match DnsTransportClient::connect(server_addr).await {
-                        Ok(dns_client) => {
-                            log::info!("DNS UDP tunnel connected to {}", server_addr);
-
-                            // Wrap DNS stream with KCP reliability layer
-                            let dns_stream = DnsStream::new(dns_client);
-                            let session_id = rand::random::<u32>();
-
-                            match crate::reliable_transport::ReliableTransport::new(
-                                dns_stream,
-                                session_id,
-                                600  // MTU matching DNS fragment size
-                            ) {
-                                Ok(kcp_stream) => {
-                                    log::info!("KCP reliability layer initialized (session_id: {})", session_id);
-                                    ServerStream::DnsWithKcp(kcp_stream)
-                                }
-                                Err(e) => {
-                                    log::error!("Failed to initialize KCP: {}", e);
-                                    send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                                    return Err(e.into());
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("Failed to connect DNS tunnel to {}: {}", server_addr, e);
-                            send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                            return Err(e.into());
-                        }
-                    }
-                } else {
-                    // TCP mode (HTTPS, HTTP, etc.)
-                    match TcpStream::connect(server_addr).await {
-                        Ok(stream) => {
-                            // Enable TCP_NODELAY for low latency (critical for HTTP/2)
-                            stream.set_nodelay(true)?;
-                            log::debug!("TCP connected to server {}", server_addr);
-                            ServerStream::Tcp(stream)
-                        }
-                        Err(e) => {
-                            log::error!("Failed to connect to server {}: {}", server_addr, e);
-                            send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                            return Err(e.into());
-                        }
-                    }
-                };
-
-                // Create protocol wrapper for handshake wrapping
-                // NOTE: DNS protocol doesn't need wrapper - DNS format IS the protocol wrapping
-                let is_dns = protocol_id.as_str() == "dns-udp-tunnel"
-                    || protocol_id.as_str() == "dns_udp_tunnel"
-                    || protocol_id.as_str() == "dnsudptunnel";
-
-                let mut protocol_wrapper = if !is_dns {
-                    Some(ProtocolWrapper::new(protocol_id.clone(), crate::WrapperRole::Client, None))
-                } else {
-                    None
-                };
-                log::debug!("Using protocol: {} (wrapper: {})", protocol_id.as_str(), protocol_wrapper.is_some());
-
-                // Perform Noise handshake with protocol wrapping (if applicable)
-                let (mut noise_transport, use_tls_emulation) = match NoiseTransport::client_handshake(&mut server_stream, &noise_config, protocol_wrapper.as_mut()).await {
-                    Ok(mut transport) => {
-                        log::debug!("Noise handshake completed with server using {}", protocol_id.as_str());
-
-                        // Enable TLS session emulation if configured AND protocol is TLS-based
-                        let is_tls_protocol = protocol_id.as_str().starts_with("https") ||
-                                              protocol_id.as_str().starts_with("tls") ||
-                                              protocol_id.as_str() == "dns" || // DNS over TLS
-                                              protocol_id.as_str() == "dns-google";
-
-                        // Check if we should enable TLS session emulation
-                        let use_tls_emulation = config.detection.enable_tls_session_emulation && is_tls_protocol;
-                        if use_tls_emulation {
-                            transport.enable_tls_wrapping();
-                            log::info!("Full TLS session emulation enabled for protocol: {}", protocol_id.as_str());
-                        }
+            if !crate::acl::AccessPolicy::new(&config.access)?.allows(&target.host, target.port) {
+                log::warn!("Access rules deny {}:{} requested by {}", target.host, target.port, peer_addr);
+                send_versioned_reply(&mut socket, version, ReplyCode::NotAllowed, &target).await?;
+                return Err(format!("Connection to {}:{} not allowed", target.host, target.port).into());
+            }
 
-                        (transport, use_tls_emulation)
-                    }
-                    Err(e) => {
-                        log::error!("Noise handshake failed: {}", e);
-                        send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                        return Err(e.into());
+            // Check if we should tunnel through server or connect directly
+            let has_server = (server_addr.is_some() || upstreams.is_some()) && noise_config.is_some();
+            if let (true, Some(base_noise_config)) = (has_server, noise_config) {
+                // Names mapped in [hosts] reach the server as their address
+                let host = match crate::hosts::lookup(&config.hosts, &target.host) {
+                    Some(addrs) => {
+                        log::debug!("Mapping {} to {} from [hosts]", target.host, addrs[0]);
+                        addrs[0].to_string()
                     }
+                    None => target.host.clone(),
                 };
-
                 // Send target info to server through encrypted tunnel
                 // IPv6 addresses must be wrapped in brackets: [2a00:800::1]:80
-                let target_info = if target.host.contains(':') {
+                let target_info = if host.contains(':') {
                     // IPv6 address - wrap in brackets
-                    format!("[{}]:{}", target.host, target.port)
+                    format!("[{}]:{}", host, target.port)
                 } else {
                     // IPv4 or hostname
-                    format!("{}:{}", target.host, target.port)
+                    format!("{}:{}", host, target.port)
                 };
-                // Use write_raw() for DNS (no length prefix needed for UDP)
-                let write_result = if is_dns {
-                    noise_transport.write_raw(&mut server_stream, target_info.as_bytes()).await
-                } else {
-                    noise_transport.write(&mut server_stream, target_info.as_bytes()).await
+
+                let route = TunnelRoute {
+                    server_addr,
+                    upstreams: upstreams.as_ref(),
+                    chain: chain.as_ref(),
+                    capture: capture.as_ref(),
+                    noise_config: &base_noise_config,
+                    protocol_id: &protocol_id,
+                    config: &config,
+                    controller: controller.as_ref(),
+                    kill_switch: kill_switch.as_ref(),
+                    experiment: experiment.as_ref(),
+                    target_info,
                 };
-                match write_result {
-                    Ok(_) => {
-                        log::debug!("Sent target info to server: {}", target_info);
+                let mut tried: Vec<SocketAddr> = Vec::new();
+
+                // Fail over to the next-best upstream server while the SOCKS
+                // client is still waiting for our reply
+                let (mut tunnel, mut _lease, mut tunnel_server) = match route.establish(&mut tried, None).await {
+                    Ok(established) => established,
+                    Err(TunnelSetupError::Rejected(reply, message)) => {
+                        log::error!("Server returned error: {}", message);
+                        send_versioned_reply(&mut socket, version, reply, &target).await?;
+                        return Err(format!("Server error: {}", message).into());
                     }
-                    Err(e) => {
-                        log::error!("Failed to send target info: {}", e);
-                        send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                        return Err(e.into());
+                    Err(TunnelSetupError::Upstream(message)) | Err(TunnelSetupError::Handshake(message)) => {
+                        send_versioned_reply(&mut socket, version, ReplyCode::GeneralFailure, &target).await?;
+                        return Err(message.into());
                     }
-                }
+                };
+
+                // Send success reply to SOCKS5 client
+                send_versioned_reply(&mut socket, version, ReplyCode::Succeeded, &target).await?;
+                log::info!("Tunnel established to {}:{} via server", target.host, target.port);
 
-                // Wait for server's connection confirmation
-                // Use read_raw() for DNS (no length prefix), read() for TCP
-                let response = if is_dns {
-                    match noise_transport.read_raw(&mut server_stream).await {
-                        Ok(data) => data,
+                // Chain hops keep the protocol they were configured with;
+                // rotation would switch the exit tunnel to the client's protocol
+                let relay_controller = if chain.is_some() { None } else { controller.clone() };
+                let mut client = crate::upstream::HandoffStream::new(socket);
+                let relay_started = std::time::Instant::now();
+                let (mut bytes_sent, mut bytes_received) = (0, 0);
+                let mut handoffs = 0;
+                loop {
+                    // Relay data bidirectionally through encrypted tunnel
+                    log::debug!("Starting encrypted relay for {}:{}", target.host, target.port);
+
+                    let mut shaping = crate::traffic::RelayShaping::new(&config, tunnel.protocol.as_str());
+                    if let Some(ref ctrl) = controller {
+                        shaping = shaping.with_counters(ctrl.read().await.traffic().connection(&tunnel.protocol));
+                    }
+                    shaping.counters.record_rtt(tunnel.handshake_rtt);
+                    let counters = shaping.counters.clone();
+
+                    let relay_failed = match relay_tunnel(&mut client, tunnel, shaping, &config, relay_controller.clone()).await {
                         Err(e) => {
-                            log::error!("Failed to receive server response (DNS): {}", e);
-                            send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                            return Err(e.into());
+                            log::debug!("Tunnel relay ended for {}:{}: {}", target.host, target.port, e);
+                            true
                         }
+                        Ok(()) => {
+                            log::debug!("Tunnel relay completed successfully for {}:{}", target.host, target.port);
+                            false
+                        }
+                    };
+                    let traffic = counters.snapshot();
+                    bytes_sent += traffic.bytes_sent;
+                    bytes_received += traffic.bytes_received;
+
+                    // A tunnel that ended before the server answered moves to
+                    // the next-best server, replaying what the client sent
+                    let Some(pool) = upstreams.as_ref() else { break };
+                    if handoffs >= MAX_HANDOFFS || !client.hand_off(relay_failed) {
+                        break;
                     }
-                } else {
-                    match noise_transport.read(&mut server_stream).await {
-                        Ok(data) => data,
+                    pool.record_failure(tunnel_server);
+                    tried.push(tunnel_server);
+                    let Some(next) = pool.select_excluding(&protocol_id, &tried) else {
+                        log::warn!("Tunnel to {} ended before answering and no other server is left", tunnel_server);
+                        break;
+                    };
+                    pool.record_failover(tunnel_server, next.addr(), "tunnel ended before the server answered");
+                    if let Some(ref controller) = controller {
+                        controller.write().await.record_failover();
+                    }
+                    match route.establish(&mut tried, Some(next)).await {
+                        Ok((next_tunnel, next_lease, next_server)) => {
+                            tunnel = next_tunnel;
+                            _lease = next_lease;
+                            tunnel_server = next_server;
+                            handoffs += 1;
+                        }
                         Err(e) => {
-                            log::error!("Failed to receive server response: {}", e);
-                            send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
-                            return Err(e.into());
+                            log::warn!("Handing off {}:{} failed: {}", target.host, target.port, setup_error_message(e));
+                            break;
                         }
                     }
-                };
-
-                let response_str = String::from_utf8_lossy(&response);
-                if response_str != "OK" {
-                    log::error!("Server returned error: {}", response_str);
-                    let reply = if response_str.contains("refused") {
-                        ReplyCode::ConnectionRefused
-                    } else if response_str.contains("unreachable") {
-                        ReplyCode::HostUnreachable
-                    } else {
-                        ReplyCode::GeneralFailure
-                    };
-                    send_reply(&mut socket, reply, &target).await?;
-                    return Err(format!("Server error: {}", response_str).into());
                 }
 
-                // Send success reply to SOCKS5 client
-                send_reply(&mut socket, ReplyCode::Succeeded, &target).await?;
-                log::info!("Tunnel established to {}:{} via server", target.host, target.port);
-
-                // Relay data bidirectionally through encrypted tunnel
-                log::debug!("Starting encrypted relay for {}:{}", target.host, target.port);
-
-                // DNS uses UDP (no length prefix), TLS emulation uses built-in wrapping
-                if is_dns {
-                    // Use DNS-specific relay (no length prefix for UDP)
-                    log::debug!("Using DNS transport layer (UDP, no length prefix)");
-                    if let Err(e) = relay_dns_tunnel(socket, server_stream, noise_transport).await {
-                        log::debug!("Tunnel relay ended for {}:{}: {}", target.host, target.port, e);
-                    } else {
-                        log::debug!("Tunnel relay completed successfully for {}:{}", target.host, target.port);
-                    }
-                } else if use_tls_emulation {
-                    // Use NoiseTransport's built-in TLS wrapping (no protocol wrapper)
-                    log::debug!("Using TLS session emulation (no protocol wrapper)");
-                    if let Err(e) = relay_with_noise_only(socket, server_stream, noise_transport).await {
-                        log::debug!("Tunnel relay ended for {}:{}: {}", target.host, target.port, e);
-                    } else {
-                        log::debug!("Tunnel relay completed successfully for {}:{}", target.host, target.port);
-                    }
-                } else {
-                    // Use protocol wrapper for obfuscation
-                    let wrapper = crate::ProtocolWrapper::new(protocol_id.clone(), crate::WrapperRole::Client, None);
-                    log::debug!("Created {} protocol wrapper for traffic obfuscation", protocol_id.as_str());
-                    if let Err(e) = relay_through_noise_tunnel(socket, server_stream, noise_transport, wrapper, controller).await {
-                        log::debug!("Tunnel relay ended for {}:{}: {}", target.host, target.port, e);
-                    } else {
-                        log::debug!("Tunnel relay completed successfully for {}:{}", target.host, target.port);
-                    }
+                log::info!(
+                    "Connection to {}:{} closed: {} bytes sent, {} bytes received",
+                    target.host, target.port, bytes_sent, bytes_received
+                );
+                if let Some(ref experiment) = experiment {
+                    experiment.record_transfer(&protocol_id, bytes_sent + bytes_received, relay_started.elapsed());
                 }
             } else {
                 // NO SERVER CONFIGURED: Refuse connection for security
                 log::error!("No server configured - refusing direct connection to {}:{} for security", target.host, target.port);
-                send_reply(&mut socket, ReplyCode::NotAllowed, &target).await?;
+                send_versioned_reply(&mut socket, version, ReplyCode::NotAllowed, &target).await?;
                 return Err("Direct connections not allowed - server configuration required".into());
             }
             /*
@@ -493,11 +582,11 @@ async fn handle_socks5(
             } else {
                 let mut target_stream = match connect_target(&target).await {
                     Ok(stream) => {
-                        send_reply(&mut socket, ReplyCode::Succeeded, &target).await?;
+                        send_versioned_reply(&mut socket, version, ReplyCode::Succeeded, &target).await?;
                         stream
                     }
                     Err(e) => {
-                        send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
+                        send_versioned_reply(&mut socket, version, ReplyCode::GeneralFailure, &target).await?;
                         return Err(e.into());
                     }
                 };
@@ -510,18 +599,1051 @@ async fn handle_socks5(
             }
             */
         }
-        Command::Bind => {
-            log::warn!("SOCKS5 BIND command not supported");
-            send_reply(&mut socket, ReplyCode::CommandNotSupported, &target).await?;
+        Command::Bind => {
+            log::warn!("SOCKS5 BIND command not supported");
+            send_versioned_reply(&mut socket, version, ReplyCode::CommandNotSupported, &target).await?;
+        }
+        Command::UdpAssociate => {
+            log::info!("[UDP] SOCKS5 UDP ASSOCIATE request received for target {}:{}", target.host, target.port);
+            log::warn!("[UDP] SOCKS5 UDP ASSOCIATE command not yet integrated - DNS queries will fail");
+            send_versioned_reply(&mut socket, version, ReplyCode::CommandNotSupported, &target).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Times a proxied stream is handed to another server before giving up
+const MAX_HANDOFFS: usize = 2;
+
+/// Everything a SOCKS connection needs to set up its tunnel, possibly more
+/// than once when it is handed off to another server
+struct TunnelRoute<'a> {
+    server_addr: Option<SocketAddr>,
+    upstreams: Option<&'a Arc<crate::upstream::UpstreamPool>>,
+    chain: Option<&'a Arc<crate::chain::RelayChain>>,
+    capture: Option<&'a crate::capture::PacketCapture>,
+    noise_config: &'a NoiseConfig,
+    protocol_id: &'a crate::ProtocolId,
+    config: &'a Arc<NooshdarooConfig>,
+    controller: Option<&'a Arc<RwLock<crate::ShapeShiftController>>>,
+    kill_switch: Option<&'a Arc<crate::kill_switch::KillSwitch>>,
+    experiment: Option<&'a Arc<crate::experiment::Experiment>>,
+    target_info: String,
+}
+
+impl TunnelRoute<'_> {
+    /// Open a tunnel to the target, starting with `next` if given and
+    /// failing over to the next-best upstream server; servers in `tried` are
+    /// skipped, and every server that fails is added to it
+    async fn establish(
+        &self,
+        tried: &mut Vec<SocketAddr>,
+        mut next: Option<crate::upstream::UpstreamLease>,
+    ) -> Result<(EstablishedTunnel, Option<crate::upstream::UpstreamLease>, SocketAddr), TunnelSetupError> {
+        let protocol_id = self.protocol_id;
+        let setup_timeout = Duration::from_secs(self.config.socks.connect_timeout_secs.max(1));
+        loop {
+            let lease = match self.upstreams {
+                Some(pool) => match next.take().or_else(|| pool.select_excluding(protocol_id, tried)) {
+                    Some(lease) => Some(lease),
+                    None => {
+                        log::error!("No upstream server available for {}", self.target_info);
+                        return Err(TunnelSetupError::Upstream("All upstream servers failed".to_string()));
+                    }
+                },
+                None => None,
+            };
+            let (server_addr, noise_config) = match (&lease, self.server_addr) {
+                (Some(lease), _) => (lease.addr(), lease.server().noise_config(self.noise_config)),
+                (None, Some(addr)) => (addr, self.noise_config.clone()),
+                (None, None) => unreachable!("has_server implies a server address"),
+            };
+
+            // TUNNEL MODE: Connect to server via Noise encryption
+            let timeout = match self.chain {
+                Some(chain) => {
+                    log::info!("Tunneling to {} via relay chain {}", self.target_info, chain);
+                    // Every hop adds a connection and a handshake
+                    setup_timeout * chain.hops.len() as u32
+                }
+                None => {
+                    log::info!("Tunneling to {} via server {}", self.target_info, server_addr);
+                    setup_timeout
+                }
+            };
+            let setup = async {
+                match self.chain {
+                    Some(chain) => establish_chain(server_addr, chain, &noise_config, protocol_id, self.config, &self.target_info, self.capture).await,
+                    None => establish_tunnel(server_addr, &noise_config, protocol_id, self.config, &self.target_info, false, self.capture).await,
+                }
+            };
+
+            let attempt_started = std::time::SystemTime::now();
+            let result = match tokio::time::timeout(timeout, setup).await {
+                Ok(result) => result,
+                Err(_) => Err(TunnelSetupError::Upstream(format!(
+                    "tunnel setup timed out after {:?}", timeout
+                ))),
+            };
+            match result {
+                Ok(ref tunnel) => crate::measurement::record_tunnel(protocol_id, attempt_started, Ok(tunnel.handshake_rtt)),
+                Err(TunnelSetupError::Upstream(ref message)) | Err(TunnelSetupError::Handshake(ref message)) => {
+                    crate::measurement::record_tunnel(protocol_id, attempt_started, Err(message))
+                }
+                // The tunnel came up but the target did not, which says
+                // nothing about the protocol
+                Err(TunnelSetupError::Rejected(..)) => {}
+            }
+
+            match result {
+                Ok(tunnel) => {
+                    if let Some(pool) = self.upstreams {
+                        pool.record_success(server_addr);
+                    }
+                    if let Some(kill_switch) = self.kill_switch {
+                        kill_switch.record_tunnel_up().await;
+                    }
+                    if let Some(experiment) = self.experiment {
+                        experiment.record_success(protocol_id, tunnel.handshake_rtt);
+                    }
+                    return Ok((tunnel, lease, server_addr));
+                }
+                Err(TunnelSetupError::Rejected(reply, message)) => return Err(TunnelSetupError::Rejected(reply, message)),
+                Err(TunnelSetupError::Upstream(message)) | Err(TunnelSetupError::Handshake(message)) => {
+                    log::error!("Tunnel to server {} failed: {}", server_addr, message);
+                    tried.push(server_addr);
+                    if let Some(controller) = self.controller {
+                        controller.read().await.record_handshake_failure(protocol_id);
+                    }
+
+                    // The server picked here is the one tried next
+                    next = self.upstreams.and_then(|pool| {
+                        pool.record_failure(server_addr);
+                        pool.select_excluding(protocol_id, tried)
+                    });
+
+                    match (self.upstreams, &next) {
+                        (Some(pool), Some(next)) => {
+                            pool.record_failover(server_addr, next.addr(), message);
+                            if let Some(controller) = self.controller {
+                                controller.write().await.record_failover();
+                            }
+                        }
+                        _ => {
+                            if let Some(controller) = self.controller {
+                                controller.read().await.events().emit(crate::events::ClientEvent::TunnelDown {
+                                    server: server_addr,
+                                    protocol: protocol_id.clone(),
+                                    reason: message.clone(),
+                                });
+                            }
+                            if let Some(kill_switch) = self.kill_switch {
+                                kill_switch.record_tunnel_down().await;
+                            }
+                            if let Some(experiment) = self.experiment {
+                                experiment.record_failure(protocol_id);
+                            }
+                            return Err(TunnelSetupError::Upstream(message));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A connected, handshaken tunnel to the server with the target accepted
+struct EstablishedTunnel {
+    stream: ServerStream,
+    noise: NoiseTransport,
+    protocol: crate::ProtocolId,
+    use_tls_emulation: bool,
+    is_dns: bool,
+    /// The server agreed to protocol hops
+    hopping: bool,
+    capture_flow: Option<crate::capture::CaptureFlow>,
+    /// Duration of the Noise handshake (one round trip)
+    handshake_rtt: Duration,
+}
+
+/// Why a tunnel could not be set up
+enum TunnelSetupError {
+    /// Server unreachable, timed out, or handshake failed - another server may work
+    Upstream(String),
+    /// Server is healthy but could not reach the target
+    Rejected(crate::socks5::ReplyCode, String),
+    /// Noise handshake failed - the server may be using another pinned key
+    Handshake(String),
+}
+
+/// Open a tunnel to `server_addr`, trying each currently valid pinned server
+/// key in turn until one completes the handshake
+async fn establish_tunnel(
+    server_addr: SocketAddr,
+    noise_config: &NoiseConfig,
+    protocol_id: &crate::ProtocolId,
+    config: &NooshdarooConfig,
+    target_info: &str,
+    routed: bool,
+    capture: Option<&crate::capture::PacketCapture>,
+) -> Result<EstablishedTunnel, TunnelSetupError> {
+    let keys = noise_config.candidate_remote_keys(std::time::SystemTime::now());
+    if keys.len() <= 1 {
+        return establish_tunnel_once(server_addr, noise_config, protocol_id, config, target_info, routed, capture).await;
+    }
+
+    let mut last_error = None;
+    for key in &keys {
+        let keyed = noise_config.with_remote_key(key);
+        match establish_tunnel_once(server_addr, &keyed, protocol_id, config, target_info, routed, capture).await {
+            Err(TunnelSetupError::Handshake(message)) => {
+                log::warn!("Handshake with {} failed using pinned key {}: {}", server_addr, key, message);
+                last_error = Some(message);
+            }
+            result => return result,
+        }
+    }
+    Err(TunnelSetupError::Handshake(format!(
+        "{} (tried {} pinned keys)",
+        last_error.unwrap_or_default(),
+        keys.len()
+    )))
+}
+
+/// Open a tunnel through every hop of `chain`, entering it at `entry_addr`,
+/// and ask the exit hop to open `target_info`
+///
+/// Each hop's tunnel runs inside the previous one: a relay task carries it
+/// through the outer tunnel the same way client connections are carried, so
+/// a hop only ever sees the encrypted session to the next one.
+async fn establish_chain(
+    entry_addr: SocketAddr,
+    chain: &crate::chain::RelayChain,
+    noise_config: &NoiseConfig,
+    protocol_id: &crate::ProtocolId,
+    config: &Arc<NooshdarooConfig>,
+    target_info: &str,
+    capture: Option<&crate::capture::PacketCapture>,
+) -> Result<EstablishedTunnel, TunnelSetupError> {
+    let entry = chain.entry();
+    let mut tunnel = establish_tunnel(
+        entry_addr,
+        &entry.noise_config(noise_config),
+        &entry.protocol_or(protocol_id),
+        config,
+        &chain.hops[1].address,
+        true,
+        capture,
+    )
+    .await?;
+
+    for (i, hop) in chain.hops.iter().enumerate().skip(1) {
+        let target = chain.hops.get(i + 1).map_or(target_info, |next| next.address.as_str());
+        let (near, far) = tokio::io::duplex(CHAIN_HOP_BUFFER);
+        let outer = tunnel;
+        let shaping = crate::traffic::RelayShaping::new(config, outer.protocol.as_str());
+        let relay_config = Arc::clone(config);
+        let previous = chain.hops[i - 1].to_string();
+        tokio::spawn(async move {
+            if let Err(e) = relay_tunnel(far, outer, shaping, &relay_config, None).await {
+                log::debug!("Relay through hop {} ended: {}", previous, e);
+            }
+        });
+
+        log::debug!("Opening tunnel to hop {} inside the tunnel to {}", hop, chain.hops[i - 1]);
+        tunnel = open_tunnel(
+            ServerStream::Hop(near),
+            None,
+            &hop.noise_config(noise_config),
+            &hop.protocol_or(protocol_id),
+            config,
+            target,
+            true,
+        )
+        .await?;
+    }
+    Ok(tunnel)
+}
+
+/// Buffer between a chain hop's tunnel and the tunnel carrying it
+const CHAIN_HOP_BUFFER: usize = 64 * 1024;
+
+/// Buffer between a [`crate::NooshdarooStream`] and its tunnel
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// Open a tunnel to `target_info` through `server_addr` (or `chain`, entered
+/// there) and relay it, wrapped and shaped like a SOCKS5 connection, into
+/// the returned end of an in-memory pipe
+///
+/// The relay ends when the returned end is dropped.
+pub(crate) async fn open_stream(
+    server_addr: SocketAddr,
+    chain: Option<&crate::chain::RelayChain>,
+    noise_config: &NoiseConfig,
+    protocol_id: &crate::ProtocolId,
+    config: &Arc<NooshdarooConfig>,
+    controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
+    target_info: &str,
+) -> Result<tokio::io::DuplexStream, String> {
+    let setup_timeout = Duration::from_secs(config.socks.connect_timeout_secs.max(1));
+    // Every chain hop adds a connection and a handshake
+    let timeout = setup_timeout * chain.map_or(1, |chain| chain.hops.len() as u32);
+    let setup = async {
+        match chain {
+            Some(chain) => establish_chain(server_addr, chain, noise_config, protocol_id, config, target_info, None).await,
+            None => establish_tunnel(server_addr, noise_config, protocol_id, config, target_info, false, None).await,
+        }
+    };
+    let error = match tokio::time::timeout(timeout, setup).await {
+        Ok(Ok(tunnel)) => Ok(tunnel),
+        // A target the server could not reach says nothing about the protocol
+        Ok(Err(TunnelSetupError::Rejected(_, message))) => return Err(message),
+        Ok(Err(e)) => Err(setup_error_message(e)),
+        Err(_) => Err(format!("tunnel setup timed out after {:?}", timeout)),
+    };
+    let tunnel = match error {
+        Ok(tunnel) => tunnel,
+        Err(reason) => {
+            if let Some(ref controller) = controller {
+                let controller = controller.read().await;
+                controller.record_handshake_failure(protocol_id);
+                controller.events().emit(crate::events::ClientEvent::TunnelDown {
+                    server: server_addr,
+                    protocol: protocol_id.clone(),
+                    reason: reason.clone(),
+                });
+            }
+            return Err(reason);
+        }
+    };
+
+    let mut shaping = crate::traffic::RelayShaping::new(config, tunnel.protocol.as_str());
+    if let Some(ref controller) = controller {
+        shaping = shaping.with_counters(controller.read().await.traffic().connection(&tunnel.protocol));
+    }
+    shaping.counters.record_rtt(tunnel.handshake_rtt);
+
+    let (near, far) = tokio::io::duplex(STREAM_BUFFER);
+    // Chain hops keep the protocol they were configured with
+    let controller = if chain.is_some() { None } else { controller };
+    let (relay_config, target) = (Arc::clone(config), target_info.to_string());
+    tokio::spawn(async move {
+        if let Err(e) = relay_tunnel(far, tunnel, shaping, &relay_config, controller).await {
+            log::debug!("Stream relay to {} ended: {}", target, e);
+        }
+    });
+    Ok(near)
+}
+
+/// Connect to `server_addr`, perform the Noise handshake, and ask the server
+/// to open `target_info`
+async fn establish_tunnel_once(
+    server_addr: SocketAddr,
+    noise_config: &NoiseConfig,
+    protocol_id: &crate::ProtocolId,
+    config: &NooshdarooConfig,
+    target_info: &str,
+    routed: bool,
+    capture: Option<&crate::capture::PacketCapture>,
+) -> Result<EstablishedTunnel, TunnelSetupError> {
+    let server_stream = connect_server(server_addr, protocol_id, config, capture).await?;
+    open_tunnel(server_stream, Some(server_addr), noise_config, protocol_id, config, target_info, routed).await
+}
+
+/// Connect to `server_addr` with the transport `protocol_id` runs over
+async fn connect_server(
+    server_addr: SocketAddr,
+    protocol_id: &crate::ProtocolId,
+    config: &NooshdarooConfig,
+    capture: Option<&crate::capture::PacketCapture>,
+) -> Result<ServerStream, TunnelSetupError> {
+    let is_dns = crate::chain::is_udp_protocol(protocol_id);
+
+    // Connect to server - use DNS tunnel if protocol is dns-udp-tunnel
+    let server_stream = if is_dns {
+        // DNS UDP Tunnel mode
+        let dns_client = if config.relay.port_hopping.enabled {
+            let schedule = crate::port_hop::PortHopSchedule::new(&config.relay.port_hopping).map_err(TunnelSetupError::Upstream)?;
+            DnsTransportClient::connect_hopping(server_addr, schedule).await
+        } else {
+            DnsTransportClient::connect(server_addr).await
+        }
+        .map_err(|e| {
+            TunnelSetupError::Upstream(format!("Failed to connect DNS tunnel to {}: {}", server_addr, e))
+        })?;
+        let dns_client = dns_client.with_nat_keepalive(crate::nat_keepalive::NatKeepalive::for_server(
+            server_addr,
+            &config.relay.nat_keepalive,
+        ))
+        .with_fec(&config.relay.fec);
+        log::info!("DNS UDP tunnel connected to {}", server_addr);
+
+        // Wrap DNS stream with KCP reliability layer
+        let dns_stream = DnsStream::new(dns_client);
+        let session_id = rand::random::<u32>();
+
+        let kcp_stream = crate::reliable_transport::ReliableTransport::new(
+            dns_stream,
+            session_id,
+            600  // MTU matching DNS fragment size
+        ).map_err(|e| TunnelSetupError::Upstream(format!("Failed to initialize KCP: {}", e)))?;
+        log::info!("KCP reliability layer initialized (session_id: {})", session_id);
+        ServerStream::DnsWithKcp(kcp_stream)
+    } else if config.socks.transport == crate::config::TransportType::Kcp {
+        let game_profile = config.relay.game_netcode.as_deref().and_then(crate::app_profiles::ApplicationProfile::get);
+        let turn = config.socks.turn.as_ref();
+        let stream = match (game_profile, config.relay.voip_codec) {
+            (Some(profile), _) => crate::game_netcode::connect(server_addr, &profile, turn).await,
+            (None, Some(codec)) if config.relay.sip => {
+                let domain = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                crate::sip::call(server_addr, &domain, codec, turn).await
+            }
+            (None, Some(codec)) => crate::rtp_voip::connect(server_addr, codec, turn).await,
+            (None, None) if config.relay.wireguard => crate::wireguard::connect(server_addr, turn).await,
+            (None, None) if config.relay.coap => crate::coap::connect(server_addr, turn).await,
+            (None, None) if config.relay.openvpn => {
+                let server_name = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                crate::openvpn::connect_kcp(server_addr, &server_name, turn).await
+            }
+            (None, None) => match turn {
+                Some(turn) => crate::turn_relay::connect(server_addr, turn).await,
+                None => crate::kcp_transport::connect(server_addr).await,
+            },
+        }
+        .map_err(|e| TunnelSetupError::Upstream(format!("Failed to open KCP session to {}: {}", server_addr, e)))?;
+        log::debug!("KCP session opened to {}", server_addr);
+        ServerStream::Kcp(stream)
+    } else {
+        // TCP mode (HTTPS, HTTP, etc.)
+        let stream = crate::upstream_proxy::connect_server(&config.socks, server_addr, &config.traffic_shaping)
+            .await
+            .map_err(|e| TunnelSetupError::Upstream(format!("Failed to connect to server {}: {}", server_addr, e)))?;
+        // Enable TCP_NODELAY for low latency (critical for HTTP/2)
+        stream.set_nodelay(true).map_err(|e| TunnelSetupError::Upstream(e.to_string()))?;
+        log::debug!("TCP connected to server {}", server_addr);
+        let local = stream.local_addr().map_err(|e| TunnelSetupError::Upstream(e.to_string()))?;
+        let mut stream = match capture {
+            Some(capture) => {
+                let flow = capture.flow(local, server_addr);
+                ServerStream::Captured(crate::capture::CaptureStream::new(stream, flow))
+            }
+            None => ServerStream::Tcp(stream),
+        };
+        match config.socks.transport {
+            crate::config::TransportType::HttpChunked => {
+                let host = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::http_chunked::connect(stream, &host).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("Failed to start HTTP stream to {}: {}", server_addr, e))
+                })?;
+                ServerStream::Http(stream)
+            }
+            crate::config::TransportType::Smtp => {
+                let helo_name = crate::smtp_transport::address_literal(local.ip());
+                crate::smtp_transport::connect(&mut stream, &helo_name).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("SMTP conversation with {} failed: {}", server_addr, e))
+                })?;
+                stream
+            }
+            crate::config::TransportType::Rdp => {
+                crate::rdp_transport::connect(&mut stream, config.socks.rdp_username.as_deref()).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("RDP negotiation with {} failed: {}", server_addr, e))
+                })?;
+                stream
+            }
+            crate::config::TransportType::Bittorrent => {
+                let stream = crate::bittorrent_transport::connect(stream).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("BitTorrent handshake with {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Bittorrent(stream)
+            }
+            crate::config::TransportType::Ftp => {
+                let stream = crate::ftp_transport::connect(stream, server_addr.ip()).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("FTP login to {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Ftp(stream)
+            }
+            crate::config::TransportType::Grpc => {
+                let host = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::grpc_transport::connect(stream, &host).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("Failed to open gRPC stream to {}: {}", server_addr, e))
+                })?;
+                ServerStream::Grpc(stream)
+            }
+            crate::config::TransportType::Openvpn => {
+                let server_name = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::openvpn::connect(stream, &server_name).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("OpenVPN handshake with {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Openvpn(stream)
+            }
+            crate::config::TransportType::Mqtt => {
+                let stream = crate::mqtt_transport::connect(stream, config.socks.mqtt_version).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("MQTT connection to {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Mqtt(stream)
+            }
+            crate::config::TransportType::Sip => {
+                let domain = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::sip::connect(stream, &domain, local, config.relay.voip_codec).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("SIP call to {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Sip(stream)
+            }
+            crate::config::TransportType::Rtsp => {
+                let stream = crate::rtsp_transport::connect(stream, server_addr).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("RTSP session with {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Rtsp(stream)
+            }
+            crate::config::TransportType::Smb => {
+                let server_name = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::smb_transport::connect(stream, &server_name).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("SMB2 session with {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Smb(stream)
+            }
+            crate::config::TransportType::Syslog => {
+                let server_name = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::syslog_transport::connect(stream, &server_name).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("Syslog session with {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Syslog(stream)
+            }
+            crate::config::TransportType::Steam => {
+                let host = config.socks.http_host.clone().unwrap_or_else(|| server_addr.ip().to_string());
+                let stream = crate::steam_cdn::connect(stream, server_addr, &host).await.map_err(|e| {
+                    TunnelSetupError::Upstream(format!("Steam download from {} failed: {}", server_addr, e))
+                })?;
+                ServerStream::Steam(stream)
+            }
+            _ => stream,
+        }
+    };
+    Ok(server_stream)
+}
+
+/// Perform the Noise handshake over `server_stream` (connected to `server`,
+/// if directly) and ask the server to open `target_info`, in a [routing header](crate::chain::RoutingHeader)
+/// when `routed` (the server is a hop of a relay chain) or when asking for
+/// protocol hops
+async fn open_tunnel(
+    mut server_stream: ServerStream,
+    server: Option<SocketAddr>,
+    noise_config: &NoiseConfig,
+    protocol_id: &crate::ProtocolId,
+    config: &NooshdarooConfig,
+    target_info: &str,
+    routed: bool,
+) -> Result<EstablishedTunnel, TunnelSetupError> {
+    use crate::protocol_wrapper::ProtocolWrapper;
+    use crate::socks5::ReplyCode;
+
+    let is_dns = crate::chain::is_udp_protocol(protocol_id);
+    let capture_flow = match server_stream {
+        ServerStream::Captured(ref stream) => Some(stream.flow().clone()),
+        _ => None,
+    };
+
+    // Create protocol wrapper for handshake wrapping
+    // NOTE: DNS protocol doesn't need wrapper - DNS format IS the protocol wrapping
+    let mut protocol_wrapper = if !is_dns {
+        Some(
+            ProtocolWrapper::new(protocol_id.clone(), crate::WrapperRole::Client, None)
+                .with_payload_encoding(config.detection.payload_encoding(protocol_id.as_str())),
+        )
+    } else {
+        None
+    };
+    log::debug!("Using protocol: {} (wrapper: {})", protocol_id.as_str(), protocol_wrapper.is_some());
+
+    // Perform Noise handshake with protocol wrapping (if applicable)
+    let handshake_start = std::time::Instant::now();
+    let mut noise_transport = NoiseTransport::client_handshake(&mut server_stream, noise_config, protocol_wrapper.as_mut())
+        .await
+        .map_err(|e| {
+            if crate::blocking::is_handshake_reset(&e, handshake_start.elapsed()) {
+                let event = crate::blocking::BlockingEvent::new(
+                    crate::blocking::BlockingKind::HandshakeReset,
+                    format!("reset {:?} after the first handshake message", handshake_start.elapsed()),
+                )
+                .with_protocol(protocol_id.clone());
+                crate::blocking::record(match server {
+                    Some(server) => event.with_server(server),
+                    None => event,
+                });
+            }
+            TunnelSetupError::Handshake(format!("Noise handshake failed: {}", e))
+        })?;
+    let handshake_rtt = handshake_start.elapsed();
+    log::debug!("Noise handshake completed with server using {} in {:?}", protocol_id.as_str(), handshake_rtt);
+
+    // Enable TLS session emulation if configured AND protocol is TLS-based
+    let is_tls_protocol = protocol_id.as_str().starts_with("https") ||
+                          protocol_id.as_str().starts_with("tls") ||
+                          protocol_id.as_str() == "dns" || // DNS over TLS
+                          protocol_id.as_str() == "dns-google";
+
+    // Check if we should enable TLS session emulation
+    let use_tls_emulation = config.detection.enable_tls_session_emulation && is_tls_protocol;
+    if use_tls_emulation {
+        noise_transport.enable_tls_wrapping();
+        log::info!("Full TLS session emulation enabled for protocol: {}", protocol_id.as_str());
+    }
+
+    let padding = &config.relay.chain_padding;
+    let probing = target_info == crate::path_quality::PROBE_TARGET;
+    // The server relays reverse tunnels without hop framing
+    let reversing = crate::reverse::is_reverse_target(target_info);
+    let hopping = config.shapeshift.hopping.enabled && !routed && !is_dns && !probing && !reversing;
+    let request = if routed || hopping || probing {
+        let header = crate::chain::RoutingHeader {
+            target: target_info.to_string(),
+            pad_records: routed && padding.enabled,
+            protocol_hops: hopping,
+            ping: probing,
+        };
+        header
+            .encode()
+            .map_err(|e| TunnelSetupError::Rejected(ReplyCode::AddressTypeNotSupported, e))?
+    } else {
+        target_info.as_bytes().to_vec()
+    };
+
+    // Use write_raw() for DNS (no length prefix needed for UDP)
+    let write_result = if is_dns {
+        noise_transport.write_raw(&mut server_stream, &request).await
+    } else {
+        noise_transport.write(&mut server_stream, &request).await
+    };
+    write_result.map_err(|e| TunnelSetupError::Upstream(format!("Failed to send target info: {}", e)))?;
+    log::debug!("Sent target info to server: {}", target_info);
+
+    // The server pads from its reply on
+    if routed && padding.enabled {
+        noise_transport.enable_record_padding(padding.record_padding());
+    }
+
+    // Wait for server's connection confirmation
+    // Use read_raw() for DNS (no length prefix), read() for TCP
+    let response = if is_dns {
+        noise_transport.read_raw(&mut server_stream).await
+    } else {
+        noise_transport.read(&mut server_stream).await
+    }
+    .map_err(|e| TunnelSetupError::Upstream(format!("Failed to receive server response: {}", e)))?;
+
+    let response_str = String::from_utf8_lossy(&response);
+    if response_str != "OK" {
+        let reply = if response_str.contains("not allowed") {
+            ReplyCode::NotAllowed
+        } else if response_str.contains("refused") {
+            ReplyCode::ConnectionRefused
+        } else if response_str.contains("unreachable") {
+            ReplyCode::HostUnreachable
+        } else {
+            ReplyCode::GeneralFailure
+        };
+        return Err(TunnelSetupError::Rejected(reply, response_str.into_owned()));
+    }
+
+    Ok(EstablishedTunnel {
+        stream: server_stream,
+        noise: noise_transport,
+        protocol: protocol_id.clone(),
+        use_tls_emulation,
+        is_dns,
+        hopping,
+        capture_flow,
+        handshake_rtt,
+    })
+}
+
+/// Measure RTT, jitter and loss to `server_addr` with pings on a tunnel of
+/// their own (see [`crate::path_quality`]), for as long as the process runs
+pub fn spawn_path_probe(
+    server_addr: SocketAddr,
+    noise_config: NoiseConfig,
+    protocol_id: crate::ProtocolId,
+    config: Arc<NooshdarooConfig>,
+) {
+    tokio::spawn(async move {
+        let settings = config.path_quality.clone();
+        let mut monitor = crate::path_quality::PathMonitor::new(settings.window);
+        let mut interval = tokio::time::interval(settings.interval);
+        let mut seq = 0u64;
+        loop {
+            interval.tick().await;
+            let setup = tokio::time::timeout(
+                settings.timeout,
+                establish_tunnel(server_addr, &noise_config, &protocol_id, &config, crate::path_quality::PROBE_TARGET, false, None),
+            )
+            .await;
+            let setup = match setup {
+                Ok(Ok(tunnel)) => Ok(tunnel),
+                Ok(Err(TunnelSetupError::Upstream(e) | TunnelSetupError::Rejected(_, e) | TunnelSetupError::Handshake(e))) => Err(e),
+                Err(_) => Err("timed out".to_string()),
+            };
+            let mut tunnel = match setup {
+                Ok(tunnel) => tunnel,
+                Err(e) => {
+                    // An unreachable server loses every ping
+                    log::debug!("Path probe to {} could not open a tunnel: {}", server_addr, e);
+                    monitor.record(None);
+                    crate::path_quality::update(server_addr, monitor.quality());
+                    continue;
+                }
+            };
+            loop {
+                seq += 1;
+                let rtt = crate::path_quality::ping(&mut tunnel.noise, &mut tunnel.stream, seq, settings.timeout)
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::debug!("Path probe tunnel to {} failed: {}", server_addr, e);
+                        None
+                    });
+                monitor.record(rtt);
+                crate::path_quality::update(server_addr, monitor.quality());
+                if rtt.is_none() {
+                    break;
+                }
+                interval.tick().await;
+            }
+        }
+    });
+}
+
+/// Expose the services of `[reverse]` through its server (see
+/// [`crate::reverse`]), reconnecting whenever the control tunnel ends
+pub async fn run_reverse_agent(config: Arc<NooshdarooConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    let reverse = config.reverse.clone().ok_or("No [reverse] section in the configuration")?;
+    let noise_config = config.transport.clone().ok_or("Reverse tunnels need the server's key in [transport]")?;
+    let protocol_id = crate::ProtocolId::from(reverse.protocol.as_str());
+    if crate::chain::is_udp_protocol(&protocol_id) {
+        return Err(format!("Reverse tunnels need a stream protocol, not {}", protocol_id).into());
+    }
+    loop {
+        match run_reverse_control(&reverse, &noise_config, &protocol_id, &config).await {
+            Ok(()) => log::warn!("Control tunnel to {} closed", reverse.server),
+            Err(e) => log::warn!("Control tunnel to {} failed: {}", reverse.server, e),
+        }
+        tokio::time::sleep(reverse.retry_interval).await;
+    }
+}
+
+fn setup_error_message(error: TunnelSetupError) -> String {
+    match error {
+        TunnelSetupError::Upstream(e) | TunnelSetupError::Rejected(_, e) | TunnelSetupError::Handshake(e) => e,
+    }
+}
+
+/// Register the agent's services and open a data tunnel for every
+/// connection the server announces, until the control tunnel ends
+async fn run_reverse_control(
+    reverse: &crate::config::ReverseAgentConfig,
+    noise_config: &NoiseConfig,
+    protocol_id: &crate::ProtocolId,
+    config: &Arc<NooshdarooConfig>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::reverse::{ControlMessage, ServiceRequest};
+
+    let tunnel = establish_tunnel(reverse.server, noise_config, protocol_id, config, crate::reverse::CONTROL_TARGET, false, None)
+        .await
+        .map_err(setup_error_message)?;
+    let EstablishedTunnel { stream, mut noise, .. } = tunnel;
+    let mut stream = tokio::io::BufReader::new(stream);
+
+    let services = reverse
+        .services
+        .iter()
+        .map(|service| ServiceRequest { name: service.name.clone(), remote_port: service.remote_port })
+        .collect();
+    crate::reverse::send(&mut noise, &mut stream, &ControlMessage::Register { secret: reverse.secret.clone(), services }).await?;
+    match crate::reverse::recv(&mut noise, &mut stream).await? {
+        ControlMessage::Registered => {
+            for service in &reverse.services {
+                log::info!("Exposing {} ({}) on port {} of {}", service.name, service.local, service.remote_port, reverse.server);
+            }
+        }
+        ControlMessage::Error { message } => return Err(format!("Server refused the services: {}", message).into()),
+        other => return Err(format!("Unexpected reply to registration: {:?}", other).into()),
+    }
+
+    let mut heartbeat = tokio::time::interval(crate::reverse::HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                crate::reverse::send(&mut noise, &mut stream, &ControlMessage::Heartbeat).await?;
+            }
+            readable = crate::reverse::readable(&mut stream) => {
+                if !readable? {
+                    return Ok(());
+                }
+                match crate::reverse::recv(&mut noise, &mut stream).await? {
+                    ControlMessage::Connect { service, id } => {
+                        let Some(local) = reverse.services.iter().find(|s| s.name == service).map(|s| s.local.clone()) else {
+                            log::warn!("Server announced a connection to unknown service {}", service);
+                            continue;
+                        };
+                        let (server, noise_config, protocol_id, config) =
+                            (reverse.server, noise_config.clone(), protocol_id.clone(), Arc::clone(config));
+                        tokio::spawn(async move {
+                            if let Err(e) = relay_reverse_connection(server, noise_config, protocol_id, config, &local, &id).await {
+                                log::warn!("Reverse connection to {} failed: {}", local, e);
+                            }
+                        });
+                    }
+                    other => log::debug!("Ignoring control message {:?}", other),
+                }
+            }
+        }
+    }
+}
+
+/// Carry connection `id` from the server to the service at `local`
+async fn relay_reverse_connection(
+    server: SocketAddr,
+    noise_config: NoiseConfig,
+    protocol_id: crate::ProtocolId,
+    config: Arc<NooshdarooConfig>,
+    local: &str,
+    id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tunnel = establish_tunnel(server, &noise_config, &protocol_id, &config, &crate::reverse::data_target(id), false, None)
+        .await
+        .map_err(setup_error_message)?;
+    // Dropping the tunnel closes the public connection too
+    let service = TcpStream::connect(local).await?;
+    service.set_nodelay(true)?;
+    log::debug!("Relaying reverse connection {} to {}", id, local);
+    let shaping = crate::traffic::RelayShaping::new(&config, protocol_id.as_str());
+    relay_tunnel(service, tunnel, shaping, &config, None).await
+}
+
+/// Run as a Tor managed pluggable transport client (see
+/// [`crate::tor_bridge`]), speaking the pt-spec on stdout
+pub async fn run_tor_pt(config: Arc<NooshdarooConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let env = |name: &str| std::env::var(name).ok();
+    let requested = match crate::tor_bridge::client_setup(env) {
+        Ok(requested) => requested,
+        Err(line) => {
+            println!("{}", line);
+            return Ok(());
+        }
+    };
+    println!("VERSION 1");
+    if !requested {
+        println!("CMETHODS DONE");
+        return Ok(());
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    println!("CMETHOD {} socks5 {}", crate::tor_bridge::TRANSPORT_NAME, listener.local_addr()?);
+    println!("CMETHODS DONE");
+    std::io::stdout().flush()?;
+
+    let accept = async {
+        loop {
+            let (socket, peer_addr) = listener.accept().await?;
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                if let Err(e) = handle_tor_connection(socket, &config).await {
+                    log::warn!("Tor connection from {} failed: {}", peer_addr, e);
+                }
+            });
+        }
+    };
+    if !crate::tor_bridge::exit_on_stdin_close(env) {
+        return accept.await;
+    }
+    let stdin_closed = tokio::task::spawn_blocking(|| {
+        let mut sink = [0u8; 256];
+        while !matches!(std::io::Read::read(&mut std::io::stdin(), &mut sink), Ok(0) | Err(_)) {}
+    });
+    tokio::select! {
+        result = accept => result,
+        _ = stdin_closed => {
+            log::info!("Tor closed stdin, exiting");
+            Ok(())
         }
-        Command::UdpAssociate => {
-            log::info!("[UDP] SOCKS5 UDP ASSOCIATE request received for target {}:{}", target.host, target.port);
-            log::warn!("[UDP] SOCKS5 UDP ASSOCIATE command not yet integrated - DNS queries will fail");
+    }
+}
+
+/// Open a tunnel to the bridge Tor asked for and carry its connection
+async fn handle_tor_connection(mut socket: TcpStream, config: &Arc<NooshdarooConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::socks5::{send_reply, socks5_handshake_with_credentials, Command, ReplyCode};
+
+    let (command, target, credentials) = socks5_handshake_with_credentials(&mut socket).await?;
+    let bridge = match (command, target.to_socket_addr()) {
+        (Command::Connect, Some(bridge)) => bridge,
+        _ => {
             send_reply(&mut socket, ReplyCode::CommandNotSupported, &target).await?;
+            return Err(format!("Tor must connect to a bridge address, not {}:{}", target.host, target.port).into());
+        }
+    };
+    let (username, password) = credentials.ok_or("Tor sent no bridge line arguments")?;
+    let args = match crate::tor_bridge::BridgeArgs::from_socks(&username, &password) {
+        Ok(args) => args,
+        Err(e) => {
+            send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
+            return Err(e.into());
+        }
+    };
+    let noise_config = NoiseConfig {
+        remote_public_key: Some(args.public_key),
+        elligator: args.elligator,
+        ..Default::default()
+    };
+    let protocol_id = crate::ProtocolId::from(args.protocol.as_str());
+    let tunnel = match establish_tunnel(bridge, &noise_config, &protocol_id, config, crate::tor_bridge::TOR_TARGET, false, None).await {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            send_reply(&mut socket, ReplyCode::GeneralFailure, &target).await?;
+            return Err(setup_error_message(e).into());
+        }
+    };
+    send_reply(&mut socket, ReplyCode::Succeeded, &target).await?;
+    log::info!("Carrying Tor traffic to bridge {} over {}", bridge, protocol_id);
+    let shaping = crate::traffic::RelayShaping::new(config, protocol_id.as_str());
+    relay_tunnel(socket, tunnel, shaping, config, None).await
+}
+
+/// Carry connections between `[peer]`'s local address and the other
+/// client over a punched UDP path (see [`crate::punch`])
+pub async fn run_peer(config: Arc<NooshdarooConfig>) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::noise_transport::{NoiseKeypair, NoisePattern};
+    use crate::punch::Role;
+
+    let peer = config.peer.clone().ok_or("No [peer] section in the configuration")?;
+    let role = if peer.listen.is_some() { Role::Dial } else { Role::Answer };
+    let tag = crate::punch::meeting_tag(
+        &NoiseKeypair::decode_private_key(&peer.private_key)?,
+        &NoiseKeypair::decode_public_key(&peer.peer_public_key)?,
+        role,
+    )?;
+    let noise_config = NoiseConfig {
+        pattern: NoisePattern::KK,
+        local_private_key: Some(peer.private_key.clone()),
+        remote_public_key: Some(peer.peer_public_key.clone()),
+        elligator: true,
+        ..Default::default()
+    };
+
+    if let Some(listen) = peer.listen {
+        let listener = TcpListener::bind(listen).await?;
+        log::info!("Carrying connections to {} to the peer", listen);
+        loop {
+            let (local, addr) = listener.accept().await?;
+            let (peer, noise_config, config) = (peer.clone(), noise_config.clone(), Arc::clone(&config));
+            tokio::spawn(async move {
+                if let Err(e) = dial_peer(local, &peer, tag, &noise_config, &config).await {
+                    log::warn!("Connection from {} to the peer failed: {}", addr, e);
+                }
+            });
         }
     }
 
-    Ok(())
+    let forward = peer.forward.clone().ok_or("[peer] needs listen or forward")?;
+    log::info!("Forwarding connections from the peer to {}", forward);
+    loop {
+        let meeting = match crate::punch::meet(peer.rendezvous, tag, role).await {
+            Ok(meeting) => meeting,
+            Err(e) => {
+                log::warn!("Rendezvous at {} failed: {}", peer.rendezvous, e);
+                tokio::time::sleep(peer.punch_timeout).await;
+                continue;
+            }
+        };
+        let (peer, noise_config, config, forward) = (peer.clone(), noise_config.clone(), Arc::clone(&config), forward.clone());
+        tokio::spawn(async move {
+            if let Err(e) = answer_peer(meeting, &peer, &noise_config, &config, &forward).await {
+                log::warn!("Connection from the peer to {} failed: {}", forward, e);
+            }
+        });
+    }
+}
+
+/// Carry `local` to the peer over a new session
+async fn dial_peer(
+    local: TcpStream,
+    peer: &crate::config::PeerConfig,
+    tag: [u8; 32],
+    noise_config: &NoiseConfig,
+    config: &NooshdarooConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let meeting = crate::punch::meet(peer.rendezvous, tag, crate::punch::Role::Dial).await?;
+    let mut session = crate::punch::dial(meeting, peer.punch_timeout, peer.relay).await?;
+    log::info!("Connected to the peer {}", session.path);
+    let noise = NoiseTransport::client_handshake(&mut session.stream, noise_config, None).await?;
+    local.set_nodelay(true)?;
+    let shaping = crate::traffic::RelayShaping::new(config, "kcp");
+    relay_with_noise_only(local, &mut session.stream, noise, shaping, None).await
+}
+
+/// Accept the session of a peer met at the rendezvous and carry it to
+/// `forward`
+async fn answer_peer(
+    meeting: crate::punch::Meeting,
+    peer: &crate::config::PeerConfig,
+    noise_config: &NoiseConfig,
+    config: &NooshdarooConfig,
+    forward: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut session = crate::punch::answer(meeting, peer.punch_timeout).await?;
+    log::info!("Peer connected {}", session.path);
+    let noise = NoiseTransport::server_handshake(&mut session.stream, noise_config, None).await?;
+    let target = TcpStream::connect(forward).await?;
+    target.set_nodelay(true)?;
+    let shaping = crate::traffic::RelayShaping::new(config, "kcp");
+    relay_with_noise_only(target, &mut session.stream, noise, shaping, None).await
+}
+
+/// Relay `client` through an established tunnel with the relay loop its
+/// transport needs
+async fn relay_tunnel(
+    client: impl AsyncReadExt + AsyncWriteExt + Unpin,
+    tunnel: EstablishedTunnel,
+    shaping: crate::traffic::RelayShaping,
+    config: &NooshdarooConfig,
+    controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let EstablishedTunnel {
+        stream: server_stream,
+        noise: noise_transport,
+        protocol: protocol_id,
+        use_tls_emulation,
+        is_dns,
+        hopping,
+        capture_flow,
+        ..
+    } = tunnel;
+
+    // DNS uses UDP (no length prefix), TLS emulation uses built-in wrapping
+    if hopping {
+        log::debug!("Using hop session framing for {}", protocol_id.as_str());
+        let session = crate::protocol_hop::HopSession::new(
+            crate::protocol_hop::HopTarget { protocol: protocol_id.clone(), tls_emulation: use_tls_emulation },
+            crate::WrapperRole::Client,
+            config.detection.payload_encoding(protocol_id.as_str()),
+        );
+        let schedule = crate::protocol_hop::HopSchedule::new(config);
+        relay_hopping_tunnel(client, server_stream, noise_transport, session, schedule, shaping, config, controller, capture_flow).await
+    } else if is_dns {
+        // Use DNS-specific relay (no length prefix for UDP)
+        log::debug!("Using DNS transport layer (UDP, no length prefix)");
+        relay_dns_tunnel(client, server_stream, noise_transport, shaping).await
+    } else if use_tls_emulation {
+        // Use NoiseTransport's built-in TLS wrapping (no protocol wrapper)
+        log::debug!("Using TLS session emulation (no protocol wrapper)");
+        relay_with_noise_only(client, server_stream, noise_transport, shaping, capture_flow).await
+    } else {
+        // Use protocol wrapper for obfuscation
+        let wrapper = crate::ProtocolWrapper::new(protocol_id.clone(), crate::WrapperRole::Client, None)
+            .with_payload_encoding(config.detection.payload_encoding(protocol_id.as_str()));
+        log::debug!("Created {} protocol wrapper for traffic obfuscation", protocol_id.as_str());
+        relay_through_noise_tunnel(client, server_stream, noise_transport, wrapper, shaping, controller, capture_flow).await
+    }
 }
 
 /// Relay data through Noise-encrypted tunnel with protocol wrapping and dynamic rotation
@@ -530,8 +1652,11 @@ async fn relay_with_noise_only(
     mut client: impl AsyncReadExt + AsyncWriteExt + Unpin,
     mut server: impl AsyncReadExt + AsyncWriteExt + Unpin,
     mut noise: NoiseTransport,
+    mut shaping: crate::traffic::RelayShaping,
+    capture: Option<crate::capture::CaptureFlow>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut client_buf = vec![0u8; 8192];
+    use crate::capture::Direction;
+    let mut client_buf = vec![0u8; shaping.record_size];
     let mut client_closed = false;
     let mut server_closed = false;
 
@@ -544,17 +1669,29 @@ async fn relay_with_noise_only(
                         // Client closed write half - shutdown server write, but keep reading
                         log::debug!("Client closed connection, shutting down server write");
                         client_closed = true;
+                        if let Err(e) = noise.close(&mut server).await {
+                            log::debug!("Failed to shut down server write: {}", e);
+                        }
                         if server_closed {
                             break;
                         }
                     }
                     Ok(n) => {
+                        let n = shaping.coalesce(&mut client, &mut client_buf, n).await;
+                        if let Some(ref flow) = capture {
+                            flow.note_plaintext(Direction::Outbound, n);
+                        }
                         // NoiseTransport.write() handles encryption AND TLS wrapping
-                        noise.write(&mut server, &client_buf[..n]).await?;
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, noise.write(&mut server, &client_buf[..n])).await?;
+                        shaping.record_sent(n);
                     }
                     Err(e) => {
                         log::debug!("Client read error: {}", e);
                         client_closed = true;
+                        if let Err(e) = noise.close(&mut server).await {
+                            log::debug!("Failed to shut down server write: {}", e);
+                        }
                         if server_closed {
                             break;
                         }
@@ -566,26 +1703,51 @@ async fn relay_with_noise_only(
                 match result {
                     Ok(data) if !data.is_empty() => {
                         // NoiseTransport.read() handles TLS unwrapping AND decryption
-                        client.write_all(&data).await?;
+                        if let Some(ref flow) = capture {
+                            flow.note_plaintext(Direction::Inbound, data.len());
+                        }
+                        shaping.deliver(data.len(), client.write_all(&data)).await?;
+                        shaping.record_received(data.len());
                         client.flush().await?;
                     }
                     Ok(_) => {
-                        // Server closed connection
-                        log::debug!("Server closed connection");
-                        server_closed = true;
-                        if client_closed {
-                            break;
-                        }
+                        // Empty message: the server's keepalive
+                        shaping.keepalive.touch();
                     }
                     Err(e) => {
+                        // Server closed write half - shutdown client write, but keep reading
                         log::debug!("Noise read error: {}", e);
                         server_closed = true;
+                        if let Err(e) = client.shutdown().await {
+                            log::debug!("Failed to shut down client write: {}", e);
+                        }
                         if client_closed {
                             break;
                         }
                     }
                 }
             }
+            // Idle tunnel: send an empty TLS application data record
+            _ = shaping.keepalive.due(), if !client_closed => {
+                shaping.deliver(0, noise.write(&mut server, &[])).await?;
+                shaping.keepalive.touch();
+            }
+            // No data for the idle timeout: close both ways and let the server finish
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle connection", shaping.idle.interval().unwrap_or_default());
+                if !client_closed {
+                    if let Err(e) = noise.close(&mut server).await {
+                        log::debug!("Failed to shut down server write: {}", e);
+                    }
+                }
+                if !server_closed {
+                    if let Err(e) = client.shutdown().await {
+                        log::debug!("Failed to shut down client write: {}", e);
+                    }
+                    shaping.drain(&mut server).await;
+                }
+                break;
+            }
         }
     }
 
@@ -597,11 +1759,14 @@ async fn relay_dns_tunnel(
     mut client: impl AsyncReadExt + AsyncWriteExt + Unpin,
     mut server: impl AsyncReadExt + AsyncWriteExt + Unpin,
     mut noise: NoiseTransport,
+    mut shaping: crate::traffic::RelayShaping,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut client_buf = vec![0u8; 8192];
+    let mut client_buf = vec![0u8; shaping.record_size];
     let mut client_closed = false;
     let mut server_closed = false;
     let mut poll_interval = tokio::time::interval(tokio::time::Duration::from_millis(50));
+    // Started when the client closes: how long to keep polling for the rest of the response
+    let mut drain = crate::traffic::IdleTimer::new(None);
 
     loop {
         tokio::select! {
@@ -609,19 +1774,24 @@ async fn relay_dns_tunnel(
             result = client.read(&mut client_buf), if !client_closed => {
                 match result {
                     Ok(0) => {
-                        log::debug!("Client closed connection, shutting down server write");
+                        // DNS has no half-close; keep polling for the rest of the response
+                        log::debug!("Client closed connection, draining server");
                         client_closed = true;
+                        drain = crate::traffic::IdleTimer::new(Some(shaping.drain_timeout));
                         if server_closed {
                             break;
                         }
                     }
                     Ok(n) => {
                         // Use write_raw() for DNS - no length prefix
-                        noise.write_raw(&mut server, &client_buf[..n]).await?;
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, noise.write_raw(&mut server, &client_buf[..n])).await?;
+                        shaping.record_sent(n);
                     }
                     Err(e) => {
                         log::debug!("Client read error: {}", e);
                         client_closed = true;
+                        drain = crate::traffic::IdleTimer::new(Some(shaping.drain_timeout));
                         if server_closed {
                             break;
                         }
@@ -633,12 +1803,16 @@ async fn relay_dns_tunnel(
                 match result {
                     Ok(data) if !data.is_empty() => {
                         // read_raw() handles decryption without length prefix
-                        client.write_all(&data).await?;
+                        shaping.deliver(data.len(), client.write_all(&data)).await?;
+                        shaping.record_received(data.len());
                         client.flush().await?;
                     }
                     Ok(_) => {
-                        log::debug!("Server closed connection");
+                        log::debug!("Server closed connection, shutting down client write");
                         server_closed = true;
+                        if let Err(e) = client.shutdown().await {
+                            log::debug!("Failed to shut down client write: {}", e);
+                        }
                         if client_closed {
                             break;
                         }
@@ -646,6 +1820,9 @@ async fn relay_dns_tunnel(
                     Err(e) => {
                         log::debug!("Noise read error: {}", e);
                         server_closed = true;
+                        if let Err(e) = client.shutdown().await {
+                            log::debug!("Failed to shut down client write: {}", e);
+                        }
                         if client_closed {
                             break;
                         }
@@ -653,39 +1830,80 @@ async fn relay_dns_tunnel(
                 }
             }
             // DNS polling: periodically send empty requests to trigger server responses
-            _ = poll_interval.tick(), if !server_closed && !client_closed => {
+            _ = poll_interval.tick(), if !server_closed => {
                 // Send empty encrypted message to poll for server data
                 if let Err(e) = noise.write_raw(&mut server, b"").await {
                     log::debug!("DNS poll write error: {}", e);
                     server_closed = true;
                 }
             }
+            // The server had the drain timeout to deliver the rest of the session
+            _ = drain.due() => {
+                log::debug!("DNS session drained, closing");
+                break;
+            }
+            // No data for the idle timeout: stop polling like an idle resolver
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle DNS session", shaping.idle.interval().unwrap_or_default());
+                if !server_closed {
+                    if let Err(e) = client.shutdown().await {
+                        log::debug!("Failed to shut down client write: {}", e);
+                    }
+                }
+                break;
+            }
         }
     }
 
     Ok(())
 }
 
+/// Frames between emulation fidelity reports to the controller
+const FIDELITY_REPORT_INTERVAL: u64 = 64;
+
 async fn relay_through_noise_tunnel(
     mut client: impl AsyncReadExt + AsyncWriteExt + Unpin,
     mut server: impl AsyncReadExt + AsyncWriteExt + Unpin,
     mut noise: NoiseTransport,
     mut wrapper: crate::ProtocolWrapper,
+    mut shaping: crate::traffic::RelayShaping,
     controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
+    capture: Option<crate::capture::CaptureFlow>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::capture::Direction;
+    use crate::fidelity::{FidelityScorer, ProtocolSignature};
     use tokio::io::AsyncWriteExt;
-    let mut client_buf = vec![0u8; 8192];
+    let mut client_buf = vec![0u8; shaping.record_size];
+    let mut client_closed = false;
+    let mut server_closed = false;
+
+    // Score how closely the wrapped frames match the protocol's signature
+    let current_meta = |ctrl: &crate::ShapeShiftController| ctrl.current_protocol_meta().cloned();
+    let meta = match controller {
+        Some(ref ctrl) => current_meta(&*ctrl.read().await),
+        None => None,
+    };
+    let mut scorer = FidelityScorer::new(ProtocolSignature::new(wrapper.outbound_frame(), meta.as_ref()));
 
     loop {
         // Check if rotation is needed (if controller exists)
         if let Some(ref ctrl) = controller {
             if let Ok(mut guard) = ctrl.try_write() {
+                guard.sync_traffic();
                 if guard.should_rotate() {
                     if guard.rotate().is_ok() {
                         let new_protocol = guard.stats().current_protocol.clone();
                         log::info!("Protocol rotation triggered: switching to {}", new_protocol.as_str());
-                        wrapper = crate::ProtocolWrapper::new(new_protocol, crate::WrapperRole::Client, None);
+                        shaping.jitter.set_protocol(new_protocol.as_str());
+                        shaping.keepalive.set_protocol(new_protocol.as_str());
+                        shaping.counters.set_protocol(guard.traffic().protocol(&new_protocol));
+                        // The server decodes with the encoding negotiated for this connection
+                        let encoding = wrapper.payload_encoding();
+                        wrapper = crate::ProtocolWrapper::new(new_protocol, crate::WrapperRole::Client, None)
+                            .with_payload_encoding(encoding);
                         log::debug!("Protocol wrapper updated for rotation");
+                        let meta = current_meta(&guard);
+                        scorer = FidelityScorer::new(ProtocolSignature::new(wrapper.outbound_frame(), meta.as_ref()));
                     }
                 }
             }
@@ -693,10 +1911,25 @@ async fn relay_through_noise_tunnel(
 
         tokio::select! {
             // Read from client, encrypt, wrap, send to server
-            result = client.read(&mut client_buf) => {
+            result = client.read(&mut client_buf), if !client_closed => {
                 match result {
-                    Ok(0) => break, // EOF
+                    Ok(0) => {
+                        // Client closed write half - shutdown server write, but keep reading
+                        log::debug!("Client closed connection, shutting down server write");
+                        client_closed = true;
+                        if let Err(e) = server.shutdown().await {
+                            log::debug!("Failed to shut down server write: {}", e);
+                        }
+                        if server_closed {
+                            break;
+                        }
+                    }
                     Ok(n) => {
+                        let n = shaping.coalesce(&mut client, &mut client_buf, n).await;
+                        if let Some(ref flow) = capture {
+                            flow.note_plaintext(Direction::Outbound, n);
+                        }
+
                         // Encrypt with Noise
                         let encrypted = noise.encrypt(&client_buf[..n])?;
                         log::debug!("Encrypted {} bytes to {} bytes", n, encrypted.len());
@@ -706,17 +1939,34 @@ async fn relay_through_noise_tunnel(
                         let wrapped_len = wrapped.len();
                         log::debug!("Wrapped {} bytes to {} bytes with protocol obfuscation", encrypted.len(), wrapped_len);
 
+                        scorer.observe(&wrapped);
+                        if scorer.frames().is_multiple_of(FIDELITY_REPORT_INTERVAL) {
+                            if let Some(ref ctrl) = controller {
+                                if let Ok(mut guard) = ctrl.try_write() {
+                                    guard.record_fidelity(scorer.score().overall);
+                                }
+                            }
+                        }
+
                         // Write wrapped data to server
-                        noise.write_raw(&mut server, &wrapped).await?;
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, noise.write_raw(&mut server, &wrapped)).await?;
+                        shaping.record_sent(n);
                     }
                     Err(e) => {
                         log::debug!("Client read error: {}", e);
-                        break;
+                        client_closed = true;
+                        if let Err(e) = server.shutdown().await {
+                            log::debug!("Failed to shut down server write: {}", e);
+                        }
+                        if server_closed {
+                            break;
+                        }
                     }
                 }
             }
             // Read from server (wrapped), unwrap, decrypt, send to client
-            result = noise.read_raw(&mut server) => {
+            result = noise.read_raw(&mut server), if !server_closed => {
                 match result {
                     Ok(wrapped) if !wrapped.is_empty() => {
                         let wrapped_len = wrapped.len();
@@ -729,16 +1979,183 @@ async fn relay_through_noise_tunnel(
                         // Decrypt with Noise
                         let data = noise.decrypt(&encrypted)?;
                         log::debug!("Decrypted {} bytes to {} bytes", encrypted_len, data.len());
+                        shaping.keepalive.touch();
+                        if data.is_empty() {
+                            // The server's keepalive
+                            continue;
+                        }
+                        if let Some(ref flow) = capture {
+                            flow.note_plaintext(Direction::Inbound, data.len());
+                        }
 
                         // Send to client
-                        client.write_all(&data).await?;
+                        shaping.deliver(data.len(), client.write_all(&data)).await?;
+                        shaping.record_received(data.len());
                     }
-                    Ok(_) => break, // Empty read = EOF
-                    Err(e) => {
-                        log::debug!("Noise read error: {}", e);
-                        break;
+                    // Empty read or error: server closed its write half
+                    result => {
+                        if let Err(e) = result {
+                            log::debug!("Noise read error: {}", e);
+                        }
+                        log::debug!("Server closed connection, shutting down client write");
+                        server_closed = true;
+                        if let Err(e) = client.shutdown().await {
+                            log::debug!("Failed to shut down client write: {}", e);
+                        }
+                        if client_closed {
+                            break;
+                        }
+                    }
+                }
+            }
+            // Idle tunnel: send an empty message in the protocol's framing
+            _ = shaping.keepalive.due(), if !client_closed => {
+                let wrapped = wrapper.wrap(&noise.encrypt(&[])?)?;
+                shaping.deliver(0, noise.write_raw(&mut server, &wrapped)).await?;
+                shaping.keepalive.touch();
+            }
+            // No data for the idle timeout: close both ways and let the server finish
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle connection", shaping.idle.interval().unwrap_or_default());
+                if !client_closed {
+                    if let Err(e) = server.shutdown().await {
+                        log::debug!("Failed to shut down server write: {}", e);
+                    }
+                }
+                if !server_closed {
+                    if let Err(e) = client.shutdown().await {
+                        log::debug!("Failed to shut down client write: {}", e);
+                    }
+                    shaping.drain(&mut server).await;
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Relay through a tunnel that agreed to protocol hops, hopping on the
+/// schedule and whenever the controller rotates
+#[allow(clippy::too_many_arguments)]
+async fn relay_hopping_tunnel(
+    mut client: impl AsyncReadExt + AsyncWriteExt + Unpin,
+    mut server: impl AsyncReadExt + AsyncWriteExt + Unpin,
+    mut noise: NoiseTransport,
+    mut session: crate::protocol_hop::HopSession,
+    mut schedule: crate::protocol_hop::HopSchedule,
+    mut shaping: crate::traffic::RelayShaping,
+    config: &NooshdarooConfig,
+    controller: Option<Arc<RwLock<crate::ShapeShiftController>>>,
+    capture: Option<crate::capture::CaptureFlow>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::capture::Direction;
+    use crate::protocol_hop::{HopTarget, Inbound};
+    let mut client_buf = vec![0u8; shaping.record_size];
+    let mut client_closed = false;
+    let mut server_closed = false;
+
+    loop {
+        // One hop at a time: the next waits for the server's acknowledgement
+        if !session.is_pending() && !client_closed {
+            let mut next = schedule.next(session.current());
+            if let Some(ref ctrl) = controller {
+                if let Ok(mut guard) = ctrl.try_write() {
+                    guard.sync_traffic();
+                    if guard.should_rotate() && guard.rotate().is_ok() {
+                        let protocol = guard.stats().current_protocol.clone();
+                        shaping.counters.set_protocol(guard.traffic().protocol(&protocol));
+                        next = Some(HopTarget::for_protocol(protocol, config));
+                    }
+                }
+            }
+            if let Some(target) = next {
+                log::info!("Hopping tunnel to {} (TLS emulation: {})", target.protocol.as_str(), target.tls_emulation);
+                shaping.jitter.set_protocol(target.protocol.as_str());
+                shaping.keepalive.set_protocol(target.protocol.as_str());
+                session.hop(&mut noise, &mut server, target).await?;
+            }
+        }
+
+        tokio::select! {
+            result = client.read(&mut client_buf), if !client_closed => {
+                match result {
+                    Ok(n) if n > 0 => {
+                        let n = shaping.coalesce(&mut client, &mut client_buf, n).await;
+                        if let Some(ref flow) = capture {
+                            flow.note_plaintext(Direction::Outbound, n);
+                        }
+                        shaping.jitter.pace().await;
+                        shaping.deliver(n, session.send(&mut noise, &mut server, &client_buf[..n])).await?;
+                        shaping.record_sent(n);
+                        schedule.record(n);
+                    }
+                    // EOF or error: client closed its write half
+                    result => {
+                        if let Err(e) = result {
+                            log::debug!("Client read error: {}", e);
+                        }
+                        client_closed = true;
+                        if let Err(e) = session.shutdown(&mut server).await {
+                            log::debug!("Failed to shut down server write: {}", e);
+                        }
+                        if server_closed {
+                            break;
+                        }
+                    }
+                }
+            }
+            result = session.recv(&mut noise, &mut server), if !server_closed => {
+                match result {
+                    Ok(Inbound::Data(data)) => {
+                        if let Some(ref flow) = capture {
+                            flow.note_plaintext(Direction::Inbound, data.len());
+                        }
+                        shaping.deliver(data.len(), client.write_all(&data)).await?;
+                        shaping.record_received(data.len());
+                        schedule.record(data.len());
                     }
+                    Ok(Inbound::Keepalive) => shaping.keepalive.touch(),
+                    Ok(Inbound::Hopped) => {
+                        if !client_closed {
+                            session.acknowledge(&mut noise, &mut server).await?;
+                        }
+                        shaping.keepalive.touch();
+                    }
+                    result => {
+                        if let Err(e) = result {
+                            log::debug!("Hop session read error: {}", e);
+                        }
+                        log::debug!("Server closed connection, shutting down client write");
+                        server_closed = true;
+                        if let Err(e) = client.shutdown().await {
+                            log::debug!("Failed to shut down client write: {}", e);
+                        }
+                        if client_closed {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = shaping.keepalive.due(), if !client_closed => {
+                shaping.deliver(0, session.keepalive(&mut noise, &mut server)).await?;
+                shaping.keepalive.touch();
+            }
+            _ = shaping.idle.due() => {
+                log::debug!("No data for {:?}, closing idle connection", shaping.idle.interval().unwrap_or_default());
+                if !client_closed {
+                    if let Err(e) = session.shutdown(&mut server).await {
+                        log::debug!("Failed to shut down server write: {}", e);
+                    }
+                }
+                if !server_closed {
+                    if let Err(e) = client.shutdown().await {
+                        log::debug!("Failed to shut down client write: {}", e);
+                    }
+                    shaping.drain(&mut server).await;
                 }
+                break;
             }
         }
     }
@@ -929,24 +2346,62 @@ async fn handle_http_connection(
 
 /// Session state for each DNS client
 struct DnsSession {
-    last_seen: std::time::Instant,
     noise_transport: Option<Arc<Mutex<NoiseTransport>>>,  // Serialize Noise operations
     target_conn: Option<TcpStream>,
     pending_response: Vec<u8>,
     handshake_complete: bool,
 }
 
+/// A DNS client's session and the server resources it holds
+struct DnsSessionSlot {
+    last_seen: std::time::Instant,
+    session: Arc<Mutex<DnsSession>>,
+    /// Bytes of queries waiting for the session's handler
+    budget: crate::isolation::SessionBudget,
+    _client: crate::isolation::ClientSession,
+    _audit: Option<crate::audit::AuditedConnection<'static>>,
+}
+
+impl DnsSessionSlot {
+    fn new(client_addr: SocketAddr, client: crate::isolation::ClientSession, session_buffer: usize) -> Self {
+        Self {
+            last_seen: std::time::Instant::now(),
+            session: Arc::new(Mutex::new(DnsSession {
+                noise_transport: None,
+                target_conn: None,
+                pending_response: Vec::new(),
+                handshake_complete: false,
+            })),
+            budget: crate::isolation::SessionBudget::new(session_buffer),
+            _client: client,
+            _audit: crate::audit::connection(client_addr.ip(), &crate::protocol::ProtocolId::from("dns-udp-tunnel")),
+        }
+    }
+}
+
 /// UDP DNS server for dns-udp-tunnel protocol
 pub async fn run_udp_dns_server(
     addr: SocketAddr,
     noise_config: Option<NoiseConfig>,
     config: Arc<NooshdarooConfig>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let dns_server = Arc::new(DnsTransportServer::bind(addr).await?);
+    let dns_server = if config.relay.port_hopping.enabled {
+        let schedule = crate::port_hop::PortHopSchedule::new(&config.relay.port_hopping)?;
+        DnsTransportServer::bind_hopping(addr.ip(), schedule).await?
+    } else {
+        DnsTransportServer::bind(addr).await?
+    };
+    let dns_server = dns_server.with_fec(&config.relay.fec);
+    let dns_server = match config.server.as_ref().and_then(|s| s.cluster.as_ref()) {
+        Some(cluster) => dns_server.with_cluster(crate::cluster::Cluster::start(cluster).await?),
+        None => dns_server,
+    };
+    let dns_server = Arc::new(dns_server);
     log::info!("UDP DNS server listening on {}", addr);
 
-    let sessions: Arc<Mutex<HashMap<SocketAddr, DnsSession>>> =
+    let sessions: Arc<Mutex<HashMap<SocketAddr, DnsSessionSlot>>> =
         Arc::new(Mutex::new(HashMap::new()));
+    let client_sessions = crate::isolation::ClientSessions::new(config.relay.max_sessions_per_client);
 
     // Spawn cleanup task
     let sessions_cleanup = Arc::clone(&sessions);
@@ -955,8 +2410,8 @@ pub async fn run_udp_dns_server(
             tokio::time::sleep(Duration::from_secs(30)).await;
             let mut sessions = sessions_cleanup.lock().await;
             let now = std::time::Instant::now();
-            sessions.retain(|addr, session| {
-                let keep = now.duration_since(session.last_seen) < Duration::from_secs(300);
+            sessions.retain(|addr, slot| {
+                let keep = now.duration_since(slot.last_seen) < Duration::from_secs(300);
                 if !keep {
                     log::debug!("Cleaning up stale DNS session for {}", addr);
                 }
@@ -968,6 +2423,16 @@ pub async fn run_udp_dns_server(
     // Main loop: receive and handle DNS queries
     loop {
         match dns_server.receive_query().await {
+            Ok((payload, client_addr, tx_id)) if payload.is_empty() => {
+                // NAT keepalive: a plain lookup of the tunnel domain
+                log::debug!("DNS keepalive query from {}", client_addr);
+                if let Some(slot) = sessions.lock().await.get_mut(&client_addr) {
+                    slot.last_seen = std::time::Instant::now();
+                }
+                if let Err(e) = dns_server.send_response(&[], client_addr, tx_id).await {
+                    log::debug!("Failed to answer DNS keepalive from {}: {}", client_addr, e);
+                }
+            }
             Ok((payload, client_addr, tx_id)) => {
                 log::debug!(
                     "DNS query from {}: {} bytes, tx_id={}",
@@ -976,18 +2441,45 @@ pub async fn run_udp_dns_server(
                     tx_id
                 );
 
+                let (session, budget) = {
+                    let mut sessions = sessions.lock().await;
+                    let slot = match sessions.entry(client_addr) {
+                        std::collections::hash_map::Entry::Occupied(slot) => slot.into_mut(),
+                        std::collections::hash_map::Entry::Vacant(slot) => {
+                            let Some(client) = client_sessions.try_open(client_addr.ip()) else {
+                                log::debug!(
+                                    "Dropping DNS query from {}: its address has {} sessions open",
+                                    client_addr,
+                                    client_sessions.limit()
+                                );
+                                continue;
+                            };
+                            log::info!("Creating new DNS tunnel session for {}", client_addr);
+                            slot.insert(DnsSessionSlot::new(client_addr, client, config.relay.session_buffer))
+                        }
+                    };
+                    slot.last_seen = std::time::Instant::now();
+                    (Arc::clone(&slot.session), slot.budget.clone())
+                };
+                // Held until the handler is done; a session that fills its
+                // budget has its queries dropped for the client to retransmit
+                let Some(reserved) = budget.try_reserve(payload.len()) else {
+                    log::debug!("DNS session {} is over its buffer, dropping query", client_addr);
+                    continue;
+                };
+
                 let dns_server = Arc::clone(&dns_server);
-                let sessions = Arc::clone(&sessions);
                 let noise_config = noise_config.clone();
                 let config = Arc::clone(&config);
 
                 tokio::spawn(async move {
+                    let _reserved = reserved;
                     if let Err(e) = handle_dns_query(
                         dns_server,
                         payload,
                         client_addr,
                         tx_id,
-                        sessions,
+                        session,
                         noise_config,
                         config,
                     )
@@ -1010,7 +2502,7 @@ async fn handle_dns_query(
     payload: Vec<u8>,
     client_addr: SocketAddr,
     tx_id: u16,
-    sessions: Arc<Mutex<HashMap<SocketAddr, DnsSession>>>,
+    dns_session: Arc<Mutex<DnsSession>>,
     noise_config: Option<NoiseConfig>,
     config: Arc<NooshdarooConfig>,
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -1021,21 +2513,9 @@ async fn handle_dns_query(
         tx_id
     );
 
-    // Get or create session
-    let mut sessions_guard = sessions.lock().await;
-    let session = sessions_guard
-        .entry(client_addr)
-        .or_insert_with(|| {
-            log::info!("Creating new DNS tunnel session for {}", client_addr);
-            DnsSession {
-                last_seen: std::time::Instant::now(),
-                noise_transport: None,
-                target_conn: None,
-                pending_response: Vec::new(),
-                handshake_complete: false,
-            }
-        });
-    session.last_seen = std::time::Instant::now();
+    // Queries of this session wait for each other, not for other sessions
+    let mut session_guard = dns_session.lock().await;
+    let session = &mut *session_guard;
 
     // Check if we need to perform Noise handshake
     if session.noise_transport.is_none() {
@@ -1070,7 +2550,7 @@ async fn handle_dns_query(
                 }
                 Err(e) => {
                     log::error!("Failed to initialize server-side KCP: {}", e);
-                    drop(sessions_guard);
+                    drop(session_guard);
                     dns_server.send_response(b"KCP_INIT_ERROR", client_addr, tx_id).await?;
                     return Err(e.into());
                 }
@@ -1089,12 +2569,12 @@ async fn handle_dns_query(
                     session.handshake_complete = true;
 
                     // Handshake generates responses - they're already sent by virtual_stream
-                    drop(sessions_guard);
+                    drop(session_guard);
                     return Ok(());
                 }
                 Err(e) => {
                     log::error!("Noise handshake failed for {}: {}", client_addr, e);
-                    drop(sessions_guard);
+                    drop(session_guard);
 
                     // Send error response
                     dns_server
@@ -1105,15 +2585,15 @@ async fn handle_dns_query(
             }
         } else {
             log::error!("No Noise config provided for DNS tunnel server");
-            drop(sessions_guard);
+            drop(session_guard);
             return Err("Noise encryption required for DNS tunnel".into());
         }
     }
 
     // At this point, we have an established Noise session
-    // Clone the Arc to release the sessions lock while we do crypto
+    // Clone the Arc to release the session lock while we do crypto
     let noise_transport_arc = Arc::clone(session.noise_transport.as_ref().unwrap());
-    drop(sessions_guard);  // Release session lock early to avoid holding it during crypto
+    drop(session_guard);  // Release session lock early to avoid holding it during crypto
 
     // Lock the Noise transport to serialize decrypt/encrypt operations
     let mut noise_transport = noise_transport_arc.lock().await;
@@ -1143,22 +2623,32 @@ async fn handle_dns_query(
     };
 
     // Re-acquire session lock to check/update target_conn
-    let mut sessions_guard = sessions.lock().await;
-    let session = sessions_guard.get_mut(&client_addr).unwrap();
+    let mut session_guard = dns_session.lock().await;
+    let session = &mut *session_guard;
 
     // Check if this is the initial target connection request
     if session.target_conn.is_none() {
-        drop(sessions_guard);  // Release before async operations
-
-        // Parse target address (format: "host:port" or "[ipv6]:port")
-        let target_str = String::from_utf8_lossy(&decrypted);
+        drop(session_guard);  // Release before async operations
+
+        // Parse target address (format: "host:port" or "[ipv6]:port"), sent
+        // in a routing header when the client is relaying through a chain
+        let target_str = if crate::chain::RoutingHeader::is_header(&decrypted) {
+            let header = crate::chain::RoutingHeader::decode(&decrypted)?;
+            if header.pad_records {
+                noise_transport.enable_record_padding(config.relay.chain_padding.record_padding());
+            }
+            header.target
+        } else {
+            String::from_utf8_lossy(&decrypted).into_owned()
+        };
         log::info!("DNS tunnel connection request from {}: target={}", client_addr, target_str);
 
         // Parse target address
         let target_addr = parse_target_address(&target_str)?;
 
         // Connect to target
-        match TcpStream::connect(&target_addr).await {
+        let access = crate::acl::AccessPolicy::new(&config.access)?;
+        match access.connect_target(&target_addr).await {
             Ok(mut stream) => {
                 stream.set_nodelay(true)?;
                 log::info!(
@@ -1168,10 +2658,10 @@ async fn handle_dns_query(
                 );
 
                 // Store connection in session
-                let mut sessions_guard = sessions.lock().await;
-                let session = sessions_guard.get_mut(&client_addr).unwrap();
+                let mut session_guard = dns_session.lock().await;
+                let session = &mut *session_guard;
                 session.target_conn = Some(stream);
-                drop(sessions_guard);
+                drop(session_guard);
 
                 // Send success response
                 let success_msg = b"OK";
@@ -1221,7 +2711,7 @@ async fn handle_dns_query(
             // Send error response (encrypt before dropping guard)
             let error_msg = b"TARGET_WRITE_ERROR";
             let encrypted = noise_transport.encrypt(error_msg)?;
-            drop(sessions_guard);
+            drop(session_guard);
 
             dns_server
                 .send_response(&encrypted, client_addr, tx_id)
@@ -1264,7 +2754,7 @@ async fn handle_dns_query(
         if let Some(data) = response_data {
             // Encrypt and send response
             let encrypted = noise_transport.encrypt(data)?;
-            drop(sessions_guard);
+            drop(session_guard);
 
             dns_server
                 .send_response(&encrypted, client_addr, tx_id)
@@ -1277,7 +2767,7 @@ async fn handle_dns_query(
                 encrypted.len()
             );
         } else {
-            drop(sessions_guard);
+            drop(session_guard);
         }
     }
 
@@ -1434,6 +2924,9 @@ mod tests {
         let result = detect_proxy_type(&buf, &[ProxyType::Socks5, ProxyType::Http]);
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), ProxyType::Socks5);
+
+        let socks4 = BytesMut::from(&b"\x04\x01\x00\x50"[..]);
+        assert_eq!(detect_proxy_type(&socks4, &[ProxyType::Socks5, ProxyType::Http]).unwrap(), ProxyType::Socks5);
     }
 
     #[test]
@@ -1453,4 +2946,240 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), "example.com:443");
     }
+
+    #[tokio::test]
+    async fn test_relay_half_close() {
+        use crate::noise_transport::NoiseKeypair;
+
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            ..Default::default()
+        };
+
+        let (mut relay_tunnel, mut peer_tunnel) = tokio::io::duplex(8192);
+        let (client_noise, peer_noise) = tokio::join!(
+            NoiseTransport::client_handshake(&mut relay_tunnel, &client_config, None),
+            NoiseTransport::server_handshake(&mut peer_tunnel, &server_config, None),
+        );
+        let mut peer_noise = peer_noise.unwrap();
+
+        let (mut app, relay_client) = tokio::io::duplex(8192);
+        let shaping = crate::traffic::RelayShaping::new(&NooshdarooConfig::default(), "https");
+        let counters = shaping.counters.clone();
+        let relay = relay_with_noise_only(relay_client, relay_tunnel, client_noise.unwrap(), shaping, None);
+
+        // The app sends its request and half-closes; the response must still arrive
+        let peer = async {
+            app.write_all(b"request").await.unwrap();
+            app.shutdown().await.unwrap();
+
+            assert_eq!(peer_noise.read(&mut peer_tunnel).await.unwrap(), b"request");
+            assert!(peer_noise.read(&mut peer_tunnel).await.is_err());
+            peer_noise.write(&mut peer_tunnel, b"response").await.unwrap();
+            peer_tunnel.shutdown().await.unwrap();
+
+            let mut response = Vec::new();
+            app.read_to_end(&mut response).await.unwrap();
+            response
+        };
+
+        let (relayed, response) = tokio::join!(relay, peer);
+        assert!(relayed.is_ok());
+        assert_eq!(response, b"response");
+
+        let traffic = counters.snapshot();
+        assert_eq!((traffic.bytes_sent, traffic.bytes_received), (7, 8));
+        assert_eq!(traffic.packets(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_keepalive() {
+        use crate::noise_transport::NoiseKeypair;
+
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            ..Default::default()
+        };
+
+        let (mut relay_tunnel, mut peer_tunnel) = tokio::io::duplex(8192);
+        let (client_noise, peer_noise) = tokio::join!(
+            NoiseTransport::client_handshake(&mut relay_tunnel, &client_config, None),
+            NoiseTransport::server_handshake(&mut peer_tunnel, &server_config, None),
+        );
+        let mut peer_noise = peer_noise.unwrap();
+
+        let mut config = NooshdarooConfig::default();
+        config.traffic_shaping.keepalive.enabled = true;
+        config.traffic_shaping.keepalive.intervals.insert("https".to_string(), 10);
+        let (mut app, relay_client) = tokio::io::duplex(8192);
+        let shaping = crate::traffic::RelayShaping::new(&config, "https");
+        let counters = shaping.counters.clone();
+        let relay = relay_with_noise_only(relay_client, relay_tunnel, client_noise.unwrap(), shaping, None);
+
+        let peer = async {
+            // The idle tunnel sends an empty record once the interval passes
+            let start = tokio::time::Instant::now();
+            assert_eq!(peer_noise.read(&mut peer_tunnel).await.unwrap(), b"");
+            assert!(start.elapsed() >= Duration::from_secs(10));
+
+            // The peer's keepalive is dropped, not taken for a close
+            peer_noise.write(&mut peer_tunnel, b"").await.unwrap();
+            peer_noise.write(&mut peer_tunnel, b"data").await.unwrap();
+            let mut data = [0u8; 4];
+            app.read_exact(&mut data).await.unwrap();
+
+            app.shutdown().await.unwrap();
+            assert!(peer_noise.read(&mut peer_tunnel).await.is_err());
+            peer_tunnel.shutdown().await.unwrap();
+            data
+        };
+
+        let (relayed, data) = tokio::join!(relay, peer);
+        assert!(relayed.is_ok());
+        assert_eq!(&data, b"data");
+        assert_eq!(counters.snapshot().packets(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_idle_timeout() {
+        use crate::noise_transport::NoiseKeypair;
+
+        let server_keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(server_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(server_keypair.public_key_base64()),
+            ..Default::default()
+        };
+
+        let (mut relay_tunnel, mut peer_tunnel) = tokio::io::duplex(8192);
+        let (client_noise, peer_noise) = tokio::join!(
+            NoiseTransport::client_handshake(&mut relay_tunnel, &client_config, None),
+            NoiseTransport::server_handshake(&mut peer_tunnel, &server_config, None),
+        );
+        let (mut client_noise, mut peer_noise) = (client_noise.unwrap(), peer_noise.unwrap());
+        client_noise.enable_tls_wrapping();
+        peer_noise.enable_tls_wrapping();
+
+        let mut config = NooshdarooConfig::default();
+        config.relay.idle_timeout = Some(Duration::from_secs(60));
+        let (mut app, relay_client) = tokio::io::duplex(8192);
+        let shaping = crate::traffic::RelayShaping::new(&config, "https");
+        let relay = relay_with_noise_only(relay_client, relay_tunnel, client_noise, shaping, None);
+
+        let peer = async {
+            app.write_all(b"request").await.unwrap();
+            assert_eq!(peer_noise.read(&mut peer_tunnel).await.unwrap(), b"request");
+
+            // After a minute without data the relay sends close_notify and both FINs
+            let start = tokio::time::Instant::now();
+            let err = peer_noise.read(&mut peer_tunnel).await.unwrap_err();
+            assert!(err.to_string().contains("close_notify"));
+            assert!(start.elapsed() >= Duration::from_secs(60));
+            let mut rest = Vec::new();
+            app.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            peer_tunnel.shutdown().await.unwrap();
+        };
+
+        let (relayed, ()) = tokio::join!(relay, peer);
+        assert!(relayed.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_through_hop() {
+        use crate::noise_transport::NoiseKeypair;
+
+        let entry_keypair = NoiseKeypair::generate().unwrap();
+        let exit_keypair = NoiseKeypair::generate().unwrap();
+        let config = Arc::new(NooshdarooConfig::default());
+
+        // Tunnel to the entry hop, which forwards it to the exit hop
+        let entry_config = NoiseConfig {
+            local_private_key: Some(entry_keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(entry_keypair.public_key_base64()),
+            ..Default::default()
+        };
+        let (mut client_io, mut entry_io) = tokio::io::duplex(8192);
+        let (client_noise, entry_noise) = tokio::join!(
+            NoiseTransport::client_handshake(&mut client_io, &client_config, None),
+            NoiseTransport::server_handshake(&mut entry_io, &entry_config, None),
+        );
+        let (mut client_noise, mut entry_noise) = (client_noise.unwrap(), entry_noise.unwrap());
+        client_noise.enable_tls_wrapping();
+        entry_noise.enable_tls_wrapping();
+        let (entry_target, mut exit_io) = tokio::io::duplex(8192);
+        let entry_shaping = crate::traffic::RelayShaping::new(&config, "https");
+        tokio::spawn(async move {
+            let _ = relay_with_noise_only(entry_target, entry_io, entry_noise, entry_shaping, None).await;
+        });
+
+        let outer = EstablishedTunnel {
+            stream: ServerStream::Hop(client_io),
+            noise: client_noise,
+            protocol: crate::ProtocolId::from("https"),
+            use_tls_emulation: true,
+            is_dns: false,
+            hopping: false,
+            capture_flow: None,
+            handshake_rtt: Duration::ZERO,
+        };
+        let (near, far) = tokio::io::duplex(CHAIN_HOP_BUFFER);
+        let relay_config = Arc::clone(&config);
+        tokio::spawn(async move {
+            let shaping = crate::traffic::RelayShaping::new(&relay_config, "https");
+            let _ = relay_tunnel(far, outer, shaping, &relay_config, None).await;
+        });
+
+        // The exit hop sees a plain http-wrapped handshake and a routing
+        // header for the real target, then pads its session
+        let exit = async {
+            let mut wrapper = crate::ProtocolWrapper::new(crate::ProtocolId::from("http"), crate::WrapperRole::Server, None)
+                .with_payload_encoding(config.detection.payload_encoding("http"));
+            let exit_config = NoiseConfig {
+                local_private_key: Some(exit_keypair.private_key_base64()),
+                ..Default::default()
+            };
+            let mut noise = NoiseTransport::server_handshake(&mut exit_io, &exit_config, Some(&mut wrapper)).await.unwrap();
+            let request = noise.read(&mut exit_io).await.unwrap();
+            assert_eq!(request.len(), crate::chain::ROUTING_HEADER_SIZE);
+            let header = crate::chain::RoutingHeader::decode(&request).unwrap();
+            assert_eq!(header.target, "example.com:443");
+            assert!(header.pad_records);
+            noise.enable_record_padding(config.relay.chain_padding.record_padding());
+            noise.write(&mut exit_io, b"OK").await.unwrap();
+            noise.read(&mut exit_io).await.unwrap()
+        };
+        let hop_config = NoiseConfig {
+            remote_public_key: Some(exit_keypair.public_key_base64()),
+            ..Default::default()
+        };
+        let client = async {
+            let mut tunnel = open_tunnel(ServerStream::Hop(near), None, &hop_config, &crate::ProtocolId::from("http"), &config, "example.com:443", true)
+                .await
+                .unwrap_or_else(|_| panic!("tunnel through the entry hop failed"));
+            tunnel.noise.write(&mut tunnel.stream, b"hello").await.unwrap();
+            tunnel
+        };
+
+        let (received, tunnel) = tokio::join!(exit, client);
+        assert_eq!(received, b"hello");
+        assert_eq!(tunnel.protocol, crate::ProtocolId::from("http"));
+        assert!(tunnel.noise.is_record_padding_enabled());
+    }
 }
@@ -7,10 +7,12 @@
 //! - Lexer: Tokenizes PSF files into tokens
 //! - Parser: Builds AST from tokens
 //! - Interpreter: Generates wrap/unwrap functions from AST
+//! - Signature: Verifies Ed25519 signatures on PSF files
 
 pub mod lexer;
 pub mod parser;
 pub mod interpreter;
+pub mod signature;
 pub mod types;
 
 pub use interpreter::PsfInterpreter;
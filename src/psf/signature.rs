@@ -0,0 +1,181 @@
+//! PSF Signatures - Ed25519 detached signatures for protocol definition files
+//!
+//! A signature for `https.psf` lives next to it in `https.psf.sig` and holds
+//! the base64-encoded Ed25519 signature over the file name, a zero byte and
+//! the exact file bytes. The protocol id comes from the name, so a signed
+//! file cannot be renamed to stand in for another protocol.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::path::{Path, PathBuf};
+
+/// Extension appended to a PSF path to locate its signature
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Path of the detached signature for `psf_path`
+pub fn signature_path(psf_path: &Path) -> PathBuf {
+    let mut path = psf_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(SIGNATURE_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Bytes a PSF signature covers: file name, `0x00`, contents
+fn signed_payload(psf_path: &Path, content: &[u8]) -> Vec<u8> {
+    let name = psf_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let mut payload = Vec::with_capacity(name.len() + 1 + content.len());
+    payload.extend_from_slice(name.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(content);
+    payload
+}
+
+/// Result of checking a PSF file against the trusted keys
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    /// Signed by a trusted key
+    Verified,
+    /// No signature file present
+    Unsigned,
+    /// Signature present but not valid for any trusted key
+    Invalid(String),
+}
+
+/// Verifies PSF signatures against a set of trusted Ed25519 public keys
+#[derive(Debug, Clone)]
+pub struct PsfVerifier {
+    keys: Vec<Vec<u8>>,
+}
+
+impl PsfVerifier {
+    /// Create a verifier from base64-encoded 32-byte public keys
+    pub fn new(trusted_keys: &[String]) -> Result<Self, String> {
+        let keys = trusted_keys
+            .iter()
+            .map(|key| {
+                let bytes = BASE64
+                    .decode(key.trim())
+                    .map_err(|e| format!("Invalid trusted key {}: {}", key, e))?;
+                if bytes.len() != 32 {
+                    return Err(format!("Trusted key {} is not a 32-byte Ed25519 key", key));
+                }
+                Ok(bytes)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { keys })
+    }
+
+    /// Whether `signature` over `content` was made by a trusted key
+    pub fn verify(&self, content: &[u8], signature: &[u8]) -> bool {
+        self.keys
+            .iter()
+            .any(|key| UnparsedPublicKey::new(&ED25519, key).verify(content, signature).is_ok())
+    }
+
+    /// Check `content` (read from `psf_path`) against its detached signature
+    pub fn verify_file(&self, psf_path: &Path, content: &[u8]) -> Verification {
+        let sig_path = signature_path(psf_path);
+        let encoded = match std::fs::read_to_string(&sig_path) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Verification::Unsigned,
+            Err(e) => return Verification::Invalid(format!("cannot read {}: {}", sig_path.display(), e)),
+        };
+        let signature = match BASE64.decode(encoded.trim()) {
+            Ok(signature) => signature,
+            Err(e) => return Verification::Invalid(format!("malformed signature: {}", e)),
+        };
+        if self.verify(&signed_payload(psf_path, content), &signature) {
+            Verification::Verified
+        } else {
+            Verification::Invalid("signature does not match any trusted key".to_string())
+        }
+    }
+}
+
+/// Signs PSF files with an Ed25519 private key
+pub struct PsfSigner {
+    keypair: Ed25519KeyPair,
+}
+
+impl PsfSigner {
+    /// Generate a new signing key, returned as base64 PKCS#8
+    pub fn generate_key() -> Result<String, String> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| "Failed to generate Ed25519 key".to_string())?;
+        Ok(BASE64.encode(pkcs8.as_ref()))
+    }
+
+    /// Load a signer from a base64 PKCS#8 private key
+    pub fn from_pkcs8_base64(key: &str) -> Result<Self, String> {
+        let pkcs8 = BASE64
+            .decode(key.trim())
+            .map_err(|e| format!("Invalid signing key: {}", e))?;
+        let keypair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| format!("Invalid signing key: {}", e))?;
+        Ok(Self { keypair })
+    }
+
+    /// Base64 public key to list under trusted keys
+    pub fn public_key_base64(&self) -> String {
+        BASE64.encode(self.keypair.public_key().as_ref())
+    }
+
+    /// Base64 signature over `content`
+    pub fn sign(&self, content: &[u8]) -> String {
+        BASE64.encode(self.keypair.sign(content).as_ref())
+    }
+
+    /// Sign the file at `psf_path`, writing its detached signature
+    pub fn sign_file(&self, psf_path: &Path) -> std::io::Result<PathBuf> {
+        let content = std::fs::read(psf_path)?;
+        let sig_path = signature_path(psf_path);
+        std::fs::write(&sig_path, format!("{}\n", self.sign(&signed_payload(psf_path, &content))))?;
+        Ok(sig_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        let verifier = PsfVerifier::new(&[signer.public_key_base64()]).unwrap();
+
+        let content = b"@SEGMENT.FORMATS\n";
+        let signature = BASE64.decode(signer.sign(content)).unwrap();
+        assert!(verifier.verify(content, &signature));
+        assert!(!verifier.verify(b"@SEGMENT.FORMATS tampered\n", &signature));
+
+        let other = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        let untrusted = BASE64.decode(other.sign(content)).unwrap();
+        assert!(!verifier.verify(content, &untrusted));
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let dir = std::env::temp_dir().join(format!("nooshdaroo-psf-sig-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let psf = dir.join("test.psf");
+        std::fs::write(&psf, "@SEGMENT.FORMATS\n").unwrap();
+
+        let signer = PsfSigner::from_pkcs8_base64(&PsfSigner::generate_key().unwrap()).unwrap();
+        let verifier = PsfVerifier::new(&[signer.public_key_base64()]).unwrap();
+
+        assert_eq!(verifier.verify_file(&psf, b"@SEGMENT.FORMATS\n"), Verification::Unsigned);
+        assert_eq!(signer.sign_file(&psf).unwrap(), dir.join("test.psf.sig"));
+        assert_eq!(verifier.verify_file(&psf, b"@SEGMENT.FORMATS\n"), Verification::Verified);
+        assert!(matches!(verifier.verify_file(&psf, b"tampered"), Verification::Invalid(_)));
+
+        // The signature does not carry over to another protocol's name
+        let renamed = dir.join("other.psf");
+        std::fs::write(&renamed, "@SEGMENT.FORMATS\n").unwrap();
+        std::fs::copy(dir.join("test.psf.sig"), dir.join("other.psf.sig")).unwrap();
+        assert!(matches!(verifier.verify_file(&renamed, b"@SEGMENT.FORMATS\n"), Verification::Invalid(_)));
+
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(PsfVerifier::new(&["not-base64!".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+//! UDP hole punching between clients
+//!
+//! Two clients behind NAT can usually exchange UDP datagrams directly once
+//! each has sent one toward the other, opening a mapping in its own NAT.
+//! They learn each other's public address from a rendezvous on a server
+//! (`[server.rendezvous]`) and then run a KCP session (see
+//! [`crate::kcp_transport`]) between them, encrypted with Noise KK under
+//! their own keys:
+//!
+//! 1. Each peer sends `REGISTER` from the socket it will use, with its role
+//!    and a meeting tag derived from the dialing and the answering peer's
+//!    public keys, in that order; the rendezvous
+//!    pairs a dialing and an answering registration of the same tag and
+//!    sends each the other's address as it saw it
+//! 2. Both peers send `PUNCH` datagrams to that address and answer each
+//!    punch they receive with an `ACK`; a peer receiving an `ACK` knows
+//!    datagrams pass both ways
+//! 3. The dialing peer opens its session directly if it got an `ACK`
+//!    within the punch timeout, and otherwise through the rendezvous, which
+//!    relays datagrams between paired addresses when `relay` is enabled.
+//!    The answering peer accepts the session from either.
+//!
+//! NATs that map every destination to a new port defeat punching, so
+//! traffic between peers behind them always takes the relay. The rendezvous
+//! learns which addresses meet but not what they exchange.
+
+use crate::config::RendezvousConfig;
+use crate::kcp_transport::{KcpListener, Socket};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ring::digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::net::UdpSocket;
+
+/// Peer to rendezvous: `role (1) | tag (32)`
+const REGISTER: u8 = 1;
+
+/// Rendezvous to peer: `tag (32) | family (1) | address | port (2)`
+const PEER: u8 = 2;
+
+/// Peer to peer: the first half of the tag
+const PUNCH: u8 = 3;
+
+/// Peer to peer, answering a punch: the first half of the tag
+const ACK: u8 = 4;
+
+const PUNCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Acknowledgements sent back once one arrives, so the peer is not left
+/// punching
+const ACK_REPEATS: usize = 3;
+
+/// Registrations are repeated this often until the peer turns up
+const DIAL_REGISTER_INTERVAL: Duration = Duration::from_secs(1);
+const ANSWER_REGISTER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a dialing peer waits for the other at the rendezvous
+const MEET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long after punching an answering peer waits for the session
+const SESSION_GRACE: Duration = Duration::from_secs(5);
+
+/// Registrations not repeated for this long are forgotten
+const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pairs quiet for this long are forgotten, ending their relaying
+const PAIR_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Which end of the tunnel a peer is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    /// Opens the session, choosing the direct path or the relay
+    Dial,
+    /// Accepts the session on whichever path it arrives
+    Answer,
+}
+
+impl Role {
+    fn other(self) -> Self {
+        match self {
+            Self::Dial => Self::Answer,
+            Self::Answer => Self::Dial,
+        }
+    }
+}
+
+/// Tag under which the holder of `private_key`, in `role`, meets the holder
+/// of `peer_public_key` in the other role. Peers that both answer each
+/// other thus wait under different tags.
+pub fn meeting_tag(private_key: &[u8], peer_public_key: &[u8], role: Role) -> io::Result<[u8; 32]> {
+    let private: [u8; 32] = private_key
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "private key must be 32 bytes"))?;
+    let own = MontgomeryPoint::mul_base_clamped(private).to_bytes();
+    let (dialer, answerer) = match role {
+        Role::Dial => (&own[..], peer_public_key),
+        Role::Answer => (peer_public_key, &own[..]),
+    };
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(b"nooshdaroo meeting");
+    context.update(dialer);
+    context.update(answerer);
+    let mut tag = [0u8; 32];
+    tag.copy_from_slice(context.finish().as_ref());
+    Ok(tag)
+}
+
+fn register_message(role: Role, tag: &[u8; 32]) -> Vec<u8> {
+    let mut message = vec![REGISTER, role as u8];
+    message.extend_from_slice(tag);
+    message
+}
+
+fn parse_register(datagram: &[u8]) -> Option<(Role, [u8; 32])> {
+    let role = match datagram {
+        [REGISTER, 0, ..] => Role::Dial,
+        [REGISTER, 1, ..] => Role::Answer,
+        _ => return None,
+    };
+    Some((role, datagram.get(2..)?.try_into().ok()?))
+}
+
+fn peer_message(tag: &[u8; 32], peer: SocketAddr) -> Vec<u8> {
+    let mut message = vec![PEER];
+    message.extend_from_slice(tag);
+    match peer.ip() {
+        IpAddr::V4(ip) => {
+            message.push(4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&peer.port().to_be_bytes());
+    message
+}
+
+fn parse_peer(datagram: &[u8], tag: &[u8; 32]) -> Option<SocketAddr> {
+    let rest = datagram.strip_prefix(&[PEER])?.strip_prefix(tag.as_slice())?;
+    let (ip, port) = match rest {
+        [4, address @ .., high, low] if address.len() == 4 => {
+            (IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(address).ok()?)), [*high, *low])
+        }
+        [6, address @ .., high, low] if address.len() == 16 => {
+            (IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(address).ok()?)), [*high, *low])
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+fn token_message(kind: u8, tag: &[u8; 32]) -> Vec<u8> {
+    let mut message = vec![kind];
+    message.extend_from_slice(&tag[..16]);
+    message
+}
+
+#[derive(Debug)]
+struct Pair {
+    partner: SocketAddr,
+    tag: [u8; 32],
+    last_seen: Instant,
+}
+
+/// Rendezvous state: registrations waiting for their peer and the
+/// addresses already paired
+#[derive(Debug)]
+pub struct Rendezvous {
+    relay: bool,
+    waiting: HashMap<([u8; 32], Role), (SocketAddr, Instant)>,
+    pairs: HashMap<SocketAddr, Pair>,
+    swept: Instant,
+}
+
+impl Rendezvous {
+    pub fn new(relay: bool) -> Self {
+        Self { relay, waiting: HashMap::new(), pairs: HashMap::new(), swept: Instant::now() }
+    }
+
+    /// Handle a datagram from `from`, returning the datagrams to send and
+    /// where
+    pub fn handle(&mut self, datagram: &[u8], from: SocketAddr) -> Vec<(Vec<u8>, SocketAddr)> {
+        self.sweep();
+        let now = Instant::now();
+        if let Some((role, tag)) = parse_register(datagram) {
+            // A repeat whose answer was lost
+            if let Some(pair) = self.pairs.get(&from).filter(|pair| pair.tag == tag) {
+                return vec![(peer_message(&tag, pair.partner), from)];
+            }
+            return match self.waiting.remove(&(tag, role.other())) {
+                Some((other, _)) if other != from => {
+                    log::debug!("Rendezvous: paired {} and {}", from, other);
+                    self.pairs.insert(from, Pair { partner: other, tag, last_seen: now });
+                    self.pairs.insert(other, Pair { partner: from, tag, last_seen: now });
+                    vec![(peer_message(&tag, other), from), (peer_message(&tag, from), other)]
+                }
+                _ => {
+                    self.waiting.insert((tag, role), (from, now));
+                    Vec::new()
+                }
+            };
+        }
+        if !self.relay {
+            return Vec::new();
+        }
+        match self.pairs.get_mut(&from) {
+            Some(pair) => {
+                pair.last_seen = now;
+                vec![(datagram.to_vec(), pair.partner)]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Forget stale registrations and pairs, at most once a second
+    fn sweep(&mut self) {
+        if self.swept.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.swept = Instant::now();
+        self.waiting.retain(|_, (_, since)| since.elapsed() < WAIT_TIMEOUT);
+        self.pairs.retain(|_, pair| pair.last_seen.elapsed() < PAIR_TIMEOUT);
+    }
+}
+
+/// Run the rendezvous of `[server.rendezvous]`
+pub async fn serve_rendezvous(config: &RendezvousConfig) -> io::Result<()> {
+    let socket = UdpSocket::bind(config.listen).await?;
+    log::info!("Hole punching rendezvous on {} (relay {})", config.listen, if config.relay { "on" } else { "off" });
+    let mut rendezvous = Rendezvous::new(config.relay);
+    let mut buf = vec![0u8; crate::kcp_transport::KCP_MTU * 2];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::debug!("Rendezvous: receive error: {}", e);
+                continue;
+            }
+        };
+        for (datagram, to) in rendezvous.handle(&buf[..n], from) {
+            if let Err(e) = socket.send_to(&datagram, to).await {
+                log::debug!("Rendezvous: failed to send to {}: {}", to, e);
+            }
+        }
+    }
+}
+
+/// Two peers paired at the rendezvous, before punching
+#[derive(Debug)]
+pub struct Meeting {
+    socket: Arc<UdpSocket>,
+    rendezvous: SocketAddr,
+    tag: [u8; 32],
+    /// The peer's address as the rendezvous saw it
+    pub peer: SocketAddr,
+}
+
+/// Register at `rendezvous` and wait for the peer meeting under `tag`.
+/// Dialing peers give up after a while; answering ones wait for as long as
+/// it takes.
+pub async fn meet(rendezvous: SocketAddr, tag: [u8; 32], role: Role) -> io::Result<Meeting> {
+    let socket = Arc::new(UdpSocket::bind(crate::kcp_transport::any_local_addr(rendezvous)).await?);
+    let register = register_message(role, &tag);
+    let interval = match role {
+        Role::Dial => DIAL_REGISTER_INTERVAL,
+        Role::Answer => ANSWER_REGISTER_INTERVAL,
+    };
+    let started = Instant::now();
+    let mut buf = [0u8; 64];
+    loop {
+        socket.send_to(&register, rendezvous).await?;
+        let repeat = tokio::time::sleep(interval);
+        tokio::pin!(repeat);
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (n, from) = received?;
+                    if let Some(peer) = parse_peer(&buf[..n], &tag).filter(|_| from == rendezvous) {
+                        return Ok(Meeting { socket, rendezvous, tag, peer });
+                    }
+                }
+                _ = &mut repeat => break,
+            }
+        }
+        if role == Role::Dial && started.elapsed() >= MEET_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the peer is not waiting at the rendezvous"));
+        }
+    }
+}
+
+/// Punch through to the peer, returning the address its acknowledgement
+/// came from, or `None` if none came within `timeout`
+async fn punch(meeting: &Meeting, timeout: Duration) -> io::Result<Option<SocketAddr>> {
+    let punch = token_message(PUNCH, &meeting.tag);
+    let ack = token_message(ACK, &meeting.tag);
+    let mut ticker = tokio::time::interval(PUNCH_INTERVAL);
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+    let mut buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                meeting.socket.send_to(&punch, meeting.peer).await?;
+            }
+            received = meeting.socket.recv_from(&mut buf) => {
+                let (n, from) = received?;
+                if buf[..n] == punch[..] {
+                    meeting.socket.send_to(&ack, from).await?;
+                } else if buf[..n] == ack[..] {
+                    for _ in 0..ACK_REPEATS {
+                        meeting.socket.send_to(&ack, from).await?;
+                    }
+                    return Ok(Some(from));
+                }
+            }
+            _ = &mut deadline => return Ok(None),
+        }
+    }
+}
+
+/// Path a peer session took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerPath {
+    /// Punched through to the peer at this address
+    Direct(SocketAddr),
+    /// Relayed by the rendezvous at this address
+    Relayed(SocketAddr),
+}
+
+impl PeerPath {
+    fn addr(self) -> SocketAddr {
+        match self {
+            Self::Direct(addr) | Self::Relayed(addr) => addr,
+        }
+    }
+}
+
+impl fmt::Display for PeerPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct(addr) => write!(f, "directly with {}", addr),
+            Self::Relayed(addr) => write!(f, "through the relay at {}", addr),
+        }
+    }
+}
+
+/// KCP session between two peers, not yet encrypted
+pub struct PeerSession {
+    pub stream: DuplexStream,
+    pub path: PeerPath,
+    /// Routes the answering peer's datagrams for as long as the session
+    _listener: Option<KcpListener>,
+}
+
+/// Punch through to the answering peer and open a session to it, through
+/// the rendezvous if punching fails and `relay` allows
+pub async fn dial(meeting: Meeting, timeout: Duration, relay: bool) -> io::Result<PeerSession> {
+    let path = match punch(&meeting, timeout).await? {
+        Some(peer) => PeerPath::Direct(peer),
+        None if relay => PeerPath::Relayed(meeting.rendezvous),
+        None => {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("could not punch through to {}", meeting.peer)));
+        }
+    };
+    let socket: Socket = meeting.socket;
+    let stream = crate::kcp_transport::connect_over(socket, path.addr(), ());
+    Ok(PeerSession { stream, path, _listener: None })
+}
+
+/// Punch through to the dialing peer and accept its session on whichever
+/// path it arrives
+pub async fn answer(meeting: Meeting, timeout: Duration) -> io::Result<PeerSession> {
+    // Punching opens this side's NAT even when the dialer ends up relayed
+    punch(&meeting, timeout).await?;
+    let rendezvous = meeting.rendezvous;
+    let socket: Socket = meeting.socket;
+    let mut listener = KcpListener::bind_over(socket)?;
+    let (stream, from) = tokio::time::timeout(timeout + SESSION_GRACE, listener.accept())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "the peer did not open a session"))??;
+    let path = if from == rendezvous { PeerPath::Relayed(from) } else { PeerPath::Direct(from) };
+    Ok(PeerSession { stream, path, _listener: Some(listener) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_rendezvous() {
+        let tag = [7u8; 32];
+        let (dialer, answerer, stranger): (SocketAddr, SocketAddr, SocketAddr) =
+            ("198.51.100.1:4000".parse().unwrap(), "[2001:db8::2]:5000".parse().unwrap(), "192.0.2.9:6000".parse().unwrap());
+        let mut rendezvous = Rendezvous::new(true);
+
+        assert!(rendezvous.handle(&register_message(Role::Answer, &tag), answerer).is_empty());
+        // Two registrations of the same role do not pair
+        assert!(rendezvous.handle(&register_message(Role::Answer, &tag), stranger).is_empty());
+        let replies = rendezvous.handle(&register_message(Role::Dial, &tag), dialer);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].1, dialer);
+        assert_eq!(parse_peer(&replies[0].0, &tag), Some(stranger));
+        assert_eq!(parse_peer(&replies[1].0, &tag), Some(dialer));
+        assert_eq!(parse_peer(&replies[1].0, &[8u8; 32]), None);
+
+        // Repeats are answered again and other datagrams relayed
+        let replies = rendezvous.handle(&register_message(Role::Dial, &tag), dialer);
+        assert_eq!(parse_peer(&replies[0].0, &tag), Some(stranger));
+        assert_eq!(rendezvous.handle(b"kcp segment", stranger), vec![(b"kcp segment".to_vec(), dialer)]);
+        assert!(rendezvous.handle(b"kcp segment", answerer).is_empty());
+
+        let mut no_relay = Rendezvous::new(false);
+        no_relay.handle(&register_message(Role::Answer, &tag), answerer);
+        no_relay.handle(&register_message(Role::Dial, &tag), dialer);
+        assert!(no_relay.handle(b"kcp segment", dialer).is_empty());
+
+        let (a, b) = ([1u8; 32], [2u8; 32]);
+        let public = |private: [u8; 32]| MontgomeryPoint::mul_base_clamped(private).to_bytes();
+        let tag = meeting_tag(&a, &public(b), Role::Dial).unwrap();
+        assert_eq!(tag, meeting_tag(&b, &public(a), Role::Answer).unwrap());
+        assert_ne!(tag, meeting_tag(&a, &public(b), Role::Answer).unwrap());
+        assert_ne!(tag, meeting_tag(&b, &public(a), Role::Dial).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_punch_through() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rendezvous_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut rendezvous = Rendezvous::new(true);
+            let mut buf = [0u8; 2048];
+            while let Ok((n, from)) = server.recv_from(&mut buf).await {
+                for (datagram, to) in rendezvous.handle(&buf[..n], from) {
+                    server.send_to(&datagram, to).await.unwrap();
+                }
+            }
+        });
+
+        let tag = [3u8; 32];
+        let answering = tokio::spawn(async move {
+            let meeting = meet(rendezvous_addr, tag, Role::Answer).await.unwrap();
+            let mut session = answer(meeting, Duration::from_secs(2)).await.unwrap();
+            let mut buf = [0u8; 4];
+            session.stream.read_exact(&mut buf).await.unwrap();
+            session.stream.write_all(b"pong").await.unwrap();
+            session.stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            (buf, session.path)
+        });
+
+        let meeting = meet(rendezvous_addr, tag, Role::Dial).await.unwrap();
+        let mut session = dial(meeting, Duration::from_secs(2), true).await.unwrap();
+        assert!(matches!(session.path, PeerPath::Direct(_)));
+        session.stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        session.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        let (received, path) = answering.await.unwrap();
+        assert_eq!(&received, b"ping");
+        assert!(matches!(path, PeerPath::Direct(_)));
+    }
+}
@@ -0,0 +1,286 @@
+//! Python module (feature "python")
+//!
+//! Exposes clients, servers, configuration, key generation and path tests
+//! to Python, for scripting censorship measurements. Build and install it
+//! into the current virtualenv with maturin (see `pyproject.toml`):
+//!
+//! ```text
+//! maturin develop --release
+//! ```
+//!
+//! ```python
+//! import nooshdaroo
+//!
+//! private_key, public_key = nooshdaroo.generate_keypair()
+//! config = nooshdaroo.Config(server="vpn.example.org:443", server_public_key=public_key, protocol="https")
+//! client = nooshdaroo.Client(config)
+//! client.start()                      # SOCKS5 listener of [socks]
+//! print(client.stats()["current_protocol"], client.poll_events())
+//! for path in nooshdaroo.test_paths("203.0.113.7"):
+//!     print(path["protocol"], path["success"], path["latency"])
+//! ```
+//!
+//! Calls that wait on the network release the GIL.
+
+// The #[pymethods] expansion converts every PyResult's error into a PyErr
+#![allow(clippy::useless_conversion)]
+
+use crate::config::{NooshdarooConfig, TransportType};
+use crate::events::ClientEvent;
+use crate::strategy::{FixedStrategy, StrategyType};
+use crate::{NooshdarooClient, NooshdarooServer};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+fn runtime_error(e: impl ToString) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+fn value_error(e: impl ToString) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// Python objects for JSON values: dicts, lists, strings and numbers
+fn to_python(py: Python<'_>, value: &impl serde::Serialize) -> PyResult<PyObject> {
+    let json = serde_json::to_string(value).map_err(runtime_error)?;
+    Ok(py.import_bound("json")?.call_method1("loads", (json,))?.unbind())
+}
+
+fn new_runtime() -> PyResult<Runtime> {
+    tokio::runtime::Builder::new_multi_thread().enable_all().build().map_err(runtime_error)
+}
+
+/// A nooshdaroo configuration
+#[pyclass(name = "Config", module = "nooshdaroo")]
+#[derive(Clone)]
+struct PyConfig {
+    config: NooshdarooConfig,
+}
+
+#[pymethods]
+impl PyConfig {
+    /// A client configuration: the defaults with the given settings applied
+    #[new]
+    #[pyo3(signature = (server=None, server_public_key=None, transport=None, protocol=None, shaping_profile=None))]
+    fn new(
+        server: Option<String>,
+        server_public_key: Option<String>,
+        transport: Option<&str>,
+        protocol: Option<&str>,
+        shaping_profile: Option<&str>,
+    ) -> PyResult<Self> {
+        let mut config = NooshdarooConfig::default();
+        config.socks.server_address = server;
+        if let Some(key) = server_public_key {
+            config.transport.get_or_insert_with(Default::default).remote_public_key = Some(key);
+        }
+        if let Some(transport) = transport {
+            config.socks.transport =
+                serde_json::from_value::<TransportType>(serde_json::Value::from(transport)).map_err(value_error)?;
+        }
+        if let Some(protocol) = protocol {
+            config.shapeshift.strategy = StrategyType::Fixed(FixedStrategy::new(protocol.into()));
+        }
+        if let Some(profile) = shaping_profile {
+            config.traffic_shaping = crate::profiles::load_profile(profile).map_err(value_error)?.traffic_shaping;
+        }
+        config.validate().map_err(value_error)?;
+        Ok(Self { config })
+    }
+
+    /// Parse a configuration in TOML
+    #[staticmethod]
+    fn from_toml(text: &str) -> PyResult<Self> {
+        let config: NooshdarooConfig = toml::from_str(text).map_err(value_error)?;
+        config.validate().map_err(value_error)?;
+        Ok(Self { config })
+    }
+
+    /// Load a configuration file
+    #[staticmethod]
+    fn from_file(path: std::path::PathBuf) -> PyResult<Self> {
+        let config = NooshdarooConfig::from_file(&path).map_err(value_error)?;
+        config.validate().map_err(value_error)?;
+        Ok(Self { config })
+    }
+
+    /// The configuration in TOML
+    fn to_toml(&self) -> PyResult<String> {
+        toml::to_string_pretty(&self.config).map_err(runtime_error)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Config(server={:?})", self.config.socks.server_address)
+    }
+}
+
+/// A client, with its own runtime
+#[pyclass(name = "Client", module = "nooshdaroo")]
+struct PyClient {
+    runtime: Runtime,
+    client: NooshdarooClient,
+    listener: Option<JoinHandle<()>>,
+    events: Mutex<broadcast::Receiver<ClientEvent>>,
+}
+
+#[pymethods]
+impl PyClient {
+    #[new]
+    fn new(config: &PyConfig) -> PyResult<Self> {
+        let runtime = new_runtime()?;
+        let client = {
+            let _guard = runtime.enter();
+            NooshdarooClient::new(config.config.clone()).map_err(runtime_error)?
+        };
+        let events = Mutex::new(client.events());
+        Ok(Self { runtime, client, listener: None, events })
+    }
+
+    /// Protocol new tunnels use
+    fn current_protocol(&self) -> String {
+        self.runtime.block_on(self.client.current_protocol()).to_string()
+    }
+
+    /// Switch to `protocol`, overriding the strategy
+    fn set_protocol(&self, protocol: &str) -> PyResult<()> {
+        self.runtime.block_on(self.client.set_protocol(protocol.into())).map_err(value_error)
+    }
+
+    /// Rotate to the next protocol of the strategy
+    fn rotate(&self) -> PyResult<()> {
+        self.runtime.block_on(self.client.rotate()).map_err(runtime_error)
+    }
+
+    /// Statistics as a dict
+    fn stats(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let stats = self.runtime.block_on(self.client.stats());
+        to_python(py, &stats.to_json())
+    }
+
+    /// Start the SOCKS5 listener of `[socks]`, tunneling to its server
+    fn start(&mut self, py: Python<'_>) -> PyResult<()> {
+        if self.listener.as_ref().is_some_and(|listener| !listener.is_finished()) {
+            return Err(runtime_error("The client is already running"));
+        }
+        let (runtime, client) = (&self.runtime, &self.client);
+        let listener = py.allow_threads(|| runtime.block_on(client.socks_listener())).map_err(runtime_error)?;
+        self.listener = Some(self.runtime.spawn(async move {
+            if let Err(e) = listener.listen().await {
+                log::error!("SOCKS5 listener failed: {}", e);
+            }
+        }));
+        Ok(())
+    }
+
+    /// Stop the SOCKS5 listener; tunnels already open are not interrupted
+    fn stop(&mut self) -> PyResult<()> {
+        match self.listener.take() {
+            Some(listener) => {
+                listener.abort();
+                Ok(())
+            }
+            None => Err(runtime_error("The client is not running")),
+        }
+    }
+
+    /// Events since the client was created or last polled, as dicts with a
+    /// "type" key
+    fn poll_events(&self, py: Python<'_>) -> PyResult<Vec<PyObject>> {
+        let mut receiver = self.events.lock().unwrap();
+        let mut events = Vec::new();
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => events.push(to_python(py, &event)?),
+                Err(broadcast::error::TryRecvError::Lagged(missed)) => {
+                    log::warn!("{} client events dropped before they were polled", missed);
+                }
+                Err(_) => return Ok(events),
+            }
+        }
+    }
+}
+
+/// A server's protocol library
+#[pyclass(name = "Server", module = "nooshdaroo")]
+struct PyServer {
+    server: NooshdarooServer,
+}
+
+#[pymethods]
+impl PyServer {
+    #[new]
+    fn new(config: &PyConfig) -> PyResult<Self> {
+        Ok(Self { server: NooshdarooServer::new(config.config.clone()).map_err(runtime_error)? })
+    }
+
+    /// IDs of the protocols the server can speak
+    fn protocols(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.server.library().all().iter().map(|meta| meta.id.to_string()).collect();
+        ids.sort();
+        ids
+    }
+}
+
+/// A new Noise keypair as (private key, public key), base64
+#[pyfunction]
+fn generate_keypair() -> PyResult<(String, String)> {
+    let keypair = crate::noise_transport::NoiseKeypair::generate().map_err(runtime_error)?;
+    Ok((keypair.private_key_base64(), keypair.public_key_base64()))
+}
+
+/// Test every protocol's standard port on `server` (an address), best
+/// paths first, as dicts
+#[pyfunction]
+#[pyo3(signature = (server, protocol_dir=None))]
+fn test_paths(py: Python<'_>, server: &str, protocol_dir: Option<std::path::PathBuf>) -> PyResult<PyObject> {
+    let protocol_dir = protocol_dir.unwrap_or_else(|| NooshdarooConfig::default().protocol_dir);
+    let library = Arc::new(crate::ProtocolLibrary::load(&protocol_dir).map_err(value_error)?);
+    let results = py.allow_threads(|| {
+        let runtime = new_runtime()?;
+        let tester = crate::PathTester::new(library);
+        Ok::<_, PyErr>(runtime.block_on(tester.test_all_paths(server, &crate::MultiPortConfig::default())))
+    })?;
+    to_python(py, &results)
+}
+
+#[pymodule]
+fn nooshdaroo(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    m.add_class::<PyConfig>()?;
+    m.add_class::<PyClient>()?;
+    m.add_class::<PyServer>()?;
+    m.add_function(wrap_pyfunction!(generate_keypair, m)?)?;
+    m.add_function(wrap_pyfunction!(test_paths, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_python_module() {
+        pyo3::append_to_inittab!(nooshdaroo);
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let run = |code: &str| py.run_bound(code, None, None);
+            run(r#"
+import nooshdaroo
+private_key, public_key = nooshdaroo.generate_keypair()
+config = nooshdaroo.Config(server="192.0.2.7:443", server_public_key=public_key, transport="kcp", protocol="https")
+assert 'server_address = "192.0.2.7:443"' in config.to_toml()
+client = nooshdaroo.Client(config)
+assert client.current_protocol() == "https"
+assert client.stats()["current_protocol"] == "https"
+assert client.poll_events() == []
+assert "https" in nooshdaroo.Server(config).protocols()
+"#)
+            .unwrap();
+            assert!(run("import nooshdaroo\nnooshdaroo.Config(transport='carrier-pigeon')").is_err());
+        });
+    }
+}
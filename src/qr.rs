@@ -0,0 +1,660 @@
+//! QR code rendering for client onboarding
+//!
+//! A small byte-mode QR encoder (versions 1-40, error correction L or M) so
+//! `genconf` can show a client configuration as a scannable code, either in
+//! the terminal or as a PNG, without pulling in an image stack.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Error correction level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcLevel {
+    /// Recovers ~7% of codewords, maximum capacity
+    Low,
+    /// Recovers ~15% of codewords
+    Medium,
+}
+
+impl EcLevel {
+    fn index(self) -> usize {
+        match self {
+            EcLevel::Low => 0,
+            EcLevel::Medium => 1,
+        }
+    }
+
+    fn format_bits(self) -> u32 {
+        match self {
+            EcLevel::Low => 1,
+            EcLevel::Medium => 0,
+        }
+    }
+}
+
+/// Error correction codewords per block, indexed by level then version
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 2] = [
+    [
+        0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28,
+        30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28,
+        28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    ],
+];
+
+/// Number of error correction blocks, indexed by level then version
+const NUM_ECC_BLOCKS: [[u8; 41]; 2] = [
+    [
+        0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13,
+        14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25,
+    ],
+    [
+        0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21,
+        23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+    ],
+];
+
+/// Modules of light border required around the symbol
+const QUIET_ZONE: usize = 4;
+
+/// An encoded QR symbol
+#[derive(Debug, Clone)]
+pub struct QrCode {
+    version: usize,
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode `data` in byte mode using the smallest version that fits
+    pub fn encode(data: &[u8], level: EcLevel) -> Result<Self, String> {
+        let version = (1..=40)
+            .find(|&v| data_bits_needed(data.len(), v) <= data_codewords(v, level) * 8)
+            .ok_or_else(|| {
+                format!(
+                    "{} bytes is too large for a QR code (max {})",
+                    data.len(),
+                    data_codewords(40, level) - 3
+                )
+            })?;
+
+        let codewords = add_error_correction(&encode_data(data, version, level), version, level);
+
+        let size = version * 4 + 17;
+        let mut qr = Self {
+            version,
+            size,
+            modules: vec![false; size * size],
+            function: vec![false; size * size],
+        };
+        qr.draw_function_patterns();
+        qr.draw_codewords(&codewords);
+
+        let mut best: Option<(usize, u32)> = None;
+        for mask in 0..8 {
+            qr.apply_mask(mask);
+            qr.draw_format_bits(level, mask);
+            let penalty = qr.penalty();
+            if best.is_none_or(|(_, p)| penalty < p) {
+                best = Some((mask, penalty));
+            }
+            qr.apply_mask(mask);
+        }
+        let (mask, _) = best.unwrap();
+        qr.apply_mask(mask);
+        qr.draw_format_bits(level, mask);
+        Ok(qr)
+    }
+
+    /// Symbol version (1-40)
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Width and height in modules, excluding the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module at column `x`, row `y` is dark
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        x < self.size && y < self.size && self.modules[y * self.size + x]
+    }
+
+    /// Render using Unicode half blocks, two module rows per line.
+    ///
+    /// Dark modules are drawn as blank cells, so the code reads correctly on
+    /// terminals with a dark background.
+    pub fn to_terminal(&self) -> String {
+        let total = self.size + 2 * QUIET_ZONE;
+        let dark = |x: usize, y: usize| {
+            x >= QUIET_ZONE
+                && y >= QUIET_ZONE
+                && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE)
+        };
+
+        let mut out = String::new();
+        for y in (0..total).step_by(2) {
+            for x in 0..total {
+                let top = dark(x, y);
+                let bottom = y + 1 < total && dark(x, y + 1);
+                out.push(match (top, bottom) {
+                    (false, false) => '█',
+                    (false, true) => '▀',
+                    (true, false) => '▄',
+                    (true, true) => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Encode as an 8-bit grayscale PNG with `scale` pixels per module
+    pub fn to_png(&self, scale: usize) -> Vec<u8> {
+        let scale = scale.max(1);
+        let width = (self.size + 2 * QUIET_ZONE) * scale;
+
+        let mut raw = Vec::with_capacity((width + 1) * width);
+        for py in 0..width {
+            raw.push(0); // filter type: none
+            for px in 0..width {
+                let (x, y) = (px / scale, py / scale);
+                let dark = x >= QUIET_ZONE
+                    && y >= QUIET_ZONE
+                    && self.is_dark(x - QUIET_ZONE, y - QUIET_ZONE);
+                raw.push(if dark { 0x00 } else { 0xff });
+            }
+        }
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, 0, 0, 0, 0]); // 8-bit grayscale, no interlace
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &ihdr);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    /// Write the PNG rendering to `path`
+    pub fn save_png(&self, path: &Path, scale: usize) -> io::Result<()> {
+        std::fs::File::create(path)?.write_all(&self.to_png(scale))
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        let idx = y * self.size + x;
+        self.modules[idx] = dark;
+        self.function[idx] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        self.draw_finder(3, 3);
+        self.draw_finder(size - 4, 3);
+        self.draw_finder(3, size - 4);
+
+        let positions = alignment_positions(self.version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &x) in positions.iter().enumerate() {
+            for (j, &y) in positions.iter().enumerate() {
+                let overlaps_finder = (i == 0 && (j == 0 || j == last)) || (i == last && j == 0);
+                if !overlaps_finder {
+                    self.draw_alignment(x, y);
+                }
+            }
+        }
+
+        // Reserve format areas; real bits are drawn once the mask is chosen
+        self.draw_format_bits(EcLevel::Low, 0);
+        self.draw_version_bits();
+    }
+
+    fn draw_finder(&mut self, cx: usize, cy: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                if x < 0 || y < 0 || x >= self.size as i32 || y >= self.size as i32 {
+                    continue;
+                }
+                let dist = dx.abs().max(dy.abs());
+                self.set_function(x as usize, y as usize, dist != 2 && dist != 4);
+            }
+        }
+    }
+
+    fn draw_alignment(&mut self, cx: usize, cy: usize) {
+        for dy in -2i32..=2 {
+            for dx in -2i32..=2 {
+                let dist = dx.abs().max(dy.abs());
+                self.set_function((cx as i32 + dx) as usize, (cy as i32 + dy) as usize, dist != 1);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, level: EcLevel, mask: usize) {
+        let data = (level.format_bits() << 3) | mask as u32;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    fn draw_version_bits(&mut self) {
+        if self.version < 7 {
+            return;
+        }
+        let mut rem = self.version as u32;
+        for _ in 0..12 {
+            rem = (rem << 1) ^ ((rem >> 11) * 0x1f25);
+        }
+        let bits = ((self.version as u32) << 12) | rem;
+        for i in 0..18 {
+            let dark = (bits >> i) & 1 != 0;
+            let a = self.size - 11 + i % 3;
+            let b = i / 3;
+            self.set_function(a, b, dark);
+            self.set_function(b, a, dark);
+        }
+    }
+
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let total_bits = codewords.len() * 8;
+        let mut i = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vert in 0..size {
+                for j in 0..2 {
+                    let x = (right - j) as usize;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vert } else { vert };
+                    let idx = y * size + x;
+                    if !self.function[idx] && i < total_bits {
+                        self.modules[idx] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: usize) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let idx = y * self.size + x;
+                if invert && !self.function[idx] {
+                    self.modules[idx] = !self.modules[idx];
+                }
+            }
+        }
+    }
+
+    /// Mask penalty per the four rules of ISO/IEC 18004 section 7.8.3
+    fn penalty(&self) -> u32 {
+        let size = self.size;
+        let mut penalty = 0;
+
+        // Rules 1 and 3 along rows and columns
+        for transpose in [false, true] {
+            for a in 0..size {
+                let line: Vec<bool> = (0..size)
+                    .map(|b| if transpose { self.is_dark(a, b) } else { self.is_dark(b, a) })
+                    .collect();
+
+                let mut run = 1;
+                for i in 1..=size {
+                    if i < size && line[i] == line[i - 1] {
+                        run += 1;
+                    } else {
+                        if run >= 5 {
+                            penalty += run - 2;
+                        }
+                        run = 1;
+                    }
+                }
+
+                const FINDER: [bool; 7] = [true, false, true, true, true, false, true];
+                for i in 0..size.saturating_sub(6) {
+                    if line[i..i + 7] != FINDER {
+                        continue;
+                    }
+                    let light_before = i >= 4 && line[i - 4..i].iter().all(|d| !d);
+                    let light_after = i + 11 <= size && line[i + 7..i + 11].iter().all(|d| !d);
+                    if light_before || light_after {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+
+        // Rule 2: 2x2 blocks of one color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let c = self.is_dark(x, y);
+                if c == self.is_dark(x + 1, y) && c == self.is_dark(x, y + 1) && c == self.is_dark(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+
+        // Rule 4: dark/light balance
+        let dark = self.modules.iter().filter(|&&d| d).count();
+        let total = size * size;
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty += deviation.div_ceil(total) * 10 - 10;
+
+        penalty as u32
+    }
+}
+
+/// Bits needed to encode `len` bytes in byte mode at `version`
+fn data_bits_needed(len: usize, version: usize) -> usize {
+    let count_bits = if version < 10 { 8 } else { 16 };
+    4 + count_bits + len * 8
+}
+
+/// Number of modules available for data and error correction
+fn raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let num_align = version / 7 + 2;
+        result -= (25 * num_align - 10) * num_align - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn data_codewords(version: usize, level: EcLevel) -> usize {
+    let l = level.index();
+    raw_data_modules(version) / 8
+        - ECC_CODEWORDS_PER_BLOCK[l][version] as usize * NUM_ECC_BLOCKS[l][version] as usize
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let num_align = version / 7 + 2;
+    let size = version * 4 + 17;
+    let step = if version == 32 {
+        26
+    } else {
+        (version * 4 + num_align * 2 + 1) / (num_align * 2 - 2) * 2
+    };
+    let mut positions = vec![6];
+    positions.extend((0..num_align - 1).rev().map(|i| size - 7 - i * step));
+    positions
+}
+
+/// Byte-mode segment, terminator and padding filling the data capacity
+fn encode_data(data: &[u8], version: usize, level: EcLevel) -> Vec<u8> {
+    let capacity = data_codewords(version, level) * 8;
+    let mut bits = BitBuffer::default();
+    bits.push(0b0100, 4);
+    bits.push(data.len() as u32, if version < 10 { 8 } else { 16 });
+    for &byte in data {
+        bits.push(byte as u32, 8);
+    }
+    bits.push(0, (capacity - bits.len).min(4));
+    bits.push(0, (8 - bits.len % 8) % 8);
+
+    let mut bytes = bits.bytes;
+    for pad in [0xec, 0x11].into_iter().cycle() {
+        if bytes.len() * 8 >= capacity {
+            break;
+        }
+        bytes.push(pad);
+    }
+    bytes
+}
+
+/// Split data into blocks, append Reed-Solomon codewords and interleave
+fn add_error_correction(data: &[u8], version: usize, level: EcLevel) -> Vec<u8> {
+    let l = level.index();
+    let num_blocks = NUM_ECC_BLOCKS[l][version] as usize;
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[l][version] as usize;
+    let raw_codewords = raw_data_modules(version) / 8;
+    let num_short = num_blocks - raw_codewords % num_blocks;
+    let short_len = raw_codewords / num_blocks;
+
+    let divisor = rs_divisor(ecc_len);
+    let mut blocks = Vec::with_capacity(num_blocks);
+    let mut offset = 0;
+    for i in 0..num_blocks {
+        let data_len = short_len - ecc_len + usize::from(i >= num_short);
+        let block = &data[offset..offset + data_len];
+        offset += data_len;
+        blocks.push((block.to_vec(), rs_remainder(block, &divisor)));
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    let max_data = short_len - ecc_len + 1;
+    for i in 0..max_data {
+        for (block, _) in &blocks {
+            if let Some(&b) = block.get(i) {
+                result.push(b);
+            }
+        }
+    }
+    for i in 0..ecc_len {
+        for (_, ecc) in &blocks {
+            result.push(ecc[i]);
+        }
+    }
+    result
+}
+
+/// Multiply in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_mul(mut x: u8, mut y: u8) -> u8 {
+    let mut z = 0u8;
+    while y != 0 {
+        if y & 1 != 0 {
+            z ^= x;
+        }
+        let carry = x & 0x80 != 0;
+        x <<= 1;
+        if carry {
+            x ^= 0x1d;
+        }
+        y >>= 1;
+    }
+    z
+}
+
+/// Generator polynomial coefficients (leading 1 omitted) for `degree`
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_mul(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_mul(root, 0x02);
+    }
+    result
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &b in data {
+        let factor = b ^ result.remove(0);
+        result.push(0);
+        for (r, &d) in result.iter_mut().zip(divisor) {
+            *r ^= gf_mul(d, factor);
+        }
+    }
+    result
+}
+
+#[derive(Default)]
+struct BitBuffer {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitBuffer {
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 != 0 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Zlib stream using uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut chunks = data.chunks(0xffff).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reed_solomon_reference() {
+        // "HELLO WORLD" 1-M data codewords and their published ECC bytes
+        let data = [
+            0x20, 0x5b, 0x0b, 0x78, 0xd1, 0x72, 0xdc, 0x4d, 0x43, 0x40, 0xec, 0x11, 0xec, 0x11,
+            0xec, 0x11,
+        ];
+        assert_eq!(
+            rs_remainder(&data, &rs_divisor(10)),
+            vec![0xc4, 0x23, 0x27, 0x77, 0xeb, 0xd7, 0xe7, 0xe2, 0x5d, 0x17]
+        );
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_version_selection_and_structure() {
+        let small = QrCode::encode(b"nooshdaroo", EcLevel::Medium).unwrap();
+        assert_eq!(small.version(), 1);
+        assert_eq!(small.size(), 21);
+        // Finder pattern corners and the always-dark module
+        assert!(small.is_dark(0, 0) && small.is_dark(20, 0) && small.is_dark(0, 20));
+        assert!(!small.is_dark(7, 7));
+        assert!(small.is_dark(8, 13));
+
+        let large = QrCode::encode(&[b'x'; 1000], EcLevel::Medium).unwrap();
+        assert!(large.version() >= 7);
+        assert!(QrCode::encode(&[0; 3000], EcLevel::Low).is_err());
+
+        for version in 1..=40 {
+            assert!(data_codewords(version, EcLevel::Medium) < data_codewords(version, EcLevel::Low));
+        }
+        assert_eq!(data_codewords(40, EcLevel::Low), 2956);
+        assert_eq!(data_codewords(40, EcLevel::Medium), 2334);
+    }
+
+    #[test]
+    fn test_renderings() {
+        let qr = QrCode::encode(b"nooshdaroo", EcLevel::Low).unwrap();
+        let terminal = qr.to_terminal();
+        assert_eq!(terminal.lines().count(), (21 + 2 * QUIET_ZONE).div_ceil(2));
+
+        let png = qr.to_png(4);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), (21 + 8) * 4);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}
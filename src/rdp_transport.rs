@@ -0,0 +1,225 @@
+//! Tunnel connections opened as RDP sessions
+//!
+//! Remote Desktop to cloud hosts is routine on corporate networks, and its
+//! sessions are long-lived and carry a lot of data. A Windows client opens
+//! one with an X.224 Connection Request in a TPKT header, asking for TLS or
+//! CredSSP security; once the server confirms, everything else — CredSSP,
+//! the MCS connection sequence and the virtual channel traffic — runs
+//! inside TLS. This transport performs that negotiation before the tunnel,
+//! which then takes the place of the TLS session. Pair it with
+//! `tls_handshake` so the first tunnel bytes are the TLS handshake the
+//! negotiation promises.
+//!
+//! ```toml
+//! [socks]
+//! transport = "rdp"
+//! rdp_username = "jsmith"
+//!
+//! [server]
+//! transport = "rdp"
+//! ```
+//!
+//! A scanner asking for legacy RDP security gets the negotiation failure a
+//! server requiring Network Level Authentication sends.
+
+use rand::Rng;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// TPKT version (RFC 1006)
+const TPKT_VERSION: u8 = 3;
+
+/// X.224 TPDU codes
+const X224_CONNECTION_REQUEST: u8 = 0xe0;
+const X224_CONNECTION_CONFIRM: u8 = 0xd0;
+
+/// RDP negotiation structure types ([MS-RDPBCGR] 2.2.1.1.1, 2.2.1.2.1, 2.2.1.2.2)
+const NEG_REQUEST: u8 = 0x01;
+const NEG_RESPONSE: u8 = 0x02;
+const NEG_FAILURE: u8 = 0x03;
+
+/// Security protocols
+const PROTOCOL_SSL: u32 = 0x01;
+const PROTOCOL_HYBRID: u32 = 0x02;
+const PROTOCOL_HYBRID_EX: u32 = 0x08;
+
+/// Failure code sent when only legacy RDP security is offered
+const HYBRID_REQUIRED_BY_SERVER: u32 = 0x05;
+
+/// Response flags of a current Windows server: extended client data,
+/// graphics pipeline, restricted admin and redirected authentication
+const NEG_RESPONSE_FLAGS: u8 = 0x1f;
+
+/// Largest TPKT accepted during negotiation
+const MAX_TPKT: usize = 1024;
+
+/// mstsc truncates the user name in the routing cookie to this many bytes
+const MAX_COOKIE_NAME: usize = 9;
+
+/// Send the Connection Request, introducing the user as `username` if
+/// given, and wait for the server's confirmation; the tunnel follows
+pub async fn connect<S>(stream: &mut S, username: Option<&str>) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&connection_request(username)).await?;
+    stream.flush().await?;
+
+    let tpdu = read_tpkt(stream).await?;
+    let negotiation = parse_tpdu(&tpdu, X224_CONNECTION_CONFIRM)?;
+    match negotiation {
+        &[NEG_RESPONSE, _, 8, 0, a, b, c, d] => {
+            let protocol = u32::from_le_bytes([a, b, c, d]);
+            if protocol & (PROTOCOL_SSL | PROTOCOL_HYBRID | PROTOCOL_HYBRID_EX) == 0 {
+                return Err(invalid(format!("RDP server selected protocol {:#x}", protocol)));
+            }
+            Ok(())
+        }
+        [NEG_FAILURE, ..] => Err(invalid("RDP server refused the negotiation".to_string())),
+        _ => Err(invalid("Malformed RDP negotiation response".to_string())),
+    }
+}
+
+/// Answer an accepted `stream`'s Connection Request; `Ok` once TLS-based
+/// security is agreed, so the tunnel follows
+pub async fn accept<S>(stream: &mut S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tpdu = read_tpkt(stream).await?;
+    let request = parse_tpdu(&tpdu, X224_CONNECTION_REQUEST)?;
+
+    // Skip the routing cookie or token, a CRLF-terminated line
+    let negotiation = match request.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if request.starts_with(b"Cookie:") => &request[end + 2..],
+        _ => request,
+    };
+    let requested = match negotiation {
+        [NEG_REQUEST, _, 8, 0, protocols @ ..] if protocols.len() >= 4 => {
+            u32::from_le_bytes([protocols[0], protocols[1], protocols[2], protocols[3]])
+        }
+        _ => 0,
+    };
+
+    let selected = if requested & PROTOCOL_HYBRID != 0 {
+        PROTOCOL_HYBRID
+    } else if requested & PROTOCOL_SSL != 0 {
+        PROTOCOL_SSL
+    } else {
+        stream.write_all(&connection_confirm(NEG_FAILURE, 0, HYBRID_REQUIRED_BY_SERVER)).await?;
+        stream.flush().await?;
+        return Err(invalid("RDP client offered only legacy security".to_string()));
+    };
+    stream.write_all(&connection_confirm(NEG_RESPONSE, NEG_RESPONSE_FLAGS, selected)).await?;
+    stream.flush().await
+}
+
+/// Connection Request asking for TLS, CredSSP or CredSSP with early user
+/// authorization, as mstsc does
+fn connection_request(username: Option<&str>) -> Vec<u8> {
+    let mut variable = Vec::new();
+    if let Some(name) = username {
+        let name: String = name.chars().take(MAX_COOKIE_NAME).collect();
+        variable.extend_from_slice(format!("Cookie: mstshash={}\r\n", name).as_bytes());
+    }
+    variable.extend_from_slice(&negotiation(NEG_REQUEST, 0, PROTOCOL_SSL | PROTOCOL_HYBRID | PROTOCOL_HYBRID_EX));
+    tpkt(X224_CONNECTION_REQUEST, [0, 0], &variable)
+}
+
+fn connection_confirm(kind: u8, flags: u8, value: u32) -> Vec<u8> {
+    let source_ref = rand::thread_rng().gen::<[u8; 2]>();
+    tpkt(X224_CONNECTION_CONFIRM, source_ref, &negotiation(kind, flags, value))
+}
+
+/// RDP_NEG_REQ, RDP_NEG_RSP or RDP_NEG_FAILURE
+fn negotiation(kind: u8, flags: u8, value: u32) -> [u8; 8] {
+    let mut structure = [kind, flags, 8, 0, 0, 0, 0, 0];
+    structure[4..].copy_from_slice(&value.to_le_bytes());
+    structure
+}
+
+/// TPKT carrying a connection TPDU with `variable` after its fixed part
+fn tpkt(code: u8, source_ref: [u8; 2], variable: &[u8]) -> Vec<u8> {
+    let length = 4 + 7 + variable.len();
+    let mut packet = Vec::with_capacity(length);
+    packet.extend_from_slice(&[TPKT_VERSION, 0]);
+    packet.extend_from_slice(&(length as u16).to_be_bytes());
+    // Length indicator counts the header after itself
+    packet.push((6 + variable.len()) as u8);
+    packet.push(code);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&source_ref);
+    packet.push(0);
+    packet.extend_from_slice(variable);
+    packet
+}
+
+/// Body of one TPKT
+async fn read_tpkt<S>(stream: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != TPKT_VERSION {
+        return Err(invalid(format!("Not a TPKT header (version {})", header[0])));
+    }
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if !(4 + 7..=MAX_TPKT).contains(&length) {
+        return Err(invalid(format!("Bad TPKT length {}", length)));
+    }
+    let mut body = vec![0u8; length - 4];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Variable part of a connection TPDU with `code`
+fn parse_tpdu(tpdu: &[u8], code: u8) -> io::Result<&[u8]> {
+    let indicator = tpdu[0] as usize;
+    if indicator + 1 != tpdu.len() || tpdu[1] != code {
+        return Err(invalid(format!("Unexpected X.224 TPDU {:#04x}", tpdu[1])));
+    }
+    Ok(&tpdu[7..])
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_negotiation_then_tunnel() {
+        let request = connection_request(Some("administrator"));
+        assert_eq!(&request[..4], &[3, 0, 0, request.len() as u8]);
+        assert!(request.windows(26).any(|w| w == b"Cookie: mstshash=administr"));
+
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let server_handle = tokio::spawn(async move {
+            accept(&mut server).await.unwrap();
+            let mut buf = [0u8; 6];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+        });
+        connect(&mut client, Some("jsmith")).await.unwrap();
+        client.write_all(b"tunnel").await.unwrap();
+        let mut buf = [0u8; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tunnel");
+        server_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_security_refused() {
+        let (mut scanner, mut server) = tokio::io::duplex(4096);
+        let server_handle = tokio::spawn(async move { accept(&mut server).await });
+
+        scanner.write_all(&tpkt(X224_CONNECTION_REQUEST, [0, 0], &negotiation(NEG_REQUEST, 0, 0))).await.unwrap();
+        let tpdu = read_tpkt(&mut scanner).await.unwrap();
+        let reply = parse_tpdu(&tpdu, X224_CONNECTION_CONFIRM).unwrap();
+        assert_eq!(reply, negotiation(NEG_FAILURE, 0, HYBRID_REQUIRED_BY_SERVER));
+        assert!(server_handle.await.unwrap().is_err());
+    }
+}
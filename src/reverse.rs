@@ -0,0 +1,318 @@
+//! Reverse tunnels: services behind NAT exposed through a server
+//!
+//! A machine that cannot accept connections runs `nooshdaroo agent`, which
+//! dials the server like any client and keeps a control tunnel open. Over
+//! it the agent registers its services, and the server listens on a public
+//! port for each:
+//!
+//! 1. The agent opens a tunnel to [`CONTROL_TARGET`] and sends
+//!    [`ControlMessage::Register`] with the shared secret; the server binds
+//!    the requested ports and answers [`ControlMessage::Registered`]
+//! 2. For each connection to a public port the server keeps it pending and
+//!    sends [`ControlMessage::Connect`] with a random id
+//! 3. The agent opens a tunnel to [`data_target`] for that id, which the
+//!    server pairs with the pending connection, and relays it to the
+//!    service's local address
+//!
+//! Control messages are JSON in Noise messages, and every tunnel carries
+//! the protocol emulation of an ordinary one. The agent sends
+//! [`ControlMessage::Heartbeat`] every [`HEARTBEAT_INTERVAL`]; the server
+//! closes the public ports when the control tunnel ends or goes quiet.
+
+use crate::config::ReverseServerConfig;
+use crate::noise_transport::NoiseTransport;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Target of the control tunnel; the server never opens it
+pub const CONTROL_TARGET: &str = "reverse-control.invalid:0";
+
+/// Suffix of data tunnel targets, which start with the connection id
+const DATA_SUFFIX: &str = ".reverse-data.invalid:0";
+
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The server closes control tunnels without a message for this long
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How long a public connection waits for the agent's data tunnel
+const PENDING_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceRequest {
+    pub name: String,
+    pub remote_port: u16,
+}
+
+/// Message on the control tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlMessage {
+    /// Agent: expose these services
+    Register { secret: String, services: Vec<ServiceRequest> },
+    /// Server: the services are listening
+    Registered,
+    /// Server: registration failed
+    Error { message: String },
+    /// Server: open a data tunnel for connection `id` to `service`
+    Connect { service: String, id: String },
+    /// Agent: still here
+    Heartbeat,
+}
+
+pub async fn send<S>(noise: &mut NoiseTransport, stream: &mut S, message: &ControlMessage) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    noise.write(stream, &serde_json::to_vec(message)?).await
+}
+
+pub async fn recv<S>(noise: &mut NoiseTransport, stream: &mut S) -> anyhow::Result<ControlMessage>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Ok(serde_json::from_slice(&noise.read(stream).await?)?)
+}
+
+/// Wait until a message starts arriving on a control tunnel, returning
+/// false once it has closed. Nothing is consumed, so unlike a read the wait
+/// can be abandoned to send a message instead.
+pub async fn readable<S>(stream: &mut BufReader<S>) -> std::io::Result<bool>
+where
+    S: AsyncRead + Unpin,
+{
+    Ok(!stream.fill_buf().await?.is_empty())
+}
+
+/// Target of the data tunnel for connection `id`
+pub fn data_target(id: &str) -> String {
+    format!("{}{}", id, DATA_SUFFIX)
+}
+
+/// Connection id of a data tunnel target
+pub fn parse_data_target(target: &str) -> Option<&str> {
+    target.strip_suffix(DATA_SUFFIX).filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Whether `target` is a reverse tunnel's rather than a destination
+pub fn is_reverse_target(target: &str) -> bool {
+    target == CONTROL_TARGET || parse_data_target(target).is_some()
+}
+
+/// Ports agents may claim
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRanges(Vec<(u16, u16)>);
+
+impl PortRanges {
+    pub fn parse(ports: &[String]) -> Result<Self, String> {
+        if ports.is_empty() {
+            return Err("no ports listed".to_string());
+        }
+        ports
+            .iter()
+            .map(|range| crate::acl::parse_ports(range).ok_or_else(|| format!("invalid port or range {:?}", range)))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|&(first, last)| (first..=last).contains(&port))
+    }
+}
+
+/// Public connections waiting for their data tunnel, by id
+static PENDING: Mutex<Option<HashMap<String, (Instant, TcpStream)>>> = Mutex::new(None);
+
+fn add_pending(id: String, stream: TcpStream) {
+    let mut pending = PENDING.lock().unwrap();
+    let pending = pending.get_or_insert_with(HashMap::new);
+    pending.retain(|_, (since, _)| since.elapsed() < PENDING_TIMEOUT);
+    pending.insert(id, (Instant::now(), stream));
+}
+
+/// Take the public connection waiting for the data tunnel `id`
+pub fn take_pending(id: &str) -> Option<TcpStream> {
+    let (since, stream) = PENDING.lock().unwrap().as_mut()?.remove(id)?;
+    (since.elapsed() < PENDING_TIMEOUT).then_some(stream)
+}
+
+fn secrets_match(offered: &str, expected: &str) -> bool {
+    let (offered, expected) = (offered.as_bytes(), expected.as_bytes());
+    offered.len() == expected.len() && offered.iter().zip(expected).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Bind the public port of every service, or say why not
+fn bind_services(config: &ReverseServerConfig, services: &[ServiceRequest]) -> Result<Vec<(String, StdTcpListener)>, String> {
+    let ranges = PortRanges::parse(&config.ports)?;
+    services
+        .iter()
+        .map(|service| {
+            if !ranges.contains(service.remote_port) {
+                return Err(format!("port {} is not open to agents", service.remote_port));
+            }
+            let listener = StdTcpListener::bind(SocketAddr::new(config.bind, service.remote_port))
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                .map_err(|e| format!("cannot listen on port {}: {}", service.remote_port, e))?;
+            Ok((service.name.clone(), listener))
+        })
+        .collect()
+}
+
+/// Server side of a control tunnel from `peer`: register the agent's
+/// services and announce connections to them until the tunnel ends
+pub async fn serve_control<S>(
+    noise: &mut NoiseTransport,
+    stream: &mut S,
+    config: &ReverseServerConfig,
+    peer: SocketAddr,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let services = match tokio::time::timeout(CONTROL_TIMEOUT, recv(noise, stream)).await?? {
+        ControlMessage::Register { secret, services } if secrets_match(&secret, &config.secret) => services,
+        ControlMessage::Register { .. } => {
+            send(noise, stream, &ControlMessage::Error { message: "wrong secret".to_string() }).await?;
+            anyhow::bail!("Agent {} sent the wrong secret", peer);
+        }
+        other => anyhow::bail!("Agent {} sent {:?} before registering", peer, other),
+    };
+    let listeners = match bind_services(config, &services) {
+        Ok(listeners) => listeners,
+        Err(message) => {
+            send(noise, stream, &ControlMessage::Error { message: message.clone() }).await?;
+            anyhow::bail!("Registration of agent {} failed: {}", peer, message);
+        }
+    };
+
+    // Accept on every public port, ending with the control tunnel
+    let (accepted_tx, mut accepted) = mpsc::channel(16);
+    let mut acceptors = Vec::new();
+    for (name, listener) in listeners {
+        let listener = TcpListener::from_std(listener)?;
+        log::info!("Agent {} exposes {} on {}", peer, name, listener.local_addr()?);
+        let accepted_tx = accepted_tx.clone();
+        acceptors.push(tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((connection, _)) => {
+                        if accepted_tx.send((name.clone(), connection)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => log::warn!("Accept error on reverse port {}: {}", name, e),
+                }
+            }
+        }));
+    }
+    send(noise, stream, &ControlMessage::Registered).await?;
+
+    let mut stream = BufReader::new(stream);
+    let result = loop {
+        tokio::select! {
+            readable = tokio::time::timeout(CONTROL_TIMEOUT, readable(&mut stream)) => match readable {
+                Ok(Ok(true)) => match recv(noise, &mut stream).await {
+                    Ok(ControlMessage::Heartbeat) => {}
+                    Ok(other) => break Err(anyhow::anyhow!("Unexpected message from agent {}: {:?}", peer, other)),
+                    Err(_) => break Ok(()),
+                },
+                Ok(Ok(false) | Err(_)) => break Ok(()),
+                Err(_) => break Err(anyhow::anyhow!("Agent {} stopped sending heartbeats", peer)),
+            },
+            Some((service, connection)) = accepted.recv() => {
+                let id = hex::encode(rand::random::<[u8; 16]>());
+                add_pending(id.clone(), connection);
+                if let Err(e) = send(noise, &mut stream, &ControlMessage::Connect { service, id }).await {
+                    break Err(e);
+                }
+            }
+        }
+    };
+    for acceptor in acceptors {
+        acceptor.abort();
+    }
+    log::info!("Agent {} disconnected; its ports are closed", peer);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise_transport::{NoiseConfig, NoiseKeypair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_reverse_targets() {
+        let target = data_target("0a1b2c");
+        assert_eq!(parse_data_target(&target), Some("0a1b2c"));
+        assert!(is_reverse_target(&target));
+        assert!(is_reverse_target(CONTROL_TARGET));
+        assert_eq!(parse_data_target("example.com:443"), None);
+        assert_eq!(parse_data_target("evil/../x.reverse-data.invalid:0"), None);
+
+        let ranges = PortRanges::parse(&["2222".to_string(), "10000-10100".to_string()]).unwrap();
+        assert!(ranges.contains(2222) && ranges.contains(10050));
+        assert!(!ranges.contains(22));
+        assert!(PortRanges::parse(&[]).is_err());
+
+        let message: ControlMessage = serde_json::from_str(r#"{"type":"connect","service":"ssh","id":"ab"}"#).unwrap();
+        assert_eq!(message, ControlMessage::Connect { service: "ssh".to_string(), id: "ab".to_string() });
+    }
+
+    #[tokio::test]
+    async fn test_control_tunnel() {
+        let keypair = NoiseKeypair::generate().unwrap();
+        let server_config = NoiseConfig {
+            local_private_key: Some(keypair.private_key_base64()),
+            ..Default::default()
+        };
+        let client_config = NoiseConfig {
+            remote_public_key: Some(keypair.public_key_base64()),
+            ..Default::default()
+        };
+        let port = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let reverse = ReverseServerConfig {
+            secret: "0123456789abcdef".to_string(),
+            ports: vec![port.to_string()],
+            bind: "127.0.0.1".parse().unwrap(),
+        };
+
+        let (mut agent_stream, mut server_stream) = tokio::io::duplex(8192);
+        let server = tokio::spawn(async move {
+            let mut noise = NoiseTransport::server_handshake(&mut server_stream, &server_config, None).await.unwrap();
+            serve_control(&mut noise, &mut server_stream, &reverse, "192.0.2.1:5000".parse().unwrap()).await
+        });
+        let mut noise = NoiseTransport::client_handshake(&mut agent_stream, &client_config, None).await.unwrap();
+        let services = vec![ServiceRequest { name: "web".to_string(), remote_port: port }];
+        send(&mut noise, &mut agent_stream, &ControlMessage::Register { secret: "0123456789abcdef".to_string(), services })
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut noise, &mut agent_stream).await.unwrap(), ControlMessage::Registered);
+
+        // A public connection is announced and handed to its data tunnel
+        let mut public = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let ControlMessage::Connect { service, id } = recv(&mut noise, &mut agent_stream).await.unwrap() else {
+            panic!("expected a connection");
+        };
+        assert_eq!(service, "web");
+        let mut paired = take_pending(&id).unwrap();
+        assert!(take_pending(&id).is_none());
+        public.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        paired.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Closing the control tunnel closes the public port
+        drop(noise);
+        drop(agent_stream);
+        server.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+}
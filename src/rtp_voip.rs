@@ -0,0 +1,548 @@
+//! Voice call framing for KCP sessions
+//!
+//! Calls are everywhere, and a flow of small SRTP packets every 20 ms with
+//! a sender report every few seconds is about the most ordinary UDP there
+//! is. With `relay.voip_codec` set, `kcp` datagrams travel in the payloads
+//! of an SRTP stream paced and sized like the codec's: G.711 (`pcmu`,
+//! `pcma`) sends 160 bytes of audio per packet, Opus (`opus`) a variable
+//! 60–160 bytes while talking and a few bytes of comfort noise in the
+//! pauses. RTP and RTCP share the socket, as WebRTC multiplexes them.
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//!
+//! [server]
+//! transport = "kcp"
+//!
+//! [relay]
+//! voip_codec = "opus"
+//! ```
+//!
+//! Both ends must use the same codec. Each side picks its SSRC, initial
+//! sequence number and timestamp per call at random; the first packet of
+//! each talkspurt has the marker bit set, RTCP sender reports follow every
+//! 2.5–7.5 seconds and a BYE ends the call. Payloads hold datagram
+//! fragments, each behind `length (2, BE) | offset (2, BE)` masked with a
+//! digest of the RTP header so the payload looks encrypted throughout,
+//! followed by random fill and a random 80-bit authentication tag.
+//!
+//! One packet per frame leaves a call a few kilobytes per second: enough
+//! for interactive use, not for bulk transfers.
+
+use crate::config::{TurnConfig, VoipCodec};
+use crate::kcp_transport::{self, KcpListener, Socket};
+use async_trait::async_trait;
+use rand::Rng;
+use ring::digest;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use webrtc::util::Conn;
+
+/// Packetization time of every codec
+pub const PTIME: Duration = Duration::from_millis(20);
+
+const RTP_HEADER_SIZE: usize = 12;
+
+/// SRTP authentication tag of AES_CM_128_HMAC_SHA1_80
+const AUTH_TAG_SIZE: usize = 10;
+
+/// `length | offset` before each fragment
+const FRAGMENT_HEADER_SIZE: usize = 4;
+
+/// RTCP packet types (RFC 3550); RTCP shares the port (RFC 5761)
+const RTCP_SR: u8 = 200;
+const RTCP_SDES: u8 = 202;
+const RTCP_BYE: u8 = 203;
+
+/// Opus payload type in the offers of browsers
+const OPUS_PAYLOAD_TYPE: u8 = 111;
+
+/// Average interval between sender reports
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The call goes on for this long after the last tunnel datagram
+pub const CALL_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Bytes of datagrams queued per call, about two seconds of G.711; more
+/// are dropped like a full socket buffer and KCP retransmits them
+const QUEUE_BYTES: usize = 16 * 1024;
+
+/// Received datagrams waiting for `recv_from`
+const RECEIVE_QUEUE: usize = 1024;
+
+/// Open a KCP session to `server_addr` carried in a call with `codec`,
+/// through a relay allocated on `turn` if given
+pub async fn connect(server_addr: SocketAddr, codec: VoipCodec, turn: Option<&TurnConfig>) -> io::Result<DuplexStream> {
+    match turn {
+        Some(turn) => {
+            let (relay, client) = crate::turn_relay::allocate(turn).await?;
+            Ok(kcp_transport::connect_over(Arc::new(RtpConn::new(relay, codec)), server_addr, client))
+        }
+        None => {
+            let udp = Arc::new(UdpSocket::bind(kcp_transport::any_local_addr(server_addr)).await?);
+            Ok(kcp_transport::connect_over(Arc::new(RtpConn::new(udp, codec)), server_addr, ()))
+        }
+    }
+}
+
+/// Accept KCP sessions on `addr` carried in calls with `codec`
+pub async fn bind(addr: SocketAddr, codec: VoipCodec) -> io::Result<KcpListener> {
+    let udp = Arc::new(UdpSocket::bind(addr).await?);
+    KcpListener::bind_over(Arc::new(RtpConn::new(udp, codec)))
+}
+
+/// Datagram socket sending in SRTP packets over `inner`, one call per peer
+pub struct RtpConn {
+    inner: Socket,
+    calls: Arc<Mutex<HashMap<SocketAddr, Call>>>,
+    received: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl RtpConn {
+    pub fn new(inner: Socket, codec: VoipCodec) -> Self {
+        let calls = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, received) = mpsc::channel(RECEIVE_QUEUE);
+        let tasks = [
+            tokio::spawn(send_frames(Arc::clone(&inner), Arc::clone(&calls), codec)),
+            tokio::spawn(receive(Arc::clone(&inner), Arc::clone(&calls), tx)),
+        ];
+        Self {
+            inner,
+            calls,
+            received: tokio::sync::Mutex::new(received),
+            tasks,
+        }
+    }
+}
+
+impl Drop for RtpConn {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for RtpConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(unsupported())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(webrtc::util::Error::ErrUseClosedNetworkConn)?;
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// Queue `buf` for the next frames to `target`
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let mut calls = self.calls.lock().unwrap();
+        let call = calls.entry(target).or_insert_with(Call::new);
+        call.last_data = Instant::now();
+        if call.queued + buf.len() <= QUEUE_BYTES && !buf.is_empty() {
+            call.queued += buf.len();
+            call.queue.push_back(buf.to_vec());
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// One call: our outgoing stream and the reassembly of the peer's
+struct Call {
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+    /// Bytes of the front datagram already sent
+    sent: usize,
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+    packets: u32,
+    octets: u32,
+    talking: bool,
+    next_report: Instant,
+    srtcp_index: u32,
+    last_data: Instant,
+    peer: Reassembly,
+}
+
+#[derive(Default)]
+struct Reassembly {
+    ssrc: Option<u32>,
+    last_sequence: Option<u16>,
+    datagram: Vec<u8>,
+    length: usize,
+}
+
+impl Call {
+    fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            queue: VecDeque::new(),
+            queued: 0,
+            sent: 0,
+            ssrc: rng.gen(),
+            sequence: rng.gen(),
+            timestamp: rng.gen(),
+            packets: 0,
+            octets: 0,
+            talking: false,
+            next_report: Instant::now() + report_interval(&mut rng),
+            srtcp_index: 0,
+            last_data: Instant::now(),
+            peer: Reassembly::default(),
+        }
+    }
+
+    /// Next SRTP packet, carrying as much of the queue as the frame holds
+    fn packet(&mut self, codec: VoipCodec, rng: &mut impl Rng) -> Vec<u8> {
+        let talking = !self.queue.is_empty();
+        let size = match codec {
+            VoipCodec::Pcmu | VoipCodec::Pcma => 160,
+            // VBR speech around 40 kbit/s; comfort noise in the pauses
+            VoipCodec::Opus if talking => (100.0 + 20.0 * rng.gen_range(-1.0f64..1.0).powi(3)) as usize,
+            VoipCodec::Opus => rng.gen_range(3..=8),
+        };
+
+        let mut packet = Vec::with_capacity(RTP_HEADER_SIZE + size + AUTH_TAG_SIZE);
+        let payload_type = match codec {
+            VoipCodec::Pcmu => 0,
+            VoipCodec::Pcma => 8,
+            VoipCodec::Opus => OPUS_PAYLOAD_TYPE,
+        };
+        let marker = if talking && !self.talking { 0x80 } else { 0 };
+        self.talking = talking;
+        packet.extend_from_slice(&[0x80, marker | payload_type]);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        let header: [u8; RTP_HEADER_SIZE] = packet[..].try_into().unwrap();
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(match codec {
+            VoipCodec::Opus => 960,
+            _ => 160,
+        });
+
+        let end = RTP_HEADER_SIZE + size;
+        let mut index = 0u8;
+        while packet.len() + FRAGMENT_HEADER_SIZE < end {
+            let Some(datagram) = self.queue.front() else {
+                break;
+            };
+            let chunk = (datagram.len() - self.sent).min(end - packet.len() - FRAGMENT_HEADER_SIZE);
+            put_fragment_header(&mut packet, &header, index, datagram.len() as u16, self.sent as u16);
+            packet.extend_from_slice(&datagram[self.sent..self.sent + chunk]);
+            self.sent += chunk;
+            if self.sent == datagram.len() {
+                self.queued -= datagram.len();
+                self.queue.pop_front();
+                self.sent = 0;
+            }
+            index += 1;
+        }
+        if packet.len() + FRAGMENT_HEADER_SIZE <= end {
+            put_fragment_header(&mut packet, &header, index, 0, 0);
+        }
+        let fill = packet.len();
+        packet.resize(end + AUTH_TAG_SIZE, 0);
+        rng.fill_bytes(&mut packet[fill..]);
+
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add(size as u32);
+        packet
+    }
+
+    /// Sender report and source description, as SRTCP, or a goodbye when
+    /// `bye` is set
+    fn report(&mut self, bye: bool, rng: &mut impl Rng) -> Vec<u8> {
+        let mut packet = Vec::new();
+        // Sender report: NTP timestamp, RTP timestamp, packet and octet counts
+        packet.extend_from_slice(&[0x80, RTCP_SR, 0, 6]);
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(&crate::ntp_channel::ntp_timestamp(std::time::SystemTime::now()));
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.packets.to_be_bytes());
+        packet.extend_from_slice(&self.octets.to_be_bytes());
+        if bye {
+            packet.extend_from_slice(&[0x81, RTCP_BYE, 0, 1]);
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        } else {
+            // CNAME of 16 random characters, as browsers send
+            packet.extend_from_slice(&[0x81, RTCP_SDES, 0, 6]);
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+            packet.extend_from_slice(&[1, 16]);
+            const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+            packet.extend((0..16).map(|_| CHARSET[rng.gen_range(0..CHARSET.len())]));
+            packet.extend_from_slice(&[0, 0]);
+        }
+
+        // SRTCP encrypts everything after the first header and SSRC
+        rng.fill_bytes(&mut packet[8..]);
+        packet.extend_from_slice(&(0x8000_0000 | self.srtcp_index).to_be_bytes());
+        self.srtcp_index = (self.srtcp_index + 1) & 0x7fff_ffff;
+        let tag = packet.len();
+        packet.resize(tag + AUTH_TAG_SIZE, 0);
+        rng.fill_bytes(&mut packet[tag..]);
+        packet
+    }
+}
+
+impl Reassembly {
+    /// Take the datagrams completed by a received RTP `packet`; returns
+    /// whether it carried any fragment
+    fn receive(&mut self, packet: &[u8], datagrams: &mut Vec<Vec<u8>>) -> bool {
+        let Some(payload) = packet.get(RTP_HEADER_SIZE..packet.len().saturating_sub(AUTH_TAG_SIZE)) else {
+            return false;
+        };
+        let header: [u8; RTP_HEADER_SIZE] = packet[..RTP_HEADER_SIZE].try_into().unwrap();
+        let sequence = u16::from_be_bytes([header[2], header[3]]);
+        let ssrc = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+
+        // A new SSRC is a new call; a gap loses the datagram in progress
+        if self.ssrc != Some(ssrc) || self.last_sequence.map(|last| last.wrapping_add(1)) != Some(sequence) {
+            self.datagram.clear();
+        }
+        self.ssrc = Some(ssrc);
+        self.last_sequence = Some(sequence);
+
+        let mut rest = payload;
+        for index in 0..=u8::MAX {
+            let Some(masked) = rest.get(..FRAGMENT_HEADER_SIZE) else {
+                return index > 0;
+            };
+            let mask = fragment_mask(&header, index);
+            let length = u16::from_be_bytes([masked[0] ^ mask[0], masked[1] ^ mask[1]]) as usize;
+            let offset = u16::from_be_bytes([masked[2] ^ mask[2], masked[3] ^ mask[3]]) as usize;
+            if length == 0 || offset >= length {
+                return index > 0;
+            }
+            rest = &rest[FRAGMENT_HEADER_SIZE..];
+            let chunk = &rest[..(length - offset).min(rest.len())];
+            rest = &rest[chunk.len()..];
+
+            if offset == 0 {
+                self.datagram.clear();
+                self.length = length;
+            } else if offset != self.datagram.len() || length != self.length {
+                self.datagram.clear();
+                continue;
+            }
+            self.datagram.extend_from_slice(chunk);
+            if self.datagram.len() == length {
+                datagrams.push(std::mem::take(&mut self.datagram));
+            }
+        }
+        true
+    }
+}
+
+/// Send every call its frame each packetization interval, and its reports
+async fn send_frames(inner: Socket, calls: Arc<Mutex<HashMap<SocketAddr, Call>>>, codec: VoipCodec) {
+    let mut interval = tokio::time::interval(PTIME);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let packets = {
+            let mut rng = rand::thread_rng();
+            let mut calls = calls.lock().unwrap();
+            let mut packets = Vec::new();
+            calls.retain(|addr, call| {
+                if call.last_data.elapsed() >= CALL_TIMEOUT {
+                    packets.push((call.report(true, &mut rng), *addr));
+                    return false;
+                }
+                packets.push((call.packet(codec, &mut rng), *addr));
+                if call.next_report <= Instant::now() {
+                    packets.push((call.report(false, &mut rng), *addr));
+                    call.next_report = Instant::now() + report_interval(&mut rng);
+                }
+                true
+            });
+            packets
+        };
+        for (packet, addr) in packets {
+            if let Err(e) = inner.send_to(&packet, addr).await {
+                log::debug!("RTP: send to {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// Reassemble the datagrams in received RTP packets
+async fn receive(inner: Socket, calls: Arc<Mutex<HashMap<SocketAddr, Call>>>, received: mpsc::Sender<(Vec<u8>, SocketAddr)>) {
+    let mut buf = vec![0u8; 2048];
+    let mut datagrams = Vec::new();
+    loop {
+        let (n, from) = match inner.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(webrtc::util::Error::ErrUseClosedNetworkConn) => return,
+            Err(e) => {
+                log::debug!("RTP: receive error: {}", e);
+                continue;
+            }
+        };
+        let packet = &buf[..n];
+        // RTCP carries nothing for the tunnel
+        if n < RTP_HEADER_SIZE + AUTH_TAG_SIZE || packet[0] >> 6 != 2 || (192..=223).contains(&packet[1]) {
+            continue;
+        }
+
+        {
+            let mut calls = calls.lock().unwrap();
+            match calls.get_mut(&from) {
+                Some(call) => {
+                    call.peer.receive(packet, &mut datagrams);
+                }
+                None => {
+                    // Calls start with tunnel data, not with any packet
+                    let mut call = Call::new();
+                    if call.peer.receive(packet, &mut datagrams) {
+                        calls.insert(from, call);
+                    }
+                }
+            }
+            if let Some(call) = calls.get_mut(&from).filter(|_| !datagrams.is_empty()) {
+                call.last_data = Instant::now();
+            }
+        }
+        for datagram in datagrams.drain(..) {
+            match received.try_send((datagram, from)) {
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => log::debug!("RTP: receive queue full, dropping datagram"),
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+fn put_fragment_header(packet: &mut Vec<u8>, header: &[u8; RTP_HEADER_SIZE], index: u8, length: u16, offset: u16) {
+    let mask = fragment_mask(header, index);
+    let [l0, l1] = length.to_be_bytes();
+    let [o0, o1] = offset.to_be_bytes();
+    packet.extend_from_slice(&[l0 ^ mask[0], l1 ^ mask[1], o0 ^ mask[2], o1 ^ mask[3]]);
+}
+
+/// Mask of the `index`th fragment header in the packet with RTP `header`
+fn fragment_mask(header: &[u8; RTP_HEADER_SIZE], index: u8) -> [u8; FRAGMENT_HEADER_SIZE] {
+    let mut input = [0u8; RTP_HEADER_SIZE + 1];
+    input[..RTP_HEADER_SIZE].copy_from_slice(header);
+    input[RTP_HEADER_SIZE] = index;
+    let hash = digest::digest(&digest::SHA256, &input);
+    hash.as_ref()[..FRAGMENT_HEADER_SIZE].try_into().unwrap()
+}
+
+/// Randomized between half and one and a half times the average (RFC 3550
+/// section 6.3.1)
+fn report_interval(rng: &mut impl Rng) -> Duration {
+    RTCP_INTERVAL.mul_f64(rng.gen_range(0.5..1.5))
+}
+
+fn unsupported() -> webrtc::util::Error {
+    webrtc::util::Error::Other("RTP sockets are unconnected".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_frames_carry_fragments() {
+        let mut rng = rand::thread_rng();
+        let (mut sender, mut receiver) = (Call::new(), Reassembly::default());
+        let datagram: Vec<u8> = (0..400u32).map(|i| i as u8).collect();
+        sender.queue.extend([datagram.clone(), vec![7u8; 30]]);
+        sender.queued = 430;
+
+        let mut datagrams = Vec::new();
+        let mut sequences = Vec::new();
+        for _ in 0..4 {
+            let packet = sender.packet(VoipCodec::Pcmu, &mut rng);
+            assert_eq!(packet.len(), RTP_HEADER_SIZE + 160 + AUTH_TAG_SIZE);
+            assert_eq!(packet[1] & 0x7f, 0);
+            sequences.push(u16::from_be_bytes([packet[2], packet[3]]));
+            receiver.receive(&packet, &mut datagrams);
+        }
+        assert_eq!(datagrams, [datagram, vec![7u8; 30]]);
+        assert_eq!(sequences[3], sequences[0].wrapping_add(3));
+        assert_eq!((sender.queued, sender.packets, sender.octets), (0, 4, 640));
+
+        let report = sender.report(false, &mut rng);
+        assert_eq!(&report[..2], &[0x80, RTCP_SR]);
+        assert_eq!(&report[4..8], &sender.ssrc.to_be_bytes());
+        assert_eq!(report.len(), 28 + 28 + 4 + AUTH_TAG_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_kcp_in_a_call() {
+        let mut listener = bind("127.0.0.1:0".parse().unwrap(), VoipCodec::Pcma).await.unwrap();
+        let mut client = connect(listener.local_addr(), VoipCodec::Pcma, None).await.unwrap();
+
+        // Spans several frames
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = vec![0u8; data.len()];
+        tokio::time::timeout(Duration::from_secs(5), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"bye").await.unwrap();
+        let mut buf = [0u8; 3];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"bye");
+    }
+}
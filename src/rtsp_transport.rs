@@ -0,0 +1,694 @@
+//! Tunnel stream carried as an RTSP session of an IP camera
+//!
+//! Security cameras stream to recorders and viewers around the clock, and
+//! a megabit or two of RTSP from a camera is unremarkable on most
+//! networks. This transport opens the tunnel the way a viewer opens a
+//! camera's main stream: `OPTIONS`, a `DESCRIBE` answered with the SDP of
+//! an H.264 video track and an ONVIF audio backchannel, a `SETUP` of each
+//! track with RTP interleaved on the RTSP connection, and `PLAY`. Tunnel
+//! data then travels in interleaved RTP packets: downstream as H.264
+//! fragmentation units of up to [`MAX_PAYLOAD`] bytes on the video channel,
+//! upstream as G.711 frames on the backchannel, the way a viewer talks
+//! through the camera's speaker.
+//!
+//! ```toml
+//! [socks]
+//! transport = "rtsp"
+//!
+//! [server]
+//! transport = "rtsp"
+//! ```
+//!
+//! Both ends send RTCP reports every few seconds, the viewer keeps the
+//! session alive with `GET_PARAMETER` at half its timeout and ends it with
+//! `TEARDOWN`. RTP timestamps follow the wall clock, at 90 kHz for video
+//! and 8 kHz for audio, and each chunk of tunnel data is one video frame
+//! with the marker bit on its last packet.
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::sync::mpsc;
+
+/// Buffer between the tunnel and the session
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest tunnel chunk in one video packet, keeping packets within an
+/// Ethernet MTU as cameras do
+pub const MAX_PAYLOAD: usize = 1400;
+
+/// Tunnel bytes in one backchannel packet: 20 ms of G.711
+const AUDIO_FRAME: usize = 160;
+
+/// Tunnel bytes read at once, sent as one video frame
+const FRAME_SIZE: usize = 16 * 1024;
+
+/// Largest start line and headers accepted
+const MAX_HEADERS: usize = 8192;
+
+/// Requests the camera takes before playing
+const SETUP_MESSAGES: usize = 8;
+
+/// Session timeout the camera announces, in seconds
+const SESSION_TIMEOUT: u64 = 60;
+
+/// Average interval between RTCP reports
+const RTCP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often keepalive and report timers are checked
+const TIMER_TICK: Duration = Duration::from_secs(1);
+
+/// Interleaved channels: RTP and RTCP of the video and of the backchannel
+const VIDEO_RTP: u8 = 0;
+const VIDEO_RTCP: u8 = 1;
+const AUDIO_RTP: u8 = 2;
+const AUDIO_RTCP: u8 = 3;
+
+/// Dynamic payload type of H.264 in camera SDPs
+const H264_PAYLOAD_TYPE: u8 = 96;
+
+/// G.711 µ-law
+const PCMU_PAYLOAD_TYPE: u8 = 0;
+
+/// FU-A indicator of a reference picture's fragments (RFC 6184)
+const FU_A: u8 = 0x7c;
+
+/// Slice of a non-IDR picture
+const NAL_SLICE: u8 = 1;
+
+/// RTCP packet types
+const RTCP_SR: u8 = 200;
+const RTCP_RR: u8 = 201;
+
+/// Stream paths of common camera brands
+const PATHS: &[&str] = &[
+    "/Streaming/Channels/101",
+    "/cam/realmonitor?channel=1&subtype=0",
+    "/stream1",
+    "/live/ch00_0",
+];
+
+/// Viewers and recorders the client passes for
+const USER_AGENTS: &[&str] = &[
+    "LibVLC/3.0.20 (LIVE555 Streaming Media v2016.11.28)",
+    "Lavf60.16.100",
+    "Blue Iris",
+];
+
+/// Camera firmware RTSP servers
+const SERVER_AGENTS: &[&str] = &["Rtsp Server/3.0", "HiIpcam/V100R003 VodServer/1.0.0", "GStreamer RTSP server"];
+
+const PUBLIC: &str = "OPTIONS, DESCRIBE, SETUP, TEARDOWN, PLAY, PAUSE, GET_PARAMETER, SET_PARAMETER";
+
+/// Start the client side over `stream`, connected to the camera at
+/// `server_addr`; the returned stream carries the tunnel
+pub async fn connect<S>(stream: S, server_addr: SocketAddr) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (url, agent) = {
+        let mut rng = rand::thread_rng();
+        let url = format!("rtsp://{}{}", server_addr, PATHS.choose(&mut rng).unwrap());
+        (url, *USER_AGENTS.choose(&mut rng).unwrap())
+    };
+    let mut stream = BufReader::new(stream);
+    let mut client = Client { agent, cseq: 0, session: None };
+
+    client.exchange(&mut stream, "OPTIONS", &url, &[]).await?;
+    let description = client
+        .exchange(&mut stream, "DESCRIBE", &url, &[("Accept", "application/sdp"), ("Require", "www.onvif.org/ver20/backchannel")])
+        .await?;
+    let base = description.header("Content-Base").unwrap_or(&url).to_string();
+    let sdp = String::from_utf8_lossy(&description.body).into_owned();
+    let controls: Vec<&str> = sdp.lines().filter_map(|line| line.strip_prefix("a=control:")).collect();
+    // The session's control comes first, then one per track
+    let [_, video, audio] = controls[..] else {
+        return Err(invalid(format!("Expected a video and a backchannel track, got {} controls", controls.len())));
+    };
+    for (control, channel) in [(video, VIDEO_RTP), (audio, AUDIO_RTP)] {
+        let transport = format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel + 1);
+        let setup = client.exchange(&mut stream, "SETUP", &resolve(&base, control), &[("Transport", &transport)]).await?;
+        let session = setup.header("Session").and_then(|s| s.split(';').next()).unwrap_or_default();
+        client.session = Some(session.trim().to_string());
+    }
+    client.exchange(&mut stream, "PLAY", &base, &[("Range", "npt=0.000-")]).await?;
+    log::debug!("RTSP session {} playing {}", client.session.as_deref().unwrap_or_default(), url);
+
+    Ok(spawn_session(stream, Role::Client { client, url: base }))
+}
+
+/// Start the server side over an accepted `stream`; the returned stream
+/// carries the tunnel
+pub async fn accept<S>(stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut stream = BufReader::new(stream);
+    let (agent, session) = {
+        let mut rng = rand::thread_rng();
+        (*SERVER_AGENTS.choose(&mut rng).unwrap(), rng.gen_range(10_000_000u32..99_999_999).to_string())
+    };
+    for _ in 0..SETUP_MESSAGES {
+        let request = read_message(&mut stream).await?;
+        let url = request.start.split(' ').nth(1).unwrap_or_default().to_string();
+        let session_header = format!("{};timeout={}", session, SESSION_TIMEOUT);
+        let reply = match request.method() {
+            "OPTIONS" => response(&request, "200 OK", agent, &[("Public", PUBLIC)], b""),
+            "DESCRIBE" => {
+                let base = format!("{}/", url.trim_end_matches('/'));
+                let sdp = sdp(&base);
+                response(
+                    &request,
+                    "200 OK",
+                    agent,
+                    &[("Content-Base", &base), ("Content-Type", "application/sdp")],
+                    sdp.as_bytes(),
+                )
+            }
+            "SETUP" => {
+                let transport = format!(
+                    "{};ssrc={:08X};mode=\"play\"",
+                    request.header("Transport").unwrap_or_default(),
+                    rand::random::<u32>()
+                );
+                response(&request, "200 OK", agent, &[("Session", &session_header), ("Transport", &transport)], b"")
+            }
+            "PLAY" => {
+                let rtp_info = {
+                    let mut rng = rand::thread_rng();
+                    format!(
+                        "url={}trackID=1;seq={};rtptime={}",
+                        url,
+                        rng.gen::<u16>(),
+                        rng.gen::<u32>()
+                    )
+                };
+                let reply = response(
+                    &request,
+                    "200 OK",
+                    agent,
+                    &[("Session", &session_header), ("Range", "npt=0.000-"), ("RTP-Info", &rtp_info)],
+                    b"",
+                );
+                stream.write_all(&reply).await?;
+                stream.flush().await?;
+                return Ok(spawn_session(stream, Role::Server { agent }));
+            }
+            method => {
+                stream.write_all(&response(&request, "455 Method Not Valid in This State", agent, &[], b"")).await?;
+                stream.flush().await?;
+                return Err(invalid(format!("Unexpected RTSP request {} before PLAY", method)));
+            }
+        };
+        stream.write_all(&reply).await?;
+        stream.flush().await?;
+    }
+    Err(invalid("RTSP session never played".to_string()))
+}
+
+/// The viewer's side of the conversation
+struct Client {
+    agent: &'static str,
+    cseq: u32,
+    session: Option<String>,
+}
+
+impl Client {
+    fn request(&mut self, method: &str, url: &str, headers: &[(&str, &str)]) -> Vec<u8> {
+        self.cseq += 1;
+        let mut request = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, url, self.cseq);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(ref session) = self.session {
+            request.push_str(&format!("Session: {}\r\n", session));
+        }
+        request.push_str(&format!("User-Agent: {}\r\n\r\n", self.agent));
+        request.into_bytes()
+    }
+
+    /// Send a request and read its successful response
+    async fn exchange<S>(&mut self, stream: &mut BufReader<S>, method: &str, url: &str, headers: &[(&str, &str)]) -> io::Result<Message>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        stream.write_all(&self.request(method, url, headers)).await?;
+        stream.flush().await?;
+        let response = read_message(stream).await?;
+        if !response.start.starts_with("RTSP/1.0 200") {
+            return Err(invalid(format!("RTSP {} refused: {}", method, response.start)));
+        }
+        Ok(response)
+    }
+}
+
+/// What a side sends, and its duties
+enum Role {
+    /// Video downstream is received, backchannel audio sent; the session is
+    /// kept alive and torn down at `url`
+    Client { client: Client, url: String },
+    /// Video sent, backchannel received; requests are answered as `agent`
+    Server { agent: &'static str },
+}
+
+fn spawn_session<S>(stream: BufReader<S>, role: Role) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = run_session(stream, role, local).await {
+            log::debug!("RTSP session ended: {}", e);
+        }
+    });
+    tunnel
+}
+
+/// Move tunnel bytes between `local` and interleaved RTP packets until the
+/// session is torn down
+async fn run_session<S>(stream: BufReader<S>, mut role: Role, local: DuplexStream) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // Bytes read ahead during the setup come first
+    let buffered = stream.buffer().to_vec();
+    let (reader, mut writer) = tokio::io::split(stream.into_inner());
+    let mut reader = BufReader::new(Cursor::new(buffered).chain(reader));
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+
+    // Frames are read in their own task, as a partly read frame must not
+    // be dropped when another branch wins
+    let (frames_tx, mut frames) = mpsc::channel(64);
+    let reader = tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            if frames_tx.send(frame).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let result = async {
+        let server = matches!(role, Role::Server { .. });
+        let (receive_channel, mut stream_out) = match server {
+            true => (AUDIO_RTP, RtpStream::new(H264_PAYLOAD_TYPE, 90)),
+            false => (VIDEO_RTP, RtpStream::new(PCMU_PAYLOAD_TYPE, 8)),
+        };
+        let mut timer = tokio::time::interval(TIMER_TICK);
+        let mut buf = vec![0u8; FRAME_SIZE];
+        let (mut last_report, mut last_keepalive) = (Instant::now(), Instant::now());
+        let mut next_report = report_interval();
+        let mut app_open = true;
+        loop {
+            tokio::select! {
+                frame = frames.recv() => {
+                    let Some(frame) = frame else {
+                        return app_write.shutdown().await;
+                    };
+                    match (frame, &mut role) {
+                        (Frame::Interleaved(channel, packet), _) if channel == receive_channel => {
+                            let payload = rtp_payload(&packet)?;
+                            // Video payloads start with the FU indicator and header
+                            let data = if server { payload } else { payload.get(2..).unwrap_or_default() };
+                            app_write.write_all(data).await?;
+                        }
+                        (Frame::Interleaved(..), _) => {}
+                        (Frame::Message(request), Role::Server { agent }) => {
+                            let reply = response(&request, "200 OK", agent, &[], b"");
+                            writer.write_all(&reply).await?;
+                            if request.method() == "TEARDOWN" {
+                                return app_write.shutdown().await;
+                            }
+                        }
+                        // Responses to keepalives
+                        (Frame::Message(_), Role::Client { .. }) => {}
+                    }
+                }
+                n = app_read.read(&mut buf), if app_open => {
+                    let n = n?;
+                    if n == 0 {
+                        app_open = false;
+                        if let Role::Client { ref mut client, ref url } = role {
+                            writer.write_all(&client.request("TEARDOWN", url, &[])).await?;
+                        }
+                        writer.shutdown().await?;
+                        continue;
+                    }
+                    let packets = match server {
+                        true => stream_out.video_frame(&buf[..n]),
+                        false => stream_out.audio(&buf[..n]),
+                    };
+                    writer.write_all(&packets).await?;
+                }
+                _ = timer.tick(), if app_open => {
+                    if last_report.elapsed() >= next_report {
+                        let report = match server {
+                            true => interleaved(VIDEO_RTCP, &stream_out.sender_report()),
+                            false => {
+                                let mut packets = interleaved(AUDIO_RTCP, &stream_out.sender_report());
+                                packets.extend_from_slice(&interleaved(VIDEO_RTCP, &receiver_report(stream_out.ssrc)));
+                                packets
+                            }
+                        };
+                        writer.write_all(&report).await?;
+                        last_report = Instant::now();
+                        next_report = report_interval();
+                    }
+                    if let Role::Client { ref mut client, ref url } = role {
+                        if last_keepalive.elapsed() >= Duration::from_secs(SESSION_TIMEOUT / 2) {
+                            writer.write_all(&client.request("GET_PARAMETER", url, &[])).await?;
+                            last_keepalive = Instant::now();
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    result
+}
+
+/// Outgoing RTP stream of one track
+struct RtpStream {
+    payload_type: u8,
+    /// Clock rate in ticks per millisecond
+    clock: u32,
+    ssrc: u32,
+    sequence: u16,
+    timestamp_base: u32,
+    start: Instant,
+    packets: u32,
+    octets: u32,
+}
+
+impl RtpStream {
+    fn new(payload_type: u8, clock: u32) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            payload_type,
+            clock,
+            ssrc: rng.gen(),
+            sequence: rng.gen(),
+            timestamp_base: rng.gen(),
+            start: Instant::now(),
+            packets: 0,
+            octets: 0,
+        }
+    }
+
+    fn timestamp(&self) -> u32 {
+        let ticks = self.start.elapsed().as_millis() as u64 * self.clock as u64;
+        self.timestamp_base.wrapping_add(ticks as u32)
+    }
+
+    /// Interleaved RTP packet of `payload`
+    fn packet(&mut self, marker: bool, timestamp: u32, payload: &[&[u8]]) -> Vec<u8> {
+        let length: usize = payload.iter().map(|part| part.len()).sum();
+        let mut packet = Vec::with_capacity(12 + length);
+        packet.push(0x80);
+        packet.push(self.payload_type | if marker { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        for part in payload {
+            packet.extend_from_slice(part);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add(length as u32);
+        packet
+    }
+
+    /// `data` as one video frame of FU-A fragments, the last with the
+    /// marker bit
+    fn video_frame(&mut self, data: &[u8]) -> Vec<u8> {
+        let timestamp = self.timestamp();
+        let chunks: Vec<&[u8]> = data.chunks(MAX_PAYLOAD).collect();
+        let mut packets = Vec::with_capacity(data.len() + chunks.len() * 20);
+        for (i, chunk) in chunks.iter().enumerate() {
+            let (first, last) = (i == 0, i == chunks.len() - 1);
+            let header = NAL_SLICE | if first { 0x80 } else { 0 } | if last { 0x40 } else { 0 };
+            let packet = self.packet(last, timestamp, &[&[FU_A, header], chunk]);
+            packets.extend_from_slice(&interleaved(VIDEO_RTP, &packet));
+        }
+        packets
+    }
+
+    /// `data` as backchannel audio frames
+    fn audio(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut packets = Vec::with_capacity(data.len() + data.len() / AUDIO_FRAME * 16 + 16);
+        let timestamp = self.timestamp();
+        for (i, chunk) in data.chunks(AUDIO_FRAME).enumerate() {
+            let packet = self.packet(false, timestamp.wrapping_add((i * AUDIO_FRAME) as u32), &[chunk]);
+            packets.extend_from_slice(&interleaved(AUDIO_RTP, &packet));
+        }
+        packets
+    }
+
+    /// RTCP sender report of the stream so far
+    fn sender_report(&self) -> Vec<u8> {
+        let ntp = ntp_timestamp();
+        let mut report = vec![0x80, RTCP_SR, 0, 6];
+        report.extend_from_slice(&self.ssrc.to_be_bytes());
+        report.extend_from_slice(&ntp.to_be_bytes());
+        report.extend_from_slice(&self.timestamp().to_be_bytes());
+        report.extend_from_slice(&self.packets.to_be_bytes());
+        report.extend_from_slice(&self.octets.to_be_bytes());
+        report
+    }
+}
+
+/// Empty RTCP receiver report from `ssrc`, as viewers send over TCP where
+/// nothing is lost
+fn receiver_report(ssrc: u32) -> Vec<u8> {
+    let mut report = vec![0x80, RTCP_RR, 0, 1];
+    report.extend_from_slice(&ssrc.to_be_bytes());
+    report
+}
+
+/// `packet` behind an interleaved frame header (RFC 2326, section 10.12)
+fn interleaved(channel: u8, packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + packet.len());
+    frame.push(b'$');
+    frame.push(channel);
+    frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    frame.extend_from_slice(packet);
+    frame
+}
+
+/// Payload of an RTP packet, after its CSRCs and header extension
+fn rtp_payload(packet: &[u8]) -> io::Result<&[u8]> {
+    let truncated = || invalid("Truncated RTP packet".to_string());
+    let mut offset = 12 + 4 * (*packet.first().ok_or_else(truncated)? & 0x0f) as usize;
+    if packet[0] & 0x10 != 0 {
+        let words = packet.get(offset + 2..offset + 4).ok_or_else(truncated)?;
+        offset += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+    }
+    packet.get(offset..).ok_or_else(truncated)
+}
+
+fn ntp_timestamp() -> u64 {
+    // Seconds from 1900 to the Unix epoch
+    const NTP_OFFSET: u64 = 2_208_988_800;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let fraction = (u64::from(now.subsec_nanos()) << 32) / 1_000_000_000;
+    (now.as_secs() + NTP_OFFSET) << 32 | fraction
+}
+
+fn report_interval() -> Duration {
+    RTCP_INTERVAL.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+/// SDP of the camera's stream at `base`: H.264 video and a G.711
+/// backchannel
+fn sdp(base: &str) -> String {
+    let session: u64 = rand::thread_rng().gen_range(1_000_000_000..9_999_999_999);
+    format!(
+        "v=0\r\no=- {session} 1 IN IP4 0.0.0.0\r\ns=Media Presentation\r\ne=NONE\r\nb=AS:5100\r\nt=0 0\r\n\
+         a=control:{base}\r\n\
+         m=video 0 RTP/AVP {H264_PAYLOAD_TYPE}\r\nc=IN IP4 0.0.0.0\r\nb=AS:5000\r\na=recvonly\r\n\
+         a=x-dimensions:1920,1080\r\na=control:{base}trackID=1\r\na=rtpmap:{H264_PAYLOAD_TYPE} H264/90000\r\n\
+         a=fmtp:{H264_PAYLOAD_TYPE} profile-level-id=420029; packetization-mode=1; sprop-parameter-sets=Z00AKpWoHgCJ+WEAAAMAAQAAAwAyhA==,aO48gA==\r\n\
+         m=audio 0 RTP/AVP {PCMU_PAYLOAD_TYPE}\r\nc=IN IP4 0.0.0.0\r\na=control:{base}trackID=2\r\n\
+         a=rtpmap:{PCMU_PAYLOAD_TYPE} PCMU/8000\r\na=sendonly\r\n"
+    )
+}
+
+/// Absolute URL of `control` relative to `base`
+fn resolve(base: &str, control: &str) -> String {
+    match control.starts_with("rtsp://") {
+        true => control.to_string(),
+        false => format!("{}/{}", base.trim_end_matches('/'), control),
+    }
+}
+
+/// Response to `request` from a camera running `agent`
+fn response(request: &Message, status: &str, agent: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "RTSP/1.0 {}\r\nCSeq: {}\r\n",
+        status,
+        request.header("CSeq").unwrap_or_default()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!("Date: {}\r\n", chrono::Utc::now().format("%a, %b %d %Y %H:%M:%S GMT")));
+    response.push_str(&format!("Server: {}\r\n", agent));
+    if !body.is_empty() {
+        response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// A request or response
+struct Message {
+    start: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn method(&self) -> &str {
+        self.start.split(' ').next().unwrap_or_default()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// What arrives on the connection once playing
+enum Frame {
+    Interleaved(u8, Vec<u8>),
+    Message(Message),
+}
+
+async fn read_frame<R>(reader: &mut R) -> io::Result<Frame>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let buf = reader.fill_buf().await?;
+    match buf.first() {
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+        Some(b'$') => {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header).await?;
+            let mut packet = vec![0u8; u16::from_be_bytes([header[2], header[3]]).into()];
+            reader.read_exact(&mut packet).await?;
+            Ok(Frame::Interleaved(header[1], packet))
+        }
+        Some(_) => Ok(Frame::Message(read_message(reader).await?)),
+    }
+}
+
+async fn read_message<R>(reader: &mut R) -> io::Result<Message>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    while line.trim().is_empty() {
+        line.clear();
+        read_line(reader, &mut line).await?;
+    }
+    let start = line.trim_end().to_string();
+    let mut size = line.len();
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_line(reader, &mut line).await?;
+        size += line.len();
+        if size > MAX_HEADERS {
+            return Err(invalid("RTSP message headers too long".to_string()));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let mut message = Message { start, headers, body: Vec::new() };
+    let length: usize = match message.header("Content-Length") {
+        Some(length) => length.parse().map_err(|_| invalid(format!("Bad RTSP Content-Length {:?}", length)))?,
+        None => 0,
+    };
+    if length > BUFFER_SIZE {
+        return Err(invalid(format!("RTSP message body of {} bytes", length)));
+    }
+    message.body = vec![0u8; length];
+    reader.read_exact(&mut message.body).await?;
+    Ok(message)
+}
+
+async fn read_line<R>(reader: &mut R, line: &mut String) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let n = (&mut *reader).take(MAX_HEADERS as u64).read_line(line).await?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid("RTSP message line too long".to_string()));
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_play_then_tunnel() {
+        let (client_side, server_side) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(async move {
+            let mut tunnel = accept(server_side).await.unwrap();
+            let mut request = vec![0u8; 1000];
+            tunnel.read_exact(&mut request).await.unwrap();
+            assert!(request.iter().enumerate().all(|(i, b)| *b == i as u8));
+            let response: Vec<u8> = (0..50_000u32).map(|i| (i * 7) as u8).collect();
+            tunnel.write_all(&response).await.unwrap();
+        });
+        let mut tunnel = connect(client_side, "192.0.2.1:554".parse().unwrap()).await.unwrap();
+        let request: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        tunnel.write_all(&request).await.unwrap();
+        let mut response = vec![0u8; 50_000];
+        tunnel.read_exact(&mut response).await.unwrap();
+        assert!(response.iter().enumerate().all(|(i, b)| *b == (i * 7) as u8));
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_video_frame_fragments() {
+        let mut stream = RtpStream::new(H264_PAYLOAD_TYPE, 90);
+        let data = vec![0xabu8; MAX_PAYLOAD * 2 + 10];
+        let frames = stream.video_frame(&data);
+        let mut rest = &frames[..];
+        let mut received = Vec::new();
+        let mut markers = Vec::new();
+        while !rest.is_empty() {
+            assert_eq!((rest[0], rest[1]), (b'$', VIDEO_RTP));
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let packet = &rest[4..4 + length];
+            assert!(packet.len() <= 12 + 2 + MAX_PAYLOAD);
+            markers.push(packet[1] & 0x80 != 0);
+            let payload = rtp_payload(packet).unwrap();
+            assert_eq!(payload[0], FU_A);
+            received.extend_from_slice(&payload[2..]);
+            rest = &rest[4 + length..];
+        }
+        assert_eq!(received, data);
+        assert_eq!(markers, [false, false, true]);
+    }
+}
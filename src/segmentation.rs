@@ -0,0 +1,158 @@
+//! TCP segmentation shaping for tunnel sockets
+//!
+//! Relay loops read up to [`DEFAULT_RECORD_SIZE`] bytes at a time, so the
+//! kernel emits full-MSS segments followed by an odd-sized tail - a size
+//! pattern no real TLS or SSH stack produces. With segmentation control
+//! enabled, the MSS is clamped (including in the SYN), Nagle is disabled,
+//! and each write is one record sized to fit a single segment.
+
+use crate::config::{SegmentationConfig, TrafficShapingConfig};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Relay read size when segmentation control is off
+pub const DEFAULT_RECORD_SIZE: usize = 8192;
+
+/// Room left in each segment for protocol framing and the AEAD tag
+const RECORD_OVERHEAD: usize = 64;
+
+/// Smallest record size accepted, to keep relays from crawling
+const MIN_RECORD_SIZE: usize = 128;
+
+/// Plaintext bytes per tunnel write
+pub fn record_size(config: &SegmentationConfig) -> usize {
+    if !config.enabled {
+        return DEFAULT_RECORD_SIZE;
+    }
+    config
+        .record_size
+        .or_else(|| config.mss.map(|mss| (mss as usize).saturating_sub(RECORD_OVERHEAD)))
+        .unwrap_or(DEFAULT_RECORD_SIZE)
+        .clamp(MIN_RECORD_SIZE, u16::MAX as usize - RECORD_OVERHEAD)
+}
+
+/// Time to wait for more data before sending a partial record
+pub fn coalesce_window(config: &SegmentationConfig) -> Duration {
+    if config.enabled {
+        Duration::from_micros(config.coalesce_window)
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// Connect to `addr`, applying segmentation, TCP fingerprint, and TTL
+/// options before the SYN is sent
+pub async fn connect(addr: SocketAddr, shaping: &TrafficShapingConfig) -> io::Result<TcpStream> {
+    if !shaping.segmentation.enabled && !shaping.tcp_fingerprint.enabled && !shaping.ttl.enabled {
+        return TcpStream::connect(addr).await;
+    }
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Fingerprint first so an explicit segmentation MSS takes precedence
+    crate::tcp_fingerprint::apply(&socket, &shaping.tcp_fingerprint)?;
+    apply(&socket, &shaping.segmentation)?;
+    crate::tcp_fingerprint::apply_ttl(&socket, &shaping.ttl, &shaping.tcp_fingerprint)?;
+    socket.connect(addr).await
+}
+
+/// Apply segmentation options to a socket, listener, or stream
+#[cfg(unix)]
+pub fn apply(socket: &impl std::os::unix::io::AsRawFd, config: &SegmentationConfig) -> io::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let fd = socket.as_raw_fd();
+    set_tcp_option(fd, libc::TCP_NODELAY, 1)?;
+    if let Some(mss) = config.mss {
+        set_tcp_option(fd, libc::TCP_MAXSEG, mss as libc::c_int)?;
+    }
+    if let Some(lowat) = config.notsent_lowat {
+        #[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+        set_tcp_option(fd, libc::TCP_NOTSENT_LOWAT, lowat as libc::c_int)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_vendor = "apple")))]
+        log::warn!("TCP_NOTSENT_LOWAT ({}) is not supported on this platform", lowat);
+    }
+    Ok(())
+}
+
+/// Apply segmentation options to a socket, listener, or stream
+#[cfg(not(unix))]
+pub fn apply<T>(_socket: &T, config: &SegmentationConfig) -> io::Result<()> {
+    if config.enabled {
+        log::warn!("TCP segmentation control is only supported on Unix; ignoring");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_tcp_option(fd: std::os::unix::io::RawFd, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_size() {
+        let mut config = SegmentationConfig::default();
+        assert_eq!(record_size(&config), DEFAULT_RECORD_SIZE);
+
+        config.enabled = true;
+        assert_eq!(record_size(&config), DEFAULT_RECORD_SIZE);
+        config.mss = Some(1360);
+        assert_eq!(record_size(&config), 1296);
+        config.record_size = Some(500);
+        assert_eq!(record_size(&config), 500);
+        config.record_size = Some(1);
+        assert_eq!(record_size(&config), MIN_RECORD_SIZE);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_mss_clamp() {
+        use std::os::unix::io::AsRawFd;
+
+        let config = SegmentationConfig {
+            enabled: true,
+            mss: Some(1200),
+            record_size: None,
+            notsent_lowat: Some(16384),
+            coalesce_window: 0,
+        };
+        let socket = TcpSocket::new_v4().unwrap();
+        apply(&socket, &config).unwrap();
+
+        let mut mss: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&mss) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_MAXSEG,
+                &mut mss as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(mss, 1200);
+    }
+}
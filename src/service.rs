@@ -0,0 +1,797 @@
+//! Running as a system service or daemon
+//!
+//! `nooshdaroo --config <FILE> service install client|server` registers a
+//! service that runs the client or server with that config file, so
+//! deployments need no hand-written unit files:
+//!
+//! | Platform | Service                                                          |
+//! |----------|------------------------------------------------------------------|
+//! | Linux    | systemd unit in `/etc/systemd/system` (`--user`: `~/.config/systemd/user`) |
+//! | macOS    | launchd plist in `/Library/LaunchDaemons` (`--user`: `~/Library/LaunchAgents`) |
+//! | Windows  | Service Control Manager service, via `sc.exe`                    |
+//!
+//! Windows services run `service run`, which answers the Service Control
+//! Manager through [`scm`]: stop ends the process cleanly, pause stops
+//! accepting new connections until continued, and logs go to the
+//! Application event log under the service name.
+//!
+//! `service start|stop|uninstall` manage it through the same tools. Where no
+//! service manager is used, `--daemon` detaches from the terminal (Unix
+//! only) and `--pidfile` records the process ID, refusing to start while
+//! another process holds the file.
+
+use crate::key_provider::run;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Event log registration of Windows services
+const EVENT_LOG_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Services\EventLog\Application";
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the service runs the client or the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    /// Subcommand that runs the role
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Client => "client",
+            Role::Server => "server",
+        }
+    }
+}
+
+/// A service to install
+#[derive(Debug, Clone)]
+pub struct ServiceSpec {
+    /// Service name, also the unit, plist label or Windows service name
+    pub name: String,
+    pub role: Role,
+    /// Absolute path of the nooshdaroo binary
+    pub executable: PathBuf,
+    /// Absolute path of the config file
+    pub config: PathBuf,
+    /// Per-user service (systemd `--user`, LaunchAgent) instead of system-wide
+    pub user: bool,
+}
+
+impl ServiceSpec {
+    /// Command line the service runs
+    fn arguments(&self) -> Vec<String> {
+        vec![
+            self.executable.display().to_string(),
+            "--config".to_string(),
+            self.config.display().to_string(),
+            self.role.as_str().to_string(),
+        ]
+    }
+
+    /// Command line the Service Control Manager starts
+    fn windows_arguments(&self) -> Vec<String> {
+        let mut arguments = self.arguments();
+        let role = arguments.pop().unwrap_or_default();
+        arguments.extend(["service".to_string(), "run".to_string(), "--name".to_string(), self.name.clone(), role]);
+        arguments
+    }
+}
+
+/// Request from the Windows Service Control Manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Stop,
+    Shutdown,
+    Pause,
+    Continue,
+    Interrogate,
+    /// Anything the service does not accept
+    Other,
+}
+
+/// State reported to the Service Control Manager after a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportedState {
+    Running,
+    Paused,
+    StopPending,
+}
+
+/// How the service answers a [`Control`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ControlResponse {
+    /// Whether the request is implemented
+    pub handled: bool,
+    /// Whether to stop accepting new connections, if that changes
+    pub paused: Option<bool>,
+    /// Whether to shut down
+    pub shutdown: bool,
+    /// State to report, if it changes
+    pub report: Option<ReportedState>,
+}
+
+/// Answer to `control`: stop and shutdown end the process, pause stops
+/// accepting new connections until continued
+pub fn respond(control: Control) -> ControlResponse {
+    match control {
+        Control::Stop | Control::Shutdown => ControlResponse {
+            handled: true,
+            shutdown: true,
+            report: Some(ReportedState::StopPending),
+            ..Default::default()
+        },
+        Control::Pause => ControlResponse {
+            handled: true,
+            paused: Some(true),
+            report: Some(ReportedState::Paused),
+            ..Default::default()
+        },
+        Control::Continue => ControlResponse {
+            handled: true,
+            paused: Some(false),
+            report: Some(ReportedState::Running),
+            ..Default::default()
+        },
+        Control::Interrogate => ControlResponse { handled: true, ..Default::default() },
+        Control::Other => ControlResponse::default(),
+    }
+}
+
+/// Stop or resume accepting new connections, for pausing a service
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+}
+
+/// Wait until accepting new connections is not paused
+pub async fn resumed() {
+    while PAUSED.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Write and enable the service; returns where it was registered
+pub fn install(spec: &ServiceSpec) -> Result<String> {
+    if spec.name.is_empty() || spec.name.contains(['/', '\\', ' ']) {
+        bail!("Invalid service name {:?}", spec.name);
+    }
+    if cfg!(target_os = "macos") {
+        let path = launchd_path(&spec.name, spec.user)?;
+        write_file(&path, &launchd_plist(spec, &launchd_log(&spec.name, spec.user)?))?;
+        Ok(path.display().to_string())
+    } else if cfg!(windows) {
+        if spec.user {
+            bail!("Windows services are system-wide; install without --user");
+        }
+        for (program, arguments) in windows_install_commands(spec) {
+            run(Command::new(program).args(arguments), None)?;
+        }
+        Ok(format!("Windows service {}", spec.name))
+    } else {
+        let path = systemd_path(&spec.name, spec.user)?;
+        write_file(&path, &systemd_unit(spec))?;
+        systemctl(spec.user, &["daemon-reload"])?;
+        systemctl(spec.user, &["enable", &format!("{}.service", spec.name)])?;
+        Ok(path.display().to_string())
+    }
+}
+
+/// Stop and remove the service
+pub fn uninstall(name: &str, user: bool) -> Result<()> {
+    // Stopping fails when it is not running, which is fine here
+    let _ = stop(name, user);
+    if cfg!(target_os = "macos") {
+        remove_file(&launchd_path(name, user)?)
+    } else if cfg!(windows) {
+        run(Command::new("sc.exe").args(["delete", name]), None)?;
+        let _ = run(Command::new("reg").args(["delete", &format!("{}\\{}", EVENT_LOG_KEY, name), "/f"]), None);
+        Ok(())
+    } else {
+        systemctl(user, &["disable", &format!("{}.service", name)])?;
+        remove_file(&systemd_path(name, user)?)?;
+        systemctl(user, &["daemon-reload"])
+    }
+}
+
+/// Start the installed service
+pub fn start(name: &str, user: bool) -> Result<()> {
+    if cfg!(target_os = "macos") {
+        let path = launchd_path(name, user)?;
+        run(Command::new("launchctl").args(["bootstrap", &launchd_domain(user), &path.display().to_string()]), None)?;
+    } else if cfg!(windows) {
+        run(Command::new("sc.exe").args(["start", name]), None)?;
+    } else {
+        systemctl(user, &["start", &format!("{}.service", name)])?;
+    }
+    Ok(())
+}
+
+/// Stop the running service
+pub fn stop(name: &str, user: bool) -> Result<()> {
+    if cfg!(target_os = "macos") {
+        run(Command::new("launchctl").args(["bootout", &format!("{}/{}", launchd_domain(user), name)]), None)?;
+    } else if cfg!(windows) {
+        run(Command::new("sc.exe").args(["stop", name]), None)?;
+    } else {
+        systemctl(user, &["stop", &format!("{}.service", name)])?;
+    }
+    Ok(())
+}
+
+fn systemctl(user: bool, args: &[&str]) -> Result<()> {
+    let mut command = Command::new("systemctl");
+    if user {
+        command.arg("--user");
+    }
+    run(command.args(args), None)?;
+    Ok(())
+}
+
+fn home() -> Result<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from).context("HOME is not set")
+}
+
+fn systemd_path(name: &str, user: bool) -> Result<PathBuf> {
+    let directory = if user {
+        match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(config_home) => PathBuf::from(config_home).join("systemd/user"),
+            None => home()?.join(".config/systemd/user"),
+        }
+    } else {
+        PathBuf::from("/etc/systemd/system")
+    };
+    Ok(directory.join(format!("{}.service", name)))
+}
+
+/// systemd unit running `spec`
+fn systemd_unit(spec: &ServiceSpec) -> String {
+    let exec_start: Vec<String> = spec
+        .arguments()
+        .iter()
+        .map(|arg| format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%")))
+        .collect();
+    format!(
+        "[Unit]\n\
+         Description=Nooshdaroo {role}\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=simple\n\
+         ExecStart={exec_start}\n\
+         Restart=on-failure\n\
+         RestartSec=5\n\
+         \n\
+         [Install]\n\
+         WantedBy={target}\n",
+        role = spec.role.as_str(),
+        exec_start = exec_start.join(" "),
+        target = if spec.user { "default.target" } else { "multi-user.target" },
+    )
+}
+
+fn launchd_path(name: &str, user: bool) -> Result<PathBuf> {
+    let directory = if user { home()?.join("Library/LaunchAgents") } else { PathBuf::from("/Library/LaunchDaemons") };
+    Ok(directory.join(format!("{}.plist", name)))
+}
+
+fn launchd_log(name: &str, user: bool) -> Result<PathBuf> {
+    let directory = if user { home()?.join("Library/Logs") } else { PathBuf::from("/var/log") };
+    Ok(directory.join(format!("{}.log", name)))
+}
+
+/// `system` for daemons, the user's GUI session for agents
+fn launchd_domain(user: bool) -> String {
+    if user {
+        // SAFETY: getuid has no preconditions
+        format!("gui/{}", unsafe { libc::getuid() })
+    } else {
+        "system".to_string()
+    }
+}
+
+/// launchd property list running `spec`, logging to `log`
+fn launchd_plist(spec: &ServiceSpec, log: &Path) -> String {
+    let escape = |value: &str| value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    let arguments: String = spec
+        .arguments()
+        .iter()
+        .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+        .collect();
+    let log = escape(&log.display().to_string());
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{label}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {arguments}\
+         \x20   </array>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>KeepAlive</key>\n\
+         \x20   <dict>\n\
+         \x20       <key>SuccessfulExit</key>\n\
+         \x20       <false/>\n\
+         \x20   </dict>\n\
+         \x20   <key>StandardErrorPath</key>\n\
+         \x20   <string>{log}</string>\n\
+         </dict>\n\
+         </plist>\n",
+        label = escape(&spec.name),
+    )
+}
+
+/// `sc.exe` and `reg` invocations registering `spec` as a Windows service
+/// logging to the event log
+fn windows_install_commands(spec: &ServiceSpec) -> Vec<(&'static str, Vec<String>)> {
+    let strings = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let name = spec.name.as_str();
+    let command_line = windows_command_line(&spec.windows_arguments());
+    let display_name = format!("Nooshdaroo {}", spec.role.as_str());
+    let source = format!("{}\\{}", EVENT_LOG_KEY, spec.name);
+    vec![
+        (
+            "sc.exe",
+            strings(&["create", name, "binPath=", &command_line, "start=", "auto", "DisplayName=", &display_name]),
+        ),
+        ("sc.exe", strings(&["description", name, "Protocol shape-shifting proxy"])),
+        // Restart after crashes, like Restart=on-failure
+        ("sc.exe", strings(&["failure", name, "reset=", "86400", "actions=", "restart/5000"])),
+        // EventCreate.exe's message table shows event 1 as its text
+        (
+            "reg",
+            strings(&[
+                "add",
+                &source,
+                "/v",
+                "EventMessageFile",
+                "/t",
+                "REG_EXPAND_SZ",
+                "/d",
+                r"%SystemRoot%\System32\EventCreate.exe",
+                "/f",
+            ]),
+        ),
+        ("reg", strings(&["add", &source, "/v", "TypesSupported", "/t", "REG_DWORD", "/d", "7", "/f"])),
+    ]
+}
+
+/// Windows command line quoting each argument
+fn windows_command_line(arguments: &[String]) -> String {
+    arguments
+        .iter()
+        .map(|arg| format!("\"{}\"", arg.replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Cannot create {}", parent.display()))?;
+    }
+    fs::write(path, contents).with_context(|| format!("Cannot write {} (administrator rights may be needed)", path.display()))
+}
+
+fn remove_file(path: &Path) -> Result<()> {
+    fs::remove_file(path).with_context(|| format!("Cannot remove {}", path.display()))
+}
+
+/// Detach from the terminal: fork into the background, start a new session
+/// and point stdin and stdout at `/dev/null` and stderr at `log` (or
+/// `/dev/null`). Must run before any threads are started. The working
+/// directory is kept, so relative paths in the config still resolve.
+#[cfg(unix)]
+pub fn daemonize(log: Option<&Path>) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let null = fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let log = match log {
+        Some(path) => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open log file {}", path.display()))?,
+        None => null.try_clone()?,
+    };
+    // SAFETY: the process is still single-threaded, so forking is sound;
+    // the parents exit without running destructors
+    unsafe {
+        fork_and_exit_parent()?;
+        // A new session leaves the terminal; forking again makes sure the
+        // daemon, no longer the session leader, never acquires one
+        if libc::setsid() == -1 {
+            bail!("setsid failed: {}", std::io::Error::last_os_error());
+        }
+        fork_and_exit_parent()?;
+        for (file, fd) in [(&null, 0), (&null, 1), (&log, 2)] {
+            if libc::dup2(file.as_raw_fd(), fd) == -1 {
+                bail!("dup2 failed: {}", std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Continue in a child process
+///
+/// # Safety
+/// The process must be single-threaded.
+#[cfg(unix)]
+unsafe fn fork_and_exit_parent() -> Result<()> {
+    match libc::fork() {
+        -1 => bail!("fork failed: {}", std::io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => libc::_exit(0),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log: Option<&Path>) -> Result<()> {
+    bail!("--daemon is not supported on this platform; install a service instead")
+}
+
+/// File holding the process ID, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    /// Kept open to hold the lock
+    _file: File,
+}
+
+impl PidFile {
+    /// Write the process ID to `path`, failing if another running process
+    /// holds it
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .read(true)
+            .open(path)
+            .with_context(|| format!("Cannot open pidfile {}", path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            // SAFETY: the descriptor belongs to `file`, which is open
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let running = fs::read_to_string(path).unwrap_or_default();
+                if !crate::drain::is_successor() {
+                    bail!("Already running (pid {}) according to {}", running.trim(), path.display());
+                }
+                // The draining predecessor holds the lock until it exits
+                let fd = file.as_raw_fd();
+                // SAFETY: `file` stays open for the life of the process
+                std::thread::spawn(move || unsafe { libc::flock(fd, libc::LOCK_EX) });
+            }
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(Self { path: path.to_path_buf(), _file: file })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // A successor the listeners were handed to owns the file now
+        let owner = fs::read_to_string(&self.path).unwrap_or_default();
+        if owner.trim() == std::process::id().to_string() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Windows Service Control Manager runtime
+#[cfg(windows)]
+pub mod scm {
+    use super::{respond, set_paused, Control, ReportedState};
+    use crate::system_proxy::trigger_shutdown;
+    use anyhow::{bail, Result};
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::{define_windows_service, service_dispatcher};
+    use windows_sys::Win32::System::EventLog::{
+        RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+    };
+
+    type Serve = Box<dyn FnOnce() -> Result<()> + Send>;
+
+    /// Service name and what it runs, handed to the dispatcher's thread
+    static SERVICE: Mutex<Option<(String, Serve)>> = Mutex::new(None);
+
+    /// Handle for reporting state, set once the control handler is registered
+    static STATUS: Mutex<Option<ServiceStatusHandle>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Run `serve` as the service `name`, returning once it has stopped.
+    /// Stop and shutdown requests make
+    /// [`shutdown_signal`](crate::system_proxy::shutdown_signal) return,
+    /// so `serve` should finish when it does.
+    pub fn run(name: &str, serve: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+        *SERVICE.lock().unwrap() = Some((name.to_string(), Box::new(serve)));
+        // Blocks until the service stops, calling service_main on its own thread
+        service_dispatcher::start(name, ffi_service_main)?;
+        Ok(())
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some((name, serve)) = SERVICE.lock().unwrap().take() else {
+            return;
+        };
+        let handler = |control: ServiceControl| {
+            let response = respond(control.into());
+            if !response.handled {
+                return ServiceControlHandlerResult::NotImplemented;
+            }
+            if response.shutdown {
+                log::info!("Stop requested by the Service Control Manager");
+            }
+            if let Some(paused) = response.paused {
+                set_paused(paused);
+                if paused {
+                    log::info!("Paused; not accepting new connections");
+                } else {
+                    log::info!("Continued; accepting connections");
+                }
+            }
+            if let Some(state) = response.report {
+                report(state.into(), ServiceExitCode::Win32(0));
+            }
+            if response.shutdown {
+                trigger_shutdown();
+            }
+            ServiceControlHandlerResult::NoError
+        };
+        match service_control_handler::register(&name, handler) {
+            Ok(handle) => *STATUS.lock().unwrap() = Some(handle),
+            Err(e) => {
+                log::error!("Cannot register the {} service control handler: {}", name, e);
+                return;
+            }
+        }
+
+        report(ServiceState::Running, ServiceExitCode::Win32(0));
+        let exit_code = match serve() {
+            Ok(()) => ServiceExitCode::Win32(0),
+            Err(e) => {
+                log::error!("Service {} failed: {:#}", name, e);
+                ServiceExitCode::ServiceSpecific(1)
+            }
+        };
+        report(ServiceState::Stopped, exit_code);
+    }
+
+    impl From<ServiceControl> for Control {
+        fn from(control: ServiceControl) -> Self {
+            match control {
+                ServiceControl::Stop => Control::Stop,
+                ServiceControl::Shutdown => Control::Shutdown,
+                ServiceControl::Pause => Control::Pause,
+                ServiceControl::Continue => Control::Continue,
+                ServiceControl::Interrogate => Control::Interrogate,
+                _ => Control::Other,
+            }
+        }
+    }
+
+    impl From<ReportedState> for ServiceState {
+        fn from(state: ReportedState) -> Self {
+            match state {
+                ReportedState::Running => ServiceState::Running,
+                ReportedState::Paused => ServiceState::Paused,
+                ReportedState::StopPending => ServiceState::StopPending,
+            }
+        }
+    }
+
+    fn report(state: ServiceState, exit_code: ServiceExitCode) {
+        let Some(handle) = *STATUS.lock().unwrap() else {
+            return;
+        };
+        let controls_accepted = match state {
+            ServiceState::Running | ServiceState::Paused => {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN | ServiceControlAccept::PAUSE_CONTINUE
+            }
+            _ => ServiceControlAccept::empty(),
+        };
+        let wait_hint = match state {
+            ServiceState::StopPending => Duration::from_secs(10),
+            _ => Duration::default(),
+        };
+        let status = ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        };
+        if let Err(e) = handle.set_service_status(status) {
+            log::warn!("Cannot report service state {:?}: {}", state, e);
+        }
+    }
+
+    /// Logger writing to the Application event log
+    pub struct EventLog {
+        /// Event source handle, valid for the life of the process
+        source: usize,
+        level: log::LevelFilter,
+    }
+
+    impl EventLog {
+        /// Log records up to `level` under the event source `name`
+        pub fn install(name: &str, level: log::LevelFilter) -> Result<()> {
+            let name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+            // SAFETY: `name` is NUL-terminated; a null server is the local machine
+            let source = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+            if source.is_null() {
+                bail!("Cannot register the event source: {}", std::io::Error::last_os_error());
+            }
+            let logger = Box::leak(Box::new(EventLog { source: source as usize, level }));
+            log::set_logger(logger).map_err(|e| anyhow::anyhow!("{}", e))?;
+            log::set_max_level(level);
+            Ok(())
+        }
+    }
+
+    impl log::Log for EventLog {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= self.level
+        }
+
+        fn log(&self, record: &log::Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let kind = match record.level() {
+                log::Level::Error => EVENTLOG_ERROR_TYPE,
+                log::Level::Warn => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let message: Vec<u16> = format!("{}: {}", record.target(), record.args())
+                .encode_utf16()
+                .chain(Some(0))
+                .collect();
+            let strings = [message.as_ptr()];
+            // SAFETY: the source handle stays open and `strings` holds one
+            // NUL-terminated string
+            unsafe {
+                ReportEventW(
+                    self.source as _,
+                    kind,
+                    0,
+                    1,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null(),
+                );
+            }
+        }
+
+        fn flush(&self) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_files() {
+        let spec = ServiceSpec {
+            name: "nooshdaroo".to_string(),
+            role: Role::Server,
+            executable: PathBuf::from("/usr/local/bin/nooshdaroo"),
+            config: PathBuf::from("/etc/nooshdaroo/server 1.toml"),
+            user: false,
+        };
+        let unit = systemd_unit(&spec);
+        assert!(unit.contains(
+            "ExecStart=\"/usr/local/bin/nooshdaroo\" \"--config\" \"/etc/nooshdaroo/server 1.toml\" \"server\"\n"
+        ));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+
+        let plist = launchd_plist(&spec, Path::new("/var/log/nooshdaroo.log"));
+        assert!(plist.contains("    <key>Label</key>\n    <string>nooshdaroo</string>\n"));
+        assert!(plist.contains("        <string>/etc/nooshdaroo/server 1.toml</string>\n        <string>server</string>\n"));
+
+        assert_eq!(
+            windows_command_line(&["C:\\nooshdaroo.exe".to_string(), "server".to_string()]),
+            "\"C:\\nooshdaroo.exe\" \"server\""
+        );
+        assert_eq!(
+            spec.windows_arguments()[3..],
+            ["service", "run", "--name", "nooshdaroo", "server"]
+        );
+    }
+
+    #[test]
+    fn test_windows_install_commands() {
+        let spec = ServiceSpec {
+            name: "nooshdaroo".to_string(),
+            role: Role::Client,
+            executable: PathBuf::from("C:\\Program Files\\Nooshdaroo\\nooshdaroo.exe"),
+            config: PathBuf::from("C:\\ProgramData\\Nooshdaroo\\client.toml"),
+            user: false,
+        };
+        let commands = windows_install_commands(&spec);
+        let programs: Vec<&str> = commands.iter().map(|(program, _)| *program).collect();
+        assert_eq!(programs, ["sc.exe", "sc.exe", "sc.exe", "reg", "reg"]);
+        assert_eq!(
+            commands[0].1,
+            [
+                "create",
+                "nooshdaroo",
+                "binPath=",
+                "\"C:\\Program Files\\Nooshdaroo\\nooshdaroo.exe\" \"--config\" \
+                 \"C:\\ProgramData\\Nooshdaroo\\client.toml\" \"service\" \"run\" \"--name\" \"nooshdaroo\" \"client\"",
+                "start=",
+                "auto",
+                "DisplayName=",
+                "Nooshdaroo client",
+            ]
+        );
+        assert_eq!(commands[2].1, ["failure", "nooshdaroo", "reset=", "86400", "actions=", "restart/5000"]);
+        let source = format!("{}\\nooshdaroo", EVENT_LOG_KEY);
+        assert!(commands[3..].iter().all(|(_, arguments)| arguments[..2] == ["add", source.as_str()]));
+    }
+
+    #[test]
+    fn test_control_responses() {
+        for control in [Control::Stop, Control::Shutdown] {
+            let response = respond(control);
+            assert!(response.handled && response.shutdown);
+            assert_eq!(response.report, Some(ReportedState::StopPending));
+            assert_eq!(response.paused, None);
+        }
+        let pause = respond(Control::Pause);
+        assert_eq!((pause.paused, pause.report, pause.shutdown), (Some(true), Some(ReportedState::Paused), false));
+        let resume = respond(Control::Continue);
+        assert_eq!((resume.paused, resume.report, resume.shutdown), (Some(false), Some(ReportedState::Running), false));
+        assert_eq!(respond(Control::Interrogate), ControlResponse { handled: true, ..Default::default() });
+        assert!(!respond(Control::Other).handled);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_scm_controls() {
+        use windows_service::service::{ServiceControl, ServiceState};
+
+        assert_eq!(Control::from(ServiceControl::Stop), Control::Stop);
+        assert_eq!(Control::from(ServiceControl::Continue), Control::Continue);
+        assert_eq!(Control::from(ServiceControl::NetBindAdd), Control::Other);
+        assert_eq!(ServiceState::from(ReportedState::StopPending), ServiceState::StopPending);
+    }
+
+    #[test]
+    fn test_pidfile_lock() {
+        let path = std::env::temp_dir().join(format!("nooshdaroo-test-{}.pid", std::process::id()));
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        #[cfg(unix)]
+        assert!(PidFile::create(&path).is_err());
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
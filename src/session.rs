@@ -0,0 +1,141 @@
+//! Resumable client session state
+//!
+//! The client remembers the protocol and server port its last tunnels were
+//! established with, along with the upstream pool's health, in a small JSON
+//! file (`[socks] session_file`). On restart a fresh enough session is reused
+//! directly, so the first connection goes out on the path that worked
+//! instead of waiting for path discovery and health checks.
+
+use crate::protocol::ProtocolId;
+use crate::upstream::UpstreamPool;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Health of one upstream server at the time the session was saved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedUpstream {
+    pub addr: SocketAddr,
+    pub healthy: bool,
+    pub score: f64,
+    pub consecutive_failures: u32,
+}
+
+/// Last known working path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionState {
+    /// Unix seconds
+    pub saved_at: u64,
+    /// Protocol the last tunnels were established with
+    pub protocol: ProtocolId,
+    /// Server (with the port that worked), when not using an upstream pool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<SocketAddr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<SavedUpstream>,
+}
+
+impl SessionState {
+    /// Capture the current path and pool health
+    pub fn new(protocol: ProtocolId, server: Option<SocketAddr>, upstreams: Option<&UpstreamPool>) -> Self {
+        let upstreams = upstreams
+            .map(|pool| {
+                pool.status()
+                    .into_iter()
+                    .map(|s| SavedUpstream {
+                        addr: s.server.addr,
+                        healthy: s.healthy,
+                        score: s.score,
+                        consecutive_failures: s.consecutive_failures,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            protocol,
+            server,
+            upstreams,
+        }
+    }
+
+    /// Load the session at `path` if it exists and is younger than `max_age`
+    pub fn load(path: &Path, max_age: Duration) -> io::Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let state: Self = serde_json::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((state.age() <= max_age).then_some(state))
+    }
+
+    /// Write the session to `path`, replacing it atomically (mode 600)
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Time since the session was saved
+    pub fn age(&self) -> Duration {
+        let saved = UNIX_EPOCH + Duration::from_secs(self.saved_at);
+        SystemTime::now().duration_since(saved).unwrap_or_default()
+    }
+
+    /// The saved server address if it is `configured` (possibly on another port)
+    pub fn server_for(&self, configured: SocketAddr) -> Option<SocketAddr> {
+        self.server.filter(|saved| saved.ip() == configured.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::UpstreamServer;
+
+    #[test]
+    fn test_session_round_trip() {
+        let path = std::env::temp_dir().join(format!("nooshdaroo-session-{}.json", rand::random::<u32>()));
+        assert_eq!(SessionState::load(&path, Duration::from_secs(60)).unwrap(), None);
+
+        let a: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:443".parse().unwrap();
+        let pool = UpstreamPool::new(vec![UpstreamServer::new(a), UpstreamServer::new(b)]);
+        for _ in 0..3 {
+            pool.record_failure(b);
+        }
+
+        let state = SessionState::new(ProtocolId::from("ssh"), Some("192.0.2.1:22".parse().unwrap()), Some(&pool));
+        state.save(&path).unwrap();
+        let loaded = SessionState::load(&path, Duration::from_secs(60)).unwrap().unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(loaded.server_for("192.0.2.1:8443".parse().unwrap()), Some("192.0.2.1:22".parse().unwrap()));
+        assert_eq!(loaded.server_for("198.51.100.1:22".parse().unwrap()), None);
+
+        // A fresh pool starts out trusting every server until restored
+        let restarted = UpstreamPool::new(vec![UpstreamServer::new(a), UpstreamServer::new(b)]);
+        restarted.restore(&loaded.upstreams);
+        let status = restarted.status();
+        assert!(status[0].healthy);
+        assert!(!status[1].healthy);
+        assert_eq!(status[1].consecutive_failures, 3);
+
+        let stale = SessionState { saved_at: state.saved_at - 120, ..state };
+        stale.save(&path).unwrap();
+        assert_eq!(SessionState::load(&path, Duration::from_secs(60)).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
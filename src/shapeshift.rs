@@ -1,10 +1,13 @@
 //! Shape-shifting controller
 
 use super::config::ShapeShiftConfig;
+use super::counters::{ConnectionCounters, TrafficRegistry, TrafficSnapshot};
+use super::events::{ClientEvent, EventSender};
 use super::library::ProtocolLibrary;
 use super::protocol::ProtocolId;
 use super::strategy::StrategyType;
 use super::{NooshdarooError, ProtocolStats};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -15,6 +18,10 @@ pub struct ShapeShiftController {
     strategy: StrategyType,
     stats: ProtocolStats,
     start_time: Instant,
+    traffic: Arc<TrafficRegistry>,
+    /// Relayed traffic already fed to the strategy
+    synced_traffic: TrafficSnapshot,
+    events: EventSender,
 }
 
 impl ShapeShiftController {
@@ -43,13 +50,30 @@ impl ShapeShiftController {
                 total_switches: 0,
                 bytes_transferred: 0,
                 packets_transferred: 0,
+                protocol_traffic: HashMap::new(),
                 uptime: Duration::ZERO,
                 last_switch: None,
+                total_failovers: 0,
+                last_failover: None,
+                emulation_fidelity: None,
+                dns_leaks_blocked: 0,
+                last_dns_leak: None,
+                dns_cache: Default::default(),
+                blocking_events: Vec::new(),
+                path_quality: HashMap::new(),
             },
             start_time: Instant::now(),
+            traffic: Arc::new(TrafficRegistry::default()),
+            synced_traffic: TrafficSnapshot::default(),
+            events: EventSender::new(),
         })
     }
 
+    /// Channel this client's events go out on
+    pub fn events(&self) -> &EventSender {
+        &self.events
+    }
+
     /// Get current active protocol
     pub fn current_protocol(&self) -> ProtocolId {
         self.stats.current_protocol.clone()
@@ -64,9 +88,16 @@ impl ShapeShiftController {
             ));
         }
 
+        if protocol_id != self.stats.current_protocol {
+            self.events.emit(ClientEvent::ProtocolRotated {
+                from: self.stats.current_protocol.clone(),
+                to: protocol_id.clone(),
+            });
+        }
         self.stats.current_protocol = protocol_id;
         self.stats.total_switches += 1;
         self.stats.last_switch = Some(Instant::now());
+        self.stats.emulation_fidelity = None;
 
         Ok(())
     }
@@ -98,9 +129,16 @@ impl ShapeShiftController {
                 return Err(NooshdarooError::ProtocolNotFound(protocol.to_string()));
             }
 
+            if protocol != self.stats.current_protocol {
+                self.events.emit(ClientEvent::ProtocolRotated {
+                    from: self.stats.current_protocol.clone(),
+                    to: protocol.clone(),
+                });
+            }
             self.stats.current_protocol = protocol;
             self.stats.total_switches += 1;
             self.stats.last_switch = Some(Instant::now());
+            self.stats.emulation_fidelity = None;
         }
 
         Ok(())
@@ -116,6 +154,66 @@ impl ShapeShiftController {
         }
     }
 
+    /// Per-protocol counters updated by the relay loops
+    pub fn traffic(&self) -> &Arc<TrafficRegistry> {
+        &self.traffic
+    }
+
+    /// Counters for a new connection using the current protocol
+    pub fn connection_counters(&self) -> ConnectionCounters {
+        self.traffic.connection(&self.stats.current_protocol)
+    }
+
+    /// Record a tunnel using `protocol` that could not be established
+    pub fn record_handshake_failure(&self, protocol: &ProtocolId) {
+        self.traffic.protocol(protocol).record_handshake_failure();
+    }
+
+    /// Feed traffic relayed since the last call to traffic-based strategies
+    pub fn sync_traffic(&mut self) {
+        let total = self.traffic.total();
+        let bytes = total.bytes() - self.synced_traffic.bytes();
+        let packets = total.packets() - self.synced_traffic.packets();
+        self.synced_traffic = total;
+
+        if let StrategyType::TrafficBased(ref mut s) = self.strategy {
+            s.record_traffic(bytes, packets);
+        }
+    }
+
+    /// Record a failover to a different upstream server
+    pub fn record_failover(&mut self) {
+        self.stats.total_failovers += 1;
+        self.stats.last_failover = Some(Instant::now());
+    }
+
+    /// Record DNS queries blocked from leaking outside the tunnel
+    pub fn record_dns_leaks(&mut self, count: u64) {
+        self.stats.dns_leaks_blocked += count;
+        self.stats.last_dns_leak = Some(Instant::now());
+    }
+
+    /// Record the tunneled DNS cache's counters
+    pub fn record_dns_cache(&mut self, stats: crate::dns_cache::DnsCacheStats) {
+        self.stats.dns_cache = stats;
+    }
+
+    /// React to a detected censorship event: blocking of the current
+    /// protocol raises suspicion for adaptive strategies
+    pub fn record_blocking(&mut self, event: &crate::blocking::BlockingEvent) {
+        if event.protocol.as_ref().is_none_or(|p| *p == self.stats.current_protocol) {
+            self.update_suspicion(1.0);
+        }
+    }
+
+    /// Record the measured emulation fidelity of the current protocol.
+    /// Poor fidelity raises suspicion for adaptive strategies.
+    pub fn record_fidelity(&mut self, fidelity: f64) {
+        let fidelity = fidelity.clamp(0.0, 1.0);
+        self.stats.emulation_fidelity = Some(fidelity);
+        self.update_suspicion(1.0 - fidelity);
+    }
+
     /// Update suspicion score (for adaptive strategies)
     pub fn update_suspicion(&mut self, score: f64) {
         if let StrategyType::Adaptive(ref mut s) = self.strategy {
@@ -126,12 +224,19 @@ impl ShapeShiftController {
     /// Get statistics
     pub fn stats(&self) -> ProtocolStats {
         let mut stats = self.stats.clone();
+        let relayed = self.traffic.total();
+        stats.bytes_transferred += relayed.bytes();
+        stats.packets_transferred += relayed.packets();
+        stats.protocol_traffic = self.traffic.by_protocol();
         stats.uptime = self.start_time.elapsed();
+        stats.blocking_events = crate::blocking::recent();
+        stats.path_quality = crate::path_quality::snapshot();
         stats
     }
 
     /// Check and auto-rotate if needed
     pub async fn check_and_rotate(&mut self) -> Result<bool, NooshdarooError> {
+        self.sync_traffic();
         if self.should_rotate() {
             self.rotate()?;
             Ok(true)
@@ -140,6 +245,17 @@ impl ShapeShiftController {
         }
     }
 
+    /// Protocol library protocols are chosen from
+    pub fn library(&self) -> &Arc<ProtocolLibrary> {
+        &self.library
+    }
+
+    /// Swap in an updated protocol library; protocols already chosen stay
+    /// in use
+    pub fn set_library(&mut self, library: Arc<ProtocolLibrary>) {
+        self.library = library;
+    }
+
     /// Get protocol metadata for current protocol
     pub fn current_protocol_meta(&self) -> Option<&super::protocol::ProtocolMeta> {
         self.library.get(&self.stats.current_protocol)
@@ -165,6 +281,7 @@ mod tests {
         let library = Arc::new(ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap());
         let config = ShapeShiftConfig {
             strategy: StrategyType::Fixed(FixedStrategy::new(ProtocolId::from("https"))),
+            ..Default::default()
         };
 
         let controller = ShapeShiftController::new(config, library);
@@ -176,6 +293,7 @@ mod tests {
         let library = Arc::new(ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap());
         let config = ShapeShiftConfig {
             strategy: StrategyType::Fixed(FixedStrategy::new(ProtocolId::from("https"))),
+            ..Default::default()
         };
 
         let mut controller = ShapeShiftController::new(config, library).unwrap();
@@ -190,6 +308,7 @@ mod tests {
         let library = Arc::new(ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap());
         let config = ShapeShiftConfig {
             strategy: StrategyType::Fixed(FixedStrategy::new(ProtocolId::from("https"))),
+            ..Default::default()
         };
 
         let mut controller = ShapeShiftController::new(config, library).unwrap();
@@ -199,4 +318,29 @@ mod tests {
         assert_eq!(stats.bytes_transferred, 1000);
         assert_eq!(stats.packets_transferred, 10);
     }
+
+    #[test]
+    fn test_relayed_traffic_stats() {
+        let library = Arc::new(ProtocolLibrary::load(&PathBuf::from("protocols")).unwrap());
+        let config = ShapeShiftConfig {
+            strategy: StrategyType::Fixed(FixedStrategy::new(ProtocolId::from("https"))),
+            ..Default::default()
+        };
+
+        let controller = ShapeShiftController::new(config, library).unwrap();
+        let counters = controller.connection_counters();
+        counters.record_rtt(Duration::from_millis(30));
+        counters.record_sent(300);
+        counters.record_received(1200);
+        controller.record_handshake_failure(&ProtocolId::from("https"));
+
+        let stats = controller.stats();
+        assert_eq!(stats.bytes_transferred, 1500);
+        assert_eq!(stats.packets_transferred, 2);
+        let https = &stats.protocol_traffic[&ProtocolId::from("https")];
+        assert_eq!(https.bytes_received, 1200);
+        assert_eq!(https.connections, 1);
+        assert_eq!(https.avg_rtt(), Some(Duration::from_millis(30)));
+        assert_eq!(https.failure_rate(), 0.5);
+    }
 }
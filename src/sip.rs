@@ -0,0 +1,836 @@
+//! SIP registration and calls of a VoIP phone
+//!
+//! Desk phones and softphones register with their PBX and place calls over
+//! SIP, and on TCP port 5060 that conversation is plain text. With the `sip`
+//! transport a tunnel opens the way a softphone starts its day: a
+//! `REGISTER` answered with a digest challenge, the `REGISTER` repeated
+//! with its `Authorization` and accepted, then an `INVITE` offering a voice
+//! codec in SDP, which the PBX answers with `100 Trying`, `180 Ringing` and
+//! after a short ring `200 OK`, and the phone's `ACK`. Tunnel data then
+//! travels in the bodies of `INFO` requests within the call, each answered
+//! with `200 OK`. The PBX qualifies the phone with an `OPTIONS` every
+//! minute, the phone refreshes its registration before it expires and a
+//! `BYE` ends the call.
+//!
+//! ```toml
+//! [socks]
+//! transport = "sip"
+//! http_host = "pbx.example.com"   # SIP domain, the server's IP by default
+//!
+//! [server]
+//! transport = "sip"
+//! ```
+//!
+//! A call whose media never flows is odd, though. With `relay.sip` set next
+//! to `relay.voip_codec`, `kcp` sessions become the media of a signaled
+//! call instead: the client registers and places the call over TCP to the
+//! server's port, offering the codec and the UDP port of its RTP stream,
+//! and the [voice call](crate::rtp_voip) carrying the session follows once
+//! the PBX answers. The call is hung up when the session ends.
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//!
+//! [server]
+//! transport = "kcp"
+//!
+//! [relay]
+//! voip_codec = "pcmu"
+//! sip = true
+//! ```
+//!
+//! The server takes any credentials, having no directory of phones to check
+//! them against.
+
+use crate::config::{TurnConfig, VoipCodec};
+use crate::kcp_transport;
+use crate::rtp_voip::RtpConn;
+use md5::{Digest, Md5};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Buffer between the tunnel and the session
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest tunnel chunk sent in one `INFO`
+pub const MAX_BODY: usize = 4096;
+
+/// Largest start line and headers accepted
+const MAX_HEADERS: usize = 8192;
+
+/// Largest body accepted
+const MAX_MESSAGE_BODY: usize = 64 * 1024;
+
+/// Registration lifetime phones ask for, in seconds
+const REGISTER_EXPIRES: u64 = 3600;
+
+/// How often the PBX qualifies the phone, Asterisk's default
+const QUALIFY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time from `180 Ringing` to the answer, in milliseconds
+const RING_TIME: std::ops::Range<u64> = 500..1500;
+
+/// Messages the PBX takes before the call must be up
+const SETUP_MESSAGES: usize = 8;
+
+/// How often registration and qualify timers are checked
+const TIMER_TICK: Duration = Duration::from_secs(1);
+
+/// Softphones and desk phones the client passes for
+const USER_AGENTS: &[&str] = &[
+    "Linphone/5.2.5 (belle-sip/5.2.4)",
+    "Zoiper rv2.10.20.4",
+    "MicroSIP/3.21.4",
+    "Yealink SIP-T46U 108.86.0.70",
+];
+
+/// PBXs the server passes for
+const SERVER_AGENTS: &[&str] = &["Asterisk PBX 18.20.2", "Asterisk PBX 20.6.0", "FPBX-16.0.40.7(18.20.2)"];
+
+const ALLOW: &str = "INVITE, ACK, CANCEL, BYE, OPTIONS, INFO, REFER, NOTIFY, MESSAGE, SUBSCRIBE, UPDATE";
+
+/// Payload type of the RFC 4733 telephone events offered with the codec
+const TELEPHONE_EVENT: u8 = 101;
+
+/// Content type of tunnel data in `INFO` bodies
+const DATA_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Compact forms of header names (RFC 3261, section 7.3.3)
+const COMPACT_FORMS: &[(&str, &str)] = &[
+    ("v", "Via"),
+    ("f", "From"),
+    ("t", "To"),
+    ("i", "Call-ID"),
+    ("m", "Contact"),
+    ("l", "Content-Length"),
+    ("c", "Content-Type"),
+];
+
+/// Start the client side over `stream`, connected from `local`, registering
+/// with `domain`; the returned stream carries the tunnel
+///
+/// The call offers `codec`, G.711 µ-law if unset.
+pub async fn connect<S>(stream: S, domain: &str, local: SocketAddr, codec: Option<VoipCodec>) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let media_port = rand::thread_rng().gen_range(5000..16000) * 2;
+    open(stream, domain, local, codec.unwrap_or(VoipCodec::Pcmu), SocketAddr::new(local.ip(), media_port)).await
+}
+
+/// Start the server side over an accepted `stream`, accepted on `local`;
+/// the returned stream carries the tunnel
+pub async fn accept<S>(stream: S, local: SocketAddr) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let media_port = rand::thread_rng().gen_range(5000..16000) * 2;
+    let (stream, dialog) = answer_call(stream, local, SocketAddr::new(local.ip(), media_port)).await?;
+    Ok(spawn_session(stream, dialog, Role::Pbx(Instant::now())))
+}
+
+/// Register and place a call with `codec` over TCP to `server_addr`, then
+/// open a KCP session to it carried in the call's media, through a relay
+/// allocated on `turn` if given; the call is hung up when the session ends
+pub async fn call(server_addr: SocketAddr, domain: &str, codec: VoipCodec, turn: Option<&TurnConfig>) -> io::Result<DuplexStream> {
+    let stream = TcpStream::connect(server_addr).await?;
+    let local = stream.local_addr()?;
+    match turn {
+        Some(turn) => {
+            let (relay, client) = crate::turn_relay::allocate(turn).await?;
+            let media = relay.local_addr().map_err(io::Error::other)?;
+            let signaling = open(stream, domain, local, codec, media).await?;
+            Ok(kcp_transport::connect_over(Arc::new(RtpConn::new(relay, codec)), server_addr, (client, signaling)))
+        }
+        None => {
+            let udp = Arc::new(UdpSocket::bind(kcp_transport::any_local_addr(server_addr)).await?);
+            let media = SocketAddr::new(local.ip(), udp.local_addr()?.port());
+            let signaling = open(stream, domain, local, codec, media).await?;
+            Ok(kcp_transport::connect_over(Arc::new(RtpConn::new(udp, codec)), server_addr, signaling))
+        }
+    }
+}
+
+/// Answers the signaling of `relay.sip` calls on TCP next to the KCP
+/// listener carrying their media, until dropped
+pub struct Registrar {
+    task: JoinHandle<()>,
+}
+
+/// Answer calls on TCP `addr`, the port of the calls' media
+pub async fn serve(addr: SocketAddr) -> io::Result<Registrar> {
+    let listener = TcpListener::bind(addr).await?;
+    let task = tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Failed to accept SIP connection: {}", e);
+                    tokio::time::sleep(TIMER_TICK).await;
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let result = async {
+                    let local = stream.local_addr()?;
+                    let (stream, dialog) = answer_call(stream, local, local).await?;
+                    // Nothing but signaling flows here, the media is the KCP
+                    // session
+                    let mut signaling = spawn_session(stream, dialog, Role::Pbx(Instant::now()));
+                    tokio::io::copy(&mut signaling, &mut tokio::io::sink()).await
+                }
+                .await;
+                if let Err(e) = result {
+                    log::debug!("SIP signaling with {} ended: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(Registrar { task })
+}
+
+impl Drop for Registrar {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Register, place a call with `codec` whose media goes to `media` and
+/// start the session
+async fn open<S>(stream: S, domain: &str, local: SocketAddr, codec: VoipCodec, media: SocketAddr) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut stream = BufReader::new(stream);
+    let (agent, user, callee) = {
+        let mut rng = rand::thread_rng();
+        let agent = Agent { product: USER_AGENTS.choose(&mut rng).unwrap(), pbx: false };
+        (agent, rng.gen_range(100..500).to_string(), rng.gen_range(500..1000).to_string())
+    };
+    let contact = format!("<sip:{}@{};transport=tcp>", user, local);
+
+    let mut registration = Registration {
+        dialog: Dialog {
+            target: format!("sip:{}", domain),
+            local: format!("<sip:{}@{}>", user, domain),
+            tag: token(4),
+            remote: format!("<sip:{}@{}>", user, domain),
+            call_id: token(12),
+            cseq: 0,
+            via: local.to_string(),
+            agent,
+        },
+        contact: contact.clone(),
+        authorization: None,
+        at: Instant::now(),
+    };
+    stream.write_all(&registration.request()).await?;
+    stream.flush().await?;
+    let mut response = read_message(&mut stream).await?;
+    if response.status() == Some(401) {
+        let challenge = response.header("WWW-Authenticate").unwrap_or_default();
+        registration.authorization = Some(authorization(challenge, &user, &format!("sip:{}", domain))?);
+        stream.write_all(&registration.request()).await?;
+        stream.flush().await?;
+        response = read_message(&mut stream).await?;
+    }
+    if response.status() != Some(200) {
+        return Err(invalid(format!("SIP registration refused: {}", response.start)));
+    }
+
+    let mut dialog = Dialog {
+        target: format!("sip:{}@{}", callee, domain),
+        local: format!("<sip:{}@{}>", user, domain),
+        tag: token(4),
+        remote: format!("<sip:{}@{}>", callee, domain),
+        call_id: token(12),
+        cseq: 0,
+        via: local.to_string(),
+        agent,
+    };
+    let offer = sdp(&user, media, codec);
+    let invite = dialog.request(
+        "INVITE",
+        &[("Contact", &contact), ("Allow", ALLOW), ("Content-Type", "application/sdp")],
+        offer.as_bytes(),
+    );
+    stream.write_all(&invite).await?;
+    stream.flush().await?;
+    loop {
+        let response = read_message(&mut stream).await?;
+        match response.status() {
+            Some(100..=199) => {}
+            Some(200) => {
+                if let Some(to) = response.header("To") {
+                    dialog.remote = to.to_string();
+                }
+                if let Some(contact) = response.header("Contact") {
+                    dialog.target = uri(contact).to_string();
+                }
+                break;
+            }
+            _ => return Err(invalid(format!("SIP call refused: {}", response.start))),
+        }
+    }
+    stream.write_all(&dialog.request("ACK", &[], b"")).await?;
+    stream.flush().await?;
+    log::debug!("SIP call from {} to {} answered", user, callee);
+
+    Ok(spawn_session(stream, dialog, Role::Phone(Box::new(registration))))
+}
+
+/// Answer registrations and the call of a phone, offering `media` for the
+/// call's media
+async fn answer_call<S>(stream: S, local: SocketAddr, media: SocketAddr) -> io::Result<(BufReader<S>, Dialog)>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut stream = BufReader::new(stream);
+    let agent = Agent { product: SERVER_AGENTS.choose(&mut rand::thread_rng()).unwrap(), pbx: true };
+    let (tag, nonce, opaque) = (token(4), token(16), token(8));
+    for _ in 0..SETUP_MESSAGES {
+        let request = read_message(&mut stream).await?;
+        let reply = match request.method() {
+            Some("REGISTER") if request.header("Authorization").is_none() => {
+                let challenge = format!(
+                    "Digest realm=\"asterisk\",nonce=\"{}\",opaque=\"{}\",algorithm=md5,qop=\"auth\"",
+                    nonce, opaque
+                );
+                response(&request, "401 Unauthorized", agent, &tag, &[("WWW-Authenticate", &challenge)], b"")
+            }
+            Some("REGISTER") => registered(&request, agent, &tag),
+            Some("OPTIONS") => response(&request, "200 OK", agent, &tag, &[("Allow", ALLOW), ("Accept", "application/sdp")], b""),
+            Some("INVITE") => {
+                let offer = String::from_utf8_lossy(&request.body);
+                let Some(codec) = offered_codec(&offer) else {
+                    stream.write_all(&response(&request, "488 Not Acceptable Here", agent, &tag, &[], b"")).await?;
+                    stream.flush().await?;
+                    return Err(invalid("SIP call offers no supported codec".to_string()));
+                };
+                stream.write_all(&response(&request, "100 Trying", agent, "", &[], b"")).await?;
+                stream.flush().await?;
+                let (trying, ring) = {
+                    let mut rng = rand::thread_rng();
+                    (rng.gen_range(20..120), rng.gen_range(RING_TIME))
+                };
+                tokio::time::sleep(Duration::from_millis(trying)).await;
+                stream.write_all(&response(&request, "180 Ringing", agent, &tag, &[], b"")).await?;
+                stream.flush().await?;
+                tokio::time::sleep(Duration::from_millis(ring)).await;
+
+                let callee = uri(request.start.split(' ').nth(1).unwrap_or_default())
+                    .trim_start_matches("sip:")
+                    .split('@')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                let contact = format!("<sip:{}@{};transport=tcp>", callee, local);
+                let answer = sdp(&callee, media, codec);
+                let ok = response(
+                    &request,
+                    "200 OK",
+                    agent,
+                    &tag,
+                    &[("Contact", &contact), ("Allow", ALLOW), ("Content-Type", "application/sdp")],
+                    answer.as_bytes(),
+                );
+                stream.write_all(&ok).await?;
+                stream.flush().await?;
+                let ack = read_message(&mut stream).await?;
+                if ack.method() != Some("ACK") {
+                    return Err(invalid(format!("Expected ACK, got {}", ack.start)));
+                }
+                let dialog = Dialog {
+                    target: uri(request.header("Contact").unwrap_or_default()).to_string(),
+                    local: request.header("To").unwrap_or_default().to_string(),
+                    tag,
+                    remote: request.header("From").unwrap_or_default().to_string(),
+                    call_id: request.header("Call-ID").unwrap_or_default().to_string(),
+                    cseq: rand::thread_rng().gen_range(100..30000),
+                    via: local.to_string(),
+                    agent,
+                };
+                return Ok((stream, dialog));
+            }
+            _ => {
+                stream.write_all(&response(&request, "405 Method Not Allowed", agent, &tag, &[("Allow", ALLOW)], b"")).await?;
+                stream.flush().await?;
+                return Err(invalid(format!("Unexpected SIP request {}", request.start)));
+            }
+        };
+        stream.write_all(&reply).await?;
+        stream.flush().await?;
+    }
+    Err(invalid("No SIP call after registration".to_string()))
+}
+
+/// Timer duty of a side
+enum Role {
+    /// Refresh this registration before it expires
+    Phone(Box<Registration>),
+    /// Qualify the phone every [`QUALIFY_INTERVAL`], last at this time
+    Pbx(Instant),
+}
+
+fn spawn_session<S>(stream: BufReader<S>, dialog: Dialog, role: Role) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = run_session(stream, dialog, role, local).await {
+            log::debug!("SIP session ended: {}", e);
+        }
+    });
+    tunnel
+}
+
+/// Move tunnel bytes between `local` and `INFO` requests within `dialog`
+/// until either side hangs up
+async fn run_session<S>(stream: BufReader<S>, mut dialog: Dialog, mut role: Role, local: DuplexStream) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // Bytes read ahead during the call setup come first
+    let buffered = stream.buffer().to_vec();
+    let (reader, mut writer) = tokio::io::split(stream.into_inner());
+    let mut reader = BufReader::new(Cursor::new(buffered).chain(reader));
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+
+    // Messages are read in their own task, as a partly read message must
+    // not be dropped when another branch wins
+    let (messages_tx, mut messages) = mpsc::channel(64);
+    let reader = tokio::spawn(async move {
+        while let Ok(message) = read_message(&mut reader).await {
+            if messages_tx.send(message).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let result = async {
+        let mut timer = tokio::time::interval(TIMER_TICK);
+        let mut buf = vec![0u8; MAX_BODY];
+        let mut app_open = true;
+        let (agent, tag) = (dialog.agent, dialog.tag.clone());
+        loop {
+            tokio::select! {
+                message = messages.recv() => {
+                    let Some(message) = message else {
+                        return app_write.shutdown().await;
+                    };
+                    match message.method() {
+                        Some("INFO") => {
+                            app_write.write_all(&message.body).await?;
+                            writer.write_all(&response(&message, "200 OK", agent, &tag, &[], b"")).await?;
+                        }
+                        Some("BYE") => {
+                            writer.write_all(&response(&message, "200 OK", agent, &tag, &[], b"")).await?;
+                            return app_write.shutdown().await;
+                        }
+                        Some("REGISTER") => writer.write_all(&registered(&message, agent, &tag)).await?,
+                        Some("ACK") => {}
+                        Some(_) => {
+                            let headers = [("Allow", ALLOW), ("Accept", "application/sdp")];
+                            writer.write_all(&response(&message, "200 OK", agent, &tag, &headers, b"")).await?;
+                        }
+                        // Responses to our requests
+                        None => {}
+                    }
+                }
+                n = app_read.read(&mut buf), if app_open => {
+                    let n = n?;
+                    if n == 0 {
+                        app_open = false;
+                        writer.write_all(&dialog.request("BYE", &[], b"")).await?;
+                        writer.shutdown().await?;
+                        continue;
+                    }
+                    let info = dialog.request("INFO", &[("Content-Type", DATA_CONTENT_TYPE)], &buf[..n]);
+                    writer.write_all(&info).await?;
+                }
+                _ = timer.tick(), if app_open => match role {
+                    Role::Phone(ref mut registration)
+                        if registration.at.elapsed() >= Duration::from_secs(REGISTER_EXPIRES) * 9 / 10 =>
+                    {
+                        writer.write_all(&registration.request()).await?;
+                    }
+                    Role::Pbx(ref mut qualified) if qualified.elapsed() >= QUALIFY_INTERVAL => {
+                        writer.write_all(&dialog.request("OPTIONS", &[("Accept", "application/sdp")], b"")).await?;
+                        *qualified = Instant::now();
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    result
+}
+
+/// Product of one side: a phone's `User-Agent` or a PBX's `Server`
+#[derive(Clone, Copy)]
+struct Agent {
+    product: &'static str,
+    pbx: bool,
+}
+
+/// Requests of one side within a dialog
+struct Dialog {
+    /// Request-URI, the peer's contact
+    target: String,
+    /// Our `From` and its tag
+    local: String,
+    tag: String,
+    /// Our `To`, with the peer's tag once known
+    remote: String,
+    call_id: String,
+    cseq: u32,
+    /// Address in our `Via`
+    via: String,
+    agent: Agent,
+}
+
+impl Dialog {
+    fn request(&mut self, method: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        // An ACK shares the sequence number of its INVITE
+        if method != "ACK" {
+            self.cseq += 1;
+        }
+        let via = format!("SIP/2.0/TCP {};rport;branch=z9hG4bK{}", self.via, token(8));
+        let from = format!("{};tag={}", self.local, self.tag);
+        let cseq = format!("{} {}", self.cseq, method);
+        let mut all = vec![
+            ("Via", via.as_str()),
+            ("Max-Forwards", "70"),
+            ("From", from.as_str()),
+            ("To", self.remote.as_str()),
+            ("Call-ID", self.call_id.as_str()),
+            ("CSeq", cseq.as_str()),
+        ];
+        all.extend_from_slice(headers);
+        all.push(("User-Agent", self.agent.product));
+        message(&format!("{} {} SIP/2.0", method, self.target), &all, body)
+    }
+}
+
+/// A phone's registration with its PBX
+struct Registration {
+    dialog: Dialog,
+    contact: String,
+    authorization: Option<String>,
+    /// Time of the last `REGISTER`
+    at: Instant,
+}
+
+impl Registration {
+    fn request(&mut self) -> Vec<u8> {
+        self.at = Instant::now();
+        let contact = format!("{};expires={}", self.contact, REGISTER_EXPIRES);
+        let expires = REGISTER_EXPIRES.to_string();
+        let mut headers = vec![("Contact", contact.as_str()), ("Expires", expires.as_str()), ("Allow", ALLOW)];
+        if let Some(ref authorization) = self.authorization {
+            headers.push(("Authorization", authorization.as_str()));
+        }
+        self.dialog.request("REGISTER", &headers, b"")
+    }
+}
+
+/// A request or response
+struct Message {
+    start: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    /// Method of a request
+    fn method(&self) -> Option<&str> {
+        match self.start.starts_with("SIP/2.0 ") {
+            true => None,
+            false => self.start.split(' ').next(),
+        }
+    }
+
+    /// Status code of a response
+    fn status(&self) -> Option<u16> {
+        self.start.strip_prefix("SIP/2.0 ")?.get(..3)?.parse().ok()
+    }
+
+    /// First header `name`, in full or compact form
+    fn header(&self, name: &str) -> Option<&str> {
+        let compact = COMPACT_FORMS.iter().find(|(_, full)| full.eq_ignore_ascii_case(name)).map(|(compact, _)| *compact);
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name) || compact.is_some_and(|compact| n.eq_ignore_ascii_case(compact)))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+async fn read_message<R>(reader: &mut R) -> io::Result<Message>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // Keepalive CRLFs (RFC 5626) may come between messages
+    let mut line = String::new();
+    while line.trim().is_empty() {
+        line.clear();
+        read_line(reader, &mut line).await?;
+    }
+    let start = line.trim_end().to_string();
+    let mut size = line.len();
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_line(reader, &mut line).await?;
+        size += line.len();
+        if size > MAX_HEADERS {
+            return Err(invalid("SIP message headers too long".to_string()));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    let mut message = Message { start, headers, body: Vec::new() };
+    let length: usize = match message.header("Content-Length") {
+        Some(length) => length.parse().map_err(|_| invalid(format!("Bad SIP Content-Length {:?}", length)))?,
+        None => 0,
+    };
+    if length > MAX_MESSAGE_BODY {
+        return Err(invalid(format!("SIP message body of {} bytes", length)));
+    }
+    message.body = vec![0u8; length];
+    reader.read_exact(&mut message.body).await?;
+    Ok(message)
+}
+
+async fn read_line<R>(reader: &mut R, line: &mut String) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let n = (&mut *reader).take(MAX_HEADERS as u64).read_line(line).await?;
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid("SIP message line too long".to_string()));
+    }
+    Ok(())
+}
+
+/// `start` line, `headers`, `Content-Length` and `body`
+fn message(start: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut head = format!("{}\r\n", start);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+    let mut message = head.into_bytes();
+    message.extend_from_slice(body);
+    message
+}
+
+/// Response to `request` with its transaction headers, tagging `To` with
+/// `tag` unless it is empty or the peer's request already carries a tag
+fn response(request: &Message, status: &str, agent: Agent, tag: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let to = request.header("To").unwrap_or_default();
+    let to = match to.contains(";tag=") || tag.is_empty() {
+        true => to.to_string(),
+        false => format!("{};tag={}", to, tag),
+    };
+    let mut all = Vec::new();
+    for name in ["Via", "From"] {
+        all.push((name, request.header(name).unwrap_or_default()));
+    }
+    all.push(("To", &to));
+    for name in ["Call-ID", "CSeq"] {
+        all.push((name, request.header(name).unwrap_or_default()));
+    }
+    all.extend_from_slice(headers);
+    all.push((if agent.pbx { "Server" } else { "User-Agent" }, agent.product));
+    message(&format!("SIP/2.0 {}", status), &all, body)
+}
+
+/// `200 OK` to an authorized `REGISTER`
+fn registered(request: &Message, agent: Agent, tag: &str) -> Vec<u8> {
+    let contact = format!("{};expires={}", uri_field(request.header("Contact").unwrap_or_default()), REGISTER_EXPIRES);
+    let expires = REGISTER_EXPIRES.to_string();
+    response(request, "200 OK", agent, tag, &[("Contact", &contact), ("Expires", &expires)], b"")
+}
+
+/// Digest `Authorization` of `user` for a `REGISTER` to `uri`, answering
+/// `challenge` (RFC 2617)
+fn authorization(challenge: &str, user: &str, uri: &str) -> io::Result<String> {
+    let (Some(realm), Some(nonce)) = (auth_param(challenge, "realm"), auth_param(challenge, "nonce")) else {
+        return Err(invalid(format!("Unusable SIP challenge {:?}", challenge)));
+    };
+    // Any password does, the server does not check
+    let ha1 = md5_hex(&format!("{}:{}:{}", user, realm, token(8)));
+    let ha2 = md5_hex(&format!("REGISTER:{}", uri));
+    let mut authorization = format!("Digest username=\"{}\",realm=\"{}\",nonce=\"{}\",uri=\"{}\"", user, realm, nonce, uri);
+    match auth_param(challenge, "qop").filter(|qop| qop.split(',').any(|qop| qop.trim() == "auth")) {
+        Some(_) => {
+            let cnonce = token(8);
+            let response = md5_hex(&format!("{}:{}:00000001:{}:auth:{}", ha1, nonce, cnonce, ha2));
+            authorization.push_str(&format!(",response=\"{}\",cnonce=\"{}\",nc=00000001,qop=auth", response, cnonce));
+        }
+        None => {
+            let response = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+            authorization.push_str(&format!(",response=\"{}\"", response));
+        }
+    }
+    authorization.push_str(",algorithm=MD5");
+    if let Some(opaque) = auth_param(challenge, "opaque") {
+        authorization.push_str(&format!(",opaque=\"{}\"", opaque));
+    }
+    Ok(authorization)
+}
+
+/// Parameter `name` of a digest challenge
+fn auth_param<'a>(challenge: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = challenge.trim_start().strip_prefix("Digest")?;
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+        let (key, value) = rest.split_once('=')?;
+        let (value, after) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => {
+                let end = value.find(',').unwrap_or(value.len());
+                (value[..end].trim(), &value[end..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = after;
+    }
+}
+
+/// SDP offering or answering `codec` with media at `media`
+fn sdp(user: &str, media: SocketAddr, codec: VoipCodec) -> String {
+    let (payload_type, rtpmap) = match codec {
+        VoipCodec::Pcmu => (0, "PCMU/8000"),
+        VoipCodec::Pcma => (8, "PCMA/8000"),
+        VoipCodec::Opus => (111, "opus/48000/2"),
+    };
+    let family = if media.is_ipv4() { "IP4" } else { "IP6" };
+    let session: u32 = rand::thread_rng().gen_range(1000..u32::MAX);
+    let mut sdp = format!(
+        "v=0\r\no={user} {session} {session} IN {family} {ip}\r\ns=Talk\r\nc=IN {family} {ip}\r\nt=0 0\r\n\
+         m=audio {port} RTP/AVP {payload_type} {TELEPHONE_EVENT}\r\na=rtpmap:{payload_type} {rtpmap}\r\n",
+        ip = media.ip(),
+        port = media.port(),
+    );
+    if codec == VoipCodec::Opus {
+        sdp.push_str(&format!("a=fmtp:{} useinbandfec=1\r\n", payload_type));
+    }
+    sdp.push_str(&format!(
+        "a=rtpmap:{TELEPHONE_EVENT} telephone-event/8000\r\na=fmtp:{TELEPHONE_EVENT} 0-16\r\na=ptime:20\r\na=sendrecv\r\n"
+    ));
+    sdp
+}
+
+/// First codec of an SDP offer's audio stream that calls are carried in
+fn offered_codec(sdp: &str) -> Option<VoipCodec> {
+    let formats = sdp.lines().find_map(|line| line.strip_prefix("m=audio "))?;
+    formats.split_whitespace().skip(2).find_map(|format| match format {
+        "0" => Some(VoipCodec::Pcmu),
+        "8" => Some(VoipCodec::Pcma),
+        _ if sdp.contains(&format!("a=rtpmap:{} opus/", format)) => Some(VoipCodec::Opus),
+        _ => None,
+    })
+}
+
+/// `<uri>` of a name-addr header value, without its angle brackets
+fn uri(value: &str) -> &str {
+    let field = uri_field(value);
+    field.trim_start_matches('<').trim_end_matches('>')
+}
+
+/// `<uri>` of a name-addr header value, without its parameters
+fn uri_field(value: &str) -> &str {
+    match (value.find('<'), value.find('>')) {
+        (Some(start), Some(end)) if start < end => &value[start..=end],
+        _ => value.split(';').next().unwrap_or_default().trim(),
+    }
+}
+
+fn md5_hex(data: &str) -> String {
+    hex::encode(Md5::digest(data.as_bytes()))
+}
+
+/// Random hex of `bytes` bytes, for tags, branches and Call-IDs
+fn token(bytes: usize) -> String {
+    let random: Vec<u8> = (0..bytes).map(|_| rand::random()).collect();
+    hex::encode(random)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_call_then_tunnel() {
+        let media: SocketAddr = "192.0.2.10:16384".parse().unwrap();
+        for codec in [VoipCodec::Pcmu, VoipCodec::Pcma, VoipCodec::Opus] {
+            assert_eq!(offered_codec(&sdp("201", media, codec)), Some(codec));
+        }
+
+        let (client_side, server_side) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(async move {
+            let mut tunnel = accept(server_side, "192.0.2.1:5060".parse().unwrap()).await.unwrap();
+            let mut request = vec![0u8; 10_000];
+            tunnel.read_exact(&mut request).await.unwrap();
+            assert!(request.iter().enumerate().all(|(i, b)| *b == i as u8));
+            tunnel.write_all(b"response").await.unwrap();
+        });
+        let local = "192.0.2.10:50412".parse().unwrap();
+        let mut tunnel = connect(client_side, "pbx.example.com", local, Some(VoipCodec::Opus)).await.unwrap();
+        let request: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        tunnel.write_all(&request).await.unwrap();
+        let mut response = [0u8; 8];
+        tunnel.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"response");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kcp_in_a_signaled_call() {
+        let mut listener = crate::rtp_voip::bind("127.0.0.1:0".parse().unwrap(), VoipCodec::Pcmu).await.unwrap();
+        let _registrar = serve(listener.local_addr()).await.unwrap();
+        let mut client = call(listener.local_addr(), "pbx.example.com", VoipCodec::Pcmu, None).await.unwrap();
+
+        client.write_all(b"hello").await.unwrap();
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), server.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,226 @@
+//! Tunnel connections opened as SMTP sessions upgraded with STARTTLS
+//!
+//! On ports 25 and 587 a bare TLS-looking stream is out of place: mail
+//! starts in plaintext, with the server's greeting, the client's `EHLO` and
+//! the `STARTTLS` upgrade, at the pace of real mail software. This
+//! transport runs that conversation before the tunnel, which then takes
+//! the place of the TLS session; `MAIL FROM`, `RCPT TO` and the message
+//! itself would be inside it in real submission traffic too. Pair it with
+//! `tls_handshake` so the first tunnel bytes look like the TLS handshake
+//! the upgrade promises.
+//!
+//! ```toml
+//! [socks]
+//! transport = "smtp"
+//!
+//! [server]
+//! transport = "smtp"
+//! smtp_hostname = "mail.example.com"
+//! ```
+//!
+//! The server answers like Postfix: unknown commands and attempts to send
+//! mail before the upgrade get the usual errors, and `QUIT` ends the
+//! session without a tunnel, so a prober sees an ordinary mail server.
+
+use rand::Rng;
+use std::io;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Greeting hostname when the server has none configured
+pub const DEFAULT_HOSTNAME: &str = "mail.example.com";
+
+/// Longest command or reply line, including CRLF (RFC 5321 section 4.5.3.1)
+const MAX_LINE: usize = 512;
+
+/// Commands a client may send before the upgrade
+const MAX_COMMANDS: usize = 20;
+
+/// Client pause before each command, in milliseconds
+const CLIENT_DELAY_MS: Range<u64> = 30..250;
+
+/// Server pause before each reply, in milliseconds
+const SERVER_DELAY_MS: Range<u64> = 2..30;
+
+/// Run the client side of the conversation on `stream`, introducing the
+/// client as `helo_name`; the tunnel follows once this returns
+pub async fn connect<S>(stream: &mut S, helo_name: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    expect_reply(stream, 220).await?;
+
+    pause(CLIENT_DELAY_MS).await;
+    send(stream, &format!("EHLO {}\r\n", helo_name)).await?;
+    let extensions = expect_reply(stream, 250).await?;
+    if !extensions.iter().any(|line| line.eq_ignore_ascii_case("STARTTLS")) {
+        return Err(invalid("SMTP server does not offer STARTTLS".to_string()));
+    }
+
+    pause(CLIENT_DELAY_MS).await;
+    send(stream, "STARTTLS\r\n").await?;
+    expect_reply(stream, 220).await?;
+    Ok(())
+}
+
+/// Run the server side of the conversation on an accepted `stream`; `Ok`
+/// once the client has asked to upgrade, so the tunnel follows
+pub async fn accept<S>(stream: &mut S, hostname: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    reply(stream, &format!("220 {} ESMTP Postfix (Ubuntu)\r\n", hostname)).await?;
+
+    let mut greeted = false;
+    for _ in 0..MAX_COMMANDS {
+        let line = read_line(stream).await?;
+        let verb = line.split(' ').next().unwrap_or("").to_ascii_uppercase();
+        let response = match verb.as_str() {
+            "EHLO" if line.len() > 5 => {
+                greeted = true;
+                format!(
+                    "250-{}\r\n250-PIPELINING\r\n250-SIZE 10240000\r\n250-VRFY\r\n250-ETRN\r\n250-STARTTLS\r\n\
+                     250-ENHANCEDSTATUSCODES\r\n250-8BITMIME\r\n250-DSN\r\n250-SMTPUTF8\r\n250 CHUNKING\r\n",
+                    hostname
+                )
+            }
+            "HELO" if line.len() > 5 => {
+                greeted = true;
+                format!("250 {}\r\n", hostname)
+            }
+            "EHLO" | "HELO" => "501 Syntax: EHLO hostname\r\n".to_string(),
+            "STARTTLS" if !greeted => "503 5.5.1 Error: send HELO/EHLO first\r\n".to_string(),
+            "STARTTLS" => {
+                reply(stream, "220 2.0.0 Ready to start TLS\r\n").await?;
+                return Ok(());
+            }
+            "MAIL" | "RCPT" | "DATA" | "BDAT" | "AUTH" => "530 5.7.0 Must issue a STARTTLS command first\r\n".to_string(),
+            "NOOP" | "RSET" => "250 2.0.0 Ok\r\n".to_string(),
+            "QUIT" => {
+                reply(stream, "221 2.0.0 Bye\r\n").await?;
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "SMTP client quit"));
+            }
+            _ => "502 5.5.2 Error: command not recognized\r\n".to_string(),
+        };
+        reply(stream, &response).await?;
+    }
+
+    reply(stream, "421 4.7.0 Error: too many errors\r\n").await?;
+    Err(invalid("SMTP client sent too many commands".to_string()))
+}
+
+/// How a client without a DNS name introduces itself in `EHLO`
+pub fn address_literal(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("[{}]", ip),
+        IpAddr::V6(ip) => format!("[IPv6:{}]", ip),
+    }
+}
+
+/// Read a possibly multi-line reply, failing unless its code is `code`;
+/// returns the text of each line
+async fn expect_reply<S>(stream: &mut S, code: u16) -> io::Result<Vec<String>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut lines = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        let (status, separator) = match (line.get(..3), line.as_bytes().get(3)) {
+            (Some(status), separator) => (status, separator.copied()),
+            _ => return Err(invalid(format!("Malformed SMTP reply {:?}", line))),
+        };
+        if status != code.to_string() {
+            return Err(invalid(format!("Unexpected SMTP reply {:?}", line)));
+        }
+        lines.push(line.get(4..).unwrap_or("").to_string());
+        if separator != Some(b'-') {
+            return Ok(lines);
+        }
+    }
+}
+
+/// One CRLF-terminated line, without the terminator
+///
+/// Read a byte at a time so nothing after the line, such as the first
+/// tunnel bytes, is consumed.
+async fn read_line<S>(stream: &mut S) -> io::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_LINE {
+            return Err(invalid("SMTP line too long".to_string()));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| invalid("SMTP line is not UTF-8".to_string()))
+}
+
+async fn send<S>(stream: &mut S, command: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(command.as_bytes()).await?;
+    stream.flush().await
+}
+
+async fn reply<S>(stream: &mut S, response: &str) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    pause(SERVER_DELAY_MS).await;
+    send(stream, response).await
+}
+
+async fn pause(range_ms: Range<u64>) {
+    let delay = rand::thread_rng().gen_range(range_ms);
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_starttls_then_tunnel() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let server_handle = tokio::spawn(async move {
+            accept(&mut server, "mx.example.org").await.unwrap();
+            let mut buf = [0u8; 6];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&buf).await.unwrap();
+        });
+
+        connect(&mut client, &address_literal("192.0.2.7".parse().unwrap())).await.unwrap();
+        client.write_all(b"tunnel").await.unwrap();
+        let mut buf = [0u8; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tunnel");
+        server_handle.await.unwrap();
+        assert_eq!(address_literal("2001:db8::1".parse().unwrap()), "[IPv6:2001:db8::1]");
+    }
+
+    #[tokio::test]
+    async fn test_prober_sees_mail_server() {
+        let (mut prober, mut server) = tokio::io::duplex(4096);
+        let server_handle = tokio::spawn(async move { accept(&mut server, "mx.example.org").await });
+
+        assert_eq!(expect_reply(&mut prober, 220).await.unwrap(), ["mx.example.org ESMTP Postfix (Ubuntu)"]);
+        send(&mut prober, "MAIL FROM:<a@example.org>\r\n").await.unwrap();
+        assert!(expect_reply(&mut prober, 530).await.is_ok());
+        send(&mut prober, "STARTTLS\r\n").await.unwrap();
+        assert!(expect_reply(&mut prober, 503).await.is_ok());
+        send(&mut prober, "QUIT\r\n").await.unwrap();
+        assert!(expect_reply(&mut prober, 221).await.is_ok());
+        assert!(server_handle.await.unwrap().is_err());
+    }
+}
@@ -77,6 +77,9 @@ impl PrefixedStream {
 /// SOCKS5 protocol constants
 const SOCKS5_VERSION: u8 = 0x05;
 
+/// SOCKS4 and SOCKS4a version byte
+pub const SOCKS4_VERSION: u8 = 0x04;
+
 /// Authentication methods
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
@@ -138,6 +141,27 @@ impl TargetAddr {
 
 /// Perform SOCKS5 handshake and return target address
 pub async fn socks5_handshake<S>(stream: &mut S) -> Result<(Command, TargetAddr), Error>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let (command, target, _) = handshake(stream, false).await?;
+    Ok((command, target))
+}
+
+/// User name and password a client authenticated with
+pub type Credentials = (Vec<u8>, Vec<u8>);
+
+/// [`socks5_handshake`] that also accepts user name and password
+/// authentication (RFC 1929), returning the credentials without checking
+/// them, as pluggable transports receive their arguments
+pub async fn socks5_handshake_with_credentials<S>(stream: &mut S) -> Result<(Command, TargetAddr, Option<Credentials>), Error>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    handshake(stream, true).await
+}
+
+async fn handshake<S>(stream: &mut S, accept_credentials: bool) -> Result<(Command, TargetAddr, Option<Credentials>), Error>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
@@ -175,20 +199,39 @@ where
     // | 1  |   1    |
     // +----+--------+
 
-    // We only support no authentication for now
-    if !methods.contains(&(AuthMethod::NoAuth as u8)) {
+    let credentials = if accept_credentials && methods.contains(&(AuthMethod::UsernamePassword as u8)) {
+        stream.write_all(&[SOCKS5_VERSION, AuthMethod::UsernamePassword as u8]).await?;
+        // +----+------+----------+------+----------+
+        // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+        // +----+------+----------+------+----------+
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await?;
+        let mut username = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut username).await?;
+        let mut password = vec![0u8; stream.read_u8().await? as usize];
+        stream.read_exact(&mut password).await?;
+        stream.write_all(&[0x01, 0x00]).await?;
+        Some((username, password))
+    } else {
+        None
+    };
+
+    // Otherwise only no authentication is supported
+    if credentials.is_some() {
+        log::trace!("[SOCKS5] Accepted auth method: UsernamePassword");
+    } else if !methods.contains(&(AuthMethod::NoAuth as u8)) {
         // Send "no acceptable methods"
         stream.write_all(&[SOCKS5_VERSION, AuthMethod::NoAcceptable as u8]).await?;
         return Err(Error::new(
             ErrorKind::PermissionDenied,
             "No supported authentication method",
         ));
+    } else {
+        // Accept no authentication
+        stream.write_all(&[SOCKS5_VERSION, AuthMethod::NoAuth as u8]).await?;
+        log::trace!("[SOCKS5] Accepted auth method: NoAuth");
     }
 
-    // Accept no authentication
-    stream.write_all(&[SOCKS5_VERSION, AuthMethod::NoAuth as u8]).await?;
-    log::trace!("[SOCKS5] Accepted auth method: NoAuth");
-
     // Step 3: Client request
     // +----+-----+-------+------+----------+----------+
     // |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
@@ -285,9 +328,96 @@ where
     log::trace!("[SOCKS5] Target port: {}", port);
     log::info!("[SOCKS5] Handshake complete: command={:?}, target={}:{}", command, host, port);
 
+    Ok((command, TargetAddr { host, port }, credentials))
+}
+
+/// Perform SOCKS4 or SOCKS4a handshake and return target address
+///
+/// SOCKS4 clients only ask for CONNECT or BIND to an IPv4 address; SOCKS4a
+/// clients send the placeholder address 0.0.0.x and append the host name.
+pub async fn socks4_handshake<S>(stream: &mut S) -> Result<(Command, TargetAddr), Error>
+where
+    S: AsyncReadExt + Unpin,
+{
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    // | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    // | 1  | 1  |    2    |         4         |   variable   | 1  |
+    let mut request = [0u8; 8];
+    stream.read_exact(&mut request).await?;
+    if request[0] != SOCKS4_VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported SOCKS version: {}", request[0])));
+    }
+    let command = match request[1] {
+        0x01 => Command::Connect,
+        0x02 => Command::Bind,
+        cmd => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown SOCKS4 command: {}", cmd))),
+    };
+    let port = u16::from_be_bytes([request[2], request[3]]);
+    let ip = Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+
+    // The user ID is not checked
+    read_socks4_string(stream).await?;
+    let host = if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+        let host = read_socks4_string(stream).await?;
+        String::from_utf8(host).map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid domain name encoding"))?
+    } else {
+        ip.to_string()
+    };
+
+    log::info!("[SOCKS4] Handshake complete: command={:?}, target={}:{}", command, host, port);
     Ok((command, TargetAddr { host, port }))
 }
 
+/// A NUL-terminated field of a SOCKS4 request, at most 255 bytes long
+async fn read_socks4_string<S>(stream: &mut S) -> Result<Vec<u8>, Error>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let mut field = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            _ if field.len() == 255 => {
+                return Err(Error::new(ErrorKind::InvalidData, "SOCKS4 request field too long"));
+            }
+            byte => field.push(byte),
+        }
+    }
+}
+
+/// Send SOCKS4 reply to client
+pub async fn send_socks4_reply<S>(stream: &mut S, reply: ReplyCode, bind_addr: &TargetAddr) -> Result<(), Error>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    // +----+----+----+----+----+----+----+----+
+    // | VN | CD | DSTPORT |      DSTIP        |
+    // +----+----+----+----+----+----+----+----+
+    // | 1  | 1  |    2    |         4         |
+    let status = match reply {
+        ReplyCode::Succeeded => 0x5A,
+        _ => 0x5B,
+    };
+    let ip = bind_addr.host.parse::<Ipv4Addr>().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let mut response = vec![0x00, status];
+    response.extend_from_slice(&bind_addr.port.to_be_bytes());
+    response.extend_from_slice(&ip.octets());
+    stream.write_all(&response).await
+}
+
+/// Send the reply of a SOCKS `version` handshake
+pub async fn send_versioned_reply<S>(stream: &mut S, version: u8, reply: ReplyCode, bind_addr: &TargetAddr) -> Result<(), Error>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    if version == SOCKS4_VERSION {
+        send_socks4_reply(stream, reply, bind_addr).await
+    } else {
+        send_reply(stream, reply, bind_addr).await
+    }
+}
+
 /// Send SOCKS5 reply to client
 pub async fn send_reply<S>(
     stream: &mut S,
@@ -361,6 +491,86 @@ pub async fn connect_target(target: &TargetAddr) -> Result<TcpStream, Error> {
     }
 }
 
+/// Client side of a CONNECT to `host:port` through the SOCKS5 proxy `stream`
+/// is connected to, authenticating with a user name and password (RFC 1929)
+/// if `credentials` are given
+pub async fn client_connect<S>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match credentials {
+        Some(_) => AuthMethod::UsernamePassword,
+        None => AuthMethod::NoAuth,
+    };
+    stream.write_all(&[SOCKS5_VERSION, 1, method as u8]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [SOCKS5_VERSION, method as u8] {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("SOCKS5 proxy refused authentication method {:?}", method),
+        ));
+    }
+
+    if let Some((username, password)) = credentials {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "SOCKS5 user name or password too long"));
+        }
+        let mut auth = vec![0x01, username.len() as u8];
+        auth.extend_from_slice(username.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        stream.write_all(&auth).await?;
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[1] != 0x00 {
+            return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy rejected the credentials"));
+        }
+    }
+
+    let mut request = vec![SOCKS5_VERSION, Command::Connect as u8, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(AddressType::IPv4 as u8);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(AddressType::IPv6 as u8);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(Error::new(ErrorKind::InvalidInput, "hostname too long"));
+            }
+            request.push(AddressType::DomainName as u8);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != ReplyCode::Succeeded as u8 {
+        return Err(Error::other(format!("SOCKS5 connect failed (reply code {})", head[1])));
+    }
+    let addr_len = match head[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        other => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid SOCKS5 address type {}", other))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
 /// Bidirectional copy between two streams
 pub async fn copy_bidirectional<C, T>(
     client: C,
@@ -414,4 +624,52 @@ mod tests {
         };
         assert!(target.to_socket_addr().is_none());
     }
+
+    #[tokio::test]
+    async fn test_client_connect_with_credentials() {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        let proxy_task = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, AuthMethod::UsernamePassword as u8]);
+            proxy.write_all(&[5, AuthMethod::UsernamePassword as u8]).await.unwrap();
+            let mut auth = [0u8; 14];
+            proxy.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x05alice\x06secret");
+            proxy.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            proxy.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 1, 192, 0, 2, 7, 1, 187]);
+            let bound = TargetAddr { host: "10.0.0.1".to_string(), port: 4000 };
+            send_reply(&mut proxy, ReplyCode::Succeeded, &bound).await.unwrap();
+            proxy.write_all(b"tunnel").await.unwrap();
+        });
+
+        client_connect(&mut client, "192.0.2.7", 443, Some(("alice", "secret"))).await.unwrap();
+        let mut buf = [0u8; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tunnel");
+        proxy_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks4_handshake() {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        client.write_all(b"\x04\x01\x00\x50\xc0\x00\x02\x07user\x00").await.unwrap();
+        let (command, target) = socks4_handshake(&mut proxy).await.unwrap();
+        assert!(matches!(command, Command::Connect));
+        assert_eq!((target.host.as_str(), target.port), ("192.0.2.7", 80));
+
+        // SOCKS4a: the host name follows the user ID
+        client.write_all(b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00").await.unwrap();
+        let (_, target) = socks4_handshake(&mut proxy).await.unwrap();
+        assert_eq!((target.host.as_str(), target.port), ("example.com", 443));
+
+        send_versioned_reply(&mut proxy, SOCKS4_VERSION, ReplyCode::Succeeded, &target).await.unwrap();
+        send_versioned_reply(&mut proxy, SOCKS4_VERSION, ReplyCode::NotAllowed, &target).await.unwrap();
+        let mut replies = [0u8; 16];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0, 0x5A, 1, 187, 0, 0, 0, 0, 0, 0x5B, 1, 187, 0, 0, 0, 0]);
+    }
 }
@@ -0,0 +1,346 @@
+//! Speed tests through the tunnel
+//!
+//! Measures download, upload and round-trip time over a SOCKS5 proxy against
+//! either a plain HTTP endpoint or a bench sink (see [`crate::bench`]). Used by
+//! `nooshdaroo speedtest` to compare protocols empirically.
+
+use crate::bench::{sink_exchange, socks5_connect};
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Number of round trips used for the latency measurement
+const RTT_SAMPLES: usize = 5;
+
+/// Chunk size used for uploads and downloads
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Endpoint a speed test runs against
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeedtestEndpoint {
+    /// Plain HTTP server: download via GET, upload via POST, RTT via HEAD
+    Http { host: String, port: u16, path: String },
+    /// Bench sink (`nooshdaroo bench --serve-sink`)
+    Sink { host: String, port: u16 },
+}
+
+impl SpeedtestEndpoint {
+    fn host_port(&self) -> (&str, u16) {
+        match self {
+            Self::Http { host, port, .. } | Self::Sink { host, port } => (host, *port),
+        }
+    }
+}
+
+/// Split `host:port`, falling back to `default_port`
+fn split_host_port(authority: &str, default_port: Option<u16>) -> Result<(String, u16), String> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), addr.port()));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => {
+            let port = port.parse().map_err(|_| format!("Invalid port in {}", authority))?;
+            Ok((host.trim_matches(|c| c == '[' || c == ']').to_string(), port))
+        }
+        _ => match default_port {
+            Some(port) => Ok((authority.trim_matches(|c| c == '[' || c == ']').to_string(), port)),
+            None => Err(format!("Missing port in {}", authority)),
+        },
+    }
+}
+
+impl FromStr for SpeedtestEndpoint {
+    type Err = String;
+
+    /// Parse `http://host[:port][/path]` or `sink://host:port`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("http://") {
+            let (authority, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, "/"),
+            };
+            if authority.is_empty() {
+                return Err(format!("Missing host in {}", s));
+            }
+            let (host, port) = split_host_port(authority, Some(80))?;
+            Ok(Self::Http { host, port, path: path.to_string() })
+        } else if let Some(rest) = s.strip_prefix("sink://") {
+            let (host, port) = split_host_port(rest.trim_end_matches('/'), None)?;
+            Ok(Self::Sink { host, port })
+        } else if s.starts_with("https://") {
+            Err("https endpoints are not supported; the tunnel already encrypts, use http://".to_string())
+        } else {
+            Err(format!("Unsupported endpoint {} (expected http:// or sink://)", s))
+        }
+    }
+}
+
+/// Result of one speed test
+#[derive(Debug, Clone, Serialize)]
+pub struct SpeedtestResult {
+    /// Protocol (or "active" for an already running client)
+    pub protocol: String,
+    /// Download throughput in Mbps
+    pub download_mbps: f64,
+    /// Upload throughput in Mbps
+    pub upload_mbps: f64,
+    /// Median round-trip time in milliseconds
+    pub rtt_ms: f64,
+    /// Error, if the test failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SpeedtestResult {
+    /// Result for a test that could not be completed
+    pub fn failed(protocol: &str, error: impl std::fmt::Display) -> Self {
+        Self {
+            protocol: protocol.to_string(),
+            download_mbps: 0.0,
+            upload_mbps: 0.0,
+            rtt_ms: 0.0,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+fn mbps(bytes: usize, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        (bytes as f64 * 8.0) / secs / 1_000_000.0
+    } else {
+        0.0
+    }
+}
+
+fn median_ms(mut samples: Vec<Duration>) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.sort();
+    samples[samples.len() / 2].as_secs_f64() * 1000.0
+}
+
+/// Run a speed test through the SOCKS5 proxy at `proxy`, transferring `bytes`
+/// in each direction
+pub async fn run_speedtest(
+    proxy: SocketAddr,
+    endpoint: &SpeedtestEndpoint,
+    bytes: usize,
+    label: &str,
+) -> io::Result<SpeedtestResult> {
+    let (host, port) = endpoint.host_port();
+    let (download, upload, rtts) = match endpoint {
+        SpeedtestEndpoint::Sink { .. } => {
+            let mut stream = socks5_connect(proxy, host, port).await?;
+            let mut rtts = Vec::with_capacity(RTT_SAMPLES);
+            for _ in 0..RTT_SAMPLES {
+                rtts.push(sink_exchange(&mut stream, 1, 1).await?);
+            }
+            let download = sink_exchange(&mut stream, 0, bytes).await?;
+            let upload = sink_exchange(&mut stream, bytes, 0).await?;
+            (download, upload, rtts)
+        }
+        SpeedtestEndpoint::Http { path, .. } => {
+            let mut rtts = Vec::with_capacity(RTT_SAMPLES);
+            for _ in 0..RTT_SAMPLES {
+                let mut stream = socks5_connect(proxy, host, port).await?;
+                let start = Instant::now();
+                http_request(&mut stream, "HEAD", host, path, 0).await?;
+                rtts.push(start.elapsed());
+            }
+
+            let mut stream = socks5_connect(proxy, host, port).await?;
+            let start = Instant::now();
+            let received = http_request(&mut stream, "GET", host, path, 0).await?;
+            let download = start.elapsed();
+            if received == 0 {
+                return Err(io::Error::other("endpoint returned an empty body"));
+            }
+
+            let mut stream = socks5_connect(proxy, host, port).await?;
+            let start = Instant::now();
+            http_request(&mut stream, "POST", host, path, bytes).await?;
+            let upload = start.elapsed();
+
+            return Ok(SpeedtestResult {
+                protocol: label.to_string(),
+                download_mbps: mbps(received, download),
+                upload_mbps: mbps(bytes, upload),
+                rtt_ms: median_ms(rtts),
+                error: None,
+            });
+        }
+    };
+
+    Ok(SpeedtestResult {
+        protocol: label.to_string(),
+        download_mbps: mbps(bytes, download),
+        upload_mbps: mbps(bytes, upload),
+        rtt_ms: median_ms(rtts),
+        error: None,
+    })
+}
+
+/// Send an HTTP/1.1 request with `body_len` zero bytes and read the whole
+/// response, returning the number of body bytes received
+async fn http_request(
+    stream: &mut TcpStream,
+    method: &str,
+    host: &str,
+    path: &str,
+    body_len: usize,
+) -> io::Result<usize> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: nooshdaroo-speedtest\r\nConnection: close\r\n",
+        method, path, host
+    );
+    if method == "POST" {
+        request.push_str(&format!(
+            "Content-Type: application/octet-stream\r\nContent-Length: {}\r\n",
+            body_len
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let chunk = vec![0u8; CHUNK_SIZE];
+    let mut remaining = body_len;
+    while remaining > 0 {
+        let n = remaining.min(chunk.len());
+        stream.write_all(&chunk[..n]).await?;
+        remaining -= n;
+    }
+    stream.flush().await?;
+
+    read_http_response(stream, method == "HEAD").await
+}
+
+/// Read an HTTP response, returning the body length
+async fn read_http_response<S>(stream: &mut S, head_only: bool) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+
+    let header_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > 64 * 1024 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP headers too large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before headers"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let headers = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let status_ok = headers
+        .split_whitespace()
+        .nth(1)
+        .map(|code| code.starts_with('2'))
+        .unwrap_or(false);
+    if !status_ok {
+        let status = headers.lines().next().unwrap_or("").to_string();
+        return Err(io::Error::other(format!("endpoint answered: {}", status)));
+    }
+    if head_only {
+        return Ok(0);
+    }
+
+    let content_length = headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            value.trim().parse::<usize>().ok()
+        } else {
+            None
+        }
+    });
+
+    let mut received = buf.len() - header_end;
+    loop {
+        if content_length.is_some_and(|len| received >= len) {
+            break;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        received += n;
+    }
+
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_endpoint_parsing() {
+        assert_eq!(
+            "http://example.com/10MB.bin".parse::<SpeedtestEndpoint>().unwrap(),
+            SpeedtestEndpoint::Http { host: "example.com".into(), port: 80, path: "/10MB.bin".into() }
+        );
+        assert_eq!(
+            "http://10.0.0.1:8080".parse::<SpeedtestEndpoint>().unwrap(),
+            SpeedtestEndpoint::Http { host: "10.0.0.1".into(), port: 8080, path: "/".into() }
+        );
+        assert_eq!(
+            "sink://198.51.100.7:9000".parse::<SpeedtestEndpoint>().unwrap(),
+            SpeedtestEndpoint::Sink { host: "198.51.100.7".into(), port: 9000 }
+        );
+        assert!("sink://example.com".parse::<SpeedtestEndpoint>().is_err());
+        assert!("https://example.com/".parse::<SpeedtestEndpoint>().is_err());
+        assert!("ftp://example.com/".parse::<SpeedtestEndpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_http_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let mut reader = &response[..];
+        assert_eq!(read_http_response(&mut reader, false).await.unwrap(), 5);
+
+        let mut reader = &b"HTTP/1.1 404 Not Found\r\n\r\n"[..];
+        assert!(read_http_response(&mut reader, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_speedtest_against_sink() {
+        use crate::bench::run_sink_server;
+
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink_addr = sink.local_addr().unwrap();
+        tokio::spawn(run_sink_server(sink));
+
+        // Minimal SOCKS5 proxy that always connects to the sink
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            client.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 10];
+            client.read_exact(&mut request).await.unwrap();
+            client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut upstream = TcpStream::connect(sink_addr).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+        });
+
+        let endpoint = SpeedtestEndpoint::Sink { host: "127.0.0.1".into(), port: sink_addr.port() };
+        let result = run_speedtest(proxy_addr, &endpoint, 256 * 1024, "direct").await.unwrap();
+        assert!(result.error.is_none());
+        assert!(result.download_mbps > 0.0);
+        assert!(result.upload_mbps > 0.0);
+    }
+}
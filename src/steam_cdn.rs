@@ -0,0 +1,633 @@
+//! Tunnel carried as a Steam game download from a content server
+//!
+//! Game downloads are among the largest flows a home network carries. The
+//! Steam client opens several connections to a content server and pulls
+//! depot chunks of up to a megabyte over plain HTTP, as chunks are
+//! encrypted before they are published: full-sized segments pour
+//! downstream for as long as the download lasts, answering a steady
+//! trickle of chunk requests. A tunnel moving a lot of data looks right in
+//! that shape where it would look wrong as DNS or SSH; the
+//! [`steam`](crate::app_profiles::ApplicationProfile::steam) application
+//! profile describes the same traffic.
+//!
+//! ```toml
+//! [socks]
+//! transport = "steam"
+//! http_host = "cache4-fra1.steamcontent.com"   # Host header, the server's IP by default
+//!
+//! [server]
+//! transport = "steam"
+//! listen_addr = "0.0.0.0:80"
+//! ```
+//!
+//! The client opens [`CONNECTIONS`] connections, each fetching the
+//! manifest of one of the game's depots first, all with the same request
+//! code, by which the server groups them into one tunnel. Each connection
+//! then keeps [`REQUESTS_IN_FLIGHT`] chunk requests pipelined, and the
+//! server answers the oldest request of any connection whenever it has
+//! tunnel data, up to a chunk's worth, numbering its chunks so the client
+//! can put chunks from different connections back in order. Tunnel bytes
+//! upstream ride in the chunk IDs of the first connection's requests,
+//! [`UPSTREAM_PER_REQUEST`] bytes each, so the tunnel suits downloads far
+//! better than uploads. Chunk IDs and chunk numbers are masked with a
+//! keystream derived from the request code, so they look like the SHA-1
+//! hashes and encrypted chunk data they stand in for.
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use ring::digest;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::Mutex;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+    ReadHalf, WriteHalf,
+};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Buffer between the tunnel and the download
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Connections a download opens to the content server
+pub const CONNECTIONS: usize = 4;
+
+/// Chunk requests each connection keeps pipelined
+pub const REQUESTS_IN_FLIGHT: usize = 2;
+
+/// Tunnel bytes upstream in one chunk ID, after its length byte
+pub const UPSTREAM_PER_REQUEST: usize = 19;
+
+/// Length byte of the chunk ID ending the upload
+const END_OF_UPLOAD: u8 = 0xff;
+
+/// Tunnel bytes in one chunk, the size of the largest depot chunks
+const MAX_CHUNK: usize = 1024 * 1024;
+
+/// Chunk number preceding the tunnel bytes of a chunk
+const SEQUENCE_SIZE: usize = 4;
+
+/// Size of a depot manifest, in bytes
+const MANIFEST_SIZE: Range<usize> = 16 * 1024..96 * 1024;
+
+/// Longest request, status or header line
+const MAX_LINE: usize = 1024;
+
+/// Most lines in a request or response head
+const MAX_HEAD_LINES: usize = 32;
+
+const USER_AGENT: &str = "Valve/Steam HTTP Client 1.0";
+
+/// Games being downloaded; their depots are numbered after them
+const APPS: &[u32] = &[1091500, 1245620, 271590, 1172470, 2358720, 1086940];
+
+/// Connection of a download, whatever its stream
+trait Plain: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Plain for T {}
+
+type Connection = BufReader<Box<dyn Plain>>;
+
+/// A connection whose manifest was fetched, with its depot
+struct Joined {
+    depot: u32,
+    connection: Connection,
+}
+
+/// Downloads being served, by request code, taking the connections that
+/// join them
+static DOWNLOADS: Mutex<Option<HashMap<u64, mpsc::Sender<Joined>>>> = Mutex::new(None);
+
+/// Start a download over `stream` and from further connections to
+/// `server_addr`, sending `host` as the `Host` header; the returned stream
+/// carries the tunnel
+pub async fn connect<S>(stream: S, server_addr: SocketAddr, host: &str) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (code, app) = {
+        let mut rng = rand::thread_rng();
+        (rng.gen_range(1..u64::MAX), *APPS.choose(&mut rng).unwrap())
+    };
+    let mut connections = vec![fetch_manifest(Box::new(stream), host, code, app + 1, false).await?];
+    for depot in (app + 2..).take(CONNECTIONS - 1) {
+        let joined = async {
+            let stream = TcpStream::connect(server_addr).await?;
+            fetch_manifest(Box::new(stream), host, code, depot, true).await
+        };
+        match joined.await {
+            Ok(joined) => connections.push(joined),
+            Err(e) => log::debug!("Steam download connection to {} failed: {}", server_addr, e),
+        }
+    }
+    log::debug!("Steam download of app {} over {} connections", app, connections.len());
+
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    let host = host.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = fetch(code, &host, connections, local).await {
+            log::debug!("Steam download ended: {}", e);
+        }
+    });
+    Ok(tunnel)
+}
+
+/// Serve the download `stream` starts or joins; the returned stream
+/// carries the tunnel of a download it starts, and is `None` when it
+/// joins one under way
+pub async fn accept<S>(stream: S) -> io::Result<Option<DuplexStream>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut connection: Connection = BufReader::new(Box::new(stream));
+    let head = read_head(&mut connection).await?;
+    let (depot, gid, code) = manifest_path(&head[0])?;
+    let manifest_size = rand::thread_rng().gen_range(MANIFEST_SIZE);
+    let mut manifest = response_head(manifest_size).into_bytes();
+    manifest.extend_from_slice(&random_bytes(manifest_size));
+    connection.write_all(&manifest).await?;
+    connection.flush().await?;
+    let joined = Joined { depot, connection };
+
+    if gid == joining_gid(code, depot) {
+        let download = DOWNLOADS.lock().unwrap().as_ref().and_then(|downloads| downloads.get(&code).cloned());
+        let Some(download) = download else {
+            return Err(invalid(format!("No Steam download with request code {}", code)));
+        };
+        download.send(joined).await.map_err(|_| invalid(format!("Steam download {} ended", code)))?;
+        return Ok(None);
+    }
+
+    let (joins_tx, joins) = mpsc::channel(CONNECTIONS);
+    {
+        let mut downloads = DOWNLOADS.lock().unwrap();
+        let downloads = downloads.get_or_insert_with(HashMap::new);
+        downloads.retain(|_, download| !download.is_closed());
+        downloads.insert(code, joins_tx);
+    }
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = serve(code, joined, joins, local).await {
+            log::debug!("Steam download ended: {}", e);
+        }
+        if let Some(downloads) = DOWNLOADS.lock().unwrap().as_mut() {
+            downloads.remove(&code);
+        }
+    });
+    Ok(Some(tunnel))
+}
+
+/// Fetch the manifest of `depot` over `stream`, the first connection of a
+/// download or one `joining` it
+async fn fetch_manifest(stream: Box<dyn Plain>, host: &str, code: u64, depot: u32, joining: bool) -> io::Result<Joined> {
+    let gid = match joining {
+        true => joining_gid(code, depot),
+        false => rand::thread_rng().gen_range(1 << 60..u64::MAX),
+    };
+    let mut connection = BufReader::new(stream);
+    let path = format!("/depot/{}/manifest/{}/5/{}", depot, gid, code);
+    connection.write_all(request(&path, host).as_bytes()).await?;
+    connection.flush().await?;
+    let length = response_length(&read_head(&mut connection).await?)?;
+    let mut manifest = vec![0u8; length];
+    connection.read_exact(&mut manifest).await?;
+    Ok(Joined { depot, connection })
+}
+
+/// What a connection's reader reports
+enum Event {
+    /// The server took a chunk request; `upload` is `None` at the end of
+    /// the upload
+    Request { connection: usize, upload: Option<Vec<u8>> },
+    /// The client got a chunk; an empty one ends the download
+    Chunk { connection: usize, sequence: u32, data: Vec<u8> },
+    Closed(usize),
+}
+
+/// Writing side of a download connection
+struct Writer {
+    depot: u32,
+    writer: WriteHalf<Connection>,
+    /// Chunk requests sent or chunks answered on the connection
+    count: u64,
+    /// Chunk requests awaiting a chunk, on the client
+    in_flight: usize,
+}
+
+impl Writer {
+    /// Chunk request carrying `upload`, `None` to end the upload
+    async fn request(&mut self, code: u64, host: &str, upload: Option<&[u8]>) -> io::Result<()> {
+        let path = format!("/depot/{}/chunk/{}", self.depot, chunk_id(code, self.depot, self.count, upload));
+        self.writer.write_all(request(&path, host).as_bytes()).await?;
+        self.count += 1;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Chunk number `sequence` carrying `data`, which is empty at the end
+    /// of the download
+    async fn chunk(&mut self, code: u64, sequence: u32, data: &[u8]) -> io::Result<()> {
+        let mut response = response_head(SEQUENCE_SIZE + data.len()).into_bytes();
+        response.extend_from_slice(&masked_sequence(code, self.depot, self.count, sequence));
+        response.extend_from_slice(data);
+        self.writer.write_all(&response).await?;
+        self.writer.flush().await?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Split each connection into a reader task reporting `events` and a
+/// writer
+fn attach(
+    joined: Vec<Joined>,
+    code: u64,
+    client: bool,
+    events: &mpsc::Sender<Event>,
+    writers: &mut Vec<Writer>,
+    readers: &mut Vec<tokio::task::JoinHandle<()>>,
+) {
+    for Joined { depot, connection } in joined {
+        let (reader, writer) = tokio::io::split(connection);
+        let (index, events) = (writers.len(), events.clone());
+        readers.push(tokio::spawn(async move {
+            let read = match client {
+                true => read_chunks(reader, index, code, depot, &events).await,
+                false => read_requests(reader, index, code, depot, &events).await,
+            };
+            if let Err(e) = read {
+                log::debug!("Steam download connection {} closed: {}", index, e);
+            }
+            let _ = events.send(Event::Closed(index)).await;
+        }));
+        writers.push(Writer { depot, writer, count: 0, in_flight: 0 });
+    }
+}
+
+/// Client side of a download: upload in chunk IDs, download in chunks
+async fn fetch(code: u64, host: &str, connections: Vec<Joined>, local: DuplexStream) -> io::Result<()> {
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (mut writers, mut readers) = (Vec::new(), Vec::new());
+    attach(connections, code, true, &events_tx, &mut writers, &mut readers);
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+
+    let result = async {
+        for writer in writers.iter_mut() {
+            while writer.in_flight < REQUESTS_IN_FLIGHT {
+                writer.request(code, host, Some(&[])).await?;
+            }
+            writer.writer.flush().await?;
+        }
+        let mut buf = vec![0u8; UPSTREAM_PER_REQUEST * 64];
+        let mut chunks = BTreeMap::new();
+        let (mut next, mut open) = (0u32, writers.len());
+        let (mut app_open, mut download_open) = (true, true);
+        while app_open || download_open {
+            tokio::select! {
+                event = events.recv() => match event.expect("the download holds a sender") {
+                    Event::Chunk { connection, sequence, data } => {
+                        chunks.insert(sequence, data);
+                        while let Some(data) = chunks.remove(&next) {
+                            next = next.wrapping_add(1);
+                            if data.is_empty() {
+                                download_open = false;
+                                app_write.shutdown().await?;
+                            } else {
+                                app_write.write_all(&data).await?;
+                            }
+                        }
+                        let writer = &mut writers[connection];
+                        writer.in_flight -= 1;
+                        if download_open && writer.in_flight < REQUESTS_IN_FLIGHT {
+                            writer.request(code, host, Some(&[])).await?;
+                            writer.writer.flush().await?;
+                        }
+                    }
+                    Event::Closed(_) => {
+                        open -= 1;
+                        if open == 0 {
+                            return app_write.shutdown().await;
+                        }
+                    }
+                    Event::Request { .. } => {}
+                },
+                n = app_read.read(&mut buf), if app_open => {
+                    let n = n?;
+                    let writer = &mut writers[0];
+                    if n == 0 {
+                        app_open = false;
+                        writer.request(code, host, None).await?;
+                    }
+                    for upload in buf[..n].chunks(UPSTREAM_PER_REQUEST) {
+                        writer.request(code, host, Some(upload)).await?;
+                    }
+                    writer.writer.flush().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    readers.iter().for_each(|reader| reader.abort());
+    result
+}
+
+/// Server side of a download: answer the oldest chunk request with the
+/// tunnel data at hand
+async fn serve(code: u64, first: Joined, mut joins: mpsc::Receiver<Joined>, local: DuplexStream) -> io::Result<()> {
+    let (events_tx, mut events) = mpsc::channel(64);
+    let (mut writers, mut readers) = (Vec::new(), Vec::new());
+    attach(vec![first], code, false, &events_tx, &mut writers, &mut readers);
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+
+    let result = async {
+        let mut buf = vec![0u8; MAX_CHUNK];
+        let mut pending = VecDeque::new();
+        let (mut sequence, mut open) = (0u32, 1);
+        let (mut app_open, mut upload_open) = (true, true);
+        while app_open || upload_open {
+            tokio::select! {
+                Some(joined) = joins.recv() => {
+                    attach(vec![joined], code, false, &events_tx, &mut writers, &mut readers);
+                    open += 1;
+                }
+                event = events.recv() => match event.expect("the download holds a sender") {
+                    Event::Request { connection, upload } => {
+                        match upload {
+                            Some(upload) => app_write.write_all(&upload).await?,
+                            None => {
+                                upload_open = false;
+                                app_write.shutdown().await?;
+                            }
+                        }
+                        pending.push_back(connection);
+                    }
+                    Event::Closed(connection) => {
+                        pending.retain(|&pending| pending != connection);
+                        open -= 1;
+                        if open == 0 {
+                            return Ok(());
+                        }
+                    }
+                    Event::Chunk { .. } => {}
+                },
+                n = app_read.read(&mut buf), if app_open && !pending.is_empty() => {
+                    let n = n?;
+                    app_open = n > 0;
+                    let connection = pending.pop_front().expect("a chunk request is pending");
+                    writers[connection].chunk(code, sequence, &buf[..n]).await?;
+                    sequence = sequence.wrapping_add(1);
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    readers.iter().for_each(|reader| reader.abort());
+    result
+}
+
+/// Report the chunk requests of a server's connection
+async fn read_requests(
+    reader: ReadHalf<Connection>,
+    connection: usize,
+    code: u64,
+    depot: u32,
+    events: &mpsc::Sender<Event>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    for count in 0.. {
+        let head = read_head(&mut reader).await?;
+        let id = head[0]
+            .split(' ')
+            .nth(1)
+            .and_then(|path| path.strip_prefix(&format!("/depot/{}/chunk/", depot)))
+            .ok_or_else(|| invalid(format!("Not a Steam chunk request: {}", head[0])))?;
+        let upload = upload(code, depot, count, id)?;
+        if events.send(Event::Request { connection, upload }).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Report the chunks arriving on a client's connection
+async fn read_chunks(
+    reader: ReadHalf<Connection>,
+    connection: usize,
+    code: u64,
+    depot: u32,
+    events: &mpsc::Sender<Event>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    for count in 0.. {
+        let length = response_length(&read_head(&mut reader).await?)?;
+        if !(SEQUENCE_SIZE..=SEQUENCE_SIZE + MAX_CHUNK).contains(&length) {
+            return Err(invalid(format!("Steam chunk of {} bytes", length)));
+        }
+        let mut data = vec![0u8; length];
+        reader.read_exact(&mut data).await?;
+        let masked: [u8; SEQUENCE_SIZE] = data[..SEQUENCE_SIZE].try_into().unwrap();
+        let sequence = u32::from_be_bytes(masked_sequence(code, depot, count, u32::from_be_bytes(masked)));
+        let data = data.split_off(SEQUENCE_SIZE);
+        if events.send(Event::Chunk { connection, sequence, data }).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Keystream block of the `count`th chunk request or chunk on the
+/// connection of `depot`
+fn mask(code: u64, depot: u32, label: &[u8], count: u64) -> digest::Digest {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(b"nooshdaroo steam framing");
+    context.update(label);
+    context.update(&code.to_be_bytes());
+    context.update(&depot.to_be_bytes());
+    context.update(&count.to_be_bytes());
+    context.finish()
+}
+
+/// Manifest ID a joining connection asks for
+fn joining_gid(code: u64, depot: u32) -> u64 {
+    u64::from_be_bytes(mask(code, depot, b"manifest", 0).as_ref()[..8].try_into().unwrap())
+}
+
+/// Chunk ID of the `count`th request, carrying `upload`
+fn chunk_id(code: u64, depot: u32, count: u64, upload: Option<&[u8]>) -> String {
+    let mut id: [u8; 20] = rand::random();
+    match upload {
+        Some(upload) => {
+            id[0] = upload.len() as u8;
+            id[1..1 + upload.len()].copy_from_slice(upload);
+        }
+        None => id[0] = END_OF_UPLOAD,
+    }
+    for (byte, mask) in id.iter_mut().zip(mask(code, depot, b"chunk", count).as_ref()) {
+        *byte ^= mask;
+    }
+    hex::encode(id)
+}
+
+/// Tunnel bytes carried by the chunk ID `id` of the `count`th request,
+/// `None` at the end of the upload
+fn upload(code: u64, depot: u32, count: u64, id: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut id = hex::decode(id)
+        .ok()
+        .filter(|id| id.len() == 20)
+        .ok_or_else(|| invalid(format!("Malformed Steam chunk ID {}", id)))?;
+    for (byte, mask) in id.iter_mut().zip(mask(code, depot, b"chunk", count).as_ref()) {
+        *byte ^= mask;
+    }
+    match id[0] {
+        END_OF_UPLOAD => Ok(None),
+        length if length as usize <= UPSTREAM_PER_REQUEST => Ok(Some(id[1..1 + length as usize].to_vec())),
+        length => Err(invalid(format!("Steam chunk ID carries {} bytes", length))),
+    }
+}
+
+/// `sequence` masked as the start of the `count`th chunk, or unmasked
+fn masked_sequence(code: u64, depot: u32, count: u64, sequence: u32) -> [u8; SEQUENCE_SIZE] {
+    let mask = mask(code, depot, b"sequence", count);
+    let mut masked = sequence.to_be_bytes();
+    for (byte, mask) in masked.iter_mut().zip(mask.as_ref()) {
+        *byte ^= mask;
+    }
+    masked
+}
+
+/// Depot, manifest ID and request code of a manifest request line
+fn manifest_path(line: &str) -> io::Result<(u32, u64, u64)> {
+    let path = line.split(' ').nth(1).unwrap_or_default();
+    let parts: Vec<&str> = path.split('/').collect();
+    match parts[..] {
+        ["", "depot", depot, "manifest", gid, "5", code] => match (depot.parse(), gid.parse(), code.parse()) {
+            (Ok(depot), Ok(gid), Ok(code)) => Ok((depot, gid, code)),
+            _ => Err(invalid(format!("Malformed Steam manifest request: {}", line))),
+        },
+        _ => Err(invalid(format!("Not a Steam manifest request: {}", line))),
+    }
+}
+
+fn request(path: &str, host: &str) -> String {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: text/html,*/*;q=0.9\r\naccept-encoding: gzip,identity,*;q=0\r\naccept-charset: ISO-8859-1,utf-8,*;q=0.7\r\nUser-Agent: {}\r\n\r\n",
+        path, host, USER_AGENT
+    )
+}
+
+fn response_head(length: usize) -> String {
+    format!(
+        "HTTP/1.1 200 OK\r\nServer: nginx\r\nDate: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: keep-alive\r\nCache-Control: public, max-age=2592000\r\nAccept-Ranges: bytes\r\n\r\n",
+        chrono::Utc::now().format("%a, %d %b %Y %H:%M:%S GMT"),
+        length
+    )
+}
+
+/// Body length of a successful response
+fn response_length(head: &[String]) -> io::Result<usize> {
+    if !head[0].starts_with("HTTP/1.1 200 ") {
+        return Err(invalid(format!("Steam content server answered {}", head[0])));
+    }
+    head[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .ok_or_else(|| invalid("Steam response without a length".to_string()))
+}
+
+/// Start line and headers of a request or response
+async fn read_head<R>(reader: &mut R) -> io::Result<Vec<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        if (&mut *reader).take(MAX_LINE as u64).read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !line.ends_with('\n') {
+            return Err(invalid("HTTP line too long".to_string()));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(head);
+        }
+        if head.len() == MAX_HEAD_LINES {
+            return Err(invalid("HTTP head too long".to_string()));
+        }
+        head.push(line.to_string());
+    }
+}
+
+fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    rand::thread_rng().fill(&mut bytes[..]);
+    bytes
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_download_over_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (tunnels_tx, mut tunnels) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tunnels_tx = tunnels_tx.clone();
+                tokio::spawn(async move {
+                    if let Some(tunnel) = accept(stream).await.unwrap() {
+                        tunnels_tx.send(tunnel).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let stream = TcpStream::connect(server_addr).await.unwrap();
+        let mut tunnel = connect(stream, server_addr, "cache4-fra1.steamcontent.com").await.unwrap();
+        let mut server = tunnels.recv().await.unwrap();
+        let request: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        tunnel.write_all(&request).await.unwrap();
+        let mut received = vec![0u8; request.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, request);
+
+        let response: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7) as u8).collect();
+        let writer = tokio::spawn(async move {
+            server.write_all(&response).await.unwrap();
+            server.shutdown().await.unwrap();
+            server
+        });
+        let mut received = Vec::new();
+        tunnel.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 3_000_000);
+        assert!(received.iter().enumerate().all(|(i, b)| *b == (i * 7) as u8));
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn test_chunk_ids() {
+        let id = chunk_id(42, 1091501, 7, Some(b"tunnel bytes"));
+        assert_eq!(id.len(), 40);
+        assert_eq!(upload(42, 1091501, 7, &id).unwrap().unwrap(), b"tunnel bytes");
+        assert!(upload(42, 1091501, 7, &chunk_id(42, 1091501, 7, None)).unwrap().is_none());
+
+        let (depot, gid, code) = manifest_path("GET /depot/1091502/manifest/123/5/42 HTTP/1.1").unwrap();
+        assert_eq!((depot, gid, code), (1091502, 123, 42));
+        assert!(manifest_path("GET /depot/1091502/chunk/00 HTTP/1.1").is_err());
+    }
+}
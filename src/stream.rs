@@ -0,0 +1,186 @@
+//! Tunneled connections for embedding applications
+//!
+//! [`NooshdarooClient::connect`](crate::NooshdarooClient::connect) opens a
+//! tunnel to a target through the configured server and hands it back as a
+//! [`NooshdarooStream`], so a Rust program can use the tunnel without running
+//! the local SOCKS5 listener:
+//!
+//! ```rust,no_run
+//! use nooshdaroo::{NooshdarooClient, NooshdarooConfig};
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = NooshdarooConfig::from_file(std::path::Path::new("client.toml"))?;
+//! let client = NooshdarooClient::new(config)?;
+//! let mut stream = client.connect("example.com:80").await?;
+//! stream.write_all(b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n").await?;
+//! let mut response = Vec::new();
+//! stream.read_to_end(&mut response).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The tunnel is set up exactly as for a SOCKS5 connection: the server from
+//! `server_address` under `[socks]` (a relay chain there is entered at its
+//! first hop), the key in `[transport]`, the current protocol's wrapping,
+//! `[access]` rules, `[hosts]` mappings and traffic shaping all apply.
+
+use crate::chain::RelayChain;
+use crate::config::NooshdarooConfig;
+use crate::noise_transport::NoiseConfig;
+use crate::proxy::{ProxyType, UnifiedProxyListener};
+use crate::{NooshdarooError, ProtocolId, ShapeShiftController};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::RwLock;
+
+/// A connection to a target through the tunnel
+///
+/// Bytes written are wrapped, encrypted and shaped on their way to the
+/// server; dropping the stream closes the tunnel.
+#[derive(Debug)]
+pub struct NooshdarooStream {
+    inner: DuplexStream,
+    target: String,
+    protocol: ProtocolId,
+}
+
+impl NooshdarooStream {
+    /// The "host:port" the stream is connected to
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The protocol the tunnel was opened with
+    pub fn protocol(&self) -> &ProtocolId {
+        &self.protocol
+    }
+}
+
+impl AsyncRead for NooshdarooStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for NooshdarooStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Open a tunnel to `target` ("host:port") with the current protocol
+pub(crate) async fn connect(
+    config: &Arc<NooshdarooConfig>,
+    controller: &Arc<RwLock<ShapeShiftController>>,
+    target: &str,
+) -> Result<NooshdarooStream, NooshdarooError> {
+    let (host, port) = crate::acl::split_target(target)
+        .map_err(|_| NooshdarooError::InvalidConfig(format!("Target {} is not host:port", target)))?;
+    let policy = crate::acl::AccessPolicy::new(&config.access).map_err(NooshdarooError::InvalidConfig)?;
+    if !policy.allows(host, port) {
+        return Err(NooshdarooError::Tunnel(format!("Connection to {} not allowed", target)));
+    }
+    let (server_addr, chain, noise_config) = configured_server(config).await?;
+
+    // Names mapped in [hosts] reach the server as their address
+    let host = match crate::hosts::lookup(&config.hosts, host) {
+        Some(addrs) => addrs[0].to_string(),
+        None => host.to_string(),
+    };
+    let target_info = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+
+    let protocol = controller.read().await.current_protocol();
+    log::info!("Opening stream to {} via server {} using {}", target, server_addr, protocol);
+    let inner = crate::proxy::open_stream(
+        server_addr,
+        chain.as_ref(),
+        &noise_config,
+        &protocol,
+        config,
+        Some(Arc::clone(controller)),
+        &target_info,
+    )
+    .await
+    .map_err(NooshdarooError::Tunnel)?;
+
+    Ok(NooshdarooStream { inner, target: target.to_string(), protocol })
+}
+
+/// Address of the server under `[socks]` (the entry hop of a relay chain
+/// there), the chain, and the Noise settings to reach it with
+async fn configured_server(
+    config: &NooshdarooConfig,
+) -> Result<(SocketAddr, Option<RelayChain>, NoiseConfig), NooshdarooError> {
+    let noise_config = config
+        .transport
+        .clone()
+        .ok_or_else(|| NooshdarooError::InvalidConfig("Tunneling needs the server's key in [transport]".to_string()))?;
+    let server = config
+        .socks
+        .server_address
+        .as_deref()
+        .ok_or_else(|| NooshdarooError::InvalidConfig("Tunneling needs server_address under [socks]".to_string()))?;
+
+    let chain = if RelayChain::is_chain(server) {
+        Some(RelayChain::parse(server).map_err(NooshdarooError::InvalidConfig)?)
+    } else {
+        None
+    };
+    let server_addr = match chain {
+        Some(ref chain) => chain.entry_addr().map_err(NooshdarooError::InvalidConfig)?,
+        None => crate::doh::resolve_socket_addr(&config.doh, &config.hosts, server)
+            .await
+            .map_err(|e| NooshdarooError::Tunnel(format!("Cannot resolve server {}: {}", server, e)))?,
+    };
+    Ok((server_addr, chain, noise_config))
+}
+
+/// SOCKS5 listener on `[socks]`'s listen address tunneling through its server
+pub(crate) async fn socks_listener(
+    config: &Arc<NooshdarooConfig>,
+    controller: &Arc<RwLock<ShapeShiftController>>,
+) -> Result<UnifiedProxyListener, NooshdarooError> {
+    let (server_addr, chain, noise_config) = configured_server(config).await?;
+    let protocol = controller.read().await.current_protocol();
+    let listener = UnifiedProxyListener::new(config.socks.listen_addr.clone(), vec![ProxyType::Socks5], protocol, Arc::clone(config))
+        .with_server(server_addr, noise_config)
+        .with_controller(Arc::clone(controller));
+    Ok(match chain {
+        Some(chain) => listener.with_chain(chain),
+        None => listener,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_requires_server() {
+        let mut config = NooshdarooConfig::default();
+        let client = crate::NooshdarooClient::new(config.clone()).unwrap();
+        assert!(matches!(client.connect("example.com:80").await, Err(NooshdarooError::InvalidConfig(_))));
+        assert!(matches!(client.connect("example.com").await, Err(NooshdarooError::InvalidConfig(_))));
+
+        // Nothing listens on the server's port
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let key = crate::noise_transport::NoiseKeypair::generate().unwrap().public_key_base64();
+        config.transport = Some(NoiseConfig { remote_public_key: Some(key), ..Default::default() });
+        config.socks.server_address = Some(unused.to_string());
+        let client = crate::NooshdarooClient::new(config).unwrap();
+        assert!(matches!(client.connect("example.com:80").await, Err(NooshdarooError::Tunnel(_))));
+    }
+}
@@ -0,0 +1,464 @@
+//! Tunnel stream carried as syslog shipped over TLS to a log collector
+//!
+//! Servers everywhere ship their logs to a SIEM, and the connection doing
+//! it is a long-lived TLS session on port 6514 with a steady stream of
+//! records going out. This transport is that connection: a TLS 1.3
+//! handshake (from [`crate::tls_handshake`]), then RFC 5425 syslog over
+//! TLS, octet-counted RFC 5424 messages batched into application data
+//! records.
+//!
+//! ```toml
+//! [socks]
+//! transport = "syslog"
+//! http_host = "logs.siem.example.com"   # TLS server name, the server's IP by default
+//!
+//! [server]
+//! transport = "syslog"
+//! listen_addr = "0.0.0.0:6514"
+//! ```
+//!
+//! Tunnel bytes go base64-encoded in the MSG part of messages from
+//! daemons of the client's host, each with the priority its facility and
+//! severity give and an RFC 3339 timestamp, and tagged with a `meta`
+//! sequence ID. Each record is sealed with ChaCha20-Poly1305 in TLS 1.3's
+//! layout, so only its size and timing show, and those follow a shipper:
+//! a batch of messages per record, and a few ordinary log lines every
+//! [`COVER_INTERVAL`] or so when the tunnel is idle. Syslog itself flows
+//! one way; what the collector sends back travels the same way, as
+//! records in the other direction.
+//!
+//! The record keys are derived from the TLS session ID: they disguise the
+//! tunnel, the Noise session inside protects it.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::digest;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+
+/// Buffer between the tunnel and the session
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Tunnel bytes batched into one record, which stays under TLS's 16 KiB
+/// once encoded
+const READ_SIZE: usize = 8 * 1024;
+
+/// Tunnel bytes in one message, a typical log line's worth
+const MESSAGE_DATA: std::ops::Range<usize> = 180..1100;
+
+/// Mean silence after which the client ships a few ordinary log lines
+pub const COVER_INTERVAL: Duration = Duration::from_secs(10);
+
+const RECORD_APPLICATION_DATA: u8 = 0x17;
+const TAG_SIZE: usize = 16;
+
+/// Largest record accepted
+const MAX_RECORD: usize = 16384 + 256;
+
+/// Daemons messages come from, with their facility and the severities
+/// they log at
+const SOURCES: &[(&str, u8, &[u8])] = &[
+    ("sshd", 10, &[6, 6, 6, 5]),
+    ("sudo", 10, &[5, 6]),
+    ("CRON", 9, &[6]),
+    ("systemd", 3, &[6, 6, 5, 4]),
+    ("kernel", 0, &[6, 4, 3]),
+    ("nginx", 23, &[6, 6, 6, 4, 3]),
+    ("postgres", 16, &[6, 5, 4]),
+    ("dockerd", 3, &[6, 4]),
+];
+
+/// Host names of shippers and collectors
+const HOSTS: &[&str] = &["web", "app", "api", "db", "worker", "cache"];
+const COLLECTORS: &[&str] = &["siem-ingest", "logcollector", "syslog"];
+const DOMAIN: &str = "prod.internal";
+
+/// Ordinary log lines shipped while the tunnel is idle
+const COVER_LINES: &[(&str, &str)] = &[
+    ("sshd", "Accepted publickey for deploy from 10.20.{a}.{b} port {port} ssh2: ED25519 SHA256:{hash}"),
+    ("CRON", "(root) CMD (/usr/lib/sysstat/debian-sa1 1 1)"),
+    ("systemd", "Started Session {n} of User deploy."),
+    ("nginx", "10.20.{a}.{b} - - \"GET /healthz HTTP/1.1\" 200 2 \"-\" \"kube-probe/1.29\""),
+    ("sudo", "deploy : TTY=pts/0 ; PWD=/home/deploy ; USER=root ; COMMAND=/usr/bin/systemctl status app"),
+    ("kernel", "[UFW BLOCK] IN=eth0 OUT= SRC=10.20.{a}.{b} DST=10.20.0.4 PROTO=TCP SPT={port} DPT=23"),
+];
+
+/// Start the client side over `stream`, shipping logs to `server_name`;
+/// the returned stream carries the tunnel
+pub async fn connect<S>(mut stream: S, server_name: &str) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (hello, session_id) =
+        crate::tls_handshake::client_hello(server_name, &rand::random::<[u8; 32]>()).map_err(io::Error::other)?;
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+    crate::tls_handshake::read_server_flight(&mut stream, &session_id).await.map_err(|e| invalid(e.to_string()))?;
+    stream.write_all(&crate::tls_handshake::client_finished(&[]).map_err(io::Error::other)?).await?;
+    stream.flush().await?;
+
+    let hostname = {
+        let mut rng = rand::thread_rng();
+        format!("{}-{:02}.{}", HOSTS.choose(&mut rng).unwrap(), rng.gen_range(1..24), DOMAIN)
+    };
+    let (send, receive) = (RecordKey::derive(&session_id, 1), RecordKey::derive(&session_id, 2));
+    Ok(spawn_session(stream, Shipper::new(hostname, true), send, receive))
+}
+
+/// Start the server side over an accepted `stream`; the returned stream
+/// carries the tunnel
+pub async fn accept<S>(mut stream: S) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (_, session_id) =
+        crate::tls_handshake::read_client_hello(&mut stream).await.map_err(|e| invalid(e.to_string()))?;
+    let flight =
+        crate::tls_handshake::server_flight(&session_id, &rand::random::<[u8; 32]>()).map_err(io::Error::other)?;
+    stream.write_all(&flight).await?;
+    stream.flush().await?;
+    crate::tls_handshake::read_client_finished(&mut stream).await.map_err(|e| invalid(e.to_string()))?;
+
+    let hostname = {
+        let mut rng = rand::thread_rng();
+        format!("{}-{:02}.{}", COLLECTORS.choose(&mut rng).unwrap(), rng.gen_range(1..4), DOMAIN)
+    };
+    let (send, receive) = (RecordKey::derive(&session_id, 2), RecordKey::derive(&session_id, 1));
+    Ok(spawn_session(stream, Shipper::new(hostname, false), send, receive))
+}
+
+fn spawn_session<S>(stream: S, shipper: Shipper, send: RecordKey, receive: RecordKey) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (tunnel, local) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        if let Err(e) = run_session(stream, shipper, send, receive, local).await {
+            log::debug!("Syslog session ended: {}", e);
+        }
+    });
+    tunnel
+}
+
+/// Move tunnel bytes between `local` and records until either side closes
+async fn run_session<S>(
+    stream: S,
+    mut shipper: Shipper,
+    mut send: RecordKey,
+    mut receive: RecordKey,
+    local: DuplexStream,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (mut app_read, mut app_write) = tokio::io::split(local);
+
+    // Records are read in their own task, as a partly read record must not
+    // be dropped when another branch wins
+    let (data_tx, mut data) = mpsc::channel(64);
+    let reader = tokio::spawn(async move {
+        let mut messages = Vec::new();
+        loop {
+            let record = match read_record(&mut reader).await {
+                Ok(record) => record,
+                Err(e) => {
+                    let _ = data_tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let Some(plaintext) = receive.open(&record) else {
+                let _ = data_tx.send(Err(invalid("Syslog record does not authenticate".to_string()))).await;
+                return;
+            };
+            messages.extend_from_slice(&plaintext);
+            match take_messages(&mut messages) {
+                Ok(bytes) if bytes.is_empty() => {}
+                Ok(bytes) => {
+                    if data_tx.send(Ok(bytes)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let _ = data_tx.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+    });
+
+    let result = async {
+        let mut buf = vec![0u8; READ_SIZE];
+        let mut app_open = true;
+        let cover = tokio::time::sleep(cover_interval());
+        tokio::pin!(cover);
+        loop {
+            tokio::select! {
+                bytes = data.recv() => match bytes {
+                    Some(Ok(bytes)) => app_write.write_all(&bytes).await?,
+                    Some(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return app_write.shutdown().await,
+                    Some(Err(e)) => return Err(e),
+                    None => return app_write.shutdown().await,
+                },
+                n = app_read.read(&mut buf), if app_open => {
+                    let n = n?;
+                    if n == 0 {
+                        app_open = false;
+                        writer.shutdown().await?;
+                        continue;
+                    }
+                    writer.write_all(&send.seal(shipper.data_batch(&buf[..n]).as_bytes())).await?;
+                    cover.as_mut().reset(tokio::time::Instant::now() + cover_interval());
+                }
+                _ = &mut cover, if app_open && shipper.client => {
+                    writer.write_all(&send.seal(shipper.cover_batch().as_bytes())).await?;
+                    cover.as_mut().reset(tokio::time::Instant::now() + cover_interval());
+                }
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    result
+}
+
+/// Silence before the next cover batch, around [`COVER_INTERVAL`]
+fn cover_interval() -> Duration {
+    COVER_INTERVAL.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
+
+/// Writes the messages of one side
+struct Shipper {
+    hostname: String,
+    /// Whether this is the shipping side, which sends cover batches
+    client: bool,
+    /// Process ID of each source on this host
+    pids: Vec<u32>,
+    /// Next `meta` sequence ID
+    sequence: u64,
+}
+
+impl Shipper {
+    fn new(hostname: String, client: bool) -> Self {
+        let mut rng = rand::thread_rng();
+        let pids = SOURCES.iter().map(|_| rng.gen_range(300..65000)).collect();
+        Self { hostname, client, pids, sequence: 1 }
+    }
+
+    /// Octet-counted messages carrying `data`
+    fn data_batch(&mut self, data: &[u8]) -> String {
+        let mut rng = rand::thread_rng();
+        let mut batch = String::with_capacity(data.len() * 4 / 3 + 256);
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(rng.gen_range(MESSAGE_DATA).min(rest.len()));
+            rest = tail;
+            let source = rng.gen_range(0..SOURCES.len());
+            let sd = format!("[meta sequenceId=\"{}\"]", self.sequence);
+            self.sequence += 1;
+            push_message(&mut batch, &self.message(source, &sd, &BASE64.encode(chunk)));
+        }
+        batch
+    }
+
+    /// Octet-counted ordinary log lines
+    fn cover_batch(&mut self) -> String {
+        let mut rng = rand::thread_rng();
+        let mut batch = String::new();
+        for _ in 0..rng.gen_range(1..5) {
+            let (app, line) = COVER_LINES.choose(&mut rng).unwrap();
+            let line = line
+                .replace("{a}", &rng.gen_range(0..8).to_string())
+                .replace("{b}", &rng.gen_range(2..255).to_string())
+                .replace("{port}", &rng.gen_range(32768..61000).to_string())
+                .replace("{n}", &rng.gen_range(100..9000).to_string())
+                .replace("{hash}", &BASE64.encode(rand::random::<[u8; 32]>())[..43]);
+            let source = SOURCES.iter().position(|(name, _, _)| name == app).unwrap();
+            push_message(&mut batch, &self.message(source, "-", &line));
+        }
+        batch
+    }
+
+    /// RFC 5424 message from `SOURCES[source]`
+    fn message(&self, source: usize, sd: &str, msg: &str) -> String {
+        let (app, facility, severities) = SOURCES[source];
+        let severity = *severities.choose(&mut rand::thread_rng()).unwrap();
+        let timestamp = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ");
+        // The kernel logs without a process ID
+        let pid = match app {
+            "kernel" => "-".to_string(),
+            _ => self.pids[source].to_string(),
+        };
+        format!("<{}>1 {} {} {} {} - {} {}", facility * 8 + severity, timestamp, self.hostname, app, pid, sd, msg)
+    }
+}
+
+/// Append `message` with its RFC 5425 octet count
+fn push_message(batch: &mut String, message: &str) {
+    batch.push_str(&message.len().to_string());
+    batch.push(' ');
+    batch.push_str(message);
+}
+
+/// Tunnel bytes of the complete messages at the start of `buffer`,
+/// leaving a partial message there
+fn take_messages(buffer: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut pos = 0;
+    while let Some(space) = buffer[pos..].iter().position(|&b| b == b' ') {
+        let length = std::str::from_utf8(&buffer[pos..pos + space])
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|&length| length <= MAX_RECORD)
+            .ok_or_else(|| invalid("Syslog message without an octet count".to_string()))?;
+        let start = pos + space + 1;
+        if buffer.len() < start + length {
+            break;
+        }
+        if let Some(bytes) = message_data(&buffer[start..start + length])? {
+            data.extend_from_slice(&bytes);
+        }
+        pos = start + length;
+    }
+    buffer.drain(..pos);
+    Ok(data)
+}
+
+/// Tunnel bytes of an RFC 5424 message; `None` for an ordinary log line
+fn message_data(message: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let message = std::str::from_utf8(message).map_err(|_| invalid("Syslog message is not UTF-8".to_string()))?;
+    // PRI and version, timestamp, host, app, process ID, message ID
+    let Some(rest) = message.splitn(7, ' ').nth(6) else {
+        return Err(invalid("Truncated syslog message".to_string()));
+    };
+    let Some(rest) = rest.strip_prefix("[meta sequenceId=\"") else {
+        return Ok(None);
+    };
+    let msg = rest.split_once("] ").map(|(_, msg)| msg).unwrap_or_default();
+    BASE64.decode(msg).map(Some).map_err(|_| invalid("Syslog message data is not base64".to_string()))
+}
+
+/// ChaCha20-Poly1305 key of one direction, with TLS 1.3's per-record nonce
+struct RecordKey {
+    key: LessSafeKey,
+    iv: [u8; 12],
+    sequence: u64,
+}
+
+impl RecordKey {
+    fn derive(session_id: &[u8], direction: u8) -> Self {
+        let derive = |label: u8| {
+            let mut context = digest::Context::new(&digest::SHA256);
+            context.update(b"nooshdaroo syslog framing");
+            context.update(&[label]);
+            context.update(session_id);
+            context.finish()
+        };
+        let key = UnboundKey::new(&CHACHA20_POLY1305, derive(direction).as_ref()).expect("32-byte key");
+        let iv = derive(direction + 2).as_ref()[..12].try_into().unwrap();
+        Self { key: LessSafeKey::new(key), iv, sequence: 0 }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = self.iv;
+        for (byte, sequence) in nonce[4..].iter_mut().zip(self.sequence.to_be_bytes()) {
+            *byte ^= sequence;
+        }
+        self.sequence += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Application data record carrying `plaintext`
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let length = plaintext.len() + 1 + TAG_SIZE;
+        let mut record = Vec::with_capacity(5 + length);
+        record.extend_from_slice(&[RECORD_APPLICATION_DATA, 0x03, 0x03]);
+        record.extend_from_slice(&(length as u16).to_be_bytes());
+        let mut ciphertext = [plaintext, &[RECORD_APPLICATION_DATA]].concat();
+        let nonce = self.nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(&record[..5]), &mut ciphertext)
+            .expect("batch fits a record");
+        record.extend_from_slice(&ciphertext);
+        record
+    }
+
+    /// Plaintext of a record read by [`read_record`]; `None` if it does
+    /// not authenticate
+    fn open(&mut self, record: &[u8]) -> Option<Vec<u8>> {
+        let (header, ciphertext) = record.split_at(5);
+        let mut plaintext = ciphertext.to_vec();
+        let nonce = self.nonce();
+        let length = self.key.open_in_place(nonce, Aad::from(header), &mut plaintext).ok()?.len();
+        // Drop the inner content type
+        plaintext.truncate(length.checked_sub(1)?);
+        Some(plaintext)
+    }
+}
+
+/// Next application data record, header included
+async fn read_record<R>(reader: &mut R) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut record = vec![0u8; 5];
+    reader.read_exact(&mut record).await?;
+    let length = u16::from_be_bytes([record[3], record[4]]) as usize;
+    if record[0] != RECORD_APPLICATION_DATA || length > MAX_RECORD {
+        return Err(invalid("Unexpected TLS record in syslog session".to_string()));
+    }
+    record.resize(5 + length, 0);
+    reader.read_exact(&mut record[5..]).await?;
+    Ok(record)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_then_tunnel() {
+        let (client_side, server_side) = tokio::io::duplex(BUFFER_SIZE);
+        let server = tokio::spawn(async move {
+            let mut tunnel = accept(server_side).await.unwrap();
+            let mut request = vec![0u8; 50_000];
+            tunnel.read_exact(&mut request).await.unwrap();
+            assert!(request.iter().enumerate().all(|(i, b)| *b == i as u8));
+            tunnel.write_all(b"collector reply").await.unwrap();
+        });
+        let mut tunnel = connect(client_side, "logs.siem.example.com").await.unwrap();
+        let request: Vec<u8> = (0..50_000u32).map(|i| i as u8).collect();
+        tunnel.write_all(&request).await.unwrap();
+        let mut reply = Vec::new();
+        tunnel.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"collector reply");
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_batches_parse() {
+        let mut shipper = Shipper::new("web-01.prod.internal".to_string(), true);
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 13) as u8).collect();
+        let batch = shipper.data_batch(&data);
+        assert!(batch.len() < 16384);
+        assert!(batch.split(' ').nth(1).unwrap().starts_with('<'));
+
+        // Cover lines carry nothing, and a partial message waits for the rest
+        let mut buffer = [shipper.cover_batch(), batch].concat().into_bytes();
+        let tail = buffer.split_off(buffer.len() - 10);
+        let mut received = take_messages(&mut buffer).unwrap();
+        assert!(!buffer.is_empty());
+        buffer.extend_from_slice(&tail);
+        received.extend_from_slice(&take_messages(&mut buffer).unwrap());
+        assert_eq!(received, data);
+        assert!(buffer.is_empty());
+    }
+}
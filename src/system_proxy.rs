@@ -0,0 +1,284 @@
+//! Pointing the operating system's proxy settings at the client
+//!
+//! With `nooshdaroo client --system-proxy`, the client sets the system
+//! proxy to its local listener on start, so browsers and other apps that
+//! follow the system settings use the tunnel without configuring each one,
+//! and puts the previous settings back when it exits:
+//!
+//! | Platform | Settings                                                      |
+//! |----------|---------------------------------------------------------------|
+//! | macOS    | SOCKS or web proxies of every enabled service, via `networksetup` |
+//! | Windows  | WinINET `ProxyEnable`/`ProxyServer` of the current user, via `reg` |
+//! | Linux    | GNOME `org.gnome.system.proxy` settings, via `gsettings`      |
+//!
+//! WinINET apps already running keep their settings until restarted. Like
+//! [`crate::key_provider`], this shells out to the platform tools.
+
+use crate::key_provider::run;
+use crate::proxy::ProxyType;
+use anyhow::{bail, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// WinINET settings of the current user
+const WININET_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+/// The system proxy settings the client replaced, restored when dropped
+#[derive(Debug)]
+pub struct SystemProxy {
+    saved: Saved,
+    restored: bool,
+}
+
+#[derive(Debug)]
+enum Saved {
+    /// `networksetup` proxy kind, service and its previous setting
+    MacOs(Vec<(&'static str, String, MacProxy)>),
+    /// Previous `ProxyEnable` and `ProxyServer` values
+    Windows { enable: Option<u32>, server: Option<String> },
+    /// GNOME schema, key and previous value (as `gsettings get` prints it)
+    Gnome(Vec<(&'static str, &'static str, String)>),
+}
+
+/// A proxy setting of a macOS network service
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MacProxy {
+    enabled: bool,
+    server: String,
+    port: u16,
+}
+
+impl SystemProxy {
+    /// Point the system proxy at the `proxy_type` listener on `listen`,
+    /// remembering the settings it replaces
+    pub fn apply(listen: SocketAddr, proxy_type: ProxyType) -> Result<Self> {
+        if proxy_type == ProxyType::Transparent {
+            bail!("A transparent proxy cannot be set as the system proxy");
+        }
+        let host = match listen.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => "127.0.0.1".to_string(),
+            IpAddr::V6(ip) if ip.is_unspecified() => "::1".to_string(),
+            ip => ip.to_string(),
+        };
+        let port = listen.port();
+        let socks = proxy_type == ProxyType::Socks5;
+
+        let saved = if cfg!(target_os = "macos") {
+            Saved::MacOs(apply_macos(&host, port, socks)?)
+        } else if cfg!(windows) {
+            apply_windows(&host, port, socks)?
+        } else {
+            Saved::Gnome(apply_gnome(&host, port, socks)?)
+        };
+        log::info!("System proxy set to {} {}:{}", if socks { "SOCKS" } else { "HTTP" }, host, port);
+        Ok(Self { saved, restored: false })
+    }
+
+    /// Put the previous settings back
+    pub fn restore(mut self) -> Result<()> {
+        self.restored = true;
+        restore(&self.saved)
+    }
+}
+
+impl Drop for SystemProxy {
+    fn drop(&mut self) {
+        if !self.restored {
+            if let Err(e) = restore(&self.saved) {
+                log::warn!("Failed to restore the system proxy settings: {}", e);
+            }
+        }
+    }
+}
+
+fn restore(saved: &Saved) -> Result<()> {
+    match saved {
+        Saved::MacOs(services) => {
+            for (kind, service, proxy) in services {
+                if !proxy.server.is_empty() {
+                    let port = proxy.port.to_string();
+                    run(Command::new("networksetup").args([&format!("-set{}", kind), service, &proxy.server, &port]), None)?;
+                }
+                let state = if proxy.enabled { "on" } else { "off" };
+                run(Command::new("networksetup").args([&format!("-set{}state", kind), service, state]), None)?;
+            }
+        }
+        Saved::Windows { enable, server } => {
+            set_reg_dword("ProxyEnable", enable.unwrap_or(0))?;
+            match server {
+                Some(server) => set_reg_string("ProxyServer", server)?,
+                None => {
+                    run(Command::new("reg").args(["delete", WININET_KEY, "/v", "ProxyServer", "/f"]), None)?;
+                }
+            }
+        }
+        Saved::Gnome(settings) => {
+            for (schema, key, value) in settings {
+                run(Command::new("gsettings").args(["set", schema, key, value]), None)?;
+            }
+        }
+    }
+    log::info!("System proxy settings restored");
+    Ok(())
+}
+
+fn apply_macos(host: &str, port: u16, socks: bool) -> Result<Vec<(&'static str, String, MacProxy)>> {
+    let kinds: &[&'static str] = if socks { &["socksfirewallproxy"] } else { &["webproxy", "securewebproxy"] };
+    let listing = run(Command::new("networksetup").arg("-listallnetworkservices"), None)?;
+    let mut saved = Vec::new();
+    // The first line explains that disabled services are marked with '*'
+    for service in listing.lines().skip(1).filter(|line| !line.is_empty() && !line.starts_with('*')) {
+        for &kind in kinds {
+            let current = run(Command::new("networksetup").args([&format!("-get{}", kind), service]), None)?;
+            saved.push((kind, service.to_string(), parse_networksetup(&current)));
+            run(
+                Command::new("networksetup").args([&format!("-set{}", kind), service, host, &port.to_string()]),
+                None,
+            )?;
+            run(Command::new("networksetup").args([&format!("-set{}state", kind), service, "on"]), None)?;
+        }
+    }
+    if saved.is_empty() {
+        bail!("networksetup lists no enabled network services");
+    }
+    Ok(saved)
+}
+
+/// Parse `networksetup -getwebproxy` and the like
+fn parse_networksetup(output: &str) -> MacProxy {
+    let field = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':').map(str::trim))
+            .unwrap_or("")
+    };
+    MacProxy {
+        enabled: field("Enabled") == "Yes",
+        server: field("Server").to_string(),
+        port: field("Port").parse().unwrap_or(0),
+    }
+}
+
+fn apply_windows(host: &str, port: u16, socks: bool) -> Result<Saved> {
+    let saved = Saved::Windows {
+        enable: query_reg("ProxyEnable")?.and_then(|value| u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()),
+        server: query_reg("ProxyServer")?,
+    };
+    let address = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let server = if socks { format!("socks={}", address) } else { address };
+    set_reg_string("ProxyServer", &server)?;
+    set_reg_dword("ProxyEnable", 1)?;
+    Ok(saved)
+}
+
+/// Value of `name` under [`WININET_KEY`], if set
+fn query_reg(name: &str) -> Result<Option<String>> {
+    match run(Command::new("reg").args(["query", WININET_KEY, "/v", name]), None) {
+        Ok(output) => Ok(parse_reg_query(&output, name)),
+        // reg fails when the value does not exist
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parse the value out of `reg query` output such as
+/// `    ProxyEnable    REG_DWORD    0x1`
+fn parse_reg_query(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next()? != name || !fields.next()?.starts_with("REG_") {
+            return None;
+        }
+        Some(fields.collect::<Vec<_>>().join(" "))
+    })
+}
+
+fn set_reg_dword(name: &str, value: u32) -> Result<()> {
+    run(
+        Command::new("reg").args(["add", WININET_KEY, "/v", name, "/t", "REG_DWORD", "/d", &value.to_string(), "/f"]),
+        None,
+    )?;
+    Ok(())
+}
+
+fn set_reg_string(name: &str, value: &str) -> Result<()> {
+    run(Command::new("reg").args(["add", WININET_KEY, "/v", name, "/t", "REG_SZ", "/d", value, "/f"]), None)?;
+    Ok(())
+}
+
+fn apply_gnome(host: &str, port: u16, socks: bool) -> Result<Vec<(&'static str, &'static str, String)>> {
+    let mut settings = vec![("org.gnome.system.proxy", "mode", "'manual'".to_string())];
+    for (schema, used) in [
+        ("org.gnome.system.proxy.socks", socks),
+        ("org.gnome.system.proxy.http", !socks),
+        ("org.gnome.system.proxy.https", !socks),
+    ] {
+        // Proxies for the other protocols are cleared so apps cannot bypass the tunnel
+        let host = if used { format!("'{}'", host) } else { "''".to_string() };
+        settings.push((schema, "host", host));
+        settings.push((schema, "port", if used { port } else { 0 }.to_string()));
+    }
+
+    let mut saved = Vec::new();
+    for (schema, key, value) in settings {
+        let previous = run(Command::new("gsettings").args(["get", schema, key]), None)?.trim().to_string();
+        if let Err(e) = run(Command::new("gsettings").args(["set", schema, key, &value]), None) {
+            // Undo what was already changed
+            let _ = restore(&Saved::Gnome(std::mem::take(&mut saved)));
+            return Err(e);
+        }
+        saved.push((schema, key, previous));
+    }
+    Ok(saved)
+}
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Make [`shutdown_signal`] return, as a termination signal would
+pub fn trigger_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Wait for Ctrl-C or a termination request, so the settings can be
+/// restored before exiting
+pub async fn shutdown_signal() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+        #[cfg(unix)]
+        libc::signal(libc::SIGHUP, handler);
+    }
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_current_settings() {
+        let output = "Enabled: Yes\nServer: proxy.corp.example\nPort: 8080\nAuthenticated Proxy Enabled: 0\n";
+        assert_eq!(
+            parse_networksetup(output),
+            MacProxy {
+                enabled: true,
+                server: "proxy.corp.example".to_string(),
+                port: 8080,
+            }
+        );
+        assert_eq!(parse_networksetup("Enabled: No\nServer: \nPort: 0\n").server, "");
+
+        let output = "\r\nHKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings\r\n    ProxyServer    REG_SZ    socks=127.0.0.1:1080\r\n\r\n";
+        assert_eq!(parse_reg_query(output, "ProxyServer").as_deref(), Some("socks=127.0.0.1:1080"));
+        assert_eq!(parse_reg_query(output, "ProxyEnable"), None);
+    }
+}
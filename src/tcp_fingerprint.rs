@@ -0,0 +1,741 @@
+//! TCP/IP stack fingerprint camouflage
+//!
+//! Passive OS fingerprinting classifies a host from its SYN (or SYN-ACK):
+//! initial TTL, MSS, window size and scale, TCP option layout, and IP header
+//! quirks. A [`TcpSignature`] describes one such fingerprint in p0f v3 form
+//! (`ver:ittl:olen:mss:wsize,scale:olayout:quirks:pclass`).
+//!
+//! Two paths are provided:
+//!
+//! - [`apply`] sets what socket options can reach on any Unix kernel: the
+//!   initial TTL / hop limit, the MSS, and optionally the window clamp. The
+//!   option layout and timestamps of kernel-built packets stay the kernel's.
+//!   This is the only path tunnel sockets take.
+//! - On Linux, [`raw::send_syn`] emits a SYN built entirely from the
+//!   signature through a raw socket (requires `CAP_NET_RAW`), for probes and
+//!   for checking how a path classifies a given fingerprint. It is probe-only:
+//!   no connection can be carried over it, and nothing in the tunnel path
+//!   calls it.
+//!
+//! Tunnel SYNs therefore match a signature in TTL, MSS and (with
+//! `clamp_window`) window, but their option order, window scale and
+//! timestamps are still the kernel's. Rewriting them in flight (NFQUEUE or
+//! tc-eBPF) is out of scope.
+//!
+//! [`TcpSignature::observe`] turns a captured SYN back into a signature so
+//! the result of either path can be compared against the target.
+
+use crate::config::{TcpFingerprintConfig, TtlConfig};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// MSS assumed when the signature leaves it open
+const DEFAULT_MSS: u16 = 1460;
+
+/// Window size field of a signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowSize {
+    /// Any window (`*`)
+    Any,
+    /// Exact window
+    Fixed(u16),
+    /// Multiple of the MSS (`mss*N`)
+    Mss(u16),
+    /// Multiple of the MTU (`mtu*N`)
+    Mtu(u16),
+    /// Any multiple of N (`%N`)
+    Modulo(u16),
+}
+
+/// TCP option in a signature's option layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpOption {
+    /// End of options followed by N bytes of padding (`eol+N`)
+    Eol(u8),
+    Nop,
+    Mss,
+    Ws,
+    Sok,
+    Sack,
+    Ts,
+    /// Option of another kind (`?N`)
+    Unknown(u8),
+}
+
+/// A p0f v3 TCP signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpSignature {
+    /// IP version (`None` for either)
+    pub version: Option<u8>,
+    /// Initial TTL / hop limit
+    pub ittl: u8,
+    /// IPv4 options length
+    pub olen: u8,
+    /// Maximum segment size (`None` for any)
+    pub mss: Option<u16>,
+    /// Initial window
+    pub window: WindowSize,
+    /// Window scale (`None` for any)
+    pub scale: Option<u8>,
+    /// TCP option layout
+    pub options: Vec<TcpOption>,
+    /// IP/TCP header quirks (`df`, `id+`, `ts1-`, ...)
+    pub quirks: Vec<String>,
+    /// Payload class (`0`, `+`, or `*`)
+    pub payload_class: String,
+}
+
+impl TcpSignature {
+    /// Built-in signature by name
+    pub fn preset(name: &str) -> Option<Self> {
+        let signature = match name.to_lowercase().as_str() {
+            "windows" => "*:128:0:*:64240,8:mss,nop,ws,nop,nop,sok:df,id+:0",
+            "linux" | "android" => "*:64:0:*:mss*44,7:mss,sok,ts,nop,ws:df,id+:0",
+            "macos" | "ios" => "*:64:0:*:65535,6:mss,nop,ws,nop,nop,ts,sok,eol+1:df,id+:0",
+            "freebsd" => "*:64:0:*:65535,6:mss,nop,ws,sok,ts:df,id+:0",
+            _ => return None,
+        };
+        signature.parse().ok()
+    }
+
+    /// Whether a quirk is part of the signature
+    pub fn has_quirk(&self, quirk: &str) -> bool {
+        self.quirks.iter().any(|q| q == quirk)
+    }
+
+    /// SYN window for a connection using `mss`
+    pub fn window_for(&self, mss: u16) -> u16 {
+        match self.window {
+            WindowSize::Any => u16::MAX,
+            WindowSize::Fixed(window) => window,
+            WindowSize::Mss(n) => mss.saturating_mul(n),
+            WindowSize::Mtu(n) => mss.saturating_add(40).saturating_mul(n),
+            WindowSize::Modulo(n) if n > 0 => u16::MAX - u16::MAX % n,
+            WindowSize::Modulo(_) => u16::MAX,
+        }
+    }
+
+    /// Encode the option layout for a SYN
+    pub fn syn_options(&self, mss: u16, ts_val: u32) -> Vec<u8> {
+        let mut out = Vec::with_capacity(40);
+        for option in &self.options {
+            match option {
+                TcpOption::Eol(padding) => {
+                    out.push(0);
+                    out.extend(std::iter::repeat_n(0, *padding as usize));
+                }
+                TcpOption::Nop => out.push(1),
+                TcpOption::Mss => {
+                    out.extend_from_slice(&[2, 4]);
+                    out.extend_from_slice(&mss.to_be_bytes());
+                }
+                TcpOption::Ws => out.extend_from_slice(&[3, 3, self.scale.unwrap_or(0)]),
+                TcpOption::Sok => out.extend_from_slice(&[4, 2]),
+                // SACK blocks never appear in a SYN
+                TcpOption::Sack => {}
+                TcpOption::Ts => {
+                    out.extend_from_slice(&[8, 10]);
+                    let ts_val = if self.has_quirk("ts1-") { 0 } else { ts_val };
+                    out.extend_from_slice(&ts_val.to_be_bytes());
+                    out.extend_from_slice(&0u32.to_be_bytes());
+                }
+                TcpOption::Unknown(kind) => out.extend_from_slice(&[*kind, 2]),
+            }
+        }
+        while out.len() % 4 != 0 {
+            out.push(0);
+        }
+        out.truncate(40);
+        out
+    }
+
+    /// Build an IP/TCP SYN matching the signature, with valid checksums
+    pub fn build_syn(&self, src: SocketAddr, dst: SocketAddr, seq: u32, ts_val: u32) -> Option<Vec<u8>> {
+        let mss = self.mss.unwrap_or(DEFAULT_MSS);
+        let options = self.syn_options(mss, ts_val);
+        let tcp_len = 20 + options.len();
+
+        let mut tcp = Vec::with_capacity(tcp_len);
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&0u32.to_be_bytes());
+        tcp.extend_from_slice(&[((tcp_len / 4) as u8) << 4, 0x02]);
+        tcp.extend_from_slice(&self.window_for(mss).to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(&options);
+
+        let mut pseudo = Vec::with_capacity(40 + tcp_len);
+        let mut packet = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                let mut ip = vec![0x45, 0];
+                ip.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+                let id = if self.has_quirk("id+") || !self.has_quirk("df") {
+                    rand::random::<u16>().max(1)
+                } else {
+                    0
+                };
+                ip.extend_from_slice(&id.to_be_bytes());
+                ip.extend_from_slice(&[if self.has_quirk("df") { 0x40 } else { 0 }, 0]);
+                ip.extend_from_slice(&[self.ittl, 6, 0, 0]);
+                ip.extend_from_slice(&s.octets());
+                ip.extend_from_slice(&d.octets());
+                let checksum = crate::pcap::internet_checksum(&ip);
+                ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+                pseudo.extend_from_slice(&s.octets());
+                pseudo.extend_from_slice(&d.octets());
+                pseudo.extend_from_slice(&[0, 6]);
+                pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+                ip
+            }
+            (IpAddr::V6(s), IpAddr::V6(d)) => {
+                let mut ip = vec![0x60, 0, 0, 0];
+                ip.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+                ip.extend_from_slice(&[6, self.ittl]);
+                ip.extend_from_slice(&s.octets());
+                ip.extend_from_slice(&d.octets());
+
+                pseudo.extend_from_slice(&s.octets());
+                pseudo.extend_from_slice(&d.octets());
+                pseudo.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, 6]);
+                ip
+            }
+            _ => return None,
+        };
+
+        pseudo.extend_from_slice(&tcp);
+        let checksum = crate::pcap::internet_checksum(&pseudo);
+        tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(&tcp);
+        Some(packet)
+    }
+
+    /// Derive the signature of a raw IP SYN or SYN-ACK packet
+    pub fn observe(packet: &[u8]) -> Option<Self> {
+        let mut quirks = Vec::new();
+        let (version, ttl, olen, tcp) = match packet.first()? >> 4 {
+            4 => {
+                let ihl = ((*packet.first()? & 0x0f) as usize) * 4;
+                if ihl < 20 || packet.len() < ihl || packet[9] != 6 {
+                    return None;
+                }
+                let df = packet[6] & 0x40 != 0;
+                let id = u16::from_be_bytes([packet[4], packet[5]]);
+                if df {
+                    quirks.push("df".to_string());
+                    if id != 0 {
+                        quirks.push("id+".to_string());
+                    }
+                } else if id == 0 {
+                    quirks.push("id-".to_string());
+                }
+                (4, packet[8], (ihl - 20) as u8, &packet[ihl..])
+            }
+            6 => {
+                if packet.len() < 40 || packet[6] != 6 {
+                    return None;
+                }
+                (6, packet[7], 0, &packet[40..])
+            }
+            _ => return None,
+        };
+        if tcp.len() < 20 || tcp[13] & 0x02 == 0 {
+            return None;
+        }
+        let data_offset = ((tcp[12] >> 4) as usize) * 4;
+        if data_offset < 20 || data_offset > tcp.len() {
+            return None;
+        }
+        let window = u16::from_be_bytes([tcp[14], tcp[15]]);
+
+        let mut options = Vec::new();
+        let mut mss = None;
+        let mut scale = None;
+        let raw = &tcp[20..data_offset];
+        let mut i = 0;
+        while i < raw.len() {
+            match raw[i] {
+                0 => {
+                    options.push(TcpOption::Eol((raw.len() - i - 1) as u8));
+                    break;
+                }
+                1 => {
+                    options.push(TcpOption::Nop);
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            let kind = raw[i];
+            let len = *raw.get(i + 1)? as usize;
+            if len < 2 || i + len > raw.len() {
+                return None;
+            }
+            let body = &raw[i + 2..i + len];
+            options.push(match kind {
+                2 if len == 4 => {
+                    mss = Some(u16::from_be_bytes([body[0], body[1]]));
+                    TcpOption::Mss
+                }
+                3 if len == 3 => {
+                    scale = Some(body[0]);
+                    TcpOption::Ws
+                }
+                4 => TcpOption::Sok,
+                5 => TcpOption::Sack,
+                8 if len == 10 => {
+                    if body[..4] == [0, 0, 0, 0] {
+                        quirks.push("ts1-".to_string());
+                    }
+                    TcpOption::Ts
+                }
+                other => TcpOption::Unknown(other),
+            });
+            i += len;
+        }
+
+        Some(Self {
+            version: Some(version),
+            ittl: guess_initial_ttl(ttl),
+            olen,
+            mss,
+            window: WindowSize::Fixed(window),
+            scale,
+            options,
+            quirks,
+            payload_class: if tcp.len() > data_offset { "+" } else { "0" }.to_string(),
+        })
+    }
+
+    /// Whether an observed signature (see [`observe`](Self::observe)) fits this one
+    pub fn matches(&self, observed: &TcpSignature) -> bool {
+        let window_ok = match observed.window {
+            WindowSize::Fixed(window) => match self.window {
+                WindowSize::Any => true,
+                WindowSize::Modulo(n) => n > 0 && window % n == 0,
+                _ => self.window_for(observed.mss.unwrap_or(DEFAULT_MSS)) == window,
+            },
+            other => other == self.window,
+        };
+        let mut quirks = self.quirks.clone();
+        let mut observed_quirks = observed.quirks.clone();
+        quirks.sort();
+        observed_quirks.sort();
+
+        self.version.is_none_or(|v| observed.version == Some(v))
+            && self.ittl == observed.ittl
+            && self.olen == observed.olen
+            && self.mss.is_none_or(|m| observed.mss == Some(m))
+            && window_ok
+            && self.scale.is_none_or(|s| observed.scale == Some(s))
+            && self.options == observed.options
+            && quirks == observed_quirks
+    }
+}
+
+/// Round an observed TTL up to the nearest common initial TTL
+fn guess_initial_ttl(ttl: u8) -> u8 {
+    [32, 64, 128, 255].into_iter().find(|&initial| ttl <= initial).unwrap_or(255)
+}
+
+impl FromStr for TcpSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(preset) = Self::preset(s) {
+            return Ok(preset);
+        }
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        if fields.len() != 8 {
+            return Err(format!("Invalid TCP signature '{}': expected a preset or 8 p0f fields", s));
+        }
+        let number = |field: &str, what: &str| -> Result<u16, String> {
+            field
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid {} '{}' in TCP signature", what, field))
+        };
+        let any_or = |field: &str, what: &str| -> Result<Option<u16>, String> {
+            if field == "*" {
+                Ok(None)
+            } else {
+                number(field, what).map(Some)
+            }
+        };
+        let byte = |value: u16, what: &str| -> Result<u8, String> {
+            u8::try_from(value).map_err(|_| format!("{} {} out of range in TCP signature", what, value))
+        };
+
+        let version = any_or(fields[0], "IP version")?.map(|v| byte(v, "IP version")).transpose()?;
+        // p0f marks guessed TTLs as "64-" and distances as "64+3"
+        let ittl_digits: String = fields[1].chars().take_while(|c| c.is_ascii_digit()).collect();
+        let ittl = byte(number(&ittl_digits, "initial TTL")?, "Initial TTL")?;
+        let olen = byte(number(fields[2], "options length")?, "Options length")?;
+        let mss = any_or(fields[3], "MSS")?;
+
+        let (wsize, wscale) = fields[4]
+            .split_once(',')
+            .ok_or_else(|| format!("Invalid window '{}' in TCP signature", fields[4]))?;
+        let window = if wsize == "*" {
+            WindowSize::Any
+        } else if let Some(n) = wsize.strip_prefix("mss*") {
+            WindowSize::Mss(number(n, "window multiplier")?)
+        } else if let Some(n) = wsize.strip_prefix("mtu*") {
+            WindowSize::Mtu(number(n, "window multiplier")?)
+        } else if let Some(n) = wsize.strip_prefix('%') {
+            WindowSize::Modulo(number(n, "window modulus")?)
+        } else {
+            WindowSize::Fixed(number(wsize, "window")?)
+        };
+        let scale = any_or(wscale, "window scale")?.map(|s| byte(s, "Window scale")).transpose()?;
+
+        let mut options = Vec::new();
+        for option in fields[5].split(',').filter(|o| !o.is_empty()) {
+            options.push(match option {
+                "nop" => TcpOption::Nop,
+                "mss" => TcpOption::Mss,
+                "ws" => TcpOption::Ws,
+                "sok" => TcpOption::Sok,
+                "sack" => TcpOption::Sack,
+                "ts" => TcpOption::Ts,
+                _ => {
+                    if let Some(n) = option.strip_prefix("eol+") {
+                        TcpOption::Eol(byte(number(n, "EOL padding")?, "EOL padding")?)
+                    } else if let Some(n) = option.strip_prefix('?') {
+                        TcpOption::Unknown(byte(number(n, "option kind")?, "Option kind")?)
+                    } else {
+                        return Err(format!("Unknown TCP option '{}' in signature", option));
+                    }
+                }
+            });
+        }
+
+        Ok(Self {
+            version,
+            ittl,
+            olen,
+            mss,
+            window,
+            scale,
+            options,
+            quirks: fields[6].split(',').filter(|q| !q.is_empty()).map(str::to_string).collect(),
+            payload_class: fields[7].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for TcpSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let any = |value: Option<String>| value.unwrap_or_else(|| "*".to_string());
+        let window = match self.window {
+            WindowSize::Any => "*".to_string(),
+            WindowSize::Fixed(n) => n.to_string(),
+            WindowSize::Mss(n) => format!("mss*{}", n),
+            WindowSize::Mtu(n) => format!("mtu*{}", n),
+            WindowSize::Modulo(n) => format!("%{}", n),
+        };
+        let options: Vec<String> = self
+            .options
+            .iter()
+            .map(|option| match option {
+                TcpOption::Eol(n) => format!("eol+{}", n),
+                TcpOption::Nop => "nop".to_string(),
+                TcpOption::Mss => "mss".to_string(),
+                TcpOption::Ws => "ws".to_string(),
+                TcpOption::Sok => "sok".to_string(),
+                TcpOption::Sack => "sack".to_string(),
+                TcpOption::Ts => "ts".to_string(),
+                TcpOption::Unknown(kind) => format!("?{}", kind),
+            })
+            .collect();
+        write!(
+            f,
+            "{}:{}:{}:{}:{},{}:{}:{}:{}",
+            any(self.version.map(|v| v.to_string())),
+            self.ittl,
+            self.olen,
+            any(self.mss.map(|m| m.to_string())),
+            window,
+            any(self.scale.map(|s| s.to_string())),
+            options.join(","),
+            self.quirks.join(","),
+            self.payload_class
+        )
+    }
+}
+
+/// Apply the configured fingerprint to a socket, listener, or stream
+///
+/// Sets the initial TTL (hop limit on IPv6), the MSS if the signature fixes
+/// one, and with `clamp_window` the receive window clamp (Linux).
+#[cfg(unix)]
+pub fn apply(socket: &impl std::os::unix::io::AsRawFd, config: &TcpFingerprintConfig) -> io::Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let signature: TcpSignature = config
+        .signature
+        .parse()
+        .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let fd = socket.as_raw_fd();
+
+    set_ttl(fd, signature.ittl)?;
+    if let Some(mss) = signature.mss {
+        set_option(fd, libc::IPPROTO_TCP, libc::TCP_MAXSEG, mss as libc::c_int)?;
+    }
+    if config.clamp_window {
+        let window = signature.window_for(signature.mss.unwrap_or(DEFAULT_MSS));
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        set_option(fd, libc::IPPROTO_TCP, libc::TCP_WINDOW_CLAMP, window as libc::c_int)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        log::warn!("TCP window clamp ({}) is only supported on Linux", window);
+    }
+    Ok(())
+}
+
+/// Apply the configured fingerprint to a socket, listener, or stream
+#[cfg(not(unix))]
+pub fn apply<T>(_socket: &T, config: &TcpFingerprintConfig) -> io::Result<()> {
+    if config.enabled {
+        log::warn!("TCP fingerprint camouflage is only supported on Unix; ignoring");
+    }
+    Ok(())
+}
+
+/// TTL for a new tunnel connection, or `None` when TTL control is off
+///
+/// The base TTL is lowered by a random amount up to `variation`, never
+/// below 1.
+pub fn connection_ttl(config: &TtlConfig, fingerprint: &TcpFingerprintConfig) -> Option<u8> {
+    if !config.enabled {
+        return None;
+    }
+    let base = config.ttl.unwrap_or_else(|| {
+        fingerprint
+            .enabled
+            .then(|| fingerprint.signature.parse::<TcpSignature>().ok())
+            .flatten()
+            .map(|signature| signature.ittl)
+            .unwrap_or(64)
+    });
+    let reduction = rand::Rng::gen_range(&mut rand::thread_rng(), 0..=config.variation);
+    Some(base.saturating_sub(reduction).max(1))
+}
+
+/// Apply per-connection TTL control to a socket or stream
+#[cfg(unix)]
+pub fn apply_ttl(
+    socket: &impl std::os::unix::io::AsRawFd,
+    config: &TtlConfig,
+    fingerprint: &TcpFingerprintConfig,
+) -> io::Result<()> {
+    match connection_ttl(config, fingerprint) {
+        Some(ttl) => set_ttl(socket.as_raw_fd(), ttl),
+        None => Ok(()),
+    }
+}
+
+/// Apply per-connection TTL control to a socket or stream
+#[cfg(not(unix))]
+pub fn apply_ttl<T>(_socket: &T, config: &TtlConfig, _fingerprint: &TcpFingerprintConfig) -> io::Result<()> {
+    if config.enabled {
+        log::warn!("TTL control is only supported on Unix; ignoring");
+    }
+    Ok(())
+}
+
+/// Set the TTL (hop limit on IPv6) of a socket
+#[cfg(unix)]
+fn set_ttl(fd: std::os::unix::io::RawFd, ttl: u8) -> io::Result<()> {
+    if is_ipv6(fd)? {
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, ttl as libc::c_int)
+    } else {
+        set_option(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+    }
+}
+
+#[cfg(unix)]
+fn is_ipv6(fd: std::os::unix::io::RawFd) -> io::Result<bool> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+    let ret = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.ss_family as libc::c_int == libc::AF_INET6)
+}
+
+#[cfg(unix)]
+fn set_option(
+    fd: std::os::unix::io::RawFd,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            option,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Raw-socket SYN sender (Linux, requires `CAP_NET_RAW`)
+#[cfg(target_os = "linux")]
+pub mod raw {
+    use super::TcpSignature;
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+
+    /// Send a SYN built from `signature`, returning the sequence number used
+    ///
+    /// The kernel does not know about the connection, so it answers the
+    /// SYN-ACK with a RST; this is meant for probes, and tunnel connections
+    /// never use it (see the module docs). IPv4 only.
+    pub fn send_syn(signature: &TcpSignature, src: SocketAddr, dst: SocketAddr) -> io::Result<u32> {
+        let (IpAddr::V4(_), IpAddr::V4(dst_ip)) = (src.ip(), dst.ip()) else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "raw SYNs are only supported over IPv4"));
+        };
+        let seq = rand::random::<u32>();
+        let ts_val = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u32)
+            .unwrap_or(1);
+        let packet = signature
+            .build_syn(src, dst, seq, ts_val)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cannot build SYN"))?;
+
+        // IPPROTO_RAW implies IP_HDRINCL: the packet carries its own IP header
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_RAW) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_addr.s_addr = u32::from_ne_bytes(dst_ip.octets());
+        let ret = unsafe {
+            libc::sendto(
+                fd,
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                std::mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        let result = if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(seq) };
+        unsafe { libc::close(fd) };
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_parsing() {
+        for name in ["windows", "linux", "macos", "freebsd"] {
+            let signature = TcpSignature::preset(name).unwrap();
+            let text = signature.to_string();
+            assert_eq!(text.parse::<TcpSignature>().unwrap(), signature, "{}", name);
+        }
+
+        let windows: TcpSignature = "windows".parse().unwrap();
+        assert_eq!(windows.ittl, 128);
+        assert_eq!(windows.window, WindowSize::Fixed(64240));
+        assert_eq!(windows.scale, Some(8));
+
+        let p0f: TcpSignature = "4:64-:0:1460:mss*20,10:mss,sok,ts,nop,ws:df,id+:0".parse().unwrap();
+        assert_eq!(p0f.version, Some(4));
+        assert_eq!(p0f.ittl, 64);
+        assert_eq!(p0f.window_for(1460), 29200);
+
+        assert!("solaris".parse::<TcpSignature>().is_err());
+        assert!("*:64:0:*:mss*20,10:mss,bogus:df:0".parse::<TcpSignature>().is_err());
+    }
+
+    #[test]
+    fn test_build_and_observe_syn() {
+        let src: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        for name in ["windows", "linux", "macos"] {
+            let signature = TcpSignature::preset(name).unwrap();
+            let packet = signature.build_syn(src, dst, 12345, 1000).unwrap();
+
+            // IP header and TCP checksums verify to zero
+            assert_eq!(crate::pcap::internet_checksum(&packet[..20]), 0);
+            let mut pseudo = packet[12..20].to_vec();
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&((packet.len() - 20) as u16).to_be_bytes());
+            pseudo.extend_from_slice(&packet[20..]);
+            assert_eq!(crate::pcap::internet_checksum(&pseudo), 0);
+
+            let observed = TcpSignature::observe(&packet).unwrap();
+            assert!(signature.matches(&observed), "{}: {}", name, observed);
+            assert_eq!(observed.mss, Some(DEFAULT_MSS));
+        }
+
+        let windows = TcpSignature::preset("windows").unwrap();
+        let linux_syn = TcpSignature::preset("linux").unwrap().build_syn(src, dst, 1, 1).unwrap();
+        assert!(!windows.matches(&TcpSignature::observe(&linux_syn).unwrap()));
+    }
+
+    #[test]
+    fn test_connection_ttl() {
+        let mut config = TtlConfig::default();
+        let mut fingerprint = TcpFingerprintConfig::default();
+        assert_eq!(connection_ttl(&config, &fingerprint), None);
+
+        config.enabled = true;
+        assert_eq!(connection_ttl(&config, &fingerprint), Some(64));
+        fingerprint.enabled = true;
+        assert_eq!(connection_ttl(&config, &fingerprint), Some(128));
+
+        config.ttl = Some(3);
+        config.variation = 5;
+        for _ in 0..50 {
+            let ttl = connection_ttl(&config, &fingerprint).unwrap();
+            assert!((1..=3).contains(&ttl));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply_sets_ttl() {
+        use std::os::unix::io::AsRawFd;
+
+        let config = TcpFingerprintConfig {
+            enabled: true,
+            signature: "windows".to_string(),
+            clamp_window: false,
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        apply(&listener, &config).unwrap();
+
+        let mut ttl: libc::c_int = 0;
+        let mut len = std::mem::size_of_val(&ttl) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                listener.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_TTL,
+                &mut ttl as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(ttl, 128);
+    }
+}
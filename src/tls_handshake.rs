@@ -0,0 +1,448 @@
+//! Noise handshake carried inside TLS 1.3 hello messages
+//!
+//! With [`TlsHandshakeConfig`](crate::noise_transport::TlsHandshakeConfig)
+//! set, the handshake flights are not wrapped protocol frames but a TLS 1.3
+//! exchange that parses field by field, so validators that check the
+//! handshake itself see nothing unusual:
+//!
+//! ```text
+//! ClientHello                  random, session ID and both key shares carry message 1
+//!        ServerHello           random and key share carry message 2,
+//!        ChangeCipherSpec      the rest of it goes in the "encrypted"
+//!        ApplicationData       extensions and certificate
+//! ChangeCipherSpec
+//! ApplicationData              the "Finished" record carries message 3, if any
+//! ```
+//!
+//! The first 32 bytes of the first two messages, the ephemeral key, sit in
+//! an X25519 key share, where a real key belongs. The rest follows as
+//! `length (2, BE) | bytes | random fill` through the X25519MLKEM768 share
+//! and the other random-looking fields, all of which are uniformly random
+//! in real TLS too when the ephemeral keys are Elligator-encoded.
+
+use anyhow::{anyhow, Result};
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const RECORD_APPLICATION_DATA: u8 = 0x17;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+
+const EXT_KEY_SHARE: u16 = 0x0033;
+
+/// Hybrid post-quantum key exchange browsers offer first
+const GROUP_X25519_MLKEM768: u16 = 0x11ec;
+const GROUP_X25519: u16 = 0x001d;
+
+/// ML-KEM-768 encapsulation key followed by an X25519 share
+const CLIENT_HYBRID_SHARE: usize = 1184 + 32;
+/// ML-KEM-768 ciphertext followed by an X25519 share
+const SERVER_HYBRID_SHARE: usize = 1088 + 32;
+
+const X25519_SHARE: usize = 32;
+
+/// Size of a TLS 1.3 Finished record body
+const FINISHED_SIZE: usize = 53;
+
+/// Largest record accepted
+const MAX_RECORD: usize = 16384 + 256;
+
+/// Cipher suites offered, browser order
+const CIPHER_SUITES: &[u16] = &[
+    0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d,
+    0x002f, 0x0035,
+];
+
+const SIGNATURE_ALGORITHMS: &[u16] = &[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601];
+
+/// ClientHello carrying `message`, and the session ID the server must echo
+pub fn client_hello(server_name: &str, message: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, rest) = split_key(message)?;
+    let mut carrier = Carrier::new(rest, CLIENT_HYBRID_SHARE + 32 + 32)?;
+    let mut hybrid = carrier.take(CLIENT_HYBRID_SHARE - X25519_SHARE);
+    // The X25519 half of the hybrid share holds a fresh random key
+    hybrid.extend_from_slice(&random_bytes(X25519_SHARE));
+    let session_id = carrier.take(32);
+    let random = carrier.take(32);
+
+    let mut body = Vec::with_capacity(2048);
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&random);
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(&session_id);
+    put_u16(&mut body, (CIPHER_SUITES.len() * 2) as u16);
+    for suite in CIPHER_SUITES {
+        put_u16(&mut body, *suite);
+    }
+    body.extend_from_slice(&[0x01, 0x00]);
+
+    let mut extensions = Vec::new();
+    let host = server_name.as_bytes();
+    let mut sni = Vec::new();
+    put_u16(&mut sni, host.len() as u16 + 3);
+    sni.push(0x00);
+    put_u16(&mut sni, host.len() as u16);
+    sni.extend_from_slice(host);
+    put_extension(&mut extensions, 0x0000, &sni);
+    put_extension(&mut extensions, 0x0017, &[]);
+    put_extension(&mut extensions, 0xff01, &[0x00]);
+    put_extension(&mut extensions, 0x000a, &[0x00, 0x08, 0x11, 0xec, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18]);
+    put_extension(&mut extensions, 0x000b, &[0x01, 0x00]);
+    put_extension(&mut extensions, 0x0023, &[]);
+    put_extension(&mut extensions, 0x0010, b"\x00\x0c\x02h2\x08http/1.1");
+    put_extension(&mut extensions, 0x0005, &[0x01, 0x00, 0x00, 0x00, 0x00]);
+    let mut algorithms = Vec::new();
+    put_u16(&mut algorithms, (SIGNATURE_ALGORITHMS.len() * 2) as u16);
+    for algorithm in SIGNATURE_ALGORITHMS {
+        put_u16(&mut algorithms, *algorithm);
+    }
+    put_extension(&mut extensions, 0x000d, &algorithms);
+    put_extension(&mut extensions, 0x0012, &[]);
+    let mut shares = Vec::new();
+    put_u16(&mut shares, (4 + CLIENT_HYBRID_SHARE + 4 + X25519_SHARE) as u16);
+    put_u16(&mut shares, GROUP_X25519_MLKEM768);
+    put_u16(&mut shares, CLIENT_HYBRID_SHARE as u16);
+    shares.extend_from_slice(&hybrid);
+    put_u16(&mut shares, GROUP_X25519);
+    put_u16(&mut shares, X25519_SHARE as u16);
+    shares.extend_from_slice(key);
+    put_extension(&mut extensions, EXT_KEY_SHARE, &shares);
+    put_extension(&mut extensions, 0x002d, &[0x01, 0x01]);
+    put_extension(&mut extensions, 0x002b, &[0x04, 0x03, 0x04, 0x03, 0x03]);
+    put_extension(&mut extensions, 0x001b, &[0x02, 0x00, 0x02]);
+    put_u16(&mut body, extensions.len() as u16);
+    body.extend_from_slice(&extensions);
+
+    Ok((record(RECORD_HANDSHAKE, [0x03, 0x01], &handshake(HANDSHAKE_CLIENT_HELLO, &body)), session_id))
+}
+
+/// Read a ClientHello; returns the message it carries and its session ID
+pub async fn read_client_hello<S>(stream: &mut S) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let body = read_handshake(stream, HANDSHAKE_CLIENT_HELLO).await?;
+    let mut reader = Reader::new(&body);
+    reader.take(2)?;
+    let random = reader.take(32)?;
+    let session_id = reader.take_u8_prefixed()?;
+    reader.take_u16_prefixed()?;
+    reader.take_u8_prefixed()?;
+    let extensions = find_extension(reader.take_u16_prefixed()?, EXT_KEY_SHARE)?;
+
+    let mut shares = Reader::new(Reader::new(extensions).take_u16_prefixed()?);
+    let (mut hybrid, mut key) = (None, None);
+    while !shares.is_empty() {
+        let group = shares.take_u16()?;
+        let share = shares.take_u16_prefixed()?;
+        match group {
+            GROUP_X25519_MLKEM768 if share.len() == CLIENT_HYBRID_SHARE => hybrid = Some(share),
+            GROUP_X25519 if share.len() == X25519_SHARE => key = Some(share),
+            _ => {}
+        }
+    }
+    let (Some(hybrid), Some(key)) = (hybrid, key) else {
+        return Err(anyhow!("ClientHello lacks the expected key shares"));
+    };
+    if session_id.len() != 32 {
+        return Err(anyhow!("ClientHello session ID has {} bytes", session_id.len()));
+    }
+
+    let carried = [&hybrid[..CLIENT_HYBRID_SHARE - X25519_SHARE], session_id, random].concat();
+    Ok((join_key(key, &carried)?, session_id.to_vec()))
+}
+
+/// ServerHello, ChangeCipherSpec and encrypted-looking handshake records
+/// carrying `message`
+pub fn server_flight(session_id: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let (key, rest) = split_key(message)?;
+    // What follows the ServerHello is as long as a certificate chain
+    let mut rng = rand::thread_rng();
+    let needed = (2 + rest.len()).saturating_sub(SERVER_HYBRID_SHARE - X25519_SHARE + 32);
+    let encrypted_len = rng.gen_range(2500..4000).max(needed);
+    let mut carrier = Carrier::new(rest, SERVER_HYBRID_SHARE - X25519_SHARE + 32 + encrypted_len)?;
+    let mut hybrid = carrier.take(SERVER_HYBRID_SHARE - X25519_SHARE);
+    hybrid.extend_from_slice(key);
+    let random = carrier.take(32);
+    let encrypted = carrier.take(encrypted_len);
+
+    let mut body = Vec::with_capacity(1280);
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&random);
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    body.extend_from_slice(&[0x13, 0x01, 0x00]);
+    let mut extensions = Vec::new();
+    put_extension(&mut extensions, 0x002b, &[0x03, 0x04]);
+    let mut share = Vec::new();
+    put_u16(&mut share, GROUP_X25519_MLKEM768);
+    put_u16(&mut share, SERVER_HYBRID_SHARE as u16);
+    share.extend_from_slice(&hybrid);
+    put_extension(&mut extensions, EXT_KEY_SHARE, &share);
+    put_u16(&mut body, extensions.len() as u16);
+    body.extend_from_slice(&extensions);
+
+    let mut flight = record(RECORD_HANDSHAKE, [0x03, 0x03], &handshake(HANDSHAKE_SERVER_HELLO, &body));
+    flight.extend_from_slice(&record(RECORD_CHANGE_CIPHER_SPEC, [0x03, 0x03], &[0x01]));
+    flight.extend_from_slice(&record(RECORD_APPLICATION_DATA, [0x03, 0x03], &encrypted));
+    Ok(flight)
+}
+
+/// Read the server's flight, checking it echoes `session_id`; returns the
+/// message it carries
+pub async fn read_server_flight<S>(stream: &mut S, session_id: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let body = read_handshake(stream, HANDSHAKE_SERVER_HELLO).await?;
+    let mut reader = Reader::new(&body);
+    reader.take(2)?;
+    let random = reader.take(32)?;
+    if reader.take_u8_prefixed()? != session_id {
+        return Err(anyhow!("ServerHello does not echo the session ID"));
+    }
+    reader.take(3)?;
+    let extensions = find_extension(reader.take_u16_prefixed()?, EXT_KEY_SHARE)?;
+    let mut share = Reader::new(extensions);
+    if share.take_u16()? != GROUP_X25519_MLKEM768 {
+        return Err(anyhow!("ServerHello picked an unexpected group"));
+    }
+    let hybrid = share.take_u16_prefixed()?;
+    if hybrid.len() != SERVER_HYBRID_SHARE {
+        return Err(anyhow!("ServerHello key share has {} bytes", hybrid.len()));
+    }
+
+    read_record(stream, RECORD_CHANGE_CIPHER_SPEC).await?;
+    let encrypted = read_record(stream, RECORD_APPLICATION_DATA).await?;
+    let (ciphertext, key) = hybrid.split_at(SERVER_HYBRID_SHARE - X25519_SHARE);
+    join_key(key, &[ciphertext, random, &encrypted].concat())
+}
+
+/// ChangeCipherSpec and a Finished record carrying `message`, which may be
+/// empty
+pub fn client_finished(message: &[u8]) -> Result<Vec<u8>> {
+    let mut carrier = Carrier::new(message, FINISHED_SIZE.max(2 + message.len()))?;
+    let finished = carrier.take(FINISHED_SIZE.max(2 + message.len()));
+    let mut flight = record(RECORD_CHANGE_CIPHER_SPEC, [0x03, 0x03], &[0x01]);
+    flight.extend_from_slice(&record(RECORD_APPLICATION_DATA, [0x03, 0x03], &finished));
+    Ok(flight)
+}
+
+/// Read the client's closing flight; returns the message it carries
+pub async fn read_client_finished<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    read_record(stream, RECORD_CHANGE_CIPHER_SPEC).await?;
+    let finished = read_record(stream, RECORD_APPLICATION_DATA).await?;
+    unframe(&finished)
+}
+
+/// Ephemeral key and the rest of a message
+fn split_key(message: &[u8]) -> Result<(&[u8], &[u8])> {
+    if message.len() < X25519_SHARE {
+        return Err(anyhow!("Handshake message of {} bytes has no ephemeral key", message.len()));
+    }
+    Ok(message.split_at(X25519_SHARE))
+}
+
+fn join_key(key: &[u8], carried: &[u8]) -> Result<Vec<u8>> {
+    Ok([key, &unframe(carried)?].concat())
+}
+
+/// `length | bytes | random fill`, handed out field by field
+struct Carrier {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl Carrier {
+    fn new(message: &[u8], capacity: usize) -> Result<Self> {
+        if 2 + message.len() > capacity {
+            return Err(anyhow!("Handshake message of {} bytes does not fit in TLS hellos", message.len()));
+        }
+        let mut bytes = Vec::with_capacity(capacity);
+        bytes.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(message);
+        bytes.extend_from_slice(&random_bytes(capacity - bytes.len()));
+        Ok(Self { bytes, pos: 0 })
+    }
+
+    fn take(&mut self, n: usize) -> Vec<u8> {
+        let field = self.bytes[self.pos..self.pos + n].to_vec();
+        self.pos += n;
+        field
+    }
+}
+
+/// Message in carried bytes
+fn unframe(carried: &[u8]) -> Result<Vec<u8>> {
+    let len = match carried {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]) as usize,
+        _ => return Err(anyhow!("Carried handshake message is truncated")),
+    };
+    carried
+        .get(2..2 + len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| anyhow!("Carried handshake message is truncated"))
+}
+
+fn random_bytes(n: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; n];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_extension(buf: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    put_u16(buf, ext_type);
+    put_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+
+fn handshake(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(4 + body.len());
+    message.push(msg_type);
+    message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    message.extend_from_slice(body);
+    message
+}
+
+fn record(content_type: u8, version: [u8; 2], body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(5 + body.len());
+    record.push(content_type);
+    record.extend_from_slice(&version);
+    put_u16(&mut record, body.len() as u16);
+    record.extend_from_slice(body);
+    record
+}
+
+async fn read_record<S>(stream: &mut S, content_type: u8) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 5];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| anyhow!("Connection closed during TLS handshake: {}", e))?;
+    if header[0] != content_type {
+        return Err(anyhow!("Expected TLS record type {:#04x}, got {:#04x}", content_type, header[0]));
+    }
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_RECORD {
+        return Err(anyhow!("TLS record too large: {}", len));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Body of a handshake message of `msg_type` filling one record
+async fn read_handshake<S>(stream: &mut S, msg_type: u8) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let record = read_record(stream, RECORD_HANDSHAKE).await?;
+    let mut reader = Reader::new(&record);
+    if reader.take(1)?[0] != msg_type {
+        return Err(anyhow!("Expected TLS handshake message {}", msg_type));
+    }
+    let len = reader.take(3)?;
+    let len = u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize;
+    Ok(reader.take(len)?.to_vec())
+}
+
+/// Extension `ext_type` in an extensions block
+fn find_extension(mut extensions: &[u8], ext_type: u16) -> Result<&[u8]> {
+    while !extensions.is_empty() {
+        let mut reader = Reader::new(extensions);
+        let found = reader.take_u16()?;
+        let data = reader.take_u16_prefixed()?;
+        if found == ext_type {
+            return Ok(data);
+        }
+        extensions = reader.rest();
+    }
+    Err(anyhow!("TLS hello lacks extension {:#06x}", ext_type))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.data.len() {
+            return Err(anyhow!("Truncated TLS hello"));
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn take_u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn take_u8_prefixed(&mut self) -> Result<&'a [u8]> {
+        let len = self.take(1)?[0] as usize;
+        self.take(len)
+    }
+
+    fn take_u16_prefixed(&mut self) -> Result<&'a [u8]> {
+        let len = self.take_u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hellos_carry_messages() {
+        let message: Vec<u8> = (0..1248u32).map(|i| i as u8).collect();
+        let (hello, session_id) = client_hello("www.example.com", &message).unwrap();
+
+        // The ClientHello parses as one, with the ephemeral key as its X25519 share
+        let fingerprint = crate::analysis::parse_client_hello(&hello).unwrap();
+        assert_eq!(fingerprint.sni.as_deref(), Some("www.example.com"));
+        assert_eq!(fingerprint.groups, vec![GROUP_X25519_MLKEM768, GROUP_X25519, 0x0017, 0x0018]);
+        assert_eq!(fingerprint.alpn, vec!["h2", "http/1.1"]);
+        assert!(hello.windows(32).any(|window| window == &message[..32]));
+
+        let (carried, echoed) = read_client_hello(&mut &hello[..]).await.unwrap();
+        assert_eq!(carried, message);
+        assert_eq!(echoed, session_id);
+
+        let reply = &message[..96];
+        let flight = server_flight(&session_id, reply).unwrap();
+        assert_eq!(read_server_flight(&mut &flight[..], &session_id).await.unwrap(), reply);
+        assert!(read_server_flight(&mut &flight[..], &[0u8; 32]).await.is_err());
+
+        let finished = client_finished(&[]).unwrap();
+        assert_eq!(finished.len(), 6 + 5 + FINISHED_SIZE);
+        assert!(read_client_finished(&mut &finished[..]).await.unwrap().is_empty());
+        assert!(client_hello("www.example.com", &[0u8; 16]).is_err());
+    }
+}
@@ -158,6 +158,19 @@ impl TlsRecordLayer {
         let version = &header[1..3];
         let length = u16::from_be_bytes([header[3], header[4]]) as usize;
 
+        // The peer's close_notify ends the session like a FIN would
+        if content_type == TlsContentType::Alert as u8 && length == 2 {
+            let mut alert = [0u8; 2];
+            stream.read_exact(&mut alert).await?;
+            if alert[1] == TlsAlertDescription::CloseNotify as u8 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "TLS close_notify received"));
+            }
+            return Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("TLS alert received: level {} description {}", alert[0], alert[1]),
+            ));
+        }
+
         // Validate content type
         if content_type != TlsContentType::ApplicationData as u8 {
             return Err(Error::new(
@@ -286,4 +299,17 @@ mod tests {
         assert_eq!(alert[5], TlsAlertLevel::Warning as u8);
         assert_eq!(alert[6], TlsAlertDescription::CloseNotify as u8);
     }
+
+    #[tokio::test]
+    async fn test_close_notify_ends_session() {
+        let tls = TlsRecordLayer::new();
+        let mut stream = Vec::new();
+        tls.write_application_data(&mut stream, b"last").await.unwrap();
+        tls.send_close_notify(&mut stream).await.unwrap();
+
+        let mut reader = &stream[..];
+        assert_eq!(tls.read_application_data(&mut reader).await.unwrap(), b"last");
+        let err = tls.read_application_data(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
 }
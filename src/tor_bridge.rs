@@ -0,0 +1,184 @@
+//! Tor bridge integration
+//!
+//! A nooshdaroo server can serve as an obfuscated Tor bridge: with
+//! `[server.tor]` set, tunnels to [`TOR_TARGET`] are connected to the local
+//! Tor relay's OR port instead of a destination, so Tor's traffic to the
+//! bridge carries whichever protocol emulation the tunnel uses.
+//!
+//! ```toml
+//! [server.tor]
+//! or_port = "127.0.0.1:9001"
+//! fingerprint = "4A0C4E2B7F1D3C5E6A8B9D0F1E2C3B4A5D6E7F80"
+//! ```
+//!
+//! On the Tor side, `nooshdaroo tor-pt` is a managed pluggable transport
+//! client (Tor's pt-spec, version 1). `nooshdaroo tor-bridge-line` prints
+//! the lines a Tor user adds to their torrc:
+//!
+//! ```text
+//! UseBridges 1
+//! ClientTransportPlugin nooshdaroo exec /usr/local/bin/nooshdaroo tor-pt
+//! Bridge nooshdaroo 203.0.113.10:443 4A0C...7F80 key=<public key> protocol=https
+//! ```
+//!
+//! Tor hands the bridge's address to the transport as a SOCKS5 destination
+//! and the `key=value` arguments of the bridge line as the SOCKS5 user name
+//! and password; the transport opens a tunnel to the bridge with them.
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use std::collections::HashMap;
+
+/// Name of the transport in bridge lines and torrc
+pub const TRANSPORT_NAME: &str = "nooshdaroo";
+
+/// Target of tunnels carrying Tor's traffic to the bridge's OR port
+pub const TOR_TARGET: &str = "tor-bridge.invalid:0";
+
+/// Check the environment Tor launched the transport in, returning whether
+/// Tor asked for it. The error is the line to report to Tor.
+pub fn client_setup(env: impl Fn(&str) -> Option<String>) -> Result<bool, String> {
+    let versions = env("TOR_PT_MANAGED_TRANSPORT_VER").unwrap_or_default();
+    if !versions.split(',').any(|version| version == "1") {
+        return Err("VERSION-ERROR no-version".to_string());
+    }
+    if env("TOR_PT_PROXY").is_some() {
+        return Err("PROXY-ERROR upstream proxies are not supported".to_string());
+    }
+    let transports = env("TOR_PT_CLIENT_TRANSPORTS").unwrap_or_default();
+    Ok(transports.split(',').any(|name| name == TRANSPORT_NAME || name == "*"))
+}
+
+/// Whether Tor wants the transport to exit when its stdin closes
+pub fn exit_on_stdin_close(env: impl Fn(&str) -> Option<String>) -> bool {
+    env("TOR_PT_EXIT_ON_STDIN_CLOSE").as_deref() == Some("1")
+}
+
+/// Bridge line arguments, as passed in a SOCKS5 user name and password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BridgeArgs {
+    /// The bridge's Noise public key (standard base64)
+    pub public_key: String,
+    pub protocol: String,
+    pub elligator: bool,
+}
+
+impl BridgeArgs {
+    /// Arguments from the user name and password Tor sent; arguments too
+    /// long for the user name continue in the password, which is otherwise
+    /// a single NUL
+    pub fn from_socks(username: &[u8], password: &[u8]) -> Result<Self, String> {
+        let mut raw = username.to_vec();
+        if password != [0] {
+            raw.extend_from_slice(password);
+        }
+        let raw = String::from_utf8(raw).map_err(|_| "bridge arguments are not UTF-8".to_string())?;
+        let args = parse_args(&raw)?;
+        let key = args.get("key").ok_or("bridge line has no key= argument")?;
+        let key = URL_SAFE_NO_PAD
+            .decode(key.trim_end_matches('='))
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or("bridge key must be 32 bytes of URL-safe base64")?;
+        Ok(Self {
+            public_key: STANDARD.encode(key),
+            protocol: args.get("protocol").cloned().unwrap_or_else(|| "https".to_string()),
+            elligator: matches!(args.get("elligator").map(String::as_str), Some("1" | "true")),
+        })
+    }
+}
+
+/// `key=value` pairs separated by `;`, where `\` escapes the next character
+pub fn parse_args(raw: &str) -> Result<HashMap<String, String>, String> {
+    let mut args = HashMap::new();
+    let (mut key, mut value, mut in_value, mut escaped) = (String::new(), String::new(), false, false);
+    for c in raw.chars().chain(std::iter::once(';')) {
+        match c {
+            _ if escaped => {
+                (if in_value { &mut value } else { &mut key }).push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '=' if !in_value => in_value = true,
+            ';' => {
+                if !key.is_empty() || in_value {
+                    if !in_value {
+                        return Err(format!("bridge argument {:?} has no value", key));
+                    }
+                    args.insert(std::mem::take(&mut key), std::mem::take(&mut value));
+                }
+                in_value = false;
+            }
+            _ => (if in_value { &mut value } else { &mut key }).push(c),
+        }
+    }
+    Ok(args)
+}
+
+/// Public key, in URL-safe base64 for a bridge line, of `private_key`
+pub fn bridge_key(private_key: &[u8]) -> Result<String, String> {
+    let private: [u8; 32] = private_key.try_into().map_err(|_| "private key must be 32 bytes".to_string())?;
+    Ok(URL_SAFE_NO_PAD.encode(MontgomeryPoint::mul_base_clamped(private).to_bytes()))
+}
+
+/// The `Bridge` line of a bridge at `address`, whose relay has
+/// `fingerprint` if known
+pub fn bridge_line(address: &str, fingerprint: Option<&str>, key: &str, protocol: &str, elligator: bool) -> String {
+    let mut line = format!("Bridge {} {}", TRANSPORT_NAME, address);
+    if let Some(fingerprint) = fingerprint {
+        line.push(' ');
+        line.push_str(fingerprint);
+    }
+    line.push_str(&format!(" key={} protocol={}", key, protocol));
+    if elligator {
+        line.push_str(" elligator=1");
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_setup() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+        };
+        assert_eq!(
+            client_setup(env(&[("TOR_PT_MANAGED_TRANSPORT_VER", "1"), ("TOR_PT_CLIENT_TRANSPORTS", "obfs4,nooshdaroo")])),
+            Ok(true)
+        );
+        assert_eq!(client_setup(env(&[("TOR_PT_MANAGED_TRANSPORT_VER", "1"), ("TOR_PT_CLIENT_TRANSPORTS", "obfs4")])), Ok(false));
+        assert_eq!(client_setup(env(&[("TOR_PT_MANAGED_TRANSPORT_VER", "2")])), Err("VERSION-ERROR no-version".to_string()));
+        assert!(client_setup(env(&[("TOR_PT_MANAGED_TRANSPORT_VER", "1"), ("TOR_PT_PROXY", "socks5://127.0.0.1:9050")]))
+            .unwrap_err()
+            .starts_with("PROXY-ERROR"));
+        assert!(exit_on_stdin_close(env(&[("TOR_PT_EXIT_ON_STDIN_CLOSE", "1")])));
+        assert!(!exit_on_stdin_close(env(&[])));
+    }
+
+    #[test]
+    fn test_bridge_args() {
+        let key = bridge_key(&[9u8; 32]).unwrap();
+        let line = bridge_line("203.0.113.10:443", Some("4A0C4E2B7F1D3C5E6A8B9D0F1E2C3B4A5D6E7F80"), &key, "https", false);
+        assert_eq!(line, format!("Bridge nooshdaroo 203.0.113.10:443 4A0C4E2B7F1D3C5E6A8B9D0F1E2C3B4A5D6E7F80 key={} protocol=https", key));
+
+        // As Tor passes the arguments of that line
+        let raw = format!("key={};protocol=ssh;elligator=1", key);
+        let args = BridgeArgs::from_socks(raw.as_bytes(), &[0]).unwrap();
+        assert_eq!(STANDARD.decode(&args.public_key).unwrap(), URL_SAFE_NO_PAD.decode(&key).unwrap());
+        assert_eq!(args.protocol, "ssh");
+        assert!(args.elligator);
+        let (head, tail) = raw.split_at(10);
+        assert_eq!(BridgeArgs::from_socks(head.as_bytes(), tail.as_bytes()).unwrap(), args);
+        assert!(BridgeArgs::from_socks(b"protocol=https", &[0]).is_err());
+
+        let escaped = parse_args(r"a=x\;y;b=\\;c=").unwrap();
+        assert_eq!(escaped["a"], "x;y");
+        assert_eq!(escaped["b"], "\\");
+        assert_eq!(escaped["c"], "");
+        assert!(parse_args("novalue").is_err());
+    }
+}
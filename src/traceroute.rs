@@ -3,11 +3,19 @@
 //! This module provides traceroute functionality to discover the network path
 //! to the server. This is optional and can be disabled on mobile platforms
 //! where ICMP permissions may not be available.
+//!
+//! It can also localize censorship middleboxes: [`Traceroute::locate_interference`]
+//! opens a normal TCP connection, lowers the TTL, and sends a protocol-specific
+//! trigger (TLS ClientHello with SNI, HTTP Host header, DNS query). A reset or
+//! injected response at a TTL too small to reach the server reveals the hop
+//! where blocking happens. No raw sockets or elevated privileges are required.
 
 use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, SocketAddr};
 use std::process::Command;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 
 /// A hop in the network path
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -267,6 +275,290 @@ impl Traceroute {
     }
 }
 
+/// What was observed after sending a probe at a given TTL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProbeOutcome {
+    /// Nothing came back before the timeout
+    Timeout,
+    /// The connection was reset
+    Reset,
+    /// The connection was closed (FIN)
+    Closed,
+    /// Response bytes were received
+    Data,
+    /// TCP connection to the target could not be established
+    ConnectFailed,
+}
+
+impl ProbeOutcome {
+    /// Whether anything answered the probe
+    pub fn answered(&self) -> bool {
+        matches!(self, Self::Reset | Self::Closed | Self::Data)
+    }
+}
+
+/// Result of one TTL-limited probe
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TtlProbe {
+    /// TTL used for the trigger payload
+    pub ttl: u8,
+    /// Outcome for the sensitive (possibly blocked) payload
+    pub outcome: ProbeOutcome,
+    /// Outcome for the control payload (benign domain), if probed
+    pub control_outcome: Option<ProbeOutcome>,
+    /// Bytes received in response to the sensitive payload
+    pub response_len: usize,
+}
+
+/// Kind of interference detected on the path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterferenceKind {
+    /// No interference observed
+    None,
+    /// A middlebox injected a TCP reset
+    Reset,
+    /// A middlebox injected a response (e.g. a block page)
+    Injection,
+    /// The server answers the control payload but never the sensitive one
+    Drop,
+}
+
+/// Report locating censorship interference on the path to a target
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterferenceReport {
+    /// Target address
+    pub target: SocketAddr,
+    /// Protocol whose trigger was used
+    pub protocol: String,
+    /// Sensitive domain placed in the trigger
+    pub domain: String,
+    /// Hop count at which the server first answers the control payload
+    pub server_hop: Option<u8>,
+    /// First hop at which interference was observed
+    pub interference_hop: Option<u8>,
+    /// Kind of interference
+    pub interference: InterferenceKind,
+    /// Address of the interfering hop, if known from a traceroute
+    pub interference_address: Option<IpAddr>,
+    /// Per-TTL probe results
+    pub probes: Vec<TtlProbe>,
+    /// Time taken
+    pub duration: Duration,
+}
+
+impl InterferenceReport {
+    /// Whether interference was detected
+    pub fn is_blocked(&self) -> bool {
+        self.interference != InterferenceKind::None
+    }
+
+    /// Lowest tunnel TTL that still reaches the server, with one hop of
+    /// slack for route changes
+    pub fn suggested_ttl(&self) -> Option<u8> {
+        self.server_hop.map(|hop| hop.saturating_add(1))
+    }
+}
+
+/// Build the trigger payload for `protocol` carrying `domain`
+pub fn probe_payload(protocol: &str, domain: &str) -> Vec<u8> {
+    let protocol = protocol.to_lowercase();
+    if protocol.starts_with("https") || protocol.starts_with("tls") || protocol == "doh" || protocol == "http2" {
+        build_client_hello(domain)
+    } else if protocol.starts_with("dns") {
+        build_dns_tcp_query(domain)
+    } else {
+        format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: Mozilla/5.0\r\nAccept: */*\r\n\r\n",
+            domain
+        )
+        .into_bytes()
+    }
+}
+
+/// Minimal TLS 1.3 ClientHello with SNI
+fn build_client_hello(domain: &str) -> Vec<u8> {
+    let name = domain.as_bytes();
+
+    let mut extensions = Vec::new();
+    // server_name
+    extensions.extend_from_slice(&[0x00, 0x00]);
+    extensions.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+    extensions.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+    extensions.push(0x00);
+    extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(name);
+    // supported_groups: x25519
+    extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+    // signature_algorithms: rsa_pss_rsae_sha256, ecdsa_secp256r1_sha256
+    extensions.extend_from_slice(&[0x00, 0x0d, 0x00, 0x06, 0x00, 0x04, 0x08, 0x04, 0x04, 0x03]);
+    // supported_versions: TLS 1.3
+    extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+    // key_share: x25519
+    extensions.extend_from_slice(&[0x00, 0x33, 0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20]);
+    extensions.extend((0..32).map(|_| rand::random::<u8>()));
+
+    let mut hello = Vec::new();
+    hello.extend_from_slice(&[0x03, 0x03]);
+    hello.extend((0..32).map(|_| rand::random::<u8>()));
+    hello.push(32);
+    hello.extend((0..32).map(|_| rand::random::<u8>()));
+    // cipher suites
+    hello.extend_from_slice(&[0x00, 0x06, 0x13, 0x01, 0x13, 0x02, 0x13, 0x03]);
+    // compression methods
+    hello.extend_from_slice(&[0x01, 0x00]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// DNS-over-TCP A query for `domain`
+fn build_dns_tcp_query(domain: &str) -> Vec<u8> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&rand::random::<u16>().to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in domain.split('.').filter(|l| !l.is_empty()) {
+        query.push(label.len().min(63) as u8);
+        query.extend_from_slice(&label.as_bytes()[..label.len().min(63)]);
+    }
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&query);
+    framed
+}
+
+impl Traceroute {
+    /// Locate where on the path to `target` a `protocol` trigger carrying
+    /// `domain` is interfered with.
+    ///
+    /// For each TTL the sensitive payload and a `control_domain` payload are
+    /// sent on fresh connections. The first TTL at which the control payload
+    /// is answered is the server's distance; a sensitive payload answered at a
+    /// smaller TTL was answered by a middlebox on the path.
+    pub async fn locate_interference(
+        &self,
+        target: SocketAddr,
+        protocol: &str,
+        domain: &str,
+        control_domain: &str,
+    ) -> InterferenceReport {
+        let start = std::time::Instant::now();
+        let per_probe = Duration::from_secs(self.config.timeout_secs.max(1) as u64);
+        let sensitive = probe_payload(protocol, domain);
+        let control = probe_payload(protocol, control_domain);
+
+        let mut probes = Vec::new();
+        let mut server_hop = None;
+
+        for ttl in 1..=self.config.max_hops {
+            let (outcome, response_len) = ttl_probe(target, ttl, &sensitive, per_probe).await;
+            let control_outcome = ttl_probe(target, ttl, &control, per_probe).await.0;
+
+            log::debug!("TTL {}: sensitive={:?} control={:?}", ttl, outcome, control_outcome);
+
+            probes.push(TtlProbe {
+                ttl,
+                outcome,
+                control_outcome: Some(control_outcome),
+                response_len,
+            });
+
+            if outcome == ProbeOutcome::ConnectFailed {
+                break;
+            }
+            if control_outcome.answered() {
+                server_hop = Some(ttl);
+                break;
+            }
+        }
+
+        let (interference_hop, interference) = classify_interference(&probes, server_hop);
+
+        // Map the hop number to an address when a system traceroute is available
+        let interference_address = match interference_hop {
+            Some(hop) if self.config.enabled => self
+                .system_traceroute(target)
+                .await
+                .ok()
+                .and_then(|hops| hops.into_iter().find(|h| h.hop_number == hop))
+                .and_then(|h| h.address),
+            _ => None,
+        };
+
+        InterferenceReport {
+            target,
+            protocol: protocol.to_string(),
+            domain: domain.to_string(),
+            server_hop,
+            interference_hop,
+            interference,
+            interference_address,
+            probes,
+            duration: start.elapsed(),
+        }
+    }
+}
+
+/// Decide where interference begins from per-TTL probe outcomes
+fn classify_interference(probes: &[TtlProbe], server_hop: Option<u8>) -> (Option<u8>, InterferenceKind) {
+    // Sensitive payload answered before the server is reachable: injected by a middlebox
+    for probe in probes {
+        if server_hop.is_some_and(|hop| probe.ttl >= hop) {
+            break;
+        }
+        match probe.outcome {
+            ProbeOutcome::Reset | ProbeOutcome::Closed => return (Some(probe.ttl), InterferenceKind::Reset),
+            ProbeOutcome::Data => return (Some(probe.ttl), InterferenceKind::Injection),
+            _ => {}
+        }
+    }
+
+    // Server answers the control but never the sensitive payload: silently dropped
+    if let Some(hop) = server_hop {
+        let sensitive_answered = probes
+            .iter()
+            .find(|p| p.ttl == hop)
+            .map(|p| p.outcome.answered())
+            .unwrap_or(false);
+        if !sensitive_answered {
+            return (None, InterferenceKind::Drop);
+        }
+    }
+
+    (None, InterferenceKind::None)
+}
+
+/// Connect to `target`, lower the TTL to `ttl`, send `payload`, and observe
+async fn ttl_probe(target: SocketAddr, ttl: u8, payload: &[u8], wait: Duration) -> (ProbeOutcome, usize) {
+    let mut stream = match tokio::time::timeout(wait, TcpStream::connect(target)).await {
+        Ok(Ok(stream)) => stream,
+        _ => return (ProbeOutcome::ConnectFailed, 0),
+    };
+
+    if stream.set_ttl(ttl as u32).is_err() || stream.write_all(payload).await.is_err() {
+        return (ProbeOutcome::ConnectFailed, 0);
+    }
+
+    let mut buf = [0u8; 4096];
+    match tokio::time::timeout(wait, stream.read(&mut buf)).await {
+        Err(_) => (ProbeOutcome::Timeout, 0),
+        Ok(Ok(0)) => (ProbeOutcome::Closed, 0),
+        Ok(Ok(n)) => (ProbeOutcome::Data, n),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionReset => (ProbeOutcome::Reset, 0),
+        Ok(Err(_)) => (ProbeOutcome::Timeout, 0),
+    }
+}
+
 /// Check if traceroute is available on the system
 pub fn is_traceroute_available() -> bool {
     #[cfg(target_os = "windows")]
@@ -321,6 +613,53 @@ mod tests {
         assert_eq!(hop.rtts.len(), 3);
     }
 
+    #[test]
+    fn test_probe_payloads() {
+        let hello = probe_payload("https", "example.com");
+        assert_eq!(&hello[..3], &[0x16, 0x03, 0x01]);
+        assert_eq!(u16::from_be_bytes([hello[3], hello[4]]) as usize, hello.len() - 5);
+        assert!(hello.windows(11).any(|w| w == b"example.com"));
+
+        let dns = probe_payload("dns", "example.com");
+        assert_eq!(u16::from_be_bytes([dns[0], dns[1]]) as usize, dns.len() - 2);
+
+        let http = probe_payload("http", "example.com");
+        assert!(http.starts_with(b"GET / HTTP/1.1\r\nHost: example.com"));
+    }
+
+    fn probe(ttl: u8, outcome: ProbeOutcome, control: ProbeOutcome) -> TtlProbe {
+        TtlProbe {
+            ttl,
+            outcome,
+            control_outcome: Some(control),
+            response_len: 0,
+        }
+    }
+
+    #[test]
+    fn test_classify_interference() {
+        use ProbeOutcome::*;
+
+        // RST injected at hop 3, server at hop 6
+        let probes = vec![
+            probe(1, Timeout, Timeout),
+            probe(2, Timeout, Timeout),
+            probe(3, Reset, Timeout),
+            probe(4, Reset, Timeout),
+            probe(5, Reset, Timeout),
+            probe(6, Reset, Data),
+        ];
+        assert_eq!(classify_interference(&probes, Some(6)), (Some(3), InterferenceKind::Reset));
+
+        // Clean path
+        let probes = vec![probe(1, Timeout, Timeout), probe(2, Data, Data)];
+        assert_eq!(classify_interference(&probes, Some(2)), (None, InterferenceKind::None));
+
+        // Sensitive payload silently dropped
+        let probes = vec![probe(1, Timeout, Timeout), probe(2, Timeout, Data)];
+        assert_eq!(classify_interference(&probes, Some(2)), (None, InterferenceKind::Drop));
+    }
+
     #[test]
     fn test_auto_config() {
         let config = auto_config();
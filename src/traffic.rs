@@ -1,11 +1,12 @@
 //! Traffic shaping and timing emulation
 
-use super::config::{DistributionType, TrafficShapingConfig};
+use super::config::{DistributionType, JitterConfig, JitterDistribution, KeepaliveConfig, NooshdarooConfig, TrafficShapingConfig};
+use super::counters::ConnectionCounters;
 use rand::distributions::Distribution;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 use rand_distr::{Exp, Normal, Uniform};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// Traffic shaper for realistic traffic patterns
 pub struct TrafficShaper {
@@ -215,6 +216,343 @@ impl TimingEmulator {
     }
 }
 
+/// Paces tunnel writes so their spacing follows the cover protocol's timing
+pub struct JitterLayer {
+    config: JitterConfig,
+    distribution: Option<JitterDistribution>,
+    rng: StdRng,
+    last_write: Option<Instant>,
+}
+
+impl JitterLayer {
+    /// Create jitter layer for `protocol`
+    pub fn new(config: &JitterConfig, protocol: &str) -> Self {
+        let mut layer = Self {
+            config: config.clone(),
+            distribution: None,
+            rng: StdRng::from_entropy(),
+            last_write: None,
+        };
+        layer.set_protocol(protocol);
+        layer
+    }
+
+    /// Switch to another protocol's timing (after rotation)
+    pub fn set_protocol(&mut self, protocol: &str) {
+        let family = protocol.split(['-', '_']).next().unwrap_or(protocol);
+        self.distribution = if self.config.enabled {
+            self.config
+                .protocols
+                .get(protocol)
+                .or_else(|| self.config.protocols.get(family))
+                .copied()
+                .or_else(|| Self::default_distribution(family))
+        } else {
+            None
+        };
+    }
+
+    /// Whether writes are being paced
+    pub fn is_active(&self) -> bool {
+        self.distribution.is_some()
+    }
+
+    /// Built-in gap distributions for common cover protocols (microseconds)
+    pub fn default_distribution(family: &str) -> Option<JitterDistribution> {
+        let (distribution, mean_delay, stddev_delay, max_delay) = match family {
+            // Stub resolvers fire queries in short, irregular bursts
+            "dns" => (DistributionType::Exponential, 20_000, 0, 150_000),
+            // Keystroke-like spacing, compressed so bulk copies stay usable
+            "ssh" => (DistributionType::Normal, 30_000, 20_000, 150_000),
+            // Browsers pipeline requests a few milliseconds apart
+            "https" | "http" | "tls" => (DistributionType::Normal, 3_000, 2_000, 20_000),
+            "quic" => (DistributionType::Exponential, 1_000, 0, 10_000),
+            _ => return None,
+        };
+        Some(JitterDistribution {
+            distribution,
+            mean_delay,
+            stddev_delay,
+            max_delay,
+        })
+    }
+
+    /// Sample the gap before the next write
+    pub fn sample_gap(&mut self) -> Duration {
+        let Some(d) = self.distribution else {
+            return Duration::ZERO;
+        };
+        if d.mean_delay == 0 {
+            return Duration::ZERO;
+        }
+
+        let mean = d.mean_delay as f64;
+        let gap = match d.distribution {
+            DistributionType::Normal => Normal::new(mean, d.stddev_delay as f64)
+                .map(|n| n.sample(&mut self.rng))
+                .unwrap_or(mean),
+            DistributionType::Uniform if d.stddev_delay > 0 => {
+                let low = d.mean_delay.saturating_sub(d.stddev_delay);
+                Uniform::new(low, d.mean_delay + d.stddev_delay).sample(&mut self.rng) as f64
+            }
+            DistributionType::Uniform => mean,
+            DistributionType::Exponential => Exp::new(1.0 / mean)
+                .map(|e| e.sample(&mut self.rng))
+                .unwrap_or(mean),
+        };
+        Duration::from_micros(gap.clamp(0.0, d.max_delay as f64) as u64)
+    }
+
+    /// Wait until the sampled gap since the previous write has passed
+    pub async fn pace(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+        let gap = self.sample_gap();
+        if let Some(elapsed) = self.last_write.map(|t| t.elapsed()) {
+            if elapsed < gap {
+                tokio::time::sleep(gap - elapsed).await;
+            }
+        }
+        self.last_write = Some(Instant::now());
+    }
+}
+
+/// Fires once nothing has happened for an interval
+///
+/// [`due`](Self::due) does not borrow the timer, so it can sit in a
+/// `select!` next to branches that touch it.
+pub struct IdleTimer {
+    interval: Option<Duration>,
+    last_activity: tokio::time::Instant,
+}
+
+impl IdleTimer {
+    /// Timer firing after `interval` without activity (never when `None`)
+    pub fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            last_activity: tokio::time::Instant::now(),
+        }
+    }
+
+    /// Idle time before the timer fires, if any
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Note activity, restarting the interval
+    pub fn touch(&mut self) {
+        self.last_activity = tokio::time::Instant::now();
+    }
+
+    /// Resolves once idle for the interval
+    pub fn due(&self) -> impl std::future::Future<Output = ()> + 'static {
+        let deadline = self.interval.map(|interval| self.last_activity + interval);
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+/// Schedules the cover protocol's idle traffic on quiet tunnels
+///
+/// Tunnel activity in either direction pushes the next keepalive back, so
+/// only the side that would otherwise stay silent longest sends one.
+pub struct KeepaliveLayer {
+    config: KeepaliveConfig,
+    timer: IdleTimer,
+}
+
+impl KeepaliveLayer {
+    /// Create keepalive layer for `protocol`
+    pub fn new(config: &KeepaliveConfig, protocol: &str) -> Self {
+        let mut layer = Self {
+            config: config.clone(),
+            timer: IdleTimer::new(None),
+        };
+        layer.set_protocol(protocol);
+        layer
+    }
+
+    /// Switch to another protocol's interval (after rotation)
+    pub fn set_protocol(&mut self, protocol: &str) {
+        let family = protocol.split(['-', '_']).next().unwrap_or(protocol);
+        self.timer.interval = if self.config.enabled {
+            self.config
+                .intervals
+                .get(protocol)
+                .or_else(|| self.config.intervals.get(family))
+                .map(|&secs| Duration::from_secs(secs))
+                .or_else(|| Self::default_interval(family))
+                .filter(|interval| !interval.is_zero())
+        } else {
+            None
+        };
+    }
+
+    /// Idle time before a keepalive is sent, if any
+    pub fn interval(&self) -> Option<Duration> {
+        self.timer.interval()
+    }
+
+    /// Built-in idle intervals of common cover protocols
+    pub fn default_interval(family: &str) -> Option<Duration> {
+        let secs = match family {
+            // Resolvers re-query the zone's SOA well within TCP idle timeouts
+            "dns" => 20,
+            // OpenSSH ServerAliveInterval as commonly deployed
+            "ssh" => 30,
+            // Browsers keep idle TLS connections warm for about a minute
+            "https" | "http" | "tls" => 45,
+            // QUIC PINGs stay under the usual 30s idle timeout
+            "quic" => 15,
+            _ => return None,
+        };
+        Some(Duration::from_secs(secs))
+    }
+
+    /// Note traffic through the tunnel
+    pub fn touch(&mut self) {
+        self.timer.touch();
+    }
+
+    /// Resolves once the tunnel has been idle for the interval (never when
+    /// keepalives are off)
+    pub fn due(&self) -> impl std::future::Future<Output = ()> + 'static {
+        self.timer.due()
+    }
+}
+
+/// Per-connection write shaping used by the relay loops
+pub struct RelayShaping {
+    /// Timing of tunnel writes
+    pub jitter: JitterLayer,
+    /// Plaintext bytes read per tunnel write
+    pub record_size: usize,
+    /// Wait for more data before sending a partial record
+    pub coalesce_window: Duration,
+    /// Largest chunk held for delivery to a peer
+    pub max_in_flight: usize,
+    /// Time a peer has to accept a chunk
+    pub write_timeout: Duration,
+    /// Bytes and packets moved through the tunnel
+    pub counters: ConnectionCounters,
+    /// Idle traffic of the cover protocol
+    pub keepalive: KeepaliveLayer,
+    /// Closes the relay when no data moves in either direction
+    pub idle: IdleTimer,
+    /// Time the peer gets to finish once the relay starts closing
+    pub drain_timeout: Duration,
+}
+
+impl RelayShaping {
+    /// Shaping for a tunnel carrying `protocol`
+    pub fn new(config: &NooshdarooConfig, protocol: &str) -> Self {
+        let shaping = &config.traffic_shaping;
+        let record_size = if shaping.segmentation.enabled {
+            crate::segmentation::record_size(&shaping.segmentation)
+        } else {
+            config.relay.buffer_size
+        };
+        Self {
+            jitter: JitterLayer::new(&shaping.jitter, protocol),
+            record_size,
+            coalesce_window: crate::segmentation::coalesce_window(&shaping.segmentation),
+            max_in_flight: config.relay.max_in_flight.max(record_size),
+            write_timeout: config.relay.write_timeout,
+            counters: ConnectionCounters::default(),
+            keepalive: KeepaliveLayer::new(&shaping.keepalive, protocol),
+            idle: IdleTimer::new(config.relay.idle_timeout),
+            drain_timeout: config.relay.drain_timeout,
+        }
+    }
+
+    /// Credit relayed traffic to `counters`
+    pub fn with_counters(mut self, counters: ConnectionCounters) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Account for a chunk of `bytes` written into the tunnel
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.counters.record_sent(bytes);
+        self.keepalive.touch();
+        self.idle.touch();
+    }
+
+    /// Account for a chunk of `bytes` read from the tunnel
+    pub fn record_received(&mut self, bytes: usize) {
+        self.counters.record_received(bytes);
+        self.keepalive.touch();
+        self.idle.touch();
+    }
+
+    /// Discard what `reader` still sends until it closes or the drain
+    /// timeout passes, so closing the socket afterwards does not reset a
+    /// connection with unread data
+    pub async fn drain<R>(&self, reader: &mut R)
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 4096];
+        let deadline = tokio::time::Instant::now() + self.drain_timeout;
+        while let Ok(Ok(n)) = tokio::time::timeout_at(deadline, tokio::io::AsyncReadExt::read(reader, &mut buf)).await {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Deliver a `len`-byte chunk with `write`, enforcing the in-flight cap
+    /// and write timeout
+    pub async fn deliver<T, E, F>(&self, len: usize, write: F) -> Result<T, E>
+    where
+        F: std::future::Future<Output = Result<T, E>>,
+        E: From<std::io::Error>,
+    {
+        if len > self.max_in_flight {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("chunk of {} bytes exceeds in-flight limit of {}", len, self.max_in_flight),
+            )
+            .into());
+        }
+        match tokio::time::timeout(self.write_timeout, write).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("peer did not accept {} bytes within {:?}", len, self.write_timeout),
+            )
+            .into()),
+        }
+    }
+
+    /// Top up a read of `n` bytes in `buf` with whatever else `reader`
+    /// delivers within the coalescing window, returning the new length.
+    ///
+    /// EOF and errors end the window early; the next read reports them.
+    pub async fn coalesce<R>(&self, reader: &mut R, buf: &mut [u8], mut n: usize) -> usize
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        if self.coalesce_window.is_zero() {
+            return n;
+        }
+        let deadline = tokio::time::Instant::now() + self.coalesce_window;
+        while n < buf.len() {
+            match tokio::time::timeout_at(deadline, tokio::io::AsyncReadExt::read(reader, &mut buf[n..])).await {
+                Ok(Ok(read)) if read > 0 => n += read,
+                _ => break,
+            }
+        }
+        n
+    }
+}
+
 /// Bandwidth limiter
 pub struct BandwidthLimiter {
     max_bytes_per_sec: u64,
@@ -354,4 +692,123 @@ mod tests {
         limiter.wait_for(100).await;
         assert!(true); // If we get here, wait worked
     }
+
+    #[tokio::test]
+    async fn test_jitter_layer() {
+        use std::collections::HashMap;
+
+        let disabled = JitterLayer::new(&JitterConfig::default(), "dns");
+        assert!(!disabled.is_active());
+
+        let mut config = JitterConfig {
+            enabled: true,
+            protocols: HashMap::new(),
+        };
+        let mut dns = JitterLayer::new(&config, "dns-udp-tunnel");
+        assert!(dns.is_active());
+        for _ in 0..100 {
+            assert!(dns.sample_gap() <= Duration::from_millis(150));
+        }
+        assert!(!JitterLayer::new(&config, "bittorrent").is_active());
+
+        config.protocols.insert(
+            "https".to_string(),
+            JitterDistribution {
+                distribution: DistributionType::Uniform,
+                mean_delay: 20_000,
+                stddev_delay: 0,
+                max_delay: 50_000,
+            },
+        );
+        let mut https = JitterLayer::new(&config, "https-google-com");
+        assert_eq!(https.sample_gap(), Duration::from_millis(20));
+
+        // First write goes out immediately, the next waits out the gap
+        let start = Instant::now();
+        https.pace().await;
+        assert!(start.elapsed() < Duration::from_millis(20));
+        https.pace().await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        https.set_protocol("bittorrent");
+        assert!(!https.is_active());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_layer() {
+        use std::collections::HashMap;
+
+        assert_eq!(KeepaliveLayer::new(&KeepaliveConfig::default(), "ssh").interval(), None);
+
+        let config = KeepaliveConfig {
+            enabled: true,
+            intervals: HashMap::from([("dns".to_string(), 5), ("https-google-com".to_string(), 0)]),
+        };
+        let mut layer = KeepaliveLayer::new(&config, "ssh");
+        assert_eq!(layer.interval(), Some(Duration::from_secs(30)));
+        assert_eq!(KeepaliveLayer::new(&config, "https-google-com").interval(), None);
+        assert_eq!(KeepaliveLayer::new(&config, "bittorrent").interval(), None);
+
+        layer.set_protocol("dns-udp-tunnel");
+        assert_eq!(layer.interval(), Some(Duration::from_secs(5)));
+
+        // Activity postpones the keepalive by a full interval
+        let start = tokio::time::Instant::now();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        layer.touch();
+        layer.due().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(8));
+    }
+
+    #[tokio::test]
+    async fn test_relay_limits() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut config = NooshdarooConfig::default();
+        config.relay.buffer_size = 1024;
+        config.relay.max_in_flight = 2048;
+        config.relay.write_timeout = Duration::from_millis(50);
+        let shaping = RelayShaping::new(&config, "https");
+        assert_eq!(shaping.record_size, 1024);
+
+        // Peer never reads: the second chunk fills the pipe and times out
+        let (mut writer, _reader) = tokio::io::duplex(1500);
+        let chunk = [0u8; 1000];
+        shaping.deliver(chunk.len(), writer.write_all(&chunk)).await.unwrap();
+        let err = shaping.deliver(chunk.len(), writer.write_all(&chunk)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        let err = shaping
+            .deliver(4096, async { Ok::<_, std::io::Error>(()) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_relay_coalescing() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut config = NooshdarooConfig::default();
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        let mut buf = [0u8; 64];
+
+        writer.write_all(b"abc").await.unwrap();
+        let off = RelayShaping::new(&config, "https");
+        assert_eq!(off.coalesce(&mut reader, &mut buf, 0).await, 0);
+
+        config.traffic_shaping.segmentation.enabled = true;
+        config.traffic_shaping.segmentation.coalesce_window = 50_000;
+        let shaping = RelayShaping::new(&config, "https");
+        let writes = tokio::spawn(async move {
+            writer.write_all(b"def").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            writer.write_all(b"ghi").await.unwrap();
+            writer
+        });
+        let n = tokio::io::AsyncReadExt::read(&mut reader, &mut buf).await.unwrap();
+        let n = shaping.coalesce(&mut reader, &mut buf, n).await;
+        assert_eq!(&buf[..n], b"abcdefghi");
+        drop(writes.await.unwrap());
+    }
 }
@@ -0,0 +1,167 @@
+//! KCP sessions relayed through TURN servers
+//!
+//! Video calling apps send their media through TURN relays when a direct
+//! path fails, so censors are slow to block TURN servers, and a flow to one
+//! says nothing about where it ends up. With `[socks.turn]` set, the
+//! client allocates a relay on the TURN server with its long-term
+//! credentials and runs its `kcp` sessions through it: the censor sees
+//! STUN and ChannelData between the client and the relay, and the tunnel
+//! server sees datagrams from the relay's address, needing no changes.
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//! server_address = "203.0.113.7:4433"
+//!
+//! [socks.turn]
+//! server = "turn.example.com:3478"
+//! username = "alice"
+//! password = "secret"
+//! ```
+//!
+//! Where no third-party relay is at hand, `[server.turn]` runs a TURN
+//! server next to the `kcp` listener; clients then point `[socks.turn]` at
+//! it, and their traffic is genuine TURN on the wire.
+
+use crate::config::{TurnConfig, TurnServerConfig};
+use crate::kcp_transport::Socket;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::DuplexStream;
+use tokio::net::UdpSocket;
+use webrtc::turn::auth::{generate_auth_key, AuthHandler};
+use webrtc::turn::client::{Client, ClientConfig};
+use webrtc::turn::relay::relay_static::RelayAddressGeneratorStatic;
+use webrtc::turn::server::config::{ConnConfig, ServerConfig};
+use webrtc::turn::server::Server;
+use webrtc::util::vnet::net::Net;
+
+/// Open a KCP session to `server_addr` through a relay allocated on `turn`
+pub async fn connect(server_addr: SocketAddr, turn: &TurnConfig) -> io::Result<DuplexStream> {
+    let (relay, client) = allocate(turn).await?;
+    // The client answers the server's refreshes for as long as the session lasts
+    Ok(crate::kcp_transport::connect_over(relay, server_addr, client))
+}
+
+/// Allocate a relay on `turn`; the relay lasts as long as the returned
+/// client
+pub(crate) async fn allocate(turn: &TurnConfig) -> io::Result<(Socket, Client)> {
+    let turn_addr = tokio::net::lookup_host(&turn.server)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("TURN server {} not found", turn.server)))?;
+    let socket = UdpSocket::bind(crate::kcp_transport::any_local_addr(turn_addr)).await?;
+
+    let client = Client::new(ClientConfig {
+        stun_serv_addr: turn_addr.to_string(),
+        turn_serv_addr: turn_addr.to_string(),
+        username: turn.username.clone(),
+        password: turn.password.clone(),
+        // Learned from the server's challenge
+        realm: String::new(),
+        software: String::new(),
+        rto_in_ms: 0,
+        conn: Arc::new(socket),
+        vnet: None,
+    })
+    .await
+    .map_err(turn_error)?;
+    client.listen().await.map_err(turn_error)?;
+    let relay = client.allocate().await.map_err(turn_error)?;
+    log::debug!("TURN relay {:?} allocated on {}", webrtc::util::Conn::local_addr(&relay), turn_addr);
+    Ok((Arc::new(relay), client))
+}
+
+/// Start the TURN server of `config`; it runs until the returned server is
+/// closed
+pub async fn serve(config: &TurnServerConfig) -> io::Result<Server> {
+    let socket = UdpSocket::bind(config.listen).await?;
+    let key = generate_auth_key(&config.username, &config.realm, &config.password);
+    Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn: Arc::new(socket),
+            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                relay_address: config.relay_address,
+                address: if config.listen.is_ipv4() { "0.0.0.0" } else { "::" }.to_string(),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: config.realm.clone(),
+        auth_handler: Arc::new(StaticUsers(HashMap::from([(config.username.clone(), key)]))),
+        channel_bind_timeout: std::time::Duration::ZERO,
+        alloc_close_notify: None,
+    })
+    .await
+    .map_err(turn_error)
+}
+
+/// Long-term credential keys by user name
+struct StaticUsers(HashMap<String, Vec<u8>>);
+
+impl AuthHandler for StaticUsers {
+    fn auth_handle(&self, username: &str, _realm: &str, src_addr: SocketAddr) -> Result<Vec<u8>, webrtc::turn::Error> {
+        self.0.get(username).cloned().ok_or_else(|| {
+            log::debug!("TURN: unknown user {:?} from {}", username, src_addr);
+            webrtc::turn::Error::ErrNoSuchUser
+        })
+    }
+}
+
+fn turn_error(e: webrtc::turn::Error) -> io::Error {
+    io::Error::other(format!("TURN: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp_transport::KcpListener;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_kcp_through_turn() {
+        let probe = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let turn_addr = probe.local_addr().unwrap();
+        drop(probe);
+        let server_config = TurnServerConfig {
+            listen: turn_addr,
+            relay_address: "127.0.0.1".parse().unwrap(),
+            realm: "example.org".to_string(),
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        };
+        let turn_server = serve(&server_config).await.unwrap();
+        let mut listener = KcpListener::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let mut client_config = TurnConfig {
+            server: turn_addr.to_string(),
+            username: "alice".to_string(),
+            password: "wrong".to_string(),
+        };
+        assert!(connect(listener.local_addr(), &client_config).await.is_err());
+        client_config.password = "secret".to_string();
+        let mut client = connect(listener.local_addr(), &client_config).await.unwrap();
+
+        client.write_all(b"via relay").await.unwrap();
+        let (mut server, from) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = [0u8; 9];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"via relay");
+        // The tunnel server only ever sees the relay
+        assert_ne!(from.port(), turn_addr.port());
+
+        server.write_all(b"back").await.unwrap();
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"back");
+        turn_server.close().await.unwrap();
+    }
+}
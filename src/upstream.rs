@@ -0,0 +1,757 @@
+//! Upstream server pool with client-side load balancing
+//!
+//! A client may be configured with several Nooshdaroo servers. The pool
+//! periodically health-checks every server with [`PathTester`], keeps the
+//! resulting path scores, and hands out a server for each new proxied
+//! connection, spreading load across healthy servers in proportion to their
+//! weight and score.
+//!
+//! A server that cannot be reached or fails the handshake is skipped for the
+//! next-best one before the SOCKS client gets its reply. A tunnel that dies
+//! later, before the server has answered, is handed off: a
+//! [`HandoffStream`] keeps what the client sent, and the stream is replayed
+//! through a tunnel via the next-best server, which opens the target again.
+//! Once the server has answered, the target connection's state lives on that
+//! server, so a tunnel dying then closes the client connection.
+
+use crate::config::SocksConfig;
+use crate::library::ProtocolLibrary;
+use crate::netflow_evasion::{PathTestResult, PathTester};
+use crate::noise_transport::NoiseConfig;
+use crate::protocol::ProtocolId;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Consecutive connection failures before a server is marked unhealthy
+const FAILURE_THRESHOLD: u32 = 3;
+
+/// Number of failover events retained for inspection
+const MAX_FAILOVER_EVENTS: usize = 32;
+
+/// Client bytes kept for replay; a stream that sends more before the
+/// server answers cannot be handed off
+const HANDOFF_BUFFER: usize = 64 * 1024;
+
+/// A single upstream server
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamServer {
+    /// Server address
+    pub addr: SocketAddr,
+    /// Server's Noise static public key (base64), if it differs from the default
+    pub public_key: Option<String>,
+    /// Protocols accepted by this server (empty = any)
+    pub protocols: Vec<ProtocolId>,
+    /// Relative weight for connection distribution
+    pub weight: u32,
+}
+
+impl UpstreamServer {
+    /// Create a server entry that accepts any protocol
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            public_key: None,
+            protocols: Vec::new(),
+            weight: 1,
+        }
+    }
+
+    /// Whether this server accepts the given protocol
+    pub fn supports(&self, protocol: &ProtocolId) -> bool {
+        self.protocols.is_empty() || self.protocols.contains(protocol)
+    }
+
+    /// Build the Noise configuration for this server from the client's base config
+    pub fn noise_config(&self, base: &NoiseConfig) -> NoiseConfig {
+        match self.public_key {
+            Some(ref key) => base.with_remote_key(key),
+            None => base.clone(),
+        }
+    }
+}
+
+/// Health and load snapshot of an upstream server
+#[derive(Debug, Clone)]
+pub struct UpstreamStatus {
+    /// Server entry
+    pub server: UpstreamServer,
+    /// Whether the last health check succeeded
+    pub healthy: bool,
+    /// Last path score (0.0-1.0, higher is better)
+    pub score: f64,
+    /// Number of proxied connections currently using this server
+    pub active_connections: usize,
+    /// Time of the last health check
+    pub last_check: Option<Instant>,
+    /// Tunnel failures since the last success
+    pub consecutive_failures: u32,
+}
+
+/// A connection moved from one upstream server to another
+#[derive(Debug, Clone)]
+pub struct FailoverEvent {
+    /// Server that failed
+    pub from: SocketAddr,
+    /// Server the connection was moved to
+    pub to: SocketAddr,
+    /// Why the original server was abandoned
+    pub reason: String,
+    /// When the failover happened
+    pub at: Instant,
+}
+
+impl UpstreamStatus {
+    fn new(server: UpstreamServer) -> Self {
+        Self {
+            server,
+            healthy: true,
+            score: 1.0,
+            active_connections: 0,
+            last_check: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Selection weight: configured weight scaled by score, divided by current load
+    fn effective_weight(&self) -> f64 {
+        self.server.weight as f64 * self.score.max(0.01) / (1 + self.active_connections) as f64
+    }
+}
+
+/// Pool of upstream servers shared by all proxied connections
+pub struct UpstreamPool {
+    servers: Mutex<Vec<UpstreamStatus>>,
+    next: AtomicUsize,
+    failovers: Mutex<VecDeque<FailoverEvent>>,
+}
+
+impl UpstreamPool {
+    /// Create a pool from a list of servers
+    pub fn new(servers: Vec<UpstreamServer>) -> Self {
+        Self {
+            servers: Mutex::new(servers.into_iter().map(UpstreamStatus::new).collect()),
+            next: AtomicUsize::new(0),
+            failovers: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Build a pool from the `[socks]` config section. `primary` is the
+    /// resolved `server_address` / `--server`, added ahead of the list; an
+    /// entry for the same address adds its key, protocols and weight to it.
+    /// Entry addresses must be `ip:port` here, see [`resolve`](Self::resolve).
+    pub fn from_config(config: &SocksConfig, primary: Option<SocketAddr>) -> Result<Self, String> {
+        let mut servers = Vec::new();
+
+        if let Some(addr) = primary {
+            servers.push(UpstreamServer::new(addr));
+        }
+
+        for entry in &config.servers {
+            let addr: SocketAddr = entry
+                .address
+                .parse()
+                .map_err(|_| format!("Invalid upstream server address: {}", entry.address))?;
+
+            let server = UpstreamServer {
+                addr,
+                public_key: entry.public_key.clone(),
+                protocols: entry.protocols.iter().map(|p| ProtocolId::from(p.as_str())).collect(),
+                weight: entry.weight.max(1),
+            };
+            match servers.iter_mut().find(|s| s.addr == addr) {
+                // Details given for the primary server, or a repeated entry
+                Some(existing) => {
+                    if server.public_key.is_some() {
+                        existing.public_key = server.public_key;
+                    }
+                    if !server.protocols.is_empty() {
+                        existing.protocols = server.protocols;
+                    }
+                    existing.weight = server.weight;
+                }
+                None => servers.push(server),
+            }
+        }
+
+        if servers.is_empty() {
+            return Err("No upstream servers configured".to_string());
+        }
+
+        Ok(Self::new(servers))
+    }
+
+    /// Like [`from_config`](Self::from_config), but entry addresses may be
+    /// `host:port`, resolved over DoH like `server_address`
+    pub async fn resolve(config: &crate::NooshdarooConfig, primary: Option<SocketAddr>) -> Result<Self, String> {
+        let mut socks = config.socks.clone();
+        for entry in &mut socks.servers {
+            let addr = crate::doh::resolve_socket_addr(&config.doh, &config.hosts, &entry.address)
+                .await
+                .map_err(|e| format!("Invalid upstream server address {}: {}", entry.address, e))?;
+            entry.address = addr.to_string();
+        }
+        Self::from_config(&socks, primary)
+    }
+
+    /// Number of servers in the pool
+    pub fn len(&self) -> usize {
+        self.servers.lock().unwrap().len()
+    }
+
+    /// Check if the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Snapshot of all servers' health and load
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    /// Pick a server for a new connection using `protocol`.
+    ///
+    /// Healthy servers are preferred; if none are healthy the best-scoring
+    /// unhealthy one is returned so a stale health check never blocks traffic.
+    /// The returned lease counts as an active connection until dropped.
+    pub fn select(self: &Arc<Self>, protocol: &ProtocolId) -> Option<UpstreamLease> {
+        self.select_excluding(protocol, &[])
+    }
+
+    /// Like [`select`](Self::select), but never returns a server in `exclude`.
+    /// Used to find the next-best server after a tunnel failure.
+    pub fn select_excluding(
+        self: &Arc<Self>,
+        protocol: &ProtocolId,
+        exclude: &[SocketAddr],
+    ) -> Option<UpstreamLease> {
+        let mut servers = self.servers.lock().unwrap();
+        let len = servers.len();
+        if len == 0 {
+            return None;
+        }
+
+        // Rotate the starting point so equal weights are spread round-robin
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+
+        let pick = |healthy_only: bool| {
+            let mut best: Option<(usize, f64)> = None;
+            for offset in 0..len {
+                let i = (start + offset) % len;
+                let s = &servers[i];
+                if !s.server.supports(protocol)
+                    || exclude.contains(&s.server.addr)
+                    || (healthy_only && !s.healthy)
+                {
+                    continue;
+                }
+                let w = s.effective_weight();
+                if best.is_none_or(|(_, bw)| w > bw) {
+                    best = Some((i, w));
+                }
+            }
+            best.map(|(i, _)| i)
+        };
+
+        let index = pick(true).or_else(|| pick(false))?;
+        servers[index].active_connections += 1;
+
+        Some(UpstreamLease {
+            pool: Arc::clone(self),
+            server: servers[index].server.clone(),
+        })
+    }
+
+    /// Record a failed tunnel attempt (connect timeout, handshake failure, ...).
+    /// The server is marked unhealthy after repeated failures.
+    pub fn record_failure(&self, addr: SocketAddr) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(s) = servers.iter_mut().find(|s| s.server.addr == addr) {
+            s.consecutive_failures += 1;
+            if s.consecutive_failures >= FAILURE_THRESHOLD && s.healthy {
+                log::warn!("Upstream {} marked down after {} failures", addr, s.consecutive_failures);
+                s.healthy = false;
+            }
+        }
+    }
+
+    /// Record a successfully established tunnel
+    pub fn record_success(&self, addr: SocketAddr) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(s) = servers.iter_mut().find(|s| s.server.addr == addr) {
+            s.consecutive_failures = 0;
+            s.healthy = true;
+        }
+    }
+
+    /// Record that a connection was moved from `from` to `to`
+    pub fn record_failover(&self, from: SocketAddr, to: SocketAddr, reason: impl Into<String>) {
+        let event = FailoverEvent {
+            from,
+            to,
+            reason: reason.into(),
+            at: Instant::now(),
+        };
+        log::info!("Failover {} -> {}: {}", event.from, event.to, event.reason);
+
+        let mut events = self.failovers.lock().unwrap();
+        if events.len() >= MAX_FAILOVER_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Most recent failover events, oldest first
+    pub fn failover_events(&self) -> Vec<FailoverEvent> {
+        self.failovers.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Apply health check results to the pool
+    pub fn update_health(&self, results: &[PathTestResult]) {
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        for result in results {
+            if let Some(s) = servers.iter_mut().find(|s| s.server.addr == result.addr) {
+                s.healthy = result.success;
+                s.score = result.score();
+                if result.success {
+                    s.consecutive_failures = 0;
+                }
+                s.last_check = Some(now);
+            }
+        }
+    }
+
+    /// Seed health from a saved session, so servers that were down before a
+    /// restart are not tried first
+    pub fn restore(&self, saved: &[crate::session::SavedUpstream]) {
+        let mut servers = self.servers.lock().unwrap();
+        for saved in saved {
+            if let Some(s) = servers.iter_mut().find(|s| s.server.addr == saved.addr) {
+                s.healthy = saved.healthy;
+                s.score = saved.score;
+                s.consecutive_failures = saved.consecutive_failures;
+            }
+        }
+    }
+
+    /// Health-check every server once using the given protocol's profile
+    pub async fn health_check(&self, library: Arc<ProtocolLibrary>, protocol: &ProtocolId) {
+        let meta = match library
+            .get(protocol)
+            .or_else(|| library.get(&ProtocolId::from("https")))
+        {
+            Some(meta) => meta.clone(),
+            None => {
+                log::warn!("No protocol metadata for health checks, skipping");
+                return;
+            }
+        };
+
+        let addrs: Vec<SocketAddr> = self.status().iter().map(|s| s.server.addr).collect();
+        let tester = PathTester::new(library);
+        let results = futures::future::join_all(addrs.iter().map(|addr| tester.test_path(*addr, &meta))).await;
+
+        for result in &results {
+            log::debug!(
+                "Upstream {} health: {} (score: {:.2})",
+                result.addr,
+                if result.success { "up" } else { "down" },
+                result.score()
+            );
+        }
+
+        self.update_health(&results);
+    }
+
+    /// Spawn a background task that health-checks the pool every `interval`
+    pub fn spawn_health_checker(
+        self: &Arc<Self>,
+        library: Arc<ProtocolLibrary>,
+        protocol: ProtocolId,
+        interval: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                pool.health_check(library.clone(), &protocol).await;
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    fn release(&self, addr: SocketAddr) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(s) = servers.iter_mut().find(|s| s.server.addr == addr) {
+            s.active_connections = s.active_connections.saturating_sub(1);
+        }
+    }
+}
+
+/// A server assignment for one proxied connection.
+///
+/// Dropping the lease releases the connection slot in the pool.
+pub struct UpstreamLease {
+    pool: Arc<UpstreamPool>,
+    server: UpstreamServer,
+}
+
+impl UpstreamLease {
+    /// Assigned server
+    pub fn server(&self) -> &UpstreamServer {
+        &self.server
+    }
+
+    /// Assigned server address
+    pub fn addr(&self) -> SocketAddr {
+        self.server.addr
+    }
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        self.pool.release(self.server.addr);
+    }
+}
+
+/// Client side of a tunneled connection that can move to another server
+/// until the server answers
+///
+/// Everything the client sends is kept until the first byte comes back. If
+/// the relay shuts the client down before then (the tunnel ended), the
+/// shutdown is held back and reads report end of stream, so the relay stops
+/// while the client is left waiting. [`hand_off`](Self::hand_off) then
+/// rewinds the stream: the next relay reads the kept bytes again before
+/// anything new.
+pub struct HandoffStream<S> {
+    inner: S,
+    /// What the client sent; `None` once the server answered or it grew
+    /// past [`HANDOFF_BUFFER`]
+    sent: Option<Vec<u8>>,
+    /// How much of `sent` the current relay has read
+    replayed: usize,
+    client_closed: bool,
+    client_failed: bool,
+    /// The relay shut the client down before the server answered
+    tunnel_ended: bool,
+}
+
+impl<S> HandoffStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            sent: Some(Vec::new()),
+            replayed: 0,
+            client_closed: false,
+            client_failed: false,
+            tunnel_ended: false,
+        }
+    }
+
+    /// Rewind for a relay through a new tunnel if the last one ended, or
+    /// failed (`relay_failed`), before the server answered. False means the
+    /// stream cannot move and the client connection should be closed.
+    pub fn hand_off(&mut self, relay_failed: bool) -> bool {
+        if self.sent.is_none() || self.client_failed || !(self.tunnel_ended || relay_failed) {
+            return false;
+        }
+        self.tunnel_ended = false;
+        self.replayed = 0;
+        true
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HandoffStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.tunnel_ended {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(ref sent) = this.sent {
+            if this.replayed < sent.len() {
+                let n = buf.remaining().min(sent.len() - this.replayed);
+                buf.put_slice(&sent[this.replayed..this.replayed + n]);
+                this.replayed += n;
+                return Poll::Ready(Ok(()));
+            }
+        }
+        if this.client_closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[filled..];
+                if read.is_empty() {
+                    this.client_closed = true;
+                } else if let Some(ref mut sent) = this.sent {
+                    if sent.len() + read.len() <= HANDOFF_BUFFER {
+                        sent.extend_from_slice(read);
+                        this.replayed = sent.len();
+                    } else {
+                        this.sent = None;
+                    }
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => {
+                this.client_failed = true;
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HandoffStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_write(cx, data) {
+            Poll::Ready(Ok(n)) => {
+                if n > 0 {
+                    // The client has seen the server's answer: no going back
+                    this.sent = None;
+                }
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(Err(e)) => {
+                this.client_failed = true;
+                Poll::Ready(Err(e))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.sent.is_some() && !self.client_failed {
+            self.tunnel_ended = true;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(addr: &str, protocols: &[&str], weight: u32) -> UpstreamServer {
+        UpstreamServer {
+            addr: addr.parse().unwrap(),
+            public_key: None,
+            protocols: protocols.iter().map(|p| ProtocolId::from(*p)).collect(),
+            weight,
+        }
+    }
+
+    fn result(addr: &str, success: bool) -> PathTestResult {
+        PathTestResult {
+            addr: addr.parse().unwrap(),
+            protocol: ProtocolId::from("https"),
+            latency: Duration::from_millis(20),
+            success,
+            packet_loss: if success { 0.0 } else { 1.0 },
+            throughput: if success { 1_000_000 } else { 0 },
+            detection_risk: 0.1,
+        }
+    }
+
+    #[test]
+    fn test_select_distributes_connections() {
+        let pool = Arc::new(UpstreamPool::new(vec![
+            server("192.0.2.1:443", &[], 1),
+            server("192.0.2.2:443", &[], 1),
+        ]));
+        let https = ProtocolId::from("https");
+
+        let a = pool.select(&https).unwrap();
+        let b = pool.select(&https).unwrap();
+        assert_ne!(a.addr(), b.addr());
+
+        drop(a);
+        let status = pool.status();
+        assert_eq!(status.iter().map(|s| s.active_connections).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn test_select_respects_protocols() {
+        let pool = Arc::new(UpstreamPool::new(vec![
+            server("192.0.2.1:443", &["https"], 1),
+            server("192.0.2.2:22", &["ssh"], 1),
+        ]));
+
+        for _ in 0..4 {
+            let lease = pool.select(&ProtocolId::from("ssh")).unwrap();
+            assert_eq!(lease.addr().port(), 22);
+        }
+        assert!(pool.select(&ProtocolId::from("dns")).is_none());
+    }
+
+    #[test]
+    fn test_unhealthy_servers_are_avoided() {
+        let pool = Arc::new(UpstreamPool::new(vec![
+            server("192.0.2.1:443", &[], 1),
+            server("192.0.2.2:443", &[], 1),
+        ]));
+        pool.update_health(&[result("192.0.2.1:443", false), result("192.0.2.2:443", true)]);
+
+        let https = ProtocolId::from("https");
+        let leases: Vec<_> = (0..3).map(|_| pool.select(&https).unwrap()).collect();
+        assert!(leases.iter().all(|l| l.addr().ip().to_string() == "192.0.2.2"));
+
+        // With every server down, still fall back to something
+        pool.update_health(&[result("192.0.2.2:443", false)]);
+        assert!(pool.select(&https).is_some());
+    }
+
+    #[test]
+    fn test_failover_to_next_best() {
+        let pool = Arc::new(UpstreamPool::new(vec![
+            server("192.0.2.1:443", &[], 1),
+            server("192.0.2.2:443", &[], 1),
+        ]));
+        let https = ProtocolId::from("https");
+
+        let first = pool.select(&https).unwrap();
+        let failed = first.addr();
+        drop(first);
+
+        let next = pool.select_excluding(&https, &[failed]).unwrap();
+        assert_ne!(next.addr(), failed);
+        pool.record_failover(failed, next.addr(), "handshake failed");
+        assert_eq!(pool.failover_events().len(), 1);
+
+        // No candidates left once every server has been tried
+        assert!(pool.select_excluding(&https, &[failed, next.addr()]).is_none());
+    }
+
+    #[test]
+    fn test_repeated_failures_mark_server_down() {
+        let pool = Arc::new(UpstreamPool::new(vec![server("192.0.2.1:443", &[], 1)]));
+        let addr = "192.0.2.1:443".parse().unwrap();
+
+        for _ in 0..FAILURE_THRESHOLD {
+            pool.record_failure(addr);
+        }
+        assert!(!pool.status()[0].healthy);
+
+        pool.record_success(addr);
+        assert!(pool.status()[0].healthy);
+        assert_eq!(pool.status()[0].consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_from_config_merges_primary() {
+        let mut config = SocksConfig::default();
+        config.servers.push(crate::config::UpstreamServerConfig {
+            address: "192.0.2.1:443".to_string(),
+            public_key: Some("key".to_string()),
+            protocols: vec!["https".to_string()],
+            weight: 2,
+        });
+        config.servers.push(crate::config::UpstreamServerConfig {
+            address: "192.0.2.9:443".to_string(),
+            public_key: None,
+            protocols: Vec::new(),
+            weight: 1,
+        });
+
+        let pool = UpstreamPool::from_config(&config, Some("192.0.2.9:443".parse().unwrap())).unwrap();
+        assert_eq!(pool.len(), 2);
+
+        let base = NoiseConfig::default();
+        let status = pool.status();
+        let keyed = status.iter().find(|s| s.server.public_key.is_some()).unwrap();
+        assert_eq!(keyed.server.noise_config(&base).remote_public_key.as_deref(), Some("key"));
+    }
+
+    #[test]
+    fn test_from_config_entry_completes_primary() {
+        let mut config = SocksConfig::default();
+        config.servers.push(crate::config::UpstreamServerConfig {
+            address: "192.0.2.9:443".to_string(),
+            public_key: Some("key".to_string()),
+            protocols: vec!["ssh".to_string()],
+            weight: 3,
+        });
+        config.servers.push(crate::config::UpstreamServerConfig {
+            address: "192.0.2.1:443".to_string(),
+            public_key: None,
+            protocols: Vec::new(),
+            weight: 1,
+        });
+
+        let pool = UpstreamPool::from_config(&config, Some("192.0.2.9:443".parse().unwrap())).unwrap();
+        let status = pool.status();
+        assert_eq!(status.len(), 2);
+        let primary = &status[0].server;
+        assert_eq!(primary.addr, "192.0.2.9:443".parse().unwrap());
+        assert_eq!(primary.public_key.as_deref(), Some("key"));
+        assert_eq!(primary.protocols, vec![ProtocolId::from("ssh")]);
+        assert_eq!(primary.weight, 3);
+
+        config.servers[1].address = "upstream.example:443".to_string();
+        assert!(UpstreamPool::from_config(&config, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handoff_replays_until_answered() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (socket, mut client) = tokio::io::duplex(1024);
+        let mut stream = HandoffStream::new(socket);
+        client.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 7);
+
+        // The tunnel ends unanswered: the client is not shut down
+        stream.shutdown().await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+        assert!(stream.hand_off(false));
+
+        // The next relay reads the request again, then what follows it
+        client.write_all(b" more").await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 7);
+        assert_eq!(&buf[..7], b"request");
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b" more");
+
+        // Once answered the stream stays with its server
+        stream.write_all(b"reply").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert!(!stream.hand_off(true));
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply");
+    }
+
+    #[tokio::test]
+    async fn test_handoff_limits() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A relay that ended normally is not handed off
+        let (socket, _client) = tokio::io::duplex(1024);
+        let mut stream = HandoffStream::new(socket);
+        assert!(!stream.hand_off(false));
+        assert!(stream.hand_off(true));
+
+        // Nor is a stream that sent more than can be replayed
+        let (socket, mut client) = tokio::io::duplex(HANDOFF_BUFFER * 2);
+        let mut stream = HandoffStream::new(socket);
+        client.write_all(&vec![0u8; HANDOFF_BUFFER + 1]).await.unwrap();
+        let mut buf = vec![0u8; HANDOFF_BUFFER + 1];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(!stream.hand_off(true));
+    }
+}
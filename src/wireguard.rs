@@ -0,0 +1,664 @@
+//! WireGuard framing for KCP sessions
+//!
+//! Some networks tolerate WireGuard while blocking everything else that
+//! looks like a tunnel. With `relay.wireguard` set, `kcp` datagrams travel
+//! as WireGuard transport data messages, after a WireGuard handshake, so
+//! Noise and shape-shifting still run underneath.
+//!
+//! ```toml
+//! [socks]
+//! transport = "kcp"
+//!
+//! [server]
+//! transport = "kcp"
+//!
+//! [relay]
+//! wireguard = true
+//! ```
+//!
+//! Both ends must set it. The client is the initiator: before its first
+//! datagram it sends a 148-byte handshake initiation (type 1) and the
+//! server answers with a 92-byte handshake response (type 2), each with a
+//! random sender index and ephemeral key, reserved bytes zero and an empty
+//! `mac2` as no cookie is in play. Datagrams then go out as transport data
+//! messages (type 4: receiver index, little-endian counter, plaintext padded
+//! to 16 bytes and sealed with ChaCha20-Poly1305 under keys derived from
+//! the ephemerals), so payloads and tags look like WireGuard's.
+//!
+//! The timers follow the WireGuard paper: the initiator retransmits an
+//! unanswered initiation every [`REKEY_TIMEOUT`] for up to
+//! [`REKEY_ATTEMPT_TIME`], rekeys sessions older than [`REKEY_AFTER_TIME`]
+//! and whenever its data goes unanswered for 15 seconds, confirms a fresh
+//! session with a keepalive if it has no data to send, and either side
+//! sends a keepalive when it received data but sent nothing for
+//! [`KEEPALIVE_TIMEOUT`]. The responder only sends on a session once the
+//! initiator used it, and no session is used past [`REJECT_AFTER_TIME`].
+//! The keys are derived from values on the wire: they disguise the
+//! datagrams, the Noise session inside protects them.
+
+use crate::config::TurnConfig;
+use crate::kcp_transport::{self, KcpListener, Socket};
+use async_trait::async_trait;
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::digest;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::DuplexStream;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Message types
+const HANDSHAKE_INITIATION: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+const TRANSPORT_DATA: u8 = 4;
+
+const INITIATION_SIZE: usize = 148;
+const RESPONSE_SIZE: usize = 92;
+
+/// Type, reserved bytes, receiver index and counter
+const TRANSPORT_HEADER_SIZE: usize = 16;
+
+/// Poly1305 tag
+const TAG_SIZE: usize = 16;
+
+/// Plaintexts are padded to a multiple of this
+const PADDING: usize = 16;
+
+/// WireGuard's timer constants
+pub const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+pub const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+pub const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
+pub const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent data unanswered for this long starts a new handshake
+const UNANSWERED_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Peers silent for this long are forgotten
+const PEER_TIMEOUT: Duration = Duration::from_secs(540);
+
+/// How often the timers are checked
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+/// Bytes of datagrams queued for a handshake to finish; more are dropped
+/// like a full socket buffer and KCP retransmits them
+const QUEUE_BYTES: usize = 64 * 1024;
+
+/// Received datagrams waiting for `recv_from`
+const RECEIVE_QUEUE: usize = 1024;
+
+/// Open a KCP session to `server_addr` carried in WireGuard messages,
+/// through a relay allocated on `turn` if given
+pub async fn connect(server_addr: SocketAddr, turn: Option<&TurnConfig>) -> io::Result<DuplexStream> {
+    match turn {
+        Some(turn) => {
+            let (relay, client) = crate::turn_relay::allocate(turn).await?;
+            Ok(kcp_transport::connect_over(Arc::new(WireGuardConn::new(relay, true)), server_addr, client))
+        }
+        None => {
+            let udp = Arc::new(UdpSocket::bind(kcp_transport::any_local_addr(server_addr)).await?);
+            Ok(kcp_transport::connect_over(Arc::new(WireGuardConn::new(udp, true)), server_addr, ()))
+        }
+    }
+}
+
+/// Accept KCP sessions on `addr` carried in WireGuard messages
+pub async fn bind(addr: SocketAddr) -> io::Result<KcpListener> {
+    let udp = Arc::new(UdpSocket::bind(addr).await?);
+    KcpListener::bind_over(Arc::new(WireGuardConn::new(udp, false)))
+}
+
+/// Datagram socket sending WireGuard messages over `inner`, as the
+/// handshake initiator or the responder
+pub struct WireGuardConn {
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    initiator: bool,
+    received: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl WireGuardConn {
+    pub fn new(inner: Socket, initiator: bool) -> Self {
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, received) = mpsc::channel(RECEIVE_QUEUE);
+        let tasks = [
+            tokio::spawn(run_timers(Arc::clone(&inner), Arc::clone(&peers), initiator)),
+            tokio::spawn(receive(Arc::clone(&inner), Arc::clone(&peers), initiator, tx)),
+        ];
+        Self {
+            inner,
+            peers,
+            initiator,
+            received: tokio::sync::Mutex::new(received),
+            tasks,
+        }
+    }
+}
+
+impl Drop for WireGuardConn {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl webrtc::util::Conn for WireGuardConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(unsupported())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(webrtc::util::Error::ErrUseClosedNetworkConn)?;
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok((n, from))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(unsupported())
+    }
+
+    /// Send `buf` to `target` in a transport data message, or queue it
+    /// until a handshake finishes
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let messages = {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.entry(target).or_insert_with(Peer::new);
+            peer.send(buf.to_vec(), self.initiator)
+        };
+        for message in messages {
+            self.inner.send_to(&message, target).await?;
+        }
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.inner.close().await
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// Keys and indices of one handshake's session
+struct Session {
+    local_index: u32,
+    remote_index: u32,
+    send_key: LessSafeKey,
+    receive_key: LessSafeKey,
+    counter: u64,
+    created: Instant,
+    /// The initiator sent on it; until then the responder must not
+    confirmed: bool,
+}
+
+impl Session {
+    /// Session of a handshake between the `initiation` and `response`
+    /// ephemerals, from the point of view of `initiator` or the responder
+    fn new(local_index: u32, remote_index: u32, initiation: &[u8], response: &[u8], initiator: bool) -> Self {
+        let to_responder = derive_key(1, initiation, response);
+        let to_initiator = derive_key(2, initiation, response);
+        let (send_key, receive_key) = if initiator { (to_responder, to_initiator) } else { (to_initiator, to_responder) };
+        Self {
+            local_index,
+            remote_index,
+            send_key,
+            receive_key,
+            counter: 0,
+            created: Instant::now(),
+            confirmed: initiator,
+        }
+    }
+
+    fn usable(&self) -> bool {
+        self.confirmed && self.created.elapsed() < REJECT_AFTER_TIME
+    }
+
+    /// Transport data message carrying `datagram`; empty for a keepalive
+    fn seal(&mut self, datagram: &[u8]) -> Vec<u8> {
+        let mut plaintext = Vec::with_capacity(2 + datagram.len() + PADDING);
+        if !datagram.is_empty() {
+            plaintext.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+            plaintext.extend_from_slice(datagram);
+            plaintext.resize(plaintext.len().div_ceil(PADDING) * PADDING, 0);
+        }
+        let nonce = nonce(self.counter);
+        self.send_key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut plaintext)
+            .expect("plaintext fits a WireGuard message");
+
+        let mut message = Vec::with_capacity(TRANSPORT_HEADER_SIZE + plaintext.len());
+        message.extend_from_slice(&[TRANSPORT_DATA, 0, 0, 0]);
+        message.extend_from_slice(&self.remote_index.to_le_bytes());
+        message.extend_from_slice(&self.counter.to_le_bytes());
+        message.extend_from_slice(&plaintext);
+        self.counter += 1;
+        message
+    }
+
+    /// The datagram in a transport data `message` for this session (empty
+    /// for a keepalive), or `None` if it does not authenticate
+    fn open(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let counter = u64::from_le_bytes(message[8..16].try_into().unwrap());
+        let mut sealed = message[TRANSPORT_HEADER_SIZE..].to_vec();
+        let plaintext = self.receive_key.open_in_place(nonce(counter), Aad::empty(), &mut sealed).ok()?;
+        if plaintext.is_empty() {
+            return Some(Vec::new());
+        }
+        let length = u16::from_be_bytes([plaintext[0], *plaintext.get(1)?]) as usize;
+        plaintext.get(2..2 + length).map(<[u8]>::to_vec)
+    }
+}
+
+/// Initiation sent and not answered yet
+struct Handshake {
+    sender_index: u32,
+    message: Vec<u8>,
+    sent: Instant,
+    /// When the first initiation of this attempt went out
+    started: Instant,
+}
+
+/// WireGuard state for one peer address
+struct Peer {
+    current: Option<Session>,
+    /// Responder: session of the latest handshake, until the initiator
+    /// uses it
+    next: Option<Session>,
+    /// Previous session, for messages still in flight
+    previous: Option<Session>,
+    handshake: Option<Handshake>,
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+    last_sent: Instant,
+    last_received: Instant,
+    /// Data received and not answered by anything sent yet
+    owe_keepalive: bool,
+    /// Data sent since the last message received
+    unanswered_since: Option<Instant>,
+}
+
+impl Peer {
+    fn new() -> Self {
+        Self {
+            current: None,
+            next: None,
+            previous: None,
+            handshake: None,
+            queue: VecDeque::new(),
+            queued: 0,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            owe_keepalive: false,
+            unanswered_since: None,
+        }
+    }
+
+    /// Messages sending `datagram` now, or starting the handshake it waits
+    /// for
+    fn send(&mut self, datagram: Vec<u8>, initiator: bool) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        if initiator && self.handshake.is_none() && self.needs_rekey() {
+            messages.push(self.initiate(Instant::now()));
+        }
+        match self.current.as_mut().filter(|session| session.usable()) {
+            Some(session) => {
+                messages.push(session.seal(&datagram));
+                self.sent();
+                self.unanswered_since.get_or_insert_with(Instant::now);
+            }
+            None if self.queued + datagram.len() <= QUEUE_BYTES => {
+                self.queued += datagram.len();
+                self.queue.push_back(datagram);
+            }
+            None => {}
+        }
+        messages
+    }
+
+    fn needs_rekey(&self) -> bool {
+        match self.current {
+            Some(ref session) => {
+                session.created.elapsed() >= REKEY_AFTER_TIME
+                    || self.unanswered_since.is_some_and(|since| since.elapsed() >= UNANSWERED_TIMEOUT)
+            }
+            None => true,
+        }
+    }
+
+    /// New handshake initiation, continuing the attempt started at `started`
+    fn initiate(&mut self, started: Instant) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let sender_index = rng.gen();
+        let mut message = vec![0u8; INITIATION_SIZE];
+        message[0] = HANDSHAKE_INITIATION;
+        message[4..8].copy_from_slice(&u32::to_le_bytes(sender_index));
+        // Ephemeral, encrypted static key and timestamp, and mac1; mac2 stays zero
+        rng.fill(&mut message[8..INITIATION_SIZE - 16]);
+        self.handshake = Some(Handshake { sender_index, message: message.clone(), sent: Instant::now(), started });
+        self.unanswered_since = None;
+        self.sent();
+        message
+    }
+
+    fn sent(&mut self) {
+        self.last_sent = Instant::now();
+        self.owe_keepalive = false;
+    }
+
+    /// Make `session` current once usable, sending what waited for it
+    fn install(&mut self, session: Session, initiator: bool) -> Vec<Vec<u8>> {
+        self.previous = self.current.replace(session);
+        let session = self.current.as_mut().unwrap();
+        let mut messages: Vec<Vec<u8>> = self.queue.drain(..).map(|datagram| session.seal(&datagram)).collect();
+        self.queued = 0;
+        if !messages.is_empty() {
+            self.unanswered_since = Some(Instant::now());
+        } else if initiator {
+            // Confirm the session to the responder
+            messages.push(session.seal(&[]));
+        }
+        if !messages.is_empty() {
+            self.sent();
+        }
+        messages
+    }
+
+    /// Handle a received `message`: messages to send back, and the datagram
+    /// it carried, if any
+    fn receive(&mut self, message: &[u8], initiator: bool) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        match (message[0], message.len(), initiator) {
+            (HANDSHAKE_INITIATION, INITIATION_SIZE, false) => {
+                let mut rng = rand::thread_rng();
+                let sender_index: u32 = rng.gen();
+                let remote_index = u32::from_le_bytes(message[4..8].try_into().unwrap());
+                let mut response = vec![0u8; RESPONSE_SIZE];
+                response[0] = HANDSHAKE_RESPONSE;
+                response[4..8].copy_from_slice(&sender_index.to_le_bytes());
+                response[8..12].copy_from_slice(&remote_index.to_le_bytes());
+                // Ephemeral, encrypted nothing and mac1; mac2 stays zero
+                rng.fill(&mut response[12..RESPONSE_SIZE - 16]);
+                self.next = Some(Session::new(sender_index, remote_index, &message[8..40], &response[12..44], false));
+                self.received();
+                self.sent();
+                (vec![response], None)
+            }
+            (HANDSHAKE_RESPONSE, RESPONSE_SIZE, true) => {
+                let receiver_index = u32::from_le_bytes(message[8..12].try_into().unwrap());
+                let Some(handshake) = self.handshake.take_if(|h| h.sender_index == receiver_index) else {
+                    return (Vec::new(), None);
+                };
+                let remote_index = u32::from_le_bytes(message[4..8].try_into().unwrap());
+                let session = Session::new(
+                    handshake.sender_index,
+                    remote_index,
+                    &handshake.message[8..40],
+                    &message[12..44],
+                    true,
+                );
+                self.received();
+                (self.install(session, true), None)
+            }
+            (TRANSPORT_DATA, n, _) if n >= TRANSPORT_HEADER_SIZE + TAG_SIZE => {
+                let receiver_index = u32::from_le_bytes(message[4..8].try_into().unwrap());
+                let mut messages = Vec::new();
+                let datagram = if self.next.as_ref().is_some_and(|s| s.local_index == receiver_index) {
+                    let mut session = self.next.take().unwrap();
+                    let datagram = session.open(message);
+                    if datagram.is_some() {
+                        // The initiator uses the new session: so can we
+                        session.confirmed = true;
+                        messages = self.install(session, false);
+                    } else {
+                        self.next = Some(session);
+                    }
+                    datagram
+                } else {
+                    [&self.current, &self.previous]
+                        .into_iter()
+                        .flatten()
+                        .find(|s| s.local_index == receiver_index && s.created.elapsed() < REJECT_AFTER_TIME)
+                        .and_then(|session| session.open(message))
+                };
+                match datagram {
+                    Some(datagram) => {
+                        self.received();
+                        if !datagram.is_empty() {
+                            self.owe_keepalive = true;
+                        }
+                        (messages, Some(datagram).filter(|d| !d.is_empty()))
+                    }
+                    None => (messages, None),
+                }
+            }
+            _ => (Vec::new(), None),
+        }
+    }
+
+    fn received(&mut self) {
+        self.last_received = Instant::now();
+        self.unanswered_since = None;
+    }
+
+    /// Messages the timers send now
+    fn tick(&mut self, initiator: bool) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        if let Some(ref handshake) = self.handshake {
+            if handshake.started.elapsed() >= REKEY_ATTEMPT_TIME {
+                // Give up; the next datagram starts over
+                self.handshake = None;
+                self.queue.clear();
+                self.queued = 0;
+            } else if handshake.sent.elapsed() >= REKEY_TIMEOUT + jitter() {
+                let started = handshake.started;
+                messages.push(self.initiate(started));
+            }
+        } else if initiator && self.current.is_some() && self.needs_rekey() && self.last_sent.elapsed() < REKEY_AFTER_TIME {
+            messages.push(self.initiate(Instant::now()));
+        }
+        if self.owe_keepalive && self.last_sent.elapsed() >= KEEPALIVE_TIMEOUT {
+            if let Some(session) = self.current.as_mut().filter(|session| session.usable()) {
+                messages.push(session.seal(&[]));
+                self.sent();
+            }
+        }
+        messages
+    }
+}
+
+/// Run every peer's timers
+async fn run_timers(inner: Socket, peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>, initiator: bool) {
+    let mut interval = tokio::time::interval(TIMER_TICK);
+    loop {
+        interval.tick().await;
+        let messages: Vec<(Vec<u8>, SocketAddr)> = {
+            let mut peers = peers.lock().unwrap();
+            peers.retain(|_, peer| peer.last_sent.max(peer.last_received).elapsed() < PEER_TIMEOUT);
+            peers
+                .iter_mut()
+                .flat_map(|(addr, peer)| peer.tick(initiator).into_iter().map(|message| (message, *addr)))
+                .collect()
+        };
+        for (message, addr) in messages {
+            if let Err(e) = inner.send_to(&message, addr).await {
+                log::debug!("WireGuard: send to {} failed: {}", addr, e);
+            }
+        }
+    }
+}
+
+/// Handle received messages, passing on the datagrams they carry
+async fn receive(
+    inner: Socket,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    initiator: bool,
+    received: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, from) = match inner.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(webrtc::util::Error::ErrUseClosedNetworkConn) => return,
+            Err(e) => {
+                log::debug!("WireGuard: receive error: {}", e);
+                continue;
+            }
+        };
+        let message = &buf[..n];
+        // Type and three reserved zero bytes
+        if n < 4 || message[1..4] != [0, 0, 0] {
+            continue;
+        }
+        let (replies, datagram) = {
+            let mut peers = peers.lock().unwrap();
+            // Only initiations start talking to a new peer
+            if (initiator || message[0] != HANDSHAKE_INITIATION) && !peers.contains_key(&from) {
+                continue;
+            }
+            peers.entry(from).or_insert_with(Peer::new).receive(message, initiator)
+        };
+        for reply in replies {
+            if let Err(e) = inner.send_to(&reply, from).await {
+                log::debug!("WireGuard: send to {} failed: {}", from, e);
+            }
+        }
+        if let Some(datagram) = datagram {
+            match received.try_send((datagram, from)) {
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+                Err(mpsc::error::TrySendError::Full(_)) => log::debug!("WireGuard: receive queue full, dropping datagram"),
+                Ok(()) => {}
+            }
+        }
+    }
+}
+
+/// Key for `direction` (1: to the responder, 2: to the initiator) of the
+/// handshake between two ephemerals
+fn derive_key(direction: u8, initiation: &[u8], response: &[u8]) -> LessSafeKey {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(b"nooshdaroo wireguard framing");
+    context.update(&[direction]);
+    context.update(initiation);
+    context.update(response);
+    let key = UnboundKey::new(&CHACHA20_POLY1305, context.finish().as_ref()).expect("32-byte key");
+    LessSafeKey::new(key)
+}
+
+/// WireGuard's nonce: four zero bytes and the little-endian counter
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Up to a third of a second, as WireGuard adds to handshake retransmits
+fn jitter() -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0..=333))
+}
+
+fn unsupported() -> webrtc::util::Error {
+    webrtc::util::Error::Other("WireGuard sockets are unconnected".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_handshake_and_transport_messages() {
+        let (mut client, mut server) = (Peer::new(), Peer::new());
+        let messages = client.send(vec![1u8; 100], true);
+        assert_eq!(messages.len(), 1);
+        let initiation = &messages[0];
+        assert_eq!((initiation.len(), &initiation[..4]), (INITIATION_SIZE, &[1u8, 0, 0, 0][..]));
+        assert_eq!(&initiation[INITIATION_SIZE - 16..], &[0u8; 16]);
+
+        let (replies, datagram) = server.receive(initiation, false);
+        assert!(datagram.is_none());
+        let response = &replies[0];
+        assert_eq!((response.len(), &response[..4]), (RESPONSE_SIZE, &[2u8, 0, 0, 0][..]));
+        assert_eq!(&response[8..12], &initiation[4..8]);
+        // Not confirmed yet: the responder queues
+        assert!(server.send(vec![2u8; 10], false).is_empty());
+
+        // The queued datagram goes out once the handshake completes
+        let (messages, _) = client.receive(response, true);
+        assert_eq!(messages.len(), 1);
+        let data = &messages[0];
+        assert_eq!(&data[..4], &[4u8, 0, 0, 0]);
+        assert_eq!(&data[4..8], &response[4..8]);
+        assert_eq!(u64::from_le_bytes(data[8..16].try_into().unwrap()), 0);
+        assert_eq!(data.len(), TRANSPORT_HEADER_SIZE + 112 + TAG_SIZE);
+
+        // Using the session confirms it, releasing the responder's queue
+        let (replies, datagram) = server.receive(data, false);
+        assert_eq!(datagram, Some(vec![1u8; 100]));
+        assert_eq!(replies.len(), 1);
+        let (_, datagram) = client.receive(&replies[0], true);
+        assert_eq!(datagram, Some(vec![2u8; 10]));
+
+        // Tampered messages are dropped
+        let mut data = client.send(vec![3u8; 5], true).remove(0);
+        data[20] ^= 1;
+        assert_eq!(server.receive(&data, false).1, None);
+    }
+
+    #[tokio::test]
+    async fn test_kcp_over_wireguard() {
+        let mut listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = connect(listener.local_addr(), None).await.unwrap();
+
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = vec![0u8; data.len()];
+        tokio::time::timeout(Duration::from_secs(5), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"bye").await.unwrap();
+        let mut buf = [0u8; 3];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"bye");
+    }
+}